@@ -0,0 +1,57 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use nesemu::assembler::assemble;
+use nesemu::cpu::NesCpu;
+
+// instructions run per iteration of the CPU loop
+const INSTRUCTIONS: u64 = 10_000;
+
+// Memory used to be a Copy [u8; 65536], so every construction and move of the CPU copied
+// the whole address space on the stack. These measure that overhead.
+fn construction(c: &mut Criterion) {
+    c.bench_function("cpu new_from_bytes", |b| {
+        b.iter(|| NesCpu::new_from_bytes(black_box(&[0xEA, 0xEA, 0xEA])))
+    });
+}
+
+fn moves(c: &mut Criterion) {
+    #[inline(never)]
+    fn pass_through(cpu: NesCpu) -> NesCpu {
+        black_box(cpu)
+    }
+
+    let mut cpu = Some(NesCpu::new());
+    c.bench_function("cpu move", |b| {
+        b.iter(|| cpu = Some(pass_through(cpu.take().unwrap())))
+    });
+}
+
+// A loop of loads, adds, stores and a branch, with nothing but the CPU and RAM in use,
+// reported in instructions per second
+fn instructions(c: &mut Criterion) {
+    let program = assemble(
+        "
+        loop:   lda $00,x
+                clc
+                adc #1
+                sta $0200,x
+                inx
+                bne loop
+                jmp loop
+        ",
+    )
+    .unwrap();
+    let mut cpu = NesCpu::new_from_bytes(&program);
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.bench_function("instructions", |b| {
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                cpu.fetch_decode_next();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, construction, moves, instructions);
+criterion_main!(benches);
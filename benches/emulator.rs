@@ -0,0 +1,57 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use nesemu::{parse_bin_file, Emulator};
+
+// Whole-system speed on a ROM that keeps the PPU drawing: frames per second with the CPU, PPU
+// and APU all running, and how long a save state takes to make, to make into a buffer kept from
+// the last one, and to load.
+
+const ROM: &str = "test-bin/full_nes_palette.nes";
+// frames run before measuring, so the ROM is past its setup and drawing
+const WARM_UP_FRAMES: usize = 30;
+
+fn running(decode_cache: bool) -> Emulator {
+    let rom = parse_bin_file(ROM).unwrap();
+    let mut emulator = Emulator::new();
+    emulator.set_decode_cache(decode_cache);
+    emulator.load_cartridge(&rom).unwrap();
+    for _ in 0..WARM_UP_FRAMES {
+        emulator.run_frame();
+    }
+    emulator.clear_audio_samples();
+    emulator
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("emulator");
+    group.throughput(Throughput::Elements(1));
+    for (name, decode_cache) in [("frame", false), ("frame decode cache", true)] {
+        let mut emulator = running(decode_cache);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                emulator.run_frame();
+                // the samples would pile up otherwise, a frontend takes them every frame
+                emulator.clear_audio_samples();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn states(c: &mut Criterion) {
+    let mut emulator = running(false);
+    let state = emulator.save_state();
+    c.bench_function("emulator save_state", |b| {
+        b.iter(|| black_box(emulator.save_state()))
+    });
+    // what rewind does every few frames, into a buffer it keeps
+    let mut buffer = Vec::new();
+    c.bench_function("emulator save_state_into", |b| {
+        b.iter(|| emulator.save_state_into(black_box(&mut buffer)))
+    });
+    c.bench_function("emulator load_state", |b| {
+        b.iter(|| emulator.load_state(black_box(&state)).unwrap())
+    });
+}
+
+criterion_group!(benches, frames, states);
+criterion_main!(benches);
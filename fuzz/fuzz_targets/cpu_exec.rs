@@ -0,0 +1,31 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nesemu::cpu::NesCpu;
+use nesemu::memory::Bus;
+
+// Runs arbitrary code on a CPU with flat memory, so there's nothing but the CPU to go wrong.
+// The first bytes set the registers and the rest are loaded from $0000 up. Whatever it runs,
+// the CPU should at worst jam, never panic or overflow.
+
+const REGISTERS: usize = 7;
+const INSTRUCTIONS: usize = 1000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < REGISTERS {
+        return;
+    }
+    let (registers, code) = data.split_at(REGISTERS);
+    let mut cpu = NesCpu::new();
+    cpu.memory.set_flat(true);
+    cpu.memory.write_bytes(0, &code[..code.len().min(0x10000)]);
+    cpu.reg.pc = u16::from_le_bytes([registers[0], registers[1]]);
+    cpu.reg.set_sp(registers[2]);
+    cpu.reg.accumulator = registers[3];
+    cpu.reg.idx = registers[4];
+    cpu.reg.set_idy(registers[5]);
+    cpu.reg.set_status(registers[6]);
+    for _ in 0..INSTRUCTIONS {
+        cpu.fetch_decode_next();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nesemu::NesRom;
+
+// Any bytes at all, as an iNES, NES 2.0 or UNIF image. Bad ones should come back as
+// errors, never panics.
+fuzz_target!(|data: &[u8]| {
+    let _ = NesRom::from_bytes(data);
+});
@@ -0,0 +1,95 @@
+/* nesemu C API, generated from src/capi.rs by cbindgen, don't edit by hand */
+
+#ifndef NESEMU_H
+#define NESEMU_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define NESEMU_WIDTH 256
+
+#define NESEMU_HEIGHT 240
+
+typedef enum NesemuResult {
+  NesemuResult_Ok = 0,
+  // A pointer passed in was NULL
+  NesemuResult_NullArgument,
+  // Not a rom, or a damaged one
+  NesemuResult_BadRom,
+  // The rom's board isn't emulated
+  NesemuResult_UnsupportedMapper,
+  // Not a save state of this game
+  NesemuResult_BadState,
+  // A player number past the four there can be
+  NesemuResult_NoSuchPlayer,
+} NesemuResult;
+
+// An emulator and what's been handed out from it to C
+typedef struct NesemuEmulator NesemuEmulator;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// A new emulator with nothing loaded, freed with nesemu_free
+NesemuEmulator *nesemu_new(void);
+
+// # Safety
+// `emulator` must come from nesemu_new and not be used again. NULL is ignored.
+void nesemu_free(NesemuEmulator *emulator);
+
+// Load an iNES, NES 2.0 or UNIF rom, zipped or gzipped or not, from `len` bytes at `data` and
+// power on. The bytes are copied.
+//
+// # Safety
+// `emulator` must come from nesemu_new and `data` must point to `len` readable bytes.
+NesemuResult nesemu_load_rom(NesemuEmulator *emulator, const uint8_t *data, size_t len);
+
+// Run until the next frame is drawn
+//
+// # Safety
+// `emulator` must come from nesemu_new.
+void nesemu_run_frame(NesemuEmulator *emulator);
+
+// The last frame, NESEMU_WIDTH x NESEMU_HEIGHT pixels of RGBA, 4 bytes each, row by row.
+// It stays valid until the next call with this emulator.
+//
+// # Safety
+// `emulator` must come from nesemu_new.
+const uint8_t *nesemu_framebuffer(NesemuEmulator *emulator);
+
+// What `player` (0-3) is holding from the next frame on, one bit a button: A, B, Select,
+// Start, Up, Down, Left, Right from bit 0 up
+//
+// # Safety
+// `emulator` must come from nesemu_new.
+NesemuResult nesemu_set_buttons(NesemuEmulator *emulator, uint32_t player, uint8_t buttons);
+
+// Write a save state into `buffer`, returning its size. When that's more than `capacity`
+// nothing is written, so a call with a NULL buffer finds the size to allocate.
+//
+// # Safety
+// `emulator` must come from nesemu_new and `buffer`, unless NULL, must point to `capacity`
+// writable bytes.
+size_t nesemu_save_state(const NesemuEmulator *emulator, uint8_t *buffer, size_t capacity);
+
+// Load a save state of `len` bytes at `data`, made by nesemu_save_state with the same rom
+//
+// # Safety
+// `emulator` must come from nesemu_new and `data` must point to `len` readable bytes.
+NesemuResult nesemu_load_state(NesemuEmulator *emulator, const uint8_t *data, size_t len);
+
+// Why the last call that failed did, as UTF-8. It stays valid until the next failure.
+//
+// # Safety
+// `emulator` must come from nesemu_new.
+const char *nesemu_last_error(const NesemuEmulator *emulator);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NESEMU_H */
@@ -0,0 +1,220 @@
+use crate::emulator::Emulator;
+use crate::memory_editor::AddressSpace;
+use crate::ppu::Frame;
+use crate::{NesRom, RomError};
+
+// A gym style environment for training agents: reset to the same starting point, hold buttons
+// for a few frames at a time, and get back the picture, the RAM values asked for and a reward
+// worked out from memory by a callback. The core is deterministic, so the same buttons from a
+// reset always give the same observations.
+
+/// Reward for the frame just run, added up over a step
+pub type RewardFn = Box<dyn FnMut(&Emulator) -> f64>;
+/// Whether the episode is over after the frame just run
+pub type DoneFn = Box<dyn FnMut(&Emulator) -> bool>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvConfig {
+    /// Frames each step holds the buttons for, the observation is of the last one
+    pub frame_skip: u32,
+    /// CPU addresses read into every observation, in this order
+    pub ram: Vec<u16>,
+    /// Power on randomness, see `Emulator::set_seed`
+    pub seed: u64,
+    /// Frames run after power on before the starting point, to get past the boot
+    pub warm_up: u32,
+    /// Frames after which an episode is over whatever the done callback says, 0 for no limit
+    pub max_frames: u64,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        EnvConfig {
+            frame_skip: 4,
+            ram: Vec::new(),
+            seed: 0,
+            warm_up: 0,
+            max_frames: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Observation {
+    pub frame: Frame,
+    /// The bytes at `EnvConfig::ram`
+    pub ram: Vec<u8>,
+    /// The rewards of each frame of the step added up, 0 after a reset
+    pub reward: f64,
+    pub done: bool,
+    /// Frames run since the reset
+    pub frames: u64,
+}
+
+pub struct Env {
+    emulator: Emulator,
+    config: EnvConfig,
+    // save state of the starting point
+    start: Vec<u8>,
+    reward: Option<RewardFn>,
+    done: Option<DoneFn>,
+    frames: u64,
+}
+
+impl Env {
+    /// Power on with `rom` and run the warm up frames to make the starting point. Call
+    /// `reset` before the first step.
+    pub fn new(rom: &NesRom, config: EnvConfig) -> Result<Env, RomError> {
+        let mut emulator = Emulator::new();
+        emulator.set_decode_cache(true);
+        emulator.set_seed(config.seed);
+        emulator.load_cartridge(rom)?;
+        for _ in 0..config.warm_up {
+            emulator.run_frame();
+            emulator.clear_audio_samples();
+        }
+        Ok(Env {
+            start: emulator.save_state(),
+            emulator,
+            config,
+            reward: None,
+            done: None,
+            frames: 0,
+        })
+    }
+
+    pub fn set_reward(&mut self, reward: impl FnMut(&Emulator) -> f64 + 'static) {
+        self.reward = Some(Box::new(reward));
+    }
+
+    pub fn set_done(&mut self, done: impl FnMut(&Emulator) -> bool + 'static) {
+        self.done = Some(Box::new(done));
+    }
+
+    /// Back to the starting point with no buttons held
+    pub fn reset(&mut self) -> Observation {
+        self.emulator
+            .load_state(&self.start)
+            .expect("the emulator loads its own state");
+        self.emulator.set_buttons(0, 0);
+        self.frames = 0;
+        self.observe(0.0, false)
+    }
+
+    /// Hold `buttons` (see the `input::BUTTON_*` bits) on controller 1 for `frame_skip` frames,
+    /// or until the episode is over
+    pub fn step(&mut self, buttons: u8) -> Observation {
+        self.emulator.set_buttons(0, buttons);
+        let mut reward = 0.0;
+        let mut done = false;
+        for _ in 0..self.config.frame_skip.max(1) {
+            self.emulator.run_frame();
+            self.emulator.clear_audio_samples();
+            self.frames += 1;
+            if let Some(reward_fn) = &mut self.reward {
+                reward += reward_fn(&self.emulator);
+            }
+            done = self.done.as_mut().is_some_and(|done| done(&self.emulator))
+                || (self.config.max_frames != 0 && self.frames >= self.config.max_frames);
+            if done {
+                break;
+            }
+        }
+        self.observe(reward, done)
+    }
+
+    fn observe(&self, reward: f64, done: bool) -> Observation {
+        Observation {
+            frame: self.emulator.frame().clone(),
+            ram: self
+                .config
+                .ram
+                .iter()
+                .map(|&address| self.emulator.peek_memory(AddressSpace::Cpu, address))
+                .collect(),
+            reward,
+            done,
+            frames: self.frames,
+        }
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{BUTTON_RIGHT, BUTTON_START};
+    use crate::parse_bin_file;
+
+    fn env(config: EnvConfig) -> Env {
+        let rom = parse_bin_file("test-bin/full_nes_palette.nes").unwrap();
+        Env::new(&rom, config).unwrap()
+    }
+
+    #[test]
+    fn the_same_buttons_give_the_same_run() {
+        let config = EnvConfig {
+            ram: vec![0x0000, 0x0001, 0x00FF],
+            warm_up: 10,
+            ..EnvConfig::default()
+        };
+        let run = |env: &mut Env| {
+            env.reset();
+            [BUTTON_START, 0, BUTTON_RIGHT, 0]
+                .map(|buttons| {
+                    let observation = env.step(buttons);
+                    (observation.frame.sha1(), observation.ram)
+                })
+                .to_vec()
+        };
+        let mut first = env(config.clone());
+        let once = run(&mut first);
+        assert_eq!(run(&mut first), once);
+        assert_eq!(run(&mut env(config)), once);
+        assert_eq!(once[0].1.len(), 3);
+    }
+
+    #[test]
+    fn steps_skip_frames_and_add_up_rewards() {
+        let mut env = env(EnvConfig {
+            frame_skip: 3,
+            ..EnvConfig::default()
+        });
+        env.set_reward(|_| 0.5);
+        let observation = env.reset();
+        assert_eq!((observation.frames, observation.reward), (0, 0.0));
+        let observation = env.step(0);
+        assert_eq!((observation.frames, observation.reward), (3, 1.5));
+        assert!(!observation.done);
+        assert_eq!(env.step(0).frames, 6);
+    }
+
+    #[test]
+    fn episodes_end_early() {
+        let mut env = env(EnvConfig {
+            frame_skip: 4,
+            max_frames: 6,
+            ..EnvConfig::default()
+        });
+        env.reset();
+        assert!(!env.step(0).done);
+        let observation = env.step(0);
+        assert!(observation.done);
+        assert_eq!(observation.frames, 6);
+
+        env.set_done(|emulator| emulator.frame_count() % 2 == 0);
+        env.reset();
+        assert!(env.step(0).frames <= 2);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// https://www.nesdev.org/wiki/APU_DMC
+// timer periods in CPU cycles
+const RATES_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const RATES_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// Delta modulation channel, plays 1 bit delta encoded samples read from CPU memory
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dmc {
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "crate::serde_helpers::table",
+            deserialize_with = "deserialize_rates"
+        )
+    )]
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    // memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    irq: bool,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        let rates = Self::rates(region);
+        Dmc {
+            rates,
+            irq_enabled: false,
+            looping: false,
+            timer_period: rates[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    fn rates(region: Region) -> &'static [u16; 16] {
+        match region {
+            Region::Ntsc => &RATES_NTSC,
+            Region::Pal | Region::Dendy => &RATES_PAL,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        let index = self.rates.iter().position(|&r| r == self.timer_period);
+        self.rates = Self::rates(region);
+        self.timer_period = self.rates[index.unwrap_or(0)];
+    }
+
+    /// Register 0-3 of the channel ($4010-$4013)
+    pub fn write_register(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = byte & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = byte & 0x40 != 0;
+                self.timer_period = self.rates[(byte & 0x0F) as usize];
+            }
+            1 => self.level = byte & 0x7F,
+            2 => self.sample_address = 0xC000 | ((byte as u16) << 6),
+            3 => self.sample_length = ((byte as u16) << 4) | 1,
+            _ => {}
+        }
+    }
+
+    /// $4015 bit 4: start the sample if it isn't playing, or stop it
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            // keeps the level inside 0-127
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// Address the memory reader wants to fetch, when the sample buffer needs refilling
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Hand the byte fetched for `dma_request` to the reader
+    pub fn dma_complete(&mut self, byte: u8) {
+        self.sample_buffer = Some(byte);
+        // the address wraps from $FFFF to $8000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Sample bytes still to be fetched, what $4015 bit 4 reports
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    /// Current output level, 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    /// The reset button only keeps the level's lowest bit
+    pub fn reset_level(&mut self) {
+        self.level &= 1;
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_rates<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<&'static [u16; 16], D::Error> {
+    crate::serde_helpers::one_of(deserializer, &[&RATES_NTSC, &RATES_PAL], "DMC rate table")
+}
+
+// the rate table comes from the region, which the console restores before the channels
+impl SaveState for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        state.u8(self.level);
+        state.u16(self.sample_address);
+        state.u16(self.sample_length);
+        state.u16(self.current_address);
+        state.u16(self.bytes_remaining);
+        state.bool(self.sample_buffer.is_some());
+        state.u8(self.sample_buffer.unwrap_or(0));
+        state.u8(self.shift);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+        state.bool(self.irq);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.level = state.u8()? & 0x7F;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        let byte = state.u8()?;
+        self.sample_buffer = buffered.then_some(byte);
+        self.shift = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        self.silence = state.bool()?;
+        self.irq = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(dmc: &mut Dmc, memory: &[u8], cycles: usize) -> usize {
+        let mut fetches = 0;
+        for _ in 0..cycles {
+            dmc.clock_timer();
+            if let Some(address) = dmc.dma_request() {
+                dmc.dma_complete(memory[(address - 0xC000) as usize]);
+                fetches += 1;
+            }
+        }
+        fetches
+    }
+
+    #[test]
+    fn plays_sample_then_raises_irq() {
+        let mut dmc = Dmc::new(Region::Ntsc);
+        // irq on, fastest rate, sample at $C000, 17 bytes
+        dmc.write_register(0, 0x8F);
+        dmc.write_register(1, 64);
+        dmc.write_register(2, 0);
+        dmc.write_register(3, 1);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.bytes_remaining, 17);
+
+        // all ones ramps the level up by 2 per bit
+        let memory = [0xFF; 17];
+        assert_eq!(run(&mut dmc, &memory, 1), 1);
+        // the first byte is only picked up once the current (silent) byte runs out
+        run(&mut dmc, &memory, 54 * 7);
+        assert_eq!(dmc.output(), 64);
+        run(&mut dmc, &memory, 54 * 4);
+        assert_eq!(dmc.output(), 72);
+
+        // the second byte was fetched as soon as the first one moved to the shifter
+        assert_eq!(run(&mut dmc, &memory, 54 * 8 * 17), 15);
+        assert_eq!(dmc.bytes_remaining, 0);
+        assert!(dmc.irq_pending());
+        dmc.set_enabled(false);
+        assert!(!dmc.irq_pending());
+    }
+
+    #[test]
+    fn looping_restarts_without_irq() {
+        let mut dmc = Dmc::new(Region::Ntsc);
+        dmc.write_register(0, 0xCF);
+        dmc.write_register(3, 0);
+        dmc.set_enabled(true);
+        let memory = [0x00; 1];
+        assert_eq!(run(&mut dmc, &memory, 54 * 8 * 4), 5);
+        assert!(dmc.bytes_remaining > 0);
+        assert!(!dmc.irq_pending());
+    }
+}
@@ -0,0 +1,48 @@
+// https://www.nesdev.org/wiki/Expansion_audio
+// Famicom cartridges can mix their own sound chip into the console's audio through the
+// cartridge connector. Mappers with one expose it through `Mapper::expansion_audio`, the
+// bus clocks it alongside the APU and the mixer adds it on top of the 2A03 channels.
+
+/// Sound chips found on cartridges
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Fds,
+    Mmc5,
+    Namco163,
+    Sunsoft5b,
+}
+
+impl ExpansionChip {
+    pub const ALL: [ExpansionChip; 6] = [
+        ExpansionChip::Vrc6,
+        ExpansionChip::Vrc7,
+        ExpansionChip::Fds,
+        ExpansionChip::Mmc5,
+        ExpansionChip::Namco163,
+        ExpansionChip::Sunsoft5b,
+    ];
+
+    /// Rough loudness of the chip at full scale relative to the 2A03 at full scale. Boards
+    /// vary a lot (the N163 especially), so these are only starting points.
+    pub fn default_level(&self) -> f32 {
+        match self {
+            ExpansionChip::Vrc6 => 0.9,
+            ExpansionChip::Vrc7 => 1.0,
+            ExpansionChip::Fds => 1.2,
+            ExpansionChip::Mmc5 => 0.9,
+            ExpansionChip::Namco163 => 1.4,
+            ExpansionChip::Sunsoft5b => 1.1,
+        }
+    }
+}
+
+/// A cartridge sound chip
+pub trait ExpansionAudio {
+    fn chip(&self) -> ExpansionChip;
+    /// Advance by one CPU cycle
+    fn clock(&mut self);
+    /// Current output, 0.0 to 1.0 of the chip's full scale
+    fn output(&self) -> f32;
+}
@@ -0,0 +1,550 @@
+mod dmc;
+mod expansion;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+
+use crate::irq::IrqSource;
+use crate::logging;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use dmc::Dmc;
+pub use expansion::{ExpansionAudio, ExpansionChip};
+use log::trace;
+use noise::Noise;
+use pulse::{Pulse, PulseChannel};
+use triangle::Triangle;
+
+// https://www.nesdev.org/wiki/APU
+// $4000-$4003 pulse 1, $4004-$4007 pulse 2, $4008-$400B triangle, $400C-$400F noise,
+// $4010-$4013 DMC, $4015 channel enables, $4017 frame counter
+const STATUS_PULSE_1: u8 = 0b0000_0001;
+const STATUS_PULSE_2: u8 = 0b0000_0010;
+const STATUS_TRIANGLE: u8 = 0b0000_0100;
+const STATUS_NOISE: u8 = 0b0000_1000;
+const STATUS_DMC: u8 = 0b0001_0000;
+const STATUS_FRAME_IRQ: u8 = 0b0100_0000;
+const STATUS_DMC_IRQ: u8 = 0b1000_0000;
+
+// https://www.nesdev.org/wiki/APU_DMC#Memory_reader
+// CPU cycles lost to each DMC sample fetch. It's 1-4 on hardware depending on what the CPU
+// was doing, 4 is the common case (fetch landing on a CPU read cycle).
+pub const DMC_DMA_STALL_CYCLES: u32 = 4;
+
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+const FRAME_MODE_FIVE_STEP: u8 = 0b1000_0000;
+const FRAME_IRQ_INHIBIT: u8 = 0b0100_0000;
+
+// CPU cycles into the sequence at which each step clocks the channels, for the 4 step and
+// 5 step modes. The 5 step mode's fourth step does nothing, so it's left out.
+const FRAME_STEPS_NTSC: [[u32; 4]; 2] = [[7457, 14913, 22371, 29829], [7457, 14913, 22371, 37281]];
+const FRAME_STEPS_PAL: [[u32; 4]; 2] = [[8313, 16627, 24939, 33253], [8313, 16627, 24939, 41565]];
+
+// room for a frame of samples up front, so running frames doesn't grow the buffer: a Dendy
+// frame is the longest at about 35,500 CPU cycles
+const FRAME_SAMPLES: usize = 36_000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum FrameMode {
+    FourStep,
+    FiveStep,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "crate::serde_helpers::table",
+            deserialize_with = "deserialize_frame_steps"
+        )
+    )]
+    frame_steps: &'static [[u32; 4]; 2],
+    frame_mode: FrameMode,
+    frame_cycle: u32,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles until a $4017 write restarts the sequence
+    frame_reset_delay: Option<u8>,
+    // pulse timers run at half the CPU clock
+    odd_cycle: bool,
+
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_expansion_levels"))]
+    expansion_levels: [f32; ExpansionChip::ALL.len()],
+
+    // one mixed sample per CPU cycle, for the frontend to resample
+    #[cfg_attr(feature = "serde", serde(skip, default = "sample_buffer"))]
+    samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(Region::default())
+    }
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Apu {
+            pulse_1: Pulse::new(PulseChannel::One),
+            pulse_2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            frame_steps: Self::frame_steps(region),
+            frame_mode: FrameMode::FourStep,
+            frame_cycle: 0,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_reset_delay: None,
+            odd_cycle: false,
+            expansion_levels: default_expansion_levels(),
+            samples: sample_buffer(),
+        }
+    }
+
+    fn frame_steps(region: Region) -> &'static [[u32; 4]; 2] {
+        match region {
+            Region::Ntsc | Region::Dendy => &FRAME_STEPS_NTSC,
+            Region::Pal => &FRAME_STEPS_PAL,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.frame_steps = Self::frame_steps(region);
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+    }
+
+    // https://www.nesdev.org/wiki/CPU_power_up_state#After_reset
+    /// The reset button: every channel silenced, the triangle back to the start of its wave,
+    /// the DMC level cut to its low bit and the frame counter restarted in the mode it was in
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.triangle.reset_phase();
+        self.dmc.reset_level();
+        let mode = match self.frame_mode {
+            FrameMode::FourStep => 0,
+            FrameMode::FiveStep => FRAME_MODE_FIVE_STEP,
+        };
+        let inhibit = if self.frame_irq_inhibit {
+            FRAME_IRQ_INHIBIT
+        } else {
+            0
+        };
+        self.write_register(0x4017, mode | inhibit);
+        self.frame_irq = false;
+    }
+
+    /// CPU write to $4000-$4017
+    pub fn write_register(&mut self, address: u16, byte: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write_register(address & 0x3, byte),
+            0x4004..=0x4007 => self.pulse_2.write_register(address & 0x3, byte),
+            0x4008..=0x400B => self.triangle.write_register(address & 0x3, byte),
+            0x400C..=0x400F => self.noise.write_register(address & 0x3, byte),
+            0x4010..=0x4013 => self.dmc.write_register(address & 0x3, byte),
+            0x4015 => {
+                self.pulse_1.length.set_enabled(byte & STATUS_PULSE_1 != 0);
+                self.pulse_2.length.set_enabled(byte & STATUS_PULSE_2 != 0);
+                self.triangle
+                    .length
+                    .set_enabled(byte & STATUS_TRIANGLE != 0);
+                self.noise.length.set_enabled(byte & STATUS_NOISE != 0);
+                self.dmc.set_enabled(byte & STATUS_DMC != 0);
+            }
+            0x4017 => {
+                self.frame_mode = if byte & FRAME_MODE_FIVE_STEP != 0 {
+                    FrameMode::FiveStep
+                } else {
+                    FrameMode::FourStep
+                };
+                self.frame_irq_inhibit = byte & FRAME_IRQ_INHIBIT != 0;
+                trace!(
+                    target: logging::APU,
+                    "frame counter {:?}, IRQ {}",
+                    self.frame_mode,
+                    if self.frame_irq_inhibit { "off" } else { "on" }
+                );
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // the restart lands 3 cycles later on an APU cycle, 4 between them
+                self.frame_reset_delay = Some(if self.odd_cycle { 3 } else { 4 });
+            }
+            _ => {}
+        }
+    }
+
+    // https://www.nesdev.org/wiki/APU#Status_($4015)
+    /// CPU read of $4015, acknowledges the frame IRQ but not the DMC one
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    /// $4015 without the read side effect, for debuggers
+    pub fn peek_status(&self) -> u8 {
+        let flags = [
+            (self.pulse_1.length.is_active(), STATUS_PULSE_1),
+            (self.pulse_2.length.is_active(), STATUS_PULSE_2),
+            (self.triangle.length.is_active(), STATUS_TRIANGLE),
+            (self.noise.length.is_active(), STATUS_NOISE),
+            (self.dmc.is_active(), STATUS_DMC),
+            (self.frame_irq, STATUS_FRAME_IRQ),
+            (self.dmc.irq_pending(), STATUS_DMC_IRQ),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |status, (_, bit)| status | bit)
+    }
+
+    /// Volume of a cartridge sound chip relative to the 2A03
+    pub fn expansion_level(&self, chip: ExpansionChip) -> f32 {
+        self.expansion_levels[chip as usize]
+    }
+
+    pub fn set_expansion_level(&mut self, chip: ExpansionChip, level: f32) {
+        self.expansion_levels[chip as usize] = level;
+    }
+
+    /// Advance by one CPU cycle, along with the cartridge's sound chip if it has one
+    pub fn tick(&mut self, expansion: Option<&mut dyn ExpansionAudio>) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.clock_frame_counter();
+
+        let mut sample = self.output();
+        if let Some(expansion) = expansion {
+            expansion.clock();
+            sample += expansion.output() * self.expansion_level(expansion.chip());
+        }
+        self.samples.push(sample);
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset_delay {
+            if delay > 1 {
+                self.frame_reset_delay = Some(delay - 1);
+            } else {
+                self.frame_reset_delay = None;
+                self.frame_cycle = 0;
+                // 5 step mode clocks everything straight away
+                if self.frame_mode == FrameMode::FiveStep {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+        let steps = &self.frame_steps[self.frame_mode as usize];
+        let last = steps[steps.len() - 1];
+        if let Some(step) = steps.iter().position(|&c| c == self.frame_cycle) {
+            self.clock_quarter_frame();
+            // steps 2 and 4 are half frames too
+            if step % 2 == 1 {
+                self.clock_half_frame();
+            }
+        }
+        // the 4 step IRQ flag gets set over the 3 cycles around the last step
+        if self.frame_mode == FrameMode::FourStep
+            && !self.frame_irq_inhibit
+            && (last - 1..=last + 1).contains(&self.frame_cycle)
+        {
+            self.frame_irq = true;
+        }
+        if self.frame_cycle == last + 1 {
+            self.frame_cycle = 0;
+        }
+    }
+
+    /// Address the DMC wants read from CPU memory, the bus answers with `dmc_dma_complete`
+    /// and stalls the CPU
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, byte: u8) {
+        self.dmc.dma_complete(byte);
+    }
+
+    /// Level of the APU's IRQ line
+    pub fn irq_pending(&self) -> bool {
+        !self.irq_sources().is_empty()
+    }
+
+    /// Which of the frame counter and the DMC are asserting IRQ
+    pub fn irq_sources(&self) -> IrqSource {
+        let mut sources = IrqSource::NONE;
+        if self.frame_irq {
+            sources = sources | IrqSource::FRAME_COUNTER;
+        }
+        if self.dmc.irq_pending() {
+            sources = sources | IrqSource::DMC;
+        }
+        sources
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.clock_quarter_frame();
+        self.pulse_2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_half_frame();
+        self.pulse_2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    // https://www.nesdev.org/wiki/APU_Mixer
+    /// Mixed output of all channels, 0.0 to ~1.0
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let triangle = self.triangle.output() as f32;
+        let noise = self.noise.output() as f32;
+        let dmc = self.dmc.output() as f32;
+        let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out
+    }
+
+    /// Samples produced since the last `clear_samples`, one per CPU cycle
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+}
+
+fn default_expansion_levels() -> [f32; ExpansionChip::ALL.len()] {
+    ExpansionChip::ALL.map(|chip| chip.default_level())
+}
+
+fn sample_buffer() -> Vec<f32> {
+    Vec::with_capacity(FRAME_SAMPLES)
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_frame_steps<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<&'static [[u32; 4]; 2], D::Error> {
+    crate::serde_helpers::one_of(
+        deserializer,
+        &[&FRAME_STEPS_NTSC, &FRAME_STEPS_PAL],
+        "frame counter step table",
+    )
+}
+
+// Expansion levels are a setting rather than state and the samples are the frontend's, so
+// neither goes into save states (or through serde)
+impl SaveState for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.bool(self.frame_mode == FrameMode::FiveStep);
+        state.u32(self.frame_cycle);
+        state.bool(self.frame_irq_inhibit);
+        state.bool(self.frame_irq);
+        state.u8(self.frame_reset_delay.unwrap_or(0));
+        state.bool(self.odd_cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_mode = if state.bool()? {
+            FrameMode::FiveStep
+        } else {
+            FrameMode::FourStep
+        };
+        self.frame_cycle = state.u32()?;
+        self.frame_irq_inhibit = state.bool()?;
+        self.frame_irq = state.bool()?;
+        // a delay of 0 never happens, it counts down to 1 and then clears
+        self.frame_reset_delay = Some(state.u8()?).filter(|&delay| delay > 0);
+        self.odd_cycle = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixer_levels() {
+        let mut apu = Apu::default();
+        // the idle triangle still contributes a DC level
+        let silence = apu.output();
+
+        apu.write_register(0x4015, STATUS_PULSE_1 | STATUS_PULSE_2);
+        for base in [0x4000, 0x4004] {
+            // duty 3 is high on step 0, constant volume 15
+            apu.write_register(base, 0b1101_1111);
+            apu.write_register(base + 2, 0xFF);
+            apu.write_register(base + 3, 0);
+        }
+        assert!((apu.output() - silence - 0.2585).abs() < 0.0001);
+    }
+
+    #[test]
+    fn reset_silences_the_channels() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, FRAME_MODE_FIVE_STEP);
+        apu.write_register(0x4015, STATUS_PULSE_1 | STATUS_NOISE);
+        apu.write_register(0x4003, 1 << 3);
+        apu.write_register(0x400F, 1 << 3);
+        assert_eq!(apu.peek_status(), STATUS_PULSE_1 | STATUS_NOISE);
+        apu.reset();
+        assert_eq!(apu.peek_status(), 0);
+        assert_eq!(apu.frame_mode, FrameMode::FiveStep);
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_length_counters() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, STATUS_NOISE);
+        // length index 3 loads 2
+        apu.write_register(0x400F, 3 << 3);
+        (0..FRAME_STEPS_NTSC[0][1]).for_each(|_| apu.tick(None));
+        assert!(apu.noise.length.is_active());
+        (0..FRAME_STEPS_NTSC[0][3] - FRAME_STEPS_NTSC[0][1]).for_each(|_| apu.tick(None));
+        assert!(!apu.noise.length.is_active());
+        assert_eq!(apu.samples().len(), FRAME_STEPS_NTSC[0][3] as usize);
+
+        apu.write_register(0x4015, 0);
+        apu.write_register(0x400F, 3 << 3);
+        assert!(!apu.noise.length.is_active());
+    }
+
+    #[test]
+    fn status_reports_channels_and_acknowledges_frame_irq() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, STATUS_PULSE_2 | STATUS_NOISE | STATUS_DMC);
+        apu.write_register(0x4007, 0);
+        apu.write_register(0x400F, 0);
+        // triangle is disabled, so its length load is ignored
+        apu.write_register(0x400B, 0);
+        assert_eq!(
+            apu.read_status(),
+            STATUS_PULSE_2 | STATUS_NOISE | STATUS_DMC
+        );
+
+        apu.frame_irq = true;
+        assert_eq!(apu.peek_status() & STATUS_FRAME_IRQ, STATUS_FRAME_IRQ);
+        assert_eq!(apu.read_status() & STATUS_FRAME_IRQ, STATUS_FRAME_IRQ);
+        assert_eq!(apu.read_status() & STATUS_FRAME_IRQ, 0);
+
+        // disabling the DMC stops the sample
+        apu.write_register(0x4015, STATUS_PULSE_2);
+        assert_eq!(apu.read_status(), STATUS_PULSE_2);
+    }
+
+    struct Square {
+        high: bool,
+    }
+
+    impl ExpansionAudio for Square {
+        fn chip(&self) -> ExpansionChip {
+            ExpansionChip::Vrc6
+        }
+        fn clock(&mut self) {
+            self.high = !self.high;
+        }
+        fn output(&self) -> f32 {
+            if self.high {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+
+    #[test]
+    fn expansion_audio_is_mixed_at_its_level() {
+        let mut apu = Apu::default();
+        let silence = apu.output();
+        let mut square = Square { high: false };
+        apu.set_expansion_level(ExpansionChip::Vrc6, 0.5);
+        apu.tick(Some(&mut square));
+        apu.tick(Some(&mut square));
+        apu.tick(None);
+        let samples: Vec<f32> = apu.samples().iter().map(|s| s - silence).collect();
+        assert_eq!(samples, [0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn four_step_mode_raises_frame_irq() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4017, 0);
+        // written between APU cycles, so the sequence restarts 4 cycles later
+        (0..4 + 29827).for_each(|_| apu.tick(None));
+        assert!(!apu.irq_pending());
+        apu.tick(None);
+        assert!(apu.irq_pending());
+
+        // setting the inhibit flag acknowledges it
+        apu.write_register(0x4017, FRAME_IRQ_INHIBIT);
+        assert!(!apu.irq_pending());
+        (0..2 * 29830).for_each(|_| apu.tick(None));
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn five_step_mode_clocks_immediately_without_irq() {
+        let mut apu = Apu::default();
+        apu.write_register(0x4015, STATUS_NOISE);
+        // length index 3 loads 2
+        apu.write_register(0x400F, 3 << 3);
+        apu.write_register(0x4017, FRAME_MODE_FIVE_STEP);
+        (0..4).for_each(|_| apu.tick(None));
+        // one half frame from the write, the second at step 2
+        assert!(apu.noise.length.is_active());
+        (0..FRAME_STEPS_NTSC[1][1]).for_each(|_| apu.tick(None));
+        assert!(!apu.noise.length.is_active());
+
+        (0..2 * 37282).for_each(|_| apu.tick(None));
+        assert!(!apu.irq_pending());
+    }
+}
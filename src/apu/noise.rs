@@ -0,0 +1,177 @@
+use crate::apu::units::{Envelope, LengthCounter};
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// https://www.nesdev.org/wiki/APU_Noise
+// timer periods in CPU cycles
+const PERIODS_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PERIODS_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Noise {
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "crate::serde_helpers::table",
+            deserialize_with = "deserialize_periods"
+        )
+    )]
+    periods: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    // 15 bit linear feedback shift register
+    shift: u16,
+    // short mode taps bit 6 instead of bit 1, giving a 93 step metallic loop
+    short_mode: bool,
+    envelope: Envelope,
+    pub(crate) length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        let periods = Self::periods(region);
+        Noise {
+            periods,
+            timer_period: periods[0],
+            timer: 0,
+            shift: 1,
+            short_mode: false,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    fn periods(region: Region) -> &'static [u16; 16] {
+        match region {
+            Region::Ntsc => &PERIODS_NTSC,
+            Region::Pal | Region::Dendy => &PERIODS_PAL,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        let index = self.periods.iter().position(|&p| p == self.timer_period);
+        self.periods = Self::periods(region);
+        self.timer_period = self.periods[index.unwrap_or(0)];
+    }
+
+    /// Register 0-3 of the channel ($400C-$400F, $400D is unused)
+    pub fn write_register(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => {
+                self.length.set_halt(byte & 0b0010_0000 != 0);
+                self.envelope.write(byte);
+            }
+            2 => {
+                self.short_mode = byte & 0x80 != 0;
+                self.timer_period = self.periods[(byte & 0x0F) as usize];
+            }
+            3 => {
+                self.length.load(byte >> 3);
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// Current output level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_periods<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<&'static [u16; 16], D::Error> {
+    crate::serde_helpers::one_of(
+        deserializer,
+        &[&PERIODS_NTSC, &PERIODS_PAL],
+        "noise period table",
+    )
+}
+
+// the period table comes from the region, which the console restores before the channels
+impl SaveState for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        state.u16(self.shift);
+        state.bool(self.short_mode);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.shift = state.u16()?;
+        self.short_mode = state.bool()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new(Region::Ntsc);
+        noise.short_mode = short_mode;
+        noise.timer_period = 1;
+        let start = noise.shift;
+        (1..=32767)
+            .find(|_| {
+                noise.clock_timer();
+                noise.shift == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lfsr_periods() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+
+    #[test]
+    fn output_follows_shift_register() {
+        let mut noise = Noise::new(Region::Ntsc);
+        noise.length.set_enabled(true);
+        noise.write_register(0, 0b0001_1001);
+        noise.write_register(3, 0);
+        // bit 0 of the initial register is set
+        assert_eq!(noise.output(), 0);
+        noise.shift = 0b10;
+        assert_eq!(noise.output(), 9);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::apu::units::{Envelope, LengthCounter};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// https://www.nesdev.org/wiki/APU_Pulse
+#[rustfmt::skip]
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+/// Which of the two pulse channels, they differ in how the sweep negates
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    sweep: Sweep,
+    pub(crate) length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            channel,
+            duty: 0,
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Register 0-3 of the channel ($4000-$4003 or $4004-$4007)
+    pub fn write_register(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => {
+                self.duty = byte >> 6;
+                self.length.set_halt(byte & 0b0010_0000 != 0);
+                self.envelope.write(byte);
+            }
+            1 => {
+                self.sweep = Sweep {
+                    enabled: byte & 0x80 != 0,
+                    period: (byte >> 4) & 0x7,
+                    negate: byte & 0x08 != 0,
+                    shift: byte & 0x7,
+                    divider: self.sweep.divider,
+                    reload: true,
+                };
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | byte as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((byte as u16 & 0x7) << 8);
+                self.length.load(byte >> 3);
+                // restarts the waveform and the envelope
+                self.step = 0;
+                self.envelope.restart();
+            }
+            _ => {}
+        }
+    }
+
+    /// Clocked every APU cycle (two CPU cycles)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+
+        // https://www.nesdev.org/wiki/APU_Sweep
+        let sweep = self.sweep;
+        if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !self.sweep_muted() {
+            self.timer_period = self.sweep_target();
+        }
+        let sweep = &mut self.sweep;
+        if sweep.divider == 0 || sweep.reload {
+            sweep.divider = sweep.period;
+            sweep.reload = false;
+        } else {
+            sweep.divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if !self.sweep.negate {
+            return self.timer_period + change;
+        }
+        // pulse 1 negates with ones' complement, so it subtracts one more
+        match self.channel {
+            PulseChannel::One => self.timer_period.saturating_sub(change + 1),
+            PulseChannel::Two => self.timer_period.saturating_sub(change),
+        }
+    }
+
+    // the sweep unit mutes the channel even when it's disabled
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    /// Current output level, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.sweep_muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.step);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        self.envelope.save_state(state);
+        let sweep = &self.sweep;
+        state.bool(sweep.enabled);
+        state.u8(sweep.period);
+        state.bool(sweep.negate);
+        state.u8(sweep.shift);
+        state.u8(sweep.divider);
+        state.bool(sweep.reload);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.u8()? & 0x3;
+        self.step = state.u8()? & 0x7;
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.sweep = Sweep {
+            enabled: state.bool()?,
+            period: state.u8()?,
+            negate: state.bool()?,
+            shift: state.u8()?,
+            divider: state.u8()?,
+            reload: state.bool()?,
+        };
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(channel: PulseChannel) -> Pulse {
+        let mut pulse = Pulse::new(channel);
+        pulse.length.set_enabled(true);
+        // 50% duty, constant volume 10, period 100
+        pulse.write_register(0, 0b1001_1010);
+        pulse.write_register(2, 100);
+        pulse.write_register(3, 0b0000_1000);
+        pulse
+    }
+
+    #[test]
+    fn duty_cycle_waveform() {
+        let mut pulse = playing(PulseChannel::One);
+        let mut wave = Vec::new();
+        for _ in 0..8 {
+            wave.push(pulse.output());
+            (0..=100).for_each(|_| pulse.clock_timer());
+        }
+        assert_eq!(wave, [0, 10, 10, 10, 10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn short_periods_are_muted() {
+        let mut pulse = playing(PulseChannel::One);
+        pulse.write_register(2, 7);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn sweep_negation_differs_per_channel() {
+        for (channel, expected) in [(PulseChannel::One, 100 - 50 - 1), (PulseChannel::Two, 50)] {
+            let mut pulse = playing(channel);
+            // enabled, period 0, negate, shift 1
+            pulse.write_register(1, 0b1000_1001);
+            pulse.clock_half_frame();
+            assert_eq!(pulse.timer_period, expected);
+        }
+    }
+}
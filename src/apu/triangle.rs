@@ -0,0 +1,139 @@
+use crate::apu::units::LengthCounter;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// https://www.nesdev.org/wiki/APU_Triangle
+#[rustfmt::skip]
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Triangle {
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    // also the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    pub(crate) length: LengthCounter,
+}
+
+impl Triangle {
+    /// Back to the start of the waveform, as the reset button does
+    pub fn reset_phase(&mut self) {
+        self.step = 0;
+    }
+
+    /// Register 0-3 of the channel ($4008-$400B, $4009 is unused)
+    pub fn write_register(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => {
+                self.control = byte & 0x80 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = byte & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | byte as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((byte as u16 & 0x7) << 8);
+                self.length.load(byte >> 3);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // the sequencer only runs while both counters are non zero, so a silenced
+            // triangle holds its level instead of popping back to 0
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// Current output level, 0-15
+    pub fn output(&self) -> u8 {
+        // periods below 2 are ultrasonic, games use them to silence the channel and
+        // outputting the midpoint avoids the resulting aliasing
+        if self.timer_period < 2 {
+            7
+        } else {
+            SEQUENCE[self.step as usize]
+        }
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.step);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.u8(self.linear_counter);
+        state.bool(self.linear_reload);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.step = state.u8()? % 32;
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_counter = state.u8()?;
+        self.linear_reload = state.bool()?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_only_while_counters_run() {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        triangle.write_register(0, 0x10);
+        triangle.write_register(2, 2);
+        triangle.write_register(3, 0b0000_1000);
+        assert_eq!(triangle.output(), 15);
+
+        // the linear counter is loaded on the next quarter frame
+        (0..3).for_each(|_| triangle.clock_timer());
+        assert_eq!(triangle.output(), 15);
+        triangle.clock_quarter_frame();
+        (0..3).for_each(|_| triangle.clock_timer());
+        assert_eq!(triangle.output(), 14);
+
+        // runs out after 16 more quarter frames and holds its level
+        (0..16).for_each(|_| triangle.clock_quarter_frame());
+        (0..9).for_each(|_| triangle.clock_timer());
+        assert_eq!(triangle.output(), 14);
+    }
+}
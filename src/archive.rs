@@ -0,0 +1,175 @@
+use std::io;
+
+// Roms are usually passed around zipped or gzipped. Pulling them out is left to the flate2
+// and zip crates behind the `archives` feature, without it archives are reported as
+// unsupported.
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_LOCAL_HEADER: [u8; 4] = *b"PK\x03\x04";
+const ZIP_END_OF_DIRECTORY: [u8; 4] = *b"PK\x05\x06";
+
+/// What kind of container `bytes` looks like
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Archive {
+    Zip,
+    Gzip,
+    /// Not an archive, use the bytes as they are
+    None,
+}
+
+impl Archive {
+    pub fn detect(bytes: &[u8]) -> Archive {
+        if bytes.starts_with(&ZIP_LOCAL_HEADER) || bytes.starts_with(&ZIP_END_OF_DIRECTORY) {
+            Archive::Zip
+        } else if bytes.starts_with(&GZIP_MAGIC) {
+            Archive::Gzip
+        } else {
+            Archive::None
+        }
+    }
+}
+
+// extensions of the files a zip is searched for
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".fds"];
+
+/// Pull the rom image out of a zip or gzip archive, other data is returned unchanged.
+/// Zips use their first .nes, .unf or .fds entry, None when there isn't one.
+pub fn extract_rom(bytes: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    match Archive::detect(&bytes) {
+        Archive::Zip => unzip(&bytes, |name| {
+            let name = name.to_ascii_lowercase();
+            ROM_EXTENSIONS
+                .iter()
+                .any(|extension| name.ends_with(extension))
+        }),
+        Archive::Gzip => gunzip(&bytes).map(Some),
+        Archive::None => Ok(Some(bytes)),
+    }
+}
+
+#[cfg(not(feature = "archives"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "built without the archives feature, extract the rom first",
+    )
+}
+
+/// Decompress the first member of a gzip file
+#[cfg(feature = "archives")]
+pub fn gunzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    // the decoder checks the size and CRC in the trailer once it reaches the end
+    let mut data = Vec::new();
+    flate2::read::GzDecoder::new(bytes).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(not(feature = "archives"))]
+pub fn gunzip(_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// Decompress the first zip entry whose name `wanted` accepts
+#[cfg(feature = "archives")]
+pub fn unzip(bytes: &[u8], wanted: impl Fn(&str) -> bool) -> io::Result<Option<Vec<u8>>> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes))?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if !entry.is_file() || !wanted(entry.name()) {
+            continue;
+        }
+        // reading to the end checks the entry's CRC
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        return Ok(Some(data));
+    }
+    Ok(None)
+}
+
+#[cfg(not(feature = "archives"))]
+pub fn unzip(_bytes: &[u8], _wanted: impl Fn(&str) -> bool) -> io::Result<Option<Vec<u8>>> {
+    Err(unsupported())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "archives")]
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn test_rom() -> Vec<u8> {
+        let mut rom = b"NES\x1A\x01\x00".to_vec();
+        rom.resize(16, 0);
+        rom.resize(16 + 16384, 0xEA);
+        rom
+    }
+
+    #[test]
+    #[cfg(feature = "archives")]
+    fn gzip() {
+        let gz = hex(
+            "1f8b0800000000000203edc1210100300800b053e37d6e6fe85f8014482260b6fd9737ce28000000\
+             000000000000000000000000605d033cc5731b10400000",
+        );
+        assert_eq!(Archive::detect(&gz), Archive::Gzip);
+        assert_eq!(extract_rom(gz.clone()).unwrap().unwrap(), test_rom());
+
+        let mut bad_crc = gz;
+        let crc = bad_crc.len() - 8;
+        bad_crc[crc] ^= 1;
+        assert!(gunzip(&bad_crc).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "archives")]
+    fn zip_picks_first_nes_entry() {
+        // readme.txt stored, then Game.NES deflated
+        let zip = hex(
+            "504b030414000000000000002100ac2a93d802000000020000000a000000726561646d652e747874\
+             6869504b0304140000000800000021003cc5731b2d000000104000000800000047616d652e4e4553\
+             edc1210100300800b053e37d6e6fe85f8014482260b6fd9737ce2800000000000000000000000000\
+             0000605d03504b0102140314000000000000002100ac2a93d802000000020000000a000000000000\
+             0000000000800100000000726561646d652e747874504b01021403140000000800000021003cc573\
+             1b2d0000001040000008000000000000000000000080012a00000047616d652e4e4553504b050600\
+             000000020002006e0000007d0000000000",
+        );
+        assert_eq!(Archive::detect(&zip), Archive::Zip);
+        assert_eq!(extract_rom(zip.clone()).unwrap().unwrap(), test_rom());
+        assert_eq!(
+            unzip(&zip, |name| name == "readme.txt").unwrap().unwrap(),
+            b"hi"
+        );
+        assert_eq!(unzip(&zip, |name| name.ends_with(".fds")).unwrap(), None);
+    }
+
+    #[test]
+    fn plain_roms_pass_through() {
+        assert_eq!(extract_rom(test_rom()).unwrap().unwrap(), test_rom());
+    }
+
+    #[test]
+    #[cfg(feature = "archives")]
+    fn truncated_archives_are_errors() {
+        let zip = hex("504b0304140000000800000021003cc5731b2d000000104000000800000047616d65");
+        assert!(extract_rom(zip).is_err());
+        let gz = hex("1f8b0800000000000203edc1210100300800b053");
+        assert!(extract_rom(gz).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "archives"))]
+    fn archives_need_the_feature() {
+        let error = extract_rom(GZIP_MAGIC.to_vec()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,523 @@
+use crate::cpu::{NesCpu, Processor};
+use crate::instructions::AddressingMode;
+use std::collections::HashMap;
+use std::fmt;
+
+// A small two-pass 6502 assembler, for writing test programs as assembly rather than bytes.
+// The first pass gives every label an address, the second writes the bytes out. The syntax is
+// the usual one:
+//
+//   .org $C000          ; where the following code goes, $8000 when not given
+//   reset:  LDX #$FF
+//           TXS
+//   loop:   LDA table,X
+//           BNE loop
+//           JMP (vector)
+//   table:  .byte 1, 2, $03, %100
+//   vector: .word reset, <reset, >reset
+//
+// Mnemonics are any the disassembler prints, so the unofficial ones too. Operands are a number
+// ($hex, %binary or decimal) or a label, plus or minus more of them, and `<` or `>` in front
+// takes the low or high byte. Zero page addressing is used when the operand is known to fit by
+// the time the first pass reaches it, so labels defined further down are always absolute.
+// `.org` can skip ahead, the gap is filled with zeros, but not go back.
+
+/// Where the program starts without an `.org`, where `NesCpu::load_bytes` puts it
+pub const DEFAULT_ORIGIN: u16 = 0x8000;
+// NOP has unofficial copies at lower opcodes, the official one is tried first
+const PREFERRED_OPCODES: [u8; 1] = [0xEA];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// Counting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Whole,
+    Low,
+    High,
+}
+
+/// Numbers and labels added together
+#[derive(Debug, Clone)]
+struct Expr {
+    part: Part,
+    terms: Vec<(bool, String)>,
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Expr, String> {
+        let text = text.trim();
+        let (part, text) = match text.as_bytes().first() {
+            Some(b'<') => (Part::Low, &text[1..]),
+            Some(b'>') => (Part::High, &text[1..]),
+            _ => (Part::Whole, text),
+        };
+        let mut terms = Vec::new();
+        let mut negative = false;
+        let mut term = String::new();
+        for c in text.chars().chain(std::iter::once('+')) {
+            match c {
+                '+' | '-' => {
+                    let name = term.trim();
+                    if name.is_empty() {
+                        return Err(format!("missing value in `{}`", text));
+                    }
+                    terms.push((negative, name.to_string()));
+                    term.clear();
+                    negative = c == '-';
+                }
+                _ => term.push(c),
+            }
+        }
+        Ok(Expr { part, terms })
+    }
+
+    /// Whether it's a byte whatever the labels turn out to be
+    fn is_byte(&self) -> bool {
+        self.part != Part::Whole
+    }
+
+    /// The value, None while a label in it isn't known yet
+    fn value(&self, labels: &HashMap<String, u16>) -> Result<Option<u16>, String> {
+        let mut total = 0u16;
+        for (negative, term) in &self.terms {
+            let value = match number(term) {
+                Some(value) => value?,
+                None if is_label(term) => match labels.get(term) {
+                    Some(&value) => value,
+                    None => return Ok(None),
+                },
+                None => return Err(format!("`{}` isn't a number or a label", term)),
+            };
+            total = if *negative {
+                total.wrapping_sub(value)
+            } else {
+                total.wrapping_add(value)
+            };
+        }
+        Ok(Some(match self.part {
+            Part::Whole => total,
+            Part::Low => total & 0xFF,
+            Part::High => total >> 8,
+        }))
+    }
+}
+
+/// A number in any of the bases, None when `text` isn't trying to be one
+fn number(text: &str) -> Option<Result<u16, String>> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix('%') {
+        (binary, 2)
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        (text, 10)
+    } else {
+        return None;
+    };
+    Some(u16::from_str_radix(digits, radix).map_err(|_| format!("bad number `{}`", text)))
+}
+
+fn is_label(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// An operand as written, before zero page or absolute is picked
+#[derive(Debug, Clone)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    IndexedX(Expr),
+    IndexedY(Expr),
+    Indirect(Expr),
+    XIndirect(Expr),
+    YIndirect(Expr),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Operand, String> {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+        Ok(if text.is_empty() {
+            Operand::None
+        } else if upper == "A" {
+            Operand::Accumulator
+        } else if let Some(value) = text.strip_prefix('#') {
+            Operand::Immediate(Expr::parse(value)?)
+        } else if upper.starts_with('(') && upper.ends_with(",X)") {
+            Operand::XIndirect(Expr::parse(&text[1..text.len() - 3])?)
+        } else if upper.starts_with('(') && upper.ends_with("),Y") {
+            Operand::YIndirect(Expr::parse(&text[1..text.len() - 3])?)
+        } else if upper.starts_with('(') && upper.ends_with(')') {
+            Operand::Indirect(Expr::parse(&text[1..text.len() - 1])?)
+        } else if upper.ends_with(",X") {
+            Operand::IndexedX(Expr::parse(&text[..text.len() - 2])?)
+        } else if upper.ends_with(",Y") {
+            Operand::IndexedY(Expr::parse(&text[..text.len() - 2])?)
+        } else {
+            Operand::Direct(Expr::parse(text)?)
+        })
+    }
+
+    fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(expr)
+            | Operand::Direct(expr)
+            | Operand::IndexedX(expr)
+            | Operand::IndexedY(expr)
+            | Operand::Indirect(expr)
+            | Operand::XIndirect(expr)
+            | Operand::YIndirect(expr) => Some(expr),
+        }
+    }
+
+    /// Modes it could be, the smaller first. A short one is only offered when `short` says
+    /// the value fits in a byte.
+    fn modes(&self, short: bool) -> Vec<AddressingMode> {
+        use AddressingMode::*;
+        let sized = |zero_page, absolute| {
+            if short {
+                vec![zero_page, absolute]
+            } else {
+                vec![absolute]
+            }
+        };
+        match self {
+            Operand::None => vec![Implied, Accumulator],
+            Operand::Accumulator => vec![Accumulator],
+            Operand::Immediate(_) => vec![Immediate],
+            Operand::Direct(_) => {
+                let mut modes = vec![Relative];
+                modes.extend(sized(ZeroPage, Absolute));
+                modes
+            }
+            Operand::IndexedX(_) => sized(ZeroPageX, AbsoluteX),
+            Operand::IndexedY(_) => sized(ZeroPageY, AbsoluteY),
+            Operand::Indirect(_) => vec![Indirect],
+            Operand::XIndirect(_) => vec![XIndirect],
+            Operand::YIndirect(_) => vec![YIndirect],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Org(Expr),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Instruction(String, Operand),
+}
+
+/// Opcode of `mnemonic` in `mode`, if there is one
+fn opcode(mnemonic: &str, mode: &AddressingMode) -> Option<u8> {
+    PREFERRED_OPCODES
+        .iter()
+        .copied()
+        .chain(0..=0xFF)
+        .find(|&opcode| {
+            let (instruction, decoded) = NesCpu::decode_instruction(opcode);
+            instruction.asm() == mnemonic && decoded == *mode
+        })
+}
+
+fn list(text: &str) -> Result<Vec<Expr>, String> {
+    text.split(',').map(Expr::parse).collect()
+}
+
+/// Labels defined on the line, and what's left of it
+fn parse_line(text: &str) -> Result<(Vec<String>, Option<Statement>), String> {
+    let mut text = text.split(';').next().unwrap_or_default().trim();
+    let mut labels = Vec::new();
+    while let Some((label, rest)) = text.split_once(':') {
+        let label = label.trim();
+        if !is_label(label) {
+            return Err(format!("bad label `{}`", label));
+        }
+        labels.push(label.to_string());
+        text = rest.trim();
+    }
+    if text.is_empty() {
+        return Ok((labels, None));
+    }
+    let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(Expr::parse(rest)?),
+        ".byte" | ".db" => Statement::Bytes(list(rest)?),
+        ".word" | ".dw" => Statement::Words(list(rest)?),
+        directive if directive.starts_with('.') => {
+            return Err(format!("unknown directive `{}`", word))
+        }
+        _ => Statement::Instruction(word.to_ascii_uppercase(), Operand::parse(rest)?),
+    };
+    Ok((labels, Some(statement)))
+}
+
+/// An instruction's mode and opcode as picked by the first pass
+fn pick(
+    mnemonic: &str,
+    operand: &Operand,
+    labels: &HashMap<String, u16>,
+) -> Result<(AddressingMode, u8), String> {
+    let short = match operand.expr() {
+        Some(expr) => expr.is_byte() || matches!(expr.value(labels)?, Some(v) if v <= 0xFF),
+        None => false,
+    };
+    operand
+        .modes(short)
+        .into_iter()
+        .find_map(|mode| opcode(mnemonic, &mode).map(|opcode| (mode, opcode)))
+        .ok_or_else(|| {
+            if opcode_exists(mnemonic) {
+                format!("{} can't take that operand", mnemonic)
+            } else {
+                format!("unknown instruction `{}`", mnemonic)
+            }
+        })
+}
+
+fn opcode_exists(mnemonic: &str) -> bool {
+    (0..=0xFF).any(|opcode| NesCpu::decode_instruction(opcode).0.asm() == mnemonic)
+}
+
+/// Assemble `source` into the bytes from the first `.org` on, or from `DEFAULT_ORIGIN`
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    // first pass: parse, place every statement and learn the labels
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut address: Option<u16> = None;
+    for (number, text) in source.lines().enumerate() {
+        let error = |message| AsmError {
+            line: number + 1,
+            message,
+        };
+        let (names, statement) = parse_line(text).map_err(error)?;
+        for name in names {
+            let here = address.unwrap_or(DEFAULT_ORIGIN);
+            if labels.insert(name.clone(), here).is_some() {
+                return Err(error(format!("`{}` is defined twice", name)));
+            }
+        }
+        let Some(statement) = statement else {
+            continue;
+        };
+        let here = address.unwrap_or(DEFAULT_ORIGIN);
+        let (here, size, picked) = match &statement {
+            Statement::Org(expr) => {
+                let org = expr
+                    .value(&labels)
+                    .map_err(error)?
+                    .ok_or_else(|| error(".org can't use labels defined after it".to_string()))?;
+                if address.is_some_and(|address| org < address) {
+                    return Err(error(format!(".org ${:04X} goes backwards", org)));
+                }
+                (org, 0, None)
+            }
+            Statement::Bytes(values) => (here, values.len(), None),
+            Statement::Words(values) => (here, values.len() * 2, None),
+            Statement::Instruction(mnemonic, operand) => {
+                let (mode, opcode) = pick(mnemonic, operand, &labels).map_err(error)?;
+                (here, mode.get_increment() as usize, Some((mode, opcode)))
+            }
+        };
+        address = Some(here.wrapping_add(size as u16));
+        statements.push((number + 1, here, statement, picked));
+    }
+
+    // second pass: every label is known, write the bytes
+    let origin = statements
+        .first()
+        .map_or(DEFAULT_ORIGIN, |&(_, here, _, _)| here);
+    let mut bytes = Vec::new();
+    for (line, here, statement, picked) in statements {
+        let error = |message| AsmError { line, message };
+        let value = |expr: &Expr| {
+            expr.value(&labels)?
+                .ok_or_else(|| format!("unknown label in `{}`", describe(expr)))
+        };
+        let byte = |expr: &Expr| {
+            let value = value(expr)?;
+            u8::try_from(value).map_err(|_| format!("${:04X} doesn't fit in a byte", value))
+        };
+        let offset = here.wrapping_sub(origin) as usize;
+        if offset > bytes.len() {
+            bytes.resize(offset, 0);
+        }
+        match statement {
+            Statement::Org(_) => {}
+            Statement::Bytes(values) => {
+                for expr in &values {
+                    bytes.push(byte(expr).map_err(error)?);
+                }
+            }
+            Statement::Words(values) => {
+                for expr in &values {
+                    bytes.extend(value(expr).map_err(error)?.to_le_bytes());
+                }
+            }
+            Statement::Instruction(_, operand) => {
+                let (mode, opcode) = picked.expect("the first pass picks every instruction's mode");
+                bytes.push(opcode);
+                let Some(expr) = operand.expr() else {
+                    continue;
+                };
+                match mode.get_increment() {
+                    2 if mode == AddressingMode::Relative => {
+                        let target = value(expr).map_err(error)?;
+                        let distance = target.wrapping_sub(here.wrapping_add(2)) as i16;
+                        let distance = i8::try_from(distance)
+                            .map_err(|_| error(format!("branch to ${:04X} is too far", target)))?;
+                        bytes.push(distance as u8);
+                    }
+                    2 => bytes.push(byte(expr).map_err(error)?),
+                    _ => bytes.extend(value(expr).map_err(error)?.to_le_bytes()),
+                }
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn describe(expr: &Expr) -> String {
+    let prefix = match expr.part {
+        Part::Whole => "",
+        Part::Low => "<",
+        Part::High => ">",
+    };
+    let mut text = prefix.to_string();
+    for (i, (negative, term)) in expr.terms.iter().enumerate() {
+        if *negative {
+            text.push('-');
+        } else if i > 0 {
+            text.push('+');
+        }
+        text.push_str(term);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    fn texts(code: &[u8], origin: u16) -> Vec<String> {
+        disassemble(code, origin)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn every_addressing_mode() {
+        let source = "
+            LDA #$10
+            LDA $20
+            LDA $20,X
+            LDX $20,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $1234,Y
+            LDA ($20,X)
+            LDA ($20),Y
+            JMP ($0200)
+            ASL A
+            ASL
+            NOP
+            RTS
+        ";
+        let code = assemble(source).unwrap();
+        assert_eq!(
+            texts(&code, DEFAULT_ORIGIN),
+            [
+                "LDA #$10",
+                "LDA $20",
+                "LDA $20,X",
+                "LDX $20,Y",
+                "LDA $1234",
+                "LDA $1234,X",
+                "LDA $1234,Y",
+                "LDA ($20,X)",
+                "LDA ($20),Y",
+                "JMP ($0200)",
+                "ASL A",
+                "ASL A",
+                "NOP",
+                "RTS"
+            ]
+        );
+        assert_eq!(code[code.len() - 2], 0xEA);
+    }
+
+    #[test]
+    fn labels_and_directives() {
+        let source = "
+            .org $C000
+            reset:  ldx #3       ; lower case is fine
+            loop:   dex
+                    bne loop
+                    jsr later
+                    lda #<table+1
+                    jmp reset
+            .org $C010
+            later:  rts
+            table:  .byte 1, %10, $FF
+                    .word reset, table-1
+        ";
+        let code = assemble(source).unwrap();
+        assert_eq!(code.len(), 0x18);
+        assert_eq!(
+            texts(&code[..0x0D], 0xC000),
+            [
+                "LDX #$03",
+                "DEX",
+                "BNE $C002",
+                "JSR $C010",
+                "LDA #$12",
+                "JMP $C000"
+            ]
+        );
+        assert_eq!(code[0x0D..0x10], [0, 0, 0]);
+        assert_eq!(code[0x11..], [1, 2, 0xFF, 0x00, 0xC0, 0x10, 0xC0]);
+    }
+
+    #[test]
+    fn zero_page_only_when_known() {
+        let code =
+            assemble(".org 0\nfirst: nop\n.org $0200\nlda first\nlda later\nlater: brk\n").unwrap();
+        assert_eq!(code[0x200..0x202], [0xA5, 0x00]);
+        assert_eq!(code[0x202], 0xAD);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(error("nop\nfoo $10"), "line 2: unknown instruction `FOO`");
+        assert_eq!(error("stx $1234,X"), "line 1: STX can't take that operand");
+        assert_eq!(error("lda missing"), "line 1: unknown label in `missing`");
+        assert_eq!(error("lda #$100"), "line 1: $0100 doesn't fit in a byte");
+        assert_eq!(error("a: nop\na: nop"), "line 2: `a` is defined twice");
+        assert_eq!(
+            error(".org $9000\n.org $8000"),
+            "line 2: .org $8000 goes backwards"
+        );
+        assert_eq!(
+            error("start: .byte 0\n.org $8100\nbne start"),
+            "line 3: branch to $8000 is too far"
+        );
+    }
+}
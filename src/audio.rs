@@ -0,0 +1,225 @@
+// Turns the APU's one sample per CPU cycle into a stream the sound card can play.
+//
+// The resampler averages every APU sample that lands inside an output sample (a cheap low
+// pass that's plenty for a ~1.79MHz to 48kHz drop), then removes the DC offset with a high
+// pass like the one in the console's output stage. Output goes into a ring buffer the audio
+// callback drains.
+//
+// The emulator and the sound card run off different clocks, so the buffer slowly fills or
+// drains. Dynamic rate control nudges the output rate by up to half a percent depending on
+// how far the buffer is from the target latency, which is too small to hear as pitch.
+// https://docs.libretro.com/development/cores/dynamic-rate-control/
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+pub const DEFAULT_LATENCY_MS: u32 = 50;
+
+const MAX_RATE_DELTA: f64 = 0.005;
+// cutoff of the DC blocking filter
+const HIGH_PASS_HZ: f32 = 90.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AudioConfig {
+    /// Output sample rate, usually 44100 or 48000
+    pub sample_rate: u32,
+    /// How much audio to keep buffered ahead of the sound card
+    pub latency_ms: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            latency_ms: DEFAULT_LATENCY_MS,
+        }
+    }
+}
+
+impl AudioConfig {
+    /// Buffered samples the rate control aims for
+    pub fn target_samples(&self) -> usize {
+        (self.sample_rate as u64 * self.latency_ms as u64 / 1000) as usize
+    }
+
+    /// Room for a few times the target, so a stall doesn't drop samples straight away
+    pub fn ring_capacity(&self) -> usize {
+        self.target_samples().max(1) * 4
+    }
+}
+
+pub struct Resampler {
+    input_rate: f64,
+    output_rate: f64,
+    // output samples due per input sample, including the rate control adjustment
+    step: f64,
+    phase: f64,
+    sum: f32,
+    count: u32,
+    // DC blocking filter state
+    high_pass: f32,
+    previous_in: f32,
+    previous_out: f32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * HIGH_PASS_HZ);
+        let dt = 1.0 / output_rate as f32;
+        Resampler {
+            input_rate: input_rate as f64,
+            output_rate: output_rate as f64,
+            step: output_rate as f64 / input_rate as f64,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            high_pass: rc / (rc + dt),
+            previous_in: 0.0,
+            previous_out: 0.0,
+        }
+    }
+
+    /// The CPU clock changes with the region
+    pub fn set_input_rate(&mut self, input_rate: u32) {
+        self.input_rate = input_rate as f64;
+        self.step = self.output_rate / self.input_rate;
+    }
+
+    /// Speed the output up when fewer than `target` samples are buffered, slow it down when
+    /// more are
+    pub fn adjust_rate(&mut self, queued: usize, target: usize) {
+        let target = target.max(1) as f64;
+        let distance = ((target - queued as f64) / target).clamp(-1.0, 1.0);
+        self.step = self.output_rate * (1.0 + MAX_RATE_DELTA * distance) / self.input_rate;
+    }
+
+    /// Resample `input`, handing each finished output sample to `output`
+    pub fn process(&mut self, input: &[f32], mut output: impl FnMut(f32)) {
+        for &sample in input {
+            self.sum += sample;
+            self.count += 1;
+            self.phase += self.step;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                let average = self.sum / self.count as f32;
+                self.sum = 0.0;
+                self.count = 0;
+                output(self.filter(average));
+            }
+        }
+    }
+
+    fn filter(&mut self, sample: f32) -> f32 {
+        let out = self.high_pass * (self.previous_out + sample - self.previous_in);
+        self.previous_in = sample;
+        self.previous_out = out;
+        out
+    }
+}
+
+/// Fixed size sample queue shared with the audio callback
+#[derive(Debug, Clone)]
+pub struct SampleRing {
+    buffer: Box<[f32]>,
+    read: usize,
+    len: usize,
+    // replayed on underrun, going straight to 0 would click
+    last: f32,
+}
+
+impl SampleRing {
+    pub fn new(capacity: usize) -> Self {
+        SampleRing {
+            buffer: vec![0.0; capacity.max(1)].into_boxed_slice(),
+            read: 0,
+            len: 0,
+            last: 0.0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a sample, dropping the oldest one when full
+    pub fn push(&mut self, sample: f32) {
+        let capacity = self.buffer.len();
+        if self.len == capacity {
+            self.read = (self.read + 1) % capacity;
+            self.len -= 1;
+        }
+        self.buffer[(self.read + self.len) % capacity] = sample;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.buffer[self.read];
+        self.read = (self.read + 1) % self.buffer.len();
+        self.len -= 1;
+        self.last = sample;
+        Some(sample)
+    }
+
+    /// Fill `out` for the sound card, holding the last sample if the queue runs dry
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out {
+            *sample = self.pop().unwrap_or(self.last);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_RATE: u32 = 1_789_773;
+
+    fn resample(resampler: &mut Resampler, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        resampler.process(input, |s| output.push(s));
+        output
+    }
+
+    #[test]
+    fn resamples_to_output_rate() {
+        let mut resampler = Resampler::new(CPU_RATE, 48_000);
+        let output = resample(&mut resampler, &vec![0.5; CPU_RATE as usize]);
+        assert!((47_999..=48_001).contains(&output.len()));
+        // the DC offset is filtered out
+        assert!(output.last().unwrap().abs() < 0.001);
+    }
+
+    #[test]
+    fn rate_control_follows_buffer_fill() {
+        let input = vec![0.0; CPU_RATE as usize / 10];
+        let mut resampler = Resampler::new(CPU_RATE, 48_000);
+        let nominal = resample(&mut resampler, &input).len();
+
+        let mut resampler = Resampler::new(CPU_RATE, 48_000);
+        resampler.adjust_rate(0, 2400);
+        let starving = resample(&mut resampler, &input).len();
+
+        let mut resampler = Resampler::new(CPU_RATE, 48_000);
+        resampler.adjust_rate(4800, 2400);
+        let flooded = resample(&mut resampler, &input).len();
+
+        assert_eq!(starving, nominal + 24);
+        assert_eq!(flooded, nominal - 24);
+    }
+
+    #[test]
+    fn ring_drops_oldest_and_holds_on_underrun() {
+        let mut ring = SampleRing::new(3);
+        (1..=4).for_each(|s| ring.push(s as f32));
+        assert_eq!(ring.len(), 3);
+        let mut out = [0.0; 5];
+        ring.fill(&mut out);
+        assert_eq!(out, [2.0, 3.0, 4.0, 4.0, 4.0]);
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use nesemu::hash::to_hex;
+use nesemu::logging::StderrLogger;
+use nesemu::Emulator;
+use nesemu::{load_image, RomImage};
+use std::path::Path;
+use std::process;
+use std::{env, fs};
+
+// Runs a rom for a number of frames without a window or sound card and prints the SHA-1 of the
+// last frame, or the instruction log with --trace. For CI and testing on servers. If the CPU
+// jams, a report on it goes in the working directory, see `jam_report`.
+
+const FDS_BIOS_FILE: &str = "disksys.rom";
+const DEFAULT_FRAMES: u32 = 60;
+
+const USAGE: &str = "usage: nesemu-headless [--frames n] [--trace] rom";
+
+struct Options {
+    rom_file: String,
+    frames: u32,
+    trace: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut rom_file = None;
+    let mut frames = DEFAULT_FRAMES;
+    let mut trace = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let count = args.next().ok_or("--frames needs a value")?;
+                frames = count
+                    .parse()
+                    .map_err(|_| format!("bad frame count {:?}", count))?;
+            }
+            "--trace" => trace = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if rom_file.is_none() => rom_file = Some(arg),
+            _ => return Err("only one rom can be loaded".to_string()),
+        }
+    }
+    Ok(Options {
+        rom_file: rom_file.ok_or("no rom given")?,
+        frames,
+        trace,
+    })
+}
+
+fn load(emulator: &mut Emulator, path: &str) -> Result<(), String> {
+    let loaded = match load_image(path).map_err(|e| format!("{}: {}", path, e))? {
+        RomImage::Cartridge(rom) => emulator.load_cartridge(&rom),
+        RomImage::Disk(disk) => {
+            let bios = fs::read(FDS_BIOS_FILE)
+                .map_err(|e| format!("FDS BIOS {}: {}", FDS_BIOS_FILE, e))?;
+            emulator.load_disk(&disk, &bios)
+        }
+    };
+    loaded.map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2)
+    });
+    if let Err(e) = StderrLogger::install_from_env() {
+        eprintln!("{}", e);
+        process::exit(2)
+    }
+    let mut emulator = Emulator::new();
+    emulator.keep_history(true);
+    emulator.cpu_mut().set_logging(options.trace);
+    if let Err(e) = load(&mut emulator, &options.rom_file) {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+    for _ in 0..options.frames {
+        emulator.run_frame();
+        // nothing plays the audio, don't let it pile up
+        emulator.clear_audio_samples();
+    }
+    if let Some(report) = emulator.take_jam_report() {
+        match report.save_in(Path::new(".")) {
+            Ok(path) => eprintln!(
+                "CPU jammed at ${:04X}, report saved to {}",
+                report.pc,
+                path.display()
+            ),
+            Err(e) => eprintln!("CPU jammed at ${:04X}, can't save report: {}", report.pc, e),
+        }
+    }
+    if !options.trace {
+        println!("{}", to_hex(&emulator.frame().sha1()));
+    }
+}
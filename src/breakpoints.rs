@@ -0,0 +1,500 @@
+use crate::cpu::NesCpu;
+use crate::memory::Bus;
+use std::fmt;
+
+// Execution breakpoints stop the CPU before the instruction at an address runs, when their
+// condition holds. The emulator pauses and queues `Event::Breakpoint`, and running on from
+// there runs that instruction rather than stopping again. Breakpoints are checked after any
+// interrupt is taken, so one at the start of an NMI handler stops there.
+//
+// Conditions are C-like expressions over the registers and memory, such as
+// `A == 0x20 && X > 3` or `[$0300] != 0 || C`:
+//
+//   A X Y SP PC P         registers, P being the flags as PHP pushes them without B
+//   C Z I D V N           flags, 0 or 1
+//   scanline dot          where the PPU is
+//   [address]             a byte of memory, read without side effects
+//   $20 0x20 32 %100000   numbers
+//
+// with ( ), !, + - & |, the comparisons and && ||. Comparisons and ! give 1 or 0 and anything
+// that isn't 0 is true. Watches are written the same way, see `watches`.
+
+/// A condition that doesn't parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadCondition(pub String);
+
+impl fmt::Display for BadCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad condition: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadCondition {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Value {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    // bit of P
+    Flag(u8),
+    Scanline,
+    Dot,
+}
+
+impl Value {
+    fn named(name: &str) -> Option<Value> {
+        Some(match name.to_ascii_uppercase().as_str() {
+            "A" => Value::A,
+            "X" => Value::X,
+            "Y" => Value::Y,
+            "SP" => Value::Sp,
+            "PC" => Value::Pc,
+            "P" => Value::P,
+            "C" => Value::Flag(0),
+            "Z" => Value::Flag(1),
+            "I" => Value::Flag(2),
+            "D" => Value::Flag(3),
+            "V" => Value::Flag(6),
+            "N" => Value::Flag(7),
+            "SCANLINE" => Value::Scanline,
+            "DOT" => Value::Dot,
+            _ => return None,
+        })
+    }
+
+    fn get(self, cpu: &NesCpu) -> i64 {
+        let value = match self {
+            Value::A => cpu.reg.accumulator as u16,
+            Value::X => cpu.reg.idx as u16,
+            Value::Y => cpu.reg.idy() as u16,
+            Value::Sp => cpu.reg.sp() as u16,
+            Value::Pc => cpu.reg.pc,
+            Value::P => cpu.reg.status() as u16,
+            Value::Flag(bit) => (cpu.reg.status() >> bit & 1) as u16,
+            Value::Scanline => cpu.memory.ppu().scanline(),
+            Value::Dot => cpu.memory.ppu().dot(),
+        };
+        value as i64
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    BitAnd,
+    BitOr,
+}
+
+impl Op {
+    fn apply(self, left: i64, right: i64) -> i64 {
+        let truth = |b: bool| b as i64;
+        match self {
+            Op::Or => truth(left != 0 || right != 0),
+            Op::And => truth(left != 0 && right != 0),
+            Op::Equal => truth(left == right),
+            Op::NotEqual => truth(left != right),
+            Op::Less => truth(left < right),
+            Op::LessEqual => truth(left <= right),
+            Op::Greater => truth(left > right),
+            Op::GreaterEqual => truth(left >= right),
+            Op::Add => left + right,
+            Op::Sub => left - right,
+            Op::BitAnd => left & right,
+            Op::BitOr => left | right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Value(Value),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, cpu: &NesCpu) -> i64 {
+        match self {
+            Expr::Number(number) => *number,
+            Expr::Value(value) => value.get(cpu),
+            Expr::Memory(address) => cpu.memory.peek_byte(address.eval(cpu) as u16) as i64,
+            Expr::Not(expr) => (expr.eval(cpu) == 0) as i64,
+            Expr::Binary(op, left, right) => op.apply(left.eval(cpu), right.eval(cpu)),
+        }
+    }
+
+    // the addresses of the memory it reads, or false if one of them isn't a number
+    fn addresses(&self, out: &mut Vec<u16>) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Value(_) => true,
+            Expr::Memory(address) => match **address {
+                Expr::Number(address) => {
+                    out.push(address as u16);
+                    true
+                }
+                _ => false,
+            },
+            Expr::Not(expr) => expr.addresses(out),
+            Expr::Binary(_, left, right) => left.addresses(out) && right.addresses(out),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(Op),
+    Not,
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+fn tokens(text: &str) -> Result<Vec<Token>, BadCondition> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let two = |op| (Token::Op(op), 2);
+        let one = |token| (token, 1);
+        let (token, length) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('|', Some('|')) => two(Op::Or),
+            ('&', Some('&')) => two(Op::And),
+            ('=', Some('=')) => two(Op::Equal),
+            ('!', Some('=')) => two(Op::NotEqual),
+            ('<', Some('=')) => two(Op::LessEqual),
+            ('>', Some('=')) => two(Op::GreaterEqual),
+            ('<', _) => one(Token::Op(Op::Less)),
+            ('>', _) => one(Token::Op(Op::Greater)),
+            ('+', _) => one(Token::Op(Op::Add)),
+            ('-', _) => one(Token::Op(Op::Sub)),
+            ('&', _) => one(Token::Op(Op::BitAnd)),
+            ('|', _) => one(Token::Op(Op::BitOr)),
+            ('!', _) => one(Token::Not),
+            ('(', _) => one(Token::Open),
+            (')', _) => one(Token::Close),
+            ('[', _) => one(Token::OpenBracket),
+            (']', _) => one(Token::CloseBracket),
+            (c, _) if c.is_ascii_alphanumeric() || c == '$' || c == '%' || c == '_' => {
+                let length = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '$' | '%' | '_'))
+                    .count();
+                let word: String = chars[i..i + length].iter().collect();
+                (word_token(&word)?, length)
+            }
+            (c, _) => return Err(BadCondition(format!("unexpected `{}`", c))),
+        };
+        tokens.push(token);
+        i += length;
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: &str) -> Result<Token, BadCondition> {
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_prefix('%') {
+        (binary, 2)
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        (lower.as_str(), 10)
+    } else {
+        return Ok(Token::Name(word.to_string()));
+    };
+    i64::from_str_radix(digits, radix)
+        .map(Token::Number)
+        .map_err(|_| BadCondition(format!("bad number `{}`", word)))
+}
+
+// precedence climbing, loosest first
+const LEVELS: [&[Op]; 5] = [
+    &[Op::Or],
+    &[Op::And],
+    &[
+        Op::Equal,
+        Op::NotEqual,
+        Op::Less,
+        Op::LessEqual,
+        Op::Greater,
+        Op::GreaterEqual,
+    ],
+    &[Op::BitOr],
+    &[Op::Add, Op::Sub, Op::BitAnd],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), BadCondition> {
+        match self.next() {
+            Some(next) if next == token => Ok(()),
+            _ => Err(BadCondition(format!("missing {}", what))),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, BadCondition> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some(&Token::Op(op)) = self.peek() {
+            if !ops.contains(&op) {
+                break;
+            }
+            self.at += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, BadCondition> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Name(name)) => Value::named(&name)
+                .map(Expr::Value)
+                .ok_or_else(|| BadCondition(format!("unknown name `{}`", name))),
+            Some(Token::Open) => {
+                let expr = self.binary(0)?;
+                self.expect(Token::Close, "`)`")?;
+                Ok(expr)
+            }
+            Some(Token::OpenBracket) => {
+                let address = self.binary(0)?;
+                self.expect(Token::CloseBracket, "`]`")?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            _ => Err(BadCondition("expected a value".to_string())),
+        }
+    }
+}
+
+/// A parsed condition, shown as it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, BadCondition> {
+        let mut parser = Parser {
+            tokens: tokens(text)?,
+            at: 0,
+        };
+        let expr = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(BadCondition(format!("unexpected {:?}", token)));
+        }
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Whether it holds for `cpu` as it is now
+    pub fn holds(&self, cpu: &NesCpu) -> bool {
+        self.value(cpu) != 0
+    }
+
+    /// What it comes to for `cpu` as it is now, for watches
+    pub fn value(&self, cpu: &NesCpu) -> i64 {
+        self.expr.eval(cpu)
+    }
+
+    /// The memory it reads, or None when an address depends on registers or other memory
+    pub fn addresses(&self) -> Option<Vec<u16>> {
+        let mut addresses = Vec::new();
+        self.expr.addresses(&mut addresses).then_some(addresses)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// What the breakpoint is known by, never reused
+    pub id: u32,
+    pub address: u16,
+    /// Stop only when this holds, always without one
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// Times it has stopped the CPU
+    pub hits: u64,
+}
+
+/// The breakpoints set, in the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: u32,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an enabled breakpoint and return its id
+    pub fn add(&mut self, address: u16, condition: Option<Condition>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id,
+            address,
+            condition,
+            enabled: true,
+            hits: 0,
+        });
+        id
+    }
+
+    /// Returns whether there was a breakpoint `id`
+    pub fn remove(&mut self, id: u32) -> bool {
+        let before = self.list.len();
+        self.list.retain(|breakpoint| breakpoint.id != id);
+        self.list.len() != before
+    }
+
+    /// Returns whether there is a breakpoint `id`
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        match self.list.iter_mut().find(|breakpoint| breakpoint.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Breakpoint> {
+        self.list.iter().find(|breakpoint| breakpoint.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The first enabled breakpoint at `cpu`'s PC whose condition holds, counting the hit
+    pub(crate) fn check(&mut self, cpu: &NesCpu) -> Option<u32> {
+        let pc = cpu.reg.pc;
+        let breakpoint = self.list.iter_mut().find(|breakpoint| {
+            breakpoint.enabled
+                && breakpoint.address == pc
+                && breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(cpu))
+        })?;
+        breakpoint.hits += 1;
+        Some(breakpoint.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holds(text: &str, cpu: &NesCpu) -> bool {
+        Condition::parse(text).unwrap().holds(cpu)
+    }
+
+    #[test]
+    fn conditions() {
+        let mut cpu = NesCpu::new_from_bytes(&[0xEA]);
+        cpu.reg.accumulator = 0x20;
+        cpu.reg.idx = 4;
+        cpu.memory.write_byte(0x0300, 7);
+        assert!(holds("A == 0x20 && X > 3", &cpu));
+        assert!(!holds("A == 0x20 && X > 4", &cpu));
+        assert!(holds("a != $20 || [$0300] == 7", &cpu));
+        assert!(holds("[0x300 + X - 4] - 1 == 6", &cpu));
+        assert!(holds("!C && (P & %100) == 4 && I", &cpu));
+        assert!(holds("PC == $8000 && SP == $FD", &cpu));
+        assert!(holds("X | 1 == 5", &cpu));
+    }
+
+    #[test]
+    fn bad_conditions() {
+        let error = |text| Condition::parse(text).unwrap_err().to_string();
+        assert_eq!(error("A =="), "bad condition: expected a value");
+        assert_eq!(error("Q > 1"), "bad condition: unknown name `Q`");
+        assert_eq!(error("[$10"), "bad condition: missing `]`");
+        assert_eq!(error("A # 1"), "bad condition: unexpected `#`");
+        assert_eq!(error("0xZZ"), "bad condition: bad number `0xZZ`");
+    }
+
+    #[test]
+    fn checks_count_hits() {
+        let mut cpu = NesCpu::new_from_bytes(&[0xEA]);
+        let mut breakpoints = Breakpoints::new();
+        let never = breakpoints.add(0x8000, Some(Condition::parse("A == 1").unwrap()));
+        let other = breakpoints.add(0x9000, None);
+        let always = breakpoints.add(0x8000, None);
+        assert_eq!(breakpoints.check(&cpu), Some(always));
+        cpu.reg.accumulator = 1;
+        assert_eq!(breakpoints.check(&cpu), Some(never));
+        breakpoints.set_enabled(never, false);
+        assert_eq!(breakpoints.check(&cpu), Some(always));
+        assert_eq!(breakpoints.get(always).unwrap().hits, 2);
+        assert!(breakpoints.remove(other));
+        assert!(!breakpoints.remove(other));
+        assert_eq!(breakpoints.len(), 2);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::memory::Bus;
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+
+// A shadow of the 6502 stack kept from the calls and returns the CPU runs: every JSR, BRK, NMI
+// and IRQ pushes a frame with where it came from and where it'll return to, and every RTS and
+// RTI pops one. Debuggers show it as a backtrace.
+//
+// Returns are paired with calls by the stack pointer, the same way the profiler does it. A
+// return that doesn't line up with the innermost call is a stack imbalance, recorded along with
+// the frame it should have ended:
+//
+//   - a return with no call to return from
+//   - a return from deeper in the stack than the innermost call: something was pushed and not
+//     pulled, or RTS is being used as a jump through an address pushed on purpose
+//   - a return from further out: something was pulled that wasn't pushed, or a routine pulled
+//     its return address to return two levels at once. The calls in between are dropped.
+//   - RTS ending an interrupt, or RTI ending a JSR
+//   - a return somewhere other than the address the call pushed, the stack was overwritten
+//   - a call that wraps the stack pointer past $0100, a stack overflow
+//
+// Some of these are done on purpose, so they're recorded rather than stopping anything.
+
+/// Imbalances kept, the oldest are dropped after this many
+pub const MAX_IMBALANCES: usize = 256;
+
+/// What pushed a frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Subroutine,
+    Nmi,
+    Irq,
+    /// A BRK instruction
+    Break,
+}
+
+impl FrameKind {
+    /// Whether the frame is ended by RTI rather than RTS
+    pub fn is_interrupt(self) -> bool {
+        self != FrameKind::Subroutine
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FrameKind::Subroutine => "subroutine",
+            FrameKind::Nmi => "nmi",
+            FrameKind::Irq => "irq",
+            FrameKind::Break => "brk",
+        }
+    }
+}
+
+/// One call on the shadow stack
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub kind: FrameKind,
+    /// The JSR or BRK, or the instruction the interrupt came before
+    pub call_site: u16,
+    /// Where the call went
+    pub target: u16,
+    /// Where it goes back to, the address RTS or RTI should land on
+    pub return_address: u16,
+    /// The stack pointer once the call had pushed everything, where the return pulls from
+    pub sp: u8,
+    pub cycle: u64,
+}
+
+/// How a return or call didn't line up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImbalanceKind {
+    /// Returned with no call on the shadow stack
+    Unmatched,
+    /// Returned from deeper in the stack than the innermost call's `expected_sp`
+    Deeper { expected_sp: u8 },
+    /// Returned from further out than the innermost call's `expected_sp`, ending `skipped`
+    /// calls inside the one it returned from without their returning
+    Shallower { expected_sp: u8, skipped: usize },
+    /// RTS ended an interrupt or RTI ended a subroutine
+    WrongInstruction,
+    /// Returned to `actual` rather than where the call would return to
+    WrongReturn { actual: u16 },
+    /// A call pushed the stack pointer past the bottom of the stack
+    Overflow,
+}
+
+impl fmt::Display for ImbalanceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImbalanceKind::Unmatched => write!(f, "return without a call"),
+            ImbalanceKind::Deeper { expected_sp } => write!(
+                f,
+                "returned from deeper than the call, expected SP:{:02X}",
+                expected_sp
+            ),
+            ImbalanceKind::Shallower {
+                expected_sp,
+                skipped,
+            } => write!(
+                f,
+                "returned from further out than the call, expected SP:{:02X}, {} calls skipped",
+                expected_sp, skipped
+            ),
+            ImbalanceKind::WrongInstruction => write!(f, "wrong return instruction"),
+            ImbalanceKind::WrongReturn { actual } => write!(f, "returned to ${:04X}", actual),
+            ImbalanceKind::Overflow => write!(f, "stack overflow"),
+        }
+    }
+}
+
+/// A return or call that didn't line up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Imbalance {
+    pub kind: ImbalanceKind,
+    /// The RTS, RTI or call
+    pub pc: u16,
+    /// The stack pointer before it ran
+    pub sp: u8,
+    pub cycle: u64,
+    /// The innermost frame at the time, the one the return should have ended
+    pub frame: Option<StackFrame>,
+}
+
+impl fmt::Display for Imbalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "${:04X} SP:{:02X} CYC:{}: {}",
+            self.pc, self.sp, self.cycle, self.kind
+        )?;
+        if let Some(frame) = &self.frame {
+            write!(
+                f,
+                " (in the {} at ${:04X} called from ${:04X})",
+                frame.kind.name(),
+                frame.target,
+                frame.call_site
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The shadow call stack and the imbalances found keeping it
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<StackFrame>,
+    imbalances: VecDeque<Imbalance>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The calls not yet returned from, outermost first
+    pub fn frames(&self) -> &[StackFrame] {
+        &self.frames
+    }
+
+    /// How many calls deep the CPU is
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The imbalances found, oldest first, up to `MAX_IMBALANCES`
+    pub fn imbalances(&self) -> impl Iterator<Item = &Imbalance> {
+        self.imbalances.iter()
+    }
+
+    pub fn clear_imbalances(&mut self) {
+        self.imbalances.clear();
+    }
+
+    /// Forget every frame, when the stack can't be trusted any more, as after loading a state
+    pub fn clear_frames(&mut self) {
+        self.frames.clear();
+    }
+
+    fn report(&mut self, kind: ImbalanceKind, pc: u16, sp: u8, cycle: u64) {
+        if self.imbalances.len() == MAX_IMBALANCES {
+            self.imbalances.pop_front();
+        }
+        self.imbalances.push_back(Imbalance {
+            kind,
+            pc,
+            sp,
+            cycle,
+            frame: self.frames.last().copied(),
+        });
+    }
+
+    /// `frame` was pushed by a call that ran with the stack pointer at `sp_before`
+    pub(crate) fn call(&mut self, frame: StackFrame, sp_before: u8) {
+        if frame.sp > sp_before {
+            self.report(
+                ImbalanceKind::Overflow,
+                frame.call_site,
+                sp_before,
+                frame.cycle,
+            );
+        }
+        self.frames.push(frame);
+    }
+
+    /// The RTS or RTI at `pc`, run with the stack pointer at `sp`, went back to `returned_to`
+    pub(crate) fn ret(&mut self, pc: u16, rti: bool, sp: u8, returned_to: u16, cycle: u64) {
+        let Some(top) = self.frames.last().copied() else {
+            self.report(ImbalanceKind::Unmatched, pc, sp, cycle);
+            return;
+        };
+        if sp < top.sp {
+            // not returning from any call there's a frame for, leave them all be
+            let kind = ImbalanceKind::Deeper {
+                expected_sp: top.sp,
+            };
+            self.report(kind, pc, sp, cycle);
+            return;
+        }
+        if sp > top.sp {
+            let outer = self.frames.iter().rposition(|frame| frame.sp == sp);
+            let keep = outer.map_or_else(
+                // no call left it here, end the ones it's returning from further out than
+                || self.frames.iter().filter(|frame| frame.sp > sp).count() + 1,
+                |outer| outer + 1,
+            );
+            let kind = ImbalanceKind::Shallower {
+                expected_sp: top.sp,
+                skipped: self.frames.len() - keep,
+            };
+            self.report(kind, pc, sp, cycle);
+            self.frames.truncate(keep);
+            if outer.is_none() {
+                self.frames.pop();
+                return;
+            }
+        }
+        let frame = *self.frames.last().expect("a frame at this depth");
+        if frame.kind.is_interrupt() != rti {
+            self.report(ImbalanceKind::WrongInstruction, pc, sp, cycle);
+        } else if returned_to != frame.return_address {
+            let kind = ImbalanceKind::WrongReturn {
+                actual: returned_to,
+            };
+            self.report(kind, pc, sp, cycle);
+        }
+        self.frames.pop();
+    }
+
+    /// A backtrace, innermost first, the CPU being at `pc`. Routines are named by their labels
+    /// in `bus` where they have one:
+    ///
+    ///   #0  $C123  in update ($C100)
+    ///   #1  $C456  in main ($C400)
+    ///   #2  $C010
+    pub fn backtrace(&self, bus: &dyn Bus, pc: u16) -> String {
+        let mut text = String::new();
+        let mut at = pc;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let name = match (frame.kind, bus.label(frame.target)) {
+                (FrameKind::Subroutine, Some(label)) => label.to_string(),
+                (FrameKind::Subroutine, None) => format!("sub_{:04X}", frame.target),
+                (kind, _) => format!("<{}>", kind.name()),
+            };
+            let _ = writeln!(
+                text,
+                "#{:<2} ${:04X}  in {} (${:04X})",
+                depth, at, name, frame.target
+            );
+            at = frame.call_site;
+        }
+        let _ = writeln!(text, "#{:<2} ${:04X}", self.frames.len(), at);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::NesCpu;
+
+    fn run(source: &str, instructions: usize) -> NesCpu {
+        let mut cpu = NesCpu::new_from_bytes(&assemble(source).unwrap());
+        cpu.set_pc(0x8000);
+        cpu.set_call_stack(Some(CallStack::new()));
+        for _ in 0..instructions {
+            cpu.fetch_decode_next();
+        }
+        cpu
+    }
+
+    fn kinds(cpu: &NesCpu) -> Vec<ImbalanceKind> {
+        let stack = cpu.call_stack().unwrap();
+        stack.imbalances().map(|imbalance| imbalance.kind).collect()
+    }
+
+    #[test]
+    fn frames_follow_calls_and_returns() {
+        let cpu = run(
+            "
+                    jsr outer
+            outer:  jsr inner
+            inner:  nop
+                    rts
+            ",
+            // stopped on the NOP, then after the inner RTS
+            3,
+        );
+        let stack = cpu.call_stack().unwrap();
+        let frames = stack.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].call_site, 0x8003);
+        assert_eq!(frames[1].target, 0x8006);
+        assert_eq!(frames[1].return_address, 0x8005);
+        assert_eq!(frames[1].sp, 0xF9);
+        assert_eq!(
+            stack.backtrace(&cpu.memory, cpu.reg.pc),
+            "#0  $8007  in sub_8006 ($8006)\n\
+             #1  $8003  in sub_8003 ($8003)\n\
+             #2  $8000\n"
+        );
+        let cpu = run(
+            "
+                    jsr outer
+            outer:  jsr inner
+            inner:  nop
+                    rts
+            ",
+            4,
+        );
+        assert_eq!(cpu.call_stack().unwrap().depth(), 1);
+        assert!(kinds(&cpu).is_empty());
+    }
+
+    #[test]
+    fn unbalanced_pushes_and_pulls_are_caught() {
+        // a push the routine forgets to pull, so RTS goes to the wrong place
+        let cpu = run(
+            "
+                    jsr leaky
+                    nop
+            leaky:  pha
+                    rts
+            ",
+            3,
+        );
+        assert_eq!(kinds(&cpu), [ImbalanceKind::Deeper { expected_sp: 0xFB }]);
+        assert_eq!(cpu.call_stack().unwrap().depth(), 1);
+
+        // returning two levels at once by pulling the inner return address
+        let cpu = run(
+            "
+                    jsr outer
+                    nop
+            outer:  jsr inner
+                    nop
+            inner:  pla
+                    pla
+                    rts
+            ",
+            5,
+        );
+        assert_eq!(
+            kinds(&cpu),
+            [ImbalanceKind::Shallower {
+                expected_sp: 0xF9,
+                skipped: 1
+            }]
+        );
+        assert_eq!(cpu.call_stack().unwrap().depth(), 0);
+        assert_eq!(cpu.reg.pc, 0x8003);
+    }
+
+    #[test]
+    fn returns_that_dont_match_their_call() {
+        let mut stack = CallStack::new();
+        stack.ret(0x8000, false, 0xFD, 0x1234, 0);
+        let frame = |kind, sp| StackFrame {
+            kind,
+            call_site: 0x8000,
+            target: 0x9000,
+            return_address: 0x8000,
+            sp,
+            cycle: 0,
+        };
+        stack.call(frame(FrameKind::Nmi, 0xFA), 0xFD);
+        stack.ret(0x9000, false, 0xFA, 0x8000, 10);
+        stack.call(frame(FrameKind::Subroutine, 0xFB), 0xFD);
+        stack.ret(0x9000, false, 0xFB, 0x8100, 20);
+        stack.call(frame(FrameKind::Subroutine, 0xFF), 0x01);
+        let kinds: Vec<ImbalanceKind> = stack.imbalances().map(|i| i.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ImbalanceKind::Unmatched,
+                ImbalanceKind::WrongInstruction,
+                ImbalanceKind::WrongReturn { actual: 0x8100 },
+                ImbalanceKind::Overflow
+            ]
+        );
+        let imbalance = stack.imbalances().nth(1).unwrap();
+        assert_eq!(
+            imbalance.to_string(),
+            "$9000 SP:FA CYC:10: wrong return instruction (in the nmi at $9000 called from $8000)"
+        );
+    }
+}
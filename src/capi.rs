@@ -0,0 +1,304 @@
+// C API, for embedding the emulator in programs that aren't written in Rust. include/nesemu.h
+// declares it, regenerated with cbindgen (see cbindgen.toml) whenever this file changes. Build
+// the library for C with
+//
+//   cargo rustc --release --lib --no-default-features --features game-db --crate-type cdylib
+//
+// (or staticlib). Every call takes the handle from nesemu_new. Calls that can fail return a
+// NesemuResult, with the reason from nesemu_last_error.
+
+use crate::emulator::Emulator;
+use crate::input::PLAYERS;
+use crate::palette::Palette;
+use crate::ppu::{HEIGHT, WIDTH};
+use crate::{image_from_bytes, RomError, RomImage};
+use std::ffi::{c_char, CString};
+use std::{ptr, slice};
+
+pub const NESEMU_WIDTH: u32 = WIDTH as u32;
+pub const NESEMU_HEIGHT: u32 = HEIGHT as u32;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NesemuResult {
+    Ok = 0,
+    /// A pointer passed in was NULL
+    NullArgument,
+    /// Not a rom, or a damaged one
+    BadRom,
+    /// The rom's board isn't emulated
+    UnsupportedMapper,
+    /// Not a save state of this game
+    BadState,
+    /// A player number past the four there can be
+    NoSuchPlayer,
+}
+
+/// An emulator and what's been handed out from it to C
+pub struct NesemuEmulator {
+    emulator: Emulator,
+    palette: Palette,
+    // the last frame as RGBA, made when asked for
+    rgba: Vec<u8>,
+    last_error: CString,
+}
+
+impl NesemuEmulator {
+    fn fail(&mut self, result: NesemuResult, message: impl ToString) -> NesemuResult {
+        // the messages come from our own Display impls, which have no NULs
+        self.last_error = CString::new(message.to_string()).unwrap_or_default();
+        result
+    }
+}
+
+/// A new emulator with nothing loaded, freed with nesemu_free
+#[no_mangle]
+pub extern "C" fn nesemu_new() -> *mut NesemuEmulator {
+    Box::into_raw(Box::new(NesemuEmulator {
+        emulator: Emulator::new(),
+        palette: Palette::default(),
+        rgba: vec![0; WIDTH * HEIGHT * 4],
+        last_error: CString::default(),
+    }))
+}
+
+/// # Safety
+/// `emulator` must come from nesemu_new and not be used again. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_free(emulator: *mut NesemuEmulator) {
+    if !emulator.is_null() {
+        drop(Box::from_raw(emulator));
+    }
+}
+
+/// Load an iNES, NES 2.0 or UNIF rom, zipped or gzipped or not, from `len` bytes at `data` and
+/// power on. The bytes are copied.
+///
+/// # Safety
+/// `emulator` must come from nesemu_new and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_load_rom(
+    emulator: *mut NesemuEmulator,
+    data: *const u8,
+    len: usize,
+) -> NesemuResult {
+    let Some(emulator) = emulator.as_mut() else {
+        return NesemuResult::NullArgument;
+    };
+    if data.is_null() {
+        return NesemuResult::NullArgument;
+    }
+    let bytes = slice::from_raw_parts(data, len).to_vec();
+    let loaded = match image_from_bytes(bytes) {
+        Ok(RomImage::Cartridge(rom)) => emulator.emulator.load_cartridge(&rom),
+        Ok(RomImage::Disk(_)) => {
+            return emulator.fail(NesemuResult::BadRom, "FDS disks aren't supported here")
+        }
+        Err(e) => Err(e),
+    };
+    match loaded {
+        Ok(()) => NesemuResult::Ok,
+        Err(e @ RomError::UnsupportedMapper(_)) => {
+            emulator.fail(NesemuResult::UnsupportedMapper, e)
+        }
+        Err(e) => emulator.fail(NesemuResult::BadRom, e),
+    }
+}
+
+/// Run until the next frame is drawn
+///
+/// # Safety
+/// `emulator` must come from nesemu_new.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_run_frame(emulator: *mut NesemuEmulator) {
+    if let Some(emulator) = emulator.as_mut() {
+        emulator.emulator.clear_audio_samples();
+        emulator.emulator.run_frame();
+    }
+}
+
+/// The last frame, NESEMU_WIDTH x NESEMU_HEIGHT pixels of RGBA, 4 bytes each, row by row.
+/// It stays valid until the next call with this emulator.
+///
+/// # Safety
+/// `emulator` must come from nesemu_new.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_framebuffer(emulator: *mut NesemuEmulator) -> *const u8 {
+    let Some(emulator) = emulator.as_mut() else {
+        return ptr::null();
+    };
+    let pixels = emulator.emulator.frame().pixels();
+    for (&pixel, rgba) in pixels.iter().zip(emulator.rgba.chunks_exact_mut(4)) {
+        let [r, g, b] = emulator.palette.rgb(pixel);
+        rgba.copy_from_slice(&[r, g, b, 0xFF]);
+    }
+    emulator.rgba.as_ptr()
+}
+
+/// What `player` (0-3) is holding from the next frame on, one bit a button: A, B, Select,
+/// Start, Up, Down, Left, Right from bit 0 up
+///
+/// # Safety
+/// `emulator` must come from nesemu_new.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_set_buttons(
+    emulator: *mut NesemuEmulator,
+    player: u32,
+    buttons: u8,
+) -> NesemuResult {
+    let Some(emulator) = emulator.as_mut() else {
+        return NesemuResult::NullArgument;
+    };
+    if player as usize >= PLAYERS {
+        let message = format!("no player {}, players are 0 to {}", player, PLAYERS - 1);
+        return emulator.fail(NesemuResult::NoSuchPlayer, message);
+    }
+    emulator.emulator.set_buttons(player as usize, buttons);
+    NesemuResult::Ok
+}
+
+/// Write a save state into `buffer`, returning its size. When that's more than `capacity`
+/// nothing is written, so a call with a NULL buffer finds the size to allocate.
+///
+/// # Safety
+/// `emulator` must come from nesemu_new and `buffer`, unless NULL, must point to `capacity`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_save_state(
+    emulator: *const NesemuEmulator,
+    buffer: *mut u8,
+    capacity: usize,
+) -> usize {
+    let Some(emulator) = emulator.as_ref() else {
+        return 0;
+    };
+    let state = emulator.emulator.save_state();
+    if !buffer.is_null() && state.len() <= capacity {
+        ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+    }
+    state.len()
+}
+
+/// Load a save state of `len` bytes at `data`, made by nesemu_save_state with the same rom
+///
+/// # Safety
+/// `emulator` must come from nesemu_new and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_load_state(
+    emulator: *mut NesemuEmulator,
+    data: *const u8,
+    len: usize,
+) -> NesemuResult {
+    let Some(emulator) = emulator.as_mut() else {
+        return NesemuResult::NullArgument;
+    };
+    if data.is_null() {
+        return NesemuResult::NullArgument;
+    }
+    match emulator
+        .emulator
+        .load_state(slice::from_raw_parts(data, len))
+    {
+        Ok(()) => NesemuResult::Ok,
+        Err(e) => emulator.fail(NesemuResult::BadState, e),
+    }
+}
+
+/// Why the last call that failed did, as UTF-8. It stays valid until the next failure.
+///
+/// # Safety
+/// `emulator` must come from nesemu_new.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_last_error(emulator: *const NesemuEmulator) -> *const c_char {
+    match emulator.as_ref() {
+        Some(emulator) => emulator.last_error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fs;
+
+    #[test]
+    fn runs_a_rom_and_restores_a_state() {
+        let rom = fs::read("test-bin/full_nes_palette.nes").unwrap();
+        unsafe {
+            let emulator = nesemu_new();
+            assert_eq!(
+                nesemu_load_rom(emulator, rom.as_ptr(), rom.len()),
+                NesemuResult::Ok
+            );
+            for _ in 0..10 {
+                nesemu_run_frame(emulator);
+            }
+            let size = nesemu_save_state(emulator, ptr::null_mut(), 0);
+            let mut state = vec![0u8; size];
+            assert_eq!(nesemu_save_state(emulator, state.as_mut_ptr(), size), size);
+            let frame = |emulator| {
+                let pixels = nesemu_framebuffer(emulator);
+                slice::from_raw_parts(pixels, (NESEMU_WIDTH * NESEMU_HEIGHT * 4) as usize).to_vec()
+            };
+            nesemu_run_frame(emulator);
+            let next = frame(emulator);
+            assert!(next.chunks_exact(4).all(|pixel| pixel[3] == 0xFF));
+            assert_eq!(
+                nesemu_load_state(emulator, state.as_ptr(), size),
+                NesemuResult::Ok
+            );
+            nesemu_run_frame(emulator);
+            assert_eq!(frame(emulator), next);
+            nesemu_free(emulator);
+        }
+    }
+
+    #[test]
+    fn failures_say_why() {
+        unsafe {
+            let emulator = nesemu_new();
+            let junk = [0u8; 64];
+            assert_eq!(
+                nesemu_load_rom(emulator, junk.as_ptr(), junk.len()),
+                NesemuResult::BadRom
+            );
+            let message = CStr::from_ptr(nesemu_last_error(emulator));
+            assert_eq!(message.to_str().unwrap(), RomError::BadMagic.to_string());
+            assert_eq!(
+                nesemu_load_rom(emulator, ptr::null(), 0),
+                NesemuResult::NullArgument
+            );
+            assert_eq!(
+                nesemu_load_state(emulator, junk.as_ptr(), junk.len()),
+                NesemuResult::BadState
+            );
+            assert_eq!(nesemu_set_buttons(emulator, 3, 0xFF), NesemuResult::Ok);
+            assert_eq!(
+                nesemu_set_buttons(emulator, 4, 0xFF),
+                NesemuResult::NoSuchPlayer
+            );
+            let message = CStr::from_ptr(nesemu_last_error(emulator));
+            assert_eq!(message.to_str().unwrap(), "no player 4, players are 0 to 3");
+            nesemu_free(emulator);
+            nesemu_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn header_declares_every_function() {
+        let header = fs::read_to_string("include/nesemu.h").unwrap();
+        let source = fs::read_to_string("src/capi.rs").unwrap();
+        let functions = source
+            .lines()
+            .filter_map(|line| line.split("extern \"C\" fn ").nth(1))
+            .filter_map(|rest| rest.split('(').next());
+        for function in functions {
+            assert!(
+                header.contains(&format!("{}(", function)),
+                "include/nesemu.h is missing {}, run cbindgen",
+                function
+            );
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+// https://www.nesdev.org/wiki/Game_Genie
+// Cheat codes patch what the CPU reads: a read of the code's address returns the code's value
+// instead, or with a compare value only when the byte really there is the compare value. The
+// compare keeps a patch to bank switched ROM from hitting every bank mapped at that address.
+//
+// Codes come in three forms:
+//
+//   SXIOPO, YEUZUGAA     Game Genie, 6 letters for address and value, 8 adding a compare
+//   00075A09             Pro Action Replay, 00 then the address and value in hex
+//   075A:09, 91D9?AD:A5  raw, address:value or address?compare:value in hex
+//
+// Game Genie addresses are always in $8000-$FFFF, Pro Action Replay codes usually point into
+// RAM. Either way the patch is applied at read time and RAM itself is left alone.
+
+// each letter is a 4 bit value, in this order
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+/// A read of `address` returns `value`, if `compare` matches what's there
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub address: u16,
+    pub value: u8,
+    /// Only patch when the byte really at `address` is this one
+    pub compare: Option<u8>,
+}
+
+impl Patch {
+    /// Decode a Game Genie, Pro Action Replay or raw code, case and spaces don't matter
+    pub fn parse(code: &str) -> Option<Patch> {
+        let code = normalize(code);
+        if code.contains([':', '?']) {
+            return Self::parse_raw(&code);
+        }
+        match code.len() {
+            6 | 8 if code.bytes().all(|c| GAME_GENIE_LETTERS.contains(&c)) => {
+                Self::parse_game_genie(&code)
+            }
+            8 => Self::parse_action_replay(&code),
+            _ => None,
+        }
+    }
+
+    fn parse_game_genie(code: &str) -> Option<Patch> {
+        let n: Vec<u16> = code
+            .bytes()
+            .map(|c| GAME_GENIE_LETTERS.iter().position(|&l| l == c))
+            .map(|n| n.map(|n| n as u16))
+            .collect::<Option<_>>()?;
+        let address = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8);
+        // the last letter's high bit goes in the value, the 6th's moves to the compare
+        let last = if n.len() == 8 { n[7] } else { n[5] };
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (last & 8);
+        let compare =
+            (n.len() == 8).then(|| ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8));
+        Some(Patch {
+            address,
+            value: value as u8,
+            compare: compare.map(|compare| compare as u8),
+        })
+    }
+
+    fn parse_action_replay(code: &str) -> Option<Patch> {
+        let digits = u32::from_str_radix(code, 16).ok()?;
+        if digits >> 24 != 0 {
+            return None;
+        }
+        Some(Patch {
+            address: (digits >> 8) as u16,
+            value: digits as u8,
+            compare: None,
+        })
+    }
+
+    fn parse_raw(code: &str) -> Option<Patch> {
+        let hex = |text: &str, digits: usize| {
+            (!text.is_empty() && text.len() <= digits)
+                .then(|| u16::from_str_radix(text, 16).ok())
+                .flatten()
+        };
+        let (target, value) = code.split_once(':')?;
+        let (address, compare) = match target.split_once('?') {
+            Some((address, compare)) => (address, Some(hex(compare, 2)? as u8)),
+            None => (target, None),
+        };
+        Some(Patch {
+            address: hex(address, 4)?,
+            value: hex(value, 2)? as u8,
+            compare,
+        })
+    }
+
+    /// Whether a read of `address` is patched, `byte` being what's really there
+    pub fn matches(&self, address: u16, byte: u8) -> bool {
+        address == self.address && self.compare.is_none_or(|compare| compare == byte)
+    }
+}
+
+impl fmt::Display for Patch {
+    // the raw form
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.compare {
+            Some(compare) => write!(f, "{:04X}?{:02X}:{:02X}", self.address, compare, self.value),
+            None => write!(f, "{:04X}:{:02X}", self.address, self.value),
+        }
+    }
+}
+
+/// A code that isn't in any of the understood forms
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadCode(pub String);
+
+impl fmt::Display for BadCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} isn't a Game Genie, Pro Action Replay or address:value code",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for BadCode {}
+
+/// A code as it was entered and what it decodes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    /// Upper case, without spaces
+    pub code: String,
+    pub patch: Patch,
+    pub enabled: bool,
+}
+
+/// The cheats in use, looked up on every CPU read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+}
+
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `code`, enabled. Adding a code that's already there enables it.
+    pub fn add(&mut self, code: &str) -> Result<(), BadCode> {
+        let code = normalize(code);
+        let patch = Patch::parse(&code).ok_or_else(|| BadCode(code.clone()))?;
+        match self.cheats.iter_mut().find(|cheat| cheat.code == code) {
+            Some(cheat) => cheat.enabled = true,
+            None => self.cheats.push(Cheat {
+                code,
+                patch,
+                enabled: true,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Remove `code`, false if it wasn't there
+    pub fn remove(&mut self, code: &str) -> bool {
+        let code = normalize(code);
+        let count = self.cheats.len();
+        self.cheats.retain(|cheat| cheat.code != code);
+        self.cheats.len() != count
+    }
+
+    /// Turn `code` on or off, false if it isn't there
+    pub fn set_enabled(&mut self, code: &str, enabled: bool) -> bool {
+        let code = normalize(code);
+        match self.cheats.iter_mut().find(|cheat| cheat.code == code) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Flip `code` on or off, returning whether it's on now, None if it isn't there
+    pub fn toggle(&mut self, code: &str) -> Option<bool> {
+        let code = normalize(code);
+        let cheat = self.cheats.iter_mut().find(|cheat| cheat.code == code)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat.enabled)
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// What a read of `address` returns with the enabled cheats applied
+    pub fn apply(&self, address: u16, byte: u8) -> u8 {
+        // the first one added wins when several patch the same address
+        self.cheats
+            .iter()
+            .find(|cheat| cheat.enabled && cheat.patch.matches(address, byte))
+            .map_or(byte, |cheat| cheat.patch.value)
+    }
+
+    /// The codes as saved in the config file, disabled ones marked with a leading -
+    pub fn to_saved(&self) -> Vec<String> {
+        self.cheats
+            .iter()
+            .map(|cheat| {
+                if cheat.enabled {
+                    cheat.code.clone()
+                } else {
+                    format!("-{}", cheat.code)
+                }
+            })
+            .collect()
+    }
+
+    /// Add a code in the form `to_saved` writes
+    pub fn add_saved(&mut self, saved: &str) -> Result<(), BadCode> {
+        match saved.trim().strip_prefix('-') {
+            Some(code) => {
+                self.add(code)?;
+                self.set_enabled(code, false);
+                Ok(())
+            }
+            None => self.add(saved),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_game_genie() {
+        // Super Mario Bros. infinite lives
+        assert_eq!(
+            Patch::parse("SXIOPO"),
+            Some(Patch {
+                address: 0x91D9,
+                value: 0xAD,
+                compare: None
+            })
+        );
+        let patch = Patch::parse("yeuz ugaa").unwrap();
+        assert_eq!(patch.to_string(), "ACB3?00:07");
+        assert_eq!(Patch::parse("SXIOPQ"), None);
+    }
+
+    #[test]
+    fn decodes_action_replay_and_raw() {
+        assert_eq!(Patch::parse("00075A09"), Patch::parse("075A:09"));
+        assert_eq!(Patch::parse("075a:09").unwrap().address, 0x075A);
+        assert_eq!(
+            Patch::parse("91D9?AD:A5"),
+            Some(Patch {
+                address: 0x91D9,
+                value: 0xA5,
+                compare: Some(0xAD)
+            })
+        );
+        for bad in ["01075A09", "12345:00", "0000:100", ":00", "0000?:00", "GG"] {
+            assert_eq!(Patch::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn compare_values_guard_the_patch() {
+        let mut cheats = CheatList::new();
+        cheats.add("8000?11:22").unwrap();
+        assert_eq!(cheats.apply(0x8000, 0x11), 0x22);
+        assert_eq!(cheats.apply(0x8000, 0x33), 0x33);
+        assert_eq!(cheats.apply(0x8001, 0x11), 0x11);
+    }
+
+    #[test]
+    fn add_remove_and_toggle() {
+        let mut cheats = CheatList::new();
+        cheats.add("sxiopo").unwrap();
+        cheats.add("0300:05").unwrap();
+        assert_eq!(cheats.add("nope"), Err(BadCode("NOPE".to_string())));
+        assert_eq!(cheats.apply(0x0300, 0), 5);
+
+        assert_eq!(cheats.toggle("0300:05"), Some(false));
+        assert_eq!(cheats.apply(0x0300, 0), 0);
+        assert_eq!(cheats.to_saved(), ["SXIOPO", "-0300:05"]);
+
+        let mut saved = CheatList::new();
+        for code in cheats.to_saved() {
+            saved.add_saved(&code).unwrap();
+        }
+        assert_eq!(saved, cheats);
+
+        assert!(cheats.remove("SXIOPO"));
+        assert!(!cheats.remove("SXIOPO"));
+        assert_eq!(cheats.len(), 1);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::emulator::Emulator;
+use crate::memory_editor::AddressSpace;
+use crate::{load_image, RomError, RomImage};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+// Compatibility report: every rom in a directory run for a few seconds without a window, and
+// sorted by how far it got, so progress can be compared from one release to the next. From
+// worst to best a rom is
+//
+//   load failed         the file isn't a rom the loader understands
+//   unsupported mapper  the cartridge board isn't emulated
+//   crashes             the emulator panicked running it
+//   unknown opcode      the CPU stopped on an opcode it can't run
+//   boots               it ran the whole time but the screen stayed one colour
+//   renders             something was drawn
+//
+// The markdown is a table with a summary line above it. The JSON has the same rows:
+//
+//   {"format":"nesemu-compat","version":1,"seconds":5,"roms":[
+//    {"file":"smb.nes","result":"renders"},
+//    {"file":"mmc5.nes","result":"unsupported_mapper","detail":"mapper 5"}]}
+
+pub const FORMAT_VERSION: u32 = 1;
+const ROM_EXTENSIONS: [&str; 5] = ["nes", "unf", "unif", "fds", "zip"];
+
+/// How a report is written out
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
+
+impl ReportFormat {
+    pub fn parse(text: &str) -> Option<ReportFormat> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "markdown" | "md" => Some(ReportFormat::Markdown),
+            "json" => Some(ReportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How far a rom got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompatResult {
+    LoadFailed(String),
+    UnsupportedMapper(u16),
+    /// The panic message
+    Crashes(String),
+    UnknownOpcode {
+        opcode: u8,
+        pc: u16,
+    },
+    Boots,
+    Renders,
+}
+
+impl CompatResult {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompatResult::LoadFailed(_) => "load failed",
+            CompatResult::UnsupportedMapper(_) => "unsupported mapper",
+            CompatResult::Crashes(_) => "crashes",
+            CompatResult::UnknownOpcode { .. } => "unknown opcode",
+            CompatResult::Boots => "boots",
+            CompatResult::Renders => "renders",
+        }
+    }
+
+    pub fn detail(&self) -> String {
+        match self {
+            CompatResult::LoadFailed(why) | CompatResult::Crashes(why) => why.clone(),
+            CompatResult::UnsupportedMapper(mapper) => format!("mapper {}", mapper),
+            CompatResult::UnknownOpcode { opcode, pc } => {
+                format!("${:02X} at ${:04X}", opcode, pc)
+            }
+            CompatResult::Boots | CompatResult::Renders => String::new(),
+        }
+    }
+
+    // best to worst, the order of the summary
+    fn rank(&self) -> usize {
+        match self {
+            CompatResult::Renders => 0,
+            CompatResult::Boots => 1,
+            CompatResult::UnknownOpcode { .. } => 2,
+            CompatResult::Crashes(_) => 3,
+            CompatResult::UnsupportedMapper(_) => 4,
+            CompatResult::LoadFailed(_) => 5,
+        }
+    }
+}
+
+/// One rom's line of the report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// File name, without the directory
+    pub file: String,
+    pub result: CompatResult,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub seconds: u32,
+    pub entries: Vec<Entry>,
+}
+
+impl Report {
+    /// Run every rom in `dir` for `seconds`, in file name order. Disks are skipped as load
+    /// failures without `fds_bios`.
+    pub fn run(dir: &Path, seconds: u32, fds_bios: Option<&[u8]>) -> io::Result<Report> {
+        let mut paths: Vec<_> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        paths.retain(|path| {
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            path.is_file() && ROM_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        });
+        paths.sort();
+        let entries = paths
+            .iter()
+            .map(|path| Entry {
+                file: path.file_name().unwrap().to_string_lossy().into_owned(),
+                result: check(path, seconds, fds_bios),
+            })
+            .collect();
+        Ok(Report { seconds, entries })
+    }
+
+    /// How many roms got each result, best first, leaving out results none got
+    pub fn counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(usize, &'static str, usize)> = Vec::new();
+        for entry in &self.entries {
+            let rank = entry.result.rank();
+            match counts.iter_mut().find(|(r, _, _)| *r == rank) {
+                Some((_, _, count)) => *count += 1,
+                None => counts.push((rank, entry.result.name(), 1)),
+            }
+        }
+        counts.sort_unstable();
+        counts
+            .into_iter()
+            .map(|(_, name, count)| (name, count))
+            .collect()
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let summary: Vec<String> = self
+            .counts()
+            .iter()
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        let mut text = format!(
+            "# nesemu compatibility report\n\n{} roms, {} seconds each: {}\n\n",
+            self.entries.len(),
+            self.seconds,
+            summary.join(", ")
+        );
+        text += "| ROM | Result | Detail |\n|---|---|---|\n";
+        for entry in &self.entries {
+            // a | would end the cell early
+            let cell = |text: &str| text.replace('|', "\\|");
+            let _ = writeln!(
+                text,
+                "| {} | {} | {} |",
+                cell(&entry.file),
+                entry.result.name(),
+                cell(&entry.result.detail())
+            );
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"format\":\"nesemu-compat\",\"version\":{},\"seconds\":{},\"roms\":[",
+            FORMAT_VERSION, self.seconds
+        );
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"file\":{},\"result\":\"{}\"",
+                json_string(&entry.file),
+                entry.result.name().replace(' ', "_")
+            );
+            let detail = entry.result.detail();
+            if !detail.is_empty() {
+                let _ = write!(json, ",\"detail\":{}", json_string(&detail));
+            }
+            json.push('}');
+        }
+        json += "]}\n";
+        json
+    }
+
+    pub fn export(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Markdown => self.to_markdown(),
+            ReportFormat::Json => self.to_json(),
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            '\n' => json += "\\n",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Load and run the rom at `path` for `seconds` and see how far it gets
+pub fn check(path: &Path, seconds: u32, fds_bios: Option<&[u8]>) -> CompatResult {
+    let image = match load_image(&path.to_string_lossy()) {
+        Ok(image) => image,
+        Err(e) => return load_failure(e),
+    };
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut emulator = Emulator::new();
+        emulator.set_decode_cache(true);
+        let loaded = match &image {
+            RomImage::Cartridge(rom) => emulator.load_cartridge(rom),
+            RomImage::Disk(disk) => match fds_bios {
+                Some(bios) => emulator.load_disk(disk, bios),
+                None => return CompatResult::LoadFailed("FDS disk, no BIOS".to_string()),
+            },
+        };
+        if let Err(e) = loaded {
+            return load_failure(e);
+        }
+        run(&mut emulator, seconds)
+    }));
+    run.unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        CompatResult::Crashes(message)
+    })
+}
+
+fn load_failure(e: RomError) -> CompatResult {
+    match e {
+        RomError::UnsupportedMapper(mapper) => CompatResult::UnsupportedMapper(mapper),
+        e => CompatResult::LoadFailed(e.to_string()),
+    }
+}
+
+fn run(emulator: &mut Emulator, seconds: u32) -> CompatResult {
+    let frames = (seconds as f64 * emulator.region().frames_per_second()).round() as u32;
+    let mut rendered = false;
+    for _ in 0..frames {
+        let frame = emulator.run_frame();
+        let pixels = frame.pixels();
+        rendered |= pixels.iter().any(|&pixel| pixel != pixels[0]);
+        emulator.clear_audio_samples();
+        if let Some(pc) = emulator.cpu().jammed() {
+            let opcode = emulator.peek_memory(AddressSpace::Cpu, pc);
+            return CompatResult::UnknownOpcode { opcode, pc };
+        }
+    }
+    if rendered {
+        CompatResult::Renders
+    } else {
+        CompatResult::Boots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_a_directory() {
+        let report = Report::run(Path::new("test-bin"), 1, None).unwrap();
+        let result = |file: &str| {
+            report
+                .entries
+                .iter()
+                .find(|entry| entry.file == file)
+                .map(|entry| entry.result.clone())
+        };
+        assert_eq!(result("full_nes_palette.nes"), Some(CompatResult::Renders));
+        // only files that look like roms
+        assert_eq!(result("non-nes"), None);
+        assert!(report.entries.windows(2).all(|w| w[0].file < w[1].file));
+        let total: usize = report.counts().iter().map(|(_, count)| count).sum();
+        assert_eq!(total, report.entries.len());
+    }
+
+    #[test]
+    fn writes_markdown_and_json() {
+        let report = Report {
+            seconds: 5,
+            entries: vec![
+                Entry {
+                    file: "a|b.nes".to_string(),
+                    result: CompatResult::Renders,
+                },
+                Entry {
+                    file: "mmc5.nes".to_string(),
+                    result: CompatResult::UnsupportedMapper(5),
+                },
+                Entry {
+                    file: "jam.nes".to_string(),
+                    result: CompatResult::UnknownOpcode {
+                        opcode: 0x02,
+                        pc: 0x8004,
+                    },
+                },
+            ],
+        };
+        let markdown = report.to_markdown();
+        assert!(markdown
+            .contains("3 roms, 5 seconds each: 1 renders, 1 unknown opcode, 1 unsupported mapper"));
+        assert!(markdown.contains("| a\\|b.nes | renders |  |"));
+        assert!(markdown.contains("| jam.nes | unknown opcode | $02 at $8004 |"));
+        assert_eq!(
+            report.to_json(),
+            "{\"format\":\"nesemu-compat\",\"version\":1,\"seconds\":5,\"roms\":[\
+             {\"file\":\"a|b.nes\",\"result\":\"renders\"},\
+             {\"file\":\"mmc5.nes\",\"result\":\"unsupported_mapper\",\"detail\":\"mapper 5\"},\
+             {\"file\":\"jam.nes\",\"result\":\"unknown_opcode\",\"detail\":\"$02 at $8004\"}]}\n"
+        );
+        assert_eq!(json_string("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+}
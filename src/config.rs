@@ -0,0 +1,510 @@
+use crate::audio::AudioConfig;
+use crate::cheats::CheatList;
+use crate::power::RamPattern;
+use crate::ppu::SpriteOverflow;
+use crate::region::Region;
+use crate::rewind::RewindConfig;
+use crate::scaling::{ScaleMode, VideoConfig};
+use crate::storage::SaveNaming;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use toml::{Table, Value};
+
+// Settings for the binary, kept in a TOML file:
+//
+//   [emulation]
+//   region = "auto"          # or "ntsc", "pal", "dendy"
+//   ram = "zeros"            # power on RAM: "zeros", "ones", "alternating" or "random"
+//   seed = 0                 # for random RAM and jitter
+//   jitter = false           # chips start out of step by a random amount, as on hardware
+//   sprite_overflow = "hardware"   # or "correct", without the 2C02's buggy sprite search
+//
+//   [video]
+//   scale = 3                # 1 to 6, or "fit"
+//   fullscreen = false
+//   palette = "smooth.pal"   # left out for the built in one
+//
+//   [audio]
+//   sample_rate = 48000
+//   latency_ms = 50
+//
+//   [paths]
+//   roms = "roms"            # where to look for roms given without a directory
+//   saves = "states"         # battery saves, save states and screenshots
+//   naming = "filename"      # or "hash", what each game's saves are named after
+//
+//   [state]
+//   slot = 0
+//
+//   [rewind]
+//   seconds = 10             # 0 turns rewind off
+//   interval = 2             # frames between snapshots
+//
+//   [input.1]
+//   a = ["key:X", "pad:b"]
+//
+//   [cheats]
+//   "Super Mario Bros" = ["SXIOPO", "-0079:08"]   # by rom file name, - for switched off
+//
+// `[input.N]` tables hold player N's bindings in the input bindings format, leaving them all out
+// uses the default bindings. Unknown tables and keys are ignored.
+
+pub const CONFIG_FILE: &str = "nesemu.toml";
+
+// The file as it's laid out, with every key optional. `Config` is made from it, checking the
+// values serde can't on the way.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct File {
+    emulation: Emulation,
+    video: Video,
+    audio: Audio,
+    paths: Paths,
+    state: State,
+    rewind: Rewind,
+    // player number to button to bindings, and game to codes, in file order
+    #[serde(skip_serializing_if = "Table::is_empty")]
+    input: Table,
+    #[serde(skip_serializing_if = "Table::is_empty")]
+    cheats: Table,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Emulation {
+    region: Option<String>,
+    ram: Option<String>,
+    // TOML integers are signed, seeds past i64::MAX are written wrapped around
+    seed: Option<i64>,
+    jitter: Option<bool>,
+    sprite_overflow: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Video {
+    // a number or "fit"
+    scale: Option<Value>,
+    fullscreen: Option<bool>,
+    palette: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Audio {
+    sample_rate: Option<u32>,
+    latency_ms: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Paths {
+    roms: Option<String>,
+    saves: Option<String>,
+    naming: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct State {
+    slot: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Rewind {
+    seconds: Option<u32>,
+    interval: Option<u32>,
+}
+
+/// Everything the binary can be configured with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// None runs each rom in the region it asks for
+    pub region: Option<Region>,
+    /// What RAM holds at power on
+    pub ram: RamPattern,
+    /// Seed for everything random, see `Emulator::set_seed`
+    pub seed: u64,
+    /// See `PowerOnConfig::jitter`
+    pub jitter: bool,
+    pub sprite_overflow: SpriteOverflow,
+    pub video: VideoConfig,
+    /// .pal file to use instead of the built in palette
+    pub palette: Option<PathBuf>,
+    pub audio: AudioConfig,
+    /// Where to look for roms given without a directory
+    pub rom_directory: Option<PathBuf>,
+    /// Where battery saves, save states and screenshots go, None for the binary's default
+    pub save_directory: Option<PathBuf>,
+    pub save_naming: SaveNaming,
+    /// Save state slot last used
+    pub save_slot: u32,
+    pub rewind: RewindConfig,
+    /// ("player.button", binding) pairs in the input bindings format, empty for the defaults
+    pub input: Vec<(String, String)>,
+    /// Cheats for each game, by the rom's file name without the extension
+    pub cheats: Vec<(String, CheatList)>,
+}
+
+fn wrong_type(table: &str, key: &str, expected: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("config {}.{} should be {}", table, key, expected),
+    )
+}
+
+// one binding or a list of them
+fn bindings(value: &Value) -> Option<Vec<&str>> {
+    match value {
+        Value::String(binding) => Some(vec![binding.as_str()]),
+        Value::Array(values) => values.iter().map(Value::as_str).collect(),
+        _ => None,
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> io::Result<Config> {
+        let file: File = toml::from_str(text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("config: {}", e)))?;
+        let mut config = Config::default();
+
+        let emulation = file.emulation;
+        if let Some(region) = emulation.region {
+            config.region = match region.as_str() {
+                "auto" => None,
+                name => Some(Region::parse(name).ok_or_else(|| {
+                    wrong_type("emulation", "region", "auto, ntsc, pal or dendy")
+                })?),
+            };
+        }
+        if let Some(seed) = emulation.seed {
+            config.seed = seed as u64;
+        }
+        if let Some(jitter) = emulation.jitter {
+            config.jitter = jitter;
+        }
+        if let Some(mode) = emulation.sprite_overflow {
+            config.sprite_overflow = SpriteOverflow::parse(&mode)
+                .ok_or_else(|| wrong_type("emulation", "sprite_overflow", "hardware or correct"))?;
+        }
+        if let Some(ram) = emulation.ram {
+            config.ram = RamPattern::parse(&ram).ok_or_else(|| {
+                wrong_type("emulation", "ram", "zeros, ones, alternating or random")
+            })?;
+        }
+
+        let bad_scale = || wrong_type("video", "scale", "1 to 6 or \"fit\"");
+        match file.video.scale {
+            None => {}
+            Some(Value::Integer(scale)) => {
+                config.video.scale = ScaleMode::parse(&scale.to_string()).ok_or_else(bad_scale)?
+            }
+            Some(Value::String(scale)) if scale == "fit" => config.video.scale = ScaleMode::Fit,
+            Some(_) => return Err(bad_scale()),
+        }
+        if let Some(fullscreen) = file.video.fullscreen {
+            config.video.fullscreen = fullscreen;
+        }
+        config.palette = file.video.palette.map(PathBuf::from);
+
+        if let Some(sample_rate) = file.audio.sample_rate {
+            config.audio.sample_rate = sample_rate;
+        }
+        if let Some(latency_ms) = file.audio.latency_ms {
+            config.audio.latency_ms = latency_ms;
+        }
+
+        config.rom_directory = file.paths.roms.map(PathBuf::from);
+        config.save_directory = file.paths.saves.map(PathBuf::from);
+        if let Some(naming) = file.paths.naming {
+            config.save_naming = SaveNaming::parse(&naming)
+                .ok_or_else(|| wrong_type("paths", "naming", "\"filename\" or \"hash\""))?;
+        }
+        if let Some(slot) = file.state.slot {
+            config.save_slot = slot;
+        }
+        if let Some(seconds) = file.rewind.seconds {
+            config.rewind.seconds = seconds;
+        }
+        if let Some(interval) = file.rewind.interval {
+            if interval == 0 {
+                return Err(wrong_type("rewind", "interval", "at least 1"));
+            }
+            config.rewind.interval = interval;
+        }
+
+        for player in 1..=crate::input::PLAYERS {
+            let Some(buttons) = file.input.get(&player.to_string()) else {
+                continue;
+            };
+            let table = format!("input.{}", player);
+            let Some(buttons) = buttons.as_table() else {
+                return Err(wrong_type(
+                    "input",
+                    &player.to_string(),
+                    "a table of buttons",
+                ));
+            };
+            for (button, value) in buttons {
+                let bindings = bindings(value)
+                    .ok_or_else(|| wrong_type(&table, button, "a list of bindings"))?;
+                for binding in bindings {
+                    config
+                        .input
+                        .push((format!("{}.{}", player, button), binding.to_string()));
+                }
+            }
+        }
+        for (game, value) in &file.cheats {
+            let invalid = || wrong_type("cheats", game, "a list of cheat codes");
+            let codes = value.as_array().ok_or_else(invalid)?;
+            let mut cheats = CheatList::new();
+            for code in codes {
+                let code = code.as_str().ok_or_else(invalid)?;
+                cheats.add_saved(code).map_err(|_| invalid())?;
+            }
+            config.cheats.push((game.to_string(), cheats));
+        }
+        Ok(config)
+    }
+
+    /// The cheats saved for `game`
+    pub fn cheats_for(&self, game: &str) -> Option<&CheatList> {
+        self.cheats
+            .iter()
+            .find(|(name, _)| name == game)
+            .map(|(_, cheats)| cheats)
+    }
+
+    /// Replace the cheats saved for `game`, leaving it out once there are none
+    pub fn set_cheats(&mut self, game: &str, cheats: CheatList) {
+        self.cheats.retain(|(name, _)| name != game);
+        if !cheats.is_empty() {
+            self.cheats.push((game.to_string(), cheats));
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        // gather each button's bindings into one array
+        let mut input = Table::new();
+        for (target, binding) in &self.input {
+            let Some((player, button)) = target.split_once('.') else {
+                continue;
+            };
+            let bindings = input
+                .entry(player)
+                .or_insert_with(|| Table::new().into())
+                .as_table_mut()
+                .and_then(|buttons| {
+                    buttons
+                        .entry(button)
+                        .or_insert_with(|| Vec::<Value>::new().into())
+                        .as_array_mut()
+                });
+            if let Some(bindings) = bindings {
+                bindings.push(binding.clone().into());
+            }
+        }
+        let file = File {
+            emulation: Emulation {
+                region: Some(
+                    self.region
+                        .map_or("auto", |region| region.name())
+                        .to_string(),
+                ),
+                ram: Some(self.ram.name().to_string()),
+                seed: Some(self.seed as i64),
+                jitter: Some(self.jitter),
+                sprite_overflow: Some(self.sprite_overflow.name().to_string()),
+            },
+            video: Video {
+                scale: Some(match self.video.scale {
+                    ScaleMode::Integer(scale) => Value::Integer(scale as i64),
+                    ScaleMode::Fit => "fit".into(),
+                }),
+                fullscreen: Some(self.video.fullscreen),
+                palette: self.palette.as_deref().map(path_string),
+            },
+            audio: Audio {
+                sample_rate: Some(self.audio.sample_rate),
+                latency_ms: Some(self.audio.latency_ms),
+            },
+            paths: Paths {
+                roms: self.rom_directory.as_deref().map(path_string),
+                saves: self.save_directory.as_deref().map(path_string),
+                naming: Some(self.save_naming.name().to_string()),
+            },
+            state: State {
+                slot: Some(self.save_slot),
+            },
+            rewind: Rewind {
+                seconds: Some(self.rewind.seconds),
+                interval: Some(self.rewind.interval),
+            },
+            input,
+            cheats: self
+                .cheats
+                .iter()
+                .map(|(game, cheats)| (game.clone(), cheats.to_saved().into()))
+                .collect(),
+        };
+        toml::to_string(&file).expect("settings are all plain TOML values")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_toml())
+    }
+
+    /// Where to find `rom`: as given if it exists or has a directory in it, otherwise in the
+    /// rom directory
+    pub fn find_rom(&self, rom: &str) -> PathBuf {
+        let path = Path::new(rom);
+        match &self.rom_directory {
+            Some(directory) if !path.exists() && path.parent() == Some(Path::new("")) => {
+                directory.join(path)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_any_toml() {
+        let config = Config::from_toml(
+            "emulation.seed = 7\n\
+             video = { scale = 2, fullscreen = true }\n\
+             [input.1]\n\
+             a = [\n  \"key:X\",\n  \"pad:b\", # trailing comma\n]\n",
+        )
+        .unwrap();
+        assert_eq!(config.seed, 7);
+        assert_eq!(config.video.scale, ScaleMode::Integer(2));
+        assert!(config.video.fullscreen);
+        assert_eq!(config.input.len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_toml() {
+        for text in [
+            "key",
+            "key = ",
+            "key = \"open",
+            "[table",
+            "key = 1 2",
+            "key = yes",
+        ] {
+            let error = Config::from_toml(text).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", text);
+        }
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut cheats = CheatList::new();
+        cheats.add("SXIOPO").unwrap();
+        cheats.add("0079:08").unwrap();
+        cheats.set_enabled("0079:08", false);
+        let config = Config {
+            region: Some(Region::Pal),
+            ram: RamPattern::Random,
+            seed: 99,
+            jitter: true,
+            sprite_overflow: SpriteOverflow::Correct,
+            video: VideoConfig {
+                scale: ScaleMode::Fit,
+                fullscreen: true,
+            },
+            palette: Some(PathBuf::from("smooth.pal")),
+            audio: AudioConfig {
+                sample_rate: 44100,
+                latency_ms: 80,
+            },
+            rom_directory: Some(PathBuf::from("roms")),
+            save_directory: Some(PathBuf::from("saves")),
+            save_naming: SaveNaming::Hash,
+            save_slot: 3,
+            rewind: RewindConfig {
+                seconds: 30,
+                interval: 4,
+            },
+            input: vec![
+                ("1.a".to_string(), "key:X".to_string()),
+                ("1.a".to_string(), "pad:b".to_string()),
+                ("2.start".to_string(), "key:Y".to_string()),
+            ],
+            cheats: vec![("Super Mario Bros".to_string(), cheats)],
+        };
+        let text = config.to_toml();
+        assert!(
+            text.contains("[input.1]\na = [\"key:X\", \"pad:b\"]\n"),
+            "{}",
+            text
+        );
+        assert_eq!(Config::from_toml(&text).unwrap(), config);
+        assert_eq!(
+            Config::from_toml(&Config::default().to_toml()).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn missing_keys_use_defaults() {
+        let config = Config::from_toml("[video]\nscale = 2\n[other]\nkey = 1\n").unwrap();
+        assert_eq!(config.video.scale, ScaleMode::Integer(2));
+        assert_eq!(config.audio, AudioConfig::default());
+        assert_eq!(config.region, None);
+    }
+
+    #[test]
+    fn wrong_types() {
+        for text in [
+            "[video]\nscale = 9",
+            "[video]\nfullscreen = \"yes\"",
+            "[audio]\nlatency_ms = -5",
+            "[emulation]\nregion = \"secam\"",
+            "[rewind]\ninterval = 0",
+            "[emulation]\nram = \"noise\"",
+            "[emulation]\nsprite_overflow = \"off\"",
+            "[input.1]\na = [1]",
+            "[cheats]\ngame = [\"NOTACODE\"]",
+            "[cheats]\ngame = \"SXIOPO\"",
+            "[paths]\nnaming = \"crc\"",
+        ] {
+            assert!(Config::from_toml(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn finds_roms_in_the_rom_directory() {
+        let config = Config {
+            rom_directory: Some(PathBuf::from("test-bin")),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.find_rom("nestest.nes"),
+            PathBuf::from("test-bin/nestest.nes")
+        );
+        assert_eq!(
+            config.find_rom("other/nestest.nes"),
+            PathBuf::from("other/nestest.nes")
+        );
+        assert_eq!(
+            Config::default().find_rom("nestest.nes"),
+            PathBuf::from("nestest.nes")
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use crate::disasm::{bank_origin, disassemble_bank};
+use crate::mapper::PRG_BANK_SIZE;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+// Code coverage: which bytes of PRG ROM have run as part of an instruction, so test rom
+// authors and rom hackers can see the paths a session never reached. Coverage is kept by
+// offset into PRG ROM rather than by CPU address, so code in a bank that's switched in and out
+// is told apart from whatever else gets mapped at the same addresses. Code run from RAM isn't
+// covered.
+//
+// The text map lists the covered stretches by PRG offset and the CPU address of the bank they
+// were disassembled at:
+//
+//   ; nesemu coverage, 1834 of 16384 bytes (11.19%)
+//   000004-00009F  C004-C09F
+//
+// The JSON carries the same stretches, the ends inclusive:
+//
+//   {"format":"nesemu-coverage","version":1,"prg_size":16384,"covered":1834,
+//    "ranges":[[4,159]]}
+//
+// The annotated disassembly is each bank's disassembly with every line of code marked `+` if
+// it ran and `-` if it didn't. Data lines aren't marked.
+
+pub const FORMAT_VERSION: u32 = 1;
+
+/// How a coverage map is written out
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum CoverageFormat {
+    /// The covered stretches, one to a line
+    #[default]
+    Text,
+    Json,
+    /// Every bank disassembled, code marked with whether it ran
+    Disassembly,
+}
+
+impl CoverageFormat {
+    pub fn parse(text: &str) -> Option<CoverageFormat> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(CoverageFormat::Text),
+            "json" => Some(CoverageFormat::Json),
+            "disasm" => Some(CoverageFormat::Disassembly),
+            _ => None,
+        }
+    }
+}
+
+// what a byte of PRG ROM was run as
+const OPCODE: u8 = 1;
+const OPERAND: u8 = 2;
+
+/// The PRG ROM bytes that have run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    bytes: Vec<u8>,
+}
+
+impl Coverage {
+    /// Nothing covered yet of `prg_size` bytes of PRG ROM
+    pub fn new(prg_size: usize) -> Self {
+        Coverage {
+            bytes: vec![0; prg_size],
+        }
+    }
+
+    pub fn prg_size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// An instruction `length` bytes long ran from `offset` into PRG ROM
+    pub fn mark(&mut self, offset: usize, length: usize) {
+        for (i, byte) in self.bytes.iter_mut().skip(offset).take(length).enumerate() {
+            *byte |= if i == 0 { OPCODE } else { OPERAND };
+        }
+    }
+
+    /// Whether the byte at `offset` ran as part of an instruction
+    pub fn is_covered(&self, offset: usize) -> bool {
+        self.bytes.get(offset).is_some_and(|&byte| byte != 0)
+    }
+
+    /// Whether an instruction started at `offset`
+    pub fn is_instruction(&self, offset: usize) -> bool {
+        self.bytes
+            .get(offset)
+            .is_some_and(|&byte| byte & OPCODE != 0)
+    }
+
+    /// How many bytes have run
+    pub fn covered(&self) -> usize {
+        self.bytes.iter().filter(|&&byte| byte != 0).count()
+    }
+
+    /// The covered stretches of PRG ROM, first and last offset of each
+    pub fn ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for offset in (0..self.bytes.len()).filter(|&offset| self.is_covered(offset)) {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == offset => *last = offset,
+                _ => ranges.push((offset, offset)),
+            }
+        }
+        ranges
+    }
+
+    fn percent(&self) -> f64 {
+        self.covered() as f64 * 100.0 / self.prg_size().max(1) as f64
+    }
+
+    // where `offset` is in the CPU address space when its bank is disassembled
+    fn address(&self, offset: usize) -> u16 {
+        let banks = self.prg_size().div_ceil(PRG_BANK_SIZE);
+        let bank = offset / PRG_BANK_SIZE;
+        bank_origin(bank, banks).wrapping_add((offset % PRG_BANK_SIZE) as u16)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "; nesemu coverage, {} of {} bytes ({:.2}%)\n",
+            self.covered(),
+            self.prg_size(),
+            self.percent()
+        );
+        for (first, last) in self.ranges() {
+            let _ = writeln!(
+                text,
+                "{:06X}-{:06X}  {:04X}-{:04X}",
+                first,
+                last,
+                self.address(first),
+                self.address(last)
+            );
+        }
+        text
+    }
+
+    pub fn write_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"format\":\"nesemu-coverage\",\"version\":{},\"prg_size\":{},\"covered\":{},\"ranges\":[",
+            FORMAT_VERSION,
+            self.prg_size(),
+            self.covered()
+        )?;
+        for (i, (first, last)) in self.ranges().into_iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "[{},{}]", first, last)?;
+        }
+        writeln!(out, "]}}")
+    }
+
+    /// The map in `format`, `prg` being the PRG ROM it covers, for the disassembly
+    pub fn export(&self, format: CoverageFormat, prg: &[u8]) -> String {
+        match format {
+            CoverageFormat::Text => self.to_text(),
+            CoverageFormat::Json => {
+                let mut json = Vec::new();
+                // writing to a Vec can't fail
+                let _ = self.write_json(&mut json);
+                String::from_utf8_lossy(&json).into_owned()
+            }
+            CoverageFormat::Disassembly => self.annotated_disassembly(prg),
+        }
+    }
+
+    /// Every bank of `prg` disassembled, code marked with whether it ran
+    pub fn annotated_disassembly(&self, prg: &[u8]) -> String {
+        let banks = prg.len().div_ceil(PRG_BANK_SIZE);
+        let mut text = String::new();
+        for (bank, code) in prg.chunks(PRG_BANK_SIZE).enumerate() {
+            let origin = bank_origin(bank, banks);
+            let disassembly = disassemble_bank(code, origin);
+            let _ = writeln!(text, "; bank {} at ${:04X}", bank, origin);
+            for line in &disassembly.lines {
+                if let Some(label) = disassembly.label(line.address) {
+                    let _ = writeln!(text, "{}:", label);
+                }
+                let offset = bank * PRG_BANK_SIZE + line.address.wrapping_sub(origin) as usize;
+                let mark = if line.text.starts_with('.') {
+                    ' '
+                } else if self.is_covered(offset) {
+                    '+'
+                } else {
+                    '-'
+                };
+                let _ = writeln!(text, "{} {}", mark, line);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_of_covered_bytes() {
+        let mut coverage = Coverage::new(PRG_BANK_SIZE);
+        coverage.mark(4, 3);
+        coverage.mark(7, 1);
+        coverage.mark(0x100, 2);
+        assert_eq!(coverage.ranges(), [(4, 7), (0x100, 0x101)]);
+        assert_eq!(coverage.covered(), 6);
+        assert!(coverage.is_instruction(4) && !coverage.is_instruction(5));
+        assert!(coverage.is_covered(5));
+        // NROM-128's one bank sits at $C000
+        assert_eq!(
+            coverage.to_text(),
+            "; nesemu coverage, 6 of 16384 bytes (0.04%)\n\
+             000004-000007  C004-C007\n\
+             000100-000101  C100-C101\n"
+        );
+        let mut json = Vec::new();
+        coverage.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"format\":\"nesemu-coverage\",\"version\":1,\"prg_size\":16384,\"covered\":6,\
+             \"ranges\":[[4,7],[256,257]]}\n"
+        );
+    }
+
+    #[test]
+    fn disassembly_marks_what_ran() {
+        let mut prg = vec![0xEA; PRG_BANK_SIZE];
+        // a branch over an instruction that never runs, then an endless loop
+        prg[..7].copy_from_slice(&[0xB0, 0x01, 0xE8, 0x4C, 0x03, 0xC0, 0x00]);
+        prg[PRG_BANK_SIZE - 4..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0]);
+        let mut coverage = Coverage::new(PRG_BANK_SIZE);
+        coverage.mark(0, 2);
+        coverage.mark(3, 3);
+        let text = coverage.annotated_disassembly(&prg);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "; bank 0 at $C000");
+        assert!(lines[2].starts_with("+ C000  B0 01     BCS"), "{}", text);
+        assert!(lines[3].starts_with("- C002  E8        INX"), "{}", text);
+        assert!(lines[5].starts_with("+ C003  4C 03 C0  JMP"), "{}", text);
+    }
+}
@@ -1,13 +1,77 @@
-use crate::instructions::{AddressingMode, CurrentInstruction, Instructions};
-use crate::memory::{Bus, Memory};
-use crate::NesRom;
-use std::io;
-use std::process::exit;
+// nothing here should take down the program embedding the emulator
+#![cfg_attr(
+    not(test),
+    deny(clippy::panic, clippy::unwrap_used, clippy::expect_used)
+)]
+
+use crate::breakpoints::{BadCondition, Breakpoints, Condition};
+use crate::call_stack::{CallStack, FrameKind, StackFrame};
+use crate::decode_cache::{DecodeCache, Decoded};
+use crate::events::Event;
+use crate::fds::{Fds, FdsDisk};
+use crate::instructions::{
+    has_page_cross_penalty, AddressingMode, CurrentInstruction, Instructions, CYCLES,
+};
+use crate::jam_report::{Executed, InstructionHistory};
+use crate::logging;
+use crate::mapper;
+use crate::memory::{Bus, Memory, STACK_ADDR_LO};
+use crate::power::PowerOnConfig;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::trace::TraceLogger;
+use crate::watches::{WatchTrigger, Watches};
+use crate::{combine_bytes_to_u16, NesRom, RomError};
+use log::warn;
+use std::fmt;
 
 pub const CLOCK_RATE: u32 = 21441960;
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+const INTERRUPT_CYCLES: u32 = 7;
+
+/// Why the CPU stopped. It stays stopped until reset or power cycle, see `NesCpu::jammed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuError {
+    /// A JAM opcode, or one that isn't implemented yet
+    Jammed { opcode: u8, pc: u16 },
+    /// An instruction with an addressing mode it can't take. Decoding never gives these, only
+    /// setting `current` by hand does.
+    BadOperands {
+        op: Instructions,
+        mode: AddressingMode,
+        pc: u16,
+    },
+}
+
+impl CpuError {
+    /// Where the instruction that stopped the CPU is
+    pub fn pc(&self) -> u16 {
+        match self {
+            CpuError::Jammed { pc, .. } | CpuError::BadOperands { pc, .. } => *pc,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::Jammed { opcode, pc } => {
+                write!(f, "jammed on ${:02X} at ${:04X}", opcode, pc)
+            }
+            CpuError::BadOperands { op, mode, pc } => {
+                write!(f, "{:?} can't take {:?} at ${:04X}", op, mode, pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
 
 // https://www.nesdev.org/wiki/2A03
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Registers {
     pub pc: u16,
     sp: u8,
@@ -18,6 +82,32 @@ pub struct Registers {
 }
 
 impl Registers {
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.sp = sp;
+    }
+
+    pub fn idy(&self) -> u8 {
+        self.idy
+    }
+
+    pub fn set_idy(&mut self, idy: u8) {
+        self.idy = idy;
+    }
+
+    /// The flags as PHP pushes them, but without B
+    pub fn status(&self) -> u8 {
+        self.flags.as_byte()
+    }
+
+    /// Set the flags from a byte as PLP pulls it, B and bit 5 are ignored
+    pub fn set_status(&mut self, status: u8) {
+        self.flags.set_byte(status);
+    }
+
     fn new() -> Self {
         Registers {
             pc: 0,
@@ -30,6 +120,7 @@ impl Registers {
     }
 }
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct CPUFlags {
     carry: bool,
     zero: bool,
@@ -92,6 +183,30 @@ pub struct NesCpu {
     pub reg: Registers,
     pub current: CurrentInstruction,
     pub tick: usize,
+    // cycles on top of the base count for the current instruction (page crosses, branches)
+    extra_cycles: u32,
+    // traces each instruction as it runs
+    tracer: Option<TraceLogger>,
+    power_on: PowerOnConfig,
+    // why and where the CPU stopped on a JAM or an opcode it doesn't know
+    jammed: Option<CpuError>,
+    breakpoints: Breakpoints,
+    // where a breakpoint stopped the CPU, so it runs that instruction next instead of stopping
+    // again
+    resume_at: Option<u16>,
+    call_stack: Option<Box<CallStack>>,
+    watches: Watches,
+    // the last instructions run, for jam reports
+    history: Option<Box<InstructionHistory>>,
+    decode_cache: Option<Box<DecodeCache>>,
+    // the current instruction's operand bytes, when they came from the decode cache
+    operands: Option<[u8; 2]>,
+}
+
+impl Default for NesCpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NesCpu {
@@ -101,6 +216,17 @@ impl NesCpu {
             reg: Registers::new(),
             current: CurrentInstruction::new(),
             tick: 0,
+            extra_cycles: 0,
+            tracer: None,
+            power_on: PowerOnConfig::default(),
+            jammed: None,
+            breakpoints: Breakpoints::new(),
+            resume_at: None,
+            call_stack: None,
+            watches: Watches::new(),
+            history: None,
+            decode_cache: None,
+            operands: None,
         }
     }
     pub fn new_from_bytes(bytes: &[u8]) -> Self {
@@ -109,19 +235,36 @@ impl NesCpu {
             reg: Registers::new(),
             current: CurrentInstruction::new(),
             tick: 0,
+            extra_cycles: 0,
+            tracer: None,
+            power_on: PowerOnConfig::default(),
+            jammed: None,
+            breakpoints: Breakpoints::new(),
+            resume_at: None,
+            call_stack: None,
+            watches: Watches::new(),
+            history: None,
+            decode_cache: None,
+            operands: None,
         };
         cpu.load_bytes(bytes);
         cpu
     }
 
     /// Gets the next byte after the current instruction
-    pub fn next_byte(&self) -> u8 {
-        self.memory.read_byte(self.reg.pc + 1)
+    pub fn next_byte(&mut self) -> u8 {
+        match self.operands {
+            Some([low, _]) => low,
+            None => self.memory.read_byte(self.reg.pc.wrapping_add(1)),
+        }
     }
 
     /// Gets the next word after the current instruction
-    pub fn next_word(&self) -> u16 {
-        self.memory.read_word(self.reg.pc + 1)
+    pub fn next_word(&mut self) -> u16 {
+        match self.operands {
+            Some(operands) => u16::from_le_bytes(operands),
+            None => self.memory.read_word(self.reg.pc.wrapping_add(1)),
+        }
     }
 
     fn set_interrupts_disabled(&mut self, status: bool) {
@@ -145,8 +288,9 @@ impl NesCpu {
     }
 
     fn push_stack(&mut self, data: u8) {
-        self.memory.write_byte(self.reg.sp as u16 + 0x100, data);
-        self.reg.sp -= 1;
+        self.memory
+            .write_byte(self.reg.sp as u16 + STACK_ADDR_LO, data);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
     }
 
     fn push_stack_u16(&mut self, data: u16) {
@@ -156,30 +300,46 @@ impl NesCpu {
     }
 
     fn pop_stack(&mut self) -> u8 {
-        if self.reg.sp == 0xFF {
-            panic!("Stack pointer overflow!");
-        }
-        let address: u16 = 0x100 + self.reg.sp as u16;
-        self.reg.sp += 1;
-        let res = self.memory.read_byte(address + 1);
-        res
+        // the stack wraps around page one, like the hardware's
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        self.memory.read_byte(STACK_ADDR_LO + self.reg.sp as u16)
     }
 
-    fn get_mode_address(&self) -> u16 {
-        match self.current.mode {
+    fn get_mode_address(&mut self) -> Result<u16, CpuError> {
+        let address = match self.current.mode {
             AddressingMode::Implied => 0,     // unused
             AddressingMode::Immediate => 0,   // unused
             AddressingMode::Accumulator => 0, // unused
             AddressingMode::Absolute => self.next_word(),
-            AddressingMode::AbsoluteX => self.next_word().wrapping_add(self.reg.idx as u16),
-            AddressingMode::AbsoluteY => self.next_word().wrapping_add(self.reg.idy as u16),
+            AddressingMode::AbsoluteX => {
+                let base = self.next_word();
+                self.indexed(base, self.reg.idx)
+            }
+            AddressingMode::AbsoluteY => {
+                let base = self.next_word();
+                self.indexed(base, self.reg.idy)
+            }
             AddressingMode::ZeroPage => self.next_byte() as u16,
             AddressingMode::ZeroPageX => self.next_byte().wrapping_add(self.reg.idx) as u16,
             AddressingMode::ZeroPageY => self.next_byte().wrapping_add(self.reg.idy) as u16,
             AddressingMode::XIndirect => self.get_indirect_x(),
             AddressingMode::YIndirect => self.get_indirect_y(),
-            _ => panic!("Invalid mode for get_mode_address {:?}", self.current.mode),
+            _ => return Err(self.bad_operands()),
+        };
+        Ok(address)
+    }
+
+    /// base + index, noting the extra cycle read instructions take when that crosses a page
+    fn indexed(&mut self, base: u16, index: u8) -> u16 {
+        let address = base.wrapping_add(index as u16);
+        if address & 0xFF00 != base & 0xFF00 && has_page_cross_penalty(self.opcode()) {
+            self.extra_cycles = 1;
         }
+        address
+    }
+
+    fn opcode(&self) -> u8 {
+        self.memory.peek_byte(self.reg.pc)
     }
 
     fn pop_stack_u16(&mut self) -> u16 {
@@ -188,24 +348,24 @@ impl NesCpu {
         u16::from_le_bytes([low, hi])
     }
 
-    fn reg_to_a(&mut self) {
+    fn reg_to_a(&mut self) -> Result<(), CpuError> {
         let source_register = match self.current.op {
             Instructions::XToAccumulator => self.reg.idx,
             Instructions::YToAccumulator => self.reg.idy,
-            _ => panic!("Invalid op for transfer_reg_to_a: {:?}", self.current.op),
+            _ => return Err(self.bad_operands()),
         };
 
         self.reg.accumulator = source_register;
+        self.update_zero_and_negative(source_register);
         self.next();
+        Ok(())
     }
 
-    fn test_bit(&mut self) {
+    fn test_bit(&mut self) -> Result<(), CpuError> {
         let address = match self.current.mode {
             AddressingMode::Absolute => self.next_word(),
             AddressingMode::ZeroPage => self.next_byte() as u16,
-            _ => {
-                panic!("test_bit not implemented for mode {:?}", self.current.mode)
-            }
+            _ => return Err(self.bad_operands()),
         };
         let operand = self.memory.read_byte(address);
         // Extract bits 6 and 7 from the operand
@@ -222,11 +382,12 @@ impl NesCpu {
         self.reg.flags.zero = result == 0;
 
         self.next();
+        Ok(())
     }
 
     /// Move the processor program counter to the next instruction in memory.
     fn next(&mut self) {
-        self.reg.pc += self.current.mode.get_increment();
+        self.reg.pc = self.reg.pc.wrapping_add(self.current.mode.get_increment());
     }
 
     fn update_zero_and_negative(&mut self, value: u8) {
@@ -235,8 +396,8 @@ impl NesCpu {
     }
 
     /// Load a value into a register
-    fn load_register(&mut self) {
-        let address = self.get_mode_address();
+    fn load_register(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let value = if let AddressingMode::Immediate = self.current.mode {
             self.next_byte()
         } else {
@@ -247,56 +408,55 @@ impl NesCpu {
             Instructions::LoadAccumulator => self.reg.accumulator = value,
             Instructions::LoadX => self.reg.idx = value,
             Instructions::LoadY => self.reg.idy = value,
-            _ => panic!("Invalid op for load_register: {:?}", self.current.op),
+            Instructions::LAX => {
+                self.reg.accumulator = value;
+                self.reg.idx = value;
+            }
+            _ => return Err(self.bad_operands()),
         }
 
         self.update_zero_and_negative(value);
         self.next();
+        Ok(())
     }
 
     /// Store a register in memory
-    fn store_register(&mut self) {
-        let address = self.get_mode_address();
+    fn store_register(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let register_value = match self.current.op {
             Instructions::StoreAccumulator => self.reg.accumulator,
             Instructions::StoreX => self.reg.idx,
             Instructions::StoreY => self.reg.idy,
-            _ => panic!(
-                "Unknown instruction for store_register: {:?}",
-                self.current.op
-            ),
+            Instructions::SAX => self.reg.accumulator & self.reg.idx,
+            _ => return Err(self.bad_operands()),
         };
 
         self.memory.write_byte(address, register_value);
         self.next();
+        Ok(())
     }
 
     /// Increase a register by one
-    fn increase_register(&mut self) {
+    fn increase_register(&mut self) -> Result<(), CpuError> {
         let register = match self.current.op {
             Instructions::IncrementX => &mut self.reg.idx,
             Instructions::IncrementY => &mut self.reg.idy,
-            _ => panic!(
-                "Invalid instruction for increase_register: {:?}",
-                self.current.op
-            ),
+            _ => return Err(self.bad_operands()),
         };
         *register = register.wrapping_add(1);
 
         let value = *register;
         self.update_zero_and_negative(value);
         self.next();
+        Ok(())
     }
 
     /// Decrease a register by one
-    fn decrease_register(&mut self) {
+    fn decrease_register(&mut self) -> Result<(), CpuError> {
         let register = match self.current.op {
             Instructions::DecrementX => &mut self.reg.idx,
             Instructions::DecrementY => &mut self.reg.idy,
-            _ => panic!(
-                "Unknown instruction for decrease_register: {:?}",
-                self.current.op
-            ),
+            _ => return Err(self.bad_operands()),
         };
 
         *register = register.wrapping_sub(1);
@@ -304,45 +464,46 @@ impl NesCpu {
         let value = *register;
         self.update_zero_and_negative(value);
         self.next();
+        Ok(())
     }
 
     /// decrement mem
-    fn decrement_mem(&mut self) {
-        let address = self.get_mode_address();
+    fn decrement_mem(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let result = self.memory.read_byte(address).wrapping_sub(1);
 
         self.update_zero_and_negative(result);
         self.memory.write_byte(address, result);
         self.next();
+        Ok(())
     }
 
     /// decrement mem
-    fn increment_mem(&mut self) {
+    fn increment_mem(&mut self) -> Result<(), CpuError> {
         let address = match self.current.mode {
             AddressingMode::Absolute => self.next_word(),
             AddressingMode::AbsoluteX => self.next_word().wrapping_add(self.reg.idx as u16),
             AddressingMode::ZeroPage => self.next_byte() as u16,
             AddressingMode::ZeroPageX => self.next_byte().wrapping_add(self.reg.idx) as u16,
-            _ => panic!("Invalid mode for decrement_mem {:?}", self.current.mode),
+            _ => return Err(self.bad_operands()),
         };
         let result = self.memory.read_byte(address).wrapping_add(1);
 
         self.update_zero_and_negative(result);
         self.memory.write_byte(address, result);
         self.next();
+        Ok(())
     }
 
-    // TODO unfinished
-    fn shift_one_left(&mut self) {
-        let address = self.get_mode_address();
+    fn shift_one_left(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
 
         let result = match self.current.mode {
             AddressingMode::Accumulator => {
                 self.reg.flags.carry = self.reg.accumulator & 0x80 == 0x80;
-                self.reg.accumulator = self.reg.accumulator << 1;
+                self.reg.accumulator <<= 1;
                 self.reg.accumulator
             }
-            // TODO carry bit
             _ => {
                 let value = self.memory.read_byte(address);
                 self.reg.flags.carry = value & 0x80 == 0x80;
@@ -356,11 +517,12 @@ impl NesCpu {
         self.reg.flags.negative = result & 0x80 == 0x80;
 
         self.next();
+        Ok(())
     }
 
     // cleanup - merge with shift_one_left
-    fn shift_one_right(&mut self) {
-        let address = self.get_mode_address();
+    fn shift_one_right(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
 
         let result = match self.current.mode {
             AddressingMode::Accumulator => {
@@ -380,32 +542,27 @@ impl NesCpu {
 
         self.update_zero_and_negative(result);
         self.next();
+        Ok(())
     }
 
-    // TODO broken, fails tests
-    fn rotate(&mut self) {
-        // todo X-indexed Abs
-        let address = self.get_mode_address();
+    fn rotate(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let value = if let AddressingMode::Accumulator = self.current.mode {
             self.reg.accumulator
         } else {
             self.memory.read_byte(address)
         };
 
+        // the carry goes in one end and what falls out the other is the new carry
+        let carry = self.reg.flags.carry as u8;
         let shifted = if self.current.op == Instructions::RotateOneLeft {
             self.reg.flags.carry = 0x80 & value == 0x80;
-            self.reg.flags.negative = 0x40 & value == 0x40;
-            let bit = if self.reg.flags.carry { 0x1 } else { 0 };
-            let res = value << 1;
-            res | bit
+            (value << 1) | carry
         } else {
-            self.reg.flags.negative = self.reg.flags.carry;
             self.reg.flags.carry = 0x1 & value == 0x1;
-            let bit = if self.reg.flags.carry { 0x80 } else { 0 };
-            let res = value >> 1;
-            res | bit
+            (value >> 1) | (carry << 7)
         };
-        self.reg.flags.zero = shifted == 0;
+        self.update_zero_and_negative(shifted);
 
         if self.current.mode == AddressingMode::Accumulator {
             self.reg.accumulator = shifted;
@@ -414,32 +571,35 @@ impl NesCpu {
         }
 
         self.next();
+        Ok(())
     }
 
-    /// Execute a decoded instruction
-    pub fn execute(&mut self) {
+    /// Execute a decoded instruction, or say why it can't be. The PC is left on an instruction
+    /// that fails.
+    pub fn execute(&mut self) -> Result<(), CpuError> {
         match (&self.current.op, &self.current.mode) {
-            (Instructions::Jump, AddressingMode::Absolute) => self.set_pc(self.next_word()),
-            (Instructions::Jump, AddressingMode::Indirect) => {
-                let mut address = self.next_word(); // temp mut
-                if address == 0x2FF {
-                    // TODO TEMP broken jmp (DBAB - nesrom) - this bypass jumps over failed jump.
-                    address = 0x0300;
-                    println!("TEMP: Jumped over from 2ff, check 0xDBAB in nesrom.log for expected")
-                } else {
-                    address = self.memory.read_word(address)
-                }
-
+            (Instructions::Jump, AddressingMode::Absolute) => {
+                let address = self.next_word();
                 self.set_pc(address);
             }
+            (Instructions::Jump, AddressingMode::Indirect) => {
+                // the high byte comes from the same page, JMP ($02FF) reads $02FF and $0200
+                let pointer = self.next_word();
+                let low = self.memory.read_byte(pointer);
+                let high = self
+                    .memory
+                    .read_byte((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF));
+                self.set_pc(combine_bytes_to_u16(high, low));
+            }
 
             // JSR
             (Instructions::JumpSubroutine, AddressingMode::Absolute) => {
-                self.push_stack_u16(self.reg.pc + 2);
-                self.set_pc(self.next_word());
+                self.push_stack_u16(self.reg.pc.wrapping_add(2));
+                let address = self.next_word();
+                self.set_pc(address);
             }
             (Instructions::ReturnFromSubroutine, AddressingMode::Implied) => {
-                let addr = self.pop_stack_u16() + 1;
+                let addr = self.pop_stack_u16().wrapping_add(1);
                 self.set_pc(addr);
             }
 
@@ -451,35 +611,36 @@ impl NesCpu {
             | (Instructions::BranchOnOverflowSet, AddressingMode::Relative)
             | (Instructions::BranchOverflowClear, AddressingMode::Relative)
             | (Instructions::BranchOnCarrySet, AddressingMode::Relative)
-            | (Instructions::BranchOnCarryClear, AddressingMode::Relative) => self.branch(),
+            | (Instructions::BranchOnCarryClear, AddressingMode::Relative) => self.branch()?,
 
             // compare
             (Instructions::CompareAccumulator, _)
             | (Instructions::CompareX, _)
             | (Instructions::CompareY, _) => {
-                self.compare_register();
+                self.compare_register()?;
             }
 
             /* storing registers */
             (Instructions::StoreAccumulator, _)
             | (Instructions::StoreX, _)
-            | (Instructions::StoreY, _) => self.store_register(),
+            | (Instructions::StoreY, _)
+            | (Instructions::SAX, _) => self.store_register()?,
 
             /* load registers */
             (Instructions::LoadAccumulator, _)
             | (Instructions::LoadX, _)
-            | (Instructions::LoadY, _) => {
-                self.load_register();
+            | (Instructions::LoadY, _)
+            | (Instructions::LAX, _) => {
+                self.load_register()?;
             }
 
-            // broken
             (Instructions::RotateOneLeft, _) | (Instructions::RotateOneRight, _) => {
-                self.rotate();
+                self.rotate()?;
             }
 
             // shifts
-            (Instructions::ShiftOneLeft, _) => self.shift_one_left(),
-            (Instructions::ShiftOneRight, _) => self.shift_one_right(),
+            (Instructions::ShiftOneLeft, _) => self.shift_one_left()?,
+            (Instructions::ShiftOneRight, _) => self.shift_one_right()?,
 
             // TODO
             (Instructions::ReturnFromInterrupt, AddressingMode::Implied) => {
@@ -490,6 +651,7 @@ impl NesCpu {
 
             (Instructions::StackPointerToX, AddressingMode::Implied) => {
                 self.reg.idx = self.reg.sp;
+                self.update_zero_and_negative(self.reg.idx);
                 self.next();
             }
 
@@ -507,13 +669,13 @@ impl NesCpu {
 
             // increment/decrement registers
             (Instructions::IncrementX, AddressingMode::Implied)
-            | (Instructions::IncrementY, AddressingMode::Implied) => self.increase_register(),
+            | (Instructions::IncrementY, AddressingMode::Implied) => self.increase_register()?,
             (Instructions::DecrementX, AddressingMode::Implied)
-            | (Instructions::DecrementY, AddressingMode::Implied) => self.decrease_register(),
+            | (Instructions::DecrementY, AddressingMode::Implied) => self.decrease_register()?,
 
             // increase/decrement memory
-            (Instructions::IncrementMem, _) => self.increment_mem(),
-            (Instructions::DecrementMem, _) => self.decrement_mem(),
+            (Instructions::IncrementMem, _) => self.increment_mem()?,
+            (Instructions::DecrementMem, _) => self.decrement_mem()?,
 
             // TODO
             (Instructions::SetDecimalMode, AddressingMode::Implied) => self.set_decimal(true),
@@ -532,7 +694,7 @@ impl NesCpu {
 
             (Instructions::TestBitsAccumulator, AddressingMode::Absolute)
             | (Instructions::TestBitsAccumulator, AddressingMode::ZeroPage) => {
-                self.test_bit();
+                self.test_bit()?;
             }
 
             (Instructions::XToStackPointer, AddressingMode::Implied) => {
@@ -540,10 +702,17 @@ impl NesCpu {
                 self.next();
             }
 
-            (Instructions::ISC, AddressingMode::Absolute) => self.isc_abs(),
+            (Instructions::SLO, _)
+            | (Instructions::RLA, _)
+            | (Instructions::SRE, _)
+            | (Instructions::RRA, _)
+            | (Instructions::DCP, _)
+            | (Instructions::ISC, _) => self.combined_read_modify_write()?,
 
             (Instructions::PushStatusOnStack, AddressingMode::Implied) => {
-                self.push_stack(self.reg.flags.as_byte());
+                // https://www.nesdev.org/wiki/Status_flags#The_B_flag
+                // PHP pushes B set, like BRK
+                self.push_stack(self.reg.flags.as_byte() | 0b0001_0000);
                 self.next();
             }
             (Instructions::PullStatusFromStack, AddressingMode::Implied) => {
@@ -552,71 +721,77 @@ impl NesCpu {
                 self.next();
             }
 
-            // todo
             (Instructions::AccumulatorToX, AddressingMode::Implied) => {
                 self.reg.idx = self.reg.accumulator;
+                self.update_zero_and_negative(self.reg.idx);
                 self.next();
             }
 
-            // todo
             (Instructions::AccumulatorToY, AddressingMode::Implied) => {
                 self.reg.idy = self.reg.accumulator;
+                self.update_zero_and_negative(self.reg.idy);
                 self.next();
             }
 
-            // todo
             (Instructions::XToAccumulator, AddressingMode::Implied)
             | (Instructions::YToAccumulator, AddressingMode::Implied) => {
-                self.reg_to_a();
+                self.reg_to_a()?;
             }
 
-            // todo
-            (Instructions::AddToAccWithCarry, _) => self.add_mem_to_accumulator_with_carry(),
-            (Instructions::SubAccWithBorrow, _) => self.subtract_accumulator_with_borrow(),
+            (Instructions::AddToAccWithCarry, _) => self.add_mem_to_accumulator_with_carry()?,
+            // $EB is SBC #imm too
+            (Instructions::SubAccWithBorrow, _) | (Instructions::USBC, _) => {
+                self.subtract_accumulator_with_borrow()?
+            }
 
             /* bitwise */
-            (Instructions::ORAccumulator, _) => self.or(),
-            (Instructions::ANDAccumulator, _) => self.and(),
-            (Instructions::EORAccumulator, _) => self.eor(),
-
-            (Instructions::NoOperation, _) => self.next(),
-
-            (Instructions::ForceBreak, AddressingMode::Implied) => self.breakpoint(),
-            (Instructions::JAM, AddressingMode::Implied) => {
-                self.memory
-                    .dump_to_file("JAMMED.bin")
-                    .expect("Error while writing to dump file");
-                println!("JAM - Wrote memory dump to JAMMED.bin");
-                exit(1);
+            (Instructions::ORAccumulator, _) => self.or()?,
+            (Instructions::ANDAccumulator, _) => self.and()?,
+            (Instructions::EORAccumulator, _) => self.eor()?,
+
+            (Instructions::NoOperation, AddressingMode::Implied)
+            | (Instructions::NoOperation, AddressingMode::Immediate) => self.next(),
+            // the undocumented NOPs with an address read it and throw it away
+            (Instructions::NoOperation, _) => {
+                let address = self.get_mode_address()?;
+                self.memory.read_byte(address);
+                self.next();
             }
 
-            (_, _) => {
-                println!(
-                    "Unknown pattern! {:?}, {:?} PC: {:x}",
-                    self.current.op, self.current.mode, self.reg.pc
-                );
-                self.memory
-                    .dump_to_file("UNKNOWN.bin")
-                    .expect("Error while writing to dump file");
-                exit(1);
+            (Instructions::ForceBreak, AddressingMode::Implied) => self.breakpoint(),
+            // JAM, and what isn't implemented yet
+            _ => {
+                return Err(CpuError::Jammed {
+                    opcode: self.memory.peek_byte(self.reg.pc),
+                    pc: self.reg.pc,
+                })
             }
         }
+        Ok(())
     }
 
-    fn get_indirect_x(&self) -> u16 {
-        let address = self.next_byte();
-        self.memory
-            .read_word(address.wrapping_add(self.reg.idx) as u16)
+    // ($nn,X): the pointer is at $nn + X, wrapping around the zero page
+    fn get_indirect_x(&mut self) -> u16 {
+        let pointer = self.next_byte().wrapping_add(self.reg.idx);
+        self.read_zero_page_word(pointer)
     }
 
-    fn get_indirect_y(&self) -> u16 {
-        let address = self.next_byte();
-        self.memory
-            .read_word(address.wrapping_add(self.reg.idy) as u16)
+    // ($nn),Y: the pointer is at $nn and Y is added to the address it holds
+    fn get_indirect_y(&mut self) -> u16 {
+        let pointer = self.next_byte();
+        let base = self.read_zero_page_word(pointer);
+        self.indexed(base, self.reg.idy)
+    }
+
+    // pointers in the zero page wrap within it, ($FF) is read from $FF and $00
+    fn read_zero_page_word(&mut self, pointer: u8) -> u16 {
+        let low = self.memory.read_byte(pointer as u16);
+        let high = self.memory.read_byte(pointer.wrapping_add(1) as u16);
+        combine_bytes_to_u16(high, low)
     }
 
-    fn and(&mut self) {
-        let address = self.get_mode_address();
+    fn and(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
 
         let value = match self.current.mode {
             AddressingMode::Immediate => self.next_byte(),
@@ -627,10 +802,11 @@ impl NesCpu {
         self.update_zero_and_negative(self.reg.accumulator);
 
         self.next();
+        Ok(())
     }
 
-    fn or(&mut self) {
-        let address = self.get_mode_address();
+    fn or(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let operand = match self.current.mode {
             AddressingMode::Immediate => self.next_byte(),
             _ => self.memory.read_byte(address),
@@ -640,10 +816,11 @@ impl NesCpu {
         self.update_zero_and_negative(self.reg.accumulator);
 
         self.next();
+        Ok(())
     }
 
-    fn eor(&mut self) {
-        let address = self.get_mode_address();
+    fn eor(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let value = if let AddressingMode::Immediate = self.current.mode {
             self.next_byte()
         } else {
@@ -654,153 +831,485 @@ impl NesCpu {
         self.update_zero_and_negative(self.reg.accumulator);
 
         self.next();
+        Ok(())
     }
 
-    // todo
-    // todo broken (min: 0xC1)
-    fn add_mem_to_accumulator_with_carry(&mut self) {
-        let address = self.get_mode_address();
+    fn add_mem_to_accumulator_with_carry(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let operand = match self.current.mode {
             AddressingMode::Immediate => self.next_byte(),
             _ => self.memory.read_byte(address),
         };
-        let carry_add: u8 = if self.reg.flags.carry { 1 } else { 0 };
-        // Perform addition
-        let (result, carry_out) = self.reg.accumulator.overflowing_add(operand + carry_add);
-
-        // Update the carry flag
-        self.reg.flags.carry = carry_out;
-        dbg!(carry_out);
-
-        // Update the overflow flag
-        self.reg.flags.overflow = ((self.reg.accumulator ^ operand) & 0x80 != 0)
-            && ((self.reg.accumulator ^ result) & 0x80 != 0);
-
-        self.update_zero_and_negative(result);
-
-        self.reg.accumulator = result;
-        println!("ADDED MEM TO A, WITH CARRY {}", self.reg.accumulator);
+        self.add_to_accumulator(operand);
         self.next();
+        Ok(())
     }
 
-    // TODO bugged - use nestest to find and fix
-    fn subtract_accumulator_with_borrow(&mut self) {
-        let address = self.get_mode_address();
+    fn subtract_accumulator_with_borrow(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let operand = if let AddressingMode::Immediate = self.current.mode {
             self.next_byte()
         } else {
             self.memory.read_byte(address)
         };
+        self.add_to_accumulator(!operand);
+        self.next();
+        Ok(())
+    }
 
-        let borrow = if self.reg.flags.carry { 1 } else { 0 };
-        let result = self
-            .reg
-            .accumulator
-            .wrapping_sub(operand)
-            .wrapping_sub(borrow);
+    // https://www.nesdev.org/wiki/Instruction_reference#ADC
+    // A + operand + C, SBC is the same with the operand inverted (A - operand - !C). V is set
+    // when both inputs have the same sign and the result doesn't.
+    fn add_to_accumulator(&mut self, operand: u8) {
+        let a = self.reg.accumulator;
+        let sum = a as u16 + operand as u16 + self.reg.flags.carry as u16;
+        let result = sum as u8;
+        self.reg.flags.carry = sum > 0xFF;
+        self.reg.flags.overflow = (a ^ result) & (operand ^ result) & 0x80 != 0;
+        self.update_zero_and_negative(result);
+        self.reg.accumulator = result;
+    }
 
-        let reg_before = self.reg.accumulator;
+    /// Turn the instruction trace on or off, starting one on stdout if there's no trace logger
+    pub fn set_logging(&mut self, logging: bool) {
+        match &mut self.tracer {
+            Some(tracer) => tracer.set_enabled(logging),
+            None if logging => self.tracer = Some(TraceLogger::stdout()),
+            None => {}
+        }
+    }
 
-        // Update CPU state
-        self.reg.accumulator = result;
-        self.reg.flags.carry = result as i8 > 0 || borrow == 0;
+    pub fn is_logging(&self) -> bool {
+        self.tracer.as_ref().is_some_and(TraceLogger::is_enabled)
+    }
 
-        self.update_zero_and_negative(result);
-        let over = (borrow == 0 && operand > 127) && reg_before < 128 && self.reg.accumulator > 127;
-        let under = (reg_before > 127)
-            && (0u8.wrapping_sub(operand).wrapping_sub(borrow) > 127)
-            && self.reg.accumulator < 128;
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
 
-        self.reg.flags.overflow = over || under;
+    /// Keep a shadow call stack in `call_stack`, or stop with None
+    pub fn set_call_stack(&mut self, call_stack: Option<CallStack>) {
+        self.call_stack = call_stack.map(Box::new);
+    }
 
-        self.next();
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_deref()
+    }
+
+    pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+        self.call_stack.as_deref_mut()
+    }
+
+    /// Keep the last instructions run for jam reports, or stop with None
+    pub fn set_history(&mut self, history: Option<InstructionHistory>) {
+        self.history = history.map(Box::new);
+    }
+
+    pub fn history(&self) -> Option<&InstructionHistory> {
+        self.history.as_deref()
+    }
+
+    /// Watch `expression`, see `watches`, and return the watch's id
+    pub fn add_watch(
+        &mut self,
+        expression: Condition,
+        trigger: WatchTrigger,
+    ) -> Result<u32, BadCondition> {
+        let id = self.watches.add(expression, trigger)?;
+        self.memory.watch_writes(&self.watches.written_addresses());
+        Ok(id)
+    }
+
+    pub fn remove_watch(&mut self, id: u32) -> bool {
+        let removed = self.watches.remove(id);
+        self.memory.watch_writes(&self.watches.written_addresses());
+        removed
+    }
+
+    pub fn watches(&self) -> &Watches {
+        &self.watches
+    }
+
+    /// For draining samples and streaming them out
+    pub fn watches_mut(&mut self) -> &mut Watches {
+        &mut self.watches
+    }
+
+    /// Hand the watches over to another CPU, `set_watches` putting them in
+    pub fn take_watches(&mut self) -> Watches {
+        self.memory.watch_writes(&[]);
+        std::mem::take(&mut self.watches)
+    }
+
+    pub fn set_watches(&mut self, watches: Watches) {
+        self.memory.watch_writes(&watches.written_addresses());
+        self.watches = watches;
+    }
+
+    /// Sample the frame watches, at the end of a frame
+    pub fn sample_frame_watches(&mut self) {
+        if !self.watches.is_empty() {
+            let mut watches = std::mem::take(&mut self.watches);
+            watches.sample_frame(self);
+            self.watches = watches;
+        }
+    }
+
+    /// Whether a breakpoint stopped the CPU before the instruction at PC. Running on runs it.
+    pub fn stopped_at_breakpoint(&self) -> bool {
+        self.resume_at == Some(self.reg.pc)
+    }
+
+    /// Run the instruction at PC next even if a breakpoint is on it
+    pub fn skip_breakpoint(&mut self) {
+        self.resume_at = Some(self.reg.pc);
+    }
+
+    /// Trace every instruction into `tracer`, or stop tracing with None
+    pub fn set_trace_logger(&mut self, tracer: Option<TraceLogger>) {
+        self.tracer = tracer;
+    }
+
+    pub fn trace_logger_mut(&mut self) -> Option<&mut TraceLogger> {
+        self.tracer.as_mut()
+    }
+
+    pub fn take_trace_logger(&mut self) -> Option<TraceLogger> {
+        self.tracer.take()
     }
 
     pub fn set_pc(&mut self, addr: u16) {
         self.reg.pc = addr;
     }
 
-    fn isc_abs(&mut self) {
-        let address = self.memory.read_word(self.reg.pc + 1);
-        // Step 1: Increment memory value
-        let operand = self.memory.read_byte(address);
-        let incremented_value = operand.wrapping_add(1);
-        self.memory.write_byte(address, incremented_value);
-
-        // Step 2: Subtract with carry
-        let borrow = if self.reg.flags.carry { 0 } else { 1 };
-        let result = self
-            .reg
-            .accumulator
-            .wrapping_sub(incremented_value)
-            .wrapping_sub(borrow);
-
-        // Update flags
-        self.update_zero_and_negative(result);
-        self.reg.flags.overflow = ((self.reg.accumulator ^ incremented_value) & 0x80 != 0)
-            && ((self.reg.accumulator ^ result) & 0x80 != 0);
-        self.reg.flags.carry = result <= self.reg.accumulator; // Check if there is a borrow
-        self.reg.accumulator = result;
+    // https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+    // The undocumented read-modify-write instructions: a shift, rotate, increment or decrement
+    // of memory, then the ALU operation of the documented opcode they share decoding with, on
+    // the new value
+    fn combined_read_modify_write(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
+        let value = self.memory.read_byte(address);
+        let carry = self.reg.flags.carry as u8;
+        let result = match self.current.op {
+            // ASL, ORA
+            Instructions::SLO => {
+                self.reg.flags.carry = value & 0x80 != 0;
+                value << 1
+            }
+            // ROL, AND
+            Instructions::RLA => {
+                self.reg.flags.carry = value & 0x80 != 0;
+                (value << 1) | carry
+            }
+            // LSR, EOR
+            Instructions::SRE => {
+                self.reg.flags.carry = value & 0x01 != 0;
+                value >> 1
+            }
+            // ROR, ADC
+            Instructions::RRA => {
+                self.reg.flags.carry = value & 0x01 != 0;
+                (value >> 1) | (carry << 7)
+            }
+            // DEC, CMP
+            Instructions::DCP => value.wrapping_sub(1),
+            // INC, SBC
+            Instructions::ISC => value.wrapping_add(1),
+            _ => return Err(self.bad_operands()),
+        };
+        self.memory.write_byte(address, result);
+        match self.current.op {
+            Instructions::SLO => self.reg.accumulator |= result,
+            Instructions::RLA => self.reg.accumulator &= result,
+            Instructions::SRE => self.reg.accumulator ^= result,
+            _ => {}
+        }
+        match self.current.op {
+            Instructions::RRA => self.add_to_accumulator(result),
+            Instructions::DCP => self.compare(self.reg.accumulator, result),
+            Instructions::ISC => self.add_to_accumulator(!result),
+            _ => self.update_zero_and_negative(self.reg.accumulator),
+        }
+        self.next();
+        Ok(())
+    }
 
-        self.reg.pc += 3;
+    /// Where the CPU stopped on a JAM or an opcode it doesn't know, until reset or power cycle.
+    /// The PPU and APU carry on, so frames still come out.
+    pub fn jammed(&self) -> Option<u16> {
+        self.jammed.as_ref().map(CpuError::pc)
     }
 
-    pub fn fetch_decode_next(&mut self) {
-        let next_instruction = self.memory.read_byte(self.reg.pc);
-        let (instruction, addressing_mode) = Self::decode_instruction(next_instruction);
-        self.current = CurrentInstruction {
-            op: instruction,
-            mode: addressing_mode,
-        };
+    /// Why the CPU stopped, when `jammed` says it has
+    pub fn error(&self) -> Option<&CpuError> {
+        self.jammed.as_ref()
+    }
 
-        self.log(&next_instruction);
-        self.execute();
+    fn bad_operands(&self) -> CpuError {
+        CpuError::BadOperands {
+            op: self.current.op.clone(),
+            mode: self.current.mode.clone(),
+            pc: self.reg.pc,
+        }
     }
 
-    fn log(&mut self, binary_instruction: &u8) {
-        let bytes_fmt = match self.current.mode {
-            AddressingMode::Implied | AddressingMode::Accumulator => "     ".to_string(),
-            AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
-                let bytes = self.next_word().to_le_bytes();
-                format!("{:02X} {:02X}", bytes[0], bytes[1])
+    pub fn fetch_decode_next(&mut self) {
+        if self.jammed.is_some() {
+            // interrupts don't get it going again
+            self.add_cycles(CYCLES[0x02] as u32);
+            return;
+        }
+        let interrupt_start = self.tick as u64;
+        let (interrupted_at, sp_before) = (self.reg.pc, self.reg.sp);
+        let interrupt = if self.memory.take_nmi() {
+            self.nmi();
+            Some(FrameKind::Nmi)
+        } else if self.memory.irq_pending() && !self.reg.flags.interrupt_disable {
+            self.irq();
+            Some(FrameKind::Irq)
+        } else {
+            None
+        };
+        let (pc, sp) = (self.reg.pc, self.reg.sp);
+        if let Some(kind) = interrupt {
+            if let Some(profiler) = self.memory.profiler_mut() {
+                profiler.call(pc, sp, interrupt_start);
+            }
+            if let Some(call_stack) = &mut self.call_stack {
+                let frame = StackFrame {
+                    kind,
+                    call_site: interrupted_at,
+                    target: pc,
+                    return_address: interrupted_at,
+                    sp,
+                    cycle: interrupt_start,
+                };
+                call_stack.call(frame, sp_before);
+            }
+        }
+
+        let resume_at = self.resume_at.take();
+        if !self.breakpoints.is_empty() && resume_at != Some(self.reg.pc) {
+            let mut breakpoints = std::mem::take(&mut self.breakpoints);
+            let hit = breakpoints.check(self);
+            self.breakpoints = breakpoints;
+            if let Some(id) = hit {
+                self.resume_at = Some(self.reg.pc);
+                let address = self.reg.pc;
+                let events = self.memory.ppu_mut().events_mut();
+                events.push(Event::Breakpoint { id, address });
+                return;
             }
-            _ => {
-                format!("{:02X}   ", self.next_byte())
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.log(self);
+            self.tracer = Some(tracer);
+        }
+        if let Some(history) = &mut self.history {
+            let bytes = [0, 1, 2].map(|i| self.memory.peek_byte(pc.wrapping_add(i)));
+            history.push(Executed {
+                pc,
+                bytes,
+                a: self.reg.accumulator,
+                x: self.reg.idx,
+                y: self.reg.idy,
+                p: self.reg.status(),
+                sp: self.reg.sp,
+                cycle: self.tick as u64,
+            });
+        }
+        self.memory.set_cycle(self.tick as u64);
+        let start = self.tick as u64;
+        if self.memory.watches_instructions() {
+            let opcode = self.memory.peek_byte(pc);
+            let (_, mode) = Self::decode_instruction(opcode);
+            let cycles = CYCLES[opcode as usize] as u32;
+            self.memory
+                .instruction_fetch(pc, mode.get_increment(), cycles);
+        }
+        let next_instruction = self.fetch(pc);
+        self.extra_cycles = 0;
+        // the PC stays put, so a save state taken now stops again when loaded
+        let executed = self.execute();
+        self.operands = None;
+        if let Err(error) = executed {
+            warn!(target: logging::CPU, "{}", error);
+            let address = error.pc();
+            self.jammed = Some(error);
+            self.memory
+                .ppu_mut()
+                .events_mut()
+                .push(Event::Jam { address });
+        }
+
+        let cycles = CYCLES[next_instruction as usize] as u32
+            + self.extra_cycles
+            + self.memory.take_stall_cycles();
+        self.add_cycles(cycles);
+        if let Some(profiler) = self.memory.profiler_mut() {
+            profiler.instruction(pc, next_instruction, sp, start, cycles as u64, &self.reg);
+        }
+        if let Some(call_stack) = &mut self.call_stack {
+            match next_instruction {
+                // JSR and BRK
+                0x20 | 0x00 => {
+                    let kind = match next_instruction {
+                        0x20 => FrameKind::Subroutine,
+                        _ => FrameKind::Break,
+                    };
+                    let frame = StackFrame {
+                        kind,
+                        call_site: pc,
+                        target: self.reg.pc,
+                        return_address: pc.wrapping_add(2),
+                        sp: self.reg.sp,
+                        cycle: start,
+                    };
+                    call_stack.call(frame, sp);
+                }
+                // RTS lands one past the address it pulls
+                0x60 => call_stack.ret(pc, false, sp, self.reg.pc.wrapping_sub(1), start),
+                0x40 => call_stack.ret(pc, true, sp, self.reg.pc, start),
+                _ => {}
             }
-        };
+        }
+        let written = self.memory.take_watched_writes();
+        if !written.is_empty() {
+            let mut watches = std::mem::take(&mut self.watches);
+            watches.sample_writes(self, pc, &written);
+            self.watches = watches;
+        }
+    }
 
-        let asm_fmt = match self.current.mode {
-            AddressingMode::Absolute => format!("${:04X}", self.next_word()),
-            _ => "".to_string(),
+    // read and decode the instruction at `pc` into `current`, returning its opcode. With the
+    // decode cache on, code that's been run before comes from there with its operands.
+    fn fetch(&mut self, pc: u16) -> u8 {
+        let generation = self.memory.code_generation();
+        let cache = match self.decode_cache.as_deref_mut() {
+            Some(cache) if !self.memory.watches_fetches() => cache,
+            _ => return self.decode(pc),
         };
-
-        println!(
-            "{:4X}  {:2X} {}  {} {:<28}A:{:>2X} X:{:>2X} Y:{:>2X} P:{:>2X} SP:{:>2X} PPU:{:>2X},{:>3} CYC:{}",
-            self.reg.pc,
-            binary_instruction,
-            bytes_fmt,
-            self.current.op.asm(),
-            asm_fmt,
-            self.reg.accumulator,
-            self.reg.idx,
-            self.reg.idy,
-            self.reg.flags.as_byte(),
-            self.reg.sp,
-            20,1,0
-        );
-    }
-
-    // TODO - works with mapper 0 only
-    pub fn load_rom(&mut self, rom: &NesRom) {
-        self.memory.write_bytes(0x8000, &rom.prg_rom[0]);
-        if rom.prg_rom.len() > 1 {
-            self.memory.write_bytes(0xC000, &rom.prg_rom[1]);
-        } else {
-            self.memory.write_bytes(0xC000, &rom.prg_rom[0]);
+        if let Some(decoded) = cache.get(pc, generation) {
+            self.current = decoded.instruction;
+            self.operands = Some(decoded.operands);
+            return decoded.opcode;
+        }
+        let opcode = self.decode(pc);
+        if DecodeCache::is_cached(pc) {
+            let decoded = Decoded {
+                opcode,
+                instruction: self.current.clone(),
+                operands: [1, 2].map(|i| self.memory.peek_byte(pc.wrapping_add(i))),
+            };
+            if let Some(cache) = self.decode_cache.as_deref_mut() {
+                cache.insert(pc, generation, decoded);
+            }
         }
+        opcode
+    }
 
-        self.set_pc(0xC000);
-        // self.set_pc(0xC000);
+    fn decode(&mut self, pc: u16) -> u8 {
+        let opcode = self.memory.read_byte(pc);
+        let (op, mode) = Self::decode_instruction(opcode);
+        self.current = CurrentInstruction { op, mode };
+        opcode
+    }
+
+    /// Cache decoded instructions at $8000-$FFFF, see `decode_cache`. It's faster for long
+    /// runs without a window, and runs everything exactly as it would without.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled.then(|| Box::new(DecodeCache::new()));
+    }
+
+    pub fn decode_cache(&self) -> Option<&DecodeCache> {
+        self.decode_cache.as_deref()
+    }
+
+    /// Count CPU cycles and clock the PPU along with them
+    fn add_cycles(&mut self, cycles: u32) {
+        self.tick += cycles as usize;
+        self.memory.tick(cycles);
+    }
+
+    // https://www.nesdev.org/wiki/CPU_power_up_state
+    // the reset button: through the reset vector like an interrupt that doesn't push anything,
+    // leaving RAM and the other registers as they were
+    pub fn reset(&mut self) {
+        self.jammed = None;
+        self.memory.reset();
+        self.reg.sp = self.reg.sp.wrapping_sub(3);
+        self.reg.flags.interrupt_disable = true;
+        self.reg.pc = self.memory.read_word(RESET_VECTOR);
+        self.add_cycles(INTERRUPT_CYCLES);
+    }
+
+    /// What RAM and the registers start as at power on, from the next load or power cycle
+    pub fn set_power_on(&mut self, config: PowerOnConfig) {
+        self.power_on = config;
+    }
+
+    pub fn power_on_config(&self) -> &PowerOnConfig {
+        &self.power_on
+    }
+
+    fn power_on(&mut self) {
+        self.memory.power_on(&self.power_on);
+        self.reg = Registers::new();
+        self.reg.accumulator = self.power_on.accumulator;
+        self.reg.idx = self.power_on.x;
+        self.reg.idy = self.power_on.y;
+    }
+
+    /// Switch the console off and on again, resetting RAM and every register but keeping the
+    /// cartridge in
+    pub fn power_cycle(&mut self) {
+        self.jammed = None;
+        self.power_on();
+        self.current = CurrentInstruction::new();
+        self.tick = 0;
+        self.extra_cycles = 0;
+        self.reg.pc = self.memory.read_word(RESET_VECTOR);
+        self.add_cycles(INTERRUPT_CYCLES);
+    }
+
+    // https://www.nesdev.org/wiki/NMI
+    pub fn nmi(&mut self) {
+        self.interrupt(NMI_VECTOR);
+    }
+
+    // https://www.nesdev.org/wiki/IRQ
+    // level triggered, taken before each instruction while the line is held and I is clear
+    pub fn irq(&mut self) {
+        self.interrupt(IRQ_VECTOR);
+    }
+
+    fn interrupt(&mut self, vector: u16) {
+        self.push_stack_u16(self.reg.pc);
+        self.push_stack(self.reg.flags.as_byte());
+        self.reg.flags.interrupt_disable = true;
+        self.reg.pc = self.memory.read_word(vector);
+        self.add_cycles(INTERRUPT_CYCLES);
+    }
+
+    /// Power on with `rom` inserted, starting from its reset vector
+    pub fn load_rom(&mut self, rom: &NesRom) -> Result<(), RomError> {
+        self.memory.insert_cartridge(mapper::from_rom(rom)?);
+        self.memory.set_region(rom.region());
+        self.power_cycle();
+        Ok(())
+    }
+
+    /// Boot the FDS BIOS with `disk` in the drive
+    pub fn load_fds(&mut self, disk: &FdsDisk, bios: &[u8]) -> Result<(), RomError> {
+        self.memory
+            .insert_cartridge(Box::new(Fds::new(bios, disk)?));
+        self.memory.set_region(Region::Ntsc);
+        self.power_cycle();
+        Ok(())
     }
 
     pub fn load_bytes(&mut self, data: &[u8]) {
@@ -809,42 +1318,39 @@ impl NesCpu {
         // self.set_pc(0xC000);
     }
 
-    // 0x00
-    // TODO need to push address onto stack and set block bit
+    // https://www.nesdev.org/wiki/Status_flags#The_B_flag
+    // BRK is an IRQ from software: it skips a padding byte and pushes the flags with B set
     fn breakpoint(&mut self) {
-        // add PC
-        println!("BREAKPOINT: 0x{:X}", self.reg.pc);
-
-        // Buffer to hold the input
-        let mut input = String::new();
-
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line!");
-        self.next();
+        self.push_stack_u16(self.reg.pc.wrapping_add(2));
+        self.push_stack(self.reg.flags.as_byte() | 0b0001_0000);
+        self.reg.flags.interrupt_disable = true;
+        self.reg.pc = self.memory.read_word(IRQ_VECTOR);
     }
 
-    fn compare_register(&mut self) {
-        let address = self.get_mode_address();
+    fn compare_register(&mut self) -> Result<(), CpuError> {
+        let address = self.get_mode_address()?;
         let value = match self.current.mode {
             AddressingMode::Immediate => self.next_byte(),
             _ => self.memory.read_byte(address),
         };
 
         let register = match self.current.op {
-            Instructions::CompareAccumulator => &mut self.reg.accumulator,
-            Instructions::CompareX => &mut self.reg.idx,
-            Instructions::CompareY => &mut self.reg.idy,
-            _ => panic!("invalid current.op {:?}", self.current.op),
+            Instructions::CompareAccumulator => self.reg.accumulator,
+            Instructions::CompareX => self.reg.idx,
+            Instructions::CompareY => self.reg.idy,
+            _ => return Err(self.bad_operands()),
         };
-        let result = register.wrapping_sub(value);
-
-        self.reg.flags.carry = *register >= value;
-        self.update_zero_and_negative(result);
+        self.compare(register, value);
         self.next();
+        Ok(())
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.reg.flags.carry = register >= value;
+        self.update_zero_and_negative(register.wrapping_sub(value));
     }
 
-    fn branch(&mut self) {
+    fn branch(&mut self) -> Result<(), CpuError> {
         let condition = match self.current.op {
             Instructions::BranchOnResultMinus => self.reg.flags.negative,
             Instructions::BranchOnResultZero => self.reg.flags.zero,
@@ -854,27 +1360,68 @@ impl NesCpu {
             Instructions::BranchOverflowClear => !self.reg.flags.overflow,
             Instructions::BranchOnCarrySet => self.reg.flags.carry,
             Instructions::BranchOnCarryClear => !self.reg.flags.carry,
-            _ => panic!("Invalid instruction for branch: {:?}", self.current.op),
+            _ => return Err(self.bad_operands()),
         };
 
         if condition {
+            let next = self.reg.pc.wrapping_add(2);
             self.reg.pc = match self.current.mode {
                 AddressingMode::Relative => {
+                    // the offset is signed, backwards branches are common
                     let value = self.next_byte();
-                    self.reg.pc + 2 + value as u16
+                    next.wrapping_add(value as i8 as u16)
                 }
-                _ => panic!("Unimplemented! Branch: {:?}", self.current.mode),
+                _ => return Err(self.bad_operands()),
+            };
+            // taken branches cost a cycle, two when landing on another page
+            self.extra_cycles = if next & 0xFF00 != self.reg.pc & 0xFF00 {
+                2
+            } else {
+                1
             };
         } else {
             self.next();
         }
+        Ok(())
     }
 }
 
 // still need to test that flags are set correctly in most tests
+
+impl SaveState for NesCpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.reg.pc);
+        state.u8(self.reg.sp);
+        state.u8(self.reg.accumulator);
+        state.u8(self.reg.idx);
+        state.u8(self.reg.idy);
+        state.u8(self.reg.flags.as_byte());
+        state.u64(self.tick as u64);
+        state.u32(self.extra_cycles);
+        self.memory.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reg.pc = state.u16()?;
+        self.reg.sp = state.u8()?;
+        self.reg.accumulator = state.u8()?;
+        self.reg.idx = state.u8()?;
+        self.reg.idy = state.u8()?;
+        self.reg.flags.set_byte(state.u8()?);
+        self.tick = state.u64()? as usize;
+        self.extra_cycles = state.u32()?;
+        self.jammed = None;
+        // the calls it made before the state was saved aren't known
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.clear_frames();
+        }
+        self.memory.load_state(state)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::cpu::{NesCpu, Processor};
+    use crate::cpu::{CpuError, NesCpu, Processor};
     use crate::instructions::{AddressingMode, Instructions};
     use crate::memory::Bus;
     mod stack {
@@ -906,7 +1453,7 @@ mod tests {
                 let sp = cpu.reg.sp;
                 cpu.fetch_decode_next();
                 assert_eq!(cpu.reg.sp, sp - 1);
-                assert_eq!(cpu.pop_stack(), 0xAF);
+                assert_eq!(cpu.pop_stack(), 0xBF);
             }
         }
         mod pla {
@@ -989,6 +1536,21 @@ mod tests {
                 assert_eq!(cpu.reg.sp, sp);
             }
         }
+        #[test]
+        fn wraps_around_page_one() {
+            let mut cpu = NesCpu::new_from_bytes(&[NesCpu::encode_instructions(
+                Instructions::PopAccOffStack,
+                AddressingMode::Implied,
+            )]);
+            cpu.reg.sp = 0x00;
+            cpu.push_stack(0x12);
+            assert_eq!(cpu.reg.sp, 0xFF);
+            assert_eq!(cpu.memory.peek_byte(0x0100), 0x12);
+            cpu.memory.write_byte(0x0100, 0x34);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.reg.accumulator, 0x34);
+            assert_eq!(cpu.reg.sp, 0x00);
+        }
     }
     mod loading_registers {
         use super::*;
@@ -1133,9 +1695,10 @@ mod tests {
                     0x10,
                 ]);
                 cpu.reg.idy = 5;
-                cpu.memory.write_byte(0x15, 0x10);
-                cpu.memory.write_byte(0x16, 0x10);
-                cpu.memory.write_byte(0x1010, 0x50);
+                // the pointer at $10 is $1010, Y is added after
+                cpu.memory.write_byte(0x10, 0x10);
+                cpu.memory.write_byte(0x11, 0x10);
+                cpu.memory.write_byte(0x1015, 0x50);
                 cpu.fetch_decode_next();
                 assert_eq!(cpu.reg.accumulator, 0x50);
             }
@@ -1373,10 +1936,10 @@ mod tests {
                 ]);
                 cpu.reg.accumulator = 0x42;
                 cpu.reg.idy = 0x4;
-                cpu.memory.write_byte(0x34, 0x00);
-                cpu.memory.write_byte(0x35, 0x10);
+                cpu.memory.write_byte(0x30, 0x00);
+                cpu.memory.write_byte(0x31, 0x10);
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.memory.read_byte(0x1000), 0x42);
+                assert_eq!(cpu.memory.read_byte(0x1004), 0x42);
             }
         }
 
@@ -1410,11 +1973,11 @@ mod tests {
                 let mut cpu = NesCpu::new_from_bytes(&[
                     NesCpu::encode_instructions(Instructions::StoreX, AddressingMode::Absolute),
                     0x10,
-                    0x34,
+                    0x04,
                 ]);
                 cpu.reg.idx = 0x15;
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.memory.read_byte(0x3410), 0x15);
+                assert_eq!(cpu.memory.read_byte(0x0410), 0x15);
             }
         }
         mod sty {
@@ -1447,11 +2010,11 @@ mod tests {
                 let mut cpu = NesCpu::new_from_bytes(&[
                     NesCpu::encode_instructions(Instructions::StoreY, AddressingMode::Absolute),
                     0x10,
-                    0x34,
+                    0x04,
                 ]);
                 cpu.reg.idy = 0x15;
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.memory.read_byte(0x3410), 0x15);
+                assert_eq!(cpu.memory.read_byte(0x0410), 0x15);
             }
         }
     }
@@ -1764,13 +2327,45 @@ mod tests {
                 let mut cpu = NesCpu::new_from_bytes(&[
                     NesCpu::encode_instructions(Instructions::Jump, AddressingMode::Indirect),
                     0x20,
-                    0x20,
+                    0x02,
                 ]);
-                cpu.memory.write_byte(0x2020, 0x21);
-                cpu.memory.write_byte(0x2021, 0x34);
+                cpu.memory.write_byte(0x0220, 0x21);
+                cpu.memory.write_byte(0x0221, 0x34);
                 cpu.fetch_decode_next();
                 assert_eq!(cpu.reg.pc, 0x3421);
             }
+            #[test]
+            fn jmp_indirect_wraps_in_the_page() {
+                let mut cpu = NesCpu::new_from_bytes(&[
+                    NesCpu::encode_instructions(Instructions::Jump, AddressingMode::Indirect),
+                    0xFF,
+                    0x02,
+                ]);
+                cpu.memory.write_byte(0x02FF, 0x21);
+                cpu.memory.write_byte(0x0200, 0x34);
+                cpu.memory.write_byte(0x0300, 0x56);
+                cpu.fetch_decode_next();
+                assert_eq!(cpu.reg.pc, 0x3421);
+            }
+        }
+        mod brk {
+            use super::*;
+            use crate::memory::Bus;
+            #[test]
+            fn brk() {
+                let mut cpu = NesCpu::new_from_bytes(&[NesCpu::encode_instructions(
+                    Instructions::ForceBreak,
+                    AddressingMode::Implied,
+                )]);
+                cpu.memory.write_byte(0xFFFE, 0x00);
+                cpu.memory.write_byte(0xFFFF, 0x90);
+                cpu.reg.flags.set_byte(0x00);
+                cpu.fetch_decode_next();
+                assert_eq!(cpu.reg.pc, 0x9000);
+                assert!(cpu.reg.flags.interrupt_disable);
+                assert_eq!(cpu.pop_stack(), 0x30);
+                assert_eq!(cpu.pop_stack_u16(), 0x8002);
+            }
         }
         mod jsr {
             use super::*;
@@ -1991,6 +2586,144 @@ mod tests {
             }
         }
     }
+    mod timing {
+        use super::*;
+        #[test]
+        fn base_cycles() {
+            let mut cpu = NesCpu::new_from_bytes(&[
+                NesCpu::encode_instructions(
+                    Instructions::LoadAccumulator,
+                    AddressingMode::Immediate,
+                ),
+                0x01,
+                NesCpu::encode_instructions(Instructions::IncrementMem, AddressingMode::Absolute),
+                0x00,
+                0x02,
+            ]);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.tick, 2);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.tick, 8);
+        }
+        #[test]
+        fn page_cross_penalty() {
+            let mut cpu = NesCpu::new_from_bytes(&[
+                NesCpu::encode_instructions(
+                    Instructions::LoadAccumulator,
+                    AddressingMode::AbsoluteX,
+                ),
+                0x80,
+                0x02,
+                NesCpu::encode_instructions(
+                    Instructions::LoadAccumulator,
+                    AddressingMode::AbsoluteX,
+                ),
+                0xFF,
+                0x02,
+            ]);
+            cpu.reg.idx = 1;
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.tick, 4);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.tick, 9);
+        }
+        #[test]
+        fn branch_taken_cycles() {
+            let mut cpu = NesCpu::new_from_bytes(&[
+                NesCpu::encode_instructions(
+                    Instructions::BranchOnCarryClear,
+                    AddressingMode::Relative,
+                ),
+                0x02,
+            ]);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.reg.pc, 0x8004);
+            assert_eq!(cpu.tick, 3);
+        }
+        #[test]
+        fn ppu_clocked_three_dots_per_cycle() {
+            let mut cpu = NesCpu::new_from_bytes(&[
+                NesCpu::encode_instructions(
+                    Instructions::LoadAccumulator,
+                    AddressingMode::Immediate,
+                ),
+                0x01,
+            ]);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.memory.ppu().dot(), 6);
+        }
+        #[test]
+        fn nmi_on_vblank() {
+            let mut cpu = NesCpu::new_from_bytes(&[
+                NesCpu::encode_instructions(Instructions::Jump, AddressingMode::Absolute),
+                0x00,
+                0x80,
+            ]);
+            cpu.memory.write_bytes(0xFFFA, &[0x00, 0x90]);
+            // handler spins in place so the pc stays put once the NMI is taken
+            cpu.memory.write_bytes(0x9000, &[0x4C, 0x00, 0x90]);
+            cpu.memory.write_byte(0x2000, 0x80);
+            while cpu.reg.pc != 0x9000 {
+                cpu.fetch_decode_next();
+                assert!(cpu.tick < 40_000, "no NMI within a frame");
+            }
+            assert!(cpu.memory.ppu().in_vblank());
+            assert!(cpu.reg.flags.interrupt_disable);
+            assert_eq!(cpu.pop_stack(), 0x24);
+            assert_eq!(cpu.pop_stack_u16(), 0x8000);
+        }
+        #[test]
+        fn dmc_irq_after_sample_ends() {
+            // CLI, then JMP to itself
+            let mut cpu = NesCpu::new_from_bytes(&[0x58, 0x4C, 0x01, 0x80]);
+            cpu.memory.write_bytes(0xFFFE, &[0x00, 0x90]);
+            cpu.memory.write_bytes(0x9000, &[0x4C, 0x00, 0x90]);
+            // irq on, fastest rate, a single byte sample
+            cpu.memory.write_byte(0x4010, 0x8F);
+            cpu.memory.write_byte(0x4013, 0x00);
+            cpu.memory.write_byte(0x4015, 0x10);
+            while cpu.reg.pc != 0x9000 {
+                cpu.fetch_decode_next();
+                assert!(cpu.tick < 1_000, "no DMC IRQ");
+            }
+            assert!(cpu.reg.flags.interrupt_disable);
+            // pushed after CLI ran, so I is clear in the saved flags
+            assert_eq!(cpu.pop_stack(), 0x20);
+            assert_eq!(cpu.pop_stack_u16(), 0x8001);
+        }
+        #[test]
+        fn reset_keeps_ram() {
+            let mut cpu = NesCpu::new_from_bytes(&[0xEA]);
+            cpu.memory.write_bytes(0xFFFC, &[0x00, 0x90]);
+            cpu.memory.write_byte(0x0300, 0x55);
+            cpu.reg.accumulator = 0x42;
+            cpu.reg.flags.interrupt_disable = false;
+            cpu.reset();
+            assert_eq!(cpu.reg.pc, 0x9000);
+            assert_eq!(cpu.reg.sp, 0xFA);
+            assert!(cpu.reg.flags.interrupt_disable);
+            assert_eq!(cpu.reg.accumulator, 0x42);
+            assert_eq!(cpu.memory.read_byte(0x0300), 0x55);
+            assert_eq!(cpu.tick, 7);
+        }
+        #[test]
+        fn power_cycle_clears_ram() {
+            let mut cpu = NesCpu::new_from_bytes(&[0xEA]);
+            cpu.memory.write_bytes(0xFFFC, &[0x00, 0x90]);
+            cpu.memory.write_byte(0x0300, 0x55);
+            cpu.memory.write_byte(0x2000, 0x80);
+            cpu.reg.accumulator = 0x42;
+            cpu.fetch_decode_next();
+            cpu.power_cycle();
+            assert_eq!(cpu.reg.pc, 0x9000);
+            assert_eq!(cpu.reg.sp, 0xFD);
+            assert_eq!(cpu.reg.accumulator, 0);
+            assert_eq!(cpu.memory.read_byte(0x0300), 0);
+            assert_eq!(cpu.tick, 7);
+            // program space isn't RAM
+            assert_eq!(cpu.memory.read_byte(0x8000), 0xEA);
+        }
+    }
     mod flags {
         // fully tested, decimal not used in nes 6502 variant.
         use super::*;
@@ -2003,7 +2736,7 @@ mod tests {
                     AddressingMode::Implied,
                 )]);
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.reg.flags.interrupt_disable, true);
+                assert!(cpu.reg.flags.interrupt_disable);
             }
         }
         mod cli {
@@ -2015,7 +2748,7 @@ mod tests {
                     AddressingMode::Implied,
                 )]);
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.reg.flags.interrupt_disable, false);
+                assert!(!cpu.reg.flags.interrupt_disable);
             }
         }
         mod sec {
@@ -2027,7 +2760,7 @@ mod tests {
                     AddressingMode::Implied,
                 )]);
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.reg.flags.carry, true);
+                assert!(cpu.reg.flags.carry);
             }
         }
         mod clc {
@@ -2040,7 +2773,7 @@ mod tests {
                 )]);
                 cpu.reg.flags.carry = true;
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.reg.flags.carry, false);
+                assert!(!cpu.reg.flags.carry);
             }
         }
         mod clv {
@@ -2053,8 +2786,117 @@ mod tests {
                 )]);
                 cpu.reg.flags.overflow = true;
                 cpu.fetch_decode_next();
-                assert_eq!(cpu.reg.flags.overflow, false);
+                assert!(!cpu.reg.flags.overflow);
+            }
+        }
+    }
+    mod programs {
+        use super::*;
+        use crate::assembler::assemble;
+        #[test]
+        fn multiply_subroutine() {
+            let program = assemble(
+                "
+                        LDA #7
+                        LDX #6
+                        JSR multiply
+                        STA $10
+                done:   JMP done
+
+                ; A = A * X, for X > 0
+                multiply:
+                        STA $00
+                        LDA #0
+                        CLC
+                loop:   ADC $00
+                        DEX
+                        BNE loop
+                        RTS
+                ",
+            )
+            .unwrap();
+            let mut cpu = NesCpu::new_from_bytes(&program);
+            for _ in 0..40 {
+                cpu.fetch_decode_next();
+            }
+            assert_eq!(cpu.memory.read_byte(0x0010), 42);
+            assert_eq!(cpu.reg.pc, 0x8009);
+        }
+    }
+
+    mod errors {
+        use super::*;
+        use crate::instructions::CurrentInstruction;
+
+        #[test]
+        fn bad_operands_stop_the_cpu() {
+            // INC ($0200) doesn't exist, only a hand built instruction gets here
+            let mut cpu = NesCpu::new_from_bytes(&[0xEE, 0x00, 0x02]);
+            cpu.current = CurrentInstruction {
+                op: Instructions::IncrementMem,
+                mode: AddressingMode::Indirect,
+            };
+            let error = CpuError::BadOperands {
+                op: Instructions::IncrementMem,
+                mode: AddressingMode::Indirect,
+                pc: 0x8000,
+            };
+            assert_eq!(cpu.execute(), Err(error));
+            assert_eq!(cpu.reg.pc, 0x8000);
+        }
+
+        #[test]
+        fn rts_from_the_top_of_memory_wraps() {
+            let mut cpu = NesCpu::new_from_bytes(&[0x60]);
+            cpu.memory.set_flat(true);
+            cpu.reg.set_sp(0xFD);
+            cpu.memory.write_byte(0x01FE, 0xFF);
+            cpu.memory.write_byte(0x01FF, 0xFF);
+            cpu.fetch_decode_next();
+            assert_eq!(cpu.reg.pc, 0x0000);
+            assert_eq!(cpu.error(), None);
+        }
+    }
+
+    mod decode_cache {
+        use super::*;
+
+        #[test]
+        fn loops_come_from_the_cache() {
+            // LDX #$00, INX, JMP $8002
+            let mut cpu = NesCpu::new_from_bytes(&[0xA2, 0x00, 0xE8, 0x4C, 0x02, 0x80]);
+            cpu.set_decode_cache(true);
+            for _ in 0..21 {
+                cpu.fetch_decode_next();
+            }
+            assert_eq!(cpu.reg.idx, 10);
+            let cache = cpu.decode_cache().unwrap();
+            assert_eq!((cache.misses(), cache.hits()), (3, 18));
+        }
+
+        #[test]
+        fn code_that_rewrites_itself_is_decoded_again() {
+            // LDA #$00, INC $8001, JMP $8000: each time round loads one more
+            let mut cpu = NesCpu::new_from_bytes(&[0xA9, 0x00, 0xEE, 0x01, 0x80, 0x4C, 0x00, 0x80]);
+            cpu.set_decode_cache(true);
+            for _ in 0..9 {
+                cpu.fetch_decode_next();
+            }
+            assert_eq!(cpu.reg.accumulator, 2);
+        }
+
+        #[test]
+        fn steps_aside_for_a_bus_trace() {
+            let mut cpu = NesCpu::new_from_bytes(&[0xE8, 0x4C, 0x00, 0x80]);
+            cpu.set_decode_cache(true);
+            cpu.memory.enable_trace(16);
+            for _ in 0..6 {
+                cpu.fetch_decode_next();
             }
+            let cache = cpu.decode_cache().unwrap();
+            assert_eq!((cache.misses(), cache.hits()), (0, 0));
+            let fetches = cpu.memory.drain_trace();
+            assert!(fetches.iter().any(|access| access.address == 0x8000));
         }
     }
 }
@@ -0,0 +1,234 @@
+use crate::hash::{from_hex, to_hex};
+use crate::header::Timing;
+use crate::mapper::Mirroring;
+use crate::NesRom;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::{fs, io};
+
+// Known good dumps, for fixing roms with bad or missing header information. The format is
+// described at the top of data/games.txt, which is built in unless the `game-db` feature is
+// turned off. The built in file only lists the test roms so far.
+
+#[cfg(feature = "game-db")]
+const BUILTIN: &str = include_str!("../data/games.txt");
+#[cfg(not(feature = "game-db"))]
+const BUILTIN: &str = "";
+
+/// What the database knows about one dump
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameEntry {
+    /// CRC32 of PRG and CHR ROM
+    pub crc32: u32,
+    /// SHA-1 of PRG and CHR ROM
+    pub sha1: [u8; 20],
+    pub mapper: u16,
+    pub submapper: u8,
+    /// None leaves the header's mirroring alone
+    pub mirroring: Option<Mirroring>,
+    pub timing: Option<Timing>,
+    pub battery: bool,
+    /// Cartridge board name, e.g. NES-NROM-256
+    pub board: String,
+    pub title: String,
+}
+
+impl GameEntry {
+    fn parse(line: &str) -> Option<GameEntry> {
+        let mut fields = line.splitn(8, char::is_whitespace);
+        let crc32 = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let sha1 = from_hex(fields.next()?)?.try_into().ok()?;
+        let mapper = fields.next()?;
+        let (mapper, submapper) = match mapper.split_once('.') {
+            Some((mapper, submapper)) => (mapper.parse().ok()?, submapper.parse().ok()?),
+            None => (mapper.parse().ok()?, 0),
+        };
+        let mirroring = match fields.next()? {
+            "H" => Some(Mirroring::Horizontal),
+            "V" => Some(Mirroring::Vertical),
+            "4" => Some(Mirroring::FourScreen),
+            "-" => None,
+            _ => return None,
+        };
+        let timing = match fields.next()? {
+            "NTSC" => Some(Timing::Ntsc),
+            "PAL" => Some(Timing::Pal),
+            "Dendy" => Some(Timing::Dendy),
+            "Multi" => Some(Timing::MultiRegion),
+            "-" => None,
+            _ => return None,
+        };
+        let battery = match fields.next()? {
+            "B" => true,
+            "-" => false,
+            _ => return None,
+        };
+        Some(GameEntry {
+            crc32,
+            sha1,
+            mapper,
+            submapper,
+            mirroring,
+            timing,
+            battery,
+            board: fields.next()?.to_string(),
+            title: fields.next()?.trim().to_string(),
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        let mapper = if self.submapper == 0 {
+            self.mapper.to_string()
+        } else {
+            format!("{}.{}", self.mapper, self.submapper)
+        };
+        let mirroring = match self.mirroring {
+            Some(Mirroring::Horizontal) => "H",
+            Some(Mirroring::Vertical) => "V",
+            Some(Mirroring::FourScreen) => "4",
+            _ => "-",
+        };
+        let timing = match self.timing {
+            Some(Timing::Ntsc) => "NTSC",
+            Some(Timing::Pal) => "PAL",
+            Some(Timing::Dendy) => "Dendy",
+            Some(Timing::MultiRegion) => "Multi",
+            None => "-",
+        };
+        format!(
+            "{:08X} {} {} {} {} {} {} {}",
+            self.crc32,
+            to_hex(&self.sha1),
+            mapper,
+            mirroring,
+            timing,
+            if self.battery { "B" } else { "-" },
+            self.board,
+            self.title
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameDatabase {
+    entries: Vec<GameEntry>,
+}
+
+impl GameDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The database compiled into the emulator, empty without the `game-db` feature
+    pub fn builtin() -> &'static GameDatabase {
+        static DATABASE: OnceLock<GameDatabase> = OnceLock::new();
+        DATABASE.get_or_init(|| GameDatabase::from_text(BUILTIN).expect("built in game database"))
+    }
+
+    pub fn from_text(text: &str) -> io::Result<Self> {
+        let mut database = GameDatabase::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = GameEntry::parse(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("game database line {}: {:?}", number + 1, line),
+                )
+            })?;
+            database.insert(entry);
+        }
+        Ok(database)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_text(&fs::read_to_string(path)?)
+    }
+
+    /// Add an entry, replacing any with the same SHA-1
+    pub fn insert(&mut self, entry: GameEntry) {
+        self.entries.retain(|existing| existing.sha1 != entry.sha1);
+        self.entries.push(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find a dump by its hashes, both have to match
+    pub fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.crc32 == crc32 && &entry.sha1 == sha1)
+    }
+
+    pub fn lookup_rom(&self, rom: &NesRom) -> Option<&GameEntry> {
+        self.lookup(rom.crc32(), &rom.sha1())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str =
+        "158B0388 4131307F0F69F2A5C54B7D438328C5B2A5ED0820 4.1 V PAL B NES-TLROM Some Game (E)";
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = GameEntry::parse(LINE).unwrap();
+        assert_eq!(entry.crc32, 0x158B0388);
+        assert_eq!(entry.mapper, 4);
+        assert_eq!(entry.submapper, 1);
+        assert_eq!(entry.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(entry.timing, Some(Timing::Pal));
+        assert!(entry.battery);
+        assert_eq!(entry.board, "NES-TLROM");
+        assert_eq!(entry.title, "Some Game (E)");
+        assert_eq!(entry.to_line(), LINE);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        for line in [
+            "158B0388 4131307F 0 H NTSC - NES-NROM-128 short sha1",
+            "158B0388 4131307F0F69F2A5C54B7D438328C5B2A5ED0820 0 X NTSC - NES-NROM-128 bad",
+            "158B0388 4131307F0F69F2A5C54B7D438328C5B2A5ED0820 0 H NTSC -",
+        ] {
+            assert!(GameDatabase::from_text(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn lookup_needs_both_hashes() {
+        let database = GameDatabase::from_text(&format!("# comment\n\n{}\n", LINE)).unwrap();
+        let entry = GameEntry::parse(LINE).unwrap();
+        assert!(database.lookup(entry.crc32, &entry.sha1).is_some());
+        assert!(database.lookup(entry.crc32 ^ 1, &entry.sha1).is_none());
+    }
+
+    #[cfg(feature = "game-db")]
+    #[test]
+    fn builtin_database_parses() {
+        assert!(!GameDatabase::builtin().is_empty());
+    }
+
+    #[cfg(feature = "game-db")]
+    #[test]
+    fn builtin_mappers_are_supported() {
+        for entry in &GameDatabase::builtin().entries {
+            let mut image = b"NES\x1A\x01\x01".to_vec();
+            image.push((entry.mapper as u8 & 0x0F) << 4);
+            image.push(entry.mapper as u8 & 0xF0);
+            image.resize(16 + 16384 + 8192, 0);
+            let rom = NesRom::from_bytes(&image).unwrap();
+            assert!(crate::mapper::from_rom(&rom).is_ok(), "{}", entry.title);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::instructions::CurrentInstruction;
+
+// Cached interpreter: what the CPU decoded at each address of $8000-$FFFF, so running the same
+// code again skips reading the opcode and operands through the bus and decoding them. Each
+// entry is stamped with the bus's code generation when it was decoded, which moves on whenever
+// what's mapped there could change: cartridge writes the board says can change $8000-$FFFF (bank
+// switches, FDS RAM), cheats changing, loading and power on. A stale stamp is a miss, so
+// invalidating costs nothing however much is cached.
+//
+// Code below $8000 (RAM, PRG RAM) is read every time. With the cache on the bus doesn't see
+// instruction fetches, so it steps aside while anything that watches them (bus trace, profiler,
+// coverage, event log) is on, and runs exactly as it would without.
+
+/// Lowest address cached
+pub const CACHED_START: u16 = 0x8000;
+// instructions any later run into the vectors' wrap around to $0000 and aren't cached
+const CACHED_END: u16 = 0xFFFD;
+
+/// An instruction as it was decoded
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    pub opcode: u8,
+    pub instruction: CurrentInstruction,
+    /// The two bytes after the opcode, whether the instruction uses them or not
+    pub operands: [u8; 2],
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    // 0 is never a bus generation, so new entries miss
+    generation: u64,
+    decoded: Decoded,
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodeCache {
+    entries: Box<[Entry]>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        let empty = Entry {
+            generation: 0,
+            decoded: Decoded {
+                opcode: 0,
+                instruction: CurrentInstruction::new(),
+                operands: [0; 2],
+            },
+        };
+        DecodeCache {
+            entries: vec![empty; (CACHED_END - CACHED_START) as usize + 1].into_boxed_slice(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn is_cached(address: u16) -> bool {
+        (CACHED_START..=CACHED_END).contains(&address)
+    }
+
+    /// The instruction at `address` if it was decoded in `generation`
+    pub fn get(&mut self, address: u16, generation: u64) -> Option<Decoded> {
+        if !Self::is_cached(address) {
+            return None;
+        }
+        let entry = &self.entries[(address - CACHED_START) as usize];
+        if entry.generation == generation {
+            self.hits += 1;
+            Some(entry.decoded.clone())
+        } else {
+            self.misses += 1;
+            None
+        }
+    }
+
+    /// Keep what was decoded at `address`, unless it's outside the cached range
+    pub fn insert(&mut self, address: u16, generation: u64, decoded: Decoded) {
+        if Self::is_cached(address) {
+            self.entries[(address - CACHED_START) as usize] = Entry {
+                generation,
+                decoded,
+            };
+        }
+    }
+
+    /// Instructions found decoded already
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Instructions that had to be read and decoded
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
@@ -0,0 +1,535 @@
+use crate::cpu::{NesCpu, Processor};
+use crate::instructions::{AddressingMode, Instructions};
+use crate::mapper::PRG_BANK_SIZE;
+use crate::memory::Bus;
+use crate::symbols::SymbolTable;
+use crate::NesRom;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Two ways of telling code from data. Linear sweep takes every byte as the start of the next
+// instruction, so data mixed in with the code comes out as nonsense instructions. Tracing starts
+// from entry points, usually the vectors, and follows branches, jumps and calls, so everything
+// it reaches is code and the rest is shown as `.byte` data. Code only reached through jump
+// tables or pushed return addresses is missed and shows up as data too.
+//
+// A PRG bank is traced from whichever vectors land in it, which on most boards is only the
+// last bank. The others fall back to linear sweep, there's no telling where their code starts.
+//
+// Jump, call and branch targets inside the disassembled code get labels, used in place of the
+// address in the operand: `reset`, `nmi` and `irq` for the vectors, `sub_XXXX` for subroutines
+// and `L_XXXX` for the rest. Labels from a symbol file take the place of those, and go on data
+// and on targets outside the code too. An instruction cut off by the end of the input comes out
+// as `.byte`.
+
+/// Data bytes per `.byte` line
+const DATA_PER_LINE: usize = 8;
+const VECTORS: [(u16, &str); 3] = [(0xFFFA, "nmi"), (0xFFFC, "reset"), (0xFFFE, "irq")];
+
+/// One disassembled instruction, or a run of data bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Mnemonic and operand, such as `LDA ($20),Y`, or `.byte` and the data
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    // laid out like the nestest log: address, up to three bytes, instruction. Longer data lines
+    // spell their bytes out in the text already.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = match self.bytes.len() {
+            0..=3 => self.bytes.iter().map(|b| format!("{:02X}", b)).collect(),
+            _ => Vec::new(),
+        };
+        write!(
+            f,
+            "{:04X}  {:<8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Disassembled code with labels for where it jumps to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+impl Disassembly {
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            if let Some(label) = self.label(line.address) {
+                writeln!(f, "{}:", label)?;
+            }
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn operand(mode: &AddressingMode, address: u16, bytes: &[u8]) -> String {
+    let byte = || bytes[1];
+    let word = || u16::from_le_bytes([bytes[1], bytes[2]]);
+    match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", byte()),
+        AddressingMode::ZeroPage => format!("${:02X}", byte()),
+        AddressingMode::ZeroPageX => format!("${:02X},X", byte()),
+        AddressingMode::ZeroPageY => format!("${:02X},Y", byte()),
+        AddressingMode::Absolute => format!("${:04X}", word()),
+        AddressingMode::AbsoluteX => format!("${:04X},X", word()),
+        AddressingMode::AbsoluteY => format!("${:04X},Y", word()),
+        AddressingMode::Indirect => format!("(${:04X})", word()),
+        AddressingMode::XIndirect => format!("(${:02X},X)", byte()),
+        AddressingMode::YIndirect => format!("(${:02X}),Y", byte()),
+        AddressingMode::Relative => format!("${:04X}", branch_target(address, byte())),
+    }
+}
+
+fn branch_target(address: u16, offset: u8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+/// An instruction decoded from the input
+struct Decoded<'a> {
+    instruction: Instructions,
+    mode: AddressingMode,
+    bytes: &'a [u8],
+}
+
+impl Decoded<'_> {
+    /// Where a branch, jump or call goes, when it's known without running it
+    fn target(&self, address: u16) -> Option<u16> {
+        match (&self.instruction, &self.mode) {
+            (_, AddressingMode::Relative) => Some(branch_target(address, self.bytes[1])),
+            (Instructions::Jump | Instructions::JumpSubroutine, AddressingMode::Absolute) => {
+                Some(u16::from_le_bytes([self.bytes[1], self.bytes[2]]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the next instruction never runs after this one
+    fn ends_flow(&self) -> bool {
+        matches!(
+            self.instruction,
+            Instructions::Jump
+                | Instructions::ReturnFromSubroutine
+                | Instructions::ReturnFromInterrupt
+                | Instructions::ForceBreak
+                | Instructions::JAM
+        )
+    }
+
+    fn text(&self, address: u16, label: &dyn Fn(u16) -> Option<String>) -> String {
+        let operand = match self.target(address).and_then(label) {
+            Some(label) => label,
+            None => operand(&self.mode, address, self.bytes),
+        };
+        if operand.is_empty() {
+            self.instruction.asm().to_string()
+        } else {
+            format!("{} {}", self.instruction.asm(), operand)
+        }
+    }
+}
+
+/// The instruction at `offset`, None when it's cut off by the end of `code`
+fn decode(code: &[u8], offset: usize) -> Option<Decoded<'_>> {
+    let (instruction, mode) = NesCpu::decode_instruction(*code.get(offset)?);
+    let bytes = code.get(offset..offset + mode.get_increment() as usize)?;
+    Some(Decoded {
+        instruction,
+        mode,
+        bytes,
+    })
+}
+
+/// Offsets of the instructions linear sweep finds, up to any cut off at the end
+fn sweep(code: &[u8]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while let Some(decoded) = decode(code, offset) {
+        starts.push(offset);
+        offset += decoded.bytes.len();
+    }
+    starts
+}
+
+/// Offsets of the instructions reachable from `entries`, in order
+fn trace(code: &[u8], origin: u16, entries: &[u16]) -> Vec<usize> {
+    let offset_of = |address: u16| {
+        let offset = address.wrapping_sub(origin) as usize;
+        (offset < code.len()).then_some(offset)
+    };
+    let mut covered = vec![false; code.len()];
+    let mut starts = Vec::new();
+    let mut pending: Vec<usize> = entries.iter().filter_map(|&a| offset_of(a)).collect();
+    while let Some(mut offset) = pending.pop() {
+        // follow the flow until it leaves, or runs into code already seen
+        while let Some(decoded) = decode(code, offset) {
+            let length = decoded.bytes.len();
+            if covered[offset..offset + length].iter().any(|&c| c) {
+                break;
+            }
+            covered[offset..offset + length].fill(true);
+            starts.push(offset);
+            let address = origin.wrapping_add(offset as u16);
+            pending.extend(decoded.target(address).and_then(offset_of));
+            if decoded.ends_flow() {
+                break;
+            }
+            offset += length;
+        }
+    }
+    starts.sort_unstable();
+    starts
+}
+
+/// Lines for the instructions starting at `starts`, with everything in between as data
+fn lines(code: &[u8], origin: u16, starts: &[usize], labels: &BTreeMap<u16, String>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let data = |from: usize, to: usize, lines: &mut Vec<Line>| {
+        for (i, chunk) in code[from..to].chunks(DATA_PER_LINE).enumerate() {
+            let text: Vec<String> = chunk.iter().map(|b| format!("${:02X}", b)).collect();
+            lines.push(Line {
+                address: origin.wrapping_add((from + i * DATA_PER_LINE) as u16),
+                bytes: chunk.to_vec(),
+                text: format!(".byte {}", text.join(",")),
+            });
+        }
+    };
+    let mut offset = 0;
+    for &start in starts {
+        // a label in the middle of data starts a new line, so it has somewhere to go
+        let mut from = offset;
+        for split in
+            (offset..start).filter(|&o| labels.contains_key(&origin.wrapping_add(o as u16)))
+        {
+            data(from, split, &mut lines);
+            from = split;
+        }
+        data(from, start, &mut lines);
+        let address = origin.wrapping_add(start as u16);
+        let decoded = decode(code, start).expect("starts are whole instructions");
+        lines.push(Line {
+            address,
+            bytes: decoded.bytes.to_vec(),
+            text: decoded.text(address, &|target| labels.get(&target).cloned()),
+        });
+        offset = start + decoded.bytes.len();
+    }
+    data(offset, code.len(), &mut lines);
+    lines
+}
+
+/// Labels for the targets of the instructions at `starts` that are instructions too, and
+/// for the named entry points, then the `symbols` for anywhere in `code` or jumped to
+fn labels(
+    code: &[u8],
+    origin: u16,
+    starts: &[usize],
+    named: &[(u16, &str)],
+    symbols: &dyn Fn(u16) -> Option<String>,
+) -> BTreeMap<u16, String> {
+    let is_start = |address: u16| {
+        starts
+            .binary_search(&(address.wrapping_sub(origin) as usize))
+            .is_ok()
+    };
+    let mut labels = BTreeMap::new();
+    let mut targets = Vec::new();
+    for &start in starts {
+        let decoded = decode(code, start).expect("starts are whole instructions");
+        let Some(target) = decoded.target(origin.wrapping_add(start as u16)) else {
+            continue;
+        };
+        targets.push(target);
+        if !is_start(target) {
+            continue;
+        }
+        let label = match decoded.instruction {
+            Instructions::JumpSubroutine => format!("sub_{:04X}", target),
+            _ => format!("L_{:04X}", target),
+        };
+        // a subroutine that's also branched to is still a subroutine
+        labels
+            .entry(target)
+            .and_modify(|existing: &mut String| {
+                if label.starts_with("sub_") {
+                    existing.clone_from(&label);
+                }
+            })
+            .or_insert(label);
+    }
+    for &(address, name) in named {
+        if is_start(address) {
+            labels.insert(address, name.to_string());
+        }
+    }
+    let addresses = (0..code.len()).map(|offset| origin.wrapping_add(offset as u16));
+    for address in addresses.chain(targets) {
+        if let Some(name) = symbols(address) {
+            labels.insert(address, name);
+        }
+    }
+    labels
+}
+
+/// Disassemble `code` as if it were loaded at `origin`, by linear sweep and without labels
+pub fn disassemble(code: &[u8], origin: u16) -> Vec<Line> {
+    lines(code, origin, &sweep(code), &BTreeMap::new())
+}
+
+/// Disassemble `code` loaded at `origin` by tracing from the `entries`, named in the labels.
+/// Falls back to linear sweep when none of them are in `code`.
+pub fn disassemble_from(code: &[u8], origin: u16, entries: &[(u16, &str)]) -> Disassembly {
+    traced(code, origin, entries, &|_| None)
+}
+
+fn traced(
+    code: &[u8],
+    origin: u16,
+    entries: &[(u16, &str)],
+    symbols: &dyn Fn(u16) -> Option<String>,
+) -> Disassembly {
+    let addresses: Vec<u16> = entries.iter().map(|&(address, _)| address).collect();
+    let mut starts = trace(code, origin, &addresses);
+    if starts.is_empty() {
+        starts = sweep(code);
+    }
+    let labels = labels(code, origin, &starts, entries, symbols);
+    Disassembly {
+        lines: lines(code, origin, &starts, &labels),
+        labels,
+    }
+}
+
+/// Where PRG bank `bank` of `banks` is disassembled: the last one holds the vectors so it's at
+/// the top of memory, the others at $8000
+pub fn bank_origin(bank: usize, banks: usize) -> u16 {
+    if bank + 1 == banks {
+        (0x10000 - PRG_BANK_SIZE) as u16
+    } else {
+        0x8000
+    }
+}
+
+/// Disassemble a PRG bank loaded at `origin`, traced from the vectors it holds if any
+pub fn disassemble_bank(code: &[u8], origin: u16) -> Disassembly {
+    disassemble_bank_with_symbols(code, origin, 0, &SymbolTable::new())
+}
+
+/// Disassemble the PRG bank `prg_offset` into PRG ROM loaded at `origin`, labelled from
+/// `symbols`
+pub fn disassemble_bank_with_symbols(
+    code: &[u8],
+    origin: u16,
+    prg_offset: usize,
+    symbols: &SymbolTable,
+) -> Disassembly {
+    let label = |address: u16| {
+        let offset = address.wrapping_sub(origin) as usize;
+        let prg_offset = (offset < code.len()).then_some(prg_offset + offset);
+        let symbol = symbols.lookup(address, prg_offset)?;
+        Some(symbol.name.clone())
+    };
+    let end = origin as usize + code.len();
+    let entries: Vec<(u16, &str)> = VECTORS
+        .iter()
+        .filter(|&&(vector, _)| (origin as usize..end - 1).contains(&(vector as usize)))
+        .map(|&(vector, name)| {
+            let offset = (vector - origin) as usize;
+            (u16::from_le_bytes([code[offset], code[offset + 1]]), name)
+        })
+        .collect();
+    traced(code, origin, &entries, &label)
+}
+
+/// Every PRG bank of `rom`, with the address each was disassembled at
+pub fn disassemble_prg(rom: &NesRom) -> Vec<(u16, Disassembly)> {
+    disassemble_prg_with_symbols(rom, &SymbolTable::new())
+}
+
+/// Every PRG bank of `rom` labelled from `symbols`, with the address each was disassembled at
+pub fn disassemble_prg_with_symbols(
+    rom: &NesRom,
+    symbols: &SymbolTable,
+) -> Vec<(u16, Disassembly)> {
+    rom.prg_rom
+        .iter()
+        .enumerate()
+        .map(|(bank, code)| {
+            let origin = bank_origin(bank, rom.prg_rom.len());
+            let disassembly =
+                disassemble_bank_with_symbols(code, origin, bank * PRG_BANK_SIZE, symbols);
+            (origin, disassembly)
+        })
+        .collect()
+}
+
+/// `count` instructions of live CPU memory from `address` on, read without side effects so
+/// disassembling registers doesn't disturb them. Jumps to labelled addresses use the label.
+pub fn disassemble_memory(bus: &dyn Bus, address: u16, count: usize) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let bytes: Vec<u8> = (0..3)
+            .map(|i| bus.peek_byte(address.wrapping_add(i)))
+            .collect();
+        let decoded = decode(&bytes, 0).expect("three bytes hold any instruction");
+        lines.push(Line {
+            address,
+            bytes: decoded.bytes.to_vec(),
+            text: decoded.text(address, &|target| bus.label(target).map(str::to_string)),
+        });
+        address = address.wrapping_add(decoded.bytes.len() as u16);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::symbols::Location;
+
+    fn texts(code: &[u8], origin: u16) -> Vec<String> {
+        disassemble(code, origin)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn formats_operands() {
+        let code = [
+            0x4C, 0xF5, 0xC5, // JMP $C5F5
+            0xA9, 0x10, // LDA #$10
+            0xB1, 0x20, // LDA ($20),Y
+            0x6C, 0x00, 0x02, // JMP ($0200)
+            0x0A, // ASL A
+            0xD0, 0xFE, // BNE to itself
+            0x60, // RTS
+        ];
+        assert_eq!(
+            texts(&code, 0xC000),
+            [
+                "JMP $C5F5",
+                "LDA #$10",
+                "LDA ($20),Y",
+                "JMP ($0200)",
+                "ASL A",
+                "BNE $C00B",
+                "RTS"
+            ]
+        );
+    }
+
+    #[test]
+    fn cut_off_instructions_are_bytes() {
+        assert_eq!(texts(&[0xEA, 0x4C, 0x00], 0x8000), ["NOP", ".byte $4C,$00"]);
+    }
+
+    #[test]
+    fn line_layout() {
+        let lines = disassemble(&[0x4C, 0xF5, 0xC5, 0xEA], 0xC000);
+        assert_eq!(lines[0].to_string(), "C000  4C F5 C5  JMP $C5F5");
+        assert_eq!(lines[1].to_string(), "C003  EA        NOP");
+    }
+
+    #[test]
+    fn traces_from_the_vectors() {
+        let mut code = vec![0xFF; PRG_BANK_SIZE];
+        code[..12].copy_from_slice(&[
+            0x20, 0x08, 0xC0, // reset: JSR sub_C008
+            0x4C, 0x00, 0xC0, // JMP reset
+            0x12, 0x34, // data
+            0xCA, // sub_C008: DEX
+            0xD0, 0xFD, // BNE sub_C008
+            0x60, // RTS
+        ]);
+        code[PRG_BANK_SIZE - 4..].copy_from_slice(&[0x00, 0xC0, 0x0B, 0xC0]);
+        let disassembly = disassemble_bank(&code, 0xC000);
+        let text = disassembly.to_string();
+        assert!(text.starts_with(
+            "reset:\n\
+             C000  20 08 C0  JSR sub_C008\n\
+             C003  4C 00 C0  JMP reset\n\
+             C006  12 34     .byte $12,$34\n\
+             sub_C008:\n\
+             C008  CA        DEX\n\
+             C009  D0 FD     BNE sub_C008\n\
+             irq:\n\
+             C00B  60        RTS\n\
+             C00C            .byte $FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF\n"
+        ));
+        // the NMI vector points at data, so it's not a label
+        assert_eq!(disassembly.labels.len(), 3);
+    }
+
+    #[test]
+    fn banks_without_vectors_are_swept() {
+        let code = [0xA9, 0x00, 0xF0, 0xFC];
+        let disassembly = disassemble_bank(&code, 0x8000);
+        assert_eq!(disassembly.lines.len(), 2);
+        assert_eq!(disassembly.lines[1].text, "BEQ L_8000");
+        assert_eq!(bank_origin(0, 2), 0x8000);
+        assert_eq!(bank_origin(1, 2), 0xC000);
+    }
+
+    #[test]
+    fn live_memory() {
+        let mut memory = Memory::new();
+        memory.write_bytes(0x0300, &[0xA9, 0x01, 0x8D, 0x00, 0x20, 0xD0, 0xF9]);
+        let lines = disassemble_memory(&memory, 0x0300, 3);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["LDA #$01", "STA $2000", "BNE $0300"]);
+
+        let symbols = memory.symbols_mut();
+        symbols.add(Location::Cpu(0x0300), "ram_loop", "").unwrap();
+        assert_eq!(
+            disassemble_memory(&memory, 0x0305, 1)[0].text,
+            "BNE ram_loop"
+        );
+    }
+
+    #[test]
+    fn symbols_name_code_and_data() {
+        let mut code = vec![0xFF; PRG_BANK_SIZE];
+        code[..8].copy_from_slice(&[
+            0x20, 0x06, 0xC0, // reset: JSR sub_C006
+            0x20, 0x00, 0x03, // JSR $0300, code in RAM
+            0x60, // sub_C006: RTS
+            0x12, // data
+        ]);
+        code[PRG_BANK_SIZE - 4..].copy_from_slice(&[0x00, 0xC0, 0x06, 0xC0]);
+        let mut symbols = SymbolTable::new();
+        // the bank is the second of two, 16KB into PRG ROM
+        symbols.add(Location::Prg(0x4006), "update", "").unwrap();
+        symbols.add(Location::Cpu(0xC007), "table", "").unwrap();
+        symbols.add(Location::Cpu(0x0300), "ram_code", "").unwrap();
+        let disassembly = disassemble_bank_with_symbols(&code, 0xC000, 0x4000, &symbols);
+        assert!(disassembly.to_string().starts_with(
+            "reset:\n\
+             C000  20 06 C0  JSR update\n\
+             C003  20 00 03  JSR ram_code\n\
+             update:\n\
+             C006  60        RTS\n\
+             table:\n\
+             C007            .byte $12,$FF,$FF,$FF,$FF,$FF,$FF,$FF\n"
+        ));
+    }
+}
@@ -0,0 +1,1363 @@
+use crate::breakpoints::{BadCondition, Breakpoints, Condition};
+use crate::call_stack::CallStack;
+use crate::cheats::{BadCode, CheatList};
+use crate::coverage::Coverage;
+use crate::cpu::NesCpu;
+use crate::disasm::{self, Line};
+use crate::event_viewer::{EventLog, PpuEvent};
+use crate::events::{Event, EventKind};
+use crate::fds::FdsDisk;
+use crate::hash::Crc32;
+use crate::input::famicom::ExpansionDevice;
+use crate::input::Controller;
+use crate::jam_report::{InstructionHistory, JamReport, HISTORY_ACCESSES, HISTORY_INSTRUCTIONS};
+use crate::memory::{Bus, BusError};
+use crate::memory_editor::{self, AddressSpace, Freezes};
+use crate::power::PowerOnConfig;
+use crate::ppu::{Frame, SpriteOverflow};
+use crate::profiler::Profiler;
+use crate::region::Region;
+use crate::savestate::{self, SaveState, StateError, StateReader, StateWriter};
+use crate::snapshot::{Banks, CpuState, MachineState};
+use crate::symbols::{Location, SymbolError, SymbolTable};
+use crate::watches::{WatchSample, WatchTrigger, Watches};
+use crate::{NesRom, RomError};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+// The whole console: the CPU and everything on its bus, and the one type frontends and other
+// embedders should need. Loading a cartridge or disk builds a
+// fresh console around it, so nothing from the last game (RAM, PPU and APU registers, mapper
+// banks) carries over, and a failed load leaves the running game alone.
+//
+// While paused `run_frame` leaves everything as it is and `advance_frame` steps a frame at a
+// time, for TAS work and debugging.
+//
+// Events such as the start of vblank or of each scanline are queued for whoever subscribed,
+// see `events`. A breakpoint pauses the emulator in the middle of the frame, see
+// `breakpoints`, and `track_calls` keeps a shadow call stack for backtraces, see `call_stack`.
+// Watches sample expressions every frame or on writes to follow game variables, see `watches`.
+//
+// Cheat codes patch CPU reads, see `cheats`. They belong to the game they were entered for,
+// so loading another game clears them. The same goes for addresses frozen through the memory
+// editor functions, see `memory_editor`, and to labels, see `symbols`.
+//
+// Save states are tagged with a CRC-32 of the game, so they only load back into the game
+// they came from.
+//
+// The emulator doesn't keep time itself, `run_frame` runs as fast as it's called. The speed
+// set here is for whoever paces the frames: the frontend waits 1/speed of a frame's time
+// between them and resamples the audio as if the CPU clock was that much faster.
+
+/// Slowest speed `set_speed` allows, a quarter of real time
+pub const MIN_SPEED: f32 = 0.25;
+/// Fastest speed `set_speed` allows, eight times real time
+pub const MAX_SPEED: f32 = 8.0;
+
+pub struct Emulator {
+    cpu: NesCpu,
+    paused: bool,
+    // CRC-32 of the loaded rom or disk, None with nothing loaded
+    game: Option<u32>,
+    // the cartridge keeps its PRG RAM with a battery
+    battery: bool,
+    speed: f32,
+    // frames run since the game was loaded, counting ones run again after loading a state
+    frames_run: u64,
+    // the game didn't read the controllers during the last frame
+    lag_frame: bool,
+    freezes: Freezes,
+    // where the CPU was jammed last time it was looked at, to report each jam once
+    jammed: Option<u16>,
+    jam_report: Option<Box<JamReport>>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Emulator {
+            cpu: NesCpu::new(),
+            paused: false,
+            game: None,
+            battery: false,
+            speed: 1.0,
+            frames_run: 0,
+            lag_frame: false,
+            freezes: Freezes::new(),
+            jammed: None,
+            jam_report: None,
+        }
+    }
+
+    /// Power on with `rom` inserted, replacing whatever was running
+    pub fn load_cartridge(&mut self, rom: &NesRom) -> Result<(), RomError> {
+        let mut cpu = self.fresh_cpu();
+        cpu.load_rom(rom)?;
+        cpu.set_trace_logger(self.cpu.take_trace_logger());
+        cpu.set_watches(self.cpu.take_watches());
+        self.cpu = cpu;
+        self.game = Some(rom.crc32());
+        self.battery = rom.has_battery();
+        self.frames_run = 0;
+        self.freezes.clear();
+        Ok(())
+    }
+
+    /// Power on the Famicom Disk System with `disk` in the drive
+    pub fn load_disk(&mut self, disk: &FdsDisk, bios: &[u8]) -> Result<(), RomError> {
+        let mut cpu = self.fresh_cpu();
+        cpu.load_fds(disk, bios)?;
+        cpu.set_trace_logger(self.cpu.take_trace_logger());
+        cpu.set_watches(self.cpu.take_watches());
+        self.cpu = cpu;
+        let mut crc = Crc32::new();
+        for side in 0..disk.side_count() {
+            crc.update(disk.side(side));
+        }
+        self.game = Some(crc.finish());
+        self.battery = false;
+        self.frames_run = 0;
+        self.freezes.clear();
+        Ok(())
+    }
+
+    // a console to load the next game into, set up like this one
+    fn fresh_cpu(&self) -> NesCpu {
+        let mut cpu = NesCpu::new();
+        cpu.set_power_on(*self.cpu.power_on_config());
+        *cpu.breakpoints_mut() = self.cpu.breakpoints().clone();
+        if self.cpu.call_stack().is_some() {
+            cpu.set_call_stack(Some(CallStack::new()));
+        }
+        if let Some(history) = self.cpu.history() {
+            cpu.set_history(Some(InstructionHistory::new(history.capacity())));
+        }
+        if let Some(trace) = self.cpu.memory.trace() {
+            cpu.memory.enable_trace(trace.capacity());
+        }
+        cpu.set_decode_cache(self.cpu.decode_cache().is_some());
+        let overflow = self.cpu.memory.ppu().sprite_overflow_mode();
+        cpu.memory.ppu_mut().set_sprite_overflow_mode(overflow);
+        let events = self.cpu.memory.ppu().events();
+        cpu.memory.ppu_mut().events_mut().subscribe_like(events);
+        cpu
+    }
+
+    /// Queue `kind` of event from now on, for `drain_events`. Subscriptions last across
+    /// loading games.
+    pub fn subscribe(&mut self, kind: EventKind) {
+        self.cpu.memory.ppu_mut().events_mut().subscribe(kind);
+    }
+
+    pub fn unsubscribe(&mut self, kind: EventKind) {
+        self.cpu.memory.ppu_mut().events_mut().unsubscribe(kind);
+    }
+
+    /// The events subscribed to since the last drain, oldest first
+    pub fn drain_events(&mut self) -> impl Iterator<Item = Event> + '_ {
+        self.cpu.memory.ppu_mut().events_mut().drain()
+    }
+
+    /// What RAM and the registers hold at power on, used from the next load or power cycle
+    pub fn set_power_on(&mut self, config: PowerOnConfig) {
+        self.cpu.set_power_on(config);
+    }
+
+    /// Seed everything random about the console, from the next load or power cycle. The same
+    /// seed and inputs always play out the same.
+    pub fn set_seed(&mut self, seed: u64) {
+        let config = PowerOnConfig {
+            seed,
+            ..*self.cpu.power_on_config()
+        };
+        self.cpu.set_power_on(config);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.cpu.power_on_config().seed
+    }
+
+    pub fn cpu(&self) -> &NesCpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut NesCpu {
+        &mut self.cpu
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.memory.region()
+    }
+
+    /// Run as another region's console than the one the game asked for
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.memory.set_region(region);
+    }
+
+    /// Set what `player` is holding, see the `input::BUTTON_*` bits
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        self.cpu.memory.set_buttons(player, buttons);
+    }
+
+    /// Plug `controller` into controller port `port` (0 or 1)
+    pub fn connect_controller(
+        &mut self,
+        port: usize,
+        controller: Box<dyn Controller>,
+    ) -> Result<(), BusError> {
+        self.cpu.memory.connect_controller(port, controller)
+    }
+
+    /// Plug a Four Score in, so players 3 and 4 can use `set_buttons` too
+    pub fn connect_four_score(&mut self) {
+        self.cpu.memory.connect_four_score();
+    }
+
+    /// Plug `device` into the Famicom expansion port, or unplug whatever is there with `None`
+    pub fn connect_expansion(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+        self.cpu.memory.connect_expansion(device);
+    }
+
+    /// Audio since the last `clear_audio_samples`, one sample per CPU cycle
+    pub fn audio_samples(&self) -> &[f32] {
+        self.cpu.memory.apu().samples()
+    }
+
+    pub fn clear_audio_samples(&mut self) {
+        self.cpu.memory.apu_mut().clear_samples();
+    }
+
+    /// CPU cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.cpu.tick as u64
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Add a Game Genie, Pro Action Replay or address:value code, enabled
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), BadCode> {
+        self.cpu.memory.cheats_mut().add(code)
+    }
+
+    /// Remove `code`, false if it wasn't added
+    pub fn remove_cheat(&mut self, code: &str) -> bool {
+        self.cpu.memory.cheats_mut().remove(code)
+    }
+
+    /// Turn `code` on or off without removing it, false if it wasn't added
+    pub fn set_cheat_enabled(&mut self, code: &str, enabled: bool) -> bool {
+        self.cpu.memory.cheats_mut().set_enabled(code, enabled)
+    }
+
+    /// Flip `code` on or off, returning whether it's on now, None if it wasn't added
+    pub fn toggle_cheat(&mut self, code: &str) -> Option<bool> {
+        self.cpu.memory.cheats_mut().toggle(code)
+    }
+
+    pub fn cheats(&self) -> &CheatList {
+        self.cpu.memory.cheats()
+    }
+
+    /// Replace all the cheats, such as with the ones saved for the game
+    pub fn set_cheats(&mut self, cheats: CheatList) {
+        *self.cpu.memory.cheats_mut() = cheats;
+    }
+
+    /// Run at `speed` times real time, clamped to `MIN_SPEED`..=`MAX_SPEED`. Only the pacing
+    /// changes, the console runs exactly the same frames either way.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = if speed.is_nan() {
+            1.0
+        } else {
+            speed.clamp(MIN_SPEED, MAX_SPEED)
+        };
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Press the reset button: the game restarts through its reset vector with RAM, VRAM and
+    /// the cartridge intact, the sound silenced and the PPU ignoring its setup registers until
+    /// the next frame starts
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Turn the console off and on again with the same cartridge: RAM, the PPU and the APU all
+    /// start over as set by the power-on config
+    pub fn power_cycle(&mut self) {
+        self.cpu.power_cycle();
+    }
+
+    /// Run until the PPU finishes a frame and return it, or return the last frame again while
+    /// paused
+    pub fn run_frame(&mut self) -> &Frame {
+        if self.paused {
+            return self.frame();
+        }
+        self.advance_frame()
+    }
+
+    /// Run exactly one frame, paused or not
+    pub fn advance_frame(&mut self) -> &Frame {
+        loop {
+            self.run_instruction();
+            if self.cpu.stopped_at_breakpoint() {
+                self.paused = true;
+                return self.frame();
+            }
+            if self.cpu.memory.ppu_mut().take_frame_complete() {
+                break;
+            }
+        }
+        self.cpu.sample_frame_watches();
+        self.freezes.apply(&mut self.cpu.memory);
+        self.frames_run += 1;
+        self.lag_frame = !self.cpu.memory.take_input_polled();
+        self.frame()
+    }
+
+    // run an instruction, writing up a jam report if the CPU jams on it
+    fn run_instruction(&mut self) {
+        self.cpu.fetch_decode_next();
+        if self.cpu.jammed() != self.jammed {
+            self.jammed = self.cpu.jammed();
+            if self.jammed.is_some() {
+                self.jam_report = Some(Box::new(JamReport::new(&self.cpu)));
+            }
+        }
+    }
+
+    /// How the PPU sets the sprite overflow flag, the hardware's buggy search by default. It
+    /// stays as set across loading games and power cycles.
+    pub fn set_sprite_overflow_mode(&mut self, mode: SpriteOverflow) {
+        self.cpu.memory.ppu_mut().set_sprite_overflow_mode(mode);
+    }
+
+    /// A copy of the registers, flags, PPU position, mapped banks and timing counters, for
+    /// looking at without reaching into the CPU and bus
+    pub fn snapshot(&self) -> MachineState {
+        let memory = &self.cpu.memory;
+        MachineState {
+            cpu: CpuState::of(&self.cpu.reg),
+            ppu: memory.ppu().snapshot(),
+            banks: Banks::of(memory.cartridge()),
+            region: memory.region(),
+            cycles: self.cycles(),
+            frame_count: self.frame_count(),
+            frames_run: self.frames_run,
+            nmi_pending: memory.ppu().nmi_pending(),
+            irq: memory.irq_sources(),
+        }
+    }
+
+    /// Cache decoded instructions, see `decode_cache`, for long runs without a window such as
+    /// training agents and checking movies. Nothing runs any differently. It does nothing while
+    /// bus accesses are kept (`keep_history`) or profiled, and stays on across loading games.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.cpu.set_decode_cache(enabled);
+    }
+
+    /// Keep the last `HISTORY_INSTRUCTIONS` instructions and `HISTORY_ACCESSES` bus accesses
+    /// for jam reports, or stop. It stays on across loading games, like call tracking.
+    pub fn keep_history(&mut self, enabled: bool) {
+        if enabled {
+            self.cpu
+                .set_history(Some(InstructionHistory::new(HISTORY_INSTRUCTIONS)));
+            self.cpu.memory.enable_trace(HISTORY_ACCESSES);
+        } else {
+            self.cpu.set_history(None);
+            self.cpu.memory.disable_trace();
+        }
+    }
+
+    /// The report on the last time the CPU jammed, if it hasn't been taken. `Event::Jam` says
+    /// when there's a new one.
+    pub fn take_jam_report(&mut self) -> Option<JamReport> {
+        self.jam_report.take().map(|report| *report)
+    }
+
+    /// CRC-32 of the loaded rom or disk sides, what save states are tagged with
+    pub fn game_crc(&self) -> Option<u32> {
+        self.game
+    }
+
+    /// Whether the cartridge's PRG RAM is battery backed and should be kept between sessions
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Frames since power on, as saved in save states, so it's the frame number a movie
+    /// or a TAS is at
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.memory.ppu().frame_count()
+    }
+
+    /// Every frame run since the game was loaded, rewound and replayed ones included
+    pub fn frames_run(&self) -> u64 {
+        self.frames_run
+    }
+
+    /// The game didn't read the controllers during the last frame run, so buttons held for
+    /// it did nothing. Speedrun timing counts these as lag frames.
+    pub fn lag_frame(&self) -> bool {
+        self.lag_frame
+    }
+
+    /// Run one instruction, or take an interrupt, and return the CPU cycles it took. Pausing
+    /// and breakpoints at PC don't stop this, it's for debuggers.
+    pub fn step_instruction(&mut self) -> u64 {
+        let start = self.cycles();
+        self.cpu.skip_breakpoint();
+        self.run_instruction();
+        self.cycles() - start
+    }
+
+    /// Run whole instructions until at least `cycle` CPU cycles have passed since power on, so
+    /// it can stop a few cycles past it, or until a breakpoint stops it
+    pub fn run_until(&mut self, cycle: u64) {
+        while self.cycles() < cycle {
+            self.run_instruction();
+            if self.cpu.stopped_at_breakpoint() {
+                break;
+            }
+        }
+    }
+
+    /// Count instructions, subroutine calls and memory accesses from now until profiling stops
+    /// or another game is loaded, starting over if it was already on. See `profiler`.
+    pub fn start_profiling(&mut self) {
+        self.cpu.memory.set_profiler(Some(Profiler::new()));
+    }
+
+    /// Stop profiling, returning what was counted
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        self.cpu.memory.take_profiler()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.cpu.memory.profiler()
+    }
+
+    /// The profile as text, the `top` hottest instructions and addresses listed after every
+    /// instruction by address
+    pub fn profile_report(&self, top: usize) -> Option<String> {
+        let profiler = self.cpu.memory.profiler()?;
+        Some(profiler.report(&self.cpu.memory, top))
+    }
+
+    /// Record which bytes of PRG ROM run from now until coverage stops or another game is
+    /// loaded, starting over if it was already on. See `coverage`.
+    pub fn start_coverage(&mut self) {
+        self.cpu.memory.set_coverage(true);
+    }
+
+    /// Stop recording coverage, returning what ran
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.cpu.memory.take_coverage()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.cpu.memory.coverage()
+    }
+
+    /// Log PPU register writes, NMIs, sprite 0 hits and IRQs by scanline and dot from now until
+    /// the log stops or another game is loaded, starting over if it was already on
+    pub fn start_event_log(&mut self) {
+        self.cpu.memory.set_event_log(true);
+    }
+
+    pub fn stop_event_log(&mut self) {
+        self.cpu.memory.set_event_log(false);
+    }
+
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.cpu.memory.event_log()
+    }
+
+    /// The events of the last complete frame, from the start of one vblank to the next, for
+    /// `event_viewer::plot`
+    pub fn frame_events(&self) -> &[PpuEvent] {
+        self.event_log().map_or(&[], |log| log.last_frame())
+    }
+
+    /// `count` instructions from `address` as the CPU sees memory now, for debuggers
+    pub fn disassemble(&self, address: u16, count: usize) -> Vec<Line> {
+        disasm::disassemble_memory(&self.cpu.memory, address, count)
+    }
+
+    /// Add the labels from the symbol file at `path` for the loaded game, returning how many
+    /// new ones there were
+    pub fn load_symbols(&mut self, path: &Path) -> Result<usize, SymbolError> {
+        self.cpu.memory.symbols_mut().load_file(path)
+    }
+
+    /// Save every label as a Mesen `.mlb` file
+    pub fn save_symbols(&self, path: &Path) -> io::Result<()> {
+        let memory = &self.cpu.memory;
+        let text = memory
+            .symbols()
+            .to_mlb(|address| memory.prg_offset(address));
+        fs::write(path, text)
+    }
+
+    /// Label `address` as `name`. On PRG ROM the label goes with the bank mapped in now.
+    pub fn add_label(
+        &mut self,
+        address: u16,
+        name: &str,
+        comment: &str,
+    ) -> Result<(), SymbolError> {
+        let location = self.location(address);
+        self.cpu.memory.symbols_mut().add(location, name, comment)
+    }
+
+    /// Returns false if `address` had no label
+    pub fn remove_label(&mut self, address: u16) -> bool {
+        let location = self.location(address);
+        let symbols = self.cpu.memory.symbols_mut();
+        symbols.remove(location) || symbols.remove(Location::Cpu(address))
+    }
+
+    fn location(&self, address: u16) -> Location {
+        match self.cpu.memory.prg_offset(address) {
+            Some(offset) => Location::Prg(offset),
+            None => Location::Cpu(address),
+        }
+    }
+
+    /// The label for `address` with the banks mapped in now
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.cpu.memory.label(address)
+    }
+
+    /// Where the CPU sees the label `name` now, the lowest address if the bank is mirrored,
+    /// None if it's on a bank that isn't mapped in
+    pub fn label_address(&self, name: &str) -> Option<u16> {
+        match self.cpu.memory.symbols().find(name)? {
+            Location::Cpu(address) => Some(address),
+            Location::Prg(offset) => (0x8000..=0xFFFF)
+                .find(|&address| self.cpu.memory.prg_offset(address) == Some(offset)),
+        }
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        self.cpu.memory.symbols()
+    }
+
+    /// Stop before the instruction at `address` runs, when `condition` holds if there is one,
+    /// and return the breakpoint's id. See `breakpoints` for what conditions can say.
+    pub fn add_breakpoint(
+        &mut self,
+        address: u16,
+        condition: Option<&str>,
+    ) -> Result<u32, BadCondition> {
+        let condition = condition.map(Condition::parse).transpose()?;
+        Ok(self.cpu.breakpoints_mut().add(address, condition))
+    }
+
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        self.cpu.breakpoints_mut().remove(id)
+    }
+
+    /// The breakpoints, which last across loading games
+    pub fn breakpoints(&self) -> &Breakpoints {
+        self.cpu.breakpoints()
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        self.cpu.breakpoints_mut()
+    }
+
+    /// Keep a shadow call stack for debuggers, or stop. Like breakpoints it stays on across
+    /// loading games, starting empty with each. See `call_stack`.
+    pub fn track_calls(&mut self, enabled: bool) {
+        if enabled != self.cpu.call_stack().is_some() {
+            self.cpu.set_call_stack(enabled.then(CallStack::new));
+        }
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.cpu.call_stack()
+    }
+
+    /// A backtrace from where the CPU is now, innermost call first, None unless calls are
+    /// tracked
+    pub fn backtrace(&self) -> Option<String> {
+        let call_stack = self.cpu.call_stack()?;
+        Some(call_stack.backtrace(&self.cpu.memory, self.cpu.reg.pc))
+    }
+
+    /// Sample `expression`, written like a breakpoint condition, at the end of every frame or
+    /// whenever the CPU writes a byte it reads, and return the watch's id. Watches last across
+    /// loading games. See `watches`.
+    pub fn add_watch(
+        &mut self,
+        expression: &str,
+        trigger: WatchTrigger,
+    ) -> Result<u32, BadCondition> {
+        self.cpu.add_watch(Condition::parse(expression)?, trigger)
+    }
+
+    pub fn remove_watch(&mut self, id: u32) -> bool {
+        self.cpu.remove_watch(id)
+    }
+
+    pub fn watches(&self) -> &Watches {
+        self.cpu.watches()
+    }
+
+    /// The samples taken since the last drain, oldest first
+    pub fn drain_watch_samples(&mut self) -> impl Iterator<Item = WatchSample> + '_ {
+        self.cpu.watches_mut().drain()
+    }
+
+    /// Stream watch samples to `out` as CSV as they're taken, or stop with None
+    pub fn set_watch_output(&mut self, out: Option<Box<dyn io::Write + Send>>) {
+        self.cpu.watches_mut().set_output(out);
+    }
+
+    /// Stop streaming watch samples, flushing the output, or return the write that failed
+    pub fn finish_watch_output(&mut self) -> io::Result<()> {
+        self.cpu.watches_mut().finish_output()
+    }
+
+    /// The byte at `address` in `space` as it is now, without side effects
+    pub fn peek_memory(&self, space: AddressSpace, address: u16) -> u8 {
+        memory_editor::peek(&self.cpu.memory, space, address)
+    }
+
+    /// `len` bytes from `address` in `space`, for hex views
+    pub fn read_memory(&self, space: AddressSpace, address: u16, len: usize) -> Vec<u8> {
+        memory_editor::read_range(&self.cpu.memory, space, address, len)
+    }
+
+    /// Write `bytes` from `address` on in `space`, CPU writes going over the bus
+    pub fn write_memory(&mut self, space: AddressSpace, address: u16, bytes: &[u8]) {
+        memory_editor::write_range(&mut self.cpu.memory, space, address, bytes);
+    }
+
+    /// Hold `address` at `value`, from now and at the start of every frame until it's let go
+    pub fn freeze(&mut self, space: AddressSpace, address: u16, value: u8) {
+        self.freezes.add(space, address, value);
+        memory_editor::poke(&mut self.cpu.memory, space, address, value);
+    }
+
+    /// Let a frozen address go, returns false if it wasn't frozen
+    pub fn unfreeze(&mut self, space: AddressSpace, address: u16) -> bool {
+        self.freezes.remove(space, address)
+    }
+
+    pub fn freezes(&self) -> &Freezes {
+        &self.freezes
+    }
+
+    /// The 2KB of internal RAM, for RAM search and other tools
+    pub fn ram(&self) -> &[u8] {
+        self.cpu.memory.ram()
+    }
+
+    /// The last finished frame
+    pub fn frame(&self) -> &Frame {
+        self.cpu.memory.ppu().frame()
+    }
+
+    /// Snapshot of the whole console, see `savestate` for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        self.save_state_into(&mut state);
+        state
+    }
+
+    /// `save_state` into `buffer`, replacing what it held. Once it's been big enough for a
+    /// state this doesn't allocate, for taking states every frame.
+    pub fn save_state_into(&self, buffer: &mut Vec<u8>) {
+        let mut state = StateWriter::reuse(mem::take(buffer));
+        savestate::write_header(&mut state, self.game);
+        self.cpu.save_state(&mut state);
+        *buffer = state.finish();
+    }
+
+    /// Put the console back the way it was when `data` was saved. A state that can't be loaded
+    /// leaves the console as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        savestate::read_header(&mut state, self.game)?;
+        let backup = self.save_state();
+        let loaded = self
+            .cpu
+            .load_state(&mut state)
+            .and_then(|()| state.finish());
+        if loaded.is_err() {
+            let mut state = StateReader::new(&backup);
+            savestate::read_header(&mut state, self.game)
+                .and_then(|()| self.cpu.load_state(&mut state))
+                .expect("a state saved a moment ago loads");
+        }
+        loaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::call_stack::FrameKind;
+    use crate::cpu::CpuError;
+    use crate::event_viewer::PpuEventKind;
+    use crate::memory::Bus;
+    use crate::parse_bin_file;
+    use crate::power::RamPattern;
+
+    #[test]
+    fn snapshots_show_the_machine() {
+        assert_eq!(Emulator::new().snapshot().banks, Banks::default());
+
+        let mut emulator = Emulator::new();
+        emulator
+            .load_cartridge(&parse_bin_file("test-bin/nestest.nes").unwrap())
+            .unwrap();
+        let state = emulator.snapshot();
+        assert_eq!(state.cpu.pc, 0xC004);
+        assert_eq!(state.cpu.sp, 0xFD);
+        assert!(state.cpu.flags.interrupt_disable && !state.cpu.flags.carry);
+        // one 16KB bank, mirrored into $C000-$FFFF
+        assert_eq!(state.banks.prg, [Some(0), Some(1), Some(0), Some(1)]);
+        assert_eq!(state.banks.chr, std::array::from_fn(Some));
+
+        emulator.step_instruction();
+        let next = emulator.snapshot();
+        assert!(next.cycles > state.cycles);
+        assert_ne!(next.cpu.pc, state.cpu.pc);
+        assert_eq!(next.banks, state.banks);
+    }
+
+    #[test]
+    fn the_decode_cache_runs_the_same() {
+        for file in ["test-bin/full_nes_palette.nes", "test-bin/nestest.nes"] {
+            let rom = parse_bin_file(file).unwrap();
+            let run = |cached: bool| {
+                let mut emulator = Emulator::new();
+                emulator.set_decode_cache(cached);
+                emulator.load_cartridge(&rom).unwrap();
+                emulator.power_cycle();
+                let frames: Vec<_> = (0..60)
+                    .map(|_| {
+                        emulator.clear_audio_samples();
+                        emulator.run_frame().sha1()
+                    })
+                    .collect();
+                let hits = emulator
+                    .cpu()
+                    .decode_cache()
+                    .map_or(0, |cache| cache.hits());
+                (frames, emulator.save_state(), hits)
+            };
+            let (frames, state, hits) = run(true);
+            assert!(hits > 0);
+            assert_eq!((frames, state, 0), run(false));
+        }
+    }
+
+    #[test]
+    fn load_cartridge_starts_from_power_on() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        let pc = emulator.cpu().reg.pc;
+        emulator.cpu_mut().memory.write_byte(0x0200, 0x55);
+        emulator.cpu_mut().reg.accumulator = 0x42;
+        emulator.cpu_mut().tick = 1000;
+
+        emulator.load_cartridge(&rom).unwrap();
+        assert_eq!(emulator.cpu_mut().memory.read_byte(0x0200), 0);
+        assert_eq!(emulator.cpu().reg.accumulator, 0);
+        // the reset sequence takes 7 cycles
+        assert_eq!(emulator.cpu().tick, 7);
+        assert_eq!(emulator.cpu().reg.pc, pc);
+        assert_eq!(pc, 0xC004);
+    }
+
+    #[test]
+    fn power_on_config_is_applied_on_load_and_power_cycle() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let config = PowerOnConfig {
+            ram: RamPattern::Random,
+            accumulator: 0x12,
+            seed: 1234,
+            ..PowerOnConfig::default()
+        };
+        let ram = |emulator: &Emulator| -> Vec<u8> {
+            (0..0x800)
+                .map(|address| emulator.cpu().memory.peek_byte(address))
+                .collect()
+        };
+        let mut emulator = Emulator::new();
+        emulator.set_power_on(config);
+        emulator.load_cartridge(&rom).unwrap();
+        let first = ram(&emulator);
+        assert!(first.iter().any(|&byte| byte != 0));
+        assert_eq!(emulator.cpu().reg.accumulator, 0x12);
+        // mirrors match
+        assert_eq!(emulator.cpu().memory.peek_byte(0x1801), first[1]);
+
+        emulator.cpu_mut().memory.write_byte(0x0000, !first[0]);
+        emulator.power_cycle();
+        assert_eq!(ram(&emulator), first);
+        let mut other = Emulator::new();
+        other.set_power_on(config);
+        other.load_cartridge(&rom).unwrap();
+        assert_eq!(ram(&other), first);
+    }
+
+    #[test]
+    fn the_seed_decides_the_run() {
+        let rom = parse_bin_file("test-bin/full_nes_palette.nes").unwrap();
+        let run = |seed| {
+            let mut emulator = Emulator::new();
+            emulator.set_power_on(PowerOnConfig {
+                ram: RamPattern::Random,
+                jitter: true,
+                ..PowerOnConfig::default()
+            });
+            emulator.set_seed(seed);
+            emulator.load_cartridge(&rom).unwrap();
+            for _ in 0..10 {
+                emulator.run_frame();
+            }
+            emulator.save_state()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn reset_keeps_ram_and_power_cycle_clears_it() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        let memory = &emulator.cpu().memory;
+        let pc = u16::from_le_bytes([memory.peek_byte(0xFFFC), memory.peek_byte(0xFFFD)]);
+        emulator.run_frame();
+        emulator.cpu_mut().memory.write_byte(0x0200, 0x55);
+
+        emulator.reset();
+        assert_eq!(emulator.cpu().reg.pc, pc);
+        assert_eq!(emulator.cpu().memory.peek_byte(0x0200), 0x55);
+
+        emulator.power_cycle();
+        assert_eq!(emulator.cpu().reg.pc, pc);
+        assert_eq!(emulator.cpu().memory.peek_byte(0x0200), 0);
+    }
+
+    #[test]
+    fn register_writes_are_logged_by_dot() {
+        let program = assemble(
+            "
+            loop:   sta $2005
+                    jmp loop
+            ",
+        )
+        .unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&program);
+        emulator.start_event_log();
+        emulator.run_frame();
+        emulator.run_frame();
+        // the APU's frame counter raises IRQ too, being on at power on
+        let events: Vec<PpuEvent> = emulator
+            .frame_events()
+            .iter()
+            .filter(|event| event.kind != PpuEventKind::Irq)
+            .copied()
+            .collect();
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|event| event.pc == 0x8000
+            && event.kind
+                == PpuEventKind::RegisterWrite {
+                    address: 0x2005,
+                    value: 0
+                }));
+        // the frame starts at vblank, and STA and JMP take 7 cycles, 21 dots
+        assert_eq!(events[0].scanline, 241);
+        let dot = |event: &PpuEvent| event.scanline as u32 * 341 + event.dot as u32;
+        assert_eq!(dot(&events[1]) - dot(&events[0]), 21);
+
+        emulator.stop_event_log();
+        assert!(emulator.frame_events().is_empty());
+    }
+
+    #[test]
+    fn watches_sample_on_writes_and_frames() {
+        let program = assemble(
+            "
+            loop:   inc $0300
+                    lda $0300
+                    cmp #3
+                    bne loop
+            wait:   jmp wait
+            ",
+        )
+        .unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&program);
+        let written = emulator.add_watch("[$0300]", WatchTrigger::Write).unwrap();
+        let framed = emulator
+            .add_watch("[$0300] + 1", WatchTrigger::Frame)
+            .unwrap();
+        assert!(emulator
+            .add_watch("[$0300 + X]", WatchTrigger::Write)
+            .is_err());
+        emulator.run_frame();
+        let samples: Vec<WatchSample> = emulator.drain_watch_samples().collect();
+        let values = |id| {
+            samples
+                .iter()
+                .filter(|sample| sample.id == id)
+                .map(|sample| (sample.pc, sample.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(written), [(0x8000, 1), (0x8000, 2), (0x8000, 3)]);
+        assert_eq!(values(framed).len(), 1);
+        assert_eq!(values(framed)[0].1, 4);
+        assert_eq!(emulator.watches().get(framed).unwrap().last, Some(4));
+
+        assert!(emulator.remove_watch(written));
+        emulator.cpu_mut().memory.write_byte(0x0300, 0);
+        emulator.run_frame();
+        assert_eq!(emulator.drain_watch_samples().count(), 1);
+    }
+
+    #[test]
+    fn breakpoints_pause_mid_frame() {
+        let program = assemble(
+            "
+            loop:   inx
+            count:  iny
+                    jmp loop
+            ",
+        )
+        .unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&program);
+        emulator.subscribe(EventKind::Breakpoint);
+        let id = emulator.add_breakpoint(0x8001, Some("X == 3")).unwrap();
+        assert!(emulator.add_breakpoint(0x8001, Some("X ==")).is_err());
+
+        emulator.run_frame();
+        assert!(emulator.is_paused());
+        assert_eq!(emulator.cpu().reg.pc, 0x8001);
+        assert_eq!(emulator.cpu().reg.idx, 3);
+        assert_eq!(emulator.frames_run(), 0);
+        assert_eq!(
+            emulator.drain_events().collect::<Vec<_>>(),
+            [Event::Breakpoint {
+                id,
+                address: 0x8001
+            }]
+        );
+
+        // running on doesn't stop at the same place again, only once X comes round to 3
+        let cycles = emulator.cycles();
+        emulator.resume();
+        emulator.run_frame();
+        assert!(emulator.is_paused());
+        assert_eq!(emulator.cpu().reg.idx, 3);
+        // INX, INY and JMP take 7 cycles
+        assert_eq!(emulator.cycles() - cycles, 256 * 7);
+        assert_eq!(emulator.breakpoints().get(id).unwrap().hits, 2);
+
+        emulator.remove_breakpoint(id);
+        emulator.resume();
+        emulator.run_frame();
+        assert_eq!(emulator.frames_run(), 1);
+    }
+
+    #[test]
+    fn paused_frames_stand_still() {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&[0x4C, 0x00, 0x80]);
+        emulator.run_frame();
+        let tick = emulator.cpu().tick;
+        emulator.pause();
+        emulator.run_frame();
+        assert_eq!(emulator.cpu().tick, tick);
+        emulator.advance_frame();
+        assert!(emulator.cpu().tick > tick);
+        assert!(emulator.is_paused());
+    }
+
+    #[test]
+    fn beam_events_come_once_a_frame() {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&[0x4C, 0x00, 0x80]);
+        emulator.run_frame();
+        emulator.subscribe(EventKind::FrameComplete);
+        emulator.subscribe(EventKind::VBlankStart);
+        emulator.subscribe(EventKind::ScanlineStart);
+        emulator.run_frame();
+        let events: Vec<_> = emulator.drain_events().collect();
+        let lines = events
+            .iter()
+            .filter(|event| matches!(event, Event::ScanlineStart(_)))
+            .count();
+        assert_eq!(lines, Region::Ntsc.scanlines_per_frame() as usize);
+        // the frame ends as vblank starts, after that scanline's start
+        let vblank = Region::Ntsc.vblank_scanline();
+        assert_eq!(
+            events[events.len() - 3..],
+            [
+                Event::ScanlineStart(vblank),
+                Event::VBlankStart,
+                Event::FrameComplete
+            ]
+        );
+
+        emulator.unsubscribe(EventKind::ScanlineStart);
+        emulator.run_frame();
+        assert_eq!(
+            emulator.drain_events().collect::<Vec<_>>(),
+            [Event::VBlankStart, Event::FrameComplete]
+        );
+    }
+
+    #[test]
+    fn frames_run_keep_counting_through_loads() {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&[0x4C, 0x00, 0x80]);
+        emulator.run_frame();
+        let state = emulator.save_state();
+        let frame = emulator.frame_count();
+        emulator.run_frame();
+        emulator.run_frame();
+        assert_eq!(emulator.frame_count(), frame + 2);
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.frame_count(), frame);
+        assert_eq!(emulator.frames_run(), 3);
+    }
+
+    #[test]
+    fn frames_without_a_controller_read_are_lag() {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&[0x4C, 0x00, 0x80]);
+        emulator.run_frame();
+        assert!(emulator.lag_frame());
+        // LDA $4016, JMP $8000
+        emulator
+            .cpu_mut()
+            .load_bytes(&[0xAD, 0x16, 0x40, 0x4C, 0x00, 0x80]);
+        emulator.run_frame();
+        assert!(!emulator.lag_frame());
+    }
+
+    #[test]
+    fn jams_stop_the_cpu_not_the_process() {
+        let mut emulator = Emulator::new();
+        // LDA #1, JAM
+        emulator.cpu_mut().load_bytes(&[0xA9, 0x01, 0x02]);
+        emulator.run_frame();
+        assert_eq!(emulator.cpu().jammed(), Some(0x8002));
+        assert_eq!(
+            emulator.cpu().error(),
+            Some(&CpuError::Jammed {
+                opcode: 0x02,
+                pc: 0x8002
+            })
+        );
+        let tick = emulator.cpu().tick;
+        emulator.run_frame();
+        assert!(emulator.cpu().tick > tick);
+        emulator.power_cycle();
+        assert_eq!(emulator.cpu().jammed(), None);
+    }
+
+    #[test]
+    fn jams_are_reported_once() {
+        let program = assemble(
+            "
+                    ldx #$03
+            loop:   dex
+                    bne loop
+                    jsr jam
+            jam:    .byte $02
+            ",
+        )
+        .unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&program);
+        emulator.keep_history(true);
+        emulator.subscribe(EventKind::Jam);
+        emulator.run_frame();
+        assert_eq!(
+            emulator.drain_events().collect::<Vec<_>>(),
+            [Event::Jam { address: 0x8008 }]
+        );
+        let report = emulator.take_jam_report().unwrap();
+        assert_eq!((report.pc, report.opcode, report.x), (0x8008, 0x02, 0));
+        // JSR pushed the address of its last byte, below the two bytes above where SP starts
+        assert_eq!(report.stack, [0x07, 0x80, 0, 0]);
+        // the JAM itself is the last one
+        assert_eq!(report.instructions.len(), 9);
+        assert_eq!(report.instructions[7].pc, 0x8005);
+        assert_eq!(report.instructions[8].pc, 0x8008);
+        assert!(report
+            .accesses
+            .iter()
+            .any(|access| access.address == 0x01FC));
+        let text = report.to_string();
+        assert!(text.starts_with("CPU jammed on $02 at $8008"));
+        assert!(text.contains("--> 8008  02"));
+
+        emulator.run_frame();
+        assert!(emulator.take_jam_report().is_none());
+    }
+
+    #[test]
+    fn consoles_run_side_by_side() {
+        // each counts up in a different step, on its own thread
+        let consoles: Vec<_> = (1..=4u8)
+            .map(|step| {
+                std::thread::spawn(move || {
+                    let mut emulator = Emulator::new();
+                    // CLC, LDA $10, ADC #step, STA $10, JMP $8000
+                    emulator
+                        .cpu_mut()
+                        .load_bytes(&[0x18, 0xA5, 0x10, 0x69, step, 0x85, 0x10, 0x4C, 0x00, 0x80]);
+                    emulator.run_until(500);
+                    (step, emulator.cpu().memory.peek_byte(0x10))
+                })
+            })
+            .collect();
+        for console in consoles {
+            let (step, total) = console.join().unwrap();
+            assert_eq!(total % step, 0);
+            assert_ne!(total, 0);
+        }
+    }
+
+    #[test]
+    fn load_state_goes_back_to_the_save() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().set_logging(false);
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.run_until(5000);
+        let state = emulator.save_state();
+        let cycles = emulator.cycles();
+        let pc = emulator.cpu().reg.pc;
+        let ram = emulator.cpu().memory.peek_byte(0x0300);
+
+        emulator.run_until(10000);
+        emulator.cpu_mut().memory.write_byte(0x0300, !ram);
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.cycles(), cycles);
+        assert_eq!(emulator.cpu().reg.pc, pc);
+        assert_eq!(emulator.cpu().memory.peek_byte(0x0300), ram);
+        // and the same state comes out again
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn bad_states_leave_the_console_alone() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().set_logging(false);
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.run_until(5000);
+        let state = emulator.save_state();
+        emulator.run_until(10000);
+        let before = emulator.save_state();
+
+        assert_eq!(
+            emulator.load_state(&state[..state.len() - 10]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(emulator.save_state(), before);
+        assert_eq!(
+            Emulator::new().load_state(&state),
+            Err(StateError::WrongGame)
+        );
+    }
+
+    #[test]
+    fn step_instruction_counts_cycles() {
+        let mut emulator = Emulator::new();
+        // LDA #$01, LDA $0200, JMP $8000
+        emulator
+            .cpu_mut()
+            .load_bytes(&[0xA9, 0x01, 0xAD, 0x00, 0x02, 0x4C, 0x00, 0x80]);
+        assert_eq!(emulator.step_instruction(), 2);
+        assert_eq!(emulator.step_instruction(), 4);
+        assert_eq!(emulator.step_instruction(), 3);
+        assert_eq!(emulator.cpu().reg.pc, 0x8000);
+    }
+
+    #[test]
+    fn run_until_stops_on_an_instruction_boundary() {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&[0x4C, 0x00, 0x80]);
+        let start = emulator.cycles();
+        emulator.run_until(start + 100);
+        // 3 cycle JMPs, so 102 is the first boundary past 100
+        assert_eq!(emulator.cycles(), start + 102);
+        emulator.run_until(start + 50);
+        assert_eq!(emulator.cycles(), start + 102);
+    }
+
+    #[test]
+    fn cheats_patch_reads_until_removed() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        let real = emulator.cpu().memory.peek_byte(0xC000);
+        let code = format!("C000?{:02X}:EA", real);
+        emulator.add_cheat(&code).unwrap();
+        assert_eq!(emulator.cpu_mut().memory.read_byte(0xC000), 0xEA);
+        assert_eq!(emulator.toggle_cheat(&code), Some(false));
+        assert_eq!(emulator.cpu().memory.peek_byte(0xC000), real);
+        assert!(emulator.set_cheat_enabled(&code, true));
+        assert_eq!(emulator.cpu().memory.peek_byte(0xC000), 0xEA);
+        assert!(emulator.remove_cheat(&code));
+        assert_eq!(emulator.cpu().memory.peek_byte(0xC000), real);
+
+        emulator.add_cheat("0300:05").unwrap();
+        emulator.load_cartridge(&rom).unwrap();
+        assert!(emulator.cheats().is_empty());
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut emulator = Emulator::default();
+        assert_eq!(emulator.speed(), 1.0);
+        emulator.set_speed(1.5);
+        assert_eq!(emulator.speed(), 1.5);
+        emulator.set_speed(0.1);
+        assert_eq!(emulator.speed(), MIN_SPEED);
+        emulator.set_speed(100.0);
+        assert_eq!(emulator.speed(), MAX_SPEED);
+        emulator.set_speed(f32::NAN);
+        assert_eq!(emulator.speed(), 1.0);
+    }
+
+    #[test]
+    fn failed_load_keeps_the_running_game() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.cpu_mut().memory.write_byte(0x0200, 0x55);
+
+        let mut unsupported = parse_bin_file("test-bin/nestest.nes").unwrap();
+        unsupported.header.mapper = 4095;
+        assert!(emulator.load_cartridge(&unsupported).is_err());
+        assert_eq!(emulator.cpu_mut().memory.read_byte(0x0200), 0x55);
+    }
+
+    #[test]
+    fn frozen_addresses_are_rewritten_every_frame() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.freeze(AddressSpace::Cpu, 0x0300, 0x42);
+        emulator.freeze(AddressSpace::Ppu, 0x3F00, 0x21);
+        assert_eq!(emulator.peek_memory(AddressSpace::Cpu, 0x0300), 0x42);
+
+        emulator.write_memory(AddressSpace::Cpu, 0x0300, &[0x10, 0x11]);
+        assert_eq!(
+            emulator.read_memory(AddressSpace::Cpu, 0x0300, 2),
+            [0x10, 0x11]
+        );
+        emulator.run_frame();
+        assert_eq!(emulator.peek_memory(AddressSpace::Cpu, 0x0300), 0x42);
+        assert_eq!(emulator.peek_memory(AddressSpace::Ppu, 0x3F00), 0x21);
+
+        assert!(emulator.unfreeze(AddressSpace::Cpu, 0x0300));
+        emulator.write_memory(AddressSpace::Cpu, 0x0300, &[0x10]);
+        emulator.run_frame();
+        assert_eq!(emulator.peek_memory(AddressSpace::Cpu, 0x0300), 0x10);
+
+        emulator.load_cartridge(&rom).unwrap();
+        assert!(emulator.freezes().is_empty());
+    }
+
+    #[test]
+    fn coverage_is_by_prg_rom_offset() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.start_coverage();
+        emulator.run_frame();
+        let coverage = emulator.coverage().unwrap();
+        assert_eq!(coverage.prg_size(), 0x4000);
+        // the one 16KB bank is mirrored at $8000 and $C000, and the CPU starts on the reset
+        // routine at $C004, never running the JMP at $C000 that skips it for automation
+        assert!(coverage.is_instruction(0x0004));
+        assert!(!coverage.is_covered(0x0000));
+
+        emulator.load_cartridge(&rom).unwrap();
+        assert!(emulator.coverage().is_none());
+    }
+
+    #[test]
+    fn call_tracking_lasts_across_loads() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.track_calls(true);
+        emulator.load_cartridge(&rom).unwrap();
+        assert_eq!(emulator.call_stack().unwrap().depth(), 0);
+        // nestest calls each group of tests from $C5F5 on
+        while emulator.call_stack().unwrap().depth() == 0 {
+            emulator.step_instruction();
+        }
+        let frame = emulator.call_stack().unwrap().frames()[0];
+        assert_eq!(frame.kind, FrameKind::Subroutine);
+        assert_eq!(frame.target, emulator.cpu().reg.pc);
+        let backtrace = emulator.backtrace().unwrap();
+        assert_eq!(backtrace.lines().count(), 2);
+        assert!(backtrace.ends_with(&format!("#1  ${:04X}\n", frame.call_site)));
+        emulator.track_calls(false);
+        assert!(emulator.backtrace().is_none());
+    }
+
+    #[test]
+    fn labels_are_saved_and_loaded() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.load_cartridge(&rom).unwrap();
+        emulator.add_label(0xC004, "reset", "").unwrap();
+        emulator.add_label(0x0010, "temp", "scratch").unwrap();
+        // NROM-128 mirrors its bank, so the label is at both addresses
+        assert_eq!(emulator.label(0x8004), Some("reset"));
+        assert_eq!(emulator.label_address("reset"), Some(0x8004));
+        emulator.add_label(0xC5F5, "main", "").unwrap();
+        assert_eq!(emulator.disassemble(0xC000, 1)[0].text, "JMP main");
+
+        let path = std::env::temp_dir().join(format!("nesemu-labels-{}.mlb", std::process::id()));
+        emulator.save_symbols(&path).unwrap();
+        emulator.load_cartridge(&rom).unwrap();
+        assert!(emulator.symbols().is_empty());
+        assert_eq!(emulator.load_symbols(&path).unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(emulator.label(0xC004), Some("reset"));
+        assert!(emulator.remove_label(0xC004));
+        assert_eq!(emulator.label(0xC004), None);
+        assert_eq!(emulator.label_address("temp"), Some(0x0010));
+    }
+}
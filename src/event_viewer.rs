@@ -0,0 +1,288 @@
+use crate::ppu::Ppu;
+use crate::region::Region;
+use std::fmt;
+
+// The event viewer's log: PPU register writes, NMIs, sprite 0 hits and IRQs, each with the
+// scanline and dot it happened on, kept a frame at a time so raster effects (split scrolling,
+// mid-frame palette or bank changes, status bar IRQs) can be seen for where they land on the
+// picture. A frame's events run from the start of one vblank to the start of the next, so the
+// writes the NMI handler makes to set up a picture come before the ones made while drawing it.
+//
+// The PPU catches up after each instruction rather than during it, so a write is placed on the
+// dot of the instruction's last cycle, which is when stores and read-modify-write instructions
+// write. IRQs are placed on the CPU cycle the line went up on. NMIs and sprite 0 hits are on the
+// dot the PPU raised them, a sprite 0 hit on the dot its pixel is drawn.
+//
+// `plot` draws a frame's events as coloured marks on a dot by scanline grid, like Mesen's
+// event viewer.
+
+/// Width of `plot`'s picture, a dot per pixel
+pub const PLOT_WIDTH: usize = 341;
+
+/// What happened
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PpuEventKind {
+    /// The CPU wrote `value` to `address`, one of $2000-$2007 or OAMDMA at $4014
+    RegisterWrite {
+        address: u16,
+        value: u8,
+    },
+    /// The PPU raised NMI, at the start of vblank or for a PPUCTRL write enabling it in vblank
+    Nmi,
+    SpriteZeroHit,
+    /// The CPU's IRQ line went up, from the cartridge or the APU
+    Irq,
+}
+
+impl PpuEventKind {
+    /// The colour it's plotted in
+    pub fn colour(&self) -> [u8; 3] {
+        match self {
+            PpuEventKind::RegisterWrite { address, .. } => match address {
+                0x4014 => [0xFF, 0x80, 0x40],
+                _ => REGISTER_COLOURS[(address & 7) as usize],
+            },
+            PpuEventKind::Nmi => [0xFF, 0x40, 0x40],
+            PpuEventKind::SpriteZeroHit => [0xFF, 0xFF, 0x40],
+            PpuEventKind::Irq => [0x40, 0xFF, 0x40],
+        }
+    }
+}
+
+// PPUCTRL to PPUDATA
+const REGISTER_COLOURS: [[u8; 3]; 8] = [
+    [0xFF, 0x60, 0xC0],
+    [0xB0, 0x80, 0xFF],
+    [0x80, 0x80, 0x80],
+    [0x40, 0xC0, 0xC0],
+    [0x40, 0xA0, 0xFF],
+    [0x40, 0xFF, 0xFF],
+    [0xFF, 0xC0, 0x80],
+    [0xFF, 0xFF, 0xFF],
+];
+
+const REGISTER_NAMES: [&str; 8] = [
+    "PPUCTRL",
+    "PPUMASK",
+    "PPUSTATUS",
+    "OAMADDR",
+    "OAMDATA",
+    "PPUSCROLL",
+    "PPUADDR",
+    "PPUDATA",
+];
+
+/// An event and when it happened
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PpuEvent {
+    pub scanline: u16,
+    pub dot: u16,
+    /// The instruction running, or about to run for NMIs and IRQs
+    pub pc: u16,
+    pub kind: PpuEventKind,
+}
+
+impl fmt::Display for PpuEvent {
+    //   241,  1  $C0A2  NMI
+    //    31,287  $C1F0  PPUSCROLL ($2005) = $40
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>3},{:>3}  ${:04X}  ",
+            self.scanline, self.dot, self.pc
+        )?;
+        match self.kind {
+            PpuEventKind::RegisterWrite {
+                address: 0x4014,
+                value,
+            } => write!(f, "OAMDMA ($4014) = ${:02X}", value),
+            PpuEventKind::RegisterWrite { address, value } => write!(
+                f,
+                "{} (${:04X}) = ${:02X}",
+                REGISTER_NAMES[(address & 7) as usize],
+                address,
+                value
+            ),
+            PpuEventKind::Nmi => write!(f, "NMI"),
+            PpuEventKind::SpriteZeroHit => write!(f, "sprite 0 hit"),
+            PpuEventKind::Irq => write!(f, "IRQ"),
+        }
+    }
+}
+
+/// `dots` after `scanline` and `dot`, in a frame `scanlines` long
+fn advance(scanline: u16, dot: u16, dots: u32, scanlines: u16) -> (u16, u16) {
+    let dots_per_scanline = crate::ppu::DOTS_PER_SCANLINE as u32;
+    let at = scanline as u32 * dots_per_scanline + dot as u32 + dots;
+    let frame = scanlines as u32 * dots_per_scanline;
+    let at = at % frame;
+    (
+        (at / dots_per_scanline) as u16,
+        (at % dots_per_scanline) as u16,
+    )
+}
+
+/// The events of the frame being drawn and the one before it
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    current: Vec<PpuEvent>,
+    last_frame: Vec<PpuEvent>,
+    // the instruction running: where it is, where the PPU was as it started, and the dots to
+    // its last cycle
+    pc: u16,
+    start: (u16, u16),
+    write_dots: u32,
+    // CPU cycles run since it started
+    cycle: u32,
+    // what the PPU's lines were last looked at, to catch them going up
+    nmi: bool,
+    irq: bool,
+    sprite_zero: Option<(u16, u16)>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last complete frame's events, in the order they happened
+    pub fn last_frame(&self) -> &[PpuEvent] {
+        &self.last_frame
+    }
+
+    /// The events of the frame so far
+    pub fn current(&self) -> &[PpuEvent] {
+        &self.current
+    }
+
+    fn push(&mut self, scanline: u16, dot: u16, kind: PpuEventKind) {
+        self.current.push(PpuEvent {
+            scanline,
+            dot,
+            pc: self.pc,
+            kind,
+        });
+    }
+
+    /// The instruction at `pc` taking `cycles` is about to run, the PPU where it is now
+    pub(crate) fn instruction(&mut self, pc: u16, cycles: u32, ppu: &Ppu) {
+        let (dots, per_cycles) = ppu.region().dots_per_cpu_cycle();
+        self.pc = pc;
+        self.start = (ppu.scanline(), ppu.dot());
+        self.write_dots = cycles.saturating_sub(1) * dots / per_cycles;
+        self.cycle = 0;
+    }
+
+    /// The running instruction wrote `value` to PPU register `address`
+    pub(crate) fn register_write(&mut self, address: u16, value: u8, ppu: &Ppu) {
+        let (scanline, dot) = advance(
+            self.start.0,
+            self.start.1,
+            self.write_dots,
+            ppu.region().scanlines_per_frame(),
+        );
+        self.push(
+            scanline,
+            dot,
+            PpuEventKind::RegisterWrite { address, value },
+        );
+        // a PPUCTRL write can raise NMI
+        if ppu.nmi_pending() && !self.nmi {
+            self.push(scanline, dot, PpuEventKind::Nmi);
+        }
+        self.nmi = ppu.nmi_pending();
+    }
+
+    /// The PPU has just run a dot
+    pub(crate) fn dot(&mut self, ppu: &Ppu) {
+        let (scanline, dot) = (ppu.scanline(), ppu.dot());
+        let region = ppu.region();
+        // the flag goes up on dot 1, which has been run once the PPU is on dot 2
+        if scanline == region.vblank_scanline() && dot == 2 {
+            self.last_frame = std::mem::take(&mut self.current);
+        }
+        if ppu.nmi_pending() && !self.nmi {
+            // NMI goes up on dot 1 of vblank, so the dot that was just run is on this line
+            self.push(scanline, dot.saturating_sub(1), PpuEventKind::Nmi);
+        }
+        self.nmi = ppu.nmi_pending();
+        let sprite_zero = ppu.sprite_zero_hit_at();
+        if let Some((scanline, dot)) = sprite_zero.filter(|_| sprite_zero != self.sprite_zero) {
+            self.push(scanline, dot, PpuEventKind::SpriteZeroHit);
+        }
+        self.sprite_zero = sprite_zero;
+    }
+
+    /// The IRQ line is at `irq` after the next CPU cycle of the running instruction
+    pub(crate) fn irq_line(&mut self, irq: bool, ppu: &Ppu) {
+        self.cycle += 1;
+        if irq && !self.irq {
+            let region = ppu.region();
+            let (dots, per_cycles) = region.dots_per_cpu_cycle();
+            let (scanline, dot) = advance(
+                self.start.0,
+                self.start.1,
+                self.cycle * dots / per_cycles,
+                region.scanlines_per_frame(),
+            );
+            self.push(scanline, dot, PpuEventKind::Irq);
+        }
+        self.irq = irq;
+    }
+}
+
+/// `events` as an RGB picture `PLOT_WIDTH` dots wide and a scanline per row for `region`, the
+/// visible picture lighter than hblank and vblank, each event a 3x3 mark
+pub fn plot(events: &[PpuEvent], region: Region) -> Vec<u8> {
+    let height = region.scanlines_per_frame() as usize;
+    let mut rgb = vec![0x20; PLOT_WIDTH * height * 3];
+    for scanline in 0..crate::ppu::HEIGHT {
+        for dot in 1..=crate::ppu::WIDTH {
+            let offset = (scanline * PLOT_WIDTH + dot) * 3;
+            rgb[offset..offset + 3].fill(0x38);
+        }
+    }
+    for event in events {
+        let colour = event.kind.colour();
+        for y in event.scanline.saturating_sub(1)..=event.scanline + 1 {
+            for x in event.dot.saturating_sub(1)..=event.dot + 1 {
+                let (x, y) = (x as usize, y as usize);
+                if x < PLOT_WIDTH && y < height {
+                    let offset = (y * PLOT_WIDTH + x) * 3;
+                    rgb[offset..offset + 3].copy_from_slice(&colour);
+                }
+            }
+        }
+    }
+    rgb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_wrap_into_the_next_scanline_and_frame() {
+        assert_eq!(advance(10, 300, 40, 262), (10, 340));
+        assert_eq!(advance(10, 300, 41, 262), (11, 0));
+        assert_eq!(advance(261, 340, 1, 262), (0, 0));
+    }
+
+    #[test]
+    fn event_text() {
+        let event = PpuEvent {
+            scanline: 31,
+            dot: 287,
+            pc: 0xC1F0,
+            kind: PpuEventKind::RegisterWrite {
+                address: 0x2005,
+                value: 0x40,
+            },
+        };
+        assert_eq!(event.to_string(), " 31,287  $C1F0  PPUSCROLL ($2005) = $40");
+        let plotted = plot(&[event], Region::Ntsc);
+        assert_eq!(plotted.len(), PLOT_WIDTH * 262 * 3);
+        let offset = (31 * PLOT_WIDTH + 287) * 3;
+        assert_eq!(plotted[offset..offset + 3], REGISTER_COLOURS[5]);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+
+// Events are queued as the console runs and drained by whoever subscribed to them, usually
+// once a frame, so frontends and scripts can act on the beam position without polling the PPU
+// after every instruction. Only subscribed kinds are queued, and the queue drops its oldest
+// events past `MAX_QUEUED`, so a subscriber that stops draining can't grow it forever.
+//
+// Events come in the order they happened, timed by the PPU dot they happened on: the
+// instruction that was running is finished by the time anyone sees them.
+
+/// Events queued before the oldest start being dropped, a frame and a half of scanlines
+pub const MAX_QUEUED: usize = 400;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The picture is finished, at the same dot as `VBlankStart`
+    FrameComplete,
+    /// The vblank flag goes up, or would have if a $2002 read hadn't just suppressed it
+    VBlankStart,
+    /// Dot 0 of a scanline, 0-239 visible, then post-render, vblank and pre-render
+    ScanlineStart(u16),
+    /// The cartridge pulled the IRQ line low
+    MapperIrq,
+    /// A breakpoint stopped the CPU before the instruction at `address`
+    Breakpoint { id: u32, address: u16 },
+    /// The CPU jammed on the instruction at `address`, see `jam_report`
+    Jam { address: u16 },
+}
+
+/// What can be subscribed to, `Event` without the details
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    FrameComplete,
+    VBlankStart,
+    ScanlineStart,
+    MapperIrq,
+    Breakpoint,
+    Jam,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::FrameComplete => EventKind::FrameComplete,
+            Event::VBlankStart => EventKind::VBlankStart,
+            Event::ScanlineStart(_) => EventKind::ScanlineStart,
+            Event::MapperIrq => EventKind::MapperIrq,
+            Event::Breakpoint { .. } => EventKind::Breakpoint,
+            Event::Jam { .. } => EventKind::Jam,
+        }
+    }
+}
+
+impl EventKind {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Queue of the events subscribed to
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    subscribed: u8,
+    queue: VecDeque<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, kind: EventKind) {
+        self.subscribed |= kind.bit();
+        self.make_room();
+    }
+
+    /// Stop queueing `kind`, dropping any already queued
+    pub fn unsubscribe(&mut self, kind: EventKind) {
+        self.subscribed &= !kind.bit();
+        self.queue.retain(|event| event.kind() != kind);
+    }
+
+    pub fn is_subscribed(&self, kind: EventKind) -> bool {
+        self.subscribed & kind.bit() != 0
+    }
+
+    /// Copy the subscriptions from `other`, without its queue
+    pub fn subscribe_like(&mut self, other: &Events) {
+        self.subscribed = other.subscribed;
+        if self.subscribed != 0 {
+            self.make_room();
+        }
+    }
+
+    // the whole queue up front, so queueing while running never allocates
+    fn make_room(&mut self) {
+        self.queue
+            .reserve_exact(MAX_QUEUED.saturating_sub(self.queue.len()));
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        if !self.is_subscribed(event.kind()) {
+            return;
+        }
+        if self.queue.len() == MAX_QUEUED {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(event);
+    }
+
+    /// Take the queued events, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = Event> + '_ {
+        self.queue.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_subscribed_events_are_queued() {
+        let mut events = Events::new();
+        events.push(Event::FrameComplete);
+        assert!(events.is_empty());
+
+        events.subscribe(EventKind::FrameComplete);
+        events.subscribe(EventKind::ScanlineStart);
+        events.push(Event::ScanlineStart(3));
+        events.push(Event::MapperIrq);
+        events.push(Event::FrameComplete);
+        assert_eq!(
+            events.drain().collect::<Vec<_>>(),
+            [Event::ScanlineStart(3), Event::FrameComplete]
+        );
+
+        events.push(Event::ScanlineStart(4));
+        events.unsubscribe(EventKind::ScanlineStart);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn oldest_are_dropped_when_full() {
+        let mut events = Events::new();
+        events.subscribe(EventKind::ScanlineStart);
+        for line in 0..MAX_QUEUED as u16 + 10 {
+            events.push(Event::ScanlineStart(line));
+        }
+        assert_eq!(events.len(), MAX_QUEUED);
+        assert_eq!(events.drain().next(), Some(Event::ScanlineStart(10)));
+    }
+}
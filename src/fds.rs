@@ -0,0 +1,713 @@
+#[cfg(feature = "serde")]
+use crate::mapper::MapperStateRef;
+use crate::mapper::{Mapper, Mirroring, CHR_BANK_SIZE};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::RomError;
+
+// https://www.nesdev.org/wiki/Family_Computer_Disk_System
+// https://www.nesdev.org/wiki/FDS_disk_format
+//
+// The RAM adapter has 32KB of PRG RAM at $6000-$DFFF, the 8KB BIOS at $E000-$FFFF and 8KB of
+// CHR RAM. Disks are read and written a byte at a time through $4024/$4031 while the drive
+// streams the side past the head.
+//
+// .fds images store each 65500 byte side without the gaps and CRCs on a real disk, so sides
+// are expanded into that layout when loaded. CRC errors are never reported, the CRC bytes
+// are left zero.
+
+pub const BIOS_SIZE: usize = 8192;
+pub const DISK_SIDE_SIZE: usize = 65500;
+const FDS_HEADER: [u8; 4] = *b"FDS\x1A";
+const FDS_HEADER_SIZE: usize = 16;
+// every side starts with a disk info block carrying this
+const DISK_VERIFICATION: &[u8] = b"\x01*NINTENDO-HVC*";
+const RAM_SIZE: usize = 32 * 1024;
+
+const BLOCK_DISK_INFO: u8 = 1;
+const BLOCK_FILE_AMOUNT: u8 = 2;
+const BLOCK_FILE_HEADER: u8 = 3;
+const BLOCK_FILE_DATA: u8 = 4;
+const DISK_INFO_SIZE: usize = 56;
+const FILE_AMOUNT_SIZE: usize = 2;
+const FILE_HEADER_SIZE: usize = 16;
+
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// the first set bit after a gap marks the start of a block
+const GAP_END: u8 = 0x80;
+const CRC_SIZE: usize = 2;
+
+// CPU cycles per byte at the drive's 96.4kHz bit rate
+const BYTE_CYCLES: u32 = 149;
+// time for the head to get back to the start of the side
+const HEAD_RETURN_CYCLES: u32 = 50000;
+// a swapped disk has to read as ejected long enough for the BIOS to notice
+const SWAP_DELAY_CYCLES: u32 = 1_000_000;
+
+const CONTROL_MOTOR: u8 = 0b0000_0001;
+const CONTROL_RESET_TRANSFER: u8 = 0b0000_0010;
+const CONTROL_READ: u8 = 0b0000_0100;
+const CONTROL_HORIZONTAL: u8 = 0b0000_1000;
+const CONTROL_CRC: u8 = 0b0001_0000;
+const CONTROL_READY: u8 = 0b0100_0000;
+const CONTROL_IRQ: u8 = 0b1000_0000;
+
+const STATUS_TIMER_IRQ: u8 = 0b0000_0001;
+const STATUS_TRANSFER: u8 = 0b0000_0010;
+const STATUS_END_OF_HEAD: u8 = 0b0100_0000;
+const DRIVE_NO_DISK: u8 = 0b0000_0001;
+const DRIVE_NOT_READY: u8 = 0b0000_0010;
+const DRIVE_WRITE_PROTECTED: u8 = 0b0000_0100;
+const EXTERNAL_BATTERY_GOOD: u8 = 0b1000_0000;
+
+/// The sides of a disk image, in .fds layout
+#[derive(Debug, Clone, PartialEq)]
+pub struct FdsDisk {
+    sides: Vec<Vec<u8>>,
+}
+
+impl FdsDisk {
+    /// Parse an .fds image, with or without the 16 byte fwNES header
+    pub fn from_bytes(bytes: &[u8]) -> Result<FdsDisk, RomError> {
+        let body = if bytes.starts_with(&FDS_HEADER) {
+            bytes.get(FDS_HEADER_SIZE..).unwrap_or(&[])
+        } else {
+            bytes
+        };
+        if body.is_empty() || !body.len().is_multiple_of(DISK_SIDE_SIZE) {
+            return Err(RomError::BadDiskImage);
+        }
+        let sides: Vec<Vec<u8>> = body.chunks(DISK_SIDE_SIZE).map(<[u8]>::to_vec).collect();
+        if !sides.iter().all(|side| side.starts_with(DISK_VERIFICATION)) {
+            return Err(RomError::BadDiskImage);
+        }
+        Ok(FdsDisk { sides })
+    }
+
+    /// Whether `bytes` looks like a disk image
+    pub fn is_disk_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(&FDS_HEADER) || bytes.starts_with(DISK_VERIFICATION)
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn side(&self, side: usize) -> &[u8] {
+        &self.sides[side]
+    }
+}
+
+// Lay a side out the way it is on the disk, with gaps before blocks and a CRC after them
+fn expand_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0u8; LEADING_GAP];
+    let mut add_block = |block: &[u8]| {
+        raw.push(GAP_END);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&[0; CRC_SIZE]);
+        raw.extend_from_slice(&[0; BLOCK_GAP]);
+    };
+    let mut position = 0;
+    for (id, size) in [
+        (BLOCK_DISK_INFO, DISK_INFO_SIZE),
+        (BLOCK_FILE_AMOUNT, FILE_AMOUNT_SIZE),
+    ] {
+        match side.get(position..position + size) {
+            Some(block) if block[0] == id => add_block(block),
+            _ => return raw,
+        }
+        position += size;
+    }
+    // files past the count in block 2 are still on the disk, some games load them anyway
+    while let Some(header) = side.get(position..position + FILE_HEADER_SIZE) {
+        if header[0] != BLOCK_FILE_HEADER {
+            break;
+        }
+        add_block(header);
+        position += FILE_HEADER_SIZE;
+        let size = 1 + u16::from_le_bytes([header[13], header[14]]) as usize;
+        match side.get(position..position + size) {
+            Some(data) if data[0] == BLOCK_FILE_DATA => add_block(data),
+            _ => break,
+        }
+        position += size;
+    }
+    raw
+}
+
+/// Something with a disk drive, for swapping disks and sides
+pub trait DiskDrive {
+    fn side_count(&self) -> usize;
+    /// The side in the drive, None when it's empty
+    fn inserted_side(&self) -> Option<usize>;
+    /// Put `side` in the drive, or eject with None. Swapping one side for another leaves the
+    /// drive empty for a moment so the game sees the disk change.
+    fn insert_side(&mut self, side: Option<usize>);
+}
+
+/// Famicom Disk System RAM adapter and drive
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    chr: Vec<u8>,
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    // side to insert once the swap delay runs out
+    pending_side: Option<(usize, u32)>,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    disk_registers_enabled: bool,
+    external_output: u8,
+
+    control: u8,
+    read_data: u8,
+    write_data: u8,
+    disk_irq: bool,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+}
+
+impl Fds {
+    /// `bios` is the 8KB disksys.rom. The first side starts in the drive.
+    pub fn new(bios: &[u8], disk: &FdsDisk) -> Result<Fds, RomError> {
+        if bios.len() != BIOS_SIZE {
+            return Err(RomError::BadBios);
+        }
+        Ok(Fds {
+            bios: bios.to_vec(),
+            ram: vec![0; RAM_SIZE],
+            chr: vec![0; CHR_BANK_SIZE],
+            sides: disk.sides.iter().map(|side| expand_side(side)).collect(),
+            side: Some(0),
+            pending_side: None,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_registers_enabled: false,
+            external_output: 0,
+            control: 0,
+            read_data: 0,
+            write_data: 0,
+            disk_irq: false,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+        })
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        if !self.disk_registers_enabled {
+            return 0;
+        }
+        match address {
+            0x4030 => [
+                (self.timer_irq, STATUS_TIMER_IRQ),
+                (self.transfer_complete, STATUS_TRANSFER),
+                (self.end_of_head, STATUS_END_OF_HEAD),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .fold(0, |status, (_, bit)| status | bit),
+            0x4031 => self.read_data,
+            0x4032 => {
+                let empty = self.side.is_none();
+                let mut status = 0;
+                if empty {
+                    status |= DRIVE_NO_DISK | DRIVE_WRITE_PROTECTED;
+                }
+                if empty || !self.scanning {
+                    status |= DRIVE_NOT_READY;
+                }
+                status
+            }
+            // the connector is open collector, unconnected lines read back what was written
+            0x4033 => EXTERNAL_BATTERY_GOOD | self.external_output & 0x7F,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, byte: u8) {
+        match address {
+            0x4023 => {
+                self.disk_registers_enabled = byte & 1 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            _ if !self.disk_registers_enabled => {}
+            0x4020 => self.timer_reload = self.timer_reload & 0xFF00 | byte as u16,
+            0x4021 => self.timer_reload = self.timer_reload & 0x00FF | (byte as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = byte & 1 != 0;
+                self.timer_enabled = byte & 2 != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = byte;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.control = byte;
+                self.disk_irq = false;
+            }
+            0x4026 => self.external_output = byte,
+            _ => {}
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            if self.timer_repeat {
+                self.timer_counter = self.timer_reload;
+            } else {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if let Some((side, delay)) = self.pending_side {
+            if delay == 0 {
+                self.side = Some(side);
+                self.pending_side = None;
+            } else {
+                self.pending_side = Some((side, delay - 1));
+            }
+        }
+        let Some(side) = self.side else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.control & CONTROL_MOTOR == 0 {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.control & CONTROL_RESET_TRANSFER != 0 && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let ready = self.control & CONTROL_READY != 0;
+        let irq = self.control & CONTROL_IRQ != 0;
+        let raw = &mut self.sides[side];
+        if self.control & CONTROL_READ != 0 {
+            let byte = raw.get(self.position).copied().unwrap_or(0);
+            if !ready {
+                self.gap_ended = false;
+            } else if byte != 0 && !self.gap_ended {
+                // the gap end marker itself isn't handed to the CPU
+                self.gap_ended = true;
+            } else if self.gap_ended {
+                self.read_data = byte;
+                self.transfer_complete = true;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let byte = if self.control & CONTROL_CRC != 0 {
+                0
+            } else {
+                self.transfer_complete = true;
+                self.disk_irq |= irq;
+                if ready {
+                    self.write_data
+                } else {
+                    0
+                }
+            };
+            if self.position >= raw.len() {
+                raw.resize(self.position + 1, 0);
+            }
+            raw[self.position] = byte;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= DISK_SIDE_SIZE.max(raw.len()) {
+            self.end_of_head = true;
+            self.scanning = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl DiskDrive for Fds {
+    fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    fn inserted_side(&self) -> Option<usize> {
+        self.side
+    }
+
+    fn insert_side(&mut self, side: Option<usize>) {
+        let side = side.filter(|&side| side < self.sides.len());
+        match (self.side, side) {
+            (Some(_), Some(side)) => {
+                self.side = None;
+                self.pending_side = Some((side, SWAP_DELAY_CYCLES));
+            }
+            (None, Some(side)) => {
+                self.side = Some(side);
+                self.pending_side = None;
+            }
+            (_, None) => {
+                self.side = None;
+                self.pending_side = None;
+            }
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&self, address: u16) -> u8 {
+        match address {
+            0x4030..=0x4033 => self.read_register(address),
+            0x6000..=0xDFFF => self.ram[address as usize - 0x6000],
+            0xE000..=0xFFFF => self.bios[address as usize - 0xE000],
+            _ => 0,
+        }
+    }
+
+    fn cpu_read_mut(&mut self, address: u16) -> u8 {
+        let byte = self.cpu_read(address);
+        match address {
+            0x4030 if self.disk_registers_enabled => {
+                self.timer_irq = false;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4031 if self.disk_registers_enabled => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        byte
+    }
+
+    fn cpu_write(&mut self, address: u16, byte: u8) {
+        match address {
+            0x4020..=0x4026 => self.write_register(address, byte),
+            0x6000..=0xDFFF => self.ram[address as usize - 0x6000] = byte,
+            _ => {}
+        }
+    }
+
+    // the BIOS at $E000 is ROM
+    fn write_changes_prg(&self, address: u16) -> bool {
+        (0x8000..=0xDFFF).contains(&address)
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr[address as usize % CHR_BANK_SIZE]
+    }
+
+    fn chr_offset(&self, address: u16) -> Option<usize> {
+        Some(address as usize % CHR_BANK_SIZE)
+    }
+
+    fn ppu_write(&mut self, address: u16, byte: u8) {
+        self.chr[address as usize % CHR_BANK_SIZE] = byte;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.control & CONTROL_HORIZONTAL != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        true
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn cpu_tick(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut dyn DiskDrive> {
+        Some(self)
+    }
+
+    #[cfg(feature = "serde")]
+    fn serde_state(&self) -> Option<MapperStateRef<'_>> {
+        Some(MapperStateRef::Fds(self))
+    }
+
+    // the sides go in too, games save onto the disk
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bytes(&self.chr);
+        for side in &self.sides {
+            state.bytes(side);
+        }
+        state.bool(self.side.is_some());
+        state.u32(self.side.unwrap_or(0) as u32);
+        state.bool(self.pending_side.is_some());
+        let (pending, delay) = self.pending_side.unwrap_or((0, 0));
+        state.u32(pending as u32);
+        state.u32(delay);
+        state.u16(self.timer_reload);
+        state.u16(self.timer_counter);
+        state.bool(self.timer_repeat);
+        state.bool(self.timer_enabled);
+        state.bool(self.timer_irq);
+        state.bool(self.disk_registers_enabled);
+        state.u8(self.external_output);
+        state.u8(self.control);
+        state.u8(self.read_data);
+        state.u8(self.write_data);
+        state.bool(self.disk_irq);
+        state.bool(self.transfer_complete);
+        state.bool(self.end_of_head);
+        state.bool(self.scanning);
+        state.bool(self.gap_ended);
+        state.u32(self.position as u32);
+        state.u32(self.delay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.ram, "RAM adapter RAM size")?;
+        state.bytes_into(&mut self.chr, "CHR RAM size")?;
+        // writes past the end of a side can grow it
+        for side in self.sides.iter_mut() {
+            *side = state.bytes()?.to_vec();
+        }
+        let side_count = self.sides.len();
+        let side = |inserted: bool, side: u32| match (inserted, side as usize) {
+            (false, _) => Ok(None),
+            (true, side) if side < side_count => Ok(Some(side)),
+            _ => Err(StateError::Invalid("disk side")),
+        };
+        self.side = side(state.bool()?, state.u32()?)?;
+        let pending = side(state.bool()?, state.u32()?)?;
+        let delay = state.u32()?;
+        self.pending_side = pending.map(|side| (side, delay));
+        self.timer_reload = state.u16()?;
+        self.timer_counter = state.u16()?;
+        self.timer_repeat = state.bool()?;
+        self.timer_enabled = state.bool()?;
+        self.timer_irq = state.bool()?;
+        self.disk_registers_enabled = state.bool()?;
+        self.external_output = state.u8()?;
+        self.control = state.u8()?;
+        self.read_data = state.u8()?;
+        self.write_data = state.u8()?;
+        self.disk_irq = state.bool()?;
+        self.transfer_complete = state.bool()?;
+        self.end_of_head = state.bool()?;
+        self.scanning = state.bool()?;
+        self.gap_ended = state.bool()?;
+        self.position = state.u32()? as usize;
+        self.delay = state.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn side(files: u8) -> Vec<u8> {
+        let mut side = DISK_VERIFICATION.to_vec();
+        side.resize(DISK_INFO_SIZE, 0);
+        side.extend_from_slice(&[BLOCK_FILE_AMOUNT, files]);
+        for file in 0..files {
+            let mut header = [0u8; FILE_HEADER_SIZE];
+            header[0] = BLOCK_FILE_HEADER;
+            header[1] = file;
+            header[13] = 3;
+            side.extend_from_slice(&header);
+            side.extend_from_slice(&[BLOCK_FILE_DATA, 0xAA, 0xBB, file]);
+        }
+        side.resize(DISK_SIDE_SIZE, 0);
+        side
+    }
+
+    fn disk() -> FdsDisk {
+        let mut bytes = FDS_HEADER.to_vec();
+        bytes.resize(FDS_HEADER_SIZE, 0);
+        bytes[4] = 2;
+        bytes.extend(side(1));
+        bytes.extend(side(2));
+        FdsDisk::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn parses_images_with_and_without_header() {
+        let disk = disk();
+        assert_eq!(disk.side_count(), 2);
+        assert_eq!(FdsDisk::from_bytes(disk.side(1)).unwrap().side_count(), 1);
+        assert!(FdsDisk::is_disk_image(disk.side(0)));
+        assert!(matches!(
+            FdsDisk::from_bytes(&disk.side(0)[..1000]),
+            Err(RomError::BadDiskImage)
+        ));
+        assert!(matches!(
+            FdsDisk::from_bytes(&[0; DISK_SIDE_SIZE]),
+            Err(RomError::BadDiskImage)
+        ));
+        assert!(matches!(Fds::new(&[0; 100], &disk), Err(RomError::BadBios)));
+    }
+
+    #[test]
+    fn sides_expand_with_gaps() {
+        let raw = expand_side(&side(1));
+        assert!(raw[..LEADING_GAP].iter().all(|&b| b == 0));
+        assert_eq!(raw[LEADING_GAP], GAP_END);
+        assert_eq!(&raw[LEADING_GAP + 1..][..15], DISK_VERIFICATION);
+        let blocks = [DISK_INFO_SIZE, FILE_AMOUNT_SIZE, FILE_HEADER_SIZE, 4];
+        let length = LEADING_GAP
+            + blocks
+                .iter()
+                .map(|size| 1 + size + CRC_SIZE + BLOCK_GAP)
+                .sum::<usize>();
+        assert_eq!(raw.len(), length);
+    }
+
+    #[test]
+    fn reads_first_block() {
+        let mut fds = Fds::new(&[0; BIOS_SIZE], &disk()).unwrap();
+        fds.cpu_write(0x4023, 1);
+        fds.cpu_write(
+            0x4025,
+            CONTROL_MOTOR | CONTROL_READ | CONTROL_READY | CONTROL_IRQ,
+        );
+        let mut read = Vec::new();
+        for _ in 0..2_000_000 {
+            fds.cpu_tick();
+            if fds.irq_pending() {
+                assert_ne!(fds.cpu_read(0x4030) & STATUS_TRANSFER, 0);
+                read.push(fds.cpu_read_mut(0x4031));
+                assert!(!fds.irq_pending());
+                if read.len() == DISK_VERIFICATION.len() {
+                    break;
+                }
+            }
+        }
+        assert_eq!(read, DISK_VERIFICATION);
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = Fds::new(&[0; BIOS_SIZE], &disk()).unwrap();
+        fds.cpu_write(0x4023, 1);
+        fds.cpu_write(0x4020, 10);
+        fds.cpu_write(0x4021, 0);
+        fds.cpu_write(0x4022, 0b11);
+        for _ in 0..10 {
+            fds.cpu_tick();
+        }
+        assert!(!fds.irq_pending());
+        fds.cpu_tick();
+        assert!(fds.irq_pending());
+        assert_eq!(
+            fds.cpu_read_mut(0x4030) & STATUS_TIMER_IRQ,
+            STATUS_TIMER_IRQ
+        );
+        assert!(!fds.irq_pending());
+        // repeats
+        for _ in 0..11 {
+            fds.cpu_tick();
+        }
+        assert!(fds.irq_pending());
+    }
+
+    #[test]
+    fn swapping_sides_ejects_first() {
+        let mut fds = Fds::new(&[0; BIOS_SIZE], &disk()).unwrap();
+        fds.cpu_write(0x4023, 1);
+        assert_eq!(fds.inserted_side(), Some(0));
+        assert_eq!(fds.cpu_read(0x4032) & DRIVE_NO_DISK, 0);
+        fds.insert_side(Some(1));
+        assert_eq!(fds.inserted_side(), None);
+        assert_eq!(fds.cpu_read(0x4032) & DRIVE_NO_DISK, DRIVE_NO_DISK);
+        for _ in 0..=SWAP_DELAY_CYCLES {
+            fds.cpu_tick();
+        }
+        assert_eq!(fds.inserted_side(), Some(1));
+
+        fds.insert_side(None);
+        assert_eq!(fds.inserted_side(), None);
+        fds.insert_side(Some(5));
+        assert_eq!(fds.inserted_side(), None);
+        fds.insert_side(Some(0));
+        assert_eq!(fds.inserted_side(), Some(0));
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut bios = [0u8; BIOS_SIZE];
+        bios[BIOS_SIZE - 4] = 0x24;
+        let mut fds = Fds::new(&bios, &disk()).unwrap();
+        fds.cpu_write(0x6000, 1);
+        fds.cpu_write(0xDFFF, 2);
+        fds.cpu_write(0xFFFC, 3);
+        assert_eq!(fds.cpu_read(0x6000), 1);
+        assert_eq!(fds.cpu_read(0xDFFF), 2);
+        assert_eq!(fds.cpu_read(0xFFFC), 0x24);
+        assert_eq!(fds.mirroring(), Mirroring::Vertical);
+        fds.cpu_write(0x4023, 1);
+        fds.cpu_write(0x4025, CONTROL_HORIZONTAL);
+        assert_eq!(fds.mirroring(), Mirroring::Horizontal);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::emulator::Emulator;
+use crate::hash::Crc32;
+use crate::region::Region;
+use crate::NesRom;
+use std::io;
+use std::io::Write;
+
+// Per-frame timing export for speedrun verification.
+//
+// Format (version 1), CSV:
+//   # nesemu frame timing v1
+//   # rom_crc32: 0A1B2C3D
+//   # region: ntsc
+//   # signature: 4E5F6071
+//   frame,cycle,seconds,lag,input
+//   0,29781,0.016640,0,00
+//
+// JSON:
+//   {"format":"nesemu-frame-timing","version":1,"rom_crc32":"0A1B2C3D","region":"ntsc",
+//    "signature":"4E5F6071",
+//    "frames":[{"frame":0,"cycle":29781,"seconds":0.016640,"lag":false,"input":"00"}]}
+//
+// `cycle` is the CPU cycle count at the end of the frame, `seconds` is that count converted
+// with the region's CPU clock, `lag` is set when the game did not read $4016 or $4017 during
+// the frame and `input` is the controller 1 button byte (see `input::BUTTON_*`).
+// `signature` is a CRC32 over the rom hash, the region and every record so edited logs can be
+// detected, it is a checksum, not a cryptographic signature.
+//
+// `Movie::replay_timed` makes one of these from a movie. The core is deterministic, so timing
+// a replay of a movie just recorded gives the same log as timing it while recording.
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameRecord {
+    pub frame: u64,
+    pub cycle: u64,
+    pub lag: bool,
+    pub input: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameTimingLog {
+    rom_crc32: u32,
+    region: Region,
+    frames: Vec<FrameRecord>,
+}
+
+impl FrameTimingLog {
+    pub fn new(rom: &NesRom, region: Region) -> Self {
+        Self::with_rom_crc32(rom.crc32(), region)
+    }
+
+    pub fn with_rom_crc32(rom_crc32: u32, region: Region) -> Self {
+        FrameTimingLog {
+            rom_crc32,
+            region,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Append the frame `emulator` just ran, with controller 1 holding `input`
+    pub fn record(&mut self, emulator: &Emulator, input: u8) {
+        self.record_frame(emulator.cycles(), emulator.lag_frame(), input);
+    }
+
+    /// Append the timing of a finished frame
+    pub fn record_frame(&mut self, cycle: u64, lag: bool, input: u8) {
+        let frame = self.frames.len() as u64;
+        self.frames.push(FrameRecord {
+            frame,
+            cycle,
+            lag,
+            input,
+        });
+    }
+
+    pub fn rom_crc32(&self) -> u32 {
+        self.rom_crc32
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Time from power on to the end of `record`'s frame
+    pub fn seconds(&self, record: &FrameRecord) -> f64 {
+        record.cycle as f64 / self.region.cpu_clock_hz() as f64
+    }
+
+    pub fn frames(&self) -> &[FrameRecord] {
+        &self.frames
+    }
+
+    pub fn lag_frames(&self) -> usize {
+        self.frames.iter().filter(|record| record.lag).count()
+    }
+
+    pub fn signature(&self) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(&self.rom_crc32.to_le_bytes());
+        crc.update(self.region.name().as_bytes());
+        for record in &self.frames {
+            crc.update(&record.frame.to_le_bytes());
+            crc.update(&record.cycle.to_le_bytes());
+            crc.update(&[record.lag as u8, record.input]);
+        }
+        crc.finish()
+    }
+
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "# nesemu frame timing v{}", FORMAT_VERSION)?;
+        writeln!(out, "# rom_crc32: {:08X}", self.rom_crc32)?;
+        writeln!(out, "# region: {}", self.region.name())?;
+        writeln!(out, "# signature: {:08X}", self.signature())?;
+        writeln!(out, "frame,cycle,seconds,lag,input")?;
+        for record in &self.frames {
+            writeln!(
+                out,
+                "{},{},{:.6},{},{:02X}",
+                record.frame,
+                record.cycle,
+                self.seconds(record),
+                record.lag as u8,
+                record.input
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"format\":\"nesemu-frame-timing\",\"version\":{},\"rom_crc32\":\"{:08X}\",\"region\":\"{}\",\"signature\":\"{:08X}\",\"frames\":[",
+            FORMAT_VERSION,
+            self.rom_crc32,
+            self.region.name(),
+            self.signature()
+        )?;
+        for (i, record) in self.frames.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(
+                out,
+                "{{\"frame\":{},\"cycle\":{},\"seconds\":{:.6},\"lag\":{},\"input\":\"{:02X}\"}}",
+                record.frame,
+                record.cycle,
+                self.seconds(record),
+                record.lag,
+                record.input
+            )?;
+        }
+        writeln!(out, "]}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_export() {
+        let mut log = FrameTimingLog::with_rom_crc32(0xDEADBEEF, Region::Ntsc);
+        log.record_frame(29781, false, 0x08);
+        log.record_frame(59562, true, 0x00);
+        assert_eq!(log.lag_frames(), 1);
+
+        let mut out = Vec::new();
+        log.write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[1], "# rom_crc32: DEADBEEF");
+        assert_eq!(lines[2], "# region: ntsc");
+        assert_eq!(lines[4], "frame,cycle,seconds,lag,input");
+        assert_eq!(lines[5], "0,29781,0.016640,0,08");
+        assert_eq!(lines[6], "1,59562,0.033279,1,00");
+    }
+
+    #[test]
+    fn seconds_go_by_the_region_clock() {
+        let mut pal = FrameTimingLog::with_rom_crc32(1, Region::Pal);
+        pal.record_frame(33247, false, 0);
+        let ntsc = FrameTimingLog::with_rom_crc32(1, Region::Ntsc);
+        let record = &pal.frames()[0];
+        assert_eq!(format!("{:.6}", pal.seconds(record)), "0.019997");
+        assert!(ntsc.seconds(record) < pal.seconds(record));
+        assert_ne!(ntsc.signature(), pal.signature());
+    }
+
+    #[test]
+    fn signature_covers_records() {
+        let mut a = FrameTimingLog::with_rom_crc32(1, Region::Ntsc);
+        let mut b = FrameTimingLog::with_rom_crc32(1, Region::Ntsc);
+        a.record_frame(100, false, 0x01);
+        b.record_frame(100, false, 0x02);
+        assert_ne!(a.signature(), b.signature());
+
+        let mut out = Vec::new();
+        a.write_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.contains("\"rom_crc32\":\"00000001\""));
+        assert!(json.contains("\"lag\":false,\"input\":\"01\""));
+    }
+}
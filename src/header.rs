@@ -0,0 +1,685 @@
+use crate::mapper::Mirroring;
+use crate::region::Region;
+
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+//
+// Byte 6    NNNN FTBM  mapper bits 0-3, four screen, trainer, battery, mirroring
+// Byte 7    NNNN 10TT  mapper bits 4-7, NES 2.0 signature, console type
+// NES 2.0 only:
+// Byte 8    SSSS NNNN  submapper, mapper bits 8-11
+// Byte 9    CCCC PPPP  CHR and PRG ROM size high bits
+// Byte 10   pppp PPPP  PRG NVRAM and PRG RAM size as shift counts
+// Byte 11   cccc CCCC  CHR NVRAM and CHR RAM size as shift counts
+// Byte 12   .... ..VV  CPU/PPU timing
+// Byte 13   VS System PPU and hardware type, or the extended console type
+// Byte 14   .... ..RR  miscellaneous ROMs
+// Byte 15   ..DD DDDD  default expansion device
+// iNES 1.0 only:
+// Byte 8    PRG RAM size in 8KB units, 0 meaning 8KB
+// Byte 9    .... ...T  TV system
+
+pub const HEADER_SIZE: usize = 16;
+pub const MAGIC: [u8; 4] = *b"NES\x1A";
+/// Trainer stored between the header and PRG ROM when flags 6 bit 2 is set
+pub const TRAINER_SIZE: usize = 512;
+/// PlayChoice-10 instruction screens, after CHR ROM
+pub const INST_ROM_SIZE: usize = 8 * 1024;
+/// PlayChoice-10 PROM, 16 bytes of data then 16 of CounterOut, after the INST-ROM
+pub const PROM_SIZE: usize = 32;
+
+const FLAGS6_VERTICAL: u8 = 0b0000_0001;
+const FLAGS6_BATTERY: u8 = 0b0000_0010;
+const FLAGS6_TRAINER: u8 = 0b0000_0100;
+const FLAGS6_FOUR_SCREEN: u8 = 0b0000_1000;
+const FLAGS7_NES2_MASK: u8 = 0b0000_1100;
+const FLAGS7_NES2: u8 = 0b0000_1000;
+
+const PRG_ROM_UNIT: usize = 16 * 1024;
+const CHR_ROM_UNIT: usize = 8 * 1024;
+// iNES boards with no CHR ROM get 8KB of CHR RAM, and PRG RAM defaults to 8KB
+const INES_CHR_RAM: usize = 8 * 1024;
+const INES_PRG_RAM_UNIT: usize = 8 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderFormat {
+    /// iNES with bytes 7-15 zeroed or meaningful
+    INes,
+    /// iNES with garbage (often a ripper's name) in bytes 7-15, only byte 6 is trusted
+    Archaic,
+    Nes2,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    /// NES 2.0 extended console type from byte 13
+    Extended(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Runs on any region, emulated as NTSC
+    MultiRegion,
+    Dendy,
+}
+
+/// The PPU in a VS System cabinet, NES 2.0 byte 13 bits 0-3
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VsPpu {
+    Rp2c03b,
+    Rp2c03g,
+    Rp2c04_0001,
+    Rp2c04_0002,
+    Rp2c04_0003,
+    Rp2c04_0004,
+    Rc2c03b,
+    Rc2c03c,
+    /// $2002 reads back $3D in the low bits
+    Rc2c05_01,
+    /// $2002 reads back $3D in the low bits
+    Rc2c05_02,
+    /// $2002 reads back $1C in the low bits
+    Rc2c05_03,
+    /// $2002 reads back $1B in the low bits
+    Rc2c05_04,
+    Rc2c05_05,
+    Unknown(u8),
+}
+
+/// How a VS System PPU turns colour indexes into RGB
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VsPalette {
+    /// The RGB PPUs' palette, the same order as the NES
+    Rgb,
+    /// One of the four RP2C04 lookup tables, which scramble the order so the game only
+    /// looks right on the matching PPU
+    Rp2c04(u8),
+}
+
+impl VsPpu {
+    fn from_nibble(nibble: u8) -> VsPpu {
+        match nibble {
+            0x0 => VsPpu::Rp2c03b,
+            0x1 => VsPpu::Rp2c03g,
+            0x2 => VsPpu::Rp2c04_0001,
+            0x3 => VsPpu::Rp2c04_0002,
+            0x4 => VsPpu::Rp2c04_0003,
+            0x5 => VsPpu::Rp2c04_0004,
+            0x6 => VsPpu::Rc2c03b,
+            0x7 => VsPpu::Rc2c03c,
+            0x8 => VsPpu::Rc2c05_01,
+            0x9 => VsPpu::Rc2c05_02,
+            0xA => VsPpu::Rc2c05_03,
+            0xB => VsPpu::Rc2c05_04,
+            0xC => VsPpu::Rc2c05_05,
+            other => VsPpu::Unknown(other),
+        }
+    }
+
+    pub fn palette(&self) -> VsPalette {
+        match self {
+            VsPpu::Rp2c04_0001 => VsPalette::Rp2c04(1),
+            VsPpu::Rp2c04_0002 => VsPalette::Rp2c04(2),
+            VsPpu::Rp2c04_0003 => VsPalette::Rp2c04(3),
+            VsPpu::Rp2c04_0004 => VsPalette::Rp2c04(4),
+            _ => VsPalette::Rgb,
+        }
+    }
+
+    /// The 2C05s have PPUCTRL and PPUMASK at each other's addresses
+    pub fn swaps_ctrl_and_mask(&self) -> bool {
+        matches!(
+            self,
+            VsPpu::Rc2c05_01
+                | VsPpu::Rc2c05_02
+                | VsPpu::Rc2c05_03
+                | VsPpu::Rc2c05_04
+                | VsPpu::Rc2c05_05
+        )
+    }
+}
+
+/// VS System board variant, NES 2.0 byte 13 bits 4-7
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VsHardware {
+    Unisystem,
+    /// Unisystem with RBI Baseball's protection
+    RbiBaseball,
+    /// Unisystem with TKO Boxing's protection
+    TkoBoxing,
+    /// Unisystem with Super Xevious's protection
+    SuperXevious,
+    /// Unisystem with Ice Climber's controller swap
+    IceClimber,
+    /// Two CPUs and PPUs sharing RAM
+    DualSystem,
+    /// Dual System with Raid on Bungeling Bay's protection
+    RaidOnBungelingBay,
+    Unknown(u8),
+}
+
+impl VsHardware {
+    fn from_nibble(nibble: u8) -> VsHardware {
+        match nibble {
+            0 => VsHardware::Unisystem,
+            1 => VsHardware::RbiBaseball,
+            2 => VsHardware::TkoBoxing,
+            3 => VsHardware::SuperXevious,
+            4 => VsHardware::IceClimber,
+            5 => VsHardware::DualSystem,
+            6 => VsHardware::RaidOnBungelingBay,
+            other => VsHardware::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RomHeader {
+    bytes: [u8; HEADER_SIZE],
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    /// Sizes in bytes
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub console_type: ConsoleType,
+    pub timing: Timing,
+    /// NES 2.0 byte 13, VS System PPU/hardware type for VS System roms
+    pub vs_type: u8,
+    pub misc_roms: u8,
+    /// NES 2.0 default expansion device, see the wiki's input device list
+    pub expansion_device: u8,
+}
+
+impl RomHeader {
+    /// NES 2.0 header for a board without a header of its own, with iNES's default 8KB of
+    /// PRG RAM and CHR RAM when there's no CHR ROM
+    pub fn new(mapper: u16, prg_rom_size: usize, chr_rom_size: usize) -> RomHeader {
+        let mut header = RomHeader {
+            bytes: [0; HEADER_SIZE],
+            format: HeaderFormat::Nes2,
+            mapper,
+            submapper: 0,
+            prg_rom_size,
+            chr_rom_size,
+            prg_ram_size: INES_PRG_RAM_UNIT,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_rom_size == 0 { INES_CHR_RAM } else { 0 },
+            chr_nvram_size: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            console_type: ConsoleType::Nes,
+            timing: Timing::Ntsc,
+            vs_type: 0,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+        header.bytes = header.to_bytes();
+        header
+    }
+
+    /// Decode the 16 header bytes, the caller checks the magic number
+    pub fn parse(bytes: &[u8; HEADER_SIZE]) -> RomHeader {
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let format = if flags7 & FLAGS7_NES2_MASK == FLAGS7_NES2 {
+            HeaderFormat::Nes2
+        } else if flags7 & FLAGS7_NES2_MASK == 0 && bytes[12..16] == [0; 4] {
+            HeaderFormat::INes
+        } else {
+            HeaderFormat::Archaic
+        };
+
+        let mirroring = if flags6 & FLAGS6_FOUR_SCREEN != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & FLAGS6_VERTICAL != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let mut header = RomHeader {
+            bytes: *bytes,
+            format,
+            mapper: (flags6 >> 4) as u16,
+            submapper: 0,
+            prg_rom_size: bytes[4] as usize * PRG_ROM_UNIT,
+            chr_rom_size: bytes[5] as usize * CHR_ROM_UNIT,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring,
+            battery: flags6 & FLAGS6_BATTERY != 0,
+            trainer: flags6 & FLAGS6_TRAINER != 0,
+            console_type: ConsoleType::Nes,
+            timing: Timing::Ntsc,
+            vs_type: 0,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        match format {
+            HeaderFormat::Nes2 => header.parse_nes2(),
+            HeaderFormat::INes => {
+                header.mapper |= (flags7 & 0xF0) as u16;
+                header.console_type = match flags7 & 0b11 {
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Nes,
+                };
+                header.parse_ines_ram(bytes[8]);
+                if bytes[9] & 1 != 0 {
+                    header.timing = Timing::Pal;
+                }
+            }
+            HeaderFormat::Archaic => header.parse_ines_ram(0),
+        }
+        header
+    }
+
+    fn parse_ines_ram(&mut self, prg_ram_units: u8) {
+        let prg_ram = prg_ram_units.max(1) as usize * INES_PRG_RAM_UNIT;
+        // the battery flag is all iNES has to say about what's kept
+        if self.battery {
+            self.prg_nvram_size = prg_ram;
+        } else {
+            self.prg_ram_size = prg_ram;
+        }
+        if self.chr_rom_size == 0 {
+            self.chr_ram_size = INES_CHR_RAM;
+        }
+    }
+
+    fn parse_nes2(&mut self) {
+        let bytes = self.bytes;
+        self.mapper |= (bytes[7] & 0xF0) as u16 | ((bytes[8] & 0x0F) as u16) << 8;
+        self.submapper = bytes[8] >> 4;
+        self.prg_rom_size = rom_size(bytes[4], bytes[9] & 0x0F, PRG_ROM_UNIT);
+        self.chr_rom_size = rom_size(bytes[5], bytes[9] >> 4, CHR_ROM_UNIT);
+        self.prg_ram_size = shift_size(bytes[10] & 0x0F);
+        self.prg_nvram_size = shift_size(bytes[10] >> 4);
+        self.chr_ram_size = shift_size(bytes[11] & 0x0F);
+        self.chr_nvram_size = shift_size(bytes[11] >> 4);
+        self.console_type = match bytes[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(bytes[13] & 0x0F),
+        };
+        self.timing = match bytes[12] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        };
+        self.vs_type = bytes[13];
+        self.misc_roms = bytes[14] & 0b11;
+        self.expansion_device = bytes[15] & 0x3F;
+    }
+
+    /// The header as it was in the file
+    pub fn bytes(&self) -> &[u8; HEADER_SIZE] {
+        &self.bytes
+    }
+
+    /// Encode the fields, as iNES if the header wasn't NES 2.0 and iNES can hold everything,
+    /// otherwise as NES 2.0. Archaic headers come out with their junk cleared. Single screen
+    /// mirroring has no header bit and is written as horizontal.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[6] = (self.mapper as u8) << 4
+            | match self.mirroring {
+                Mirroring::Vertical => FLAGS6_VERTICAL,
+                Mirroring::FourScreen => FLAGS6_FOUR_SCREEN,
+                _ => 0,
+            };
+        if self.battery {
+            bytes[6] |= FLAGS6_BATTERY;
+        }
+        if self.trainer {
+            bytes[6] |= FLAGS6_TRAINER;
+        }
+        bytes[7] = self.mapper as u8 & 0xF0
+            | match self.console_type {
+                ConsoleType::Nes => 0,
+                ConsoleType::VsSystem => 1,
+                ConsoleType::Playchoice10 => 2,
+                ConsoleType::Extended(_) => 3,
+            };
+
+        if self.format != HeaderFormat::Nes2 && self.fits_ines() {
+            bytes[4] = (self.prg_rom_size / PRG_ROM_UNIT) as u8;
+            bytes[5] = (self.chr_rom_size / CHR_ROM_UNIT) as u8;
+            let prg_ram = (self.prg_ram_size + self.prg_nvram_size) / INES_PRG_RAM_UNIT;
+            // 8KB is what 0 means, and what nearly every iNES rom has
+            bytes[8] = if prg_ram == 1 { 0 } else { prg_ram as u8 };
+            bytes[9] = (self.timing == Timing::Pal) as u8;
+            return bytes;
+        }
+
+        let (prg_low, prg_high) = encode_rom_size(self.prg_rom_size, PRG_ROM_UNIT);
+        let (chr_low, chr_high) = encode_rom_size(self.chr_rom_size, CHR_ROM_UNIT);
+        bytes[4] = prg_low;
+        bytes[5] = chr_low;
+        bytes[7] |= FLAGS7_NES2;
+        bytes[8] = self.submapper << 4 | (self.mapper >> 8) as u8 & 0x0F;
+        bytes[9] = chr_high << 4 | prg_high;
+        bytes[10] = size_shift(self.prg_nvram_size) << 4 | size_shift(self.prg_ram_size);
+        bytes[11] = size_shift(self.chr_nvram_size) << 4 | size_shift(self.chr_ram_size);
+        bytes[12] = match self.timing {
+            Timing::Ntsc => 0,
+            Timing::Pal => 1,
+            Timing::MultiRegion => 2,
+            Timing::Dendy => 3,
+        };
+        bytes[13] = match self.console_type {
+            ConsoleType::Extended(console) => console & 0x0F,
+            _ => self.vs_type,
+        };
+        bytes[14] = self.misc_roms & 0b11;
+        bytes[15] = self.expansion_device & 0x3F;
+        bytes
+    }
+
+    // Whether an iNES header would parse back to the same fields
+    fn fits_ines(&self) -> bool {
+        let (ram, other_ram) = if self.battery {
+            (self.prg_nvram_size, self.prg_ram_size)
+        } else {
+            (self.prg_ram_size, self.prg_nvram_size)
+        };
+        let chr_ram = if self.chr_rom_size == 0 {
+            INES_CHR_RAM
+        } else {
+            0
+        };
+        self.mapper <= 0xFF
+            && self.submapper == 0
+            && self.prg_rom_size.is_multiple_of(PRG_ROM_UNIT)
+            && self.prg_rom_size / PRG_ROM_UNIT <= 0xFF
+            && self.chr_rom_size.is_multiple_of(CHR_ROM_UNIT)
+            && self.chr_rom_size / CHR_ROM_UNIT <= 0xFF
+            && ram != 0
+            && ram.is_multiple_of(INES_PRG_RAM_UNIT)
+            && ram / INES_PRG_RAM_UNIT <= 0xFF
+            && other_ram == 0
+            && self.chr_ram_size == chr_ram
+            && self.chr_nvram_size == 0
+            && !matches!(self.console_type, ConsoleType::Extended(_))
+            && matches!(self.timing, Timing::Ntsc | Timing::Pal)
+            && self.vs_type == 0
+            && self.misc_roms == 0
+            && self.expansion_device == 0
+    }
+
+    /// Bytes the header says the image needs: header, trainer, PRG and CHR ROM. PlayChoice-10
+    /// data is optional and not counted. None if the sizes overflow, which only a corrupt
+    /// NES 2.0 exponent can do.
+    pub fn image_size(&self) -> Option<usize> {
+        let trainer = if self.trainer { TRAINER_SIZE } else { 0 };
+        HEADER_SIZE
+            .checked_add(trainer)?
+            .checked_add(self.prg_rom_size)?
+            .checked_add(self.chr_rom_size)
+    }
+
+    /// The VS System PPU, None for other consoles. iNES has nowhere to say which PPU a
+    /// VS System rom wants so those get the RP2C03B.
+    pub fn vs_ppu(&self) -> Option<VsPpu> {
+        (self.console_type == ConsoleType::VsSystem)
+            .then(|| VsPpu::from_nibble(self.vs_type & 0x0F))
+    }
+
+    pub fn vs_hardware(&self) -> Option<VsHardware> {
+        (self.console_type == ConsoleType::VsSystem)
+            .then(|| VsHardware::from_nibble(self.vs_type >> 4))
+    }
+
+    pub fn is_nes2(&self) -> bool {
+        self.format == HeaderFormat::Nes2
+    }
+
+    /// Region to emulate, multi-region roms run as NTSC
+    pub fn region(&self) -> Region {
+        match self.timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+}
+
+// 12 bit bank count, or when the high nibble is $F an EEEEEEMM exponent-multiplier byte
+// giving 2^E * (MM * 2 + 1) bytes
+fn rom_size(low: u8, high: u8, unit: usize) -> usize {
+    if high == 0x0F {
+        let exponent = (low >> 2) as u32;
+        let multiplier = (low & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .map_or(usize::MAX, |size| size.saturating_mul(multiplier))
+    } else {
+        ((high as usize) << 8 | low as usize) * unit
+    }
+}
+
+// The other way round, sizes that aren't a whole number of banks round up to the nearest
+// exponent-multiplier size
+fn encode_rom_size(size: usize, unit: usize) -> (u8, u8) {
+    let banks = size / unit;
+    if size.is_multiple_of(unit) && banks < 0xF00 {
+        return (banks as u8, (banks >> 8) as u8);
+    }
+    let mut best = (usize::MAX, 0xFF);
+    for exponent in 0..64u8 {
+        for multiplier in 0..4u8 {
+            let low = exponent << 2 | multiplier;
+            let encoded = rom_size(low, 0x0F, unit);
+            if encoded >= size && encoded < best.0 {
+                best = (encoded, low);
+            }
+        }
+    }
+    (best.1, 0x0F)
+}
+
+// RAM sizes are 64 << shift bytes, 0 meaning none
+fn shift_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+// Smallest shift count holding `size` bytes
+fn size_shift(size: usize) -> u8 {
+    if size == 0 {
+        0
+    } else {
+        (1..0x0F)
+            .find(|&shift| shift_size(shift) >= size)
+            .unwrap_or(0x0F)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &[u8]) -> RomHeader {
+        let mut raw = [0u8; HEADER_SIZE];
+        raw[..4].copy_from_slice(&MAGIC);
+        raw[4..4 + bytes.len()].copy_from_slice(bytes);
+        RomHeader::parse(&raw)
+    }
+
+    #[test]
+    fn ines() {
+        // 2 PRG, 1 CHR, mapper $42, vertical, battery, PAL
+        let header = header(&[2, 1, 0x23, 0x40, 0, 1]);
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper, 0x42);
+        assert_eq!(header.prg_rom_size, 32 * 1024);
+        assert_eq!(header.chr_rom_size, 8 * 1024);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert_eq!(header.prg_nvram_size, 8 * 1024);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.chr_ram_size, 0);
+        assert_eq!(header.region(), Region::Pal);
+        assert!(!header.is_nes2());
+    }
+
+    #[test]
+    fn archaic_ignores_upper_mapper_bits() {
+        let mut raw = [0u8; HEADER_SIZE];
+        raw[..4].copy_from_slice(&MAGIC);
+        raw[4] = 1;
+        raw[6] = 0x10;
+        raw[7..].copy_from_slice(b"DiskDude!");
+        let header = RomHeader::parse(&raw);
+        assert_eq!(header.format, HeaderFormat::Archaic);
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.chr_ram_size, 8 * 1024);
+    }
+
+    #[test]
+    fn nes2() {
+        let header = header(&[
+            0x02, 0x01, // PRG and CHR low bytes
+            0x1C, // mapper low nibble 1, four screen, trainer
+            0x49, // mapper 4, NES 2.0, VS System
+            0x32, // submapper 3, mapper bits 8-11 = 2
+            0x01, // PRG ROM high nibble
+            0x97, // 32KB NVRAM, 8KB RAM
+            0x07, // 8KB CHR RAM
+            0x03, // Dendy
+            0x12, // VS type
+            0x01, // misc roms
+            0x08, // expansion device
+        ]);
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.mapper, 0x241);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_rom_size, 0x102 * 16 * 1024);
+        assert_eq!(header.chr_rom_size, 8 * 1024);
+        assert_eq!(header.prg_ram_size, 8 * 1024);
+        assert_eq!(header.prg_nvram_size, 32 * 1024);
+        assert_eq!(header.chr_ram_size, 8 * 1024);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert!(header.trainer);
+        assert_eq!(header.mirroring, Mirroring::FourScreen);
+        assert_eq!(header.console_type, ConsoleType::VsSystem);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(header.vs_type, 0x12);
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.expansion_device, 8);
+    }
+
+    #[test]
+    fn nes2_exponent_multiplier_size() {
+        // 2^5 * 3 bytes of PRG ROM
+        let header = header(&[0b0001_0101, 0, 0, 0x08, 0, 0x0F]);
+        assert_eq!(header.prg_rom_size, 96);
+        assert_eq!(header.to_bytes()[4], 0b0001_0101);
+        assert_eq!(encode_rom_size(95, PRG_ROM_UNIT), (0b0001_0101, 0x0F));
+    }
+
+    #[test]
+    fn vs_system() {
+        // NES 2.0 VS System, RC2C05-03 on a Super Xevious board
+        let header = header(&[1, 1, 0, 0x09, 0, 0, 0, 0, 0, 0x3A]);
+        assert_eq!(header.console_type, ConsoleType::VsSystem);
+        assert_eq!(header.vs_ppu(), Some(VsPpu::Rc2c05_03));
+        assert_eq!(header.vs_hardware(), Some(VsHardware::SuperXevious));
+        assert!(header.vs_ppu().unwrap().swaps_ctrl_and_mask());
+        assert_eq!(VsPpu::from_nibble(4).palette(), VsPalette::Rp2c04(3));
+
+        // iNES VS System and PlayChoice-10 bits
+        assert_eq!(
+            self::header(&[1, 1, 0, 0x01]).vs_ppu(),
+            Some(VsPpu::Rp2c03b)
+        );
+        let playchoice = self::header(&[1, 1, 0, 0x02]);
+        assert_eq!(playchoice.console_type, ConsoleType::Playchoice10);
+        assert_eq!(playchoice.vs_ppu(), None);
+    }
+
+    #[test]
+    fn image_size() {
+        let mut header = header(&[2, 1, 0x04]);
+        assert_eq!(header.image_size(), Some(16 + 512 + 2 * 16384 + 8192));
+        header.prg_rom_size = usize::MAX;
+        assert_eq!(header.image_size(), None);
+    }
+
+    fn round_trips(header: &RomHeader) {
+        let written = RomHeader::parse(&header.to_bytes());
+        assert_eq!(&written.to_bytes(), header.bytes());
+        assert_eq!(written.format, header.format);
+        assert_eq!(written.mapper, header.mapper);
+        assert_eq!(written.prg_rom_size, header.prg_rom_size);
+        assert_eq!(written.prg_ram_size, header.prg_ram_size);
+        assert_eq!(written.prg_nvram_size, header.prg_nvram_size);
+        assert_eq!(written.chr_ram_size, header.chr_ram_size);
+    }
+
+    #[test]
+    fn writes_headers_back_unchanged() {
+        round_trips(&header(&[2, 1, 0x23, 0x40, 0, 1]));
+        round_trips(&header(&[2, 0, 0x01, 0, 4]));
+        round_trips(&header(&[
+            0x02, 0x01, 0x1C, 0x49, 0x32, 0x01, 0x97, 0x07, 0x03, 0x12, 0x01, 0x08,
+        ]));
+    }
+
+    #[test]
+    fn archaic_headers_are_cleaned() {
+        let mut raw = [0u8; HEADER_SIZE];
+        raw[..4].copy_from_slice(&MAGIC);
+        raw[4] = 1;
+        raw[6] = 0x12;
+        raw[7..].copy_from_slice(b"DiskDude!");
+        let bytes = RomHeader::parse(&raw).to_bytes();
+        assert_eq!(bytes[..7], raw[..7]);
+        assert_eq!(bytes[7..], [0; 9]);
+    }
+
+    #[test]
+    fn ines_that_no_longer_fits_becomes_nes2() {
+        let mut header = header(&[2, 1, 0x10]);
+        header.mapper = 0x123;
+        header.submapper = 2;
+        header.timing = Timing::Dendy;
+        let written = RomHeader::parse(&header.to_bytes());
+        assert_eq!(written.format, HeaderFormat::Nes2);
+        assert_eq!(written.mapper, 0x123);
+        assert_eq!(written.submapper, 2);
+        assert_eq!(written.timing, Timing::Dendy);
+        assert_eq!(written.prg_ram_size, 8 * 1024);
+        assert_eq!(written.prg_rom_size, 32 * 1024);
+    }
+
+    #[test]
+    fn new_header() {
+        let header = RomHeader::new(7, 128 * 1024, 0);
+        assert_eq!(RomHeader::parse(header.bytes()), header);
+        assert_eq!(header.chr_ram_size, 8 * 1024);
+        assert_eq!(header.prg_ram_size, 8 * 1024);
+        assert!(header.is_nes2());
+    }
+}
@@ -0,0 +1,229 @@
+// https://www.nesdev.org/wiki/Expansion_port
+// The Famicom's 15 pin expansion port sees the same $4016 writes as the controller ports
+// (OUT0-OUT2, bits 0-2) and drives D1-D4 of $4016 and $4017 reads, so devices plugged into it
+// sit alongside the built in pads instead of replacing them.
+
+/// Data lines the expansion port can drive on a $4016/$4017 read
+pub const EXPANSION_DATA_BITS: u8 = 0b0001_1110;
+
+/// Something plugged into the Famicom expansion port
+pub trait ExpansionDevice {
+    /// $4016 write, OUT0-OUT2 in the low 3 bits
+    fn write(&mut self, outputs: u8);
+    /// Read of $4016 (port 0) or $4017 (port 1), only D1-D4 are used
+    fn read(&mut self, port: usize) -> u8;
+    /// `read` without side effects, for debuggers
+    fn peek(&self, port: usize) -> u8;
+}
+
+// https://www.nesdev.org/wiki/Family_BASIC_Keyboard
+// 9 rows of 2 columns of 4 keys. Keys are declared in matrix order so `key as usize` is
+// row * 8 + column * 4 + data line.
+#[rustfmt::skip]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum FamilyKey {
+    RightBracket, LeftBracket, Return, F8, Stop, Yen, RightShift, Kana,
+    Semicolon, Colon, At, F7, Caret, Minus, Slash, Underscore,
+    K, L, O, F6, Num0, P, Comma, Period,
+    J, U, I, F5, Num8, Num9, N, M,
+    H, G, Y, F4, Num6, Num7, V, B,
+    D, R, T, F3, Num4, Num5, C, F,
+    A, S, W, F2, Num3, E, Z, X,
+    Ctrl, Q, Escape, F1, Num2, Num1, Graph, LeftShift,
+    Left, Right, Up, ClearHome, Insert, Delete, Space, Down,
+}
+
+const KEYBOARD_ROWS: usize = 9;
+const KEYBOARD_RESET: u8 = 0b001;
+const KEYBOARD_COLUMN: u8 = 0b010;
+const KEYBOARD_ENABLE: u8 = 0b100;
+
+/// Family BASIC keyboard, read through $4017
+#[derive(Debug, Clone)]
+pub struct FamilyKeyboard {
+    pressed: [bool; KEYBOARD_ROWS * 8],
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl Default for FamilyKeyboard {
+    fn default() -> Self {
+        FamilyKeyboard {
+            pressed: [false; KEYBOARD_ROWS * 8],
+            row: 0,
+            column: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_key(&mut self, key: FamilyKey, pressed: bool) {
+        self.pressed[key as usize] = pressed;
+    }
+
+    pub fn is_pressed(&self, key: FamilyKey) -> bool {
+        self.pressed[key as usize]
+    }
+}
+
+impl ExpansionDevice for FamilyKeyboard {
+    fn write(&mut self, outputs: u8) {
+        self.enabled = outputs & KEYBOARD_ENABLE != 0;
+        let column = (outputs & KEYBOARD_COLUMN != 0) as usize;
+        // dropping the column select moves on to the next row
+        if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+        if outputs & KEYBOARD_RESET != 0 {
+            self.row = 0;
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        self.peek(port)
+    }
+
+    fn peek(&self, port: usize) -> u8 {
+        if port != 1 || !self.enabled {
+            return 0;
+        }
+        if self.row >= KEYBOARD_ROWS {
+            // past the matrix every key reads released
+            return EXPANSION_DATA_BITS;
+        }
+        // keys pull their line low when pressed
+        let base = self.row * 8 + self.column * 4;
+        (0..4)
+            .filter(|line| !self.pressed[base + line])
+            .fold(0, |data, line| data | 0b10 << line)
+    }
+}
+
+// https://www.nesdev.org/wiki/Arkanoid_controller
+// Strobing latches the knob position, which then shifts out MSB first and inverted on $4017
+// D1. The fire button is $4016 D1.
+/// The knob's useful range on the Famicom Arkanoid controller
+pub const VAUS_RANGE: std::ops::RangeInclusive<u8> = 0x62..=0xF2;
+
+/// Famicom Arkanoid "Vaus" paddle
+#[derive(Debug, Clone)]
+pub struct VausPaddle {
+    position: u8,
+    fire: bool,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Default for VausPaddle {
+    fn default() -> Self {
+        VausPaddle {
+            position: *VAUS_RANGE.start(),
+            fire: false,
+            shift: 0,
+            strobe: false,
+        }
+    }
+}
+
+impl VausPaddle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Knob position, clamped to `VAUS_RANGE`
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position.clamp(*VAUS_RANGE.start(), *VAUS_RANGE.end());
+    }
+
+    /// Knob position from 0.0 (left) to 1.0 (right), for mouse input
+    pub fn set_position_fraction(&mut self, fraction: f32) {
+        let (start, end) = (*VAUS_RANGE.start() as f32, *VAUS_RANGE.end() as f32);
+        self.set_position((start + (end - start) * fraction.clamp(0.0, 1.0)).round() as u8);
+    }
+
+    pub fn set_fire(&mut self, pressed: bool) {
+        self.fire = pressed;
+    }
+}
+
+impl ExpansionDevice for VausPaddle {
+    fn write(&mut self, outputs: u8) {
+        let strobe = outputs & 1 != 0;
+        if strobe {
+            self.shift = !self.position;
+        }
+        self.strobe = strobe;
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        let data = self.peek(port);
+        if port == 1 && !self.strobe {
+            // reads 0 once all 8 bits are out
+            self.shift <<= 1;
+        }
+        data
+    }
+
+    fn peek(&self, port: usize) -> u8 {
+        match port {
+            0 => (self.fire as u8) << 1,
+            _ => (self.shift >> 7) << 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_scans_rows_and_columns() {
+        let mut keyboard = FamilyKeyboard::new();
+        keyboard.set_key(FamilyKey::Return, true);
+        keyboard.set_key(FamilyKey::Space, true);
+        assert_eq!(keyboard.read(1), 0);
+
+        // reset to row 0, column 0
+        keyboard.write(KEYBOARD_ENABLE | KEYBOARD_RESET);
+        keyboard.write(KEYBOARD_ENABLE);
+        // Return is the third line
+        assert_eq!(keyboard.read(1), 0b1_0110);
+        keyboard.write(KEYBOARD_ENABLE | KEYBOARD_COLUMN);
+        assert_eq!(keyboard.read(1), EXPANSION_DATA_BITS);
+
+        // on to row 8, column 1
+        for _ in 0..8 {
+            keyboard.write(KEYBOARD_ENABLE);
+            keyboard.write(KEYBOARD_ENABLE | KEYBOARD_COLUMN);
+        }
+        assert_eq!(keyboard.read(1), 0b1_0110);
+        keyboard.write(KEYBOARD_ENABLE);
+        assert_eq!(keyboard.read(1), EXPANSION_DATA_BITS);
+        assert_eq!(keyboard.read(0), 0);
+    }
+
+    #[test]
+    fn vaus_shifts_out_inverted_position() {
+        let mut paddle = VausPaddle::new();
+        paddle.set_position(0xA5);
+        paddle.set_fire(true);
+        paddle.write(1);
+        paddle.write(0);
+        let bits: Vec<u8> = (0..9).map(|_| paddle.read(1) >> 1).collect();
+        // !0xA5 = 0x5A, MSB first
+        assert_eq!(bits, [0, 1, 0, 1, 1, 0, 1, 0, 0]);
+        assert_eq!(paddle.read(0), 0b10);
+
+        paddle.set_position_fraction(1.0);
+        assert_eq!(paddle.position, *VAUS_RANGE.end());
+        paddle.set_position(0);
+        assert_eq!(paddle.position, *VAUS_RANGE.start());
+    }
+}
@@ -0,0 +1,285 @@
+pub mod famicom;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// https://www.nesdev.org/wiki/Standard_controller
+// https://www.nesdev.org/wiki/Input_devices
+//
+// Writing bit 0 of $4016 sets the strobe line of both ports. While it's high the devices keep
+// reloading their state, when it drops the state is latched and each read of $4016 (port 1)
+// or $4017 (port 2) shifts one bit out on D0.
+
+// Standard controller report order, also the layout of the button byte
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+/// Button bits with the names used in binding config files
+pub const BUTTON_NAMES: [(u8, &str); 8] = [
+    (BUTTON_A, "a"),
+    (BUTTON_B, "b"),
+    (BUTTON_SELECT, "select"),
+    (BUTTON_START, "start"),
+    (BUTTON_UP, "up"),
+    (BUTTON_DOWN, "down"),
+    (BUTTON_LEFT, "left"),
+    (BUTTON_RIGHT, "right"),
+];
+
+pub fn button_from_name(name: &str) -> Option<u8> {
+    BUTTON_NAMES
+        .iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|&(button, _)| button)
+}
+
+pub fn button_name(button: u8) -> Option<&'static str> {
+    BUTTON_NAMES
+        .iter()
+        .find(|&&(b, _)| b == button)
+        .map(|&(_, name)| name)
+}
+
+/// Controller ports on the console
+pub const PORTS: usize = 2;
+/// Players with a Four Score plugged in, players 1 and 3 read through $4016, 2 and 4 $4017
+pub const PLAYERS: usize = 4;
+
+/// Bits of a $4016/$4017 read the ports don't drive. They float at the open bus value,
+/// which is the $40 high byte of the address for the usual `LDA $4016`.
+pub const OPEN_BUS_BITS: u8 = 0x40;
+
+/// Something plugged into a controller port
+pub trait Controller {
+    /// $4016 bit 0 write
+    fn write_strobe(&mut self, strobe: bool);
+    /// Serial read, the port's data lines in the low bits
+    fn read(&mut self) -> u8;
+    /// `read` without shifting, for debuggers
+    fn peek(&self) -> u8;
+    /// Buttons held this frame, set by the frontend. Devices without buttons ignore it.
+    fn set_buttons(&mut self, buttons: u8);
+    /// Buttons of the second pad behind a multitap on this port
+    fn set_tap_buttons(&mut self, _buttons: u8) {}
+    /// Write the device's latch and shift register into a save state. The buttons are
+    /// whatever is held now, so they stay out of it.
+    fn save_state(&self, _state: &mut StateWriter) {}
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+/// Button state handed from a frontend's event thread to the emulation thread
+#[derive(Debug, Default)]
+pub struct SharedButtons {
+    players: [AtomicU8; PLAYERS],
+}
+
+impl SharedButtons {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, player: usize) -> u8 {
+        self.players[player].load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, player: usize, buttons: u8) {
+        self.players[player].store(buttons, Ordering::Relaxed);
+    }
+}
+
+/// The standard 8 button pad
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StandardController {
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl StandardController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+}
+
+impl Controller for StandardController {
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            // keeps reporting A while the strobe is held
+            return self.buttons & BUTTON_A;
+        }
+        let bit = self.shift & 1;
+        // official pads shift in 1s, so reads after the 8th return 1
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & BUTTON_A
+        } else {
+            self.shift & 1
+        }
+    }
+
+    fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.shift);
+        state.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.shift = state.u8()?;
+        self.strobe = state.bool()?;
+        Ok(())
+    }
+}
+
+// https://www.nesdev.org/wiki/Four_Score
+// Each port reports its two pads back to back followed by a signature byte the game checks
+// to tell the adapter apart from a pair of plain pads.
+const FOUR_SCORE_SIGNATURES: [u8; PORTS] = [0b0000_1000, 0b0000_0100];
+
+/// One port's half of a Four Score: players 1 and 3 on $4016, or 2 and 4 on $4017
+#[derive(Debug, Copy, Clone)]
+pub struct FourScore {
+    buttons: [u8; 2],
+    signature: u8,
+    shift: u32,
+    strobe: bool,
+}
+
+impl FourScore {
+    /// The half plugged into `port` (0 for $4016, 1 for $4017)
+    pub fn new(port: usize) -> Self {
+        FourScore {
+            buttons: [0; 2],
+            signature: FOUR_SCORE_SIGNATURES[port],
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    fn report(&self) -> u32 {
+        self.buttons[0] as u32 | (self.buttons[1] as u32) << 8 | (self.signature as u32) << 16
+    }
+}
+
+impl Controller for FourScore {
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.report();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons[0] & BUTTON_A;
+        }
+        let bit = (self.shift & 1) as u8;
+        // 1s after the 24 bit report, like the standard pad
+        self.shift = (self.shift >> 1) | 0x80_0000;
+        bit
+    }
+
+    fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons[0] & BUTTON_A
+        } else {
+            (self.shift & 1) as u8
+        }
+    }
+
+    fn set_buttons(&mut self, buttons: u8) {
+        self.buttons[0] = buttons;
+    }
+
+    fn set_tap_buttons(&mut self, buttons: u8) {
+        self.buttons[1] = buttons;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.shift);
+        state.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.shift = state.u32()?;
+        self.strobe = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_buttons_in_order() {
+        let mut pad = StandardController::new();
+        pad.set_buttons(BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+        pad.write_strobe(true);
+        pad.write_strobe(false);
+        // changes after the latch don't show up until the next strobe
+        pad.set_button(BUTTON_B, true);
+        let bits: Vec<u8> = (0..10).map(|_| pad.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn four_score_reports_both_pads_and_signature() {
+        let mut port = FourScore::new(1);
+        port.set_buttons(BUTTON_A);
+        port.set_tap_buttons(BUTTON_RIGHT);
+        port.write_strobe(true);
+        port.write_strobe(false);
+        let report: u32 = (0..24).map(|bit| (port.read() as u32) << bit).sum();
+        assert_eq!(report, 0x04_80_01);
+        assert_eq!(port.read(), 1);
+    }
+
+    #[test]
+    fn strobe_high_repeats_a() {
+        let mut pad = StandardController::new();
+        pad.set_buttons(BUTTON_A);
+        pad.write_strobe(true);
+        assert_eq!(pad.read(), 1);
+        assert_eq!(pad.read(), 1);
+        pad.set_button(BUTTON_A, false);
+        assert_eq!(pad.peek(), 0);
+        assert_eq!(pad.read(), 0);
+    }
+}
@@ -231,6 +231,75 @@ impl AddressingMode {
     }
 }
 
+// https://www.nesdev.org/wiki/6502_cycle_times
+// Base cycle count for every opcode, illegal ones included
+#[rustfmt::skip]
+pub(crate) const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+
+/// Whether `opcode` is one of the 151 documented ones
+pub(crate) fn is_official(opcode: u8) -> bool {
+    match NesCpu::decode_instruction(opcode).0 {
+        Instructions::NoOperation => opcode == 0xEA,
+        Instructions::JAM
+        | Instructions::ISC
+        | Instructions::SLO
+        | Instructions::SAX
+        | Instructions::DCP
+        | Instructions::ARR
+        | Instructions::TAS
+        | Instructions::ANE
+        | Instructions::LAX
+        | Instructions::RLA
+        | Instructions::ANC
+        | Instructions::SRE
+        | Instructions::RRA
+        | Instructions::ALR
+        | Instructions::USBC
+        | Instructions::LAS
+        | Instructions::LXA
+        | Instructions::SHA
+        | Instructions::SBX
+        | Instructions::SHY
+        | Instructions::SHX => false,
+        _ => true,
+    }
+}
+
+/// Read instructions indexed by X/Y take an extra cycle when the index crosses a page.
+/// Stores and read-modify-write instructions always pay it, so it's already in CYCLES.
+pub(crate) fn has_page_cross_penalty(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x11 | 0x19 | 0x1D // ORA
+            | 0x31 | 0x39 | 0x3D // AND
+            | 0x51 | 0x59 | 0x5D // EOR
+            | 0x71 | 0x79 | 0x7D // ADC
+            | 0xB1 | 0xB9 | 0xBD | 0xBE | 0xBC // LDA LDX LDY
+            | 0xD1 | 0xD9 | 0xDD // CMP
+            | 0xF1 | 0xF9 | 0xFD // SBC
+            | 0xB3 | 0xBF | 0xBB // LAX LAS
+            | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC // NOP abs,X
+    )
+}
+
 impl Processor for NesCpu {
     fn decode_instruction(opcode: u8) -> (Instructions, AddressingMode) {
         match opcode {
@@ -348,7 +417,7 @@ impl Processor for NesCpu {
             0xA1 => (Instructions::LoadAccumulator, AddressingMode::XIndirect),
             0xB1 => (Instructions::LoadAccumulator, AddressingMode::YIndirect),
             0xA4 => (Instructions::LoadY, AddressingMode::ZeroPage),
-            0x4E => (Instructions::ShiftOneRight, AddressingMode::Accumulator),
+            0x4E => (Instructions::ShiftOneRight, AddressingMode::Absolute),
             0x35 => (Instructions::ANDAccumulator, AddressingMode::ZeroPageX),
             0xBA => (Instructions::StackPointerToX, AddressingMode::Implied),
             0x66 => (Instructions::RotateOneRight, AddressingMode::ZeroPage),
@@ -406,7 +475,7 @@ impl Processor for NesCpu {
 
             0x27 => (Instructions::RLA, AddressingMode::ZeroPage),
             0x23 => (Instructions::RLA, AddressingMode::XIndirect),
-            0x37 => (Instructions::RLA, AddressingMode::ZeroPage),
+            0x37 => (Instructions::RLA, AddressingMode::ZeroPageX),
             0x2F => (Instructions::RLA, AddressingMode::Absolute),
             0x3B => (Instructions::RLA, AddressingMode::AbsoluteY),
             0x33 => (Instructions::RLA, AddressingMode::YIndirect),
@@ -474,8 +543,10 @@ impl Processor for NesCpu {
             0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xEA | 0xFA => {
                 (Instructions::NoOperation, AddressingMode::Implied)
             }
-            0x04 | 0x44 | 0x64 | 0x89 => (Instructions::NoOperation, AddressingMode::ZeroPage),
-            0x80 | 0x82 | 0xC2 | 0xE2 => (Instructions::NoOperation, AddressingMode::Immediate),
+            0x04 | 0x44 | 0x64 => (Instructions::NoOperation, AddressingMode::ZeroPage),
+            0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => {
+                (Instructions::NoOperation, AddressingMode::Immediate)
+            }
             0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => {
                 (Instructions::NoOperation, AddressingMode::ZeroPageX)
             }
@@ -659,6 +730,7 @@ impl Processor for NesCpu {
             (Instructions::ISC, AddressingMode::AbsoluteX) => 0xFF,
             (Instructions::ISC, AddressingMode::ZeroPageX) => 0xF7,
             (Instructions::RLA, AddressingMode::ZeroPage) => 0x27,
+            (Instructions::RLA, AddressingMode::ZeroPageX) => 0x37,
             (Instructions::RLA, AddressingMode::XIndirect) => 0x23,
             (Instructions::RLA, AddressingMode::Absolute) => 0x2F,
             (Instructions::RLA, AddressingMode::AbsoluteY) => 0x3B,
@@ -730,3 +802,157 @@ impl Processor for NesCpu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    const INSTRUCTIONS: &[Instructions] = &[
+        Instructions::SetInterruptDisable,
+        Instructions::ClearInterruptDisable,
+        Instructions::SetDecimalMode,
+        Instructions::ClearDecimalMode,
+        Instructions::ClearOverflow,
+        Instructions::SetCarry,
+        Instructions::ClearCarry,
+        Instructions::LoadAccumulator,
+        Instructions::StoreAccumulator,
+        Instructions::LoadX,
+        Instructions::LoadY,
+        Instructions::StoreX,
+        Instructions::StoreY,
+        Instructions::EORAccumulator,
+        Instructions::ORAccumulator,
+        Instructions::ANDAccumulator,
+        Instructions::CompareAccumulator,
+        Instructions::CompareX,
+        Instructions::CompareY,
+        Instructions::BranchOnCarrySet,
+        Instructions::BranchOnCarryClear,
+        Instructions::BranchOnResultZero,
+        Instructions::BranchOnResultMinus,
+        Instructions::BranchNotZero,
+        Instructions::BranchOnResultPlus,
+        Instructions::BranchOverflowClear,
+        Instructions::BranchOnOverflowSet,
+        Instructions::DecrementX,
+        Instructions::DecrementY,
+        Instructions::DecrementMem,
+        Instructions::IncrementX,
+        Instructions::IncrementY,
+        Instructions::IncrementMem,
+        Instructions::JumpSubroutine,
+        Instructions::Jump,
+        Instructions::PopAccOffStack,
+        Instructions::PullStatusFromStack,
+        Instructions::PushAccOnStack,
+        Instructions::PushStatusOnStack,
+        Instructions::ShiftOneRight,
+        Instructions::ShiftOneLeft,
+        Instructions::RotateOneLeft,
+        Instructions::RotateOneRight,
+        Instructions::ReturnFromInterrupt,
+        Instructions::ReturnFromSubroutine,
+        Instructions::AccumulatorToY,
+        Instructions::AccumulatorToX,
+        Instructions::XToAccumulator,
+        Instructions::YToAccumulator,
+        Instructions::StackPointerToX,
+        Instructions::XToStackPointer,
+        Instructions::AddToAccWithCarry,
+        Instructions::TestBitsAccumulator,
+        Instructions::SubAccWithBorrow,
+        Instructions::NoOperation,
+        Instructions::JAM,
+        Instructions::ForceBreak,
+        Instructions::ISC,
+        Instructions::SLO,
+        Instructions::SAX,
+        Instructions::DCP,
+        Instructions::ARR,
+        Instructions::TAS,
+        Instructions::ANE,
+        Instructions::LAX,
+        Instructions::RLA,
+        Instructions::ANC,
+        Instructions::SRE,
+        Instructions::RRA,
+        Instructions::ALR,
+        Instructions::USBC,
+        Instructions::LAS,
+        Instructions::LXA,
+        Instructions::SHA,
+        Instructions::SBX,
+        Instructions::SHY,
+        Instructions::SHX,
+    ];
+
+    const MODES: &[AddressingMode] = &[
+        AddressingMode::Accumulator,
+        AddressingMode::Absolute,
+        AddressingMode::AbsoluteX,
+        AddressingMode::AbsoluteY,
+        AddressingMode::Immediate,
+        AddressingMode::Implied,
+        AddressingMode::Indirect,
+        AddressingMode::XIndirect,
+        AddressingMode::YIndirect,
+        AddressingMode::Relative,
+        AddressingMode::ZeroPage,
+        AddressingMode::ZeroPageX,
+        AddressingMode::ZeroPageY,
+    ];
+
+    const JAMS: [u8; 12] = [
+        0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+    ];
+
+    proptest! {
+        // enough cases to reach most of the instruction and mode pairs
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        // opcodes with the same instruction and mode may encode to either, so compare decoded
+        #[test]
+        fn decode_then_encode_round_trips(opcode in any::<u8>()) {
+            let (instruction, mode) = NesCpu::decode_instruction(opcode);
+            let encoded = NesCpu::encode_instructions(instruction.clone(), mode.clone());
+            prop_assert_eq!(NesCpu::decode_instruction(encoded), (instruction, mode));
+        }
+
+        #[test]
+        fn encode_then_decode_round_trips(
+            instruction in select(INSTRUCTIONS),
+            mode in select(MODES),
+        ) {
+            let opcode = NesCpu::encode_instructions(instruction.clone(), mode.clone());
+            // pairs with no opcode encode to a JAM
+            if opcode != 0x02 {
+                prop_assert_eq!(NesCpu::decode_instruction(opcode), (instruction, mode));
+            }
+        }
+    }
+
+    #[test]
+    fn only_jams_decode_to_jam() {
+        for opcode in 0..=0xFF {
+            let (instruction, _) = NesCpu::decode_instruction(opcode);
+            assert_eq!(
+                instruction == Instructions::JAM,
+                JAMS.contains(&opcode),
+                "${:02X} decodes to {:?}",
+                opcode,
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn lsr_absolute() {
+        assert_eq!(
+            NesCpu::decode_instruction(0x4E),
+            (Instructions::ShiftOneRight, AddressingMode::Absolute)
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::ops::BitOr;
+
+// https://www.nesdev.org/wiki/IRQ
+// The CPU's IRQ input is one wire that anything can pull low, and it stays low while any of
+// them holds it. Each thing that can interrupt is a bit here: the bus sets and clears its
+// bit as that source asserts and acknowledges, independently of the others, and the CPU
+// takes an IRQ between instructions while any bit is set and I is clear.
+
+/// A set of IRQ sources, one bit each
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IrqSource(u8);
+
+impl IrqSource {
+    pub const NONE: IrqSource = IrqSource(0);
+    /// APU frame counter, in 4 step mode unless inhibited, acknowledged by reading $4015
+    pub const FRAME_COUNTER: IrqSource = IrqSource(0b0000_0001);
+    /// DMC at the end of a sample, acknowledged by writing $4010 or $4015
+    pub const DMC: IrqSource = IrqSource(0b0000_0010);
+    /// The cartridge's IRQ line, scanline and cycle counters, the FDS timer and drive
+    pub const MAPPER: IrqSource = IrqSource(0b0000_0100);
+    /// Both of the APU's sources
+    pub const APU: IrqSource = IrqSource(Self::FRAME_COUNTER.0 | Self::DMC.0);
+    pub const ALL: IrqSource = IrqSource(Self::APU.0 | Self::MAPPER.0);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every source in `other` is in this set too
+    pub fn contains(self, other: IrqSource) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: IrqSource) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for IrqSource {
+    type Output = IrqSource;
+
+    fn bitor(self, other: IrqSource) -> IrqSource {
+        IrqSource(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for IrqSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (IrqSource::FRAME_COUNTER, "FRAME_COUNTER"),
+            (IrqSource::DMC, "DMC"),
+            (IrqSource::MAPPER, "MAPPER"),
+        ];
+        let mut set = f.debug_set();
+        for (source, name) in names {
+            if self.contains(source) {
+                set.entry(&format_args!("{}", name));
+            }
+        }
+        set.finish()
+    }
+}
+
+/// The IRQ line and who's holding it
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IrqLine {
+    asserted: IrqSource,
+}
+
+impl IrqLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn assert(&mut self, source: IrqSource) {
+        self.asserted = self.asserted | source;
+    }
+
+    pub fn acknowledge(&mut self, source: IrqSource) {
+        self.asserted.0 &= !source.0;
+    }
+
+    /// Assert or acknowledge `source` to match its level, returning whether that asserted it
+    /// when it wasn't before
+    pub fn set(&mut self, source: IrqSource, asserted: bool) -> bool {
+        let rose = asserted && !self.asserted.intersects(source);
+        if asserted {
+            self.assert(source);
+        } else {
+            self.acknowledge(source);
+        }
+        rose
+    }
+
+    /// Take the levels of the sources in `mask` from `asserted`, leaving the others be
+    pub fn update(&mut self, mask: IrqSource, asserted: IrqSource) {
+        self.asserted.0 = (self.asserted.0 & !mask.0) | (asserted.0 & mask.0);
+    }
+
+    /// Whether anything is holding the line, what the CPU checks between instructions
+    pub fn is_asserted(&self) -> bool {
+        !self.asserted.is_empty()
+    }
+
+    pub fn sources(&self) -> IrqSource {
+        self.asserted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_line_stays_up_while_anything_holds_it() {
+        let mut line = IrqLine::new();
+        assert!(!line.is_asserted());
+        line.assert(IrqSource::DMC);
+        line.assert(IrqSource::MAPPER);
+        line.acknowledge(IrqSource::DMC);
+        assert!(line.is_asserted());
+        assert_eq!(line.sources(), IrqSource::MAPPER);
+        line.acknowledge(IrqSource::MAPPER);
+        assert!(!line.is_asserted());
+    }
+
+    #[test]
+    fn sources_set_their_own_bits() {
+        let mut line = IrqLine::new();
+        assert!(line.set(IrqSource::MAPPER, true));
+        assert!(!line.set(IrqSource::MAPPER, true));
+        line.update(IrqSource::APU, IrqSource::FRAME_COUNTER);
+        assert_eq!(line.sources(), IrqSource::FRAME_COUNTER | IrqSource::MAPPER);
+        // the mapper's bit isn't the APU's to clear
+        line.update(IrqSource::APU, IrqSource::NONE);
+        assert_eq!(line.sources(), IrqSource::MAPPER);
+        assert_eq!(
+            format!("{:?}", IrqSource::ALL),
+            "{FRAME_COUNTER, DMC, MAPPER}"
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use crate::cpu::NesCpu;
+use crate::disasm::{self, Line};
+use crate::memory::{AccessKind, Bus, BusAccess};
+use crate::memory_editor::hex_lines;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// What the CPU was doing when it jammed, on a JAM opcode or one it doesn't know, for working
+// out how it got there: the registers, the code around PC, the stack, a backtrace when calls
+// are tracked, and the last instructions and bus accesses when a history is kept. The
+// emulator builds one as the CPU jams, for the embedder to take, and `save_in` writes it out
+// as text to a file named for when it happened.
+//
+// The instruction history is a ring of the last instructions run with the registers as each
+// started. Bus accesses come from the bus trace, see `Memory::enable_trace`. Both cost a
+// little on every instruction, so they're only kept when asked for.
+
+/// Instructions kept by default
+pub const HISTORY_INSTRUCTIONS: usize = 64;
+/// Bus accesses kept by default
+pub const HISTORY_ACCESSES: usize = 256;
+
+// instructions disassembled before and after PC
+const CODE_BEFORE: usize = 8;
+const CODE_AFTER: usize = 8;
+
+/// An instruction the CPU ran and the registers as it started
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Executed {
+    pub pc: u16,
+    /// The instruction and the two bytes after it, however long it is
+    pub bytes: [u8; 3],
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycle: u64,
+}
+
+impl Executed {
+    fn line(&self) -> Line {
+        // three bytes hold any instruction, so there's always a first line
+        disasm::disassemble(&self.bytes, self.pc).remove(0)
+    }
+}
+
+/// Ring buffer of the last instructions run, oldest first
+#[derive(Debug, Clone)]
+pub struct InstructionHistory {
+    entries: VecDeque<Executed>,
+    capacity: usize,
+}
+
+impl InstructionHistory {
+    pub fn new(capacity: usize) -> Self {
+        InstructionHistory {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, executed: Executed) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(executed);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Executed> {
+        self.entries.iter()
+    }
+}
+
+/// The state of the console as the CPU jammed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JamReport {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycle: u64,
+    /// Frames since power on
+    pub frame: u64,
+    /// The code around PC, the jam included
+    pub code: Vec<Line>,
+    /// What's on the stack, from SP + 1 up to $01FF
+    pub stack: Vec<u8>,
+    /// Innermost call first, when calls are tracked
+    pub backtrace: Option<String>,
+    /// Oldest first, empty unless a history was kept
+    pub instructions: Vec<Executed>,
+    /// Oldest first, empty unless the bus was traced
+    pub accesses: Vec<BusAccess>,
+}
+
+impl JamReport {
+    /// A report on `cpu` as it is now, jammed or not
+    pub fn new(cpu: &NesCpu) -> Self {
+        let pc = cpu.jammed().unwrap_or(cpu.reg.pc);
+        let sp = cpu.reg.sp();
+        let stack = (sp as u16 + 1..=0xFF)
+            .map(|offset| cpu.memory.peek_byte(0x0100 + offset))
+            .collect();
+        JamReport {
+            pc,
+            opcode: cpu.memory.peek_byte(pc),
+            a: cpu.reg.accumulator,
+            x: cpu.reg.idx,
+            y: cpu.reg.idy(),
+            p: cpu.reg.status(),
+            sp,
+            cycle: cpu.tick as u64,
+            frame: cpu.memory.ppu().frame_count(),
+            code: code_around(&cpu.memory, pc),
+            stack,
+            backtrace: cpu
+                .call_stack()
+                .map(|call_stack| call_stack.backtrace(&cpu.memory, pc)),
+            instructions: cpu
+                .history()
+                .map_or_else(Vec::new, |history| history.iter().copied().collect()),
+            accesses: cpu
+                .memory
+                .trace()
+                .map_or_else(Vec::new, |trace| trace.iter().copied().collect()),
+        }
+    }
+
+    /// `jam-YYYYMMDD-HHMMSS.txt` for now, in UTC
+    pub fn file_name() -> String {
+        let now = time::OffsetDateTime::now_utc();
+        format!(
+            "jam-{:04}{:02}{:02}-{:02}{:02}{:02}.txt",
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        )
+    }
+
+    /// Write the report to a file in `directory` named for now, returning its path
+    pub fn save_in(&self, directory: &Path) -> io::Result<PathBuf> {
+        let path = directory.join(Self::file_name());
+        fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for JamReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "CPU jammed on ${:02X} at ${:04X}, cycle {}, frame {}",
+            self.opcode, self.pc, self.cycle, self.frame
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.a, self.x, self.y, self.p, self.sp
+        )?;
+        writeln!(f)?;
+        writeln!(f, "Code")?;
+        for line in &self.code {
+            let marker = if line.address == self.pc { "-->" } else { "" };
+            writeln!(f, "{:>3} {}", marker, line)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Stack")?;
+        let origin = 0x0100 + self.sp as u16 + 1;
+        for line in hex_lines(&self.stack, origin) {
+            writeln!(f, "    {}", line)?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            writeln!(f)?;
+            writeln!(f, "Backtrace")?;
+            for line in backtrace.lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+        if !self.instructions.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Last instructions, oldest first")?;
+            for executed in &self.instructions {
+                writeln!(
+                    f,
+                    "    {:<40}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                    executed.line().to_string(),
+                    executed.a,
+                    executed.x,
+                    executed.y,
+                    executed.p,
+                    executed.sp,
+                    executed.cycle
+                )?;
+            }
+        }
+        if !self.accesses.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Last bus accesses, oldest first")?;
+            for access in &self.accesses {
+                let kind = match access.kind {
+                    AccessKind::Read => "read ",
+                    AccessKind::Write => "write",
+                };
+                writeln!(
+                    f,
+                    "    CYC:{:<10} {} ${:04X} = ${:02X}",
+                    access.cycle, kind, access.address, access.value
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Code before PC has to be found by going back and disassembling forward, taking the furthest
+// start that lands on PC. Going back over data can land on it by chance, so it's a good guess
+// rather than certain.
+fn code_around(bus: &dyn Bus, pc: u16) -> Vec<Line> {
+    let furthest = (CODE_BEFORE * 3) as u16;
+    for back in (1..=furthest).rev() {
+        let start = pc.wrapping_sub(back);
+        let lines = disasm::disassemble_memory(bus, start, CODE_BEFORE * 3);
+        if let Some(at) = lines.iter().position(|line| line.address == pc) {
+            let mut code: Vec<Line> = lines.into_iter().take(at).collect();
+            code.drain(..code.len().saturating_sub(CODE_BEFORE));
+            code.extend(disasm::disassemble_memory(bus, pc, CODE_AFTER + 1));
+            return code;
+        }
+    }
+    disasm::disassemble_memory(bus, pc, CODE_AFTER + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn code_before_pc_lines_up_with_it() {
+        let program = assemble(
+            "
+                    lda #$01
+                    sta $0300
+                    jsr $8010
+                    nop
+            ",
+        )
+        .unwrap();
+        let cpu = NesCpu::new_from_bytes(&program);
+        let code = code_around(&cpu.memory, 0x8008);
+        let at = code.iter().position(|line| line.address == 0x8008).unwrap();
+        let addresses: Vec<u16> = code[at - 3..=at].iter().map(|line| line.address).collect();
+        assert_eq!(addresses, [0x8000, 0x8002, 0x8005, 0x8008]);
+        assert_eq!(code[at].text, "NOP");
+        assert_eq!(code.len() - at, CODE_AFTER + 1);
+    }
+
+    #[test]
+    fn history_keeps_the_last_instructions() {
+        let mut history = InstructionHistory::new(2);
+        for pc in 0..3 {
+            history.push(Executed {
+                pc,
+                bytes: [0xEA, 0, 0],
+                a: 0,
+                x: 0,
+                y: 0,
+                p: 0x24,
+                sp: 0xFD,
+                cycle: pc as u64 * 2,
+            });
+        }
+        let pcs: Vec<u16> = history.iter().map(|executed| executed.pc).collect();
+        assert_eq!(pcs, [1, 2]);
+        assert_eq!(history.iter().next().unwrap().line().text, "NOP");
+    }
+}
@@ -1,103 +1,747 @@
-use std::fs::File;
+use crate::archive::extract_rom;
+use crate::database::{GameDatabase, GameEntry};
+use crate::fds::FdsDisk;
+use crate::hash::Crc32;
+use crate::header::{
+    ConsoleType, RomHeader, HEADER_SIZE, INST_ROM_SIZE, MAGIC, PROM_SIZE, TRAINER_SIZE,
+};
+use crate::mapper::Mirroring;
+use crate::region::Region;
+use md5::Md5;
+use sha1::{Digest, Sha1};
 use std::io::Read;
-use std::{fs, io};
+use std::path::Path;
+use std::{fmt, fs, io};
 
+pub use crate::emulator::Emulator;
+pub use crate::ppu::Frame;
+
+pub mod ai;
+pub mod apu;
+pub mod archive;
+pub mod assembler;
+pub mod audio;
+pub mod breakpoints;
+pub mod call_stack;
+pub mod capi;
+pub mod cheats;
+pub mod compat;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod coverage;
 pub mod cpu;
+pub mod database;
+pub mod decode_cache;
+pub mod disasm;
+pub mod emulator;
+pub mod event_viewer;
+pub mod events;
+pub mod fds;
+pub mod frame_timing;
+pub mod hash;
+pub mod header;
+pub mod input;
 pub mod instructions;
+pub mod irq;
+pub mod jam_report;
+pub mod logging;
+pub mod mapper;
 pub mod memory;
+pub mod memory_editor;
+pub mod movie;
+pub mod netplay;
+pub mod osd;
+pub mod overscan;
+pub mod pacing;
+pub mod palette;
+pub mod playtime;
+pub mod power;
 pub mod ppu;
+pub mod profiler;
+pub mod ram_search;
+pub mod recording;
+pub mod region;
+pub mod rewind;
+pub mod rng;
+pub mod rollback;
+pub mod savestate;
+pub mod scaling;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "sdl")]
 pub mod sdl;
+#[cfg(feature = "serde")]
+mod serde_helpers;
+pub mod snapshot;
+pub mod storage;
+pub mod symbols;
+pub mod test_rom;
+pub mod trace;
+pub mod unif;
+pub mod watches;
 
 #[derive(Debug)]
 pub struct NesRom {
-    header: [u8; 16], // 16 byte header, 0-3 == "NES" followed by MS-DOS EOL
-    trainer: Option<[u8; 512]>,
+    pub header: RomHeader,
+    trainer: Option<[u8; TRAINER_SIZE]>,
     pub prg_rom: Vec<[u8; 16384]>, // add x bytes extension based on header.
     pub chr_rom: Vec<[u8; 8192]>,  // add x bytes extension based on header.
-    // inst_rom: Option<[u8; 8192]>,
-    // prom: Option<[u8; 32]> // unsure
-    // todo
-    flags6: u8,
-    flags7: u8,
-    flags8: u8,
-    flags9: u8,
-    flags10: u8,
+    // PlayChoice-10 only, and often left out of dumps
+    inst_rom: Option<Box<[u8; INST_ROM_SIZE]>>,
+    prom: Option<[u8; PROM_SIZE]>,
+    crc32: u32,
+    sha1: [u8; 20],
+}
+
+/// Why a rom couldn't be loaded
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    /// Doesn't start with "NES" and an MS-DOS EOF
+    BadMagic,
+    /// Shorter than the 16 byte header
+    TruncatedHeader,
+    TruncatedTrainer,
+    /// The file is shorter than the header says it should be, sizes in bytes
+    Truncated {
+        expected: usize,
+        found: usize,
+    },
+    /// Fewer bytes of PRG ROM than the header declares
+    TruncatedPrg {
+        expected: usize,
+        found: usize,
+    },
+    TruncatedChr {
+        expected: usize,
+        found: usize,
+    },
+    UnsupportedMapper(u16),
+    /// A zip with no .nes file in it
+    NoRomInArchive,
+    /// Malformed UNIF chunks or no board name
+    BadUnif,
+    /// UNIF board without a known iNES mapper
+    UnknownBoard(String),
+    /// FDS image that isn't a whole number of disk sides
+    BadDiskImage,
+    /// FDS BIOS that isn't 8KB
+    BadBios,
+    /// A disk image where a cartridge was expected
+    DiskImage,
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::BadMagic => write!(f, "not an iNES rom"),
+            RomError::TruncatedHeader => write!(f, "rom is shorter than its header"),
+            RomError::TruncatedTrainer => write!(f, "rom ends in the trainer"),
+            RomError::Truncated { expected, found } => write!(
+                f,
+                "header declares a {} byte rom but the file is {} bytes",
+                expected, found
+            ),
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "header declares {} bytes of PRG ROM but only {} are present",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "header declares {} bytes of CHR ROM but only {} are present",
+                expected, found
+            ),
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} isn't supported", mapper),
+            RomError::NoRomInArchive => write!(f, "archive has no .nes file in it"),
+            RomError::BadUnif => write!(f, "damaged UNIF file"),
+            RomError::UnknownBoard(board) => write!(f, "UNIF board {} isn't supported", board),
+            RomError::BadDiskImage => write!(f, "not a Famicom Disk System image"),
+            RomError::BadBios => write!(f, "FDS BIOS has to be 8KB"),
+            RomError::DiskImage => write!(f, "FDS disk, load it with load_image/load_disk"),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
 }
 
 pub fn combine_bytes_to_u16(high: u8, low: u8) -> u16 {
     // Use bitwise OR to combine the bytes into a u16 value
-    let result = ((high as u16) << 8) | low as u16;
-    result
+    ((high as u16) << 8) | low as u16
 }
 
-// HEADER FLAGS
-// Byte 6
-// 76543210
-// ||||||||
-// |||||||+- Mirroring: 0: horizontal (vertical arrangement) (CIRAM A10 = PPU A11)
-// |||||||              1: vertical (horizontal arrangement) (CIRAM A10 = PPU A10)
-// ||||||+-- 1: Cartridge contains battery-backed PRG RAM ($6000-7FFF) or other persistent memory
-// |||||+--- 1: 512-byte trainer at $7000-$71FF (stored before PRG data)
-// ||||+---- 1: Ignore mirroring control or above mirroring bit; instead provide four-screen VRAM
-// ++++----- Lower nybble of mapper number
-//
-// Byte 7
-// Byte 8
-// Byte 9
-// Byte 10
-
-pub fn parse_bin_file(filename: &str) -> io::Result<NesRom> {
-    // let nes_rom = NesRom::new();
-    let mut f = File::open(filename).unwrap();
-    let metadata = fs::metadata(filename).unwrap();
-    let mut header = [0u8; 16];
-    if metadata.len() > 16 {
-        f.read_exact(&mut header)?;
-        if !header.starts_with(&[78, 69, 83, 26]) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid NES ROM file format",
-            ));
-        }
-        println!("Length of PRG_ROM: {}", header[4]);
-    }
-
-    // no trainer handled yet, check if bit is set, if it is, read trainer.
-    // let mut trainer = [0u8; 512];
-    // f.read_exact(&mut trainer)?;
-    // println!("{:?}", trainer);
-
-    /* parse prg_rom pages */
-    let prg_rom = (0..header[4])
-        .map(|_| {
-            let mut prg_rom_page = [0u8; 16384];
-            f.read_exact(&mut prg_rom_page)
-                .expect("Failed to parse file.");
-            prg_rom_page
-        })
-        .collect();
-
-    /* parse chr_rom pages */
-    let chr_rom = (0..header[5])
-        .map(|_| {
-            let mut chr_rom_page = [0u8; 8192];
-            f.read_exact(&mut chr_rom_page)
-                .expect("Failed to parse file.");
-            chr_rom_page
+impl NesRom {
+    pub fn new(
+        header: RomHeader,
+        trainer: Option<[u8; TRAINER_SIZE]>,
+        prg_rom: Vec<[u8; 16384]>,
+        chr_rom: Vec<[u8; 8192]>,
+    ) -> Self {
+        let mut crc = Crc32::new();
+        let mut sha1 = Sha1::new();
+        for bank in prg_rom
+            .iter()
+            .map(|bank| &bank[..])
+            .chain(chr_rom.iter().map(|bank| &bank[..]))
+        {
+            crc.update(bank);
+            sha1.update(bank);
+        }
+        NesRom {
+            header,
+            trainer,
+            prg_rom,
+            chr_rom,
+            inst_rom: None,
+            prom: None,
+            crc32: crc.finish(),
+            sha1: sha1.finalize().into(),
+        }
+    }
+
+    /// Boards that declare no CHR ROM banks have 8KB of CHR RAM instead
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_rom.is_empty()
+    }
+
+    /// PRG RAM size in bytes, battery backed or not. Roms with a trainer always get at least
+    /// 8KB so there's somewhere to put it.
+    pub fn prg_ram_size(&self) -> usize {
+        let size = self.header.prg_ram_size + self.header.prg_nvram_size;
+        if self.trainer.is_some() {
+            size.max(8192)
+        } else {
+            size
+        }
+    }
+
+    /// 512 bytes the cartridge maps at $7000-$71FF, mostly used by hacked copier dumps
+    pub fn trainer(&self) -> Option<&[u8; TRAINER_SIZE]> {
+        self.trainer.as_ref()
+    }
+
+    /// iNES mapper number, including the NES 2.0 high bits
+    pub fn mapper_number(&self) -> u16 {
+        self.header.mapper
+    }
+
+    /// NES 2.0 submapper, 0 for iNES roms
+    pub fn submapper(&self) -> u8 {
+        self.header.submapper
+    }
+
+    /// Nametable mirroring the board is wired for, mappers with mirroring control can change it
+    pub fn mirroring(&self) -> Mirroring {
+        self.header.mirroring
+    }
+
+    /// Battery backed PRG RAM or other memory that should be saved
+    pub fn has_battery(&self) -> bool {
+        self.header.battery
+    }
+
+    pub fn has_trainer(&self) -> bool {
+        self.trainer.is_some()
+    }
+
+    /// Attach PlayChoice-10 INST-ROM and PROM, they aren't part of the rom's hashes
+    pub fn with_playchoice_roms(
+        mut self,
+        inst_rom: Option<Box<[u8; INST_ROM_SIZE]>>,
+        prom: Option<[u8; PROM_SIZE]>,
+    ) -> Self {
+        self.inst_rom = inst_rom;
+        self.prom = prom;
+        self
+    }
+
+    /// PlayChoice-10 instruction screen data, shown by the cabinet's Z80 side
+    pub fn inst_rom(&self) -> Option<&[u8; INST_ROM_SIZE]> {
+        self.inst_rom.as_deref()
+    }
+
+    /// PlayChoice-10 PROM, 16 bytes of data then 16 bytes of CounterOut
+    pub fn prom(&self) -> Option<&[u8; PROM_SIZE]> {
+        self.prom.as_ref()
+    }
+
+    /// Remove the trainer, returning it. The rest of the rom is unchanged.
+    pub fn strip_trainer(&mut self) -> Option<[u8; TRAINER_SIZE]> {
+        self.header.trainer = false;
+        self.trainer.take()
+    }
+
+    /// CRC32 of PRG and CHR ROM, the header isn't included
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    /// SHA-1 of PRG and CHR ROM
+    pub fn sha1(&self) -> [u8; 20] {
+        self.sha1
+    }
+
+    /// MD5 of PRG and CHR ROM, what FCEUX movies identify the rom by
+    pub fn md5(&self) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        self.prg_rom.iter().for_each(|bank| md5.update(bank));
+        self.chr_rom.iter().for_each(|bank| md5.update(bank));
+        md5.finalize().into()
+    }
+
+    /// Correct the header from `database` if the rom is in it, returns the matching entry
+    pub fn apply_database<'a>(&mut self, database: &'a GameDatabase) -> Option<&'a GameEntry> {
+        let entry = database.lookup_rom(self)?;
+        let header = &mut self.header;
+        header.mapper = entry.mapper;
+        header.submapper = entry.submapper;
+        if let Some(mirroring) = entry.mirroring {
+            header.mirroring = mirroring;
+        }
+        if let Some(timing) = entry.timing {
+            header.timing = timing;
+        }
+        if entry.battery != header.battery {
+            // move the RAM over to or from the battery
+            let ram = header.prg_ram_size + header.prg_nvram_size;
+            (header.prg_ram_size, header.prg_nvram_size) =
+                if entry.battery { (0, ram) } else { (ram, 0) };
+            header.battery = entry.battery;
+        }
+        Some(entry)
+    }
+
+    /// TV system from the header, NES 2.0 byte 12 or iNES flags 9. Multi-region roms run as NTSC.
+    pub fn region(&self) -> Region {
+        self.header.region()
+    }
+
+    /// The rom as an iNES or NES 2.0 image, with a header re-encoded from `header`, so fixes
+    /// from the game database or made by hand are kept
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let prg: Vec<u8> = self.prg_rom.concat();
+        let chr: Vec<u8> = self.chr_rom.concat();
+        let mut header = self.header.clone();
+        header.trainer = self.trainer.is_some();
+        header.prg_rom_size = unpadded_size(header.prg_rom_size, prg.len(), 16384);
+        header.chr_rom_size = unpadded_size(header.chr_rom_size, chr.len(), 8192);
+
+        let mut bytes = header.to_bytes().to_vec();
+        if let Some(trainer) = &self.trainer {
+            bytes.extend_from_slice(trainer);
+        }
+        bytes.extend_from_slice(&prg[..header.prg_rom_size]);
+        bytes.extend_from_slice(&chr[..header.chr_rom_size]);
+        if let Some(inst_rom) = &self.inst_rom {
+            bytes.extend_from_slice(&inst_rom[..]);
+            if let Some(prom) = &self.prom {
+                bytes.extend_from_slice(prom);
+            }
+        }
+        bytes
+    }
+
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+// ROM that isn't a whole number of banks was zero filled by into_banks, drop the padding if
+// the header still agrees with the data
+fn unpadded_size(declared: usize, padded: usize, bank: usize) -> usize {
+    if declared <= padded && declared.saturating_add(bank) > padded {
+        declared
+    } else {
+        padded
+    }
+}
+
+/// Load a rom from disk, zipped and gzipped roms are unpacked first. Roms in the built in
+/// game database get their header corrected.
+pub fn parse_bin_file(filename: &str) -> Result<NesRom, RomError> {
+    match load_image(filename)? {
+        RomImage::Cartridge(rom) => Ok(*rom),
+        RomImage::Disk(_) => Err(RomError::DiskImage),
+    }
+}
+
+/// Anything that can be loaded and run
+#[derive(Debug)]
+pub enum RomImage {
+    /// iNES, NES 2.0 or UNIF
+    Cartridge(Box<NesRom>),
+    /// Famicom Disk System disk, needs the FDS BIOS to run
+    Disk(FdsDisk),
+}
+
+/// Load a cartridge or disk image from disk, telling them apart by their contents
+pub fn load_image(filename: &str) -> Result<RomImage, RomError> {
+    image_from_bytes(fs::read(filename)?)
+}
+
+/// `load_image` for a file already read, for frontends without a filesystem
+pub fn image_from_bytes(bytes: Vec<u8>) -> Result<RomImage, RomError> {
+    let bytes = extract_rom(bytes)?.ok_or(RomError::NoRomInArchive)?;
+    if FdsDisk::is_disk_image(&bytes) {
+        return Ok(RomImage::Disk(FdsDisk::from_bytes(&bytes)?));
+    }
+    let mut rom = NesRom::from_bytes(&bytes)?;
+    rom.apply_database(GameDatabase::builtin());
+    Ok(RomImage::Cartridge(Box::new(rom)))
+}
+
+// Reads up to `size` bytes, fewer if the reader runs out first
+fn read_up_to<R: Read>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(size as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Split into fixed size banks, zero filling the last one if it's short
+pub(crate) fn into_banks<const N: usize>(bytes: &[u8]) -> Vec<[u8; N]> {
+    bytes
+        .chunks(N)
+        .map(|chunk| {
+            let mut bank = [0u8; N];
+            bank[..chunk.len()].copy_from_slice(chunk);
+            bank
         })
-        .collect();
+        .collect()
+}
+
+impl NesRom {
+    /// Parse an iNES or NES 2.0 image from any reader, the reader is left just past CHR ROM, or
+    /// for PlayChoice-10 roms past the INST-ROM and PROM
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<NesRom, RomError> {
+        let bytes = read_up_to(&mut reader, HEADER_SIZE)?;
+        let Ok(header) = <[u8; HEADER_SIZE]>::try_from(bytes.as_slice()) else {
+            return Err(if MAGIC.starts_with(&bytes) {
+                RomError::TruncatedHeader
+            } else {
+                RomError::BadMagic
+            });
+        };
+        if !header.starts_with(&MAGIC) {
+            return Err(RomError::BadMagic);
+        }
+
+        let header = RomHeader::parse(&header);
+        let trainer = if header.trainer {
+            let bytes = read_up_to(&mut reader, TRAINER_SIZE)?;
+            Some(bytes.try_into().map_err(|_| RomError::TruncatedTrainer)?)
+        } else {
+            None
+        };
+
+        let prg = read_up_to(&mut reader, header.prg_rom_size)?;
+        if prg.len() < header.prg_rom_size {
+            return Err(RomError::TruncatedPrg {
+                expected: header.prg_rom_size,
+                found: prg.len(),
+            });
+        }
+        let chr = read_up_to(&mut reader, header.chr_rom_size)?;
+        if chr.len() < header.chr_rom_size {
+            return Err(RomError::TruncatedChr {
+                expected: header.chr_rom_size,
+                found: chr.len(),
+            });
+        }
+
+        // PlayChoice-10 roms missing either are still playable, just without instructions
+        let (inst_rom, prom) = if header.console_type == ConsoleType::Playchoice10 {
+            let inst_rom = read_up_to(&mut reader, INST_ROM_SIZE)?;
+            match inst_rom.try_into() {
+                Ok(inst_rom) => (
+                    Some(Box::new(inst_rom)),
+                    read_up_to(&mut reader, PROM_SIZE)?.try_into().ok(),
+                ),
+                Err(_) => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        Ok(
+            NesRom::new(header, trainer, into_banks(&prg), into_banks(&chr))
+                .with_playchoice_roms(inst_rom, prom),
+        )
+    }
+
+    /// Parse an iNES, NES 2.0 or UNIF image already in memory. The length is checked against
+    /// the header before anything else is read.
+    pub fn from_bytes(bytes: &[u8]) -> Result<NesRom, RomError> {
+        if unif::is_unif(bytes) {
+            return unif::parse(bytes);
+        }
+        if let Some(header) = bytes.first_chunk::<HEADER_SIZE>() {
+            if header.starts_with(&MAGIC) {
+                let expected = RomHeader::parse(header).image_size().unwrap_or(usize::MAX);
+                if bytes.len() < expected {
+                    return Err(RomError::Truncated {
+                        expected,
+                        found: bytes.len(),
+                    });
+                }
+            }
+        }
+        Self::from_reader(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        image.extend_from_slice(&[prg_banks, chr_banks, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        for bank in 0..prg_banks {
+            image.extend_from_slice(&[bank + 1; 16384]);
+        }
+        image.extend(std::iter::repeat_n(0xCC, chr_banks as usize * 8192));
+        image
+    }
+
+    #[test]
+    fn from_bytes() {
+        let rom = NesRom::from_bytes(&image(2, 1)).unwrap();
+        assert_eq!(rom.prg_rom.len(), 2);
+        assert_eq!(rom.prg_rom[1][0], 2);
+        assert_eq!(rom.chr_rom.len(), 1);
+        assert_eq!(rom.chr_rom[0][0], 0xCC);
+    }
+
+    #[test]
+    fn decoded_header_fields() {
+        let mut bytes = image(1, 1);
+        // mapper $12, submapper 3, battery, four screen, trainer
+        bytes[6] = 0x2E;
+        bytes[7] = 0x18;
+        bytes[8] = 0x30;
+        bytes.splice(HEADER_SIZE..HEADER_SIZE, [0xEA; TRAINER_SIZE]);
+        let rom = NesRom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.mapper_number(), 0x12);
+        assert_eq!(rom.submapper(), 3);
+        assert_eq!(rom.mirroring(), Mirroring::FourScreen);
+        assert!(rom.has_battery());
+        assert!(rom.has_trainer());
+        assert_eq!(rom.trainer().unwrap()[0], 0xEA);
+        assert_eq!(rom.prg_rom[0][0], 1);
+    }
+
+    #[test]
+    fn from_reader_stops_after_chr() {
+        let mut bytes = image(1, 0);
+        bytes.extend_from_slice(b"extra");
+        let mut reader = &bytes[..];
+        let rom = NesRom::from_reader(&mut reader).unwrap();
+        assert!(rom.has_chr_ram());
+        assert_eq!(reader, b"extra");
+    }
 
-    Ok(NesRom {
-        header,
-        prg_rom,
-        chr_rom,
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = image(1, 1);
+        bytes[3] = 0;
+        assert!(matches!(
+            NesRom::from_bytes(&bytes),
+            Err(RomError::BadMagic)
+        ));
+        assert!(matches!(
+            NesRom::from_bytes(b"PK\x03\x04"),
+            Err(RomError::BadMagic)
+        ));
+        assert!(matches!(
+            NesRom::from_bytes(b"NES"),
+            Err(RomError::TruncatedHeader)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_banks() {
+        let bytes = image(2, 1);
+        let prg_end = HEADER_SIZE + 2 * 16384;
+        assert!(matches!(
+            NesRom::from_reader(&bytes[..prg_end - 1]),
+            Err(RomError::TruncatedPrg {
+                expected: 32768,
+                found: 32767
+            })
+        ));
+        assert!(matches!(
+            NesRom::from_reader(&bytes[..prg_end + 100]),
+            Err(RomError::TruncatedChr {
+                expected: 8192,
+                found: 100
+            })
+        ));
+
+        let mut bytes = image(1, 0);
+        bytes[6] = 0b100;
+        assert!(matches!(
+            NesRom::from_reader(&bytes[..HEADER_SIZE + 10]),
+            Err(RomError::TruncatedTrainer)
+        ));
+    }
 
-        trainer: None,
+    #[test]
+    fn checks_file_length_first() {
+        let bytes = image(2, 1);
+        assert!(matches!(
+            NesRom::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(RomError::Truncated {
+                expected: 40976,
+                found: 40975
+            })
+        ));
 
-        flags6: header[6],
-        flags7: header[7],
-        flags8: header[8],
-        flags9: header[9],
-        flags10: header[10],
-    })
+        // a corrupt NES 2.0 exponent declaring more PRG ROM than fits in memory
+        let mut bytes = image(1, 0);
+        bytes[4] = 0xFF;
+        bytes[7] = 0x08;
+        bytes[9] = 0x0F;
+        assert!(matches!(
+            NesRom::from_bytes(&bytes),
+            Err(RomError::Truncated {
+                expected: usize::MAX,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn database_corrects_header() {
+        let mut rom = NesRom::from_bytes(&image(1, 1)).unwrap();
+        let entry = format!(
+            "{:08X} {} 1 V PAL B NES-SNROM Test Rom",
+            rom.crc32(),
+            hash::to_hex(&rom.sha1())
+        );
+        let database = GameDatabase::from_text(&entry).unwrap();
+        assert_eq!(rom.apply_database(&database).unwrap().title, "Test Rom");
+        assert_eq!(rom.mapper_number(), 1);
+        assert_eq!(rom.mirroring(), Mirroring::Vertical);
+        assert_eq!(rom.region(), Region::Pal);
+        assert!(rom.has_battery());
+        assert_eq!(rom.header.prg_nvram_size, 8192);
+
+        let mut other = NesRom::from_bytes(&image(2, 1)).unwrap();
+        assert!(other.apply_database(&database).is_none());
+        assert_eq!(other.mapper_number(), 0);
+    }
+
+    #[test]
+    fn to_bytes_round_trips() {
+        for path in ["test-bin/nestest.nes", "test-bin/cpu_dummy_reads.nes"] {
+            let bytes = fs::read(path).unwrap();
+            assert_eq!(
+                NesRom::from_bytes(&bytes).unwrap().to_bytes(),
+                bytes,
+                "{}",
+                path
+            );
+        }
+
+        let mut bytes = image(1, 1);
+        bytes[6] = 0x2E;
+        bytes[7] = 0x18;
+        bytes[8] = 0x30;
+        bytes.splice(HEADER_SIZE..HEADER_SIZE, [0xEA; TRAINER_SIZE]);
+        assert_eq!(NesRom::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn playchoice_roms() {
+        let mut bytes = image(1, 1);
+        bytes[7] = 0x02;
+        let rom = NesRom::from_bytes(&bytes).unwrap();
+        assert!(rom.inst_rom().is_none() && rom.prom().is_none());
+
+        bytes.extend_from_slice(&[0x11; INST_ROM_SIZE]);
+        let rom = NesRom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.inst_rom().unwrap()[0], 0x11);
+        assert!(rom.prom().is_none());
+
+        bytes.extend_from_slice(&[0x22; PROM_SIZE]);
+        let rom = NesRom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.prom().unwrap()[0], 0x22);
+        assert_eq!(rom.to_bytes(), bytes);
+        // not part of the rom's identity
+        assert_eq!(
+            rom.crc32(),
+            NesRom::from_bytes(&image(1, 1)).unwrap().crc32()
+        );
+    }
+
+    #[test]
+    fn strip_trainer() {
+        let mut bytes = image(1, 1);
+        bytes[6] = 0b100;
+        bytes.splice(HEADER_SIZE..HEADER_SIZE, [0xEA; TRAINER_SIZE]);
+        let mut rom = NesRom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.strip_trainer().unwrap()[0], 0xEA);
+        assert!(!rom.has_trainer());
+        assert_eq!(rom.to_bytes(), image(1, 1));
+    }
+
+    #[test]
+    fn to_bytes_keeps_database_fixes() {
+        let mut rom = NesRom::from_bytes(&image(1, 1)).unwrap();
+        rom.header.mapper = 0x1A3;
+        rom.header.mirroring = Mirroring::Vertical;
+        let fixed = NesRom::from_bytes(&rom.to_bytes()).unwrap();
+        assert!(fixed.header.is_nes2());
+        assert_eq!(fixed.mapper_number(), 0x1A3);
+        assert_eq!(fixed.mirroring(), Mirroring::Vertical);
+        assert_eq!(fixed.sha1(), rom.sha1());
+    }
+
+    #[cfg(feature = "game-db")]
+    #[test]
+    fn test_roms_are_in_builtin_database() {
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        assert_eq!(rom.crc32(), 0x158B0388);
+        let entry = GameDatabase::builtin().lookup_rom(&rom).unwrap();
+        assert_eq!(entry.title, "nestest");
+    }
+
+    #[test]
+    fn images_load_from_bytes() {
+        let bytes = fs::read("test-bin/nestest.nes").unwrap();
+        let Ok(RomImage::Cartridge(rom)) = image_from_bytes(bytes) else {
+            panic!("nestest isn't a cartridge");
+        };
+        assert_eq!(rom.crc32(), 0x158B0388);
+        assert!(matches!(
+            image_from_bytes(b"not a rom".to_vec()),
+            Err(RomError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn disk_images_are_not_cartridges() {
+        let mut disk = b"\x01*NINTENDO-HVC*".to_vec();
+        disk.resize(fds::DISK_SIDE_SIZE, 0);
+        let path = std::env::temp_dir().join(format!("nesemu-disk-{}.fds", std::process::id()));
+        fs::write(&path, disk).unwrap();
+        let result = parse_bin_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(RomError::DiskImage)));
+    }
+
+    #[test]
+    fn missing_file_is_io_error() {
+        assert!(matches!(
+            parse_bin_file("test-bin/no-such-rom.nes"),
+            Err(RomError::Io(_))
+        ));
+    }
 }
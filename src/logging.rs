@@ -0,0 +1,181 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::str::FromStr;
+
+// The library logs through the `log` crate and never prints, so whoever embeds it decides
+// where messages go, if anywhere. Each subsystem logs under its own target, below, so they can
+// be turned up or down separately. Nothing is logged per instruction or per dot above trace
+// level, and with no logger installed a log call costs a comparison.
+//
+// The binaries install `StderrLogger`, set from the NESEMU_LOG environment variable: a default
+// level and levels for subsystems, such as `warn`, `bus=debug` or `info,cpu=trace,ppu=off`.
+// Without it only warnings and errors are shown.
+
+pub const CPU: &str = "nesemu::cpu";
+pub const BUS: &str = "nesemu::bus";
+pub const PPU: &str = "nesemu::ppu";
+pub const APU: &str = "nesemu::apu";
+pub const MAPPER: &str = "nesemu::mapper";
+/// The SDL frontend
+pub const SDL: &str = "nesemu::sdl";
+
+/// The environment variable the binaries read their log levels from
+pub const LOG_ENV: &str = "NESEMU_LOG";
+
+const SUBSYSTEMS: [(&str, &str); 6] = [
+    ("cpu", CPU),
+    ("bus", BUS),
+    ("ppu", PPU),
+    ("apu", APU),
+    ("mapper", MAPPER),
+    ("sdl", SDL),
+];
+
+/// How much to log, overall and for each subsystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    // by target, the last one given for a target winning
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        LogLevels {
+            default: LevelFilter::Warn,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl LogLevels {
+    /// Parse a comma separated list of levels, a bare level setting the default and
+    /// `subsystem=level` one subsystem. Subsystems are cpu, bus, ppu, apu, mapper and sdl, and
+    /// anything else is taken as a target, such as another crate's.
+    pub fn parse(text: &str) -> Result<LogLevels, String> {
+        let mut levels = LogLevels::default();
+        for part in text
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let level = |text: &str| {
+                LevelFilter::from_str(text.trim())
+                    .map_err(|_| format!("unknown log level `{}`", text))
+            };
+            match part.split_once('=') {
+                Some((name, value)) => {
+                    let name = name.trim();
+                    let target = SUBSYSTEMS
+                        .iter()
+                        .find(|(subsystem, _)| subsystem.eq_ignore_ascii_case(name))
+                        .map_or(name, |(_, target)| target);
+                    levels.targets.push((target.to_string(), level(value)?));
+                }
+                None => levels.default = level(part)?,
+            }
+        }
+        Ok(levels)
+    }
+
+    /// The level for `target`, given by the longest target it's in
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(name, _)| {
+                target == name
+                    || target
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most that's logged anywhere, for `log::set_max_level`
+    pub fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+// Level::as_str is upper case, and lower casing it would allocate on every message
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// Writes each message to stderr as `level target: message`
+#[derive(Debug, Default)]
+pub struct StderrLogger {
+    levels: LogLevels,
+}
+
+impl StderrLogger {
+    pub fn new(levels: LogLevels) -> Self {
+        StderrLogger { levels }
+    }
+
+    /// Install a logger at `levels` as the one `log` uses, which only works once
+    pub fn install(levels: LogLevels) -> Result<(), log::SetLoggerError> {
+        let max = levels.max();
+        log::set_boxed_logger(Box::new(StderrLogger::new(levels)))?;
+        log::set_max_level(max);
+        Ok(())
+    }
+
+    /// Install a logger at the levels `LOG_ENV` gives, warnings and errors if it's not set
+    pub fn install_from_env() -> Result<(), String> {
+        let spec = std::env::var(LOG_ENV).unwrap_or_default();
+        let levels = LogLevels::parse(&spec).map_err(|e| format!("{}: {}", LOG_ENV, e))?;
+        StderrLogger::install(levels).map_err(|e| e.to_string())
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {}: {}",
+                level_name(record.level()),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_by_subsystem() {
+        let levels = LogLevels::parse("info, bus=debug,PPU=off,sdl2=trace").unwrap();
+        assert_eq!(levels.level(BUS), LevelFilter::Debug);
+        assert_eq!(levels.level(PPU), LevelFilter::Off);
+        assert_eq!(levels.level(CPU), LevelFilter::Info);
+        assert_eq!(levels.level("sdl2::video"), LevelFilter::Trace);
+        assert_eq!(levels.level("sdl2x"), LevelFilter::Info);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+
+        assert_eq!(LogLevels::parse("").unwrap(), LogLevels::default());
+        assert_eq!(
+            LogLevels::parse("cpu=trace,cpu=warn").unwrap().level(CPU),
+            LevelFilter::Warn
+        );
+        assert!(LogLevels::parse("cpu=loud").is_err());
+    }
+}
@@ -1,25 +1,1228 @@
 extern crate sdl2;
 
-use nesemu::cpu::{NesCpu, CLOCK_RATE};
-use nesemu::parse_bin_file;
-use nesemu::sdl::sdl_display;
-use std::env;
-use std::time::Duration;
+use clap::{Args, Parser, Subcommand};
+use nesemu::audio::{AudioConfig, Resampler, SampleRing};
+use nesemu::cheats::CheatList;
+use nesemu::compat::{self, ReportFormat};
+use nesemu::config::{Config, CONFIG_FILE};
+use nesemu::coverage::CoverageFormat;
+use nesemu::database::GameDatabase;
+use nesemu::disasm::{bank_origin, disassemble_bank_with_symbols, disassemble_prg_with_symbols};
+use nesemu::emulator::{Emulator, MAX_SPEED, MIN_SPEED};
+use nesemu::event_viewer;
+use nesemu::hash::to_hex;
+use nesemu::header::{ConsoleType, HeaderFormat};
+use nesemu::input::{SharedButtons, PLAYERS};
+use nesemu::jam_report::JamReport;
+use nesemu::logging::StderrLogger;
+use nesemu::mapper::PRG_BANK_SIZE;
+use nesemu::memory_editor::AddressSpace;
+use nesemu::movie::{Movie, ReplayHash};
+use nesemu::netplay::{Netplay, NetplayConfig, Session, UdpTransport};
+use nesemu::overscan::Overscan;
+use nesemu::palette::Palette;
+use nesemu::playtime::{PlayClock, PlayStats};
+use nesemu::power::PowerOnConfig;
+use nesemu::recording::{encode_png, RecordingConfig, RecordingFormat};
+use nesemu::region::Region;
+use nesemu::rewind::{RewindBuffer, RewindConfig};
+use nesemu::rollback::Rollback;
+use nesemu::scaling::ScaleMode;
+#[cfg(feature = "scripting")]
+use nesemu::script::Script;
+use nesemu::sdl::input::InputBindings;
+use nesemu::sdl::threaded::ThreadedSource;
+use nesemu::sdl::{run_frontend, FrameSource, FrontendConfig};
+use nesemu::storage::{
+    load_sram, save_sram, FileSystemStorage, GameFiles, SaveNaming, StorageBackend,
+};
+use nesemu::symbols::SymbolTable;
+use nesemu::trace::TraceLogger;
+use nesemu::watches::WatchTrigger;
+use nesemu::{load_image, NesRom, RomImage};
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const SIM_CLOCK_RATE: u32 = 1000;
+const FDS_BIOS_FILE: &str = "disksys.rom";
+const STATE_DIRECTORY: &str = "states";
+const DEFAULT_ROM: &str = "test-bin/nestest.nes";
+// chrdump draws each 8KB bank as its two pattern tables side by side, 16x16 tiles each
+const TILE_SIZE: usize = 8;
+const TABLE_TILES: usize = 16;
+const CHR_SHADES: [[u8; 3]; 4] = [[0x00; 3], [0x55; 3], [0xAA; 3], [0xFF; 3]];
+
+#[derive(Parser)]
+#[command(name = "nesemu", version, about = "NES emulator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play a rom
+    Run(RunArgs),
+    /// Show what the header and the game database say about a rom
+    Info { rom: String },
+    /// Disassemble the PRG ROM, or one 16KB bank of it
+    Disasm {
+        rom: String,
+        #[arg(long)]
+        bank: Option<usize>,
+        /// Label addresses from a ca65 .dbg, FCEUX .nl or Mesen .mlb file, can be repeated
+        #[arg(long = "symbols", value_name = "FILE")]
+        symbols: Vec<PathBuf>,
+    },
+    /// Run without a window, logging every instruction in the nestest.log format
+    Trace {
+        rom: String,
+        #[arg(long, default_value_t = 60)]
+        frames: u32,
+        /// Write the log here instead of to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Put the labels from a ca65 .dbg, FCEUX .nl or Mesen .mlb file before the
+        /// instructions they're on, can be repeated
+        #[arg(long = "symbols", value_name = "FILE")]
+        symbols: Vec<PathBuf>,
+        /// Start here instead of the reset vector, C000 runs nestest's automated mode
+        #[arg(long, value_name = "ADDRESS", value_parser = parse_address)]
+        start: Option<u16>,
+    },
+    /// Run without a window, counting where the time goes, and print a profile
+    Profile {
+        rom: String,
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+        /// How many of the hottest instructions and addresses to list
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+        /// Write the report here instead of to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Run without a window and write which parts of the PRG ROM ran
+    Coverage {
+        rom: String,
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+        /// text, the covered stretches, json, or disasm, an annotated disassembly
+        #[arg(long, value_parser = parse_coverage_format, default_value = "text")]
+        format: CoverageFormat,
+        /// Write the map here instead of to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Run without a window and list the PPU register writes and interrupts of the last frame
+    /// by scanline and dot
+    Events {
+        rom: String,
+        #[arg(long, default_value_t = 60)]
+        frames: u32,
+        /// Also draw them on a dot by scanline grid to this PNG
+        #[arg(long)]
+        png: Option<PathBuf>,
+    },
+    /// Run without a window and log the values of expressions as CSV, like `[$0075]` or `A`
+    Watch {
+        rom: String,
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+        /// Sample this at the end of every frame
+        #[arg(long = "frame", value_name = "EXPR")]
+        frame: Vec<String>,
+        /// Sample this after each write to a byte it reads
+        #[arg(long = "on-write", value_name = "EXPR")]
+        on_write: Vec<String>,
+        /// Write the log here instead of to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Run without a window and print one SHA-1 of the last frame and all the audio, for CI
+    /// and bisecting: the same build and rom always give the same hash
+    Hash {
+        rom: String,
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+    },
+    /// Run every rom in a directory for a few seconds without a window and report which boot,
+    /// draw something, crash or need a mapper that isn't there yet
+    Compat {
+        dir: PathBuf,
+        #[arg(long, default_value_t = 5)]
+        seconds: u32,
+        /// markdown, a table, or json
+        #[arg(long, value_parser = parse_report_format, default_value = "markdown")]
+        format: ReportFormat,
+        /// Write the report here instead of to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Draw the CHR ROM tiles to a PNG
+    Chrdump { rom: String, out: PathBuf },
+    /// Replay an .fm2 movie without a window and print the hash it ends on
+    VerifyMovie {
+        rom: String,
+        movie: PathBuf,
+        /// Fail unless the replay ends on this hash
+        #[arg(long, value_name = "HASH")]
+        expect_hash: Option<String>,
+        /// frame, the last frame's pixels, or state, a save state
+        #[arg(long, value_parser = parse_replay_hash, default_value = "frame")]
+        hash: ReplayHash,
+        /// Write every frame's time, lag and input here, JSON for a .json file and CSV
+        /// otherwise
+        #[arg(long, value_name = "FILE")]
+        timing: Option<PathBuf>,
+    },
+}
+
+/// Options given here override the config file
+#[derive(Args)]
+struct RunArgs {
+    /// 1 to 6, or fit
+    #[arg(long, value_parser = parse_scale)]
+    scale: Option<ScaleMode>,
+    #[arg(long)]
+    fullscreen: bool,
+    /// ntsc, pal or dendy, instead of what the rom asks for
+    #[arg(long, value_parser = parse_region)]
+    region: Option<Region>,
+    /// Emulation speed in percent of real time, 25 to 800
+    #[arg(long, value_parser = parse_speed)]
+    speed: Option<f32>,
+    /// Game Genie, Pro Action Replay or address:value cheat code, can be given more than once.
+    /// Kept for the game with --save-config.
+    #[arg(long = "cheat", value_name = "CODE")]
+    cheats: Vec<String>,
+    /// Host a two player netplay game on this UDP port, playing as player 1
+    #[arg(long, value_name = "PORT", conflicts_with = "connect")]
+    host: Option<u16>,
+    /// Join the netplay game hosted at HOST:PORT, playing as player 2
+    #[arg(long, value_name = "ADDRESS")]
+    connect: Option<String>,
+    /// Netplay input delay in frames
+    #[arg(long, default_value_t = NetplayConfig::default().delay)]
+    delay: u32,
+    /// Netplay with rollback instead of lockstep, guessing the other player's buttons up to
+    /// this many frames ahead
+    #[arg(long, value_name = "FRAMES")]
+    rollback: Option<u32>,
+    /// png or ffmpeg
+    #[arg(long, value_parser = parse_record_format)]
+    record_format: Option<RecordingFormat>,
+    /// Write the settings in use to the config file
+    #[arg(long)]
+    save_config: bool,
+    /// Run this Rhai script alongside the game. Scripts can press buttons, so not with netplay.
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["host", "connect"])]
+    script: Option<PathBuf>,
+    rom: Option<String>,
+}
+
+fn parse_scale(text: &str) -> Result<ScaleMode, String> {
+    ScaleMode::parse(text).ok_or_else(|| "expected 1-6 or fit".to_string())
+}
+
+fn parse_region(text: &str) -> Result<Region, String> {
+    Region::parse(text).ok_or_else(|| "expected ntsc, pal or dendy".to_string())
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text.trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| "expected a hex address such as C000".to_string())
+}
+
+fn parse_replay_hash(text: &str) -> Result<ReplayHash, String> {
+    ReplayHash::parse(text).ok_or_else(|| "expected frame or state".to_string())
+}
+
+fn parse_coverage_format(text: &str) -> Result<CoverageFormat, String> {
+    CoverageFormat::parse(text).ok_or_else(|| "expected text, json or disasm".to_string())
+}
+
+fn parse_report_format(text: &str) -> Result<ReportFormat, String> {
+    ReportFormat::parse(text).ok_or_else(|| "expected markdown or json".to_string())
+}
+
+fn parse_speed(text: &str) -> Result<f32, String> {
+    let percent: f32 = text
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("{} isn't a percentage", text))?;
+    let speed = percent / 100.0;
+    if (MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "speed has to be {}% to {}%",
+            MIN_SPEED * 100.0,
+            MAX_SPEED * 100.0
+        ))
+    }
+}
+
+fn parse_record_format(text: &str) -> Result<RecordingFormat, String> {
+    RecordingFormat::parse(text).ok_or_else(|| "expected png or ffmpeg".to_string())
+}
 
 pub fn main() {
-    let args: Vec<String> = env::args().collect();
-    let default = "test-bin/nestest.nes".to_string();
-    let rom_file = args.get(1).unwrap_or(&default);
-    let rom = parse_bin_file(rom_file).expect("Rom not found.");
-
-    let mut processor = NesCpu::new();
-    processor.load_rom(&rom);
-    std::thread::spawn(sdl_display);
-
-    loop {
-        processor.fetch_decode_next();
-        std::thread::sleep(Duration::new(0, 1_000_000_000u32 / SIM_CLOCK_RATE));
+    let cli = Cli::parse();
+    if let Err(e) = StderrLogger::install_from_env() {
+        eprintln!("{}", e);
+        process::exit(2)
+    }
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Info { rom } => info(&rom),
+        Command::Disasm { rom, bank, symbols } => disasm(&rom, bank, &symbols),
+        Command::Trace {
+            rom,
+            frames,
+            out,
+            symbols,
+            start,
+        } => trace(&rom, frames, out.as_deref(), &symbols, start),
+        Command::Profile {
+            rom,
+            frames,
+            top,
+            out,
+        } => profile(&rom, frames, top, out.as_deref()),
+        Command::Coverage {
+            rom,
+            frames,
+            format,
+            out,
+        } => coverage(&rom, frames, format, out.as_deref()),
+        Command::Events { rom, frames, png } => events(&rom, frames, png.as_deref()),
+        Command::Watch {
+            rom,
+            frames,
+            frame,
+            on_write,
+            out,
+        } => watch(&rom, frames, &frame, &on_write, out.as_deref()),
+        Command::Hash { rom, frames } => hash(&rom, frames),
+        Command::Compat {
+            dir,
+            seconds,
+            format,
+            out,
+        } => compat(&dir, seconds, format, out.as_deref()),
+        Command::Chrdump { rom, out } => chrdump(&rom, &out),
+        Command::VerifyMovie {
+            rom,
+            movie,
+            expect_hash,
+            hash,
+            timing,
+        } => verify_movie(
+            &rom,
+            &movie,
+            expect_hash.as_deref(),
+            hash,
+            timing.as_deref(),
+        ),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+}
+
+// settings from the config file, or the defaults when there isn't one
+fn load_config() -> Config {
+    let mut settings = if Path::new(CONFIG_FILE).exists() {
+        Config::load(CONFIG_FILE).unwrap_or_else(|e| {
+            eprintln!("using default settings: {}", e);
+            Config::default()
+        })
+    } else {
+        Config::default()
+    };
+    // written out in full so a saved config shows what can be rebound
+    if settings.input.is_empty() {
+        settings.input = InputBindings::default().to_pairs();
+    }
+    settings
+}
+
+fn run(args: RunArgs) -> Result<(), String> {
+    let mut settings = load_config();
+    if let Some(scale) = args.scale {
+        settings.video.scale = scale;
+    }
+    settings.video.fullscreen |= args.fullscreen;
+    if let Some(region) = args.region {
+        settings.region = Some(region);
+    }
+    let rom_file = settings.find_rom(args.rom.as_deref().unwrap_or(DEFAULT_ROM));
+    if !args.cheats.is_empty() {
+        let game = game_name(&rom_file.to_string_lossy());
+        let mut cheats = settings.cheats_for(&game).cloned().unwrap_or_default();
+        for code in &args.cheats {
+            cheats.add(code).map_err(|e| e.to_string())?;
+        }
+        settings.set_cheats(&game, cheats);
+    }
+    if args.save_config {
+        settings
+            .save(CONFIG_FILE)
+            .map_err(|e| format!("can't save {}: {}", CONFIG_FILE, e))?;
+    }
+    let mut recording = RecordingConfig::default();
+    if let Some(format) = args.record_format {
+        recording.format = format;
+    }
+
+    let palette = match &settings.palette {
+        Some(path) => Palette::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}, using the default palette", path.display(), e);
+            Palette::default()
+        }),
+        None => Palette::default(),
+    };
+    let bindings = InputBindings::from_pairs(&settings.input).unwrap_or_else(|e| {
+        eprintln!("using default input bindings: {}", e);
+        InputBindings::default()
+    });
+    let config = FrontendConfig {
+        audio: settings.audio,
+        video: settings.video,
+        palette,
+        recording,
+        bindings,
+        save_slot: settings.save_slot,
+        ..FrontendConfig::default()
+    };
+    let audio_config = config.audio;
+    let audio = Arc::new(Mutex::new(SampleRing::new(audio_config.ring_capacity())));
+    let buttons = Arc::new(SharedButtons::new());
+    // the console runs on a thread of its own and is made there, this one keeps the window
+    let console_audio = Arc::clone(&audio);
+    let console_buttons = Arc::clone(&buttons);
+    let mut console = ThreadedSource::spawn(move || {
+        let mut emulator = Emulator::new();
+        emulator.set_power_on(PowerOnConfig {
+            ram: settings.ram,
+            seed: settings.seed,
+            jitter: settings.jitter,
+            ..PowerOnConfig::default()
+        });
+        emulator.set_sprite_overflow_mode(settings.sprite_overflow);
+        emulator.keep_history(true);
+        let mut console = Console {
+            emulator,
+            region: settings.region,
+            audio: console_audio,
+            audio_config,
+            resampler: Resampler::new(Region::Ntsc.cpu_clock_hz(), audio_config.sample_rate),
+            buttons: console_buttons,
+            frame_audio: Vec::new(),
+            storage: FileSystemStorage::new(
+                settings
+                    .save_directory
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(STATE_DIRECTORY)),
+            ),
+            save_naming: settings.save_naming,
+            files: None,
+            game_name: String::new(),
+            rewind_config: settings.rewind,
+            rewind: RewindBuffer::new(settings.rewind, Region::Ntsc.frames_per_second()),
+            cheats: settings.cheats,
+            netplay: None,
+            clock: PlayClock::new(),
+            play_stats: PlayStats::default(),
+            #[cfg(feature = "scripting")]
+            script: None,
+        };
+        console.load_rom(&rom_file.to_string_lossy())?;
+        #[cfg(feature = "scripting")]
+        if let Some(path) = &args.script {
+            let script = Script::load(&path.to_string_lossy(), &mut console.emulator)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            console.script = Some(script);
+        }
+        let netplay = NetplayConfig {
+            delay: args.delay,
+            ..NetplayConfig::default()
+        };
+        let transport = match (args.host, &args.connect) {
+            (Some(port), _) => {
+                eprintln!("waiting for player 2 on port {}", port);
+                Some((UdpTransport::host(port), 0))
+            }
+            (None, Some(address)) => Some((UdpTransport::connect(address.as_str()), 1)),
+            (None, None) => None,
+        };
+        if let Some((transport, local_player)) = transport {
+            let transport = transport.map_err(|e| format!("netplay: {}", e))?;
+            let config = NetplayConfig {
+                local_player,
+                ..netplay
+            };
+            console.netplay = Some(match args.rollback {
+                Some(frames) => Box::new(Rollback::new(transport, config, frames)),
+                None => Box::new(Netplay::new(transport, config)),
+            });
+        }
+        if let Some(speed) = args.speed {
+            console.set_speed(speed);
+        }
+        Ok(console)
+    })?;
+    let start_slot = config.save_slot;
+    let result = run_frontend(&mut console, audio, buttons, config);
+    console.call(|console: &mut Console| console.save_game_files(console.play_stats()));
+    let slot = result?;
+    if slot != start_slot {
+        save_slot(slot);
+    }
+    Ok(())
+}
+
+// keep the last slot used for next time, leaving the rest of the config file as it is
+fn save_slot(slot: u32) {
+    let mut settings = if Path::new(CONFIG_FILE).exists() {
+        match Config::load(CONFIG_FILE) {
+            Ok(settings) => settings,
+            // don't replace a file that has mistakes in it with the defaults
+            Err(_) => return,
+        }
+    } else {
+        Config::default()
+    };
+    settings.save_slot = slot;
+    if let Err(e) = settings.save(CONFIG_FILE) {
+        eprintln!("can't save {}: {}", CONFIG_FILE, e);
+    }
+}
+
+// what save states and cheats are kept under, the rom's file name without the extension
+fn game_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map_or("game".to_string(), |stem| {
+            stem.to_string_lossy().into_owned()
+        })
+}
+
+// load a cartridge or disk, reading the FDS BIOS for disks
+fn load_into(emulator: &mut Emulator, path: &str) -> Result<(), String> {
+    let loaded = match load_image(path).map_err(|e| format!("{}: {}", path, e))? {
+        RomImage::Cartridge(rom) => emulator.load_cartridge(&rom),
+        RomImage::Disk(disk) => {
+            let bios = fs::read(FDS_BIOS_FILE)
+                .map_err(|e| format!("FDS BIOS {}: {}", FDS_BIOS_FILE, e))?;
+            emulator.load_disk(&disk, &bios)
+        }
+    };
+    loaded.map_err(|e| format!("{}: {}", path, e))
+}
+
+// the subcommands that look inside a rom only work on cartridges
+fn load_cartridge(path: &str) -> Result<NesRom, String> {
+    match load_image(path).map_err(|e| format!("{}: {}", path, e))? {
+        RomImage::Cartridge(rom) => Ok(*rom),
+        RomImage::Disk(disk) => Err(format!(
+            "{}: FDS disk image with {} sides, not a cartridge",
+            path,
+            disk.side_count()
+        )),
+    }
+}
+
+fn kilobytes(bytes: usize) -> String {
+    format!("{} KB", bytes / 1024)
+}
+
+fn info(path: &str) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let header = &rom.header;
+    let format = match header.format {
+        HeaderFormat::INes => "iNES",
+        HeaderFormat::Archaic => "iNES (archaic)",
+        HeaderFormat::Nes2 => "NES 2.0",
+    };
+    let console = match header.console_type {
+        ConsoleType::Nes => "NES/Famicom".to_string(),
+        ConsoleType::VsSystem => "VS System".to_string(),
+        ConsoleType::Playchoice10 => "PlayChoice-10".to_string(),
+        ConsoleType::Extended(kind) => format!("extended type {}", kind),
+    };
+    println!("Format:     {}", format);
+    println!("Mapper:     {}.{}", header.mapper, header.submapper);
+    println!("PRG ROM:    {}", kilobytes(header.prg_rom_size));
+    if rom.has_chr_ram() {
+        println!("CHR RAM:    {}", kilobytes(header.chr_ram_size));
+    } else {
+        println!("CHR ROM:    {}", kilobytes(header.chr_rom_size));
+    }
+    println!(
+        "PRG RAM:    {}{}",
+        kilobytes(rom.prg_ram_size()),
+        if header.battery {
+            ", battery backed"
+        } else {
+            ""
+        }
+    );
+    println!("Mirroring:  {:?}", header.mirroring);
+    println!("Console:    {}", console);
+    println!("Timing:     {:?}", header.timing);
+    println!(
+        "Trainer:    {}",
+        if rom.has_trainer() { "yes" } else { "no" }
+    );
+    println!("CRC32:      {:08X}", rom.crc32());
+    println!("SHA-1:      {}", to_hex(&rom.sha1()));
+    match GameDatabase::builtin().lookup_rom(&rom) {
+        Some(entry) => println!("Database:   {} ({})", entry.title, entry.board),
+        None => println!("Database:   not found"),
+    }
+    Ok(())
+}
+
+fn load_symbols(paths: &[PathBuf]) -> Result<SymbolTable, String> {
+    let mut symbols = SymbolTable::new();
+    for path in paths {
+        symbols
+            .load_file(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(symbols)
+}
+
+fn disasm(path: &str, bank: Option<usize>, symbol_files: &[PathBuf]) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let symbols = load_symbols(symbol_files)?;
+    let Some(bank) = bank else {
+        let banks = disassemble_prg_with_symbols(&rom, &symbols);
+        for (bank, (origin, disassembly)) in banks.iter().enumerate() {
+            println!("; bank {} at ${:04X}", bank, origin);
+            print!("{}", disassembly);
+        }
+        return Ok(());
+    };
+    let code = rom.prg_rom.get(bank).ok_or_else(|| {
+        format!(
+            "{}: no PRG bank {}, there are {}",
+            path,
+            bank,
+            rom.prg_rom.len()
+        )
+    })?;
+    let origin = bank_origin(bank, rom.prg_rom.len());
+    let disassembly = disassemble_bank_with_symbols(code, origin, bank * PRG_BANK_SIZE, &symbols);
+    print!("{}", disassembly);
+    Ok(())
+}
+
+fn trace(
+    path: &str,
+    frames: u32,
+    out: Option<&Path>,
+    symbol_files: &[PathBuf],
+    start: Option<u16>,
+) -> Result<(), String> {
+    let mut emulator = Emulator::new();
+    let tracer = match out {
+        Some(out) => {
+            let file = fs::File::create(out).map_err(|e| format!("{}: {}", out.display(), e))?;
+            TraceLogger::new(io::BufWriter::new(file))
+        }
+        None => TraceLogger::stdout(),
+    };
+    emulator.cpu_mut().set_trace_logger(Some(tracer));
+    load_into(&mut emulator, path)?;
+    if let Some(start) = start {
+        emulator.cpu_mut().set_pc(start);
+    }
+    for symbols in symbol_files {
+        emulator
+            .load_symbols(symbols)
+            .map_err(|e| format!("{}: {}", symbols.display(), e))?;
+    }
+    if let Some(tracer) = emulator.cpu_mut().trace_logger_mut() {
+        tracer.set_labels(!symbol_files.is_empty());
+    }
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+    }
+    let tracer = emulator.cpu_mut().trace_logger_mut();
+    if let Some(e) = tracer.and_then(|tracer| tracer.take_error().or(tracer.flush().err())) {
+        return Err(format!("writing the trace: {}", e));
+    }
+    Ok(())
+}
+
+fn profile(path: &str, frames: u32, top: usize, out: Option<&Path>) -> Result<(), String> {
+    let mut emulator = Emulator::new();
+    load_into(&mut emulator, path)?;
+    emulator.start_profiling();
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+    }
+    let report = emulator.profile_report(top).unwrap_or_default();
+    match out {
+        Some(out) => fs::write(out, report).map_err(|e| format!("{}: {}", out.display(), e)),
+        None => {
+            print!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+fn coverage(
+    path: &str,
+    frames: u32,
+    format: CoverageFormat,
+    out: Option<&Path>,
+) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let mut emulator = Emulator::new();
+    emulator
+        .load_cartridge(&rom)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    emulator.start_coverage();
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+    }
+    let map = emulator
+        .coverage()
+        .map(|coverage| coverage.export(format, &rom.prg_rom.concat()))
+        .unwrap_or_default();
+    match out {
+        Some(out) => fs::write(out, map).map_err(|e| format!("{}: {}", out.display(), e)),
+        None => {
+            print!("{}", map);
+            Ok(())
+        }
+    }
+}
+
+fn events(path: &str, frames: u32, png: Option<&Path>) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let mut emulator = Emulator::new();
+    emulator
+        .load_cartridge(&rom)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    emulator.start_event_log();
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+    }
+    let events = emulator.frame_events();
+    for event in events {
+        println!("{}", event);
+    }
+    match png {
+        Some(out) => {
+            let region = emulator.region();
+            let rgb = event_viewer::plot(events, region);
+            let height = region.scanlines_per_frame() as usize;
+            fs::write(out, encode_png(&rgb, event_viewer::PLOT_WIDTH, height))
+                .map_err(|e| format!("{}: {}", out.display(), e))
+        }
+        None => Ok(()),
+    }
+}
+
+fn watch(
+    path: &str,
+    frames: u32,
+    each_frame: &[String],
+    on_write: &[String],
+    out: Option<&Path>,
+) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let mut emulator = Emulator::new();
+    emulator
+        .load_cartridge(&rom)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let expressions = each_frame
+        .iter()
+        .map(|expression| (expression, WatchTrigger::Frame))
+        .chain(
+            on_write
+                .iter()
+                .map(|expression| (expression, WatchTrigger::Write)),
+        );
+    for (expression, trigger) in expressions {
+        emulator
+            .add_watch(expression, trigger)
+            .map_err(|e| format!("{}: {}", expression, e))?;
+    }
+    let writer: Box<dyn io::Write + Send> = match out {
+        Some(out) => Box::new(io::BufWriter::new(
+            fs::File::create(out).map_err(|e| format!("{}: {}", out.display(), e))?,
+        )),
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    };
+    emulator.set_watch_output(Some(writer));
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+        emulator.drain_watch_samples().for_each(drop);
+    }
+    emulator
+        .finish_watch_output()
+        .map_err(|e| format!("watch log: {}", e))
+}
+
+fn verify_movie(
+    path: &str,
+    movie_path: &Path,
+    expected: Option<&str>,
+    kind: ReplayHash,
+    timing: Option<&Path>,
+) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    let file =
+        fs::File::open(movie_path).map_err(|e| format!("{}: {}", movie_path.display(), e))?;
+    let movie = Movie::read_fm2(io::BufReader::new(file))
+        .map_err(|e| format!("{}: {}", movie_path.display(), e))?;
+    let (emulator, log) = movie
+        .replay_timed(&rom, |_, emulator| emulator.clear_audio_samples())
+        .map_err(|e| format!("{}: {}", movie_path.display(), e))?;
+    if let Some(timing) = timing {
+        let file = fs::File::create(timing).map_err(|e| format!("{}: {}", timing.display(), e))?;
+        let out = io::BufWriter::new(file);
+        if timing.extension().is_some_and(|ext| ext == "json") {
+            log.write_json(out)
+        } else {
+            log.write_csv(out)
+        }
+        .map_err(|e| format!("{}: {}", timing.display(), e))?;
+    }
+    let hash = kind.of(&emulator);
+    println!("{}", hash);
+    match expected {
+        Some(expected) if !expected.trim().eq_ignore_ascii_case(&hash) => Err(format!(
+            "hash mismatch after {} frames: expected {}, got {}",
+            movie.len(),
+            expected.trim(),
+            hash
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn hash(path: &str, frames: u32) -> Result<(), String> {
+    let mut emulator = Emulator::new();
+    emulator.set_decode_cache(true);
+    load_into(&mut emulator, path)?;
+    // the audio is hashed as it's made, the picture only at the end
+    let mut audio = Sha1::new();
+    let mut bytes = Vec::new();
+    for _ in 0..frames {
+        emulator.run_frame();
+        bytes.clear();
+        for sample in emulator.audio_samples() {
+            bytes.extend_from_slice(&sample.to_bits().to_le_bytes());
+        }
+        audio.update(&bytes);
+        emulator.clear_audio_samples();
+    }
+    let mut sha1 = Sha1::new();
+    sha1.update(emulator.frame().sha1());
+    sha1.update(audio.finalize());
+    println!("{}", to_hex(&sha1.finalize()));
+    Ok(())
+}
+
+fn compat(
+    dir: &Path,
+    seconds: u32,
+    format: ReportFormat,
+    out: Option<&Path>,
+) -> Result<(), String> {
+    // disks are only run when the BIOS is there
+    let bios = fs::read(FDS_BIOS_FILE).ok();
+    let report = compat::Report::run(dir, seconds, bios.as_deref())
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let report = report.export(format);
+    match out {
+        Some(out) => fs::write(out, report).map_err(|e| format!("{}: {}", out.display(), e)),
+        None => {
+            print!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+fn chrdump(path: &str, out: &Path) -> Result<(), String> {
+    let rom = load_cartridge(path)?;
+    if rom.chr_rom.is_empty() {
+        return Err(format!("{}: no CHR ROM, the game draws into CHR RAM", path));
+    }
+    let table_size = TABLE_TILES * TILE_SIZE;
+    let (width, height) = (table_size * 2, table_size * rom.chr_rom.len());
+    let mut rgb = vec![0u8; width * height * 3];
+    // 16 bytes a tile, the low bit plane then the high one
+    for (tile_number, tile) in rom.chr_rom.concat().chunks_exact(16).enumerate() {
+        let table = tile_number / (TABLE_TILES * TABLE_TILES);
+        let within = tile_number % (TABLE_TILES * TABLE_TILES);
+        let left = (table % 2) * table_size + (within % TABLE_TILES) * TILE_SIZE;
+        let top = (table / 2) * table_size + (within / TABLE_TILES) * TILE_SIZE;
+        for row in 0..TILE_SIZE {
+            for column in 0..TILE_SIZE {
+                let bit = 7 - column;
+                let shade = (tile[row] >> bit & 1) | (tile[row + 8] >> bit & 1) << 1;
+                let offset = ((top + row) * width + left + column) * 3;
+                rgb[offset..offset + 3].copy_from_slice(&CHR_SHADES[shade as usize]);
+            }
+        }
+    }
+    fs::write(out, encode_png(&rgb, width, height)).map_err(|e| format!("{}: {}", out.display(), e))
+}
+
+// The emulator and what it feeds: the sound card's ring buffer and the frontend's controllers
+struct Console {
+    emulator: Emulator,
+    // runs every rom as this region instead of the one it asks for
+    region: Option<Region>,
+    audio: Arc<Mutex<SampleRing>>,
+    audio_config: AudioConfig,
+    resampler: Resampler,
+    buttons: Arc<SharedButtons>,
+    // everything the APU put out this frame, for recordings
+    frame_audio: Vec<f32>,
+    // battery saves, save states, screenshots and play time
+    storage: FileSystemStorage,
+    save_naming: SaveNaming,
+    // the running game's keys in the storage
+    files: Option<GameFiles>,
+    // the rom's file name without the extension, what cheats are kept under
+    game_name: String,
+    rewind_config: RewindConfig,
+    rewind: RewindBuffer,
+    // from the config file, by game name
+    cheats: Vec<(String, CheatList)>,
+    // both consoles have to run exactly the same frames, so nothing that jumps around in time
+    // or changes the game is allowed while it's on
+    netplay: Option<Box<dyn Session>>,
+    // time played this session, and in earlier ones from the storage
+    clock: PlayClock,
+    play_stats: PlayStats,
+    #[cfg(feature = "scripting")]
+    script: Option<Script>,
+}
+
+impl Console {
+    // run a frame with the buttons the frontend has down, collecting its audio
+    fn emulate_frame(&mut self) {
+        match self.netplay.as_mut() {
+            // whichever player this side is, it's played with player 1's controls
+            Some(netplay) => match netplay.step(&mut self.emulator, self.buttons.get(0)) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    eprintln!("{}, carrying on alone", e);
+                    self.netplay = None;
+                    return;
+                }
+            },
+            None => {
+                for player in 0..PLAYERS {
+                    let held = self.buttons.get(player);
+                    #[cfg(feature = "scripting")]
+                    let held = self
+                        .script
+                        .as_ref()
+                        .map_or(held, |script| script.buttons(player, held));
+                    self.emulator.set_buttons(player, held);
+                }
+                self.emulator.advance_frame();
+                self.save_jam_report();
+                #[cfg(feature = "scripting")]
+                if let Some(script) = self.script.as_mut() {
+                    if let Err(e) = script.frame(&mut self.emulator) {
+                        eprintln!("{}, script stopped", e);
+                        self.script = None;
+                    }
+                }
+            }
+        }
+        self.flush_audio();
+        let emulator = &self.emulator;
+        self.rewind.frame(|state| emulator.save_state_into(state));
+    }
+
+    // keep the report on a jam with the save states, it's what to send with a bug report
+    fn save_jam_report(&mut self) {
+        let Some(report) = self.emulator.take_jam_report() else {
+            return;
+        };
+        let key = JamReport::file_name();
+        match self.storage.save(&key, report.to_string().as_bytes()) {
+            Ok(()) => eprintln!(
+                "CPU jammed at ${:04X}, report saved to {}",
+                report.pc,
+                self.storage.root().join(key).display()
+            ),
+            Err(e) => eprintln!("CPU jammed at ${:04X}, can't save report: {}", report.pc, e),
+        }
+    }
+
+    // resample what the APU has produced into the sound card's ring
+    fn flush_audio(&mut self) {
+        let samples = self.emulator.audio_samples();
+        let mut ring = self.audio.lock().unwrap();
+        self.resampler
+            .adjust_rate(ring.len(), self.audio_config.target_samples());
+        self.resampler.process(samples, |sample| ring.push(sample));
+        self.frame_audio.extend_from_slice(samples);
+        self.emulator.clear_audio_samples();
+    }
+
+    // the APU's samples come out `speed` times faster than the CPU clock in real time
+    fn update_audio_rate(&mut self) {
+        let clock = self.emulator.region().cpu_clock_hz() as f32;
+        self.resampler
+            .set_input_rate((clock * self.emulator.speed()).round() as u32);
+    }
+
+    fn refuse_during_netplay(&self, what: &str) -> Result<(), String> {
+        match self.netplay {
+            Some(_) => Err(format!("Can't {} during netplay", what)),
+            None => Ok(()),
+        }
+    }
+
+    // the running game's stats, this session included
+    fn play_stats(&self) -> PlayStats {
+        PlayStats {
+            played: self.play_stats.played + self.clock.elapsed(Instant::now()),
+            frames: self.play_stats.frames + self.emulator.frames_run(),
+            ..self.play_stats
+        }
+    }
+
+    // keep the running game's battery save and play time, before it's replaced or on the way
+    // out
+    fn save_game_files(&mut self, stats: PlayStats) {
+        let Some(files) = &self.files else {
+            return;
+        };
+        if let Err(e) = stats.save(&mut self.storage, files.name()) {
+            eprintln!("Can't save play time for {}: {}", files.name(), e);
+        }
+        let cartridge = self.emulator.cpu().memory.cartridge();
+        if let Some(mapper) = cartridge.filter(|_| self.emulator.has_battery()) {
+            if let Err(e) = save_sram(&mut self.storage, &files.battery(), mapper) {
+                eprintln!("Can't save {}: {}", files.battery(), e);
+            }
+        }
+    }
+
+    // name the new game's files and load its battery save
+    fn open_game_files(&mut self) {
+        let crc = self.emulator.game_crc().unwrap_or_default();
+        let files = GameFiles::claim(&mut self.storage, self.save_naming, &self.game_name, crc)
+            .unwrap_or_else(|e| {
+                eprintln!("Can't name saves after {}: {}", self.game_name, e);
+                GameFiles::by_hash(crc)
+            });
+        if self.emulator.has_battery() {
+            if let Some(mapper) = self.emulator.cpu_mut().memory.cartridge_mut() {
+                if let Err(e) = load_sram(&self.storage, &files.battery(), mapper) {
+                    eprintln!("Can't load {}: {}", files.battery(), e);
+                }
+            }
+        }
+        self.play_stats = PlayStats::load(&self.storage, files.name()).unwrap_or_else(|e| {
+            eprintln!("Can't load play time for {}: {}", files.name(), e);
+            PlayStats::default()
+        });
+        self.files = Some(files);
+    }
+
+    fn state_key(&self, slot: u32) -> Result<String, String> {
+        match &self.files {
+            Some(files) => Ok(files.state(slot)),
+            None => Err("No game loaded".to_string()),
+        }
+    }
+}
+
+impl FrameSource for Console {
+    fn run_frame(&mut self) -> &[u16] {
+        self.frame_audio.clear();
+        if !self.emulator.is_paused() {
+            self.emulate_frame();
+        }
+        self.emulator.frame().pixels()
+    }
+
+    fn advance_frame(&mut self) -> &[u16] {
+        self.frame_audio.clear();
+        self.emulate_frame();
+        self.emulator.frame().pixels()
+    }
+
+    fn frame(&self) -> &[u16] {
+        self.emulator.frame().pixels()
+    }
+
+    fn read_memory(&self, space: AddressSpace, address: u16, len: usize) -> Vec<u8> {
+        self.emulator.read_memory(space, address, len)
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if paused {
+            self.emulator.pause();
+        } else {
+            self.emulator.resume();
+        }
+        self.clock.set_running(!paused, Instant::now());
+    }
+
+    fn is_paused(&self) -> bool {
+        self.emulator.is_paused()
+    }
+
+    fn reset(&mut self) {
+        match self.refuse_during_netplay("reset") {
+            Ok(()) => self.emulator.reset(),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    fn power_cycle(&mut self) {
+        match self.refuse_during_netplay("power cycle") {
+            Ok(()) => self.emulator.power_cycle(),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    fn frame_audio(&self) -> &[f32] {
+        &self.frame_audio
+    }
+
+    fn region(&self) -> Region {
+        self.emulator.region()
+    }
+
+    fn load_rom(&mut self, path: &str) -> Result<(), String> {
+        self.refuse_during_netplay("load a rom")?;
+        self.save_game_files(self.play_stats());
+        load_into(&mut self.emulator, path)?;
+        if let Some(region) = self.region {
+            self.emulator.set_region(region);
+        }
+        // whatever the last game left in the APU is gone with it
+        self.frame_audio.clear();
+        self.update_audio_rate();
+        self.rewind = RewindBuffer::new(
+            self.rewind_config,
+            self.emulator.region().frames_per_second(),
+        );
+        self.game_name = game_name(path);
+        if let Some((_, cheats)) = self.cheats.iter().find(|(game, _)| *game == self.game_name) {
+            self.emulator.set_cheats(cheats.clone());
+        }
+        self.open_game_files();
+        self.play_stats.sessions += 1;
+        self.clock = PlayClock::new();
+        self.clock
+            .set_running(!self.emulator.is_paused(), Instant::now());
+        Ok(())
+    }
+
+    fn save_state(&mut self, slot: u32) -> Result<(), String> {
+        let key = self.state_key(slot)?;
+        self.storage
+            .save(&key, &self.emulator.save_state())
+            .map_err(|e| format!("Can't save {}: {}", key, e))
+    }
+
+    fn load_state(&mut self, slot: u32) -> Result<(), String> {
+        self.refuse_during_netplay("load a state")?;
+        let key = self.state_key(slot)?;
+        let state = self
+            .storage
+            .load(&key)
+            .map_err(|e| format!("Can't load {}: {}", key, e))?
+            .ok_or_else(|| format!("No state in slot {}", slot))?;
+        self.emulator
+            .load_state(&state)
+            .map_err(|e| format!("Can't load {}: {}", key, e))?;
+        self.frame_audio.clear();
+        Ok(())
+    }
+
+    fn rewind_frame(&mut self) -> &[u16] {
+        self.frame_audio.clear();
+        if self.netplay.is_some() {
+            return self.emulator.frame().pixels();
+        }
+        if let Some(state) = self.rewind.pop() {
+            if let Err(e) = self.emulator.load_state(&state) {
+                eprintln!("rewind: {}", e);
+                self.rewind.clear();
+            }
+        }
+        self.emulator.frame().pixels()
+    }
+
+    fn speed(&self) -> f32 {
+        self.emulator.speed()
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.emulator.set_speed(speed);
+        self.update_audio_rate();
+    }
+
+    fn ram(&self) -> &[u8] {
+        self.emulator.ram()
+    }
+
+    fn save_screenshot(&mut self, png: &[u8]) -> Result<String, String> {
+        let files = self.files.as_ref().ok_or("No game loaded")?;
+        let key = files
+            .next_screenshot(&self.storage)
+            .and_then(|key| self.storage.save(&key, png).map(|_| key))
+            .map_err(|e| format!("Can't save screenshot: {}", e))?;
+        Ok(self.storage.root().join(key).display().to_string())
+    }
+
+    fn play_time(&self) -> Duration {
+        self.play_stats().played
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.emulator.frame_count()
+    }
+
+    #[cfg(feature = "scripting")]
+    fn has_overlay(&self) -> bool {
+        self.script.is_some()
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn has_overlay(&self) -> bool {
+        false
+    }
+
+    #[cfg_attr(not(feature = "scripting"), allow(unused_variables))]
+    fn draw_overlay(&self, pixels: &mut [u8], pitch: usize, overscan: Overscan) {
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &self.script {
+            script.draw(pixels, pitch, overscan);
+        }
     }
 }
@@ -0,0 +1,420 @@
+use crate::apu::ExpansionAudio;
+use crate::fds::DiskDrive;
+#[cfg(feature = "serde")]
+use crate::fds::Fds;
+use crate::logging;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::{NesRom, RomError};
+use log::{debug, info};
+
+pub const PRG_BANK_SIZE: usize = 16384;
+pub const CHR_BANK_SIZE: usize = 8192;
+
+// https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Mirroring {
+    /// Which of the (up to four) physical nametables a logical nametable 0-3 maps to
+    pub fn physical_table(&self, table: usize) -> usize {
+        match self {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        }
+    }
+}
+
+// https://www.nesdev.org/wiki/Mapper
+// CPU side: $4020-$FFFF (PRG RAM at $6000-$7FFF, PRG ROM at $8000-$FFFF)
+// PPU side: $0000-$1FFF (pattern tables, CHR ROM or CHR RAM)
+pub trait Mapper {
+    /// Read without side effects, debuggers use this too
+    fn cpu_read(&self, address: u16) -> u8;
+    /// Read by the CPU, for registers where reading acknowledges something
+    fn cpu_read_mut(&mut self, address: u16) -> u8 {
+        self.cpu_read(address)
+    }
+    fn cpu_write(&mut self, address: u16, byte: u8);
+    /// Whether a CPU write to `address` can change what's read at $8000-$FFFF, so cached
+    /// decodes have to go. Boards with bank registers below $8000 override this.
+    fn write_changes_prg(&self, address: u16) -> bool {
+        address >= 0x8000
+    }
+    fn ppu_read(&self, address: u16) -> u8;
+    fn ppu_write(&mut self, address: u16, byte: u8);
+    fn mirroring(&self) -> Mirroring;
+
+    /// Raw pattern table memory, either CHR ROM or CHR RAM
+    fn chr(&self) -> &[u8];
+    fn has_chr_ram(&self) -> bool;
+
+    /// Bytes of PRG ROM on the board
+    fn prg_rom_size(&self) -> usize {
+        0
+    }
+
+    /// Where in PRG ROM the CPU reads `address` from with the banks mapped in now, None if
+    /// it's not PRG ROM there. For code coverage.
+    fn prg_offset(&self, _address: u16) -> Option<usize> {
+        None
+    }
+
+    /// Where in CHR the PPU reads `address` ($0000-$1FFF) from with the banks mapped in now
+    fn chr_offset(&self, _address: u16) -> Option<usize> {
+        None
+    }
+
+    /// Cartridge RAM at $6000-$7FFF, battery backed on some boards
+    fn prg_ram(&self) -> &[u8];
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    /// Sound chip on the cartridge, mixed in with the APU
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    /// Called every CPU cycle, for IRQ counters and the like
+    fn cpu_tick(&mut self) {}
+
+    /// Level of the cartridge's IRQ line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Disk drive for swapping disk sides
+    fn disk_drive(&mut self) -> Option<&mut dyn DiskDrive> {
+        None
+    }
+
+    /// Write the board's registers and RAM into a save state. Boards with anything besides
+    /// PRG RAM override both of these.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(self.prg_ram());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(self.prg_ram_mut(), "PRG RAM size")
+    }
+
+    /// The board for serde, None for boards it doesn't know
+    #[cfg(feature = "serde")]
+    fn serde_state(&self) -> Option<MapperStateRef<'_>> {
+        None
+    }
+}
+
+/// A board as serde writes it: everything on it, ROM included, so it comes back without the
+/// rom file
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+#[serde(rename = "MapperState")]
+pub enum MapperStateRef<'a> {
+    Nrom(&'a Nrom),
+    Fds(&'a Fds),
+}
+
+/// A board read back by serde, see `MapperStateRef`
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+pub enum MapperState {
+    Nrom(Nrom),
+    Fds(Fds),
+}
+
+#[cfg(feature = "serde")]
+impl MapperState {
+    pub fn into_mapper(self) -> Box<dyn Mapper> {
+        match self {
+            MapperState::Nrom(nrom) => Box::new(nrom),
+            MapperState::Fds(fds) => Box::new(fds),
+        }
+    }
+}
+
+/// Where a rom's trainer goes in the CPU address space
+pub const TRAINER_ADDRESS: u16 = 0x7000;
+
+/// Build the mapper for a parsed rom.
+// TODO - works with mapper 0 only
+pub fn from_rom(rom: &NesRom) -> Result<Box<dyn Mapper>, RomError> {
+    let mut mapper: Box<dyn Mapper> = match rom.mapper_number() {
+        0 => Box::new(Nrom::new(rom)),
+        mapper => return Err(RomError::UnsupportedMapper(mapper)),
+    };
+    info!(
+        target: logging::MAPPER,
+        "mapper {}, {}KB PRG ROM, {}KB CHR ROM",
+        rom.mapper_number(),
+        rom.prg_rom.len() * 16,
+        rom.chr_rom.len() * 8
+    );
+    if let Some(trainer) = rom.trainer() {
+        debug!(target: logging::MAPPER, "trainer copied to ${:04X}", TRAINER_ADDRESS);
+        load_trainer(mapper.as_mut(), trainer);
+    }
+    Ok(mapper)
+}
+
+// The trainer is copied into PRG RAM at power on, the game is free to overwrite it after
+fn load_trainer(mapper: &mut dyn Mapper, trainer: &[u8]) {
+    let start = (TRAINER_ADDRESS - 0x6000) as usize;
+    if let Some(ram) = mapper.prg_ram_mut().get_mut(start..start + trainer.len()) {
+        ram.copy_from_slice(trainer);
+    }
+}
+
+/// Stands in for an empty cartridge slot: reads return 0 and writes are dropped
+pub struct Unmapped;
+
+impl Mapper for Unmapped {
+    fn cpu_read(&self, _address: u16) -> u8 {
+        0
+    }
+    fn cpu_write(&mut self, _address: u16, _byte: u8) {}
+    fn ppu_read(&self, _address: u16) -> u8 {
+        0
+    }
+    fn ppu_write(&mut self, _address: u16, _byte: u8) {}
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+    fn chr(&self) -> &[u8] {
+        &[]
+    }
+    fn has_chr_ram(&self) -> bool {
+        false
+    }
+    fn prg_ram(&self) -> &[u8] {
+        &[]
+    }
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+}
+
+// https://www.nesdev.org/wiki/NROM
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: &NesRom) -> Self {
+        let prg_rom = rom.prg_rom.iter().flatten().copied().collect();
+
+        // no CHR banks in the header means the board has 8KB of CHR RAM instead
+        let chr_is_ram = rom.has_chr_ram();
+        let chr = if chr_is_ram {
+            vec![0u8; CHR_BANK_SIZE]
+        } else {
+            rom.chr_rom.iter().flatten().copied().collect()
+        };
+
+        Nrom {
+            prg_rom,
+            prg_ram: vec![0u8; rom.prg_ram_size()],
+            chr,
+            chr_is_ram,
+            mirroring: rom.mirroring(),
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(address as usize - 0x6000) % self.prg_ram.len()]
+            }
+            // NROM-128 mirrors its single bank into $C000-$FFFF
+            0x8000..=0xFFFF => self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, byte: u8) {
+        if let 0x6000..=0x7FFF = address {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address as usize - 0x6000) % len] = byte;
+            }
+        }
+    }
+
+    // no bank registers, and writes to PRG ROM are dropped
+    fn write_changes_prg(&self, _address: u16) -> bool {
+        false
+    }
+
+    fn prg_rom_size(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => Some((address as usize - 0x8000) % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr[address as usize % self.chr.len()]
+    }
+
+    fn chr_offset(&self, address: u16) -> Option<usize> {
+        Some(address as usize % self.chr.len())
+    }
+
+    fn ppu_write(&mut self, address: u16, byte: u8) {
+        // writes to CHR ROM are ignored
+        if self.chr_is_ram {
+            let len = self.chr.len();
+            self.chr[address as usize % len] = byte;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        self.chr_is_ram
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.prg_ram, "PRG RAM size")?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr, "CHR RAM size")?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn serde_state(&self) -> Option<MapperStateRef<'_>> {
+        Some(MapperStateRef::Nrom(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{RomHeader, TRAINER_SIZE};
+    use crate::storage::{load_sram, save_sram, MemoryStorage};
+    use crate::NesRom;
+
+    fn rom(prg_banks: usize, chr_banks: usize) -> NesRom {
+        let mut header = [0u8; 16];
+        header[4] = prg_banks as u8;
+        header[5] = chr_banks as u8;
+        NesRom::new(
+            RomHeader::parse(&header),
+            None,
+            (0..prg_banks)
+                .map(|bank| [bank as u8 + 1; PRG_BANK_SIZE])
+                .collect(),
+            vec![[0xCC; CHR_BANK_SIZE]; chr_banks],
+        )
+    }
+
+    #[test]
+    fn chr_ram_allocated_when_header_has_no_chr() {
+        let mut mapper = Nrom::new(&rom(1, 0));
+        assert!(mapper.has_chr_ram());
+        assert_eq!(mapper.chr().len(), CHR_BANK_SIZE);
+        mapper.ppu_write(0x1234, 0xAB);
+        assert_eq!(mapper.ppu_read(0x1234), 0xAB);
+        assert_eq!(mapper.chr()[0x1234], 0xAB);
+    }
+
+    #[test]
+    fn chr_rom_ignores_writes() {
+        let mut mapper = Nrom::new(&rom(1, 1));
+        assert!(!mapper.has_chr_ram());
+        mapper.ppu_write(0x0010, 0x00);
+        assert_eq!(mapper.ppu_read(0x0010), 0xCC);
+    }
+
+    #[test]
+    fn prg_ram_and_mirroring() {
+        let mut mapper = Nrom::new(&rom(1, 1));
+        mapper.cpu_write(0x6010, 0x42);
+        assert_eq!(mapper.cpu_read(0x6010), 0x42);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+        assert_eq!(mapper.cpu_read(0xC000), 1);
+
+        let mapper = Nrom::new(&rom(2, 1));
+        assert_eq!(mapper.cpu_read(0xC000), 2);
+    }
+
+    #[test]
+    fn unsupported_mapper() {
+        let mut rom = rom(1, 1);
+        rom.header.mapper = 4;
+        assert!(matches!(
+            from_rom(&rom),
+            Err(RomError::UnsupportedMapper(4))
+        ));
+    }
+
+    #[test]
+    fn trainer_mapped_at_7000() {
+        let mut rom = rom(1, 1);
+        let mut trainer = [0u8; TRAINER_SIZE];
+        trainer[0] = 0xAB;
+        trainer[TRAINER_SIZE - 1] = 0xCD;
+        rom.trainer = Some(trainer);
+        let mapper = from_rom(&rom).unwrap();
+        assert_eq!(mapper.cpu_read(0x6FFF), 0);
+        assert_eq!(mapper.cpu_read(0x7000), 0xAB);
+        assert_eq!(mapper.cpu_read(0x71FF), 0xCD);
+        assert_eq!(mapper.cpu_read(0x7200), 0);
+    }
+
+    #[test]
+    fn sram_round_trip() {
+        let mut storage = MemoryStorage::new();
+        let mut mapper = Nrom::new(&rom(1, 1));
+        mapper.cpu_write(0x6000, 0x12);
+        mapper.cpu_write(0x7FFF, 0x34);
+        save_sram(&mut storage, "game.sav", &mapper).unwrap();
+
+        let mut fresh = Nrom::new(&rom(1, 1));
+        assert!(load_sram(&storage, "game.sav", &mut fresh).unwrap());
+        assert_eq!(fresh.cpu_read(0x6000), 0x12);
+        assert_eq!(fresh.cpu_read(0x7FFF), 0x34);
+        assert!(!load_sram(&storage, "other.sav", &mut fresh).unwrap());
+    }
+}
@@ -1,4 +1,33 @@
+// nothing here should take down the program embedding the emulator
+#![cfg_attr(
+    not(test),
+    deny(clippy::panic, clippy::unwrap_used, clippy::expect_used)
+)]
+
+use crate::apu::{Apu, DMC_DMA_STALL_CYCLES};
+use crate::cheats::CheatList;
 use crate::combine_bytes_to_u16;
+use crate::coverage::Coverage;
+use crate::decode_cache::CACHED_START;
+use crate::event_viewer::EventLog;
+use crate::events::Event;
+use crate::fds::DiskDrive;
+use crate::input::famicom::{ExpansionDevice, EXPANSION_DATA_BITS};
+use crate::input::{Controller, FourScore, StandardController, OPEN_BUS_BITS, PORTS};
+use crate::irq::{IrqLine, IrqSource};
+use crate::logging;
+use crate::mapper::{Mapper, Unmapped};
+use crate::power::PowerOnConfig;
+use crate::ppu::{Ppu, IO_BUS_DECAY_FRAMES, OAM_SIZE};
+use crate::profiler::Profiler;
+use crate::region::Region;
+use crate::rng::Rng;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::symbols::SymbolTable;
+use crate::watches::WriteWatch;
+use log::debug;
+use std::collections::VecDeque;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::Write;
@@ -6,17 +35,44 @@ use std::io::Write;
 // https://www.nesdev.org/wiki/CPU_memory_map
 pub const ADDR_LO: u16 = 0x0000;
 pub const ADDR_HI: u16 = 0xFFFF;
-const STACK_ADDR_LO: u16 = 0x0100;
-const STACK_ADDR_HI: u16 = 0x01FF;
+pub const STACK_ADDR_LO: u16 = 0x0100;
 const MEMORY_SIZE: usize = (ADDR_HI - ADDR_LO) as usize + 1usize;
+// 2KB of internal RAM, mirrored up to everything before the PPU registers
+const RAM_SIZE: usize = 0x800;
+const RAM_END: usize = 0x2000;
+
+/// A bus operation asked for something the console doesn't have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// Only ports 0 ($4016) and 1 ($4017) exist
+    NoSuchPort(usize),
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::NoSuchPort(port) => {
+                write!(f, "no controller port {}, there are {}", port, PORTS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
 
 pub trait Bus {
-    fn read_byte(&self, address: u16) -> u8;
+    fn read_byte(&mut self, address: u16) -> u8;
     fn write_byte(&mut self, address: u16, byte: u8);
-    fn read_word(&self, address: u16) -> u16;
+    fn read_word(&mut self, address: u16) -> u16;
+    /// Read without side effects (no register reads, no trace entry), for debuggers and logging
+    fn peek_byte(&self, address: u16) -> u8;
+    /// The label for `address` with the banks mapped in now, for debuggers and logging
+    fn label(&self, _address: u16) -> Option<&str> {
+        None
+    }
     fn write_bytes(&mut self, address: u16, bytes: &[u8]) {
         bytes.iter().enumerate().for_each(|(offset, &byte)| {
-            self.write_byte(address + offset as u16, byte);
+            self.write_byte(address.wrapping_add(offset as u16), byte);
         });
     }
 }
@@ -28,9 +84,160 @@ pub trait Bus {
 //    the power on reset location ($FFFC/D)
 //    BRK/interrupt request handler ($FFFE/F)
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BusAccess {
+    pub address: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+    pub cycle: u64,
+}
+
+/// Ring buffer holding the last N bus accesses, oldest first
+#[derive(Debug, Clone)]
+pub struct BusTrace {
+    accesses: VecDeque<BusAccess>,
+    capacity: usize,
+}
+
+impl BusTrace {
+    pub fn new(capacity: usize) -> Self {
+        BusTrace {
+            accesses: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, access: BusAccess) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.accesses.len() == self.capacity {
+            self.accesses.pop_front();
+        }
+        self.accesses.push_back(access);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BusAccess> {
+        self.accesses.iter()
+    }
+
+    pub fn drain(&mut self) -> Vec<BusAccess> {
+        self.accesses.drain(..).collect()
+    }
+}
+
+// With the serde feature the bus goes through serde as the console's state: RAM, the PPU, the
+// APU and the board. The devices in the ports, the debugging tools, cheats and labels belong
+// to the frontend and are left out, a bus read back has standard controllers and none of them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Memory {
-    bytes: [u8; MEMORY_SIZE],
+    // boxed so moving a Memory (or the cpu that owns it) doesn't copy 64KB around the stack
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::boxed_array"))]
+    bytes: Box<[u8; MEMORY_SIZE]>,
+    // $4020-$FFFF is routed to the cartridge when one is inserted
+    #[cfg_attr(feature = "serde", serde(with = "serde_cartridge"))]
+    cartridge: Option<Box<dyn Mapper>>,
+    ppu: Ppu,
+    apu: Apu,
+    // devices in the $4016 and $4017 ports
+    #[cfg_attr(feature = "serde", serde(skip, default = "standard_controllers"))]
+    controllers: [Box<dyn Controller>; PORTS],
+    // Famicom expansion port device
+    #[cfg_attr(feature = "serde", serde(skip))]
+    expansion: Option<Box<dyn ExpansionDevice>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trace: Option<BusTrace>,
+    // sees every access, the CPU tells it about instructions
+    #[cfg_attr(feature = "serde", serde(skip))]
+    profiler: Option<Box<Profiler>>,
+    // PRG ROM bytes run, by offset into the cartridge's PRG ROM
+    #[cfg_attr(feature = "serde", serde(skip))]
+    coverage: Option<Box<Coverage>>,
+    // PPU register writes and interrupts by dot, for the event viewer
+    #[cfg_attr(feature = "serde", serde(skip))]
+    event_log: Option<Box<EventLog>>,
+    // the bytes on-write watches read, to note writes to
+    #[cfg_attr(feature = "serde", serde(skip))]
+    write_watch: Option<Box<WriteWatch>>,
+    // patch reads, applied after the read so compare values see the real byte
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cheats: CheatList,
+    // labels for the debugger, tracer and disassembler
+    #[cfg_attr(feature = "serde", serde(skip))]
+    symbols: SymbolTable,
+    cycle: u64,
+    // cycles the CPU is halted for by OAM and DMC DMA
+    stall_cycles: u32,
+    // leftover fraction of a PPU dot, PAL runs 3.2 dots per CPU cycle
+    dot_remainder: u32,
+    // who's holding the CPU's IRQ line, brought up to date every cycle
+    irq: IrqLine,
+    // every address plain RAM, for CPU tests
+    flat: bool,
+    // the controller ports were read since the emulator last asked, for lag frames
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input_polled: bool,
+    // moves on whenever what the CPU reads at $8000-$FFFF could change, see decode_cache
+    #[cfg_attr(feature = "serde", serde(skip, default = "first_code_generation"))]
+    code_generation: u64,
+}
+
+// through a Vec, so the 64KB is never built on the stack and copied into the box
+fn zeroed_bytes() -> Box<[u8; MEMORY_SIZE]> {
+    match vec![0u8; MEMORY_SIZE].into_boxed_slice().try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => unreachable!("a slice of MEMORY_SIZE bytes always fits"),
+    }
+}
+
+fn standard_controllers() -> [Box<dyn Controller>; PORTS] {
+    std::array::from_fn(|_| Box::new(StandardController::new()) as Box<dyn Controller>)
+}
+
+// 0 is never a generation, so the decode cache's empty entries miss
+fn first_code_generation() -> u64 {
+    1
+}
+
+// Boards serde knows go in whole, anything else (the test boards) comes back as an empty slot
+#[cfg(feature = "serde")]
+mod serde_cartridge {
+    use crate::mapper::{Mapper, MapperState};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        cartridge: &Option<Box<dyn Mapper>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        cartridge
+            .as_deref()
+            .and_then(|mapper| mapper.serde_state())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Box<dyn Mapper>>, D::Error> {
+        Ok(Option::<MapperState>::deserialize(deserializer)?.map(MapperState::into_mapper))
+    }
 }
 
 impl Default for Memory {
@@ -39,53 +246,819 @@ impl Default for Memory {
     }
 }
 impl Bus for Memory {
-    fn read_byte(&self, address: u16) -> u8 {
+    fn read_byte(&mut self, address: u16) -> u8 {
         // handle IO devices
-        match address {
-            0x2000..=0x2007 => {
-                println!("PPU Register READ (unimplemented) 0x{:x}", address);
-                0x0
+        let byte = match address {
+            _ if self.flat => self.bytes[address as usize],
+            0x2000..=0x3FFF => match self.cartridge.as_deref_mut() {
+                Some(mapper) => self.ppu.read_register(address, mapper),
+                None => self.ppu.read_register(address, &mut Unmapped),
+            },
+            0x4015 => self.apu.read_status(),
+            0x4016 | 0x4017 => {
+                let port = (address - 0x4016) as usize;
+                let expansion = match self.expansion.as_deref_mut() {
+                    Some(device) => device.read(port) & EXPANSION_DATA_BITS,
+                    None => 0,
+                };
+                self.input_polled = true;
+                OPEN_BUS_BITS | self.controllers[port].read() | expansion
             }
             0x4000..=0x401F => {
-                println!("IO PORT READ (unimplemented) 0x{:x}", address);
+                debug!(target: logging::BUS, "read from unused I/O port ${:04X}", address);
                 0x0
             }
+            0x4020..=0xFFFF => match self.cartridge.as_deref_mut() {
+                Some(mapper) => mapper.cpu_read_mut(address),
+                None => self.bytes[address as usize],
+            },
             _ => self.bytes[address as usize],
-        }
+        };
+        let byte = self.cheats.apply(address, byte);
+        self.record(address, byte, AccessKind::Read);
+        byte
     }
 
     // reads 2bytes at a time
-    fn read_word(&self, address: u16) -> u16 {
-        combine_bytes_to_u16(
-            self.bytes[(address + 1) as usize],
-            self.bytes[address as usize],
-        )
+    fn read_word(&mut self, address: u16) -> u16 {
+        let low = self.read_byte(address);
+        let high = self.read_byte(address.wrapping_add(1));
+        combine_bytes_to_u16(high, low)
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        let byte = match address {
+            _ if self.flat => self.bytes[address as usize],
+            0x2000..=0x3FFF => self
+                .ppu
+                .peek_register(address, self.cartridge().unwrap_or(&Unmapped)),
+            0x4015 => self.apu.peek_status(),
+            0x4016 | 0x4017 => {
+                let port = (address - 0x4016) as usize;
+                let expansion = self
+                    .expansion
+                    .as_deref()
+                    .map_or(0, |device| device.peek(port) & EXPANSION_DATA_BITS);
+                OPEN_BUS_BITS | self.controllers[port].peek() | expansion
+            }
+            0x4000..=0x401F => 0x0,
+            0x4020..=0xFFFF => match self.cartridge.as_deref() {
+                Some(mapper) => mapper.cpu_read(address),
+                None => self.bytes[address as usize],
+            },
+            _ => self.bytes[address as usize],
+        };
+        self.cheats.apply(address, byte)
+    }
+
+    fn label(&self, address: u16) -> Option<&str> {
+        let symbol = self.symbols.lookup(address, self.prg_offset(address))?;
+        Some(&symbol.name)
     }
 
     // handle io devices
     fn write_byte(&mut self, address: u16, byte: u8) {
+        self.record(address, byte, AccessKind::Write);
+        // a bank switch, or a write to RAM mapped where code is cached
+        let changes_code = match self.cartridge.as_deref() {
+            Some(mapper) if !self.flat => address >= 0x4020 && mapper.write_changes_prg(address),
+            _ => address >= CACHED_START,
+        };
+        if changes_code {
+            self.code_changed();
+        }
         match address {
-            0x2000..=0x2007 => {
-                println!("PPU Register WRITE (unimplemented) 0x{:x}", address);
+            _ if self.flat => self.bytes[address as usize] = byte,
+            // OAMDMA
+            0x4014 => {
+                let mut page = [0u8; OAM_SIZE];
+                let base = (byte as u16) << 8;
+                for (offset, value) in page.iter_mut().enumerate() {
+                    *value = self.read_byte(base + offset as u16);
+                }
+                self.ppu.oam_dma(&page);
+                // 513 cycles, plus one to align when started on an odd cycle
+                self.stall_cycles += 513 + (self.cycle & 1) as u32;
+            }
+            0x2000..=0x3FFF => match self.cartridge.as_deref_mut() {
+                Some(mapper) => self.ppu.write_register(address, byte, mapper),
+                None => self.ppu.write_register(address, byte, &mut Unmapped),
+            },
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(address, byte),
+            0x4016 => {
+                self.controllers
+                    .iter_mut()
+                    .for_each(|controller| controller.write_strobe(byte & 1 != 0));
+                if let Some(device) = self.expansion.as_deref_mut() {
+                    device.write(byte & 0b111);
+                }
             }
             0x4000..=0x401F => {
-                println!("IO PORT WRITE (unimplemented) 0x{:x}", address);
+                debug!(
+                    target: logging::BUS,
+                    "write of ${:02X} to unused I/O port ${:04X}", byte, address
+                );
             }
+            0x4020..=0xFFFF => match self.cartridge.as_deref_mut() {
+                Some(mapper) => mapper.cpu_write(address, byte),
+                None => self.bytes[address as usize] = byte,
+            },
             _ => self.bytes[address as usize] = byte,
         }
+        if let Some(watch) = &mut self.write_watch {
+            watch.write(address);
+        }
+        if let Some(log) = self.event_log.as_deref_mut() {
+            if matches!(address, 0x2000..=0x3FFF | 0x4014) {
+                log.register_write(address, byte, &self.ppu);
+            }
+        }
     }
 }
 
 impl Memory {
     pub fn new() -> Memory {
         Memory {
-            bytes: [0u8; MEMORY_SIZE],
+            bytes: zeroed_bytes(),
+            cartridge: None,
+            ppu: Ppu::new(),
+            apu: Apu::default(),
+            controllers: standard_controllers(),
+            expansion: None,
+            trace: None,
+            profiler: None,
+            coverage: None,
+            event_log: None,
+            write_watch: None,
+            cheats: CheatList::new(),
+            symbols: SymbolTable::new(),
+            cycle: 0,
+            stall_cycles: 0,
+            dot_remainder: 0,
+            irq: IrqLine::new(),
+            flat: false,
+            input_polled: false,
+            code_generation: first_code_generation(),
+        }
+    }
+    fn record(&mut self, address: u16, value: u8, kind: AccessKind) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.access(address, kind);
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.push(BusAccess {
+                address,
+                value,
+                kind,
+                cycle: self.cycle,
+            });
         }
     }
-    pub fn dump(&self) -> [u8; MEMORY_SIZE] {
-        self.bytes
+    /// CPU cycle stamped onto traced accesses
+    pub fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+    /// Start recording the last `capacity` bus accesses, replacing any existing trace
+    pub fn enable_trace(&mut self, capacity: usize) {
+        self.trace = Some(BusTrace::new(capacity));
+    }
+    pub fn disable_trace(&mut self) {
+        self.trace = None;
+    }
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+    pub fn trace(&self) -> Option<&BusTrace> {
+        self.trace.as_ref()
+    }
+    /// Take every recorded access, leaving the trace enabled but empty
+    pub fn drain_trace(&mut self) -> Vec<BusAccess> {
+        self.trace.as_mut().map(BusTrace::drain).unwrap_or_default()
+    }
+    /// Make every address plain RAM, with no registers or cartridge, for tests of the CPU on
+    /// its own that expect 64KB of memory
+    pub fn set_flat(&mut self, flat: bool) {
+        self.code_changed();
+        self.flat = flat;
+    }
+    /// Start profiling with `profiler`, or stop with None
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler.map(Box::new);
+    }
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_deref()
+    }
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_deref_mut()
+    }
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take().map(|profiler| *profiler)
+    }
+    /// Start recording which PRG ROM bytes run, over from nothing, or stop with false
+    pub fn set_coverage(&mut self, enabled: bool) {
+        let prg_size = self.cartridge().map_or(0, |mapper| mapper.prg_rom_size());
+        self.coverage = enabled.then(|| Box::new(Coverage::new(prg_size)));
+    }
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take().map(|coverage| *coverage)
+    }
+    /// Start logging PPU events, over from nothing, or stop with false
+    pub fn set_event_log(&mut self, enabled: bool) {
+        self.event_log = enabled.then(|| Box::new(EventLog::new()));
+    }
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.event_log.as_deref()
+    }
+    /// Note writes to `addresses` for on-write watches, or stop with none
+    pub(crate) fn watch_writes(&mut self, addresses: &[u16]) {
+        self.write_watch = (!addresses.is_empty()).then(|| Box::new(WriteWatch::new(addresses)));
+    }
+    /// The watched bytes written since the last call, empty when there are none
+    pub(crate) fn take_watched_writes(&mut self) -> Vec<u16> {
+        match &mut self.write_watch {
+            Some(watch) if watch.any_written() => watch.take_written(),
+            _ => Vec::new(),
+        }
+    }
+    /// Whether the CPU should call `instruction_fetch` before each instruction
+    pub(crate) fn watches_instructions(&self) -> bool {
+        self.profiler.is_some() || self.coverage.is_some() || self.event_log.is_some()
+    }
+    /// Whether anything needs to see the CPU read each instruction, which the decode cache
+    /// would hide
+    pub(crate) fn watches_fetches(&self) -> bool {
+        self.trace.is_some() || self.watches_instructions()
+    }
+    /// Stamps the decode cache's entries, see `decode_cache`
+    pub(crate) fn code_generation(&self) -> u64 {
+        self.code_generation
+    }
+    fn code_changed(&mut self) {
+        self.code_generation += 1;
+    }
+    /// The CPU is about to run the instruction `length` bytes long at `pc`, taking `cycles`
+    /// without page crossings or DMA
+    pub(crate) fn instruction_fetch(&mut self, pc: u16, length: u16, cycles: u32) {
+        if let Some(log) = &mut self.event_log {
+            log.instruction(pc, cycles, &self.ppu);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.fetch(pc, length);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            let offset = self
+                .cartridge
+                .as_deref()
+                .and_then(|mapper| mapper.prg_offset(pc));
+            if let Some(offset) = offset {
+                coverage.mark(offset, length as usize);
+            }
+        }
+    }
+    pub fn cheats(&self) -> &CheatList {
+        &self.cheats
+    }
+    pub fn cheats_mut(&mut self) -> &mut CheatList {
+        self.code_changed();
+        &mut self.cheats
+    }
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+    /// Where in PRG ROM `address` reads from with the banks mapped in now
+    pub fn prg_offset(&self, address: u16) -> Option<usize> {
+        self.cartridge()?.prg_offset(address)
+    }
+    pub fn insert_cartridge(&mut self, mapper: Box<dyn Mapper>) {
+        self.code_changed();
+        self.cartridge = Some(mapper);
+        self.sync_irq();
+    }
+    pub fn cartridge(&self) -> Option<&dyn Mapper> {
+        self.cartridge.as_deref()
+    }
+    pub fn cartridge_mut(&mut self) -> Option<&mut (dyn Mapper + 'static)> {
+        self.code_changed();
+        self.cartridge.as_deref_mut()
+    }
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+    /// Plug a device into port 0 ($4016) or 1 ($4017)
+    pub fn connect_controller(
+        &mut self,
+        port: usize,
+        controller: Box<dyn Controller>,
+    ) -> Result<(), BusError> {
+        *self.port_mut(port)? = controller;
+        Ok(())
+    }
+    pub fn controller_mut(&mut self, port: usize) -> Result<&mut dyn Controller, BusError> {
+        Ok(self.port_mut(port)?.as_mut())
+    }
+    fn port_mut(&mut self, port: usize) -> Result<&mut Box<dyn Controller>, BusError> {
+        self.controllers
+            .get_mut(port)
+            .ok_or(BusError::NoSuchPort(port))
+    }
+    /// Plug a device into the Famicom expansion port, or unplug it with None
+    pub fn connect_expansion(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+        self.expansion = device;
+    }
+    pub fn expansion_mut(&mut self) -> Option<&mut (dyn ExpansionDevice + 'static)> {
+        self.expansion.as_deref_mut()
+    }
+    /// Plug a Four Score into both ports for players 3 and 4
+    pub fn connect_four_score(&mut self) {
+        for (port, controller) in self.controllers.iter_mut().enumerate() {
+            *controller = Box::new(FourScore::new(port));
+        }
+    }
+    /// Buttons held by `player` (0-3), see the `input::BUTTON_*` bits. Players 3 and 4 are
+    /// ignored unless a multitap is connected, players past 4 always are.
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        if let Some(controller) = self.controllers.get_mut(player) {
+            controller.set_buttons(buttons);
+        } else if let Some(controller) = self.controllers.get_mut(player - PORTS) {
+            controller.set_tap_buttons(buttons);
+        }
+    }
+    /// Clock the rest of the system for `cpu_cycles` CPU cycles
+    pub fn tick(&mut self, cpu_cycles: u32) {
+        let (dots, per_cycles) = self.ppu.region().dots_per_cpu_cycle();
+        let total = self.dot_remainder + cpu_cycles * dots;
+        self.dot_remainder = total % per_cycles;
+
+        let mapper = self.cartridge.as_deref().unwrap_or(&Unmapped);
+        for _ in 0..total / per_cycles {
+            self.ppu.tick(mapper);
+            if let Some(log) = &mut self.event_log {
+                log.dot(&self.ppu);
+            }
+        }
+        for _ in 0..cpu_cycles {
+            if let Some(mapper) = self.cartridge.as_deref_mut() {
+                mapper.cpu_tick();
+            }
+            let expansion = self
+                .cartridge
+                .as_deref_mut()
+                .and_then(|m| m.expansion_audio());
+            self.apu.tick(expansion);
+            if let Some(address) = self.apu.dmc_dma_request() {
+                let byte = self.read_byte(address);
+                self.apu.dmc_dma_complete(byte);
+                self.stall_cycles += DMC_DMA_STALL_CYCLES;
+            }
+            if self.sync_irq() {
+                self.ppu.events_mut().push(Event::MapperIrq);
+            }
+            if let Some(log) = &mut self.event_log {
+                log.irq_line(self.irq.is_asserted(), &self.ppu);
+            }
+        }
+    }
+    // Each source sets its own bit on the line, returns whether the cartridge's went up.
+    // Changes made through `apu_mut` or `cartridge_mut` reach the line on the next cycle.
+    fn sync_irq(&mut self) -> bool {
+        self.irq.update(IrqSource::APU, self.apu.irq_sources());
+        let mapper = self.cartridge().is_some_and(|mapper| mapper.irq_pending());
+        self.irq.set(IrqSource::MAPPER, mapper)
+    }
+    /// Level of the CPU's IRQ line, which the CPU checks between instructions
+    pub fn irq_pending(&self) -> bool {
+        self.irq.is_asserted()
+    }
+    /// What's holding the IRQ line
+    pub fn irq_sources(&self) -> IrqSource {
+        self.irq.sources()
+    }
+    /// Whether $4016 or $4017 were read since the last call
+    pub fn take_input_polled(&mut self) -> bool {
+        std::mem::take(&mut self.input_polled)
+    }
+    /// The cartridge's disk drive, for FDS disk swapping
+    pub fn disk_drive(&mut self) -> Option<&mut dyn DiskDrive> {
+        self.cartridge.as_deref_mut()?.disk_drive()
+    }
+    pub fn region(&self) -> Region {
+        self.ppu.region()
+    }
+    pub fn set_region(&mut self, region: Region) {
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+        self.dot_remainder = 0;
+    }
+    /// Back to the power-on state: RAM filled as `config` says and a fresh PPU and APU. The
+    /// cartridge and whatever is plugged into the ports stay, as they would on a console.
+    pub fn power_on(&mut self, config: &PowerOnConfig) {
+        self.code_changed();
+        let region = self.region();
+        let mut rng = Rng::new(config.seed);
+        config.ram.fill(&mut self.bytes[..RAM_SIZE], &mut rng);
+        for mirror in (RAM_SIZE..RAM_END).step_by(RAM_SIZE) {
+            self.bytes.copy_within(..RAM_SIZE, mirror);
+        }
+        let mut ppu = Ppu::new();
+        ppu.events_mut().subscribe_like(self.ppu.events());
+        ppu.set_sprite_overflow_mode(self.ppu.sprite_overflow_mode());
+        self.ppu = ppu;
+        self.apu = Apu::new(region);
+        self.set_region(region);
+        if config.jitter {
+            self.jitter(&mut rng);
+        }
+        self.apu.write_register(0x4017, config.frame_counter);
+        self.cycle = 0;
+        self.stall_cycles = 0;
+        self.sync_irq();
+    }
+    // https://www.nesdev.org/wiki/PPU_frame_timing#CPU-PPU_Clock_Alignment
+    // The CPU and PPU dividers start wherever they happen to, so the PPU can be up to a CPU
+    // cycle's worth of dots ahead, and the APU on either half of its cycle
+    fn jitter(&mut self, rng: &mut Rng) {
+        let (dots, per_cycles) = self.region().dots_per_cpu_cycle();
+        let mapper = self.cartridge.as_deref().unwrap_or(&Unmapped);
+        for _ in 0..rng.below(dots.div_ceil(per_cycles)) {
+            self.ppu.tick(mapper);
+        }
+        if rng.below(2) == 1 {
+            self.apu.tick(None);
+        }
+        // ±100ms around the usual 600ms
+        let decay = IO_BUS_DECAY_FRAMES - 6 + rng.below(13) as u64;
+        self.ppu.set_io_bus_decay(decay);
+    }
+    /// The reset button: RAM, the cartridge and most of the PPU and APU are left alone, see
+    /// `Ppu::reset` and `Apu::reset`
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.stall_cycles = 0;
+        self.sync_irq();
+    }
+    pub fn take_nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.stall_cycles)
+    }
+    /// Render a full frame of the current PPU state
+    pub fn render_frame(&mut self) {
+        match self.cartridge.as_deref() {
+            Some(mapper) => self.ppu.render_frame(mapper),
+            None => self.ppu.render_frame(&Unmapped),
+        }
+    }
+    /// Read the PPU address space without side effects
+    pub fn peek_ppu(&self, address: u16) -> u8 {
+        self.ppu
+            .read(address, self.cartridge().unwrap_or(&Unmapped))
+    }
+    /// Write to the PPU address space directly, rather than through $2006/$2007
+    pub fn write_ppu(&mut self, address: u16, byte: u8) {
+        match self.cartridge.as_deref_mut() {
+            Some(mapper) => self.ppu.write(address, byte, mapper),
+            None => self.ppu.write(address, byte, &mut Unmapped),
+        }
+    }
+    /// The 2KB of internal RAM at $0000-$07FF, as it is, without cheats applied
+    pub fn ram(&self) -> &[u8] {
+        &self.bytes[..RAM_SIZE]
+    }
+    pub fn dump(&self) -> &[u8; MEMORY_SIZE] {
+        &self.bytes
     }
     pub fn dump_to_file(&self, filename: &str) -> Result<(), io::Error> {
-        File::create(filename)?.write_all(&self.bytes)
+        File::create(filename)?.write_all(self.bytes.as_slice())
+    }
+}
+
+// What's plugged into the ports is the frontend's business, only the devices' state goes in.
+// Each port's state is a block of its own so a device that saves nothing still lines up.
+impl SaveState for Memory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(self.region().name().as_bytes());
+        // without a cartridge the whole address space is plain memory, test programs run there
+        let end = if self.cartridge.is_some() {
+            RAM_END
+        } else {
+            MEMORY_SIZE
+        };
+        state.bytes(&self.bytes[..end]);
+        state.u64(self.cycle);
+        state.u32(self.stall_cycles);
+        state.u32(self.dot_remainder);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        if let Some(mapper) = self.cartridge() {
+            mapper.save_state(state);
+        }
+        for controller in &self.controllers {
+            state.block(|port| controller.save_state(port));
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.code_changed();
+        let region = std::str::from_utf8(state.bytes()?)
+            .ok()
+            .and_then(Region::parse)
+            .ok_or(StateError::Invalid("region"))?;
+        self.set_region(region);
+        let end = if self.cartridge.is_some() {
+            RAM_END
+        } else {
+            MEMORY_SIZE
+        };
+        state.bytes_into(&mut self.bytes[..end], "RAM size")?;
+        self.cycle = state.u64()?;
+        self.stall_cycles = state.u32()?;
+        self.dot_remainder = state.u32()?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        if let Some(mapper) = self.cartridge_mut() {
+            mapper.load_state(state)?;
+        }
+        for controller in self.controllers.iter_mut() {
+            let mut port = StateReader::new(state.bytes()?);
+            controller.load_state(&mut port)?;
+            port.finish()?;
+        }
+        self.sync_irq();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backing_store_is_not_inline() {
+        assert!(std::mem::size_of::<Memory>() < MEMORY_SIZE / 8);
+        assert_eq!(Memory::new().dump().len(), MEMORY_SIZE);
+    }
+
+    #[test]
+    fn oam_dma_copies_page() {
+        let mut memory = Memory::new();
+        for offset in 0..OAM_SIZE as u16 {
+            memory.write_byte(0x0200 + offset, offset as u8 ^ 0xFF);
+        }
+        memory.write_byte(0x4014, 0x02);
+        assert_eq!(memory.ppu().oam()[0], 0xFF);
+        assert_eq!(memory.ppu().oam()[0xFF], 0x00);
+        assert_eq!(memory.take_stall_cycles(), 513);
+        assert_eq!(memory.take_stall_cycles(), 0);
+    }
+
+    #[test]
+    fn flat_memory_has_no_registers() {
+        let mut memory = Memory::new();
+        memory.set_flat(true);
+        for address in [0x2000, 0x4014, 0x4016, 0xFFFF] {
+            memory.write_byte(address, 0x5A);
+            assert_eq!(memory.read_byte(address), 0x5A);
+            assert_eq!(memory.peek_byte(address), 0x5A);
+        }
+        assert_eq!(memory.take_stall_cycles(), 0);
+    }
+
+    #[test]
+    fn controller_ports() {
+        use crate::input::{BUTTON_A, BUTTON_B};
+        let mut memory = Memory::new();
+        memory.set_buttons(0, BUTTON_B);
+        memory.set_buttons(1, BUTTON_A);
+        memory.write_byte(0x4016, 1);
+        memory.write_byte(0x4016, 0);
+        assert_eq!(memory.read_byte(0x4016), 0x40);
+        assert_eq!(memory.read_byte(0x4016), 0x41);
+        assert_eq!(memory.peek_byte(0x4017), 0x41);
+        assert_eq!(memory.read_byte(0x4017), 0x41);
+        assert_eq!(memory.read_byte(0x4017), 0x40);
+        assert_eq!(
+            memory
+                .connect_controller(2, Box::new(StandardController::new()))
+                .unwrap_err(),
+            BusError::NoSuchPort(2)
+        );
+        assert!(memory.controller_mut(1).is_ok());
+    }
+
+    #[test]
+    fn expansion_port_shares_reads_with_pads() {
+        use crate::input::famicom::VausPaddle;
+        use crate::input::BUTTON_A;
+        let mut memory = Memory::new();
+        let mut paddle = VausPaddle::new();
+        paddle.set_fire(true);
+        memory.connect_expansion(Some(Box::new(paddle)));
+        memory.set_buttons(0, BUTTON_A);
+        memory.write_byte(0x4016, 1);
+        memory.write_byte(0x4016, 0);
+        assert_eq!(memory.peek_byte(0x4016), 0x43);
+        assert_eq!(memory.read_byte(0x4016), 0x43);
+        assert_eq!(memory.read_byte(0x4016), 0x42);
+    }
+
+    #[test]
+    fn four_score_players() {
+        use crate::input::BUTTON_START;
+        let mut memory = Memory::new();
+        memory.connect_four_score();
+        memory.set_buttons(3, BUTTON_START);
+        memory.write_byte(0x4016, 1);
+        memory.write_byte(0x4016, 0);
+        let bits: Vec<u8> = (0..24).map(|_| memory.read_byte(0x4017) & 1).collect();
+        // player 4's start is the 12th bit, then the $4017 signature
+        assert_eq!(bits[11], 1);
+        assert_eq!(bits[16..], [0, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(bits.iter().filter(|&&bit| bit == 1).count(), 2);
+    }
+
+    #[test]
+    fn players_past_four_are_ignored() {
+        let mut memory = Memory::new();
+        memory.connect_four_score();
+        memory.set_buttons(4, 0xFF);
+        memory.set_buttons(5, 0xFF);
+        memory.write_byte(0x4016, 1);
+        memory.write_byte(0x4016, 0);
+        for port in [0x4016, 0x4017] {
+            let bits: Vec<u8> = (0..16).map(|_| memory.read_byte(port) & 1).collect();
+            assert_eq!(bits, [0; 16]);
+        }
+    }
+
+    #[test]
+    fn trace_disabled_by_default() {
+        let mut memory = Memory::new();
+        memory.write_byte(0x10, 0x20);
+        assert!(!memory.is_tracing());
+        assert!(memory.drain_trace().is_empty());
+    }
+
+    #[test]
+    fn trace_records_reads_and_writes() {
+        let mut memory = Memory::new();
+        memory.enable_trace(8);
+        memory.set_cycle(7);
+        memory.write_byte(0x10, 0x20);
+        memory.set_cycle(9);
+        assert_eq!(memory.read_byte(0x10), 0x20);
+        assert_eq!(memory.peek_byte(0x10), 0x20);
+
+        let accesses = memory.drain_trace();
+        assert_eq!(
+            accesses,
+            vec![
+                BusAccess {
+                    address: 0x10,
+                    value: 0x20,
+                    kind: AccessKind::Write,
+                    cycle: 7
+                },
+                BusAccess {
+                    address: 0x10,
+                    value: 0x20,
+                    kind: AccessKind::Read,
+                    cycle: 9
+                },
+            ]
+        );
+        assert!(memory.is_tracing());
+        assert!(memory.drain_trace().is_empty());
+    }
+
+    #[test]
+    fn trace_keeps_last_n() {
+        let mut memory = Memory::new();
+        memory.enable_trace(2);
+        for address in 0..5 {
+            memory.write_byte(address, address as u8);
+        }
+        let addresses: Vec<u16> = memory.drain_trace().iter().map(|a| a.address).collect();
+        assert_eq!(addresses, vec![3, 4]);
+    }
+
+    #[test]
+    fn pal_runs_sixteen_dots_per_five_cycles() {
+        let mut memory = Memory::new();
+        memory.set_region(Region::Pal);
+        memory.tick(1);
+        assert_eq!(memory.ppu().dot(), 3);
+        memory.tick(4);
+        assert_eq!(memory.ppu().dot(), 16);
+    }
+
+    // raises IRQ after 10 CPU cycles, any write acknowledges and restarts it
+    struct IrqTimer {
+        count: u32,
+    }
+
+    impl Mapper for IrqTimer {
+        fn cpu_read(&self, _address: u16) -> u8 {
+            0
+        }
+        fn cpu_write(&mut self, _address: u16, _byte: u8) {
+            self.count = 0;
+        }
+        fn ppu_read(&self, _address: u16) -> u8 {
+            0
+        }
+        fn ppu_write(&mut self, _address: u16, _byte: u8) {}
+        fn mirroring(&self) -> crate::mapper::Mirroring {
+            crate::mapper::Mirroring::Horizontal
+        }
+        fn chr(&self) -> &[u8] {
+            &[]
+        }
+        fn has_chr_ram(&self) -> bool {
+            false
+        }
+        fn prg_ram(&self) -> &[u8] {
+            &[]
+        }
+        fn prg_ram_mut(&mut self) -> &mut [u8] {
+            &mut []
+        }
+        fn cpu_tick(&mut self) {
+            self.count += 1;
+        }
+        fn irq_pending(&self) -> bool {
+            self.count >= 10
+        }
+    }
+
+    #[test]
+    fn mapper_irqs_are_queued_once_per_edge() {
+        use crate::events::{Event, EventKind};
+        let mut memory = Memory::new();
+        memory.insert_cartridge(Box::new(IrqTimer { count: 0 }));
+        memory
+            .ppu_mut()
+            .events_mut()
+            .subscribe(EventKind::MapperIrq);
+        memory.tick(12);
+        memory.tick(2);
+        memory.write_byte(0x8000, 0);
+        memory.tick(12);
+        let events: Vec<_> = memory.ppu_mut().events_mut().drain().collect();
+        assert_eq!(events, [Event::MapperIrq, Event::MapperIrq]);
+    }
+
+    #[test]
+    fn only_writes_that_reach_cached_code_invalidate_it() {
+        let mut memory = Memory::new();
+        let generation = memory.code_generation();
+        memory.write_byte(0x8000, 0);
+        assert_ne!(memory.code_generation(), generation);
+
+        memory.insert_cartridge(Box::new(IrqTimer { count: 0 }));
+        let generation = memory.code_generation();
+        memory.write_byte(0x0200, 0);
+        memory.write_byte(0x4020, 0);
+        memory.write_byte(0x6000, 0);
+        memory.write_byte(0x7FFF, 0);
+        assert_eq!(memory.code_generation(), generation);
+        memory.write_byte(0x8000, 0);
+        assert_ne!(memory.code_generation(), generation);
+    }
+
+    #[test]
+    fn irq_sources_are_acknowledged_separately() {
+        let mut memory = Memory::new();
+        memory.insert_cartridge(Box::new(IrqTimer { count: 0 }));
+        // the frame counter's IRQ comes at the end of the 4 step sequence
+        memory.tick(30_000);
+        assert_eq!(
+            memory.irq_sources(),
+            IrqSource::FRAME_COUNTER | IrqSource::MAPPER
+        );
+        memory.read_byte(0x4015);
+        memory.tick(1);
+        assert_eq!(memory.irq_sources(), IrqSource::MAPPER);
+        memory.write_byte(0x8000, 0);
+        memory.tick(1);
+        assert!(!memory.irq_pending());
+        memory.tick(10);
+        assert_eq!(memory.irq_sources(), IrqSource::MAPPER);
     }
 }
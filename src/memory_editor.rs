@@ -0,0 +1,223 @@
+use crate::memory::{Bus, Memory};
+use std::fmt;
+
+// Reading and editing the console's memory for debuggers and the hex view, paused or running.
+// Both address spaces are covered: the CPU's, where reads are peeks with no side effects and
+// writes go over the bus as the CPU's would (so a write to a PPU or mapper register does what
+// it does for the game), and the PPU's, pattern tables, nametables and palettes, read and
+// written directly.
+//
+// A frozen address is written with its value at the start of every frame, so the game can
+// change it during the frame but always starts the next one from the frozen value. Unlike a
+// cheat, which only patches what the CPU reads, the memory itself holds the value. Freezes
+// belong to the game like cheats do, and loading another game clears them.
+
+/// Bytes per line of `hex_lines`
+pub const BYTES_PER_LINE: usize = 8;
+
+/// Which memory an address is in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum AddressSpace {
+    /// The CPU's 64KB: RAM, registers, the cartridge
+    #[default]
+    Cpu,
+    /// The PPU's 16KB: pattern tables, nametables and palettes
+    Ppu,
+}
+
+impl AddressSpace {
+    pub fn name(self) -> &'static str {
+        match self {
+            AddressSpace::Cpu => "CPU",
+            AddressSpace::Ppu => "PPU",
+        }
+    }
+
+    /// Addresses past this wrap around
+    pub fn last_address(self) -> u16 {
+        match self {
+            AddressSpace::Cpu => 0xFFFF,
+            AddressSpace::Ppu => 0x3FFF,
+        }
+    }
+}
+
+impl fmt::Display for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// `address` in `space` held at `value`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Freeze {
+    pub space: AddressSpace,
+    pub address: u16,
+    pub value: u8,
+}
+
+/// The frozen addresses, one value per address
+#[derive(Debug, Clone, Default)]
+pub struct Freezes {
+    freezes: Vec<Freeze>,
+}
+
+impl Freezes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold `address` at `value`, replacing what it was frozen at before
+    pub fn add(&mut self, space: AddressSpace, address: u16, value: u8) {
+        let address = address & space.last_address();
+        match self.get_mut(space, address) {
+            Some(freeze) => freeze.value = value,
+            None => self.freezes.push(Freeze {
+                space,
+                address,
+                value,
+            }),
+        }
+    }
+
+    /// Let `address` go, returns false if it wasn't frozen
+    pub fn remove(&mut self, space: AddressSpace, address: u16) -> bool {
+        let address = address & space.last_address();
+        let before = self.freezes.len();
+        self.freezes
+            .retain(|freeze| (freeze.space, freeze.address) != (space, address));
+        self.freezes.len() != before
+    }
+
+    /// What `address` is frozen at
+    pub fn get(&self, space: AddressSpace, address: u16) -> Option<u8> {
+        let address = address & space.last_address();
+        self.freezes
+            .iter()
+            .find(|freeze| (freeze.space, freeze.address) == (space, address))
+            .map(|freeze| freeze.value)
+    }
+
+    fn get_mut(&mut self, space: AddressSpace, address: u16) -> Option<&mut Freeze> {
+        self.freezes
+            .iter_mut()
+            .find(|freeze| (freeze.space, freeze.address) == (space, address))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Freeze> {
+        self.freezes.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.freezes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.freezes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.freezes.is_empty()
+    }
+
+    /// Write every frozen value into `memory`
+    pub(crate) fn apply(&self, memory: &mut Memory) {
+        for freeze in &self.freezes {
+            poke(memory, freeze.space, freeze.address, freeze.value);
+        }
+    }
+}
+
+/// The byte at `address` in `space`, without side effects
+pub fn peek(memory: &Memory, space: AddressSpace, address: u16) -> u8 {
+    match space {
+        AddressSpace::Cpu => memory.peek_byte(address),
+        AddressSpace::Ppu => memory.peek_ppu(address),
+    }
+}
+
+/// Write `value` to `address` in `space`, over the bus for the CPU's
+pub fn poke(memory: &mut Memory, space: AddressSpace, address: u16, value: u8) {
+    match space {
+        AddressSpace::Cpu => memory.write_byte(address, value),
+        AddressSpace::Ppu => memory.write_ppu(address, value),
+    }
+}
+
+/// `len` bytes from `address`, wrapping at the end of the space
+pub fn read_range(memory: &Memory, space: AddressSpace, address: u16, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|offset| {
+            let at = address.wrapping_add(offset as u16) & space.last_address();
+            peek(memory, space, at)
+        })
+        .collect()
+}
+
+/// Write `bytes` from `address` on, wrapping at the end of the space
+pub fn write_range(memory: &mut Memory, space: AddressSpace, address: u16, bytes: &[u8]) {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        let at = address.wrapping_add(offset as u16) & space.last_address();
+        poke(memory, space, at, byte);
+    }
+}
+
+/// `bytes` read from `origin` as hex dump lines, `BYTES_PER_LINE` to a line:
+///
+///   0300 A9 00 8D 00 20 A2 FF 9A
+pub fn hex_lines(bytes: &[u8], origin: u16) -> Vec<String> {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let address = origin.wrapping_add((line * BYTES_PER_LINE) as u16);
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:04X} {}", address, hex.join(" "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freezes_keep_one_value_per_address() {
+        let mut freezes = Freezes::new();
+        freezes.add(AddressSpace::Cpu, 0x0075, 3);
+        freezes.add(AddressSpace::Ppu, 0x0075, 4);
+        freezes.add(AddressSpace::Cpu, 0x0075, 9);
+        // the PPU's space is 16KB, mirrored above
+        freezes.add(AddressSpace::Ppu, 0x7F00, 0x0F);
+        assert_eq!(freezes.len(), 3);
+        assert_eq!(freezes.get(AddressSpace::Cpu, 0x0075), Some(9));
+        assert_eq!(freezes.get(AddressSpace::Ppu, 0x0075), Some(4));
+        assert_eq!(freezes.get(AddressSpace::Ppu, 0x3F00), Some(0x0F));
+        assert!(freezes.remove(AddressSpace::Cpu, 0x0075));
+        assert!(!freezes.remove(AddressSpace::Cpu, 0x0075));
+        assert_eq!(freezes.get(AddressSpace::Cpu, 0x0075), None);
+    }
+
+    #[test]
+    fn edits_both_address_spaces() {
+        let mut memory = Memory::new();
+        write_range(&mut memory, AddressSpace::Cpu, 0xFFFF, &[1, 2]);
+        assert_eq!(read_range(&memory, AddressSpace::Cpu, 0xFFFF, 2), [1, 2]);
+        assert_eq!(memory.ram()[0], 2);
+        write_range(&mut memory, AddressSpace::Ppu, 0x23FF, &[0x11, 0x22]);
+        assert_eq!(peek(&memory, AddressSpace::Ppu, 0x23FF), 0x11);
+        assert_eq!(peek(&memory, AddressSpace::Ppu, 0x2400), 0x22);
+        // palette entries are 6 bits
+        poke(&mut memory, AddressSpace::Ppu, 0x3F01, 0xFF);
+        assert_eq!(peek(&memory, AddressSpace::Ppu, 0x3F01), 0x3F);
+    }
+
+    #[test]
+    fn hex_dump() {
+        let bytes: Vec<u8> = (0..10).collect();
+        assert_eq!(
+            hex_lines(&bytes, 0x0300),
+            ["0300 00 01 02 03 04 05 06 07", "0308 08 09"]
+        );
+    }
+}
@@ -0,0 +1,554 @@
+use crate::emulator::Emulator;
+use crate::frame_timing::FrameTimingLog;
+use crate::hash::to_hex;
+use crate::input::PLAYERS;
+use crate::region::Region;
+use crate::{NesRom, RomError};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+
+// Input movies: the buttons held on every frame since power on, replayed through the core to
+// reproduce a run exactly. They're stored as FCEUX .fm2 files so TAS tools and FCEUX itself
+// can read ours and we can check theirs.
+// https://fceux.com/web/help/fm2.html
+//
+//   version 3
+//   emuVersion 22020
+//   rerecordCount 0
+//   palFlag 0
+//   romFilename smb
+//   romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+//   guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+//   fourscore 0
+//   port0 1
+//   port1 1
+//   port2 0
+//   |0|.......A|........||
+//
+// Each input line is the command byte (1 soft reset, 2 power cycle) then one RLDUTSBA field
+// per pad, any character but '.' or ' ' meaning held.
+
+const FM2_VERSION: u32 = 3;
+// written as emuVersion, what FCEUX release the file claims to be from
+const FM2_EMU_VERSION: u32 = 22020;
+// button characters in fm2 order, the highest bit of the button byte first
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+const FM2_COMMAND_SOFT_RESET: u8 = 1;
+const FM2_COMMAND_POWER: u8 = 2;
+
+/// What a replay is checked by, at the end of the movie
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ReplayHash {
+    /// SHA-1 of the last frame's pixels, what nesemu-headless prints
+    #[default]
+    Frame,
+    /// SHA-1 of a save state, which also catches differences that haven't shown on screen yet
+    State,
+}
+
+impl ReplayHash {
+    pub fn parse(text: &str) -> Option<ReplayHash> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "frame" => Some(ReplayHash::Frame),
+            "state" => Some(ReplayHash::State),
+            _ => None,
+        }
+    }
+
+    /// The hash of `emulator` as it is now, in hex
+    pub fn of(&self, emulator: &Emulator) -> String {
+        match self {
+            ReplayHash::Frame => to_hex(&emulator.frame().sha1()),
+            ReplayHash::State => {
+                let mut sha1 = Sha1::new();
+                sha1.update(emulator.save_state());
+                to_hex(&sha1.finalize())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// The rom couldn't be loaded for replay
+    Rom(RomError),
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// The movie was recorded with a different rom
+    RomMismatch,
+    /// The movie uses something the core can't replay yet
+    Unsupported(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "{}", e),
+            MovieError::Rom(e) => write!(f, "{}", e),
+            MovieError::Parse { line, message } => write!(f, "movie line {}: {}", line, message),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different rom"),
+            MovieError::Unsupported(what) => write!(f, "movie uses unsupported {}", what),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<RomError> for MovieError {
+    fn from(e: RomError) -> Self {
+        MovieError::Rom(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MovieFrame {
+    /// FM2 command bits, reset requests
+    pub commands: u8,
+    /// Buttons held by each player, see `input::BUTTON_*`
+    pub buttons: [u8; PLAYERS],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    /// MD5 of PRG and CHR ROM
+    pub rom_md5: [u8; 16],
+    pub rom_name: String,
+    pub region: Region,
+    pub four_score: bool,
+    pub rerecord_count: u32,
+    pub guid: String,
+    pub comments: Vec<String>,
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Empty movie starting from power on
+    pub fn new(rom: &NesRom, rom_name: &str) -> Self {
+        Movie {
+            rom_md5: rom.md5(),
+            rom_name: rom_name.to_string(),
+            region: rom.region(),
+            four_score: false,
+            rerecord_count: 0,
+            guid: new_guid(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn frames(&self) -> &[MovieFrame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Append the buttons held during the next frame
+    pub fn record_frame(&mut self, buttons: [u8; PLAYERS]) {
+        self.frames.push(MovieFrame {
+            commands: 0,
+            buttons,
+        });
+    }
+
+    /// Drop every frame from `frame` on, for rerecording from an earlier point
+    pub fn truncate(&mut self, frame: usize) {
+        self.frames.truncate(frame);
+        self.rerecord_count += 1;
+    }
+
+    pub fn matches_rom(&self, rom: &NesRom) -> bool {
+        self.rom_md5 == rom.md5()
+    }
+
+    /// Power on `rom` and play the movie through it, calling `each_frame` with the frame
+    /// number after every frame. Returns the console as it is at the end of the movie.
+    pub fn replay(
+        &self,
+        rom: &NesRom,
+        mut each_frame: impl FnMut(usize, &mut Emulator),
+    ) -> Result<Emulator, MovieError> {
+        if !self.matches_rom(rom) {
+            return Err(MovieError::RomMismatch);
+        }
+        let mut emulator = Emulator::new();
+        // replays run without a window, as fast as they can
+        emulator.set_decode_cache(true);
+        emulator.load_cartridge(rom)?;
+        emulator.set_region(self.region);
+        if self.four_score {
+            emulator.connect_four_score();
+        }
+        for (number, frame) in self.frames.iter().enumerate() {
+            if frame.commands != 0 {
+                return Err(MovieError::Unsupported(format!(
+                    "reset command on frame {}",
+                    number
+                )));
+            }
+            for (player, &buttons) in frame.buttons.iter().enumerate() {
+                emulator.set_buttons(player, buttons);
+            }
+            emulator.advance_frame();
+            each_frame(number, &mut emulator);
+        }
+        Ok(emulator)
+    }
+
+    /// `replay`, timing every frame for speedrun verification
+    pub fn replay_timed(
+        &self,
+        rom: &NesRom,
+        mut each_frame: impl FnMut(usize, &mut Emulator),
+    ) -> Result<(Emulator, FrameTimingLog), MovieError> {
+        let mut log = FrameTimingLog::new(rom, self.region);
+        let emulator = self.replay(rom, |number, emulator| {
+            log.record(emulator, self.frames[number].buttons[0]);
+            each_frame(number, emulator);
+        })?;
+        Ok((emulator, log))
+    }
+
+    pub fn read_fm2<R: BufRead>(reader: R) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            rom_md5: [0; 16],
+            rom_name: String::new(),
+            region: Region::Ntsc,
+            four_score: false,
+            rerecord_count: 0,
+            guid: String::new(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        };
+        let mut version = None;
+        let mut checksum = false;
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+            let error = |message: &str| MovieError::Parse {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            if line.starts_with('|') {
+                movie.frames.push(
+                    parse_fm2_frame(line, movie.four_score)
+                        .ok_or_else(|| error("bad input line"))?,
+                );
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number = || {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| error("expected a number"))
+            };
+            match key {
+                "version" => version = Some(number()?),
+                "rerecordCount" => movie.rerecord_count = number()?,
+                "palFlag" => {
+                    movie.region = if number()? == 1 {
+                        Region::Pal
+                    } else {
+                        Region::Ntsc
+                    }
+                }
+                "romFilename" => movie.rom_name = value.to_string(),
+                "romChecksum" => {
+                    let md5 = value
+                        .strip_prefix("base64:")
+                        .and_then(|text| BASE64.decode(text).ok())
+                        .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+                        .ok_or_else(|| error("bad romChecksum"))?;
+                    movie.rom_md5 = md5;
+                    checksum = true;
+                }
+                "guid" => movie.guid = value.to_string(),
+                "fourscore" => movie.four_score = number()? == 1,
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => return Err(MovieError::Unsupported("savestate start".into())),
+                "binary" if number()? == 1 => {
+                    return Err(MovieError::Unsupported("binary input".into()))
+                }
+                "FDS" if number()? == 1 => return Err(MovieError::Unsupported("FDS".into())),
+                "port2" if number()? != 0 => {
+                    return Err(MovieError::Unsupported("expansion port device".into()))
+                }
+                // emuVersion, port0/1, NewPPU and the like don't change how we replay
+                _ => {}
+            }
+        }
+        if version != Some(FM2_VERSION) {
+            return Err(MovieError::Unsupported("fm2 version".into()));
+        }
+        if !checksum {
+            return Err(MovieError::Parse {
+                line: 0,
+                message: "missing romChecksum".into(),
+            });
+        }
+        Ok(movie)
+    }
+
+    pub fn write_fm2<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "version {}", FM2_VERSION)?;
+        writeln!(out, "emuVersion {}", FM2_EMU_VERSION)?;
+        writeln!(out, "rerecordCount {}", self.rerecord_count)?;
+        writeln!(out, "palFlag {}", (self.region == Region::Pal) as u8)?;
+        writeln!(out, "romFilename {}", self.rom_name)?;
+        writeln!(out, "romChecksum base64:{}", BASE64.encode(self.rom_md5))?;
+        writeln!(out, "guid {}", self.guid)?;
+        writeln!(out, "fourscore {}", self.four_score as u8)?;
+        writeln!(out, "microphone 0")?;
+        // with a Four Score the ports are reported as empty and all 4 pads follow
+        let pads = if self.four_score { 0 } else { 1 };
+        writeln!(out, "port0 {}", pads)?;
+        writeln!(out, "port1 {}", pads)?;
+        writeln!(out, "port2 0")?;
+        writeln!(out, "FDS 0")?;
+        writeln!(out, "NewPPU 0")?;
+        for comment in &self.comments {
+            writeln!(out, "comment {}", comment)?;
+        }
+        let players = if self.four_score { PLAYERS } else { 2 };
+        for frame in &self.frames {
+            write!(out, "|{}|", frame.commands)?;
+            for &buttons in &frame.buttons[..players] {
+                let field: String = FM2_BUTTONS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &c)| {
+                        if buttons & (0x80 >> i) != 0 {
+                            c as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                write!(out, "{}|", field)?;
+            }
+            writeln!(out, "|")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_fm2_frame(line: &str, four_score: bool) -> Option<MovieFrame> {
+    let mut fields = line.strip_prefix('|')?.split('|');
+    let commands: u8 = fields.next()?.trim().parse().ok()?;
+    if commands & !(FM2_COMMAND_SOFT_RESET | FM2_COMMAND_POWER) != 0 {
+        return None;
+    }
+    let mut frame = MovieFrame {
+        commands,
+        buttons: [0; PLAYERS],
+    };
+    let players = if four_score { PLAYERS } else { 2 };
+    for buttons in frame.buttons.iter_mut().take(players) {
+        let field = fields.next()?;
+        // an empty field is an unplugged port
+        if field.is_empty() {
+            continue;
+        }
+        if field.len() != FM2_BUTTONS.len() {
+            return None;
+        }
+        *buttons = field
+            .bytes()
+            .enumerate()
+            .filter(|&(_, c)| c != b'.' && c != b' ')
+            .fold(0, |buttons, (i, _)| buttons | 0x80 >> i);
+    }
+    Some(frame)
+}
+
+// fm2 guids are only used to tell movies apart, so the clock is random enough
+fn new_guid() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    let mut state = nanos as u64 ^ (nanos >> 64) as u64 ^ 0x9E37_79B9_7F4A_7C15;
+    let mut bytes = [0u8; 16];
+    for byte in &mut bytes {
+        // xorshift
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *byte = state as u8;
+    }
+    let hex = to_hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::RomHeader;
+    use crate::input::{BUTTON_A, BUTTON_RIGHT, BUTTON_START, BUTTON_UP};
+    use crate::mapper::{CHR_BANK_SIZE, PRG_BANK_SIZE};
+    use crate::memory::Bus;
+
+    // strobes the pads then stores the 8 reads of $4016 at $10-$17, forever. It starts at
+    // $D000 from the reset vector, $C000 is all JAMs.
+    fn pad_reader_rom() -> NesRom {
+        let mut program = vec![0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40];
+        for bit in 0..8 {
+            // LDA $4016, STA $10+bit
+            program.extend_from_slice(&[0xAD, 0x16, 0x40, 0x85, 0x10 + bit]);
+        }
+        program.extend_from_slice(&[0x4C, 0x00, 0xD0]);
+        let mut prg = [0x02u8; PRG_BANK_SIZE];
+        prg[0x1000..0x1000 + program.len()].copy_from_slice(&program);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xD0]);
+        let mut header = [0u8; 16];
+        header[4] = 1;
+        header[5] = 1;
+        NesRom::new(
+            RomHeader::parse(&header),
+            None,
+            vec![prg],
+            vec![[0; CHR_BANK_SIZE]],
+        )
+    }
+
+    #[test]
+    fn replay_feeds_recorded_buttons() {
+        let rom = pad_reader_rom();
+        let mut movie = Movie::new(&rom, "pads");
+        let inputs = [BUTTON_A, 0, BUTTON_START | BUTTON_UP, BUTTON_RIGHT];
+        for &buttons in &inputs {
+            movie.record_frame([buttons, 0, 0, 0]);
+        }
+        let mut seen = Vec::new();
+        movie
+            .replay(&rom, |_, emulator| {
+                let memory = &emulator.cpu().memory;
+                let bits = (0..8).map(|bit| (memory.peek_byte(0x10 + bit) & 1) << bit);
+                seen.push(bits.sum::<u8>())
+            })
+            .unwrap();
+        assert_eq!(seen, inputs);
+        let emulator = movie.replay(&rom, |_, _| {}).unwrap();
+        assert_eq!(emulator.cpu().jammed(), None);
+
+        let mut other = pad_reader_rom();
+        other.prg_rom[0][0x100] = 1;
+        assert!(matches!(
+            movie.replay(&other, |_, _| {}),
+            Err(MovieError::RomMismatch)
+        ));
+    }
+
+    #[test]
+    fn timed_replays_log_every_frame() {
+        let rom = pad_reader_rom();
+        let mut movie = Movie::new(&rom, "pads");
+        movie.record_frame([BUTTON_A, 0, 0, 0]);
+        movie.record_frame([BUTTON_START, BUTTON_UP, 0, 0]);
+        let (emulator, log) = movie.replay_timed(&rom, |_, _| {}).unwrap();
+        let frames = log.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].input, frames[1].input), (BUTTON_A, BUTTON_START));
+        assert_eq!(frames[1].cycle, emulator.cycles());
+        assert!(frames[0].cycle < frames[1].cycle);
+        // the rom reads the pads all the time
+        assert_eq!(log.lag_frames(), 0);
+        assert_eq!(log.rom_crc32(), rom.crc32());
+    }
+
+    #[test]
+    fn replays_hash_the_same_every_time() {
+        let rom = pad_reader_rom();
+        let mut movie = Movie::new(&rom, "pads");
+        movie.record_frame([BUTTON_A, 0, 0, 0]);
+        movie.record_frame([BUTTON_START, 0, 0, 0]);
+        let hash =
+            |movie: &Movie, kind: ReplayHash| kind.of(&movie.replay(&rom, |_, _| {}).unwrap());
+        let state = hash(&movie, ReplayHash::State);
+        assert_eq!(state.len(), 40);
+        assert_eq!(hash(&movie, ReplayHash::State), state);
+        assert_eq!(
+            hash(&movie, ReplayHash::Frame),
+            hash(&movie, ReplayHash::Frame)
+        );
+
+        // the pads are read into RAM, which the picture doesn't show but the state does
+        let mut other = movie.clone();
+        other.truncate(1);
+        other.record_frame([BUTTON_UP, 0, 0, 0]);
+        assert_ne!(hash(&other, ReplayHash::State), state);
+        assert_eq!(ReplayHash::parse("State"), Some(ReplayHash::State));
+        assert_eq!(ReplayHash::parse("ram"), None);
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        let rom = pad_reader_rom();
+        let mut movie = Movie::new(&rom, "pads");
+        movie.comments.push("author someone".into());
+        movie.record_frame([BUTTON_A, BUTTON_RIGHT | BUTTON_START, 0, 0]);
+        movie.record_frame([0; PLAYERS]);
+
+        let mut out = Vec::new();
+        movie.write_fm2(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("\n|0|.......A|R...T...||\n|0|........|........||\n"));
+        assert_eq!(Movie::read_fm2(text.as_bytes()).unwrap(), movie);
+
+        movie.four_score = true;
+        movie.record_frame([0, 0, 0, BUTTON_A]);
+        let mut out = Vec::new();
+        movie.write_fm2(&mut out).unwrap();
+        let imported = Movie::read_fm2(&out[..]).unwrap();
+        assert_eq!(imported.frames()[2].buttons, [0, 0, 0, BUTTON_A]);
+    }
+
+    #[test]
+    fn fm2_import() {
+        let text = "version 3\nemuVersion 20604\nrerecordCount 12\npalFlag 1\n\
+                    romFilename game\nromChecksum base64:AAECAwQFBgcICQoLDA0ODw==\n\
+                    guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\nfourscore 0\nport0 1\n\
+                    port1 0\nport2 0\n|0|R......A|||\n|1|  U  x  |||\n";
+        let movie = Movie::read_fm2(text.as_bytes()).unwrap();
+        assert_eq!(movie.rerecord_count, 12);
+        assert_eq!(movie.region, Region::Pal);
+        assert_eq!(movie.rom_md5[15], 15);
+        assert_eq!(movie.frames()[0].buttons[0], BUTTON_RIGHT | BUTTON_A);
+        assert_eq!(movie.frames()[1].buttons[0], 0x20 | 0x04);
+        assert_eq!(movie.frames()[1].commands, FM2_COMMAND_SOFT_RESET);
+
+        let broken = text.replace("|0|R......A|||", "|0|R..A|||");
+        match Movie::read_fm2(broken.as_bytes()) {
+            Err(MovieError::Parse { line, .. }) => assert_eq!(line, 12),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,649 @@
+use crate::emulator::Emulator;
+use crate::hash::Crc32;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+// Two player netplay by lockstep: both consoles run the same frames with the same input, and
+// since emulation is deterministic they stay identical without sending anything but buttons.
+// A frame only runs once both players' buttons for it are known.
+//
+// Each player's buttons are used `delay` frames after they're read, so they have that long to
+// reach the other side before it has to wait for them. The first `delay` frames run with
+// nothing pressed on both sides. Every so often each side takes a CRC-32 of its save state and
+// sends it along, a difference means the consoles have drifted apart.
+//
+// UDP can drop and reorder packets, so every packet carries all the buttons the other side
+// hasn't acknowledged yet, and is sent every step whether anything changed or not. A packet:
+//
+//   "NP" 1           magic and version
+//   u32              ack: the first frame of the other side's buttons still wanted
+//   u32, u8          first frame of the buttons that follow and how many
+//   ...              the buttons, a byte per frame
+//   u32, u32         frame and CRC-32 of the last checksum, frame u32::MAX before the first
+//
+// Numbers are little endian.
+
+const PACKET_MAGIC: &[u8; 3] = b"NP\x01";
+const NO_CHECKSUM: u32 = u32::MAX;
+// local checksums kept to compare with the other side's, which arrive a little later
+const CHECKSUM_HISTORY: usize = 16;
+const MAX_PACKET: usize = 1500;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NetplayConfig {
+    /// Frames between reading buttons and using them
+    pub delay: u32,
+    /// Which controller this side plays, 0 or 1
+    pub local_player: usize,
+    /// Frames between state checksums
+    pub checksum_interval: u32,
+    /// Give up after hearing nothing from the other side for this long
+    pub timeout: Duration,
+}
+
+impl Default for NetplayConfig {
+    fn default() -> Self {
+        NetplayConfig {
+            delay: 2,
+            local_player: 0,
+            checksum_interval: 60,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetplayError {
+    Io(io::Error),
+    /// The consoles' states differed at the end of this frame
+    Desync {
+        frame: u32,
+    },
+    /// Nothing heard from the other side for the configured timeout
+    TimedOut,
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetplayError::Io(e) => write!(f, "netplay: {}", e),
+            NetplayError::Desync { frame } => write!(f, "netplay desynced at frame {}", frame),
+            NetplayError::TimedOut => write!(f, "netplay: the other player stopped responding"),
+        }
+    }
+}
+
+impl std::error::Error for NetplayError {}
+
+impl From<io::Error> for NetplayError {
+    fn from(e: io::Error) -> Self {
+        NetplayError::Io(e)
+    }
+}
+
+/// What a packet holds, see the layout above
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub ack: u32,
+    pub first: u32,
+    pub buttons: Vec<u8>,
+    /// Frame and CRC-32
+    pub checksum: Option<(u32, u32)>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let (checksum_frame, checksum) = self.checksum.unwrap_or((NO_CHECKSUM, 0));
+        let mut packet = PACKET_MAGIC.to_vec();
+        packet.extend_from_slice(&self.ack.to_le_bytes());
+        packet.extend_from_slice(&self.first.to_le_bytes());
+        packet.push(self.buttons.len() as u8);
+        packet.extend_from_slice(&self.buttons);
+        packet.extend_from_slice(&checksum_frame.to_le_bytes());
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet
+    }
+
+    /// None for anything that isn't a whole packet, or has frames past the last one there
+    /// can be
+    pub fn decode(packet: &[u8]) -> Option<Packet> {
+        let rest = packet.strip_prefix(PACKET_MAGIC)?;
+        let first = u32_at(rest, 4)?;
+        let count = *rest.get(8)? as usize;
+        first.checked_add(count as u32)?;
+        let buttons = rest.get(9..9 + count)?.to_vec();
+        let checksum_frame = u32_at(rest, 9 + count)?;
+        let checksum = u32_at(rest, 13 + count)?;
+        Some(Packet {
+            ack: u32_at(rest, 0)?,
+            first,
+            buttons,
+            checksum: (checksum_frame != NO_CHECKSUM).then_some((checksum_frame, checksum)),
+        })
+    }
+}
+
+/// The frame and input bookkeeping, with no networking of its own
+#[derive(Debug, Clone)]
+pub struct Lockstep {
+    config: NetplayConfig,
+    // next frame to run
+    frame: u32,
+    // buttons by frame, each starting at its base frame
+    local: VecDeque<u8>,
+    local_base: u32,
+    remote: VecDeque<u8>,
+    remote_base: u32,
+    // first frame of our buttons the other side still wants
+    peer_ack: u32,
+    checksums: VecDeque<(u32, u32)>,
+    remote_checksum: Option<(u32, u32)>,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+impl Lockstep {
+    pub fn new(config: NetplayConfig) -> Self {
+        let idle = std::iter::repeat_n(0, config.delay as usize);
+        Lockstep {
+            config,
+            frame: 0,
+            local: idle.clone().collect(),
+            local_base: 0,
+            remote: idle.collect(),
+            remote_base: 0,
+            peer_ack: 0,
+            checksums: VecDeque::new(),
+            remote_checksum: None,
+        }
+    }
+
+    /// The next frame to run
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    fn local_end(&self) -> u32 {
+        self.local_base + self.local.len() as u32
+    }
+
+    fn remote_end(&self) -> u32 {
+        self.remote_base + self.remote.len() as u32
+    }
+
+    /// Queue this side's buttons for `delay` frames from now. Does nothing while already that
+    /// far ahead, when stalled waiting for the other side.
+    pub fn add_local_input(&mut self, buttons: u8) {
+        if self.local_end() <= self.frame + self.config.delay {
+            self.local.push_back(buttons);
+        }
+    }
+
+    /// Both players' buttons for the next frame, None until the other side's have arrived
+    pub fn inputs(&self) -> Option<[u8; 2]> {
+        let local = *self.local.get((self.frame - self.local_base) as usize)?;
+        let remote = *self.remote.get((self.frame - self.remote_base) as usize)?;
+        Some(if self.config.local_player == 0 {
+            [local, remote]
+        } else {
+            [remote, local]
+        })
+    }
+
+    /// Move on after running the frame `inputs` gave buttons for. `checksum` is asked for the
+    /// state's checksum every `checksum_interval` frames.
+    pub fn frame_done(&mut self, checksum: impl FnOnce() -> u32) -> Result<(), NetplayError> {
+        let frame = self.frame;
+        if frame.is_multiple_of(self.config.checksum_interval.max(1)) {
+            if self.checksums.len() == CHECKSUM_HISTORY {
+                self.checksums.pop_front();
+            }
+            self.checksums.push_back((frame, checksum()));
+        }
+        self.frame += 1;
+        while self.remote_base < self.frame && !self.remote.is_empty() {
+            self.remote.pop_front();
+            self.remote_base += 1;
+        }
+        self.drop_sent();
+        self.check_desync()
+    }
+
+    // buttons the other side has and that have been run aren't needed any more
+    fn drop_sent(&mut self) {
+        while self.local_base < self.frame.min(self.peer_ack) && !self.local.is_empty() {
+            self.local.pop_front();
+            self.local_base += 1;
+        }
+    }
+
+    fn check_desync(&self) -> Result<(), NetplayError> {
+        let Some((frame, remote)) = self.remote_checksum else {
+            return Ok(());
+        };
+        match self.checksums.iter().find(|&&(f, _)| f == frame) {
+            Some(&(_, local)) if local != remote => Err(NetplayError::Desync { frame }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The packet to send the other side now
+    pub fn packet(&self) -> Vec<u8> {
+        let first = self.peer_ack.max(self.local_base);
+        let buttons: Vec<u8> = self
+            .local
+            .iter()
+            .skip((first - self.local_base) as usize)
+            .take(u8::MAX as usize)
+            .copied()
+            .collect();
+        Packet {
+            ack: self.remote_end(),
+            first,
+            buttons,
+            checksum: self.checksums.back().copied(),
+        }
+        .encode()
+    }
+
+    /// Take in a packet from the other side. Anything that isn't a packet is ignored, a
+    /// checksum that doesn't match is a desync.
+    pub fn receive(&mut self, packet: &[u8]) -> Result<(), NetplayError> {
+        let Some(packet) = Packet::decode(packet) else {
+            return Ok(());
+        };
+        self.peer_ack = self.peer_ack.max(packet.ack);
+        for (frame, &pressed) in (packet.first..=u32::MAX).zip(&packet.buttons) {
+            // anything past a gap comes again in a later packet
+            if frame == self.remote_end() {
+                self.remote.push_back(pressed);
+            }
+        }
+        if packet.checksum.is_some() {
+            self.remote_checksum = packet.checksum;
+        }
+        self.drop_sent();
+        self.check_desync()
+    }
+}
+
+/// Something that carries packets to the other side, without guaranteeing they get there
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+    /// The next packet that has arrived, None if there isn't one. Never blocks.
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// A UDP socket talking to one other player
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+    // learnt from the first packet when hosting
+    peer: Option<SocketAddr>,
+}
+
+impl UdpTransport {
+    /// Wait for the other player on `port`, whoever sends first becomes the peer
+    pub fn host(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket, peer: None })
+    }
+
+    /// Play with the player hosting at `address`
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let peer = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        })?;
+        let any: SocketAddr = if peer.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(any)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport {
+            socket,
+            peer: Some(peer),
+        })
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.peer {
+            Some(peer) => self.socket.send_to(packet, peer).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = [0u8; MAX_PACKET];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    let peer = *self.peer.get_or_insert(from);
+                    if from == peer {
+                        return Ok(Some(buffer[..len].to_vec()));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A netplay session, running an emulator in lockstep with the other side's
+#[derive(Debug)]
+pub struct Netplay<T: Transport> {
+    transport: T,
+    lockstep: Lockstep,
+    timeout: Duration,
+    // None until the other side first gets in touch, there's no timeout before that
+    last_heard: Option<Instant>,
+}
+
+/// A netplay session as the frontend drives it, lockstep or rollback
+pub trait Session {
+    /// Exchange buttons with the other side and run what's due on `emulator`, `buttons` being
+    /// what this side has pressed now. Returns whether a new frame ran.
+    fn step(&mut self, emulator: &mut Emulator, buttons: u8) -> Result<bool, NetplayError>;
+}
+
+/// CRC-32 of a save state, what the two sides compare
+pub(crate) fn checksum(state: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(state);
+    crc.finish()
+}
+
+impl<T: Transport> Netplay<T> {
+    /// Start at frame 0. Both sides have to have the same game freshly loaded, with the same
+    /// power on settings.
+    pub fn new(transport: T, config: NetplayConfig) -> Self {
+        Netplay {
+            transport,
+            lockstep: Lockstep::new(config),
+            timeout: config.timeout,
+            last_heard: None,
+        }
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.lockstep.frame()
+    }
+
+    /// Whether anything has come from the other side yet
+    pub fn is_connected(&self) -> bool {
+        self.last_heard.is_some()
+    }
+}
+
+impl<T: Transport> Session for Netplay<T> {
+    /// Runs the next frame once both sides' buttons for it are in, nothing while waiting for
+    /// the other side
+    fn step(&mut self, emulator: &mut Emulator, buttons: u8) -> Result<bool, NetplayError> {
+        self.lockstep.add_local_input(buttons);
+        while let Some(packet) = self.transport.recv()? {
+            self.last_heard = Some(Instant::now());
+            self.lockstep.receive(&packet)?;
+        }
+        let ran = match self.lockstep.inputs() {
+            Some(inputs) => {
+                for (player, pressed) in inputs.into_iter().enumerate() {
+                    emulator.set_buttons(player, pressed);
+                }
+                emulator.advance_frame();
+                self.lockstep
+                    .frame_done(|| checksum(&emulator.save_state()))?;
+                true
+            }
+            None => false,
+        };
+        self.transport.send(&self.lockstep.packet())?;
+        if !ran
+            && self
+                .last_heard
+                .is_some_and(|heard| heard.elapsed() > self.timeout)
+        {
+            return Err(NetplayError::TimedOut);
+        }
+        Ok(ran)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Bus;
+    use std::sync::{Arc, Mutex};
+
+    type Queue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+    // one end of an in memory link that drops every `drop_every`th packet sent
+    struct Link {
+        outgoing: Queue,
+        incoming: Queue,
+        sent: usize,
+        drop_every: usize,
+    }
+
+    fn link_pair(drop_every: usize) -> (Link, Link) {
+        let a: Queue = Arc::default();
+        let b: Queue = Arc::default();
+        (
+            Link {
+                outgoing: Arc::clone(&a),
+                incoming: Arc::clone(&b),
+                sent: 0,
+                drop_every,
+            },
+            Link {
+                outgoing: b,
+                incoming: a,
+                sent: 0,
+                drop_every,
+            },
+        )
+    }
+
+    impl Transport for Link {
+        fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            self.sent += 1;
+            if self.drop_every == 0 || !self.sent.is_multiple_of(self.drop_every) {
+                self.outgoing.lock().unwrap().push_back(packet.to_vec());
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.incoming.lock().unwrap().pop_front())
+        }
+    }
+
+    // copies both controllers' first bit to $10 and $11 forever
+    const READ_PADS: [u8; 23] = [
+        0xA9, 0x01, 0x8D, 0x16, 0x40, // strobe
+        0xA9, 0x00, 0x8D, 0x16, 0x40, //
+        0xAD, 0x16, 0x40, 0x85, 0x10, // LDA $4016, STA $10
+        0xAD, 0x17, 0x40, 0x85, 0x11, // LDA $4017, STA $11
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn console() -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().set_logging(false);
+        emulator.cpu_mut().load_bytes(&READ_PADS);
+        emulator
+    }
+
+    #[test]
+    fn buttons_arrive_after_the_delay() {
+        let config = NetplayConfig {
+            delay: 3,
+            ..NetplayConfig::default()
+        };
+        let mut a = Lockstep::new(config);
+        let mut b = Lockstep::new(NetplayConfig {
+            local_player: 1,
+            ..config
+        });
+        for frame in 0..10u8 {
+            a.add_local_input(frame);
+            b.add_local_input(0x80 | frame);
+            b.receive(&a.packet()).unwrap();
+            a.receive(&b.packet()).unwrap();
+            let inputs = a.inputs().unwrap();
+            assert_eq!(b.inputs(), Some(inputs));
+            let expected = match frame.checked_sub(3) {
+                Some(sent) => [sent, 0x80 | sent],
+                None => [0, 0],
+            };
+            assert_eq!(inputs, expected);
+            a.frame_done(|| 1).unwrap();
+            b.frame_done(|| 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn waits_for_the_other_side() {
+        let mut a = Lockstep::new(NetplayConfig {
+            delay: 1,
+            ..NetplayConfig::default()
+        });
+        a.add_local_input(1);
+        assert!(a.inputs().is_some());
+        a.frame_done(|| 0).unwrap();
+        assert_eq!(a.inputs(), None);
+        // frame 2's buttons, then stalled so no more are queued
+        a.add_local_input(2);
+        a.add_local_input(3);
+        assert_eq!(a.local, [0, 1, 2]);
+    }
+
+    #[test]
+    fn consoles_stay_in_step_over_a_lossy_link() {
+        let (link_a, link_b) = link_pair(3);
+        let config = NetplayConfig {
+            checksum_interval: 2,
+            ..NetplayConfig::default()
+        };
+        let mut a = Netplay::new(link_a, config);
+        let mut b = Netplay::new(
+            link_b,
+            NetplayConfig {
+                local_player: 1,
+                ..config
+            },
+        );
+        let (mut console_a, mut console_b) = (console(), console());
+        for step in 0..40u8 {
+            a.step(&mut console_a, step & 1).unwrap();
+            b.step(&mut console_b, !step & 1).unwrap();
+        }
+        assert!(a.frame() > 10 && b.frame() > 10);
+        // let whichever is behind catch up
+        while a.frame() != b.frame() {
+            if a.frame() < b.frame() {
+                a.step(&mut console_a, 0).unwrap();
+            } else {
+                b.step(&mut console_b, 0).unwrap();
+            }
+        }
+        assert_eq!(console_a.ram(), console_b.ram());
+        assert_eq!(console_a.save_state(), console_b.save_state());
+    }
+
+    #[test]
+    fn different_states_are_a_desync() {
+        let (link_a, link_b) = link_pair(0);
+        let mut a = Netplay::new(link_a, NetplayConfig::default());
+        let mut b = Netplay::new(
+            link_b,
+            NetplayConfig {
+                local_player: 1,
+                ..NetplayConfig::default()
+            },
+        );
+        let mut console_a = console();
+        let mut console_b = console();
+        console_b.cpu_mut().memory.write_byte(0x0300, 0x42);
+        let mut result = Ok(true);
+        for _ in 0..10 {
+            result = a
+                .step(&mut console_a, 0)
+                .and_then(|_| b.step(&mut console_b, 0));
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(NetplayError::Desync { frame: 0 })));
+    }
+
+    #[test]
+    fn udp_host_learns_its_peer() {
+        let mut host = UdpTransport::host(0).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut guest = UdpTransport::connect(("127.0.0.1", port)).unwrap();
+        // nobody to send to yet
+        host.send(b"lost").unwrap();
+        guest.send(b"hello").unwrap();
+        let received = (0..100).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(5));
+            host.recv().unwrap()
+        });
+        assert_eq!(received.as_deref(), Some(&b"hello"[..]));
+        assert!(host.peer().is_some());
+        host.send(b"back").unwrap();
+        let received = (0..100).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(5));
+            guest.recv().unwrap()
+        });
+        assert_eq!(received.as_deref(), Some(&b"back"[..]));
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        let mut a = Lockstep::new(NetplayConfig::default());
+        for packet in [
+            &b""[..],
+            b"NP",
+            b"NP\x01\x00\x00\x00\x00\x00\x00\x00\x00\x05\x01",
+        ] {
+            a.receive(packet).unwrap();
+        }
+        assert_eq!(a.remote.len(), 2);
+
+        // frames that would count past u32::MAX
+        let wrapping = Packet {
+            ack: 0,
+            first: u32::MAX,
+            buttons: vec![1, 2],
+            checksum: None,
+        };
+        assert_eq!(Packet::decode(&wrapping.encode()), None);
+        a.receive(&wrapping.encode()).unwrap();
+        assert_eq!(a.remote.len(), 2);
+        let last = Packet {
+            buttons: Vec::new(),
+            ..wrapping
+        };
+        a.receive(&last.encode()).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::time::{Duration, Instant};
+
+// On-screen display: short messages in the bottom left, an optional FPS/speed counter in the
+// top right and a panel of lines in the top left for tools like RAM search, drawn with a 3x5 pixel font onto the RGB picture the frontend shows. It only
+// ever touches the frontend's copy of the frame, never emulator state or recordings.
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+// glyph plus a pixel of spacing, which also leaves room for the shadow
+const CHAR_ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_ADVANCE: usize = GLYPH_HEIGHT + 2;
+const MARGIN: usize = 2;
+/// How long a message stays up, in frames
+pub const MESSAGE_FRAMES: u32 = 180;
+const MAX_MESSAGES: usize = 4;
+const TEXT_COLOUR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+const SHADOW_COLOUR: [u8; 3] = [0x00, 0x00, 0x00];
+// how often the FPS counter updates
+const FPS_INTERVAL: Duration = Duration::from_millis(500);
+
+// rows top to bottom, bit 2 is the left column. Lower case is drawn as upper case.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0, 0, 0, 0, 0],
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [7, 1, 7, 4, 7],
+        '3' => [7, 1, 7, 1, 7],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 7, 1, 7],
+        '6' => [7, 4, 7, 5, 7],
+        '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 7],
+        'A' => [2, 5, 7, 5, 5],
+        'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3],
+        'D' => [6, 5, 5, 5, 6],
+        'E' => [7, 4, 6, 4, 7],
+        'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3],
+        'H' => [5, 5, 7, 5, 5],
+        'I' => [7, 2, 2, 2, 7],
+        'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5],
+        'L' => [4, 4, 4, 4, 7],
+        'M' => [5, 7, 7, 5, 5],
+        'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2],
+        'P' => [6, 5, 6, 4, 4],
+        'Q' => [2, 5, 5, 6, 3],
+        'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6],
+        'T' => [7, 2, 2, 2, 2],
+        'U' => [5, 5, 5, 5, 7],
+        'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5],
+        'X' => [5, 5, 2, 5, 5],
+        'Y' => [5, 5, 2, 2, 2],
+        'Z' => [7, 1, 2, 4, 7],
+        '.' => [0, 0, 0, 0, 2],
+        ',' => [0, 0, 0, 2, 4],
+        ':' => [0, 2, 0, 2, 0],
+        '-' => [0, 0, 7, 0, 0],
+        '+' => [0, 2, 7, 2, 0],
+        '=' => [0, 7, 0, 7, 0],
+        '_' => [0, 0, 0, 0, 7],
+        '/' => [1, 1, 2, 4, 4],
+        '%' => [5, 1, 2, 4, 5],
+        '#' => [5, 7, 5, 7, 5],
+        '!' => [2, 2, 2, 0, 2],
+        '\'' => [2, 2, 0, 0, 0],
+        '(' => [1, 2, 2, 2, 1],
+        ')' => [4, 2, 2, 2, 4],
+        _ => [7, 1, 2, 0, 2],
+    }
+}
+
+/// Width in pixels `text` takes up
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * CHAR_ADVANCE
+}
+
+fn put_pixel(pixels: &mut [u8], pitch: usize, x: usize, y: usize, rgb: [u8; 3]) {
+    let offset = y * pitch + x * 3;
+    if let Some(pixel) = pixels.get_mut(offset..offset + 3) {
+        pixel.copy_from_slice(&rgb);
+    }
+}
+
+/// Draw `text` with its top left corner at `x`, `y` on an RGB24 picture, with a drop shadow
+/// so it shows up on any background. Anything off the edge is clipped.
+pub fn draw_text(pixels: &mut [u8], width: usize, pitch: usize, x: usize, y: usize, text: &str) {
+    for (shadow, colour) in [(1, SHADOW_COLOUR), (0, TEXT_COLOUR)] {
+        for (i, c) in text.chars().enumerate() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (0b100 >> column) == 0 {
+                        continue;
+                    }
+                    let px = x + i * CHAR_ADVANCE + column + shadow;
+                    if px < width {
+                        put_pixel(pixels, pitch, px, y + row + shadow, colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Frames per second over the last half second or so
+#[derive(Debug, Clone)]
+pub struct FpsCounter {
+    frames: u32,
+    since: Instant,
+    fps: f64,
+}
+
+impl FpsCounter {
+    pub fn new(now: Instant) -> Self {
+        FpsCounter {
+            frames: 0,
+            since: now,
+            fps: 0.0,
+        }
+    }
+
+    /// Count a frame finishing at `now`
+    pub fn tick(&mut self, now: Instant) {
+        self.frames += 1;
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= FPS_INTERVAL {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.frames = 0;
+            self.since = now;
+        }
+    }
+
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Osd {
+    // text and frames left to show it
+    messages: Vec<(String, u32)>,
+    counter: FpsCounter,
+    show_fps: bool,
+    panel: Vec<String>,
+}
+
+impl Osd {
+    pub fn new(now: Instant) -> Self {
+        Osd {
+            messages: Vec::new(),
+            counter: FpsCounter::new(now),
+            show_fps: false,
+            panel: Vec::new(),
+        }
+    }
+
+    /// Show a message for `MESSAGE_FRAMES` frames, below any already showing
+    pub fn message(&mut self, text: impl Into<String>) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push((text.into(), MESSAGE_FRAMES));
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.messages.iter().map(|(text, _)| text.as_str())
+    }
+
+    /// Lines to keep showing in the top left until replaced, empty for none
+    pub fn set_panel(&mut self, lines: Vec<String>) {
+        self.panel = lines;
+    }
+
+    pub fn toggle_fps(&mut self) {
+        self.show_fps = !self.show_fps;
+    }
+
+    /// Count an emulated frame, ageing messages and updating the counter
+    pub fn tick(&mut self, now: Instant) {
+        self.counter.tick(now);
+        self.messages.retain_mut(|(_, frames)| {
+            *frames -= 1;
+            *frames > 0
+        });
+    }
+
+    /// Draw onto a `width` x `height` RGB24 picture. `normal_fps` is the console's frame rate,
+    /// for showing speed as a percentage.
+    pub fn draw(
+        &self,
+        pixels: &mut [u8],
+        width: usize,
+        height: usize,
+        pitch: usize,
+        normal_fps: f64,
+    ) {
+        if self.show_fps {
+            let fps = self.counter.fps();
+            let text = format!("{:.0} FPS {:.0}%", fps, fps / normal_fps * 100.0);
+            let x = width.saturating_sub(text_width(&text) + MARGIN);
+            draw_text(pixels, width, pitch, x, MARGIN, &text);
+        }
+        for (line, text) in self.panel.iter().enumerate() {
+            draw_text(
+                pixels,
+                width,
+                pitch,
+                MARGIN,
+                MARGIN + line * LINE_ADVANCE,
+                text,
+            );
+        }
+        let top = height.saturating_sub(MARGIN + self.messages.len() * LINE_ADVANCE);
+        for (line, (text, _)) in self.messages.iter().enumerate() {
+            draw_text(
+                pixels,
+                width,
+                pitch,
+                MARGIN,
+                top + line * LINE_ADVANCE,
+                text,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 16;
+
+    fn lit(pixels: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * WIDTH + x) * 3;
+        [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+    }
+
+    #[test]
+    fn draws_glyphs_with_shadow() {
+        let mut pixels = vec![0x80; WIDTH * 8 * 3];
+        draw_text(&mut pixels, WIDTH, WIDTH * 3, 0, 0, "1");
+        // top of the 1 is the middle column
+        assert_eq!(lit(&pixels, 1, 0), TEXT_COLOUR);
+        assert_eq!(lit(&pixels, 0, 0), [0x80; 3]);
+        assert_eq!(lit(&pixels, 2, 1), SHADOW_COLOUR);
+        // bottom row is all three
+        assert_eq!(lit(&pixels, 0, 4), TEXT_COLOUR);
+        assert_eq!(lit(&pixels, 2, 4), TEXT_COLOUR);
+        assert_eq!(lit(&pixels, 3, 5), SHADOW_COLOUR);
+    }
+
+    #[test]
+    fn clips_at_the_edges() {
+        let mut pixels = vec![0; WIDTH * 4 * 3];
+        draw_text(&mut pixels, WIDTH, WIDTH * 3, WIDTH - 2, 2, "WWW");
+        assert_eq!(pixels.len(), WIDTH * 4 * 3);
+    }
+
+    #[test]
+    fn messages_expire() {
+        let now = Instant::now();
+        let mut osd = Osd::new(now);
+        osd.message("State 1 saved");
+        for _ in 0..MESSAGE_FRAMES - 1 {
+            osd.tick(now);
+        }
+        assert_eq!(osd.messages().collect::<Vec<_>>(), ["State 1 saved"]);
+        osd.tick(now);
+        assert_eq!(osd.messages().count(), 0);
+
+        for n in 0..6 {
+            osd.message(format!("{}", n));
+        }
+        assert_eq!(osd.messages().collect::<Vec<_>>(), ["2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn fps_counter() {
+        let start = Instant::now();
+        let mut counter = FpsCounter::new(start);
+        for frame in 1..=30 {
+            counter.tick(start + Duration::from_millis(frame * 1000 / 60));
+        }
+        assert!((counter.fps() - 60.0).abs() < 0.1, "{}", counter.fps());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::ppu::{HEIGHT, WIDTH};
+use crate::region::Region;
+
+// https://www.nesdev.org/wiki/Overscan
+// CRTs hid the edges of the 256x240 picture behind the bezel, so games often left garbage
+// there. Frontends crop it before scaling the result by the region's pixel aspect ratio.
+
+/// Pixels to crop from each edge of the frame
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    pub const NONE: Overscan = Overscan {
+        top: 0,
+        bottom: 0,
+        left: 0,
+        right: 0,
+    };
+
+    /// What a typical TV of the region showed: NTSC sets lose 8 lines top and bottom, the
+    /// PAL PPU blanks the top line and 2 pixels either side itself
+    pub fn for_region(region: Region) -> Self {
+        match region {
+            Region::Ntsc => Overscan {
+                top: 8,
+                bottom: 8,
+                left: 0,
+                right: 0,
+            },
+            Region::Pal | Region::Dendy => Overscan {
+                top: 1,
+                bottom: 0,
+                left: 2,
+                right: 2,
+            },
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        WIDTH.saturating_sub(self.left + self.right)
+    }
+
+    pub fn height(&self) -> usize {
+        HEIGHT.saturating_sub(self.top + self.bottom)
+    }
+
+    /// Visible rows of a `WIDTH` x `HEIGHT` frame buffer, without copying
+    pub fn rows<'a, T>(&self, frame: &'a [T]) -> impl Iterator<Item = &'a [T]> {
+        let (left, width) = (self.left.min(WIDTH), self.width());
+        frame
+            .chunks_exact(WIDTH)
+            .skip(self.top)
+            .take(self.height())
+            .map(move |row| &row[left..left + width])
+    }
+
+    /// Copy of the visible part of a frame buffer, `width()` x `height()`
+    pub fn crop<T: Copy>(&self, frame: &[T]) -> Vec<T> {
+        self.rows(frame).flatten().copied().collect()
+    }
+
+    /// Size to show the cropped frame at on a square pixel display for the given height
+    pub fn display_size(&self, region: Region, height: usize) -> (usize, usize) {
+        let scale = height as f64 / self.height().max(1) as f64;
+        let width = self.width() as f64 * scale * region.pixel_aspect_ratio();
+        (width.round() as usize, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crops_ntsc_overscan() {
+        let frame: Vec<u16> = (0..WIDTH * HEIGHT).map(|i| (i / WIDTH) as u16).collect();
+        let overscan = Overscan::for_region(Region::Ntsc);
+        let cropped = overscan.crop(&frame);
+        assert_eq!(cropped.len(), 256 * 224);
+        assert_eq!(cropped[0], 8);
+        assert_eq!(*cropped.last().unwrap(), 231);
+        assert_eq!(Overscan::NONE.crop(&frame), frame);
+
+        let pal = Overscan::for_region(Region::Pal);
+        assert_eq!((pal.width(), pal.height()), (252, 239));
+        assert_eq!(pal.rows(&frame).next().unwrap().len(), 252);
+    }
+
+    #[test]
+    fn display_size_applies_aspect_ratio() {
+        let overscan = Overscan::for_region(Region::Ntsc);
+        // 256 pixels at 8:7 is about 292 square pixels
+        assert_eq!(overscan.display_size(Region::Ntsc, 224), (293, 224));
+        assert_eq!(overscan.display_size(Region::Ntsc, 448), (585, 448));
+    }
+}
@@ -0,0 +1,296 @@
+use std::time::{Duration, Instant};
+
+// Decides when the frontend runs the next frame and whether to show it.
+//
+// Normally frames are paced by a timer at the region's frame rate, or by the audio buffer:
+// the emulator waits while more than the target latency of sound is queued, so video follows
+// the sound card's clock and audio never underruns. With vsync on, presenting blocks until
+// the display refreshes and no extra waiting is done.
+//
+// The emulation speed, 25% to 800% of real time, scales the frame rate. Audio is resampled to
+// match, so audio sync keeps working at any of them. Holding fast-forward multiplies the speed,
+// or runs as fast as the machine can go, and only presents at the normal frame rate so vsync
+// doesn't hold it back. Slow motion divides the speed instead.
+
+/// The speeds the speed hotkeys step through
+pub const SPEED_STEPS: [f32; 10] = [0.25, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0, 4.0, 8.0];
+
+/// The next step up from `speed`, or down when not `faster`, staying at the ends
+pub fn step_speed(speed: f32, faster: bool) -> f32 {
+    if faster {
+        SPEED_STEPS.into_iter().find(|&step| step > speed)
+    } else {
+        SPEED_STEPS.into_iter().rev().find(|&step| step < speed)
+    }
+    .unwrap_or(speed)
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SyncMode {
+    #[default]
+    Timer,
+    /// Wait for the audio buffer to drain to the target latency, falls back to the timer when
+    /// there's no audio device
+    Audio,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum FastForward {
+    #[default]
+    Uncapped,
+    /// Run at this many times normal speed
+    Times(u32),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PacingConfig {
+    pub sync: SyncMode,
+    pub vsync: bool,
+    pub fast_forward: FastForward,
+    /// Slow motion runs at 1 / this of normal speed
+    pub slow_motion: u32,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        PacingConfig {
+            sync: SyncMode::Timer,
+            vsync: false,
+            fast_forward: FastForward::Uncapped,
+            slow_motion: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameScheduler {
+    config: PacingConfig,
+    frame_time: Duration,
+    next_frame: Instant,
+    next_present: Instant,
+    // the emulation speed, before fast-forward and slow motion
+    base_speed: f64,
+    fast_forward: bool,
+    slow_motion: bool,
+}
+
+impl FrameScheduler {
+    pub fn new(config: PacingConfig, frames_per_second: f64) -> Self {
+        let now = Instant::now();
+        FrameScheduler {
+            config,
+            frame_time: Duration::from_secs_f64(1.0 / frames_per_second),
+            next_frame: now,
+            next_present: now,
+            base_speed: 1.0,
+            fast_forward: false,
+            slow_motion: false,
+        }
+    }
+
+    /// Switch to another region's frame rate, keeping fast-forward and slow motion as they are
+    pub fn set_frame_rate(&mut self, frames_per_second: f64) {
+        self.frame_time = Duration::from_secs_f64(1.0 / frames_per_second);
+    }
+
+    pub fn config(&self) -> &PacingConfig {
+        &self.config
+    }
+
+    /// Run at `speed` times real time when neither fast-forwarding nor in slow motion
+    pub fn set_speed(&mut self, speed: f64) {
+        self.base_speed = speed;
+    }
+
+    /// Fast-forward is held rather than toggled
+    pub fn set_fast_forward(&mut self, held: bool) {
+        self.fast_forward = held;
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.slow_motion = !self.slow_motion;
+    }
+
+    /// Speed relative to the console, None when uncapped
+    pub fn speed(&self) -> Option<f64> {
+        match (self.fast_forward, self.config.fast_forward) {
+            (true, FastForward::Uncapped) => None,
+            (true, FastForward::Times(times)) => Some(self.base_speed * times.max(1) as f64),
+            (false, _) if self.slow_motion => {
+                Some(self.base_speed / self.config.slow_motion.max(1) as f64)
+            }
+            (false, _) => Some(self.base_speed),
+        }
+    }
+
+    /// Whether audio should pace emulation. The audio is resampled for the emulation speed, so
+    /// it can't while fast-forwarding or in slow motion.
+    pub fn syncs_to_audio(&self) -> bool {
+        self.config.sync == SyncMode::Audio && !self.fast_forward && !self.slow_motion
+    }
+
+    /// Whether the frame that just ran should be shown. Everything is shown at normal speed or
+    /// slower, fast-forward drops frames down to the normal rate.
+    pub fn should_present(&mut self, now: Instant) -> bool {
+        if self.speed().is_some_and(|speed| speed <= 1.0) {
+            self.next_present = now;
+            return true;
+        }
+        if now < self.next_present {
+            return false;
+        }
+        self.next_present = (self.next_present + self.frame_time).max(now);
+        true
+    }
+
+    /// How long to wait after a frame at `now`. `audio_queued` is how many samples are
+    /// waiting to be played and `audio_target` how many there should be, or None without an
+    /// audio device.
+    pub fn frame_delay(
+        &mut self,
+        now: Instant,
+        audio_queued: Option<usize>,
+        audio_target: usize,
+        sample_rate: u32,
+    ) -> Duration {
+        let Some(speed) = self.speed() else {
+            self.next_frame = now;
+            return Duration::ZERO;
+        };
+        if self.syncs_to_audio() {
+            if let Some(queued) = audio_queued {
+                self.next_frame = now;
+                let ahead = queued.saturating_sub(audio_target);
+                // never stall for long if the sound card stops draining
+                return Duration::from_secs_f64(ahead as f64 / sample_rate.max(1) as f64)
+                    .min(self.frame_time * 2);
+            }
+        }
+        if self.config.vsync && speed == 1.0 {
+            self.next_frame = now;
+            return Duration::ZERO;
+        }
+
+        self.next_frame += self.frame_time.div_f64(speed);
+        if self.next_frame > now {
+            self.next_frame - now
+        } else {
+            if now - self.next_frame > self.frame_time {
+                // fell more than a frame behind, don't try to catch up
+                self.next_frame = now;
+            }
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FPS: f64 = 50.0;
+    const FRAME: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn timer_paces_at_frame_rate() {
+        let mut scheduler = FrameScheduler::new(PacingConfig::default(), FPS);
+        let start = scheduler.next_frame;
+        assert_eq!(scheduler.frame_delay(start, None, 0, 48000), FRAME);
+        // a slow frame shortens the next wait
+        let late = start + FRAME + Duration::from_millis(5);
+        assert_eq!(
+            scheduler.frame_delay(late, None, 0, 48000),
+            FRAME - Duration::from_millis(5)
+        );
+        // far behind resets instead of racing to catch up
+        let stalled = start + FRAME * 10;
+        assert_eq!(
+            scheduler.frame_delay(stalled, None, 0, 48000),
+            Duration::ZERO
+        );
+        assert_eq!(scheduler.frame_delay(stalled, None, 0, 48000), FRAME);
+    }
+
+    #[test]
+    fn fast_forward_and_slow_motion() {
+        let config = PacingConfig {
+            fast_forward: FastForward::Times(4),
+            ..PacingConfig::default()
+        };
+        let mut scheduler = FrameScheduler::new(config, FPS);
+        let start = scheduler.next_frame;
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.speed(), Some(4.0));
+        assert_eq!(scheduler.frame_delay(start, None, 0, 48000), FRAME / 4);
+
+        scheduler.set_fast_forward(false);
+        scheduler.toggle_slow_motion();
+        assert_eq!(scheduler.speed(), Some(0.25));
+
+        let mut uncapped = FrameScheduler::new(PacingConfig::default(), FPS);
+        uncapped.set_fast_forward(true);
+        assert_eq!(uncapped.speed(), None);
+        assert_eq!(uncapped.frame_delay(start, None, 0, 48000), Duration::ZERO);
+    }
+
+    #[test]
+    fn emulation_speed_scales_the_frame_rate() {
+        let config = PacingConfig {
+            sync: SyncMode::Audio,
+            fast_forward: FastForward::Times(2),
+            ..PacingConfig::default()
+        };
+        let mut scheduler = FrameScheduler::new(config, FPS);
+        let start = scheduler.next_frame;
+        scheduler.set_speed(0.5);
+        assert_eq!(scheduler.speed(), Some(0.5));
+        assert!(scheduler.syncs_to_audio());
+        assert_eq!(scheduler.frame_delay(start, None, 0, 48000), FRAME * 2);
+        scheduler.set_fast_forward(true);
+        assert_eq!(scheduler.speed(), Some(1.0));
+        assert!(!scheduler.syncs_to_audio());
+    }
+
+    #[test]
+    fn speed_steps() {
+        assert_eq!(step_speed(1.0, true), 1.25);
+        assert_eq!(step_speed(1.0, false), 0.75);
+        // off the steps goes to the nearest one that way
+        assert_eq!(step_speed(1.1, true), 1.25);
+        assert_eq!(step_speed(1.1, false), 1.0);
+        assert_eq!(step_speed(8.0, true), 8.0);
+        assert_eq!(step_speed(0.25, false), 0.25);
+    }
+
+    #[test]
+    fn fast_forward_drops_frames() {
+        let mut scheduler = FrameScheduler::new(PacingConfig::default(), FPS);
+        let start = Instant::now();
+        assert!(scheduler.should_present(start));
+        scheduler.set_fast_forward(true);
+        assert!(scheduler.should_present(start));
+        assert!(!scheduler.should_present(start + FRAME / 2));
+        assert!(scheduler.should_present(start + FRAME));
+    }
+
+    #[test]
+    fn audio_sync_waits_for_the_buffer() {
+        let config = PacingConfig {
+            sync: SyncMode::Audio,
+            ..PacingConfig::default()
+        };
+        let mut scheduler = FrameScheduler::new(config, FPS);
+        let now = Instant::now();
+        // 480 samples over the target at 48kHz is 10ms
+        assert_eq!(
+            scheduler.frame_delay(now, Some(2880), 2400, 48000),
+            Duration::from_millis(10)
+        );
+        assert_eq!(
+            scheduler.frame_delay(now, Some(100), 2400, 48000),
+            Duration::ZERO
+        );
+        // no device, back to the timer
+        assert_eq!(scheduler.frame_delay(now, None, 2400, 48000), FRAME);
+    }
+}
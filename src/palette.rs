@@ -0,0 +1,149 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// https://www.nesdev.org/wiki/PPU_palettes
+// The PPU outputs a 6 bit colour index plus the 3 PPUMASK emphasis bits, see
+// `Frame`. A `Palette` turns those 9 bit pixels into RGB.
+pub const COLOURS: usize = 64;
+pub const EMPHASIS_COMBINATIONS: usize = 8;
+pub const PIXEL_VALUES: usize = COLOURS * EMPHASIS_COMBINATIONS;
+
+// emphasis bits as they sit in a pixel (PPUMASK bits 5-7 shifted down by 5)
+const EMPHASIS_RED: usize = 0b001;
+const EMPHASIS_GREEN: usize = 0b010;
+const EMPHASIS_BLUE: usize = 0b100;
+// how much an emphasised colour darkens the other channels
+const ATTENUATION: f32 = 0.816328;
+
+#[rustfmt::skip]
+const DEFAULT_COLOURS: [[u8; 3]; COLOURS] = [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+];
+
+/// RGB colours for every colour index and emphasis combination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::from_colours(&DEFAULT_COLOURS)
+    }
+}
+
+impl Palette {
+    /// Build a palette from the 64 base colours, deriving the emphasised variants
+    pub fn from_colours(base: &[[u8; 3]; COLOURS]) -> Self {
+        let mut colours = Vec::with_capacity(PIXEL_VALUES);
+        for emphasis in 0..EMPHASIS_COMBINATIONS {
+            colours.extend(base.iter().map(|&colour| Self::emphasise(colour, emphasis)));
+        }
+        Palette { colours }
+    }
+
+    fn emphasise(colour: [u8; 3], emphasis: usize) -> [u8; 3] {
+        if emphasis == 0 {
+            return colour;
+        }
+        let mut out = colour;
+        for (channel, bit) in [EMPHASIS_RED, EMPHASIS_GREEN, EMPHASIS_BLUE]
+            .iter()
+            .enumerate()
+        {
+            if emphasis & bit == 0 {
+                out[channel] = (out[channel] as f32 * ATTENUATION) as u8;
+            }
+        }
+        out
+    }
+
+    /// Parse an FCEUX style .pal file: 64 RGB triples (192 bytes), or 512 (1536 bytes)
+    /// when the file also covers every emphasis combination
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        match bytes.len() {
+            len if len == COLOURS * 3 => {
+                let mut base = [[0u8; 3]; COLOURS];
+                base.iter_mut()
+                    .zip(bytes.chunks_exact(3))
+                    .for_each(|(colour, rgb)| colour.copy_from_slice(rgb));
+                Ok(Self::from_colours(&base))
+            }
+            len if len == PIXEL_VALUES * 3 => Ok(Palette {
+                colours: bytes
+                    .chunks_exact(3)
+                    .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                    .collect(),
+            }),
+            len => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Palette files are {} or {} bytes, got {}",
+                    COLOURS * 3,
+                    PIXEL_VALUES * 3,
+                    len
+                ),
+            )),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// RGB for a pixel from the frame buffer
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colours[pixel as usize % PIXEL_VALUES]
+    }
+
+    /// The palette in the 512 colour .pal layout
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.colours.iter().flatten().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palette.rgb(0x0D), [0x00, 0x00, 0x00]);
+        // red emphasis keeps red and darkens green and blue
+        let [r, g, b] = palette.rgb((EMPHASIS_RED as u16) << 6 | 0x30);
+        assert_eq!(r, 0xFF);
+        assert!(g < 0xFF && b < 0xFF);
+    }
+
+    #[test]
+    fn pal_files() {
+        let mut bytes = vec![0u8; COLOURS * 3];
+        bytes[3..6].copy_from_slice(&[1, 2, 3]);
+        let palette = Palette::from_bytes(&bytes).unwrap();
+        assert_eq!(palette.rgb(0x01), [1, 2, 3]);
+        assert_eq!(palette.to_bytes().len(), PIXEL_VALUES * 3);
+
+        let full = Palette::from_bytes(&palette.to_bytes()).unwrap();
+        assert_eq!(full, palette);
+
+        assert!(Palette::from_bytes(&[0; 10]).is_err());
+    }
+}
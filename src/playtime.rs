@@ -0,0 +1,177 @@
+use crate::storage::StorageBackend;
+use std::io;
+use std::time::{Duration, Instant};
+
+// Play time is wall clock time with a game running, stopped while it's paused, so leaving the
+// emulator paused overnight doesn't count. It's kept per game along with the frames run and
+// how many times the game was started, in the save storage under "<game>.played" as a few
+// "name value" lines:
+//
+//   played_ms 5025000
+//   frames 301500
+//   sessions 3
+//
+// Unknown lines are skipped, so older builds can read what newer ones write.
+
+/// A stopwatch that only runs while the game does
+#[derive(Debug, Clone, Default)]
+pub struct PlayClock {
+    // time from the stretches that have ended
+    played: Duration,
+    // when the current stretch started, None while stopped
+    since: Option<Instant>,
+}
+
+impl PlayClock {
+    /// A stopped clock at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start or stop the clock, doing nothing when it's already that way
+    pub fn set_running(&mut self, running: bool, now: Instant) {
+        match (running, self.since) {
+            (true, None) => self.since = Some(now),
+            (false, Some(since)) => {
+                self.played += now.saturating_duration_since(since);
+                self.since = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// Time run so far, up to `now`
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        self.played
+            + self
+                .since
+                .map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+}
+
+/// What's been played of one game, over every session
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PlayStats {
+    pub played: Duration,
+    pub frames: u64,
+    pub sessions: u32,
+}
+
+impl PlayStats {
+    /// Storage key for `game`'s stats
+    pub fn key(game: &str) -> String {
+        format!("{}.played", game)
+    }
+
+    /// `game`'s stats, zero when it's never been played
+    pub fn load(storage: &dyn StorageBackend, game: &str) -> io::Result<PlayStats> {
+        let stats = storage.load(&Self::key(game))?;
+        Ok(stats.map_or_else(PlayStats::default, |text| {
+            Self::parse(&String::from_utf8_lossy(&text))
+        }))
+    }
+
+    pub fn save(&self, storage: &mut dyn StorageBackend, game: &str) -> io::Result<()> {
+        storage.save(&Self::key(game), self.to_text().as_bytes())
+    }
+
+    pub fn parse(text: &str) -> PlayStats {
+        let mut stats = PlayStats::default();
+        for line in text.lines() {
+            let Some((name, value)) = line.trim().split_once(' ') else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<u64>() else {
+                continue;
+            };
+            match name {
+                "played_ms" => stats.played = Duration::from_millis(value),
+                "frames" => stats.frames = value,
+                "sessions" => stats.sessions = value.min(u32::MAX as u64) as u32,
+                _ => {}
+            }
+        }
+        stats
+    }
+
+    pub fn to_text(&self) -> String {
+        format!(
+            "played_ms {}\nframes {}\nsessions {}\n",
+            self.played.as_millis(),
+            self.frames,
+            self.sessions
+        )
+    }
+}
+
+/// "1h 02m 05s", "2m 05s" or "5s"
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn clock_stops_while_paused() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut clock = PlayClock::new();
+        assert_eq!(clock.elapsed(at(5)), Duration::ZERO);
+
+        clock.set_running(true, at(0));
+        clock.set_running(true, at(3));
+        assert_eq!(clock.elapsed(at(10)), Duration::from_secs(10));
+        clock.set_running(false, at(10));
+        assert_eq!(clock.elapsed(at(100)), Duration::from_secs(10));
+        clock.set_running(true, at(100));
+        assert_eq!(clock.elapsed(at(105)), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn stats_round_trip_through_storage() {
+        let mut storage = MemoryStorage::new();
+        assert_eq!(
+            PlayStats::load(&storage, "smb").unwrap(),
+            PlayStats::default()
+        );
+        let stats = PlayStats {
+            played: Duration::from_millis(5_025_000),
+            frames: 301_500,
+            sessions: 3,
+        };
+        stats.save(&mut storage, "smb").unwrap();
+        assert_eq!(PlayStats::load(&storage, "smb").unwrap(), stats);
+        assert_eq!(
+            PlayStats::parse("sessions 2\nfuture 7\nframes nope\n"),
+            PlayStats {
+                sessions: 2,
+                ..PlayStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m 05s");
+        assert_eq!(
+            format_duration(Duration::from_millis(3_725_900)),
+            "1h 02m 05s"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+// https://www.nesdev.org/wiki/CPU_power_up_state
+// What RAM and the registers hold when the console is switched on. On hardware RAM comes up
+// in a pattern that depends on the chips and how long the console was off, and a few games
+// read it before writing it (usually to seed a random number generator). The default is all
+// zeros, which is what most emulators do and what test roms expect. Random RAM, and the jitter
+// in when the chips start, come from the emulator's seed, so fixing it makes runs reproducible
+// for TAS and replay.
+
+use crate::rng::Rng;
+
+/// How RAM is filled at power on
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum RamPattern {
+    #[default]
+    Zeros,
+    Ones,
+    /// Four bytes of $00 then four of $FF, repeated, as FCEUX does
+    Alternating,
+    /// Pseudo random bytes from the emulator's seed, the same seed always gives the same RAM
+    Random,
+}
+
+impl RamPattern {
+    /// "zeros", "ones", "alternating" or "random"
+    pub fn parse(text: &str) -> Option<RamPattern> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "zeros" => Some(RamPattern::Zeros),
+            "ones" => Some(RamPattern::Ones),
+            "alternating" => Some(RamPattern::Alternating),
+            "random" => Some(RamPattern::Random),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RamPattern::Zeros => "zeros",
+            RamPattern::Ones => "ones",
+            RamPattern::Alternating => "alternating",
+            RamPattern::Random => "random",
+        }
+    }
+
+    pub fn fill(&self, ram: &mut [u8], rng: &mut Rng) {
+        match *self {
+            RamPattern::Zeros => ram.fill(0x00),
+            RamPattern::Ones => ram.fill(0xFF),
+            RamPattern::Alternating => {
+                for (address, byte) in ram.iter_mut().enumerate() {
+                    *byte = if address & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamPattern::Random => rng.fill(ram),
+        }
+    }
+}
+
+/// The console's state at power on
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PowerOnConfig {
+    /// Internal RAM at $0000-$07FF
+    pub ram: RamPattern,
+    pub accumulator: u8,
+    pub x: u8,
+    pub y: u8,
+    /// Value the APU frame counter ($4017) starts as if written with. The 2A03 behaves as if
+    /// $00 was written: 4 step mode with the IRQ enabled.
+    pub frame_counter: u8,
+    /// Seeds everything random about powering on
+    pub seed: u64,
+    /// Start the PPU a few dots into the CPU's cycle and the APU on either half of its own, and
+    /// give the PPU's open bus a decay time of its own, as differs from one console or power on
+    /// to the next. Off, every power on lines up the same, as test ROMs expect.
+    pub jitter: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills() {
+        let mut ram = [0x55u8; 16];
+        let mut rng = Rng::new(0);
+        RamPattern::Ones.fill(&mut ram, &mut rng);
+        assert_eq!(ram, [0xFF; 16]);
+        RamPattern::Alternating.fill(&mut ram, &mut rng);
+        assert_eq!(ram[..8], [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        RamPattern::Zeros.fill(&mut ram, &mut rng);
+        assert_eq!(ram, [0; 16]);
+    }
+
+    #[test]
+    fn random_depends_only_on_the_seed() {
+        let fill = |seed| {
+            let mut ram = [0u8; 64];
+            RamPattern::Random.fill(&mut ram, &mut Rng::new(seed));
+            ram
+        };
+        assert_eq!(fill(1), fill(1));
+        assert_ne!(fill(1), fill(2));
+        assert!(fill(0).iter().any(|&byte| byte != 0));
+    }
+
+    #[test]
+    fn parses_names() {
+        for pattern in [
+            RamPattern::Zeros,
+            RamPattern::Ones,
+            RamPattern::Alternating,
+            RamPattern::Random,
+        ] {
+            assert_eq!(RamPattern::parse(pattern.name()), Some(pattern));
+        }
+        assert_eq!(RamPattern::parse("garbage"), None);
+    }
+}
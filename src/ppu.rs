@@ -0,0 +1,2004 @@
+use crate::events::{Event, Events};
+use crate::logging;
+use crate::mapper::Mapper;
+use crate::palette::Palette;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use crate::snapshot::PpuState;
+use log::debug;
+use sha1::{Digest, Sha1};
+
+// https://www.nesdev.org/wiki/PPU
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+// https://www.nesdev.org/wiki/PPU_rendering
+// 341 dots per scanline, frame length and vblank placement depend on the `Region`
+pub const DOTS_PER_SCANLINE: u16 = 341;
+
+// https://www.nesdev.org/wiki/PPU_registers
+// PPUCTRL ($2000)
+const CTRL_NAMETABLE: u8 = 0b0000_0011;
+const CTRL_INCREMENT_32: u8 = 0b0000_0100;
+const CTRL_SPRITE_TABLE: u8 = 0b0000_1000;
+const CTRL_BACKGROUND_TABLE: u8 = 0b0001_0000;
+const CTRL_SPRITE_SIZE_16: u8 = 0b0010_0000;
+const CTRL_NMI_ENABLE: u8 = 0b1000_0000;
+// PPUMASK ($2001)
+const MASK_GRAYSCALE: u8 = 0b0000_0001;
+const MASK_SHOW_BACKGROUND_LEFT: u8 = 0b0000_0010;
+const MASK_SHOW_SPRITES_LEFT: u8 = 0b0000_0100;
+const MASK_SHOW_BACKGROUND: u8 = 0b0000_1000;
+const MASK_SHOW_SPRITES: u8 = 0b0001_0000;
+// red, green and blue emphasis, carried into every output pixel above the colour index
+const MASK_EMPHASIS: u8 = 0b1110_0000;
+const EMPHASIS_SHIFT: u16 = 6;
+// PPUSTATUS ($2002)
+const STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+const STATUS_SPRITE_0_HIT: u8 = 0b0100_0000;
+const STATUS_VBLANK: u8 = 0b1000_0000;
+const STATUS_BITS: u8 = 0b1110_0000;
+
+// https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+// Register reads fill the bits they don't drive from an internal latch, which holds the last
+// value on the PPU data bus. Each bit fades to 0 roughly 600ms after it was last driven.
+pub const IO_BUS_DECAY_FRAMES: u64 = 36;
+
+// https://www.nesdev.org/wiki/PPU_OAM
+// 64 sprites, 4 bytes each: y, tile, attributes, x
+pub const OAM_SIZE: usize = 256;
+const SPRITES_PER_LINE: usize = 8;
+const ATTR_PALETTE: u8 = 0b0000_0011;
+const ATTR_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+const ATTR_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const ATTR_FLIP_VERTICAL: u8 = 0b1000_0000;
+// the bits of the attribute byte OAM actually has
+const ATTR_BITS: u8 = 0b1110_0011;
+
+// PPU address space
+// $0000-$1FFF pattern tables (cartridge)
+// $2000-$2FFF nametables, $3000-$3EFF mirrors them
+// $3F00-$3F1F palette RAM, mirrored up to $3FFF
+const NAMETABLE_SIZE: usize = 0x400;
+const ATTRIBUTE_OFFSET: u16 = 0x3C0;
+
+// https://www.nesdev.org/wiki/PPU_scrolling
+// v and t are laid out as yyy NN YYYYY XXXXX (fine y, nametable, coarse y, coarse x)
+const COARSE_X: u16 = 0x001F;
+const COARSE_Y: u16 = 0x03E0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+const HORIZONTAL_BITS: u16 = COARSE_X | NAMETABLE_X;
+const VERTICAL_BITS: u16 = COARSE_Y | NAMETABLE_Y | FINE_Y;
+const PALETTE_ADDR: u16 = 0x3F00;
+const SPRITE_PALETTE_ADDR: u16 = 0x3F10;
+
+// debug viewer sizes, the buffers are RGB with 3 bytes per pixel
+// both pattern tables side by side, 16x16 tiles each
+pub const PATTERN_VIEW_WIDTH: usize = 256;
+pub const PATTERN_VIEW_HEIGHT: usize = 128;
+// the four logical nametables in a 2x2 grid
+pub const NAMETABLE_VIEW_WIDTH: usize = WIDTH * 2;
+pub const NAMETABLE_VIEW_HEIGHT: usize = HEIGHT * 2;
+// one pixel per palette entry, background palettes on the first row, sprites on the second
+pub const PALETTE_VIEW_WIDTH: usize = 16;
+pub const PALETTE_VIEW_HEIGHT: usize = 2;
+
+/// Debug override for drawing a layer, it only changes the picture: sprite 0 hit and
+/// sprite overflow still follow what the game set in PPUMASK
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum LayerOverride {
+    /// Drawn when PPUMASK enables it
+    #[default]
+    Game,
+    ForceOn,
+    ForceOff,
+}
+
+impl LayerOverride {
+    fn apply(&self, enabled: bool) -> bool {
+        match self {
+            LayerOverride::Game => enabled,
+            LayerOverride::ForceOn => true,
+            LayerOverride::ForceOff => false,
+        }
+    }
+}
+
+// https://www.nesdev.org/wiki/PPU_sprite_evaluation#Sprite_overflow_bug
+/// How the search for a ninth sprite on a line sets the sprite overflow flag
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpriteOverflow {
+    /// As the 2C02 does it: after each sprite that isn't on the line the search moves to the
+    /// next byte of the next sprite as well, so it compares tile numbers, attributes and X
+    /// positions with the line, missing sprites that are there and finding ones that aren't
+    #[default]
+    Hardware,
+    /// Set whenever more than 8 sprites are on the line
+    Correct,
+}
+
+impl SpriteOverflow {
+    pub fn parse(text: &str) -> Option<SpriteOverflow> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "hardware" => Some(SpriteOverflow::Hardware),
+            "correct" => Some(SpriteOverflow::Correct),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpriteOverflow::Hardware => "hardware",
+            SpriteOverflow::Correct => "correct",
+        }
+    }
+}
+
+/// A sprite selected for the current scanline with its pattern row already fetched
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct LineSprite {
+    x: u8,
+    low: u8,
+    high: u8,
+    attributes: u8,
+    sprite_zero: bool,
+}
+
+/// A picture from the PPU, `WIDTH` x `HEIGHT` pixels, row major. Bits 0-5 of a pixel are the
+/// NES colour index and bits 6-8 the PPUMASK emphasis bits, `Palette::rgb` turns them into
+/// colours.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::boxed_array"))]
+    pixels: Box<[u16; WIDTH * HEIGHT]>,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Frame {
+            pixels: vec![0u16; WIDTH * HEIGHT]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
+        }
+    }
+
+    pub fn pixels(&self) -> &[u16] {
+        self.pixels.as_slice()
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    /// The whole frame as RGB24
+    pub fn to_rgb(&self, palette: &Palette) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&pixel| palette.rgb(pixel))
+            .collect()
+    }
+
+    /// SHA-1 of the pixels as little endian bytes, the same on every machine
+    pub fn sha1(&self) -> [u8; 20] {
+        let mut sha1 = Sha1::new();
+        for pixel in self.pixels() {
+            sha1.update(pixel.to_le_bytes());
+        }
+        sha1.finalize().into()
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ppu {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    // current VRAM address, also the scroll position while rendering
+    v: u16,
+    // temporary VRAM address, the top left of the screen
+    t: u16,
+    fine_x: u8,
+    // shared first/second write toggle for $2005 and $2006 (w)
+    write_latch: bool,
+    // PPUDATA reads return the byte fetched by the previous read
+    read_buffer: u8,
+    io_bus: u8,
+    // frame each io bus bit was last driven on, for decay
+    io_bus_driven: [u64; 8],
+    // frames a bit takes to decay, which differs between consoles
+    io_bus_decay: u64,
+    // $2002 was read the dot before vblank starts, so this frame's flag and NMI never happen
+    suppress_vblank: bool,
+    // 2KB on the console, 4KB so four-screen carts can use the rest
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::array"))]
+    vram: [u8; NAMETABLE_SIZE * 4],
+    palette: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::array"))]
+    oam: [u8; OAM_SIZE],
+    oam_addr: u8,
+    // secondary OAM for the scanline being drawn
+    line_sprites: [LineSprite; SPRITES_PER_LINE],
+    line_sprite_count: usize,
+    frame: Frame,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    background_override: LayerOverride,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sprite_override: LayerOverride,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sprite_overflow_mode: SpriteOverflow,
+
+    region: Region,
+    dot: u16,
+    scanline: u16,
+    frame_count: u64,
+    odd_frame: bool,
+    nmi_pending: bool,
+    // where this frame's sprite 0 hit was, scanline and the dot its pixel is drawn on
+    sprite_zero_hit_at: Option<(u16, u16)>,
+    frame_complete: bool,
+    // after the reset button, $2000, $2001, $2005 and $2006 ignore writes until the pre-render
+    // line
+    resetting: bool,
+    // not part of save states, what happened is only news once
+    #[cfg_attr(feature = "serde", serde(skip))]
+    events: Events,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_latch: false,
+            read_buffer: 0,
+            io_bus: 0,
+            io_bus_driven: [0; 8],
+            io_bus_decay: IO_BUS_DECAY_FRAMES,
+            suppress_vblank: false,
+            vram: [0u8; NAMETABLE_SIZE * 4],
+            palette: [0u8; 32],
+            oam: [0u8; OAM_SIZE],
+            oam_addr: 0,
+            line_sprites: [LineSprite::default(); SPRITES_PER_LINE],
+            line_sprite_count: 0,
+            frame: Frame::new(),
+            background_override: LayerOverride::default(),
+            sprite_override: LayerOverride::default(),
+            sprite_overflow_mode: SpriteOverflow::default(),
+            region: Region::default(),
+            dot: 0,
+            scanline: 0,
+            frame_count: 0,
+            odd_frame: false,
+            nmi_pending: false,
+            sprite_zero_hit_at: None,
+            frame_complete: false,
+            resetting: false,
+            events: Events::new(),
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // https://www.nesdev.org/wiki/PPU_power_up_state
+    /// The reset button: PPUCTRL, PPUMASK, the scroll and the write toggle are cleared and
+    /// stay that way until the pre-render line, while VRAM, OAM and the palette are kept
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.write_latch = false;
+        self.read_buffer = 0;
+        self.t = 0;
+        self.fine_x = 0;
+        self.odd_frame = false;
+        self.resetting = true;
+    }
+
+    /// How many frames open bus bits take to fade once they stop being driven
+    pub fn set_io_bus_decay(&mut self, frames: u64) {
+        self.io_bus_decay = frames;
+    }
+
+    /// Switch console timing, restarting the frame from the top
+    pub fn set_region(&mut self, region: Region) {
+        debug!(target: logging::PPU, "{:?} timing", region);
+        self.region = region;
+        self.scanline = 0;
+        self.dot = 0;
+    }
+
+    /// Advance the PPU by one dot
+    pub fn tick(&mut self, mapper: &dyn Mapper) {
+        let vblank_scanline = self.region.vblank_scanline();
+        let pre_render_scanline = self.region.pre_render_scanline();
+        if self.dot == 0 {
+            self.events.push(Event::ScanlineStart(self.scanline));
+        }
+        match (self.scanline, self.dot) {
+            // the whole line is drawn from v before dot 256 moves it down a row
+            (0..=239, 256) => self.render_scanline(self.scanline as usize, mapper),
+            (scanline, 1) if scanline == vblank_scanline => {
+                if !std::mem::take(&mut self.suppress_vblank) {
+                    self.status |= STATUS_VBLANK;
+                    if self.nmi_enabled() {
+                        self.nmi_pending = true;
+                    }
+                }
+                self.frame_complete = true;
+                self.events.push(Event::VBlankStart);
+                self.events.push(Event::FrameComplete);
+            }
+            (scanline, 1) if scanline == pre_render_scanline => {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_0_HIT | STATUS_SPRITE_OVERFLOW);
+                self.sprite_zero_hit_at = None;
+                self.resetting = false;
+                if self.rendering_enabled() {
+                    self.corrupt_oam();
+                }
+            }
+            _ => {}
+        }
+
+        if self.rendering_enabled() && self.is_render_line() {
+            match self.dot {
+                256 => self.increment_y(),
+                257 => self.copy_horizontal(),
+                280..=304 if self.scanline == pre_render_scanline => self.copy_vertical(),
+                _ => {}
+            }
+            // sprite tile fetches leave OAMADDR at 0
+            if (257..=320).contains(&self.dot) {
+                self.oam_addr = 0;
+            }
+        }
+
+        self.dot += 1;
+        // odd frames skip the last dot of the pre-render line while rendering is on
+        if self.scanline == pre_render_scanline
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.odd_frame
+            && self.rendering_enabled()
+            && self.region.skips_odd_frame_dot()
+        {
+            self.dot = DOTS_PER_SCANLINE;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.region.scanlines_per_frame() {
+                self.scanline = 0;
+                self.frame_count += 1;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+    }
+
+    fn is_render_line(&self) -> bool {
+        self.scanline < HEIGHT as u16 || self.scanline == self.region.pre_render_scanline()
+    }
+
+    // the PPU is reading OAM for sprites, so the CPU can't get at it normally
+    fn is_rendering(&self) -> bool {
+        self.rendering_enabled() && self.is_render_line()
+    }
+
+    // Lines are drawn in one go, so coarse x isn't stepped every 8 dots and v keeps
+    // pointing at the first tile of the line. A v written during hblank is still where the
+    // next line starts, as on hardware once the two prefetched tiles are accounted for.
+
+    // https://www.nesdev.org/wiki/PPU_scrolling#Coarse_X_increment
+    fn increment_coarse_x(v: u16) -> u16 {
+        if v & COARSE_X == 31 {
+            (v & !COARSE_X) ^ NAMETABLE_X
+        } else {
+            v + 1
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_scrolling#Y_increment
+    fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let mut coarse_y = (self.v & COARSE_Y) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            // rows 30 and 31 are attribute data, wrapping there doesn't switch nametable
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !HORIZONTAL_BITS) | (self.t & HORIZONTAL_BITS);
+    }
+
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !VERTICAL_BITS) | (self.t & VERTICAL_BITS);
+    }
+
+    /// Whether NMI is up and the CPU hasn't taken it yet
+    pub fn nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    /// True once per NMI, the CPU acknowledges it by taking it
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    /// True once per frame, when vblank starts
+    pub fn take_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    /// Beam events subscribed to, mapper IRQs are queued here too
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn events_mut(&mut self) -> &mut Events {
+        &mut self.events
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// The registers and position, see `Emulator::snapshot`
+    pub fn snapshot(&self) -> PpuState {
+        PpuState {
+            ctrl: self.ctrl,
+            mask: self.mask,
+            status: self.status,
+            v: self.v,
+            t: self.t,
+            fine_x: self.fine_x,
+            write_latch: self.write_latch,
+            oam_addr: self.oam_addr,
+            scanline: self.scanline,
+            dot: self.dot,
+            odd_frame: self.odd_frame,
+        }
+    }
+
+    /// CPU read of $2000-$2007 (callers mirror $2008-$3FFF down)
+    pub fn read_register(&mut self, address: u16, mapper: &mut dyn Mapper) -> u8 {
+        match address & 0x7 {
+            // PPUSTATUS
+            0x2 => {
+                let vblank_scanline = self.region.vblank_scanline();
+                if self.scanline == vblank_scanline {
+                    match self.dot {
+                        // reading just before the flag is set means it's never seen this frame
+                        1 => self.suppress_vblank = true,
+                        // reading as it's set still returns it but cancels the NMI
+                        2..=3 => self.nmi_pending = false,
+                        _ => {}
+                    }
+                }
+                let status = self.status;
+                self.status &= !STATUS_VBLANK;
+                self.write_latch = false;
+                self.drive_io_bus(status, STATUS_BITS)
+            }
+            // OAMDATA, reads don't increment OAMADDR
+            0x4 => self.drive_io_bus(self.oam_data(), 0xFF),
+            // PPUDATA
+            0x7 => {
+                let palette = self.v & 0x3FFF >= PALETTE_ADDR;
+                let byte = self.read_data(mapper);
+                self.increment_addr();
+                // palette entries are 6 bits, the top two come from the bus
+                self.drive_io_bus(byte, if palette { 0x3F } else { 0xFF })
+            }
+            // write only registers
+            _ => self.io_bus(),
+        }
+    }
+
+    /// Register read without side effects, for debuggers
+    pub fn peek_register(&self, address: u16, mapper: &dyn Mapper) -> u8 {
+        let merge = |byte: u8, driven: u8| (byte & driven) | (self.io_bus() & !driven);
+        match address & 0x7 {
+            0x2 => merge(self.status, STATUS_BITS),
+            0x4 => self.oam_data(),
+            0x7 if self.v & 0x3FFF >= PALETTE_ADDR => merge(self.peek_data(mapper), 0x3F),
+            0x7 => self.peek_data(mapper),
+            _ => self.io_bus(),
+        }
+    }
+
+    /// The io bus latch with decayed bits cleared
+    pub fn io_bus(&self) -> u8 {
+        (0..8)
+            .filter(|&bit| self.frame_count - self.io_bus_driven[bit] < self.io_bus_decay)
+            .fold(0, |bus, bit| bus | (self.io_bus & (1 << bit)))
+    }
+
+    /// Put the `driven` bits of `byte` on the io bus, the result is what the CPU reads
+    fn drive_io_bus(&mut self, byte: u8, driven: u8) -> u8 {
+        self.io_bus = (byte & driven) | (self.io_bus() & !driven);
+        for bit in (0..8).filter(|bit| driven & (1 << bit) != 0) {
+            self.io_bus_driven[bit] = self.frame_count;
+        }
+        self.io_bus
+    }
+
+    /// CPU write of $2000-$2007
+    pub fn write_register(&mut self, address: u16, byte: u8, mapper: &mut dyn Mapper) {
+        self.drive_io_bus(byte, 0xFF);
+        match address & 0x7 {
+            0x0 | 0x1 | 0x5 | 0x6 if self.resetting => {
+                debug!(
+                    target: logging::PPU,
+                    "write of ${:02X} to ${:04X} ignored, the PPU is still resetting", byte, address
+                );
+            }
+            0x0 => {
+                // enabling NMI during vblank fires one straight away
+                if self.ctrl & CTRL_NMI_ENABLE == 0
+                    && byte & CTRL_NMI_ENABLE != 0
+                    && self.in_vblank()
+                {
+                    self.nmi_pending = true;
+                }
+                self.ctrl = byte;
+                self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y))
+                    | (((byte & CTRL_NAMETABLE) as u16) << 10);
+            }
+            0x1 => self.mask = byte,
+            // OAMADDR
+            0x3 => self.oam_addr = byte,
+            // OAMDATA
+            0x4 => self.write_oam(byte),
+            // PPUSCROLL
+            0x5 => {
+                let byte = byte as u16;
+                if self.write_latch {
+                    self.t =
+                        (self.t & !(COARSE_Y | FINE_Y)) | ((byte >> 3) << 5) | ((byte & 0x7) << 12);
+                } else {
+                    self.t = (self.t & !COARSE_X) | byte >> 3;
+                    self.fine_x = byte as u8 & 0x7;
+                }
+                self.write_latch = !self.write_latch;
+            }
+            // PPUADDR, high byte first, v only changes on the second write
+            0x6 => {
+                if self.write_latch {
+                    self.t = (self.t & 0xFF00) | byte as u16;
+                    self.v = self.t;
+                } else {
+                    // bit 14 is cleared as well
+                    self.t = ((byte as u16 & 0x3F) << 8) | (self.t & 0x00FF);
+                }
+                self.write_latch = !self.write_latch;
+            }
+            // PPUDATA
+            0x7 => {
+                self.write(self.v, byte, mapper);
+                self.increment_addr();
+            }
+            _ => {}
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_registers#The_PPUDATA_read_buffer
+    fn read_data(&mut self, mapper: &dyn Mapper) -> u8 {
+        let byte = self.peek_data(mapper);
+        // palette reads skip the buffer, which gets the nametable byte underneath instead
+        let address = if self.v & 0x3FFF >= PALETTE_ADDR {
+            self.v & 0x2FFF
+        } else {
+            self.v
+        };
+        self.read_buffer = self.read(address, mapper);
+        byte
+    }
+
+    fn peek_data(&self, mapper: &dyn Mapper) -> u8 {
+        if self.v & 0x3FFF >= PALETTE_ADDR {
+            self.read(self.v, mapper)
+        } else {
+            self.read_buffer
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_registers#OAMDATA
+    // While rendering, the first 64 dots of a line clear secondary OAM and $2004 reads the $FF
+    // being written there. After that it reads whatever byte OAMADDR is on, which is close to
+    // the byte sprite evaluation is looking at without stepping through it dot by dot.
+    fn oam_data(&self) -> u8 {
+        if self.is_rendering() && (1..=64).contains(&self.dot) {
+            0xFF
+        } else {
+            self.oam[self.oam_addr as usize]
+        }
+    }
+
+    fn write_oam(&mut self, byte: u8) {
+        if self.is_rendering() {
+            // the write is lost and only the sprite number of OAMADDR moves on
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
+        // bits 2-4 of the attribute byte don't exist and read back as 0
+        let byte = if self.oam_addr % 4 == 2 {
+            byte & ATTR_BITS
+        } else {
+            byte
+        };
+        self.oam[self.oam_addr as usize] = byte;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    // https://www.nesdev.org/wiki/PPU_registers#OAMADDR
+    // Rendering starting with OAMADDR at 8 or more copies the eight bytes of that row over
+    // the first eight, so sprites 0 and 1 turn into copies of two others
+    fn corrupt_oam(&mut self) {
+        if self.oam_addr >= 8 {
+            let row = (self.oam_addr & 0xF8) as usize;
+            self.oam.copy_within(row..row + 8, 0);
+        }
+    }
+
+    /// OAMDMA ($4014): copy a page of CPU memory into OAM, starting at OAMADDR
+    pub fn oam_dma(&mut self, page: &[u8; OAM_SIZE]) {
+        page.iter().for_each(|&byte| self.write_oam(byte));
+    }
+
+    pub fn oam(&self) -> &[u8; OAM_SIZE] {
+        &self.oam
+    }
+
+    fn increment_addr(&mut self) {
+        // PPUDATA access while rendering bumps coarse x and y together instead
+        if self.rendering_enabled() && self.is_render_line() {
+            self.v = Self::increment_coarse_x(self.v);
+            self.increment_y();
+            return;
+        }
+        let step = if self.ctrl & CTRL_INCREMENT_32 != 0 {
+            32
+        } else {
+            1
+        };
+        self.v = self.v.wrapping_add(step) & 0x3FFF;
+    }
+
+    fn nametable_index(&self, address: u16, mapper: &dyn Mapper) -> usize {
+        let offset = (address as usize - 0x2000) % (NAMETABLE_SIZE * 4);
+        let table = mapper.mirroring().physical_table(offset / NAMETABLE_SIZE);
+        table * NAMETABLE_SIZE + offset % NAMETABLE_SIZE
+    }
+
+    fn palette_index(address: u16) -> usize {
+        let index = (address - PALETTE_ADDR) as usize % 32;
+        // $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+        if index >= 16 && index.is_multiple_of(4) {
+            index - 16
+        } else {
+            index
+        }
+    }
+
+    /// Read from the PPU address space
+    pub fn read(&self, address: u16, mapper: &dyn Mapper) -> u8 {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => mapper.ppu_read(address),
+            0x2000..=0x3EFF => self.vram[self.nametable_index(address, mapper)],
+            _ => self.palette[Self::palette_index(address)],
+        }
+    }
+
+    /// Write to the PPU address space
+    pub fn write(&mut self, address: u16, byte: u8, mapper: &mut dyn Mapper) {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => mapper.ppu_write(address, byte),
+            0x2000..=0x3EFF => {
+                let index = self.nametable_index(address, mapper);
+                self.vram[index] = byte;
+            }
+            _ => self.palette[Self::palette_index(address)] = byte & 0x3F,
+        }
+    }
+
+    pub fn nmi_enabled(&self) -> bool {
+        self.ctrl & CTRL_NMI_ENABLE != 0
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.status & STATUS_VBLANK != 0
+    }
+
+    pub fn set_vblank(&mut self, vblank: bool) {
+        if vblank {
+            self.status |= STATUS_VBLANK;
+        } else {
+            self.status &= !STATUS_VBLANK;
+        }
+    }
+
+    /// Background colour indices for the line addressed by v: 0 when transparent,
+    /// otherwise palette * 4 + pattern value
+    fn background_line(&self, mapper: &dyn Mapper) -> [u8; WIDTH] {
+        let mut line = [0u8; WIDTH];
+        let pattern_table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = (self.v & FINE_Y) >> 12;
+
+        // 33 tiles, fine x can push the line into part of the next one
+        let mut v = self.v;
+        for tile_index in 0..=WIDTH / 8 {
+            let tile = self.read(0x2000 | (v & 0x0FFF), mapper);
+
+            // each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+            let (column, row) = (v & COARSE_X, (v & COARSE_Y) >> 5);
+            let attribute = self.read(
+                0x2000
+                    | (v & (NAMETABLE_X | NAMETABLE_Y))
+                    | ATTRIBUTE_OFFSET
+                    | ((row / 4) << 3)
+                    | (column / 4),
+                mapper,
+            );
+            let shift = ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2;
+            let palette = (attribute >> shift) & 0b11;
+
+            let plane_address = pattern_table + tile as u16 * 16 + fine_y;
+            let low = self.read(plane_address, mapper);
+            let high = self.read(plane_address + 8, mapper);
+
+            for pixel in 0..8 {
+                let x = (tile_index * 8 + pixel).wrapping_sub(self.fine_x as usize);
+                if x >= WIDTH {
+                    continue;
+                }
+                let bit = 7 - pixel;
+                let value = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+                if value != 0 {
+                    line[x] = palette * 4 + value;
+                }
+            }
+            v = Self::increment_coarse_x(v);
+        }
+        line
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.ctrl & CTRL_SPRITE_SIZE_16 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Pick the first 8 sprites in OAM order that cover scanline y and fetch their pattern rows
+    fn evaluate_sprites(&mut self, y: usize, mapper: &dyn Mapper) {
+        let height = self.sprite_height();
+        self.line_sprite_count = 0;
+
+        for sprite in 0..OAM_SIZE / 4 {
+            if self.line_sprite_count == SPRITES_PER_LINE {
+                if self.finds_ninth_sprite(sprite, y, height) {
+                    self.status |= STATUS_SPRITE_OVERFLOW;
+                }
+                break;
+            }
+            let entry = &self.oam[sprite * 4..sprite * 4 + 4];
+            if !Self::covers(entry[0], y, height) {
+                continue;
+            }
+
+            let (tile, attributes, x) = (entry[1], entry[2], entry[3]);
+            let mut row = y - (entry[0] as usize + 1);
+            if attributes & ATTR_FLIP_VERTICAL != 0 {
+                row = height - 1 - row;
+            }
+            let address = if height == 16 {
+                // 8x16 sprites take the pattern table from bit 0 of the tile number
+                let table = (tile as u16 & 1) * 0x1000;
+                let tile = (tile & 0xFE) as u16 + (row / 8) as u16;
+                table + tile * 16 + (row % 8) as u16
+            } else {
+                let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                    0x1000
+                } else {
+                    0
+                };
+                table + tile as u16 * 16 + row as u16
+            };
+
+            let (mut low, mut high) = (self.read(address, mapper), self.read(address + 8, mapper));
+            if attributes & ATTR_FLIP_HORIZONTAL != 0 {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+
+            self.line_sprites[self.line_sprite_count] = LineSprite {
+                x,
+                low,
+                high,
+                attributes,
+                sprite_zero: sprite == 0,
+            };
+            self.line_sprite_count += 1;
+        }
+    }
+
+    // sprite data is delayed by one scanline, OAM y is the line above the sprite
+    fn covers(oam_y: u8, y: usize, height: usize) -> bool {
+        let top = oam_y as usize + 1;
+        (top..top + height).contains(&y)
+    }
+
+    /// Whether the search for more sprites after the eighth, from OAM entry `first` on,
+    /// finds one
+    fn finds_ninth_sprite(&self, first: usize, y: usize, height: usize) -> bool {
+        match self.sprite_overflow_mode {
+            SpriteOverflow::Correct => {
+                (first..OAM_SIZE / 4).any(|sprite| Self::covers(self.oam[sprite * 4], y, height))
+            }
+            SpriteOverflow::Hardware => {
+                // the byte within each entry, which a miss moves on without carrying
+                let mut byte = 0;
+                for sprite in first..OAM_SIZE / 4 {
+                    if Self::covers(self.oam[sprite * 4 + byte], y, height) {
+                        return true;
+                    }
+                    byte = (byte + 1) % 4;
+                }
+                false
+            }
+        }
+    }
+
+    /// First opaque sprite pixel at x, lower OAM index wins
+    fn sprite_pixel(&self, x: usize) -> Option<LineSprite> {
+        self.line_sprites[..self.line_sprite_count]
+            .iter()
+            .find(|sprite| {
+                let column = x.wrapping_sub(sprite.x as usize);
+                column < 8 && Self::sprite_value(sprite, column) != 0
+            })
+            .copied()
+    }
+
+    fn sprite_value(sprite: &LineSprite, column: usize) -> u8 {
+        let bit = 7 - column;
+        ((sprite.low >> bit) & 1) | (((sprite.high >> bit) & 1) << 1)
+    }
+
+    /// Render one visible scanline into the frame buffer, scrolled by the current v and fine x
+    pub fn render_scanline(&mut self, y: usize, mapper: &dyn Mapper) {
+        let show_background = self.mask & MASK_SHOW_BACKGROUND != 0;
+        let show_sprites = self.mask & MASK_SHOW_SPRITES != 0;
+        let draw_background = self.background_override.apply(show_background);
+        let draw_sprites = self.sprite_override.apply(show_sprites);
+
+        if show_sprites || draw_sprites {
+            let status = self.status;
+            self.evaluate_sprites(y, mapper);
+            // sprites forced on for debugging don't get to set the overflow flag
+            if !show_sprites {
+                self.status = status;
+            }
+        } else {
+            self.line_sprite_count = 0;
+        }
+
+        let background_line = if show_background || draw_background {
+            self.background_line(mapper)
+        } else {
+            [0u8; WIDTH]
+        };
+
+        let colour_mask = if self.mask & MASK_GRAYSCALE != 0 {
+            0x30
+        } else {
+            0x3F
+        };
+        let emphasis = ((self.mask & MASK_EMPHASIS) as u16 >> 5) << EMPHASIS_SHIFT;
+
+        for (x, &line_pixel) in background_line.iter().enumerate() {
+            // the leftmost 8 pixels of each layer can be clipped
+            let left = x < 8;
+            let background = if left && self.mask & MASK_SHOW_BACKGROUND_LEFT == 0 {
+                0
+            } else {
+                line_pixel
+            };
+            let sprite = if left && self.mask & MASK_SHOW_SPRITES_LEFT == 0 {
+                None
+            } else {
+                self.sprite_pixel(x)
+            };
+
+            if let Some(sprite) = sprite {
+                // sprite 0 hit needs both layers opaque, and never triggers at x=255
+                if sprite.sprite_zero
+                    && background != 0
+                    && show_background
+                    && show_sprites
+                    && x != 255
+                {
+                    if self.status & STATUS_SPRITE_0_HIT == 0 {
+                        self.sprite_zero_hit_at = Some((y as u16, x as u16 + 1));
+                    }
+                    self.status |= STATUS_SPRITE_0_HIT;
+                }
+            }
+
+            let background = if draw_background { background } else { 0 };
+            let mut address = PALETTE_ADDR + background as u16;
+            if let Some(sprite) = sprite.filter(|_| draw_sprites) {
+                if background == 0 || sprite.attributes & ATTR_BEHIND_BACKGROUND == 0 {
+                    let value = Self::sprite_value(&sprite, x - sprite.x as usize);
+                    let palette = (sprite.attributes & ATTR_PALETTE) as u16;
+                    address = SPRITE_PALETTE_ADDR + palette * 4 + value as u16;
+                }
+            }
+            let colour = self.palette[Self::palette_index(address)] & colour_mask;
+            self.frame.pixels[y * WIDTH + x] = colour as u16 | emphasis;
+        }
+    }
+
+    /// Render a whole frame at once from the scroll position in t, as if nothing changed
+    /// mid-frame
+    pub fn render_frame(&mut self, mapper: &dyn Mapper) {
+        self.status &= !(STATUS_SPRITE_0_HIT | STATUS_SPRITE_OVERFLOW);
+        self.sprite_zero_hit_at = None;
+        let v = self.v;
+        self.v = self.t;
+        for y in 0..HEIGHT {
+            self.render_scanline(y, mapper);
+            self.increment_y();
+            self.copy_horizontal();
+        }
+        self.v = v;
+    }
+
+    fn pattern_row(&self, address: u16, mapper: &dyn Mapper) -> [u8; 8] {
+        let (low, high) = (self.read(address, mapper), self.read(address + 8, mapper));
+        let mut row = [0u8; 8];
+        for (pixel, value) in row.iter_mut().enumerate() {
+            let bit = 7 - pixel;
+            *value = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+        }
+        row
+    }
+
+    fn put_rgb(buffer: &mut [u8], width: usize, x: usize, y: usize, rgb: [u8; 3]) {
+        let offset = (y * width + x) * 3;
+        buffer[offset..offset + 3].copy_from_slice(&rgb);
+    }
+
+    /// Both pattern tables decoded with one of the 8 palettes (0-3 background, 4-7 sprites),
+    /// as a `PATTERN_VIEW_WIDTH` x `PATTERN_VIEW_HEIGHT` RGB buffer
+    pub fn render_pattern_tables(
+        &self,
+        palette_number: u8,
+        mapper: &dyn Mapper,
+        palette: &Palette,
+    ) -> Vec<u8> {
+        let mut buffer = vec![0u8; PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT * 3];
+        let palette_base = PALETTE_ADDR + (palette_number as u16 % 8) * 4;
+
+        for table in 0..2 {
+            for tile in 0..256 {
+                let (tile_x, tile_y) = (table * 128 + (tile % 16) * 8, (tile / 16) * 8);
+                for row in 0..8 {
+                    let address = (table * 0x1000 + tile * 16 + row) as u16;
+                    for (pixel, &value) in self.pattern_row(address, mapper).iter().enumerate() {
+                        let colour = self.read(palette_base + value as u16, mapper);
+                        let rgb = palette.rgb(colour as u16);
+                        Self::put_rgb(
+                            &mut buffer,
+                            PATTERN_VIEW_WIDTH,
+                            tile_x + pixel,
+                            tile_y + row,
+                            rgb,
+                        );
+                    }
+                }
+            }
+        }
+        buffer
+    }
+
+    /// The four nametables as the background would draw them, ignoring scroll, as a
+    /// `NAMETABLE_VIEW_WIDTH` x `NAMETABLE_VIEW_HEIGHT` RGB buffer
+    pub fn render_nametables(&self, mapper: &dyn Mapper, palette: &Palette) -> Vec<u8> {
+        let mut buffer = vec![0u8; NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT * 3];
+        let pattern_table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+
+        for table in 0..4 {
+            let nametable = 0x2000 + (table * NAMETABLE_SIZE) as u16;
+            let (origin_x, origin_y) = ((table % 2) * WIDTH, (table / 2) * HEIGHT);
+            for row in 0..HEIGHT / 8 {
+                for column in 0..WIDTH / 8 {
+                    let tile = self.read(nametable + (row * 32 + column) as u16, mapper);
+                    let attribute = self.read(
+                        nametable + ATTRIBUTE_OFFSET + ((row / 4) * 8 + column / 4) as u16,
+                        mapper,
+                    );
+                    let shift = ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2;
+                    let palette_number = ((attribute >> shift) & 0b11) as u16;
+
+                    for fine_y in 0..8 {
+                        let address = pattern_table + tile as u16 * 16 + fine_y as u16;
+                        for (pixel, &value) in self.pattern_row(address, mapper).iter().enumerate()
+                        {
+                            // colour 0 of every background palette is the shared backdrop
+                            let entry = if value == 0 {
+                                PALETTE_ADDR
+                            } else {
+                                PALETTE_ADDR + palette_number * 4 + value as u16
+                            };
+                            let rgb = palette.rgb(self.read(entry, mapper) as u16);
+                            Self::put_rgb(
+                                &mut buffer,
+                                NAMETABLE_VIEW_WIDTH,
+                                origin_x + column * 8 + pixel,
+                                origin_y + row * 8 + fine_y,
+                                rgb,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        buffer
+    }
+
+    /// Palette RAM as a `PALETTE_VIEW_WIDTH` x `PALETTE_VIEW_HEIGHT` RGB buffer
+    pub fn render_palettes(&self, palette: &Palette) -> Vec<u8> {
+        let mut buffer = vec![0u8; PALETTE_VIEW_WIDTH * PALETTE_VIEW_HEIGHT * 3];
+        for entry in 0..32 {
+            let colour = self.palette[Self::palette_index(PALETTE_ADDR + entry as u16)];
+            Self::put_rgb(
+                &mut buffer,
+                PALETTE_VIEW_WIDTH,
+                entry % 16,
+                entry / 16,
+                palette.rgb(colour as u16),
+            );
+        }
+        buffer
+    }
+
+    /// Force the background layer on or off in the output, for debugging
+    pub fn set_background_override(&mut self, layer: LayerOverride) {
+        self.background_override = layer;
+    }
+
+    /// Force the sprite layer on or off in the output, for debugging
+    pub fn set_sprite_override(&mut self, layer: LayerOverride) {
+        self.sprite_override = layer;
+    }
+
+    pub fn sprite_zero_hit(&self) -> bool {
+        self.status & STATUS_SPRITE_0_HIT != 0
+    }
+
+    /// The scanline and dot of this frame's sprite 0 hit, the dot its pixel is drawn on
+    pub fn sprite_zero_hit_at(&self) -> Option<(u16, u16)> {
+        self.sprite_zero_hit_at
+    }
+
+    pub fn sprite_overflow(&self) -> bool {
+        self.status & STATUS_SPRITE_OVERFLOW != 0
+    }
+
+    pub fn sprite_overflow_mode(&self) -> SpriteOverflow {
+        self.sprite_overflow_mode
+    }
+
+    pub fn set_sprite_overflow_mode(&mut self, mode: SpriteOverflow) {
+        self.sprite_overflow_mode = mode;
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// The frame's pixels, see `Frame`
+    pub fn frame_buffer(&self) -> &[u16] {
+        self.frame.pixels()
+    }
+}
+
+// The region is restored by the console before this, and the layer overrides are debug
+// settings that stay as they are, as is the sprite overflow mode (serde leaves them out too)
+impl SaveState for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.ctrl);
+        state.u8(self.mask);
+        state.u8(self.status);
+        state.u16(self.v);
+        state.u16(self.t);
+        state.u8(self.fine_x);
+        state.bool(self.write_latch);
+        state.u8(self.read_buffer);
+        state.u8(self.io_bus);
+        for &frame in &self.io_bus_driven {
+            state.u64(frame);
+        }
+        state.bool(self.suppress_vblank);
+        state.bytes(&self.vram);
+        state.bytes(&self.palette);
+        state.bytes(&self.oam);
+        state.u8(self.oam_addr);
+        state.u8(self.line_sprite_count as u8);
+        for sprite in &self.line_sprites {
+            state.u8(sprite.x);
+            state.u8(sprite.low);
+            state.u8(sprite.high);
+            state.u8(sprite.attributes);
+            state.bool(sprite.sprite_zero);
+        }
+        // kept so a paused game shows the right picture straight after loading
+        for &pixel in self.frame.pixels.iter() {
+            state.u16(pixel);
+        }
+        state.u16(self.dot);
+        state.u16(self.scanline);
+        state.u64(self.frame_count);
+        state.bool(self.odd_frame);
+        state.bool(self.nmi_pending);
+        state.bool(self.frame_complete);
+        state.bool(self.resetting);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ctrl = state.u8()?;
+        self.mask = state.u8()?;
+        self.status = state.u8()?;
+        self.v = state.u16()? & 0x7FFF;
+        self.t = state.u16()? & 0x7FFF;
+        self.fine_x = state.u8()? & 0x7;
+        self.write_latch = state.bool()?;
+        self.read_buffer = state.u8()?;
+        self.io_bus = state.u8()?;
+        for frame in self.io_bus_driven.iter_mut() {
+            *frame = state.u64()?;
+        }
+        self.suppress_vblank = state.bool()?;
+        state.bytes_into(&mut self.vram, "nametable RAM size")?;
+        state.bytes_into(&mut self.palette, "palette RAM size")?;
+        state.bytes_into(&mut self.oam, "OAM size")?;
+        self.oam_addr = state.u8()?;
+        self.line_sprite_count = state.u8()? as usize;
+        if self.line_sprite_count > SPRITES_PER_LINE {
+            return Err(StateError::Invalid("sprite count"));
+        }
+        for sprite in self.line_sprites.iter_mut() {
+            *sprite = LineSprite {
+                x: state.u8()?,
+                low: state.u8()?,
+                high: state.u8()?,
+                attributes: state.u8()?,
+                sprite_zero: state.bool()?,
+            };
+        }
+        for pixel in self.frame.pixels.iter_mut() {
+            *pixel = state.u16()?;
+        }
+        self.dot = state.u16()?;
+        self.scanline = state.u16()?;
+        if self.dot >= DOTS_PER_SCANLINE || self.scanline > self.region.pre_render_scanline() {
+            return Err(StateError::Invalid("PPU position"));
+        }
+        self.frame_count = state.u64()?;
+        if self
+            .io_bus_driven
+            .iter()
+            .any(|&frame| frame > self.frame_count)
+        {
+            return Err(StateError::Invalid("io bus decay"));
+        }
+        self.odd_frame = state.bool()?;
+        self.nmi_pending = state.bool()?;
+        self.frame_complete = state.bool()?;
+        self.resetting = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{Mirroring, Unmapped, CHR_BANK_SIZE};
+
+    struct ChrRam {
+        chr: Vec<u8>,
+        mirroring: Mirroring,
+    }
+
+    impl ChrRam {
+        fn new(mirroring: Mirroring) -> Self {
+            ChrRam {
+                chr: vec![0; CHR_BANK_SIZE],
+                mirroring,
+            }
+        }
+    }
+
+    impl Mapper for ChrRam {
+        fn cpu_read(&self, _address: u16) -> u8 {
+            0
+        }
+        fn cpu_write(&mut self, _address: u16, _byte: u8) {}
+        fn ppu_read(&self, address: u16) -> u8 {
+            self.chr[address as usize]
+        }
+        fn ppu_write(&mut self, address: u16, byte: u8) {
+            self.chr[address as usize] = byte;
+        }
+        fn mirroring(&self) -> Mirroring {
+            self.mirroring
+        }
+        fn chr(&self) -> &[u8] {
+            &self.chr
+        }
+        fn has_chr_ram(&self) -> bool {
+            true
+        }
+        fn prg_ram(&self) -> &[u8] {
+            &[]
+        }
+        fn prg_ram_mut(&mut self) -> &mut [u8] {
+            &mut []
+        }
+    }
+
+    fn set_addr(ppu: &mut Ppu, address: u16, mapper: &mut dyn Mapper) {
+        ppu.write_register(0x2006, (address >> 8) as u8, mapper);
+        ppu.write_register(0x2006, address as u8, mapper);
+    }
+
+    #[test]
+    fn ppudata_writes_increment() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        set_addr(&mut ppu, 0x2000, &mut mapper);
+        ppu.write_register(0x2007, 0x11, &mut mapper);
+        ppu.write_register(0x2007, 0x22, &mut mapper);
+        assert_eq!(ppu.read(0x2000, &mapper), 0x11);
+        assert_eq!(ppu.read(0x2001, &mapper), 0x22);
+
+        ppu.write_register(0x2000, CTRL_INCREMENT_32, &mut mapper);
+        set_addr(&mut ppu, 0x2000, &mut mapper);
+        ppu.write_register(0x2007, 0x33, &mut mapper);
+        ppu.write_register(0x2007, 0x44, &mut mapper);
+        assert_eq!(ppu.read(0x2000, &mapper), 0x33);
+        assert_eq!(ppu.read(0x2020, &mapper), 0x44);
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        ppu.write(0x2000, 0x11, &mut mapper);
+        ppu.write(0x2001, 0x22, &mut mapper);
+        set_addr(&mut ppu, 0x2000, &mut mapper);
+        // the first read returns the stale buffer
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x00);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x11);
+        assert_eq!(ppu.peek_register(0x2007, &mapper), 0x22);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x22);
+
+        // palette reads are immediate and buffer the nametable byte below them
+        ppu.write(0x2F00, 0x33, &mut mapper);
+        ppu.write(0x3F00, 0x0F, &mut mapper);
+        set_addr(&mut ppu, 0x3F00, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x0F);
+        assert_eq!(ppu.read_buffer, 0x33);
+    }
+
+    #[test]
+    fn chr_ram_written_through_mapper() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        set_addr(&mut ppu, 0x0010, &mut mapper);
+        ppu.write_register(0x2007, 0xAB, &mut mapper);
+        assert_eq!(mapper.chr()[0x10], 0xAB);
+    }
+
+    #[test]
+    fn nametable_mirroring() {
+        let mut ppu = Ppu::new();
+        let mut vertical = ChrRam::new(Mirroring::Vertical);
+        ppu.write(0x2000, 0x01, &mut vertical);
+        assert_eq!(ppu.read(0x2800, &vertical), 0x01);
+        assert_eq!(ppu.read(0x2400, &vertical), 0x00);
+        assert_eq!(ppu.read(0x3000, &vertical), 0x01);
+
+        let mut ppu = Ppu::new();
+        let mut horizontal = ChrRam::new(Mirroring::Horizontal);
+        ppu.write(0x2000, 0x01, &mut horizontal);
+        assert_eq!(ppu.read(0x2400, &horizontal), 0x01);
+        assert_eq!(ppu.read(0x2800, &horizontal), 0x00);
+    }
+
+    #[test]
+    fn palette_mirrors() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x3F10, 0x0F, &mut Unmapped);
+        assert_eq!(ppu.read(0x3F00, &Unmapped), 0x0F);
+        ppu.write(0x3F21, 0x30, &mut Unmapped);
+        assert_eq!(ppu.read(0x3F01, &Unmapped), 0x30);
+    }
+
+    #[test]
+    fn status_read_clears_vblank_and_latch() {
+        let mut ppu = Ppu::new();
+        ppu.set_vblank(true);
+        ppu.write_register(0x2006, 0x21, &mut Unmapped);
+        // low bits are whatever was last on the bus
+        assert_eq!(
+            ppu.read_register(0x2002, &mut Unmapped),
+            STATUS_VBLANK | 0x01
+        );
+        assert!(!ppu.in_vblank());
+        // latch was reset so this is the high byte again
+        set_addr(&mut ppu, 0x2345, &mut Unmapped);
+        assert_eq!(ppu.v, 0x2345);
+    }
+
+    #[test]
+    fn renders_background_tile() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        // tile 1: top row solid colour 3, second row colour 1
+        mapper.chr[16] = 0xFF;
+        mapper.chr[16 + 8] = 0xFF;
+        mapper.chr[17] = 0xFF;
+        // tile at column 1, row 0 uses tile 1 with attribute palette 2
+        ppu.write(0x2001, 0x01, &mut mapper);
+        ppu.write(0x23C0, 0b0000_0010, &mut mapper);
+        ppu.write(0x3F00, 0x0F, &mut mapper);
+        ppu.write(0x3F09, 0x16, &mut mapper);
+        ppu.write(0x3F0B, 0x2A, &mut mapper);
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT,
+            &mut mapper,
+        );
+
+        ppu.render_frame(&mapper);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[7], 0x0F);
+        assert_eq!(frame[8], 0x2A);
+        assert_eq!(frame[15], 0x2A);
+        assert_eq!(frame[WIDTH + 8], 0x16);
+        assert_eq!(frame[2 * WIDTH + 8], 0x0F);
+
+        // scrolling 8 pixels right moves the tile to column 0
+        ppu.write_register(0x2005, 8, &mut mapper);
+        ppu.write_register(0x2005, 0, &mut mapper);
+        ppu.render_frame(&mapper);
+        assert_eq!(ppu.frame_buffer()[0], 0x2A);
+    }
+
+    #[test]
+    fn loopy_register_updates() {
+        // the worked example from the nesdev scrolling page
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0b11, &mut Unmapped);
+        assert_eq!(ppu.t, 0x0C00);
+        ppu.read_register(0x2002, &mut Unmapped);
+        ppu.write_register(0x2005, 0b0111_1101, &mut Unmapped);
+        assert_eq!(ppu.t, 0x0C0F);
+        assert_eq!(ppu.fine_x, 0b101);
+        assert!(ppu.write_latch);
+        ppu.write_register(0x2005, 0b0101_1110, &mut Unmapped);
+        assert_eq!(ppu.t, 0x6D6F);
+        assert!(!ppu.write_latch);
+        ppu.write_register(0x2006, 0b0011_1101, &mut Unmapped);
+        assert_eq!(ppu.t, 0x3D6F);
+        assert_eq!(ppu.v, 0);
+        ppu.write_register(0x2006, 0b1111_0000, &mut Unmapped);
+        assert_eq!(ppu.t, 0x3DF0);
+        assert_eq!(ppu.v, 0x3DF0);
+    }
+
+    #[test]
+    fn reset_ignores_writes_until_pre_render() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2001, MASK_SHOW_BACKGROUND, &mut Unmapped);
+        ppu.reset();
+        assert_eq!(ppu.mask, 0);
+        ppu.write_register(0x2000, 0b11, &mut Unmapped);
+        ppu.write_register(0x2005, 0x7D, &mut Unmapped);
+        assert_eq!((ppu.ctrl, ppu.t, ppu.write_latch), (0, 0, false));
+        // $2007 still works
+        ppu.write_register(0x2007, 0x11, &mut Unmapped);
+        assert_eq!(ppu.v, 1);
+
+        while !(ppu.scanline() == ppu.region().pre_render_scanline() && ppu.dot() == 2) {
+            ppu.tick(&Unmapped);
+        }
+        ppu.write_register(0x2000, 0b11, &mut Unmapped);
+        assert_eq!(ppu.t, 0x0C00);
+    }
+
+    #[test]
+    fn increment_y_wraps_nametables() {
+        let mut ppu = Ppu::new();
+        ppu.v = FINE_Y | 29 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, NAMETABLE_Y);
+
+        // coarse y 31 (attribute rows) wraps without switching nametable
+        ppu.v = FINE_Y | 31 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0);
+    }
+
+    #[test]
+    fn mid_frame_scroll_split() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        // tile 1 is solid colour 1, placed at column 1 of the first nametable only
+        (0..8).for_each(|row| mapper.chr[16 + row] = 0xFF);
+        (0..30).for_each(|row| ppu.write(0x2001 + row * 32, 1, &mut mapper));
+        ppu.write(0x3F00, 0x0F, &mut mapper);
+        ppu.write(0x3F01, 0x01, &mut mapper);
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT,
+            &mut mapper,
+        );
+
+        // first frame sets v up from t on the pre-render line
+        run_until_with(&mut ppu, &mapper, 0, 0);
+        run_until_with(&mut ppu, &mapper, 100, 0);
+        // scroll 8 pixels right halfway down the screen, picked up at dot 257
+        ppu.read_register(0x2002, &mut mapper);
+        ppu.write_register(0x2005, 8, &mut mapper);
+        ppu.write_register(0x2005, 0, &mut mapper);
+        run_until_with(&mut ppu, &mapper, Region::Ntsc.vblank_scanline(), 0);
+
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[99 * WIDTH + 8], 0x01);
+        assert_eq!(frame[100 * WIDTH + 8], 0x01);
+        assert_eq!(frame[100 * WIDTH], 0x0F);
+        assert_eq!(frame[101 * WIDTH], 0x01);
+        assert_eq!(frame[101 * WIDTH + 8], 0x0F);
+    }
+
+    #[test]
+    fn grayscale_and_emphasis() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x3F00, 0x16, &mut Unmapped);
+        ppu.write_register(0x2001, MASK_GRAYSCALE, &mut Unmapped);
+        ppu.render_frame(&Unmapped);
+        assert_eq!(ppu.frame_buffer()[0], 0x10);
+
+        // palette RAM itself is untouched
+        assert_eq!(ppu.read(0x3F00, &Unmapped), 0x16);
+
+        ppu.write_register(0x2001, 0b1010_0000, &mut Unmapped);
+        ppu.render_frame(&Unmapped);
+        assert_eq!(ppu.frame_buffer()[0], 0b101 << 6 | 0x16);
+    }
+
+    fn view_pixel(buffer: &[u8], width: usize, x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * width + x) * 3;
+        [buffer[offset], buffer[offset + 1], buffer[offset + 2]]
+    }
+
+    #[test]
+    fn debug_viewers() {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        let palette = Palette::default();
+        // tile 1 of the second pattern table is solid colour 3
+        (0..16).for_each(|byte| mapper.chr[0x1010 + byte] = 0xFF);
+        ppu.write(0x3F00, 0x0D, &mut mapper);
+        ppu.write(0x3F03, 0x30, &mut mapper);
+        ppu.write(0x3F13, 0x16, &mut mapper);
+
+        let patterns = ppu.render_pattern_tables(0, &mapper, &palette);
+        assert_eq!(patterns.len(), PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT * 3);
+        assert_eq!(
+            view_pixel(&patterns, PATTERN_VIEW_WIDTH, 136, 0),
+            palette.rgb(0x30)
+        );
+        assert_eq!(
+            view_pixel(&patterns, PATTERN_VIEW_WIDTH, 8, 0),
+            palette.rgb(0x0D)
+        );
+        let sprite_colours = ppu.render_pattern_tables(4, &mapper, &palette);
+        assert_eq!(
+            view_pixel(&sprite_colours, PATTERN_VIEW_WIDTH, 143, 7),
+            palette.rgb(0x16)
+        );
+
+        // tile 1 in the top left of the right hand nametable ($2400)
+        ppu.write_register(0x2000, CTRL_BACKGROUND_TABLE, &mut mapper);
+        ppu.write(0x2400, 1, &mut mapper);
+        let nametables = ppu.render_nametables(&mapper, &palette);
+        assert_eq!(
+            view_pixel(&nametables, NAMETABLE_VIEW_WIDTH, WIDTH, 0),
+            palette.rgb(0x30)
+        );
+        assert_eq!(
+            view_pixel(&nametables, NAMETABLE_VIEW_WIDTH, 0, 0),
+            palette.rgb(0x0D)
+        );
+        // vertical mirroring repeats it in the bottom right
+        assert_eq!(
+            view_pixel(&nametables, NAMETABLE_VIEW_WIDTH, WIDTH, HEIGHT),
+            palette.rgb(0x30)
+        );
+
+        let palettes = ppu.render_palettes(&palette);
+        assert_eq!(
+            view_pixel(&palettes, PALETTE_VIEW_WIDTH, 3, 0),
+            palette.rgb(0x30)
+        );
+        assert_eq!(
+            view_pixel(&palettes, PALETTE_VIEW_WIDTH, 3, 1),
+            palette.rgb(0x16)
+        );
+        // $3F10 mirrors the backdrop
+        assert_eq!(
+            view_pixel(&palettes, PALETTE_VIEW_WIDTH, 0, 1),
+            palette.rgb(0x0D)
+        );
+    }
+
+    fn sprite_setup() -> (Ppu, ChrRam) {
+        let mut ppu = Ppu::new();
+        let mut mapper = ChrRam::new(Mirroring::Vertical);
+        // tile 1 is solid colour 1, tile 2 has only its left column set (colour 2)
+        for row in 0..8 {
+            mapper.chr[16 + row] = 0xFF;
+            mapper.chr[32 + 8 + row] = 0x80;
+        }
+        ppu.write(0x3F00, 0x0F, &mut mapper);
+        ppu.write(0x3F01, 0x01, &mut mapper);
+        ppu.write(0x3F11, 0x11, &mut mapper);
+        ppu.write(0x3F16, 0x16, &mut mapper);
+        // park unused sprites below the screen
+        ppu.oam_dma(&[0xFF; OAM_SIZE]);
+        (ppu, mapper)
+    }
+
+    fn set_sprite(ppu: &mut Ppu, index: usize, sprite: [u8; 4]) {
+        ppu.write_register(0x2003, (index * 4) as u8, &mut Unmapped);
+        sprite
+            .iter()
+            .for_each(|&byte| ppu.write_register(0x2004, byte, &mut Unmapped));
+    }
+
+    #[test]
+    fn oam_registers_and_dma() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2003, 0x10, &mut Unmapped);
+        ppu.write_register(0x2004, 0xAB, &mut Unmapped);
+        ppu.write_register(0x2003, 0x10, &mut Unmapped);
+        assert_eq!(ppu.read_register(0x2004, &mut Unmapped), 0xAB);
+
+        let mut page = [0u8; OAM_SIZE];
+        page.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        ppu.write_register(0x2003, 0, &mut Unmapped);
+        ppu.oam_dma(&page);
+        assert_eq!(ppu.oam()[0x42], 0x42);
+    }
+
+    #[test]
+    fn renders_sprites_with_flip_and_palette() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        // y=9 so the sprite starts on line 10, palette 1, flipped horizontally
+        set_sprite(&mut ppu, 0, [9, 2, ATTR_FLIP_HORIZONTAL | 1, 20]);
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut mapper);
+        ppu.render_frame(&mapper);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[9 * WIDTH + 27], 0x0F);
+        assert_eq!(frame[10 * WIDTH + 27], 0x16);
+        assert_eq!(frame[10 * WIDTH + 20], 0x0F);
+        assert_eq!(frame[17 * WIDTH + 27], 0x16);
+        assert_eq!(frame[18 * WIDTH + 27], 0x0F);
+    }
+
+    #[test]
+    fn sprite_priority_and_zero_hit() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        // background tile 1 everywhere in the first row of tiles
+        (0..32).for_each(|column| ppu.write(0x2000 + column, 1, &mut mapper));
+        set_sprite(&mut ppu, 0, [0, 1, ATTR_BEHIND_BACKGROUND, 8]);
+        set_sprite(&mut ppu, 1, [0, 1, 0, 40]);
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut mapper);
+        ppu.render_frame(&mapper);
+        assert!(!ppu.sprite_zero_hit());
+
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_SPRITES | MASK_SHOW_BACKGROUND,
+            &mut mapper,
+        );
+        ppu.render_frame(&mapper);
+        assert!(ppu.sprite_zero_hit());
+        let frame = ppu.frame_buffer();
+        // sprite 0 is behind the background, sprite 1 in front
+        assert_eq!(frame[WIDTH + 8], 0x01);
+        assert_eq!(frame[WIDTH + 40], 0x11);
+    }
+
+    #[test]
+    fn left_column_clipping() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        (0..32).for_each(|column| ppu.write(0x2000 + column, 1, &mut mapper));
+        set_sprite(&mut ppu, 0, [0, 1, 0, 4]);
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_SPRITES | MASK_SHOW_BACKGROUND,
+            &mut mapper,
+        );
+        ppu.render_frame(&mapper);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[WIDTH], 0x0F);
+        assert_eq!(frame[WIDTH + 7], 0x0F);
+        // the sprite shows from x=8 onwards, over the background
+        assert_eq!(frame[WIDTH + 8], 0x11);
+        assert_eq!(frame[WIDTH + 12], 0x01);
+        assert!(ppu.sprite_zero_hit());
+
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT,
+            &mut mapper,
+        );
+        ppu.render_frame(&mapper);
+        assert_eq!(ppu.frame_buffer()[WIDTH], 0x01);
+    }
+
+    #[test]
+    fn layer_overrides() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        (0..32).for_each(|column| ppu.write(0x2000 + column, 1, &mut mapper));
+        set_sprite(&mut ppu, 0, [0, 1, 0, 16]);
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_SPRITES | MASK_SHOW_BACKGROUND,
+            &mut mapper,
+        );
+
+        ppu.set_background_override(LayerOverride::ForceOff);
+        ppu.render_frame(&mapper);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[WIDTH + 8], 0x0F);
+        assert_eq!(frame[WIDTH + 16], 0x11);
+        // the game still sees the hidden background
+        assert!(ppu.sprite_zero_hit());
+
+        ppu.set_background_override(LayerOverride::Game);
+        ppu.set_sprite_override(LayerOverride::ForceOff);
+        ppu.render_frame(&mapper);
+        assert_eq!(ppu.frame_buffer()[WIDTH + 16], 0x01);
+
+        ppu.write_register(0x2001, 0, &mut mapper);
+        ppu.set_sprite_override(LayerOverride::ForceOn);
+        ppu.render_frame(&mapper);
+        assert_eq!(ppu.frame_buffer()[WIDTH + 16], 0x11);
+        assert!(!ppu.sprite_zero_hit());
+    }
+
+    #[test]
+    fn tall_sprites_use_tile_pairs() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        // tile 3 (odd) selects the $1000 table, tiles $1020 (top) and $1030 (bottom)
+        for row in 0..8 {
+            mapper.chr[0x1030 + row] = 0xFF;
+        }
+        set_sprite(&mut ppu, 0, [0, 3, 0, 0]);
+        ppu.write_register(0x2000, CTRL_SPRITE_SIZE_16, &mut mapper);
+        ppu.write_register(
+            0x2001,
+            MASK_SHOW_SPRITES | MASK_SHOW_SPRITES_LEFT,
+            &mut mapper,
+        );
+        ppu.render_frame(&mapper);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[4 * WIDTH], 0x0F);
+        assert_eq!(frame[9 * WIDTH], 0x11);
+        assert_eq!(frame[16 * WIDTH], 0x11);
+        assert_eq!(frame[17 * WIDTH], 0x0F);
+    }
+
+    #[test]
+    fn sprite_overflow_after_eight() {
+        let (mut ppu, mut mapper) = sprite_setup();
+        (0..8).for_each(|i| set_sprite(&mut ppu, i, [50, 1, 0, i as u8 * 8]));
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut mapper);
+        ppu.render_frame(&mapper);
+        assert!(!ppu.sprite_overflow());
+
+        // OAM can't be written while rendering
+        ppu.write_register(0x2001, 0, &mut mapper);
+        set_sprite(&mut ppu, 8, [50, 1, 0, 200]);
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut mapper);
+        ppu.render_frame(&mapper);
+        assert!(ppu.sprite_overflow());
+        // the ninth sprite isn't drawn
+        assert_eq!(ppu.frame_buffer()[60 * WIDTH + 200], 0x0F);
+    }
+
+    #[test]
+    fn the_overflow_search_is_diagonal_on_hardware() {
+        let overflows = |mode: SpriteOverflow, ninth: [u8; 4], tenth: [u8; 4]| {
+            let (mut ppu, mut mapper) = sprite_setup();
+            ppu.set_sprite_overflow_mode(mode);
+            (0..8).for_each(|i| set_sprite(&mut ppu, i, [50, 1, 0, i as u8 * 8]));
+            set_sprite(&mut ppu, 8, ninth);
+            set_sprite(&mut ppu, 9, tenth);
+            ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut mapper);
+            ppu.render_frame(&mapper);
+            ppu.sprite_overflow()
+        };
+        // after missing sprite 8 hardware reads sprite 9's tile number as its Y
+        let missed = ([0xFF; 4], [50, 0xFF, 0, 0]);
+        let imagined = ([0xFF; 4], [0xFF, 50, 0, 0]);
+        assert!(!overflows(SpriteOverflow::Hardware, missed.0, missed.1));
+        assert!(overflows(SpriteOverflow::Correct, missed.0, missed.1));
+        assert!(overflows(SpriteOverflow::Hardware, imagined.0, imagined.1));
+        assert!(!overflows(SpriteOverflow::Correct, imagined.0, imagined.1));
+    }
+
+    #[test]
+    fn oam_is_busy_while_rendering() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2003, 0x02, &mut Unmapped);
+        ppu.write_register(0x2004, 0xFF, &mut Unmapped);
+        assert_eq!(ppu.oam()[0x02], 0xE3);
+
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut Unmapped);
+        run_until(&mut ppu, 10, 30);
+        assert_eq!(ppu.read_register(0x2004, &mut Unmapped), 0xFF);
+        // writes are dropped and bump OAMADDR a whole sprite
+        ppu.write_register(0x2003, 0x10, &mut Unmapped);
+        ppu.write_register(0x2004, 0xAB, &mut Unmapped);
+        assert_eq!(ppu.oam()[0x10], 0);
+        assert_eq!(ppu.snapshot().oam_addr, 0x14);
+        run_until(&mut ppu, 10, 300);
+        assert_eq!(ppu.snapshot().oam_addr, 0);
+    }
+
+    #[test]
+    fn rendering_from_a_high_oamaddr_corrupts_oam() {
+        let mut ppu = Ppu::new();
+        let mut page = [0u8; OAM_SIZE];
+        page.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        ppu.oam_dma(&page);
+        run_until(&mut ppu, 241, 1);
+        ppu.write_register(0x2003, 0x4A, &mut Unmapped);
+        ppu.write_register(0x2001, MASK_SHOW_SPRITES, &mut Unmapped);
+        run_until(&mut ppu, 0, 0);
+        let oam = ppu.oam();
+        assert_eq!(oam[..8], oam[0x48..0x50]);
+        assert_eq!((oam[0], oam[8]), (0x48, 8));
+    }
+
+    fn run_until(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        run_until_with(ppu, &Unmapped, scanline, dot);
+    }
+
+    fn run_until_with(ppu: &mut Ppu, mapper: &dyn Mapper, scanline: u16, dot: u16) {
+        loop {
+            ppu.tick(mapper);
+            if ppu.scanline() == scanline && ppu.dot() == dot {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn vblank_and_nmi_timing() {
+        let mut ppu = Ppu::new();
+        let region = ppu.region();
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut Unmapped);
+        run_until(&mut ppu, region.vblank_scanline(), 1);
+        assert!(!ppu.in_vblank());
+        ppu.tick(&Unmapped);
+        assert!(ppu.in_vblank());
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+        assert!(ppu.take_frame_complete());
+
+        run_until(&mut ppu, region.pre_render_scanline(), 2);
+        assert!(!ppu.in_vblank());
+    }
+
+    #[test]
+    fn nmi_enabled_during_vblank() {
+        let mut ppu = Ppu::new();
+        run_until(&mut ppu, Region::Ntsc.vblank_scanline() + 1, 0);
+        assert!(!ppu.take_nmi());
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut Unmapped);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn status_read_races_vblank() {
+        let vblank_scanline = Region::Ntsc.vblank_scanline();
+        // one dot early: flag never set, no NMI
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut Unmapped);
+        run_until(&mut ppu, vblank_scanline, 1);
+        assert_eq!(ppu.read_register(0x2002, &mut Unmapped) & STATUS_VBLANK, 0);
+        ppu.tick(&Unmapped);
+        assert!(!ppu.in_vblank());
+        assert!(!ppu.take_nmi());
+        assert!(ppu.take_frame_complete());
+
+        // on the dot it's set: flag is read, NMI is cancelled
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut Unmapped);
+        run_until(&mut ppu, vblank_scanline, 2);
+        assert_ne!(ppu.read_register(0x2002, &mut Unmapped) & STATUS_VBLANK, 0);
+        assert!(!ppu.take_nmi());
+
+        // later reads leave the NMI alone
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, CTRL_NMI_ENABLE, &mut Unmapped);
+        run_until(&mut ppu, vblank_scanline, 10);
+        ppu.read_register(0x2002, &mut Unmapped);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn open_bus_reads_and_decay() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x5A, &mut Unmapped);
+        assert_eq!(ppu.read_register(0x2001, &mut Unmapped), 0x5A);
+        assert_eq!(ppu.read_register(0x2005, &mut Unmapped), 0x5A);
+        assert_eq!(ppu.peek_register(0x2003, &Unmapped), 0x5A);
+
+        // palette reads keep the top two bits of the bus
+        ppu.write(0x3F00, 0x01, &mut Unmapped);
+        ppu.write_register(0x2006, 0x3F, &mut Unmapped);
+        ppu.write_register(0x2006, 0x00, &mut Unmapped);
+        ppu.write_register(0x2003, 0xC0, &mut Unmapped);
+        assert_eq!(ppu.read_register(0x2007, &mut Unmapped), 0xC1);
+
+        // each bit fades on its own, counted from when it was last driven
+        let run_frames = |ppu: &mut Ppu, frames: u64| {
+            (0..frames).for_each(|_| run_until(ppu, 0, 0));
+        };
+        ppu.write_register(0x2000, 0xFF, &mut Unmapped);
+        run_frames(&mut ppu, IO_BUS_DECAY_FRAMES - 1);
+        assert_eq!(ppu.io_bus(), 0xFF);
+        run_frames(&mut ppu, 1);
+        assert_eq!(ppu.io_bus(), 0x00);
+
+        ppu.write(0x3F00, 0x3F, &mut Unmapped);
+        ppu.write_register(0x2006, 0x3F, &mut Unmapped);
+        ppu.write_register(0x2006, 0x00, &mut Unmapped);
+        run_frames(&mut ppu, IO_BUS_DECAY_FRAMES / 2);
+        // the palette read drives the low six bits again, the top two keep decaying
+        assert_eq!(ppu.read_register(0x2007, &mut Unmapped), 0x3F);
+        run_frames(&mut ppu, IO_BUS_DECAY_FRAMES / 2);
+        assert_eq!(ppu.io_bus(), 0x3F);
+        run_frames(&mut ppu, IO_BUS_DECAY_FRAMES / 2);
+        assert_eq!(ppu.io_bus(), 0x00);
+    }
+
+    #[test]
+    fn load_state_rejects_io_bus_driven_in_the_future() {
+        let mut ppu = Ppu::new();
+        run_until(&mut ppu, 0, 0);
+        ppu.write_register(0x2000, 0xFF, &mut Unmapped);
+        let mut writer = StateWriter::new();
+        ppu.save_state(&mut writer);
+        let data = writer.finish();
+        assert_eq!(Ppu::new().load_state(&mut StateReader::new(&data)), Ok(()));
+
+        ppu.io_bus_driven[3] = ppu.frame_count() + 1;
+        let mut writer = StateWriter::new();
+        ppu.save_state(&mut writer);
+        let data = writer.finish();
+        assert_eq!(
+            Ppu::new().load_state(&mut StateReader::new(&data)),
+            Err(StateError::Invalid("io bus decay"))
+        );
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot_when_rendering() {
+        let frame_dots = |ppu: &mut Ppu| {
+            let start = ppu.frame_count();
+            let mut dots = 0;
+            while ppu.frame_count() == start {
+                ppu.tick(&Unmapped);
+                dots += 1;
+            }
+            dots
+        };
+        let full = DOTS_PER_SCANLINE as u32 * Region::Ntsc.scanlines_per_frame() as u32;
+
+        let mut ppu = Ppu::new();
+        assert_eq!(frame_dots(&mut ppu), full);
+        assert_eq!(frame_dots(&mut ppu), full);
+
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2001, MASK_SHOW_BACKGROUND, &mut Unmapped);
+        assert_eq!(frame_dots(&mut ppu), full);
+        assert_eq!(frame_dots(&mut ppu), full - 1);
+        assert_eq!(frame_dots(&mut ppu), full);
+    }
+
+    #[test]
+    fn pal_and_dendy_timing() {
+        let full = |region: Region| DOTS_PER_SCANLINE as u32 * region.scanlines_per_frame() as u32;
+        for region in [Region::Pal, Region::Dendy] {
+            let mut ppu = Ppu::new();
+            ppu.set_region(region);
+            ppu.write_register(0x2001, MASK_SHOW_BACKGROUND, &mut Unmapped);
+            // no odd frame skip outside NTSC
+            for _ in 0..2 {
+                let mut dots = 0;
+                let start = ppu.frame_count();
+                while ppu.frame_count() == start {
+                    ppu.tick(&Unmapped);
+                    dots += 1;
+                }
+                assert_eq!(dots, full(region));
+            }
+
+            run_until(&mut ppu, region.vblank_scanline(), 1);
+            assert!(!ppu.in_vblank());
+            ppu.tick(&Unmapped);
+            assert!(ppu.in_vblank());
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::cpu::Registers;
+use crate::disasm;
+use crate::memory::{AccessKind, Bus};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// A profiler for finding where a game spends its time. While it's on it counts:
+//
+//   - how many times the instruction at each address ran and the cycles it took, including
+//     stalls for DMA it started
+//   - calls to each subroutine and the cycles spent in them, from the JSR to the matching RTS,
+//     inclusive of anything they call. NMI and IRQ handlers count as subroutines entered at
+//     their vector's target and left by RTI
+//   - reads and writes of each address, leaving out the instruction's own opcode and operand
+//     fetches
+//
+// Calls and returns are paired by the stack pointer, so code that pulls its return address
+// off the stack or uses RTS as a computed jump doesn't throw the rest of the profile off: an
+// RTS only ends a call when it's returning from that call's stack depth. Recursive calls count
+// their cycles once for every call they're nested in.
+
+const ADDRESSES: usize = 0x10000;
+
+/// One address's instruction counts
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct InstructionStats {
+    pub address: u16,
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// One subroutine's calls
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RoutineStats {
+    pub address: u16,
+    pub calls: u64,
+    /// Cycles between entering and returning, of the calls that returned
+    pub cycles: u64,
+}
+
+/// One address's data accesses
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    pub address: u16,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl AccessStats {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Call {
+    routine: u16,
+    // the stack pointer inside the call, what the return has to come back from
+    sp: u8,
+    entered: u64,
+}
+
+/// Instruction, subroutine and memory access counts
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    // by address
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    routines: BTreeMap<u16, RoutineStats>,
+    stack: Vec<Call>,
+    // the instruction being fetched, its own bytes aren't data accesses
+    fetching: (u16, u16),
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            counts: vec![0; ADDRESSES],
+            cycles: vec![0; ADDRESSES],
+            reads: vec![0; ADDRESSES],
+            writes: vec![0; ADDRESSES],
+            routines: BTreeMap::new(),
+            stack: Vec::new(),
+            fetching: (0, 0),
+        }
+    }
+
+    /// Start over, as if just switched on
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The instruction `length` bytes long at `pc` is about to run
+    pub(crate) fn fetch(&mut self, pc: u16, length: u16) {
+        self.fetching = (pc, length);
+    }
+
+    pub(crate) fn access(&mut self, address: u16, kind: AccessKind) {
+        let (pc, length) = self.fetching;
+        match kind {
+            AccessKind::Read if address.wrapping_sub(pc) < length => {}
+            AccessKind::Read => self.reads[address as usize] += 1,
+            AccessKind::Write => self.writes[address as usize] += 1,
+        }
+    }
+
+    /// The instruction with `opcode` at `pc` ran, taking `cycles` from cycle `start`. `sp`
+    /// is the stack pointer before it ran, `after` the registers once it had.
+    pub(crate) fn instruction(
+        &mut self,
+        pc: u16,
+        opcode: u8,
+        sp: u8,
+        start: u64,
+        cycles: u64,
+        after: &Registers,
+    ) {
+        self.counts[pc as usize] += 1;
+        self.cycles[pc as usize] += cycles;
+        match opcode {
+            // JSR
+            0x20 => self.call(after.pc, after.sp(), start),
+            // RTS and RTI
+            0x60 | 0x40 => self.ret(sp, start + cycles),
+            _ => {}
+        }
+        self.fetching = (0, 0);
+    }
+
+    /// Entered `routine` at cycle `start` with `sp` once the return address is pushed, by a
+    /// JSR or an interrupt
+    pub(crate) fn call(&mut self, routine: u16, sp: u8, start: u64) {
+        self.routines
+            .entry(routine)
+            .or_insert(RoutineStats {
+                address: routine,
+                ..RoutineStats::default()
+            })
+            .calls += 1;
+        self.stack.push(Call {
+            routine,
+            sp,
+            entered: start,
+        });
+    }
+
+    // returning with the stack pointer at `sp`, ending at cycle `end`
+    fn ret(&mut self, sp: u8, end: u64) {
+        // calls deeper than this one never returned normally
+        while self.stack.last().is_some_and(|call| call.sp < sp) {
+            self.stack.pop();
+        }
+        if self.stack.last().is_some_and(|call| call.sp == sp) {
+            let call = self.stack.pop().unwrap();
+            if let Some(routine) = self.routines.get_mut(&call.routine) {
+                routine.cycles += end - call.entered;
+            }
+        }
+    }
+
+    /// Every address an instruction ran at, in address order
+    pub fn instructions(&self) -> Vec<InstructionStats> {
+        (0..ADDRESSES)
+            .filter(|&address| self.counts[address] > 0)
+            .map(|address| InstructionStats {
+                address: address as u16,
+                count: self.counts[address],
+                cycles: self.cycles[address],
+            })
+            .collect()
+    }
+
+    /// Every subroutine called, in address order
+    pub fn routines(&self) -> Vec<RoutineStats> {
+        self.routines.values().copied().collect()
+    }
+
+    /// Every address read or written as data, in address order
+    pub fn accesses(&self) -> Vec<AccessStats> {
+        (0..ADDRESSES)
+            .filter(|&address| self.reads[address] + self.writes[address] > 0)
+            .map(|address| AccessStats {
+                address: address as u16,
+                reads: self.reads[address],
+                writes: self.writes[address],
+            })
+            .collect()
+    }
+
+    /// A text report: every instruction by address, then the `top` busiest instructions, the
+    /// subroutines by cycles and the `top` busiest addresses. Instructions are disassembled
+    /// from `bus` as it is now, so bank switched code shows what's mapped in at the end.
+    pub fn report(&self, bus: &dyn Bus, top: usize) -> String {
+        let text = |address| {
+            disasm::disassemble_memory(bus, address, 1)
+                .first()
+                .map_or(String::new(), |line| line.text.clone())
+        };
+        let mut instructions = self.instructions();
+        let total: u64 = instructions.iter().map(|stats| stats.cycles).sum();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total.max(1) as f64;
+        let mut report = String::new();
+
+        let _ = writeln!(report, "Instructions by address, {} cycles", total);
+        let _ = writeln!(
+            report,
+            "ADDR        COUNT       CYCLES       %  INSTRUCTION"
+        );
+        let line = |report: &mut String, stats: &InstructionStats| {
+            let _ = writeln!(
+                report,
+                "{:04X} {:>12} {:>12} {:>6.2}  {}",
+                stats.address,
+                stats.count,
+                stats.cycles,
+                percent(stats.cycles),
+                text(stats.address)
+            );
+        };
+        instructions
+            .iter()
+            .for_each(|stats| line(&mut report, stats));
+
+        instructions.sort_by_key(|stats| std::cmp::Reverse(stats.cycles));
+        let _ = writeln!(report, "\nHottest instructions");
+        let _ = writeln!(
+            report,
+            "ADDR        COUNT       CYCLES       %  INSTRUCTION"
+        );
+        for stats in instructions.iter().take(top) {
+            line(&mut report, stats);
+        }
+
+        let mut routines = self.routines();
+        routines.sort_by_key(|stats| std::cmp::Reverse(stats.cycles));
+        let _ = writeln!(report, "\nSubroutines by cycles, including what they call");
+        let _ = writeln!(
+            report,
+            "ADDR        CALLS       CYCLES       %      AVERAGE"
+        );
+        for stats in &routines {
+            let _ = writeln!(
+                report,
+                "{:04X} {:>12} {:>12} {:>6.2} {:>12.1}",
+                stats.address,
+                stats.calls,
+                stats.cycles,
+                percent(stats.cycles),
+                stats.cycles as f64 / stats.calls.max(1) as f64
+            );
+        }
+
+        let mut accesses = self.accesses();
+        accesses.sort_by_key(|stats| std::cmp::Reverse(stats.total()));
+        let _ = writeln!(report, "\nHottest memory");
+        let _ = writeln!(report, "ADDR        READS       WRITES");
+        for stats in accesses.iter().take(top) {
+            let _ = writeln!(
+                report,
+                "{:04X} {:>12} {:>12}",
+                stats.address, stats.reads, stats.writes
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::NesCpu;
+
+    fn profile(source: &str, instructions: usize) -> NesCpu {
+        let mut cpu = NesCpu::new_from_bytes(&assemble(source).unwrap());
+        cpu.set_pc(0x8000);
+        cpu.memory.set_profiler(Some(Profiler::new()));
+        for _ in 0..instructions {
+            cpu.fetch_decode_next();
+        }
+        cpu
+    }
+
+    #[test]
+    fn counts_instructions_calls_and_accesses() {
+        let cpu = profile(
+            "
+            loop:   jsr add
+                    jmp loop
+            add:    inc $10
+                    lda $10
+                    rts
+            ",
+            // three times round
+            15,
+        );
+        let profiler = cpu.memory.profiler().unwrap();
+        let instructions = profiler.instructions();
+        assert_eq!(
+            instructions[0],
+            InstructionStats {
+                address: 0x8000,
+                count: 3,
+                cycles: 18
+            }
+        );
+        assert_eq!(instructions.len(), 5);
+        // JSR 6, INC 5, LDA 3, RTS 6
+        assert_eq!(
+            profiler.routines(),
+            [RoutineStats {
+                address: 0x8006,
+                calls: 3,
+                cycles: 3 * 20
+            }]
+        );
+        let ram: Vec<AccessStats> = profiler
+            .accesses()
+            .into_iter()
+            .filter(|stats| stats.address == 0x0010)
+            .collect();
+        // INC and LDA both read it, only INC writes
+        assert_eq!(ram[0].reads, 6);
+        assert_eq!(ram[0].writes, 3);
+
+        let report = profiler.report(&cpu.memory, 2);
+        assert!(report.contains("8006            3           15  "));
+        assert!(report.contains("INC $10"));
+        assert!(report.contains("\nHottest memory\nADDR        READS       WRITES\n"));
+    }
+
+    #[test]
+    fn returns_pair_with_their_calls_by_stack_depth() {
+        let mut profiler = Profiler::new();
+        profiler.call(0x9000, 0xFB, 0);
+        profiler.call(0x9100, 0xF9, 10);
+        // 0x9100 pulls its return address and jumps back, then 0x9000 returns
+        profiler.ret(0xFB, 50);
+        // an RTS used as a jump, with nothing called from this depth
+        profiler.ret(0xFD, 60);
+        assert_eq!(
+            profiler.routines(),
+            [
+                RoutineStats {
+                    address: 0x9000,
+                    calls: 1,
+                    cycles: 50
+                },
+                RoutineStats {
+                    address: 0x9100,
+                    calls: 1,
+                    cycles: 0
+                }
+            ]
+        );
+    }
+}
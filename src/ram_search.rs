@@ -0,0 +1,173 @@
+use std::fmt;
+
+// RAM search, for finding where a game keeps a number such as lives or health. Every address
+// starts as a candidate, and each filter compares the RAM now with a snapshot taken by the last
+// filter (or when the search started), or with a number, and drops the addresses that don't
+// match. Losing a life then filtering for "decreased by 1", a few times over, usually leaves
+// only the lives counter, which a raw address:value cheat can then pin.
+
+/// How a byte compares with what it's checked against
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+}
+
+impl Comparison {
+    fn test(self, value: u8, against: u8) -> bool {
+        match self {
+            Comparison::Equal => value == against,
+            Comparison::NotEqual => value != against,
+            Comparison::Greater => value > against,
+            Comparison::Less => value < against,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Equal => "=",
+            Comparison::NotEqual => "!=",
+            Comparison::Greater => ">",
+            Comparison::Less => "<",
+        }
+    }
+}
+
+/// What the candidates are narrowed down by
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Compare with the value at the last snapshot
+    Previous(Comparison),
+    /// Compare with a number
+    Value(Comparison, u8),
+    /// Changed by exactly this much since the last snapshot, wrapping, negative for down
+    ChangedBy(i16),
+}
+
+impl Filter {
+    fn test(self, value: u8, previous: u8) -> bool {
+        match self {
+            Filter::Previous(comparison) => comparison.test(value, previous),
+            Filter::Value(comparison, number) => comparison.test(value, number),
+            Filter::ChangedBy(delta) => value == previous.wrapping_add(delta as u8),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Filter::Previous(Comparison::Equal) => write!(f, "unchanged"),
+            Filter::Previous(Comparison::NotEqual) => write!(f, "changed"),
+            Filter::Previous(Comparison::Greater) => write!(f, "increased"),
+            Filter::Previous(Comparison::Less) => write!(f, "decreased"),
+            Filter::Value(comparison, number) => write!(f, "{} {}", comparison.symbol(), number),
+            Filter::ChangedBy(delta) => write!(f, "changed by {:+}", delta),
+        }
+    }
+}
+
+/// A search in progress over a block of RAM
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    snapshot: Vec<u8>,
+    // offsets into the RAM still matching every filter, in order
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Start with every byte of `ram` a candidate
+    pub fn new(ram: &[u8]) -> Self {
+        RamSearch {
+            snapshot: ram.to_vec(),
+            candidates: (0..ram.len() as u16).collect(),
+        }
+    }
+
+    /// Drop the candidates `filter` doesn't match in `ram`, then snapshot it for the next
+    /// filter. Returns how many are left.
+    pub fn filter(&mut self, ram: &[u8], filter: Filter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&address| {
+            let address = address as usize;
+            match (ram.get(address), snapshot.get(address)) {
+                (Some(&value), Some(&previous)) => filter.test(value, previous),
+                _ => false,
+            }
+        });
+        self.snapshot.clear();
+        self.snapshot.extend_from_slice(ram);
+        self.candidates.len()
+    }
+
+    /// Take a new snapshot without filtering, so the next filter compares against now
+    pub fn snapshot(&mut self, ram: &[u8]) {
+        self.snapshot.clear();
+        self.snapshot.extend_from_slice(ram);
+    }
+
+    /// Addresses still in the running, with their values at the last snapshot
+    pub fn candidates(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.candidates
+            .iter()
+            .map(|&address| (address, self.snapshot[address as usize]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrows_down_a_counter() {
+        let mut ram = [0u8; 64];
+        ram[0x20] = 3;
+        ram[0x30] = 3;
+        let mut search = RamSearch::new(&ram);
+        assert_eq!(search.len(), 64);
+
+        // lose a life, 0x30 goes down by 2 instead
+        ram[0x20] = 2;
+        ram[0x30] = 1;
+        assert_eq!(search.filter(&ram, Filter::Previous(Comparison::Less)), 2);
+        ram[0x20] = 1;
+        ram[0x30] = 0;
+        assert_eq!(search.filter(&ram, Filter::ChangedBy(-1)), 2);
+        assert_eq!(search.filter(&ram, Filter::Value(Comparison::Equal, 1)), 1);
+        assert_eq!(search.candidates().collect::<Vec<_>>(), [(0x20, 1)]);
+    }
+
+    #[test]
+    fn changed_by_wraps() {
+        let mut ram = [0xFFu8; 4];
+        let mut search = RamSearch::new(&ram);
+        ram[1] = 0x00;
+        ram[2] = 0xFE;
+        assert_eq!(search.filter(&ram, Filter::ChangedBy(1)), 1);
+        assert_eq!(search.candidates().next(), Some((1, 0x00)));
+    }
+
+    #[test]
+    fn unchanged_and_snapshots() {
+        let mut ram = [5u8; 8];
+        let mut search = RamSearch::new(&ram);
+        ram[0] = 6;
+        search.snapshot(&ram);
+        assert_eq!(search.filter(&ram, Filter::Previous(Comparison::Equal)), 8);
+        assert_eq!(Filter::ChangedBy(-1).to_string(), "changed by -1");
+        assert_eq!(
+            Filter::Previous(Comparison::NotEqual).to_string(),
+            "changed"
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use crate::audio::Resampler;
+use crate::hash::{Adler32, Crc32};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+// Gameplay capture. Every emulated frame is recorded, shown or not, along with the APU's
+// output resampled at a fixed rate so sound stays in step with the frames however the
+// frontend was pacing them.
+//
+// An image sequence is a directory of numbered PNGs plus audio.wav. The PNGs are written
+// with stored (uncompressed) deflate blocks, which keeps the encoder simple and fast at the
+// cost of size. The ffmpeg target pipes raw RGB frames into an ffmpeg process, then muxes the
+// audio in with a second run once recording stops.
+
+pub const RECORDING_SAMPLE_RATE: u32 = 48_000;
+
+const PNG_SIGNATURE: [u8; 8] = *b"\x89PNG\r\n\x1a\n";
+const PNG_COLOUR_RGB: u8 = 2;
+// largest stored deflate block
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+const WAV_HEADER_SIZE: u32 = 44;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum RecordingFormat {
+    #[default]
+    ImageSequence,
+    Ffmpeg,
+}
+
+impl RecordingFormat {
+    /// "png" or "ffmpeg"
+    pub fn parse(text: &str) -> Option<RecordingFormat> {
+        match text.trim() {
+            "png" => Some(RecordingFormat::ImageSequence),
+            "ffmpeg" => Some(RecordingFormat::Ffmpeg),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordingConfig {
+    pub format: RecordingFormat,
+    /// Where recordings go, each one is named after the first unused number
+    pub directory: PathBuf,
+    /// The ffmpeg executable, looked up on the PATH unless it's a path
+    pub ffmpeg: PathBuf,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            format: RecordingFormat::default(),
+            directory: PathBuf::from("recordings"),
+            ffmpeg: PathBuf::from("ffmpeg"),
+        }
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.finish().to_be_bytes());
+}
+
+/// Encode `width` x `height` RGB24 pixels as a PNG
+pub fn encode_png(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    // every row starts with filter type 0, none
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks_exact(width * 3).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib: deflate with no compression, then an Adler-32 of the uncompressed data
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(STORED_BLOCK_SIZE);
+    let last = blocks.len().saturating_sub(1);
+    for (i, block) in blocks.enumerate() {
+        let length = block.len() as u16;
+        zlib.push((i == last) as u8);
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let mut adler = Adler32::new();
+    adler.update(&raw);
+    zlib.extend_from_slice(&adler.finish().to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, PNG_COLOUR_RGB, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// 16 bit mono PCM WAV, the sizes in the header are filled in by `finish`
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        // bytes per frame, bits per sample
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fill in the header sizes, returning the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+enum Sink {
+    Images,
+    Ffmpeg {
+        child: Child,
+        stdin: BufWriter<ChildStdin>,
+        video: PathBuf,
+    },
+}
+
+/// A recording in progress
+pub struct Recorder {
+    sink: Sink,
+    // the directory for image sequences, the final video file for ffmpeg
+    path: PathBuf,
+    ffmpeg: PathBuf,
+    width: usize,
+    height: usize,
+    frames: u64,
+    wav_path: PathBuf,
+    wav: WavWriter<BufWriter<File>>,
+    resampler: Resampler,
+    samples: Vec<f32>,
+}
+
+// first recording-NNN name that isn't taken
+fn unused_path(directory: &Path, extension: &str) -> PathBuf {
+    (1..)
+        .map(|n| directory.join(format!("recording-{:03}{}", n, extension)))
+        .find(|path| !path.exists())
+        .expect("ran out of recording names")
+}
+
+impl Recorder {
+    /// Start recording `width` x `height` frames at `frames_per_second`, with audio from an
+    /// APU clocked at `cpu_clock_hz`
+    pub fn start(
+        config: &RecordingConfig,
+        width: usize,
+        height: usize,
+        frames_per_second: f64,
+        cpu_clock_hz: u32,
+    ) -> io::Result<Recorder> {
+        fs::create_dir_all(&config.directory)?;
+        let (sink, path, wav_path) = match config.format {
+            RecordingFormat::ImageSequence => {
+                let path = unused_path(&config.directory, "");
+                fs::create_dir(&path)?;
+                let wav_path = path.join("audio.wav");
+                (Sink::Images, path, wav_path)
+            }
+            RecordingFormat::Ffmpeg => {
+                let path = unused_path(&config.directory, ".mkv");
+                let video = path.with_extension("video.mkv");
+                let mut child = Command::new(&config.ffmpeg)
+                    .args(["-y", "-loglevel", "error", "-f", "rawvideo"])
+                    .args(["-pix_fmt", "rgb24", "-s", &format!("{}x{}", width, height)])
+                    .args(["-r", &format!("{:.6}", frames_per_second), "-i", "-"])
+                    .args(["-pix_fmt", "yuv420p"])
+                    .arg(&video)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                let stdin = BufWriter::new(child.stdin.take().expect("piped stdin"));
+                let wav_path = path.with_extension("wav");
+                (
+                    Sink::Ffmpeg {
+                        child,
+                        stdin,
+                        video,
+                    },
+                    path,
+                    wav_path,
+                )
+            }
+        };
+        let wav = WavWriter::new(
+            BufWriter::new(File::create(&wav_path)?),
+            RECORDING_SAMPLE_RATE,
+        )?;
+        Ok(Recorder {
+            sink,
+            path,
+            ffmpeg: config.ffmpeg.clone(),
+            width,
+            height,
+            frames: 0,
+            wav_path,
+            wav,
+            resampler: Resampler::new(cpu_clock_hz, RECORDING_SAMPLE_RATE),
+            samples: Vec::new(),
+        })
+    }
+
+    /// Where the recording is going, a directory for image sequences
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Add a frame of RGB24 pixels and the APU samples produced while it was emulated
+    pub fn push_frame(&mut self, rgb: &[u8], apu_samples: &[f32]) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Images => {
+                let name = format!("frame_{:06}.png", self.frames);
+                fs::write(
+                    self.path.join(name),
+                    encode_png(rgb, self.width, self.height),
+                )?;
+            }
+            Sink::Ffmpeg { stdin, .. } => stdin.write_all(rgb)?,
+        }
+        self.frames += 1;
+
+        self.samples.clear();
+        let samples = &mut self.samples;
+        self.resampler
+            .process(apu_samples, |sample| samples.push(sample));
+        self.wav.write_samples(&self.samples)
+    }
+
+    /// Stop recording and write everything out, returning where it went
+    pub fn finish(self) -> io::Result<PathBuf> {
+        self.wav.finish()?;
+        if let Sink::Ffmpeg {
+            mut child,
+            stdin,
+            video,
+        } = self.sink
+        {
+            // closing stdin tells ffmpeg the video is over
+            drop(stdin.into_inner().map_err(|e| e.into_error())?);
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("ffmpeg failed: {}", status)));
+            }
+            let status = Command::new(&self.ffmpeg)
+                .args(["-y", "-loglevel", "error", "-i"])
+                .arg(&video)
+                .arg("-i")
+                .arg(&self.wav_path)
+                .args(["-c:v", "copy", "-shortest"])
+                .arg(&self.path)
+                .status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "ffmpeg failed to add audio: {}",
+                    status
+                )));
+            }
+            fs::remove_file(&video)?;
+            fs::remove_file(&self.wav_path)?;
+        }
+        Ok(self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::DeflateDecoder;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn png_decodes() {
+        // big enough to need two stored blocks
+        let (width, height) = (256, 100);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| i as u8).collect();
+        let png = encode_png(&rgb, width, height);
+        assert!(png.starts_with(&PNG_SIGNATURE));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 1, 0, 0, 0, 0, 100]);
+
+        let idat_length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let zlib = &png[41..41 + idat_length];
+        let mut decoder = DeflateDecoder::new(&zlib[2..]);
+        let mut raw = Vec::new();
+        decoder.read_to_end(&mut raw).unwrap();
+        let used = decoder.total_in() as usize;
+        assert_eq!(raw.len(), (width * 3 + 1) * height);
+        assert_eq!(raw[0], 0);
+        assert_eq!(raw[1..width * 3 + 1], rgb[..width * 3]);
+        let mut adler = Adler32::new();
+        adler.update(&raw);
+        assert_eq!(zlib[2 + used..], adler.finish().to_be_bytes());
+        assert!(png.ends_with(b"IEND\xAE\x42\x60\x82"));
+    }
+
+    #[test]
+    fn wav_header_sizes() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(bytes[4..8], 42u32.to_le_bytes());
+        assert_eq!(bytes[24..28], 48000u32.to_le_bytes());
+        assert_eq!(bytes[40..44], 6u32.to_le_bytes());
+        assert_eq!(bytes[46..48], i16::MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn records_image_sequence() {
+        let directory =
+            std::env::temp_dir().join(format!("nesemu-recording-{}", std::process::id()));
+        let config = RecordingConfig {
+            directory: directory.clone(),
+            ..RecordingConfig::default()
+        };
+        let mut recorder = Recorder::start(&config, 2, 2, 60.0, 1_789_773).unwrap();
+        for _ in 0..2 {
+            recorder.push_frame(&[0xFF; 12], &[0.25; 29830]).unwrap();
+        }
+        let path = recorder.finish().unwrap();
+        assert_eq!(path, directory.join("recording-001"));
+        assert!(path.join("frame_000001.png").exists());
+        let wav = fs::read(path.join("audio.wav")).unwrap();
+        // two frames of audio at 48kHz is 1600 samples, give or take the resampler's phase
+        let samples = (wav.len() - 44) / 2;
+        assert!((1599..=1601).contains(&samples), "{}", samples);
+
+        // the next one doesn't overwrite it
+        let next = Recorder::start(&config, 2, 2, 60.0, 1_789_773).unwrap();
+        assert_eq!(next.path(), directory.join("recording-002"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+// Console timing differences. Every region has 341 dots per scanline and 240 visible lines,
+// they differ in clocks, frame length and where vblank starts.
+//
+//                  NTSC        PAL         Dendy
+// CPU clock        1789773 Hz  1662607 Hz  1773448 Hz
+// PPU dots/cycle   3           3.2         3
+// scanlines        262         312         312
+// vblank scanline  241         241         291
+// odd frame skip   yes         no          no
+// pixel aspect     8:7         ~1.386      ~1.386
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /// "ntsc", "pal" or "dendy"
+    pub fn parse(text: &str) -> Option<Region> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+            Region::Dendy => "dendy",
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+
+    /// PPU dots per CPU cycle as a fraction (numerator, denominator)
+    pub fn dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// The line vblank (and the NMI) starts on
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // Dendy keeps 50 post-render lines before vblank so NTSC game logic fits
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+
+    /// Only the NTSC PPU shortens odd frames by a dot
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    /// Width of a pixel relative to its height on a CRT
+    pub fn pixel_aspect_ratio(&self) -> f64 {
+        match self {
+            Region::Ntsc => 8.0 / 7.0,
+            Region::Pal | Region::Dendy => 2_950_000.0 / 2_128_137.0,
+        }
+    }
+
+    pub fn frames_per_second(&self) -> f64 {
+        let (dots, cycles) = self.dots_per_cpu_cycle();
+        let frame_dots = 341.0 * self.scanlines_per_frame() as f64;
+        self.cpu_clock_hz() as f64 * dots as f64 / cycles as f64 / frame_dots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rates() {
+        assert!((Region::Ntsc.frames_per_second() - 60.1).abs() < 0.01);
+        assert!((Region::Pal.frames_per_second() - 50.007).abs() < 0.01);
+        assert!((Region::Dendy.frames_per_second() - 50.0).abs() < 0.1);
+        assert_eq!(Region::Pal.pre_render_scanline(), 311);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+
+// Rewind keeps a save state every few frames in a bounded ring, oldest dropped first. Only the
+// newest state is kept whole, each older one is stored as the difference to the state after it:
+// the two XORed together, so unchanged bytes come out as zeros, with the zero runs packed. Going
+// back a step rebuilds the state before the newest from it and its difference.
+//
+// A difference is the length of the older state as a varint, then pairs of varints, a run of
+// unchanged bytes and a count of changed ones, each pair followed by that many XORed bytes.
+//
+// The buffers of dropped snapshots are kept for the next ones, whole states and differences
+// apart so the small differences don't end up holding state sized allocations. Once the ring
+// is full taking snapshots allocates nothing.
+
+/// How far back rewind goes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RewindConfig {
+    /// Seconds of play kept, 0 turns rewind off
+    pub seconds: u32,
+    /// Frames between snapshots. Rewinding steps back one snapshot a frame, so this is also
+    /// how many times faster than real time it goes.
+    pub interval: u32,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig {
+            seconds: 10,
+            interval: 2,
+        }
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], offset: &mut usize) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = *data.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// byte past `newer`'s end counts as 0, so states of different lengths still line up
+fn xor_at(newer: &[u8], index: usize, byte: u8) -> u8 {
+    byte ^ newer.get(index).copied().unwrap_or(0)
+}
+
+/// Encode `older` as its difference to `newer`
+pub fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_delta_into(&mut out, older, newer);
+    out
+}
+
+/// `encode_delta` into `out`, replacing what it held
+pub fn encode_delta_into(out: &mut Vec<u8>, older: &[u8], newer: &[u8]) {
+    out.clear();
+    push_varint(out, older.len());
+    let mut index = 0;
+    while index < older.len() {
+        let unchanged = older[index..]
+            .iter()
+            .enumerate()
+            .take_while(|&(i, &byte)| xor_at(newer, index + i, byte) == 0)
+            .count();
+        index += unchanged;
+        let changed = older[index..]
+            .iter()
+            .enumerate()
+            .take_while(|&(i, &byte)| xor_at(newer, index + i, byte) != 0)
+            .count();
+        push_varint(out, unchanged);
+        push_varint(out, changed);
+        out.extend((index..index + changed).map(|i| xor_at(newer, i, older[i])));
+        index += changed;
+    }
+}
+
+/// Rebuild the older state from `newer` and the difference `encode_delta` made, None if the
+/// difference is damaged
+pub fn apply_delta(delta: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+    let mut offset = 0;
+    let len = read_varint(delta, &mut offset)?;
+    let mut older: Vec<u8> = (0..len)
+        .map(|i| newer.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut index = 0;
+    while offset < delta.len() {
+        index += read_varint(delta, &mut offset)?;
+        let changed = read_varint(delta, &mut offset)?;
+        let bytes = delta.get(offset..offset + changed)?;
+        older
+            .get_mut(index..index + changed)?
+            .iter_mut()
+            .zip(bytes)
+            .for_each(|(byte, xor)| *byte ^= xor);
+        offset += changed;
+        index += changed;
+    }
+    Some(older)
+}
+
+/// Ring of past save states
+#[derive(Debug, Clone)]
+pub struct RewindBuffer {
+    interval: u32,
+    capacity: usize,
+    // frames since the last snapshot
+    frames: u32,
+    // oldest first, each the difference to the one after it
+    deltas: VecDeque<Vec<u8>>,
+    newest: Option<Vec<u8>>,
+    // buffers to reuse
+    spare_state: Option<Vec<u8>>,
+    spare_deltas: Vec<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Enough room for `config.seconds` of play at `frames_per_second`
+    pub fn new(config: RewindConfig, frames_per_second: f64) -> Self {
+        let interval = config.interval.max(1);
+        let snapshots = config.seconds as f64 * frames_per_second / interval as f64;
+        RewindBuffer {
+            interval,
+            capacity: snapshots.ceil() as usize,
+            frames: 0,
+            deltas: VecDeque::new(),
+            newest: None,
+            spare_state: None,
+            spare_deltas: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Snapshots held
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes the snapshots take up
+    pub fn memory_used(&self) -> usize {
+        self.deltas.iter().map(Vec::len).sum::<usize>() + self.newest.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Count a frame run, taking a snapshot with `save_state` when one is due. It saves into
+    /// the buffer it's given, see `Emulator::save_state_into`.
+    pub fn frame(&mut self, save_state: impl FnOnce(&mut Vec<u8>)) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.frames == 0 {
+            let mut state = self.spare_state.take().unwrap_or_default();
+            save_state(&mut state);
+            self.push(state);
+        }
+        self.frames = (self.frames + 1) % self.interval;
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(previous) = self.newest.replace(state) {
+            let mut delta = self.spare_deltas.pop().unwrap_or_default();
+            encode_delta_into(&mut delta, &previous, self.newest.as_ref().unwrap());
+            self.deltas.push_back(delta);
+            self.spare_state = Some(previous);
+        }
+        while self.len() > self.capacity {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.spare_deltas.push(oldest),
+                None => break,
+            }
+        }
+    }
+
+    /// Take the newest snapshot off, to go back to it
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            // a damaged difference loses everything before it
+            self.newest = apply_delta(&delta, &newest);
+            if self.newest.is_none() {
+                self.deltas.clear();
+            }
+            self.spare_deltas.push(delta);
+        }
+        // the next snapshot after rewinding is a whole interval away
+        self.frames = 1 % self.interval;
+        Some(newest)
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+        self.newest = None;
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_round_trip() {
+        let newer: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut older = newer.clone();
+        older[5] ^= 0xFF;
+        older[6] = 0;
+        older[200] = 0x42;
+        let delta = encode_delta(&older, &newer);
+        assert!(delta.len() < 20, "{}", delta.len());
+        assert_eq!(apply_delta(&delta, &newer), Some(older.clone()));
+
+        // states can change length
+        assert_eq!(
+            apply_delta(&encode_delta(&older, &newer[..100]), &newer[..100]),
+            Some(older.clone())
+        );
+        assert_eq!(
+            apply_delta(&encode_delta(&older[..50], &newer), &newer),
+            Some(older[..50].to_vec())
+        );
+        assert_eq!(apply_delta(&delta[..delta.len() - 1], &newer), None);
+    }
+
+    #[test]
+    fn pops_newest_first_and_drops_the_oldest() {
+        let config = RewindConfig {
+            seconds: 1,
+            interval: 1,
+        };
+        let mut buffer = RewindBuffer::new(config, 4.0);
+        for state in 0..6u8 {
+            buffer.push(vec![state; 16]);
+        }
+        assert_eq!(buffer.len(), 4);
+        for state in (2..6u8).rev() {
+            assert_eq!(buffer.pop(), Some(vec![state; 16]));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn snapshots_every_interval() {
+        let config = RewindConfig {
+            seconds: 10,
+            interval: 3,
+        };
+        let mut buffer = RewindBuffer::new(config, 60.0);
+        let mut taken = 0;
+        for _ in 0..7 {
+            buffer.frame(|state| {
+                taken += 1;
+                state.push(taken);
+            });
+        }
+        assert_eq!(taken, 3);
+
+        let mut off = RewindBuffer::new(
+            RewindConfig {
+                seconds: 0,
+                ..config
+            },
+            60.0,
+        );
+        off.frame(|_| panic!("rewind is off"));
+        assert!(off.is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+// The one source of randomness in the emulator. Anything that comes out differently from one
+// power on to the next on hardware (RAM contents, how the CPU and PPU clocks line up, how long
+// the PPU's open bus takes to fade) draws from a generator seeded from `Emulator::set_seed`, so
+// the same seed always gives the same run, for tests, TAS and replays.
+
+/// xorshift64*, seeded through splitmix64 so nearby seeds look nothing alike
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        state ^= state >> 31;
+        // xorshift never leaves 0
+        Rng { state: state | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    /// A number from 0 up to but not including `bound`, which must not be 0
+    pub fn below(&mut self, bound: u32) -> u32 {
+        (((self.next_u64() >> 32) * bound as u64) >> 32) as u32
+    }
+
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for byte in bytes.iter_mut() {
+            *byte = self.next_u8();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depends_only_on_the_seed() {
+        let bytes = |seed| {
+            let mut bytes = [0u8; 64];
+            Rng::new(seed).fill(&mut bytes);
+            bytes
+        };
+        assert_eq!(bytes(1), bytes(1));
+        assert_ne!(bytes(1), bytes(2));
+        assert!(bytes(0).iter().any(|&byte| byte != 0));
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        let mut rng = Rng::new(5);
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            let value = rng.below(3);
+            assert!(value < 3);
+            seen[value as usize] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+}
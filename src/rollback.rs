@@ -0,0 +1,436 @@
+use crate::emulator::Emulator;
+use crate::netplay::{checksum, NetplayConfig, NetplayError, Packet, Session, Transport};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+// Rollback netplay, as GGPO does it. Instead of waiting for the other player's buttons, each
+// frame runs straight away with a guess at them: whatever they last had pressed. When the real
+// buttons arrive and differ from the guess, the console goes back to a save state from before
+// the first wrong frame and runs forward again to the present with the right buttons, all
+// within one step, so the player only sees the picture correct itself. Sound from frames run
+// again is thrown away, it has already been played.
+//
+// A save state is taken at the start of every frame for the last `max_frames` frames, which
+// is also as far as this side runs ahead of the other's buttons before it stops and waits.
+//
+// It speaks the same packets as lockstep netplay, and checksums the same frames: the state at
+// the end of every `checksum_interval`th frame, once every button before it is known for sure.
+
+/// Per frame values starting from some frame
+#[derive(Debug, Clone)]
+struct FrameQueue<T> {
+    base: u32,
+    items: VecDeque<T>,
+}
+
+impl<T> FrameQueue<T> {
+    fn new() -> Self {
+        FrameQueue {
+            base: 0,
+            items: VecDeque::new(),
+        }
+    }
+
+    // first frame without a value
+    fn end(&self) -> u32 {
+        self.base + self.items.len() as u32
+    }
+
+    fn get(&self, frame: u32) -> Option<&T> {
+        self.items.get(frame.checked_sub(self.base)? as usize)
+    }
+
+    // replace the value for `frame`, or add it when it's the next one
+    fn set(&mut self, frame: u32, item: T) {
+        match frame.checked_sub(self.base).map(|i| i as usize) {
+            Some(i) if i < self.items.len() => self.items[i] = item,
+            Some(i) if i == self.items.len() => self.items.push_back(item),
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        self.items.push_back(item);
+    }
+
+    fn drop_before(&mut self, frame: u32) {
+        while self.base < frame && !self.items.is_empty() {
+            self.items.pop_front();
+            self.base += 1;
+        }
+    }
+}
+
+/// A rollback netplay session
+#[derive(Debug)]
+pub struct Rollback<T: Transport> {
+    transport: T,
+    config: NetplayConfig,
+    max_frames: u32,
+    // next frame to run
+    frame: u32,
+    local: FrameQueue<u8>,
+    // the other side's buttons that have arrived, ending at the first frame still guessed
+    remote: FrameQueue<u8>,
+    last_remote: u8,
+    // the other side's buttons each frame was last run with, guessed or not
+    used: FrameQueue<u8>,
+    // save state at the start of each frame
+    states: FrameQueue<Vec<u8>>,
+    peer_ack: u32,
+    // earliest frame run with a wrong guess
+    mispredicted: Option<u32>,
+    next_checksum: u32,
+    checksums: VecDeque<(u32, u32)>,
+    remote_checksum: Option<(u32, u32)>,
+    rollbacks: u64,
+    last_heard: Option<Instant>,
+}
+
+// local checksums kept to compare with the other side's
+const CHECKSUM_HISTORY: usize = 16;
+
+impl<T: Transport> Rollback<T> {
+    /// Start at frame 0, guessing up to `max_frames` ahead of the other side. Both sides have
+    /// to have the same game freshly loaded, with the same power on settings.
+    pub fn new(transport: T, config: NetplayConfig, max_frames: u32) -> Self {
+        let mut local = FrameQueue::new();
+        let mut remote = FrameQueue::new();
+        // nothing is pressed for the first `delay` frames
+        for _ in 0..config.delay {
+            local.push(0);
+            remote.push(0);
+        }
+        Rollback {
+            transport,
+            config,
+            max_frames: max_frames.max(1),
+            frame: 0,
+            local,
+            remote,
+            last_remote: 0,
+            used: FrameQueue::new(),
+            states: FrameQueue::new(),
+            peer_ack: 0,
+            mispredicted: None,
+            next_checksum: 0,
+            checksums: VecDeque::new(),
+            remote_checksum: None,
+            rollbacks: 0,
+            last_heard: None,
+        }
+    }
+
+    /// The next frame to run
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// How many times a wrong guess has sent the console back
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    /// Frames run on guessed buttons that could still be taken back
+    pub fn frames_ahead(&self) -> u32 {
+        self.frame.saturating_sub(self.remote.end())
+    }
+
+    fn receive(&mut self, packet: &[u8]) -> Result<(), NetplayError> {
+        let Some(packet) = Packet::decode(packet) else {
+            return Ok(());
+        };
+        self.peer_ack = self.peer_ack.max(packet.ack);
+        for (frame, &pressed) in (packet.first..=u32::MAX).zip(&packet.buttons) {
+            if frame != self.remote.end() {
+                continue;
+            }
+            self.remote.push(pressed);
+            self.last_remote = pressed;
+            if self.used.get(frame).is_some_and(|&guess| guess != pressed) {
+                self.mispredicted = Some(self.mispredicted.map_or(frame, |f| f.min(frame)));
+            }
+        }
+        if packet.checksum.is_some() {
+            self.remote_checksum = packet.checksum;
+        }
+        Ok(())
+    }
+
+    // run `frame` with the best buttons known for it, saving the state it ends in
+    fn run(&mut self, emulator: &mut Emulator, frame: u32) {
+        let local = self.local.get(frame).copied().unwrap_or(0);
+        let remote = self.remote.get(frame).copied().unwrap_or(self.last_remote);
+        let inputs = if self.config.local_player == 0 {
+            [local, remote]
+        } else {
+            [remote, local]
+        };
+        for (player, pressed) in inputs.into_iter().enumerate() {
+            emulator.set_buttons(player, pressed);
+        }
+        emulator.advance_frame();
+        self.used.set(frame, remote);
+        self.states.set(frame + 1, emulator.save_state());
+    }
+
+    // go back to before the first wrong guess and run up to the present again
+    fn roll_back(&mut self, emulator: &mut Emulator, from: u32) -> Result<(), NetplayError> {
+        let state = self
+            .states
+            .get(from)
+            .ok_or(NetplayError::Desync { frame: from })?;
+        emulator
+            .load_state(state)
+            .map_err(|_| NetplayError::Desync { frame: from })?;
+        for frame in from..self.frame {
+            self.run(emulator, frame);
+        }
+        emulator.clear_audio_samples();
+        self.rollbacks += 1;
+        Ok(())
+    }
+
+    // checksum every state whose buttons are all known, then compare with the other side's
+    fn check_states(&mut self) -> Result<(), NetplayError> {
+        let interval = self.config.checksum_interval.max(1);
+        let known = self.remote.end().min(self.frame);
+        while self.next_checksum < known {
+            let frame = self.next_checksum;
+            if let Some(state) = self.states.get(frame + 1) {
+                if self.checksums.len() == CHECKSUM_HISTORY {
+                    self.checksums.pop_front();
+                }
+                self.checksums.push_back((frame, checksum(state)));
+            }
+            self.next_checksum += interval;
+        }
+        let Some((frame, remote)) = self.remote_checksum else {
+            return Ok(());
+        };
+        match self.checksums.iter().find(|&&(f, _)| f == frame) {
+            Some(&(_, local)) if local != remote => Err(NetplayError::Desync { frame }),
+            _ => Ok(()),
+        }
+    }
+
+    fn packet(&self) -> Vec<u8> {
+        let first = self.peer_ack.max(self.local.base);
+        let buttons = (first..self.local.end())
+            .take(u8::MAX as usize)
+            .filter_map(|frame| self.local.get(frame).copied())
+            .collect();
+        Packet {
+            ack: self.remote.end(),
+            first,
+            buttons,
+            checksum: self.checksums.back().copied(),
+        }
+        .encode()
+    }
+}
+
+impl<T: Transport> Session for Rollback<T> {
+    /// Runs the next frame on guessed buttons unless too far ahead of the other side, first
+    /// going back over any frames guessed wrong
+    fn step(&mut self, emulator: &mut Emulator, buttons: u8) -> Result<bool, NetplayError> {
+        if self.states.end() == 0 {
+            self.states.push(emulator.save_state());
+        }
+        if self.local.end() <= self.frame + self.config.delay {
+            self.local.push(buttons);
+        }
+        while let Some(packet) = self.transport.recv()? {
+            self.last_heard = Some(Instant::now());
+            self.receive(&packet)?;
+        }
+        if let Some(from) = self.mispredicted.take() {
+            self.roll_back(emulator, from)?;
+        }
+        self.check_states()?;
+
+        let ran = self.frames_ahead() < self.max_frames;
+        if ran {
+            self.run(emulator, self.frame);
+            self.frame += 1;
+        }
+        let oldest = self.frame.saturating_sub(self.max_frames);
+        self.states.drop_before(oldest);
+        self.used.drop_before(oldest);
+        self.remote.drop_before(oldest);
+        self.local.drop_before(oldest.min(self.peer_ack));
+
+        self.transport.send(&self.packet())?;
+        if !ran
+            && self
+                .last_heard
+                .is_some_and(|heard| heard.elapsed() > self.config.timeout)
+        {
+            return Err(NetplayError::TimedOut);
+        }
+        Ok(ran)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Bus;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // packets and how many more receives until they arrive
+    type Queue = Arc<Mutex<VecDeque<(u32, Vec<u8>)>>>;
+
+    // one end of an in memory link where packets take `latency` receives to arrive
+    struct Link {
+        outgoing: Queue,
+        incoming: Queue,
+        latency: u32,
+    }
+
+    fn link_pair(latency: u32) -> (Link, Link) {
+        let a: Queue = Arc::default();
+        let b: Queue = Arc::default();
+        (
+            Link {
+                outgoing: Arc::clone(&a),
+                incoming: Arc::clone(&b),
+                latency,
+            },
+            Link {
+                outgoing: b,
+                incoming: a,
+                latency,
+            },
+        )
+    }
+
+    impl Transport for Link {
+        fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            let latency = self.latency;
+            self.outgoing
+                .lock()
+                .unwrap()
+                .push_back((latency, packet.to_vec()));
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+            let mut incoming = self.incoming.lock().unwrap();
+            match incoming.front_mut() {
+                Some((0, _)) => Ok(incoming.pop_front().map(|(_, packet)| packet)),
+                Some(_) => {
+                    incoming
+                        .iter_mut()
+                        .for_each(|(wait, _)| *wait = wait.saturating_sub(1));
+                    Ok(None)
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    // copies both controllers' first bit to $10 and $11 and counts frames of player 2 holding
+    // A in $12, so a wrong guess shows in RAM
+    const READ_PADS: [u8; 30] = [
+        0xA9, 0x01, 0x8D, 0x16, 0x40, // strobe
+        0xA9, 0x00, 0x8D, 0x16, 0x40, //
+        0xAD, 0x16, 0x40, 0x85, 0x10, // LDA $4016, STA $10
+        0xAD, 0x17, 0x40, 0x85, 0x11, // LDA $4017, STA $11
+        0x29, 0x01, 0xF0, 0x02, // AND #1, BEQ over the INC
+        0xE6, 0x12, // INC $12
+        0x4C, 0x00, 0x80, // JMP $8000
+        0xEA,
+    ];
+
+    fn console() -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().set_logging(false);
+        emulator.cpu_mut().load_bytes(&READ_PADS);
+        emulator
+    }
+
+    fn config(local_player: usize) -> NetplayConfig {
+        NetplayConfig {
+            delay: 1,
+            local_player,
+            checksum_interval: 1,
+            ..NetplayConfig::default()
+        }
+    }
+
+    #[test]
+    fn wrong_guesses_are_rolled_back() {
+        let (link_a, link_b) = link_pair(3);
+        let mut a = Rollback::new(link_a, config(0), 8);
+        let mut b = Rollback::new(link_b, config(1), 8);
+        let (mut console_a, mut console_b) = (console(), console());
+        for step in 0..60u32 {
+            // player 2 changes buttons every few frames, which player 1 keeps guessing wrong
+            let pressed = (step / 5 % 2) as u8;
+            a.step(&mut console_a, 0).unwrap();
+            b.step(&mut console_b, pressed).unwrap();
+        }
+        assert!(a.rollbacks() > 0);
+        assert!(a.frame() > 40 && b.frame() > 40);
+        // every frame was checksummed, the same frames match on both sides
+        let matched = a
+            .checksums
+            .iter()
+            .filter(|checksum| b.checksums.contains(checksum))
+            .count();
+        assert!(matched > 0);
+        for (frame, crc) in &a.checksums {
+            if let Some((_, other)) = b.checksums.iter().find(|(f, _)| f == frame) {
+                assert_eq!(crc, other, "frame {}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn stops_after_max_frames_of_guessing() {
+        let (link_a, _link_b) = link_pair(0);
+        let mut a = Rollback::new(link_a, config(0), 4);
+        let mut console_a = console();
+        let ran = (0..10)
+            .filter(|_| a.step(&mut console_a, 0).unwrap())
+            .count();
+        // the delay frame is known, then 4 guessed
+        assert_eq!(ran, 5);
+        assert_eq!(a.frames_ahead(), 4);
+    }
+
+    #[test]
+    fn ignores_frames_past_the_last_one() {
+        let (link_a, _link_b) = link_pair(0);
+        let mut a = Rollback::new(link_a, config(0), 4);
+        let known = a.remote.end();
+        for buttons in [vec![1, 2], Vec::new()] {
+            let packet = Packet {
+                ack: 0,
+                first: u32::MAX,
+                buttons,
+                checksum: None,
+            };
+            a.receive(&packet.encode()).unwrap();
+        }
+        assert_eq!(a.remote.end(), known);
+    }
+
+    #[test]
+    fn different_states_are_a_desync() {
+        let (link_a, link_b) = link_pair(1);
+        let mut a = Rollback::new(link_a, config(0), 8);
+        let mut b = Rollback::new(link_b, config(1), 8);
+        let (mut console_a, mut console_b) = (console(), console());
+        console_b.cpu_mut().memory.write_byte(0x0300, 0x42);
+        let result = (0..20)
+            .map(|_| {
+                a.step(&mut console_a, 0)?;
+                b.step(&mut console_b, 0)
+            })
+            .find(Result::is_err);
+        assert!(matches!(result, Some(Err(NetplayError::Desync { .. }))));
+    }
+}
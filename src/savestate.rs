@@ -0,0 +1,298 @@
+use std::fmt;
+
+// Save states: a snapshot of everything that changes while a game runs (CPU registers, RAM,
+// PPU and APU registers and counters, mapper registers, cartridge RAM), so the game can be
+// put back exactly where it was. The rom itself isn't included, only a CRC-32 of it to catch
+// a state being loaded into the wrong game.
+//
+//   "NESS"           magic
+//   u16              format version, `STATE_VERSION`
+//   u8, u32          1 and the game's CRC-32, or 0 and 0 with nothing loaded
+//   ...              each component's fields in a fixed order, see the `save_state` methods
+//
+// Numbers are little endian, bools a byte, variable length data a u32 length then the bytes.
+// Anything that changes the fields or their order must bump `STATE_VERSION`, states with a
+// version this build doesn't know are refused rather than misread.
+
+pub const STATE_MAGIC: &[u8; 4] = b"NESS";
+pub const STATE_VERSION: u16 = 2;
+
+/// Why a save state couldn't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// Doesn't start with "NESS"
+    BadMagic,
+    /// Written by a newer build with a format this one doesn't know
+    NewerVersion(u16),
+    /// Written by an older build whose format is no longer read
+    UnsupportedVersion(u16),
+    /// The state belongs to a different rom
+    WrongGame,
+    /// Ends part way through
+    Truncated,
+    /// Something in the state that can't be true of this console, such as a RAM size
+    /// different from the cartridge's
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::NewerVersion(version) => write!(
+                f,
+                "save state is format {}, this version only reads up to {}",
+                version, STATE_VERSION
+            ),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state format {} is no longer supported", version)
+            }
+            StateError::WrongGame => write!(f, "save state is from a different game"),
+            StateError::Truncated => write!(f, "save state is cut short"),
+            StateError::Invalid(what) => write!(f, "save state has a bad {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Builds up a save state
+#[derive(Debug, Clone, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write into `buffer` in place of whatever it held, keeping its allocation
+    pub fn reuse(mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        StateWriter { data: buffer }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Length then the bytes
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// What `write` writes as one length prefixed block, read back with `StateReader::bytes`.
+    /// The same as writing into a writer of its own and `bytes` of that, without the copy.
+    pub fn block(&mut self, write: impl FnOnce(&mut StateWriter)) {
+        let start = self.data.len();
+        self.u32(0);
+        write(self);
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads a save state back in the order it was written
+#[derive(Debug, Clone)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < count {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Bytes written with `StateWriter::bytes`
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Bytes written with `StateWriter::bytes` into `out`, which they have to fill exactly.
+    /// `what` names them in the error otherwise.
+    pub fn bytes_into(&mut self, out: &mut [u8], what: &'static str) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != out.len() {
+            return Err(StateError::Invalid(what));
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Error unless everything has been read
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::Invalid("length"))
+        }
+    }
+}
+
+/// Something with state that goes into a save state
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    /// Restore what `save_state` wrote. On error the state may be partly loaded.
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Start a state with the header for the game with CRC-32 `game`
+pub fn write_header(state: &mut StateWriter, game: Option<u32>) {
+    state.data.extend_from_slice(STATE_MAGIC);
+    state.u16(STATE_VERSION);
+    state.bool(game.is_some());
+    state.u32(game.unwrap_or(0));
+}
+
+/// Check a state's header, it has to be for `game`
+pub fn read_header(state: &mut StateReader, game: Option<u32>) -> Result<(), StateError> {
+    if state.take(STATE_MAGIC.len()).ok() != Some(STATE_MAGIC.as_slice()) {
+        return Err(StateError::BadMagic);
+    }
+    match state.u16()? {
+        STATE_VERSION => {}
+        version if version > STATE_VERSION => return Err(StateError::NewerVersion(version)),
+        version => return Err(StateError::UnsupportedVersion(version)),
+    }
+    let has_game = state.bool()?;
+    let crc = state.u32()?;
+    if has_game.then_some(crc) != game {
+        return Err(StateError::WrongGame);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_fields() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u32(0x789A_BCDE);
+        writer.u64(u64::MAX - 1);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u32(), Ok(0x789A_BCDE));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 1));
+        let mut out = [0; 3];
+        reader.bytes_into(&mut out, "test").unwrap();
+        assert_eq!(out, [1, 2, 3]);
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+        assert!(reader.finish().is_ok());
+    }
+
+    #[test]
+    fn blocks_match_bytes() {
+        let mut nested = StateWriter::new();
+        nested.u16(0xBEEF);
+        let mut expected = StateWriter::new();
+        expected.bytes(&nested.finish());
+
+        let mut writer = StateWriter::reuse(vec![0xFF; 64]);
+        writer.block(|block| block.u16(0xBEEF));
+        assert_eq!(writer.finish(), expected.finish());
+    }
+
+    #[test]
+    fn header_checks() {
+        let mut writer = StateWriter::new();
+        write_header(&mut writer, Some(0xCAFE));
+        let data = writer.finish();
+        assert_eq!(
+            read_header(&mut StateReader::new(&data), Some(0xCAFE)),
+            Ok(())
+        );
+        assert_eq!(
+            read_header(&mut StateReader::new(&data), Some(0xBEEF)),
+            Err(StateError::WrongGame)
+        );
+        assert_eq!(
+            read_header(&mut StateReader::new(&data), None),
+            Err(StateError::WrongGame)
+        );
+        assert_eq!(
+            read_header(&mut StateReader::new(b"NESM\x01\x00"), None),
+            Err(StateError::BadMagic)
+        );
+
+        let mut newer = data.clone();
+        newer[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            read_header(&mut StateReader::new(&newer), Some(0xCAFE)),
+            Err(StateError::NewerVersion(STATE_VERSION + 1))
+        );
+        assert_eq!(
+            read_header(&mut StateReader::new(&data[..8]), Some(0xCAFE)),
+            Err(StateError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// How the picture is sized in the window. Integer scales keep every NES pixel the same size
+// (vertically, the width is stretched by the pixel aspect ratio anyway), fit fills as much of
+// the window as the aspect ratio allows. Both center the picture and leave black bars.
+
+pub const MAX_SCALE: u32 = 6;
+/// Also the starting window size in fit mode
+pub const DEFAULT_SCALE: u32 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ScaleMode {
+    /// 1 to `MAX_SCALE` times the picture, dropping to whatever fits in a smaller window
+    Integer(u32),
+    Fit,
+}
+
+impl Default for ScaleMode {
+    fn default() -> Self {
+        ScaleMode::Integer(DEFAULT_SCALE)
+    }
+}
+
+impl ScaleMode {
+    /// "1" to "6", or "fit"
+    pub fn parse(text: &str) -> Option<ScaleMode> {
+        match text.trim() {
+            "fit" => Some(ScaleMode::Fit),
+            scale => match scale.parse() {
+                Ok(scale @ 1..=MAX_SCALE) => Some(ScaleMode::Integer(scale)),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct VideoConfig {
+    pub scale: ScaleMode,
+    /// Borderless fullscreen at the desktop resolution
+    pub fullscreen: bool,
+}
+
+/// Rectangle in window pixels, x and y from the top left
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Window size for a picture of `picture` pixels at an integer scale
+pub fn window_size(picture: (u32, u32), scale: u32) -> (u32, u32) {
+    let scale = scale.clamp(1, MAX_SCALE);
+    (picture.0 * scale, picture.1 * scale)
+}
+
+/// Where to draw a `picture` sized image in a `window` sized area
+pub fn viewport(window: (u32, u32), picture: (u32, u32), mode: ScaleMode) -> Viewport {
+    let (picture_width, picture_height) = (picture.0.max(1), picture.1.max(1));
+    let (width, height) = match mode {
+        ScaleMode::Integer(scale) => {
+            let fits = (window.0 / picture_width).min(window.1 / picture_height);
+            let scale = scale.clamp(1, MAX_SCALE).min(fits.max(1));
+            (picture_width * scale, picture_height * scale)
+        }
+        ScaleMode::Fit => {
+            // compare the aspect ratios without dividing
+            if window.0 as u64 * picture_height as u64 > window.1 as u64 * picture_width as u64 {
+                let width = window.1 as u64 * picture_width as u64 / picture_height as u64;
+                (width as u32, window.1)
+            } else {
+                let height = window.0 as u64 * picture_height as u64 / picture_width as u64;
+                (window.0, height as u32)
+            }
+        }
+    };
+    Viewport {
+        x: window.0.saturating_sub(width) / 2,
+        y: window.1.saturating_sub(height) / 2,
+        width,
+        height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PICTURE: (u32, u32) = (293, 224);
+
+    #[test]
+    fn parse() {
+        assert_eq!(ScaleMode::parse("4"), Some(ScaleMode::Integer(4)));
+        assert_eq!(ScaleMode::parse("fit"), Some(ScaleMode::Fit));
+        assert_eq!(ScaleMode::parse("0"), None);
+        assert_eq!(ScaleMode::parse("7"), None);
+    }
+
+    #[test]
+    fn integer_scale_centres_and_shrinks_to_fit() {
+        let window = window_size(PICTURE, 3);
+        assert_eq!(window, (879, 672));
+        assert_eq!(
+            viewport(window, PICTURE, ScaleMode::Integer(3)),
+            Viewport {
+                x: 0,
+                y: 0,
+                width: 879,
+                height: 672
+            }
+        );
+        // fullscreen 1080p only fits 4x
+        let viewport = viewport((1920, 1080), PICTURE, ScaleMode::Integer(6));
+        assert_eq!((viewport.width, viewport.height), (1172, 896));
+        assert_eq!((viewport.x, viewport.y), (374, 92));
+    }
+
+    #[test]
+    fn fit_keeps_aspect_ratio() {
+        let wide = viewport((1920, 1080), PICTURE, ScaleMode::Fit);
+        assert_eq!((wide.width, wide.height), (1412, 1080));
+        assert_eq!(wide.x, 254);
+        let tall = viewport((586, 1000), PICTURE, ScaleMode::Fit);
+        assert_eq!((tall.width, tall.height), (586, 448));
+        assert_eq!(tall.y, 276);
+    }
+}
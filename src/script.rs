@@ -0,0 +1,433 @@
+use crate::emulator::Emulator;
+use crate::input::{button_from_name, PLAYERS};
+use crate::memory::Bus;
+use crate::osd::draw_text;
+use crate::overscan::Overscan;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST, INT};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{fmt, fs, io};
+
+// Rhai scripts for bots, HUDs and research, in the spirit of FCEUX's Lua scripting. A script's
+// top level runs once when it's loaded, and registers callbacks to run after every frame:
+//
+//   let deaths = 0;
+//   on_frame(|| {
+//       if read_byte(0x0E) == 0x0B { deaths += 1; }
+//       text(8, 16, `deaths ${deaths}`);
+//       set_buttons(1, "right+b");
+//   });
+//
+// Scripts get:
+//   read_byte(address), write_byte(address, value)   reads don't have side effects, writes do
+//   reg(name), set_reg(name, value)                  a, x, y, sp, pc or p
+//   frame_count()                                    frames since power on
+//   set_buttons(player, buttons)                     players from 1, buttons as a bit mask or
+//                                                    names joined with +, for the next frame only
+//   pixel(x, y, colour), rect(x, y, width, height, colour), text(x, y, message)
+//   on_frame(callback)
+//
+// Drawing is in frame coordinates, colours are 0xRRGGBB. What the callbacks draw stays up until
+// they run again after the next frame, and is only ever drawn over the frontend's copy of the
+// picture, like the on screen display.
+//
+// The emulator belongs to the frontend, so for the length of each call into the script it's
+// swapped into the host the registered functions share, and swapped back out after.
+
+// operations one call into the script may take before it's stopped, so a script stuck in a
+// loop can't hang the frontend
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+/// Why a script couldn't be loaded or stopped with an error
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(io::Error),
+    Syntax(String),
+    Runtime(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "{}", e),
+            ScriptError::Syntax(e) => write!(f, "syntax error: {}", e),
+            ScriptError::Runtime(e) => write!(f, "script error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<io::Error> for ScriptError {
+    fn from(e: io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        ScriptError::Runtime(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    Rect {
+        x: INT,
+        y: INT,
+        width: INT,
+        height: INT,
+        colour: [u8; 3],
+    },
+    Text {
+        x: INT,
+        y: INT,
+        text: String,
+    },
+}
+
+// what the registered functions share with the script's owner
+struct Host {
+    emulator: Emulator,
+    on_frame: Vec<FnPtr>,
+    // buttons the script is holding for the next frame, by player
+    buttons: [Option<u8>; PLAYERS],
+    overlay: Vec<Shape>,
+}
+
+type Shared = Rc<RefCell<Host>>;
+
+// what registered functions return when they can fail
+type Fallible<T> = Result<T, Box<EvalAltResult>>;
+
+/// A loaded script and its callbacks
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    host: Shared,
+}
+
+impl Script {
+    /// Compile `source` and run its top level against `emulator`
+    pub fn new(source: &str, emulator: &mut Emulator) -> Result<Script, ScriptError> {
+        let host = Rc::new(RefCell::new(Host {
+            emulator: Emulator::new(),
+            on_frame: Vec::new(),
+            buttons: [None; PLAYERS],
+            overlay: Vec::new(),
+        }));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        register(&mut engine, &host);
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError::Syntax(e.to_string()))?;
+        let script = Script { engine, ast, host };
+        script.with_emulator(emulator, |engine, ast| engine.run_ast(ast))?;
+        Ok(script)
+    }
+
+    /// Load the script in the file at `path`
+    pub fn load(path: &str, emulator: &mut Emulator) -> Result<Script, ScriptError> {
+        Self::new(&fs::read_to_string(path)?, emulator)
+    }
+
+    /// Run the frame callbacks, after `emulator` finishes a frame. The overlay and the held
+    /// buttons are cleared first, so they're whatever the callbacks leave.
+    pub fn frame(&mut self, emulator: &mut Emulator) -> Result<(), ScriptError> {
+        let callbacks = {
+            let mut host = self.host.borrow_mut();
+            host.overlay.clear();
+            host.buttons = [None; PLAYERS];
+            std::mem::take(&mut host.on_frame)
+        };
+        let result = self.with_emulator(emulator, |engine, ast| {
+            callbacks
+                .iter()
+                .try_for_each(|callback| callback.call::<Dynamic>(engine, ast, ()).map(drop))
+        });
+        // keep any the callbacks registered, after the ones that were there
+        let mut host = self.host.borrow_mut();
+        let added = std::mem::replace(&mut host.on_frame, callbacks);
+        host.on_frame.extend(added);
+        Ok(result?)
+    }
+
+    /// The buttons `player` (from 0) should have down next frame, `held` unless the script is
+    /// pressing its own
+    pub fn buttons(&self, player: usize, held: u8) -> u8 {
+        let host = self.host.borrow();
+        host.buttons.get(player).copied().flatten().unwrap_or(held)
+    }
+
+    /// Draw the overlay onto an RGB24 picture cropped by `overscan`
+    pub fn draw(&self, pixels: &mut [u8], pitch: usize, overscan: Overscan) {
+        let (left, top) = (overscan.left as INT, overscan.top as INT);
+        let (width, height) = (overscan.width() as INT, overscan.height() as INT);
+        for shape in &self.host.borrow().overlay {
+            match shape {
+                Shape::Rect {
+                    x,
+                    y,
+                    width: w,
+                    height: h,
+                    colour,
+                } => {
+                    let columns = (x - left).max(0)..(x.saturating_add(*w) - left).min(width);
+                    for row in (y - top).max(0)..(y.saturating_add(*h) - top).min(height) {
+                        for column in columns.clone() {
+                            let offset = row as usize * pitch + column as usize * 3;
+                            if let Some(pixel) = pixels.get_mut(offset..offset + 3) {
+                                pixel.copy_from_slice(colour);
+                            }
+                        }
+                    }
+                }
+                Shape::Text { x, y, text } => {
+                    let (x, y) = (x - left, y - top);
+                    if (0..width).contains(&x) && (0..height).contains(&y) {
+                        draw_text(pixels, width as usize, pitch, x as usize, y as usize, text);
+                    }
+                }
+            }
+        }
+    }
+
+    fn with_emulator<T>(&self, emulator: &mut Emulator, f: impl FnOnce(&Engine, &AST) -> T) -> T {
+        std::mem::swap(emulator, &mut self.host.borrow_mut().emulator);
+        let result = f(&self.engine, &self.ast);
+        std::mem::swap(emulator, &mut self.host.borrow_mut().emulator);
+        result
+    }
+}
+
+fn address(address: INT) -> Fallible<u16> {
+    u16::try_from(address).map_err(|_| format!("address {} is outside $0000-$FFFF", address).into())
+}
+
+fn player(player: INT) -> Fallible<usize> {
+    match usize::try_from(player) {
+        Ok(player @ 1..=PLAYERS) => Ok(player - 1),
+        _ => Err(format!("player {} isn't 1 to {}", player, PLAYERS).into()),
+    }
+}
+
+// "a+right" as button bits
+fn button_names(names: &str) -> Fallible<u8> {
+    names
+        .split('+')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .try_fold(0, |buttons, name| match button_from_name(name) {
+            Some(button) => Ok(buttons | button),
+            None => Err(format!("no button called {}", name).into()),
+        })
+}
+
+fn rgb(colour: INT) -> [u8; 3] {
+    let [_, _, _, _, _, r, g, b] = colour.to_be_bytes();
+    [r, g, b]
+}
+
+fn register(engine: &mut Engine, host: &Shared) {
+    let shared = host.clone();
+    engine.register_fn("read_byte", move |at: INT| -> Fallible<INT> {
+        let host = shared.borrow();
+        Ok(host.emulator.cpu().memory.peek_byte(address(at)?) as INT)
+    });
+    let shared = host.clone();
+    engine.register_fn("write_byte", move |at: INT, value: INT| -> Fallible<()> {
+        let mut host = shared.borrow_mut();
+        host.emulator
+            .cpu_mut()
+            .memory
+            .write_byte(address(at)?, value as u8);
+        Ok(())
+    });
+
+    let shared = host.clone();
+    engine.register_fn("reg", move |name: &str| -> Fallible<INT> {
+        let host = shared.borrow();
+        let reg = &host.emulator.cpu().reg;
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "a" => reg.accumulator as INT,
+            "x" => reg.idx as INT,
+            "y" => reg.idy() as INT,
+            "sp" => reg.sp() as INT,
+            "pc" => reg.pc as INT,
+            "p" => reg.status() as INT,
+            _ => return Err(format!("no register called {}", name).into()),
+        })
+    });
+    let shared = host.clone();
+    engine.register_fn("set_reg", move |name: &str, value: INT| -> Fallible<()> {
+        let mut host = shared.borrow_mut();
+        let reg = &mut host.emulator.cpu_mut().reg;
+        match name.to_ascii_lowercase().as_str() {
+            "a" => reg.accumulator = value as u8,
+            "x" => reg.idx = value as u8,
+            "y" => reg.set_idy(value as u8),
+            "sp" => reg.set_sp(value as u8),
+            "pc" => reg.pc = value as u16,
+            "p" => reg.set_status(value as u8),
+            _ => return Err(format!("no register called {}", name).into()),
+        }
+        Ok(())
+    });
+
+    let shared = host.clone();
+    engine.register_fn("frame_count", move || {
+        shared.borrow().emulator.frame_count() as INT
+    });
+
+    let shared = host.clone();
+    engine.register_fn(
+        "set_buttons",
+        move |which: INT, buttons: INT| -> Fallible<()> {
+            shared.borrow_mut().buttons[player(which)?] = Some(buttons as u8);
+            Ok(())
+        },
+    );
+    let shared = host.clone();
+    engine.register_fn(
+        "set_buttons",
+        move |which: INT, names: &str| -> Fallible<()> {
+            shared.borrow_mut().buttons[player(which)?] = Some(button_names(names)?);
+            Ok(())
+        },
+    );
+
+    let shared = host.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT, colour: INT| {
+        shared.borrow_mut().overlay.push(Shape::Rect {
+            x,
+            y,
+            width: 1,
+            height: 1,
+            colour: rgb(colour),
+        });
+    });
+    let shared = host.clone();
+    engine.register_fn(
+        "rect",
+        move |x: INT, y: INT, width: INT, height: INT, colour: INT| {
+            shared.borrow_mut().overlay.push(Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+                colour: rgb(colour),
+            });
+        },
+    );
+    let shared = host.clone();
+    engine.register_fn("text", move |x: INT, y: INT, text: &str| {
+        shared.borrow_mut().overlay.push(Shape::Text {
+            x,
+            y,
+            text: text.to_string(),
+        });
+    });
+
+    let shared = host.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| {
+        shared.borrow_mut().on_frame.push(callback);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{BUTTON_A, BUTTON_START};
+    use crate::parse_bin_file;
+
+    fn emulator() -> Emulator {
+        let mut emulator = Emulator::new();
+        let rom = parse_bin_file("test-bin/nestest.nes").unwrap();
+        emulator.load_cartridge(&rom).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn memory_and_registers() {
+        let mut emulator = emulator();
+        Script::new(
+            "
+            write_byte(0x10, 0x42);
+            write_byte(0x11, read_byte(0x10) + 1);
+            set_reg(\"x\", 5);
+            set_reg(\"a\", reg(\"x\") * 2);
+            ",
+            &mut emulator,
+        )
+        .unwrap();
+        assert_eq!(emulator.ram()[0x11], 0x43);
+        assert_eq!(emulator.cpu().reg.idx, 5);
+        assert_eq!(emulator.cpu().reg.accumulator, 10);
+
+        for (source, message) in [
+            ("read_byte(0x10000)", "outside $0000-$FFFF"),
+            ("reg(\"q\")", "no register called q"),
+            ("set_buttons(5, 0)", "player 5 isn't 1 to 4"),
+            ("set_buttons(1, \"a+turbo\")", "no button called turbo"),
+        ] {
+            let error = Script::new(source, &mut emulator).err().unwrap();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+        assert!(matches!(
+            Script::new("let = 1;", &mut emulator),
+            Err(ScriptError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn frame_callbacks_keep_their_state() {
+        let mut emulator = emulator();
+        let mut script = Script::new(
+            "
+            let frames = 0;
+            on_frame(|| {
+                frames += 1;
+                write_byte(0x20, frames);
+                write_byte(0x21, frame_count());
+                if frames == 2 { set_buttons(1, \"a+start\"); }
+            });
+            ",
+            &mut emulator,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            emulator.advance_frame();
+            script.frame(&mut emulator).unwrap();
+        }
+        assert_eq!(emulator.ram()[0x20], 2);
+        assert_eq!(emulator.ram()[0x21] as u64, emulator.frame_count());
+        assert_eq!(script.buttons(0, 0), BUTTON_A | BUTTON_START);
+        assert_eq!(script.buttons(1, BUTTON_A), BUTTON_A);
+        // only held for the frame after
+        script.frame(&mut emulator).unwrap();
+        assert_eq!(script.buttons(0, 0), 0);
+    }
+
+    #[test]
+    fn overlay_is_drawn_in_frame_coordinates() {
+        let mut emulator = emulator();
+        let mut script = Script::new(
+            "on_frame(|| { rect(-2, 8, 4, 2, 0xFF8000); pixel(10, 9, 0x0000FF); })",
+            &mut emulator,
+        )
+        .unwrap();
+        script.frame(&mut emulator).unwrap();
+        let overscan = Overscan::for_region(crate::region::Region::Ntsc);
+        let pitch = overscan.width() * 3;
+        let mut pixels = vec![0; pitch * overscan.height()];
+        script.draw(&mut pixels, pitch, overscan);
+        let at = |x: usize, y: usize| &pixels[y * pitch + x * 3..][..3];
+        // the top 8 lines are cropped
+        assert_eq!(at(0, 0), [0xFF, 0x80, 0x00]);
+        assert_eq!(at(1, 1), [0xFF, 0x80, 0x00]);
+        assert_eq!(at(2, 0), [0, 0, 0]);
+        assert_eq!(at(0, 2), [0, 0, 0]);
+        assert_eq!(at(10, 1), [0x00, 0x00, 0xFF]);
+    }
+}
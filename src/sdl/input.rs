@@ -0,0 +1,364 @@
+use crate::input::*;
+use crate::logging;
+use log::warn;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, io};
+
+// Binding config, one binding per line, `#` starts a comment:
+//
+//   1.a = key:X
+//   1.a = pad:b
+//   2.start = key:Return
+//
+// The left side is the player (1 to 4) and button name, the right side an SDL key name or
+// SDL game controller button name. A button can have any number of bindings. Pad bindings
+// apply to whichever pad is assigned to that player, pads are handed out in the order
+// they're plugged in.
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Binding {
+    Key(Keycode),
+    Pad(Button),
+}
+
+impl Binding {
+    fn parse(text: &str) -> Option<Binding> {
+        let (kind, name) = text.split_once(':')?;
+        match kind.trim() {
+            "key" => Keycode::from_name(name.trim()).map(Binding::Key),
+            "pad" => Button::from_string(name.trim()).map(Binding::Pad),
+            _ => None,
+        }
+    }
+
+    fn to_config(self) -> String {
+        match self {
+            Binding::Key(key) => format!("key:{}", key.name()),
+            Binding::Pad(button) => format!("pad:{}", button.string()),
+        }
+    }
+}
+
+/// Which keys and pad buttons press which controller buttons, for each player
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputBindings {
+    // (player, NES button) for every binding
+    bindings: HashMap<Binding, Vec<(usize, u8)>>,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        let mut bindings = InputBindings::empty();
+        let keys = [
+            [
+                (BUTTON_A, Keycode::X),
+                (BUTTON_B, Keycode::Z),
+                (BUTTON_SELECT, Keycode::RShift),
+                (BUTTON_START, Keycode::Return),
+                (BUTTON_UP, Keycode::Up),
+                (BUTTON_DOWN, Keycode::Down),
+                (BUTTON_LEFT, Keycode::Left),
+                (BUTTON_RIGHT, Keycode::Right),
+            ],
+            [
+                (BUTTON_A, Keycode::G),
+                (BUTTON_B, Keycode::F),
+                (BUTTON_SELECT, Keycode::T),
+                (BUTTON_START, Keycode::Y),
+                (BUTTON_UP, Keycode::W),
+                (BUTTON_DOWN, Keycode::S),
+                (BUTTON_LEFT, Keycode::A),
+                (BUTTON_RIGHT, Keycode::D),
+            ],
+        ];
+        // the NES's B and A sit where a modern pad's south and east face buttons are
+        let pad = [
+            (BUTTON_A, Button::B),
+            (BUTTON_B, Button::A),
+            (BUTTON_SELECT, Button::Back),
+            (BUTTON_START, Button::Start),
+            (BUTTON_UP, Button::DPadUp),
+            (BUTTON_DOWN, Button::DPadDown),
+            (BUTTON_LEFT, Button::DPadLeft),
+            (BUTTON_RIGHT, Button::DPadRight),
+        ];
+        for (player, keys) in keys.iter().enumerate() {
+            for &(button, key) in keys {
+                bindings.bind(player, button, Binding::Key(key));
+            }
+        }
+        for player in 0..PLAYERS {
+            for &(button, pad_button) in &pad {
+                bindings.bind(player, button, Binding::Pad(pad_button));
+            }
+        }
+        bindings
+    }
+}
+
+impl InputBindings {
+    pub fn empty() -> Self {
+        InputBindings {
+            bindings: HashMap::new(),
+        }
+    }
+
+    /// Make `binding` press `button` on `player`'s controller, on top of what it did before
+    pub fn bind(&mut self, player: usize, button: u8, binding: Binding) {
+        let targets = self.bindings.entry(binding).or_default();
+        if !targets.contains(&(player, button)) {
+            targets.push((player, button));
+        }
+    }
+
+    /// Remove every binding of `player`'s `button`
+    pub fn clear(&mut self, player: usize, button: u8) {
+        self.bindings.retain(|_, targets| {
+            targets.retain(|&target| target != (player, button));
+            !targets.is_empty()
+        });
+    }
+
+    /// Bindings for `player`'s `button`
+    pub fn bindings_for(&self, player: usize, button: u8) -> Vec<Binding> {
+        let mut found: Vec<Binding> = self
+            .bindings
+            .iter()
+            .filter(|(_, targets)| targets.contains(&(player, button)))
+            .map(|(&binding, _)| binding)
+            .collect();
+        found.sort_by_key(|binding| binding.to_config());
+        found
+    }
+
+    fn targets(&self, binding: Binding) -> &[(usize, u8)] {
+        self.bindings.get(&binding).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn from_config(text: &str) -> io::Result<Self> {
+        let mut bindings = InputBindings::empty();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("input bindings line {}: {:?}", number + 1, line),
+                )
+            };
+            let (target, binding) = line.split_once('=').ok_or_else(invalid)?;
+            let (player, button) = target.trim().split_once('.').ok_or_else(invalid)?;
+            let player = match player.parse::<usize>() {
+                Ok(player @ 1..=PLAYERS) => player - 1,
+                _ => return Err(invalid()),
+            };
+            let button = button_from_name(button).ok_or_else(invalid)?;
+            let binding = Binding::parse(binding).ok_or_else(invalid)?;
+            bindings.bind(player, button, binding);
+        }
+        Ok(bindings)
+    }
+
+    pub fn to_config(&self) -> String {
+        self.to_pairs()
+            .iter()
+            .map(|(target, binding)| format!("{} = {}\n", target, binding))
+            .collect()
+    }
+
+    /// Every binding as a ("player.button", binding) pair, how the config file keeps them
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+        for player in 0..PLAYERS {
+            for &(button, name) in &BUTTON_NAMES {
+                for binding in self.bindings_for(player, button) {
+                    pairs.push((format!("{}.{}", player + 1, name), binding.to_config()));
+                }
+            }
+        }
+        pairs
+    }
+
+    pub fn from_pairs(pairs: &[(String, String)]) -> io::Result<Self> {
+        let text: String = pairs
+            .iter()
+            .map(|(target, binding)| format!("{} = {}\n", target, binding))
+            .collect();
+        Self::from_config(&text)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_config(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_config())
+    }
+}
+
+/// Turns SDL keyboard and game controller events into controller state
+pub struct SdlInput {
+    bindings: InputBindings,
+    shared: Arc<SharedButtons>,
+    controllers: GameControllerSubsystem,
+    // pad assigned to each player
+    pads: [Option<GameController>; PLAYERS],
+    keys_held: [u8; PLAYERS],
+    pad_held: [u8; PLAYERS],
+}
+
+impl SdlInput {
+    pub fn new(
+        bindings: InputBindings,
+        shared: Arc<SharedButtons>,
+        controllers: GameControllerSubsystem,
+    ) -> Self {
+        SdlInput {
+            bindings,
+            shared,
+            controllers,
+            pads: Default::default(),
+            keys_held: [0; PLAYERS],
+            pad_held: [0; PLAYERS],
+        }
+    }
+
+    pub fn bindings(&self) -> &InputBindings {
+        &self.bindings
+    }
+
+    /// Swap bindings at runtime, releasing everything so nothing stays stuck down
+    pub fn set_bindings(&mut self, bindings: InputBindings) {
+        self.bindings = bindings;
+        self.keys_held = [0; PLAYERS];
+        self.pad_held = [0; PLAYERS];
+        self.publish();
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyDown {
+                keycode: Some(key),
+                repeat: false,
+                ..
+            } => self.press(Binding::Key(key), None, true),
+            Event::KeyUp {
+                keycode: Some(key), ..
+            } => self.press(Binding::Key(key), None, false),
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.press(Binding::Pad(button), self.player_of(which), true)
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.press(Binding::Pad(button), self.player_of(which), false)
+            }
+            // sent for pads already connected at startup too
+            Event::ControllerDeviceAdded { which, .. } => self.connect(which),
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(player) = self.player_of(which) {
+                    self.pads[player] = None;
+                    self.pad_held[player] = 0;
+                    self.publish();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn connect(&mut self, joystick_index: u32) {
+        let Some(slot) = self.pads.iter().position(Option::is_none) else {
+            return;
+        };
+        match self.controllers.open(joystick_index) {
+            Ok(pad) => self.pads[slot] = Some(pad),
+            Err(e) => warn!(target: logging::SDL, "failed to open game controller: {}", e),
+        }
+    }
+
+    fn player_of(&self, instance_id: u32) -> Option<usize> {
+        self.pads.iter().position(|pad| {
+            pad.as_ref()
+                .is_some_and(|pad| pad.instance_id() == instance_id)
+        })
+    }
+
+    // `pad_player` is the player the pad sending a pad event belongs to
+    fn press(&mut self, binding: Binding, pad_player: Option<usize>, pressed: bool) {
+        for &(player, button) in self.bindings.targets(binding) {
+            let held = match binding {
+                Binding::Key(_) => &mut self.keys_held[player],
+                Binding::Pad(_) if pad_player == Some(player) => &mut self.pad_held[player],
+                Binding::Pad(_) => continue,
+            };
+            if pressed {
+                *held |= button;
+            } else {
+                *held &= !button;
+            }
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        for player in 0..PLAYERS {
+            self.shared
+                .set(player, self.keys_held[player] | self.pad_held[player]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trip() {
+        let bindings = InputBindings::default();
+        let text = bindings.to_config();
+        assert!(text.contains("1.a = key:X\n"));
+        assert!(text.contains("2.up = pad:dpup\n"));
+        assert_eq!(InputBindings::from_config(&text).unwrap(), bindings);
+        let pairs = bindings.to_pairs();
+        assert_eq!(InputBindings::from_pairs(&pairs).unwrap(), bindings);
+    }
+
+    #[test]
+    fn rebinding() {
+        let mut bindings = InputBindings::from_config(
+            "# comment\n\n1.start = key:Space  # trailing\n1.start = pad:start\n",
+        )
+        .unwrap();
+        assert_eq!(
+            bindings.bindings_for(0, BUTTON_START),
+            [Binding::Key(Keycode::Space), Binding::Pad(Button::Start)]
+        );
+        bindings.clear(0, BUTTON_START);
+        bindings.bind(1, BUTTON_A, Binding::Key(Keycode::Space));
+        assert!(bindings.bindings_for(0, BUTTON_START).is_empty());
+        assert_eq!(
+            bindings.targets(Binding::Key(Keycode::Space)),
+            [(1, BUTTON_A)]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        for line in [
+            "1.a key:X",
+            "5.a = key:X",
+            "1.turbo = key:X",
+            "1.a = key:NotAKey",
+            "1.a = mouse:left",
+        ] {
+            let error = InputBindings::from_config(line).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,765 @@
+pub mod input;
+pub mod threaded;
+
+use crate::audio::{AudioConfig, SampleRing};
+use crate::input::SharedButtons;
+use crate::logging;
+use crate::memory_editor::{hex_lines, AddressSpace, BYTES_PER_LINE};
+use crate::osd::Osd;
+use crate::overscan::Overscan;
+use crate::pacing::{self, FrameScheduler, PacingConfig};
+use crate::palette::Palette;
+use crate::playtime::format_duration;
+use crate::ram_search::{Comparison, Filter, RamSearch};
+use crate::recording::{encode_png, Recorder, RecordingConfig};
+use crate::region::Region;
+use crate::scaling::{self, ScaleMode, VideoConfig};
+use input::{InputBindings, SdlInput};
+use log::warn;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::WindowCanvas;
+use sdl2::video::FullscreenType;
+use sdl2::Sdl;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// samples per callback, small enough not to add much latency on top of the ring buffer
+const AUDIO_CALLBACK_SAMPLES: u16 = 512;
+const FULLSCREEN_KEY: Keycode = Keycode::F11;
+const FAST_FORWARD_KEY: Keycode = Keycode::Tab;
+const SLOW_MOTION_KEY: Keycode = Keycode::Backspace;
+const RECORD_KEY: Keycode = Keycode::F9;
+const FPS_KEY: Keycode = Keycode::F10;
+const PAUSE_KEY: Keycode = Keycode::P;
+const FRAME_ADVANCE_KEY: Keycode = Keycode::Backslash;
+const RESET_KEY: Keycode = Keycode::F1;
+const POWER_CYCLE_KEY: Keycode = Keycode::F2;
+const SAVE_STATE_KEY: Keycode = Keycode::F5;
+const LOAD_STATE_KEY: Keycode = Keycode::F7;
+const REWIND_KEY: Keycode = Keycode::Backquote;
+const SLOWER_KEY: Keycode = Keycode::Minus;
+const FASTER_KEY: Keycode = Keycode::Equals;
+const RAM_SEARCH_KEY: Keycode = Keycode::F3;
+const RAM_SEARCH_RESTART_KEY: Keycode = Keycode::Kp0;
+const PLAY_TIME_KEY: Keycode = Keycode::F4;
+const MEMORY_VIEW_KEY: Keycode = Keycode::F6;
+const MEMORY_VIEW_SPACE_KEY: Keycode = Keycode::Home;
+const SCREENSHOT_KEY: Keycode = Keycode::F12;
+// candidates listed in the RAM search panel
+const RAM_SEARCH_LINES: usize = 12;
+// lines of the memory view, paged through with page up and down
+const MEMORY_VIEW_LINES: usize = 16;
+/// Save state slots, picked with the number keys
+pub const STATE_SLOTS: u32 = 10;
+
+/// Everything about the frontend that can be configured
+#[derive(Debug, Clone, Default)]
+pub struct FrontendConfig {
+    pub audio: AudioConfig,
+    pub pacing: PacingConfig,
+    pub video: VideoConfig,
+    pub palette: Palette,
+    pub recording: RecordingConfig,
+    pub bindings: InputBindings,
+    /// Save state slot to start on
+    pub save_slot: u32,
+}
+
+/// Feeds the sound card from the ring buffer the emulator fills
+struct RingPlayback {
+    ring: Arc<Mutex<SampleRing>>,
+}
+
+impl AudioCallback for RingPlayback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        match self.ring.lock() {
+            Ok(mut ring) => ring.fill(out),
+            Err(_) => out.fill(0.0),
+        }
+    }
+}
+
+fn open_audio(
+    sdl_context: &Sdl,
+    ring: Arc<Mutex<SampleRing>>,
+    config: AudioConfig,
+) -> Result<AudioDevice<RingPlayback>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired = AudioSpecDesired {
+        freq: Some(config.sample_rate as i32),
+        channels: Some(1),
+        samples: Some(AUDIO_CALLBACK_SAMPLES),
+    };
+    // SDL converts to whatever the device wants, so the spec we asked for is what we get
+    let device = audio_subsystem.open_playback(None, &desired, |_| RingPlayback { ring })?;
+    device.resume();
+    Ok(device)
+}
+
+/// The emulator as the frontend sees it
+pub trait FrameSource {
+    /// Run until the PPU finishes a frame and return it, `WIDTH` x `HEIGHT` pixels as
+    /// described at `Frame`. While paused this returns the last frame again.
+    fn run_frame(&mut self) -> &[u16];
+
+    /// Run a single frame even while paused
+    fn advance_frame(&mut self) -> &[u16];
+
+    /// The frame the last run, advance or rewind returned
+    fn frame(&self) -> &[u16];
+
+    fn set_paused(&mut self, paused: bool);
+
+    fn is_paused(&self) -> bool;
+
+    /// Soft reset, as if the console's reset button was pressed
+    fn reset(&mut self);
+
+    fn power_cycle(&mut self);
+
+    /// The APU's output during the last `run_frame`, one sample per CPU cycle
+    fn frame_audio(&self) -> &[f32];
+
+    fn region(&self) -> Region;
+
+    /// Power on with the rom at `path` in place of the current one. On failure the current one
+    /// keeps running and the error says why.
+    fn load_rom(&mut self, path: &str) -> Result<(), String>;
+
+    /// Save the console's state into `slot`
+    fn save_state(&mut self, slot: u32) -> Result<(), String>;
+
+    /// Go back to the state in `slot`. On failure the game carries on as it was.
+    fn load_state(&mut self, slot: u32) -> Result<(), String>;
+
+    /// Step back to the previous rewind snapshot and return its frame, or the current frame
+    /// again once there's nothing further back
+    fn rewind_frame(&mut self) -> &[u16];
+
+    /// Emulation speed as a multiple of real time
+    fn speed(&self) -> f32;
+
+    /// Run at `speed` times real time, with the audio resampled to match
+    fn set_speed(&mut self, speed: f32);
+
+    /// The console's 2KB of internal RAM, for RAM search
+    fn ram(&self) -> &[u8];
+
+    /// `len` bytes from `address` in `space`, for the memory view
+    fn read_memory(&self, space: AddressSpace, address: u16, len: usize) -> Vec<u8>;
+
+    /// Keep a PNG of the picture, returning where it went
+    fn save_screenshot(&mut self, png: &[u8]) -> Result<String, String>;
+
+    /// Time the game has been played for, over every session
+    fn play_time(&self) -> Duration;
+
+    /// Frames since power on, the frame counter TAS tools show
+    fn frame_count(&self) -> u64;
+
+    /// Whether `draw_overlay` draws anything, so it can be skipped when not
+    fn has_overlay(&self) -> bool;
+
+    /// Draw over the visible part of the frame, an RGB24 picture cropped by `overscan`, before
+    /// the on screen display goes on top
+    fn draw_overlay(&self, pixels: &mut [u8], pitch: usize, overscan: Overscan);
+}
+
+// size of the visible picture once stretched to the region's pixel aspect ratio
+fn picture_size(region: Region, overscan: Overscan) -> (u32, u32) {
+    let (width, height) = overscan.display_size(region, overscan.height());
+    (width as u32, height as u32)
+}
+
+// copy the visible part of a frame into a locked RGB24 texture
+fn upload_frame(
+    frame: &[u16],
+    overscan: Overscan,
+    palette: &Palette,
+    pixels: &mut [u8],
+    pitch: usize,
+) {
+    for (row, line) in overscan.rows(frame).zip(pixels.chunks_mut(pitch)) {
+        for (&pixel, rgb) in row.iter().zip(line.chunks_exact_mut(3)) {
+            rgb.copy_from_slice(&palette.rgb(pixel));
+        }
+    }
+}
+
+// Alt+1 to Alt+6 pick an integer scale, Alt+0 fits the window
+fn scale_hotkey(keycode: Keycode, keymod: Mod) -> Option<ScaleMode> {
+    if !keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) {
+        return None;
+    }
+    match keycode {
+        Keycode::Num0 => Some(ScaleMode::Fit),
+        Keycode::Num1 => Some(ScaleMode::Integer(1)),
+        Keycode::Num2 => Some(ScaleMode::Integer(2)),
+        Keycode::Num3 => Some(ScaleMode::Integer(3)),
+        Keycode::Num4 => Some(ScaleMode::Integer(4)),
+        Keycode::Num5 => Some(ScaleMode::Integer(5)),
+        Keycode::Num6 => Some(ScaleMode::Integer(6)),
+        _ => None,
+    }
+}
+
+// the number keys on their own pick a save state slot
+fn slot_hotkey(keycode: Keycode, keymod: Mod) -> Option<u32> {
+    if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD | Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        return None;
+    }
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
+// RAM search filters on the keypad: + and - for up and down, * and / for changed and
+// unchanged, 8 and 2 for up and down by one
+fn ram_search_filter(keycode: Keycode) -> Option<Filter> {
+    match keycode {
+        Keycode::KpPlus => Some(Filter::Previous(Comparison::Greater)),
+        Keycode::KpMinus => Some(Filter::Previous(Comparison::Less)),
+        Keycode::KpMultiply => Some(Filter::Previous(Comparison::NotEqual)),
+        Keycode::KpDivide => Some(Filter::Previous(Comparison::Equal)),
+        Keycode::Kp8 => Some(Filter::ChangedBy(1)),
+        Keycode::Kp2 => Some(Filter::ChangedBy(-1)),
+        _ => None,
+    }
+}
+
+// the memory view's panel: a page of hex from `address`
+fn memory_view_panel(source: &dyn FrameSource, space: AddressSpace, address: u16) -> Vec<String> {
+    let bytes = source.read_memory(space, address, MEMORY_VIEW_LINES * BYTES_PER_LINE);
+    let mut lines = vec![format!("{} memory", space)];
+    lines.extend(hex_lines(&bytes, address));
+    lines
+}
+
+// the memory view moved a page up or down, wrapping around the address space
+fn memory_view_scroll(keycode: Keycode, space: AddressSpace, address: u16) -> Option<u16> {
+    let page = (MEMORY_VIEW_LINES * BYTES_PER_LINE) as u16;
+    let address = match keycode {
+        Keycode::PageUp => address.wrapping_sub(page),
+        Keycode::PageDown => address.wrapping_add(page),
+        _ => return None,
+    };
+    Some(address & space.last_address())
+}
+
+// the search's panel: how many are left and the first few with their value now and at the
+// last filter
+fn ram_search_panel(search: &RamSearch, ram: &[u8]) -> Vec<String> {
+    let mut lines = vec![format!("RAM search: {} left", search.len())];
+    lines.extend(
+        search
+            .candidates()
+            .take(RAM_SEARCH_LINES)
+            .map(|(address, previous)| {
+                let now = ram.get(address as usize).copied().unwrap_or(0);
+                format!("{:04X} {:02X} was {:02X}", address, now, previous)
+            }),
+    );
+    lines
+}
+
+fn set_fullscreen(canvas: &mut WindowCanvas, fullscreen: bool) -> Result<(), String> {
+    canvas.window_mut().set_fullscreen(if fullscreen {
+        FullscreenType::Desktop
+    } else {
+        FullscreenType::Off
+    })
+}
+
+// size a window to an integer scale of the picture, other modes keep whatever size it has
+fn fit_window(
+    canvas: &mut WindowCanvas,
+    picture: (u32, u32),
+    video: VideoConfig,
+) -> Result<(), String> {
+    if let (ScaleMode::Integer(scale), false) = (video.scale, video.fullscreen) {
+        let (width, height) = scaling::window_size(picture, scale);
+        canvas
+            .window_mut()
+            .set_size(width, height)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// start a recording, or stop the one that's running, returning what happened
+fn toggle_recording(
+    recorder: &mut Option<Recorder>,
+    config: &RecordingConfig,
+    overscan: Overscan,
+    region: Region,
+) -> String {
+    match recorder.take() {
+        Some(recording) => {
+            let frames = recording.frames();
+            match recording.finish() {
+                Ok(path) => format!("Recorded {} frames to {}", frames, path.display()),
+                Err(e) => format!("Recording failed: {}", e),
+            }
+        }
+        None => {
+            match Recorder::start(
+                config,
+                overscan.width(),
+                overscan.height(),
+                region.frames_per_second(),
+                region.cpu_clock_hz(),
+            ) {
+                Ok(recording) => {
+                    let message = format!("Recording to {}", recording.path().display());
+                    *recorder = Some(recording);
+                    message
+                }
+                Err(e) => format!("Can't start recording: {}", e),
+            }
+        }
+    }
+}
+
+/// Open the window and run `source` a frame at a time until the window is closed. Tab held
+/// fast-forwards, Backspace toggles slow motion, F9 starts and stops recording, F10 shows the
+/// frame rate, F11 toggles fullscreen and Alt+0 to Alt+6 change the scale. P pauses, backslash
+/// advances a single frame, F1 resets and F2 power cycles. F5 saves a state and F7 loads it,
+/// 0 to 9 pick the slot, and holding ` rewinds. - and = step the speed down and up. F3 opens
+/// and closes RAM search, filtered from the keypad, keypad 0 starts it over. Dropping a rom onto
+/// the window loads it. Returns the save state slot picked last.
+pub fn run_frontend<S: FrameSource>(
+    source: &mut S,
+    audio: Arc<Mutex<SampleRing>>,
+    buttons: Arc<SharedButtons>,
+    config: FrontendConfig,
+) -> Result<u32, String> {
+    let FrontendConfig {
+        audio: audio_config,
+        pacing,
+        mut video,
+        palette,
+        recording: recording_config,
+        bindings,
+        save_slot,
+    } = config;
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let mut input = SdlInput::new(bindings, buttons, sdl_context.game_controller()?);
+    // keep running without sound rather than failing on machines with no audio device
+    let audio_device = open_audio(&sdl_context, Arc::clone(&audio), audio_config)
+        .map_err(|e| warn!(target: logging::SDL, "audio disabled: {}", e))
+        .ok();
+
+    let mut region = source.region();
+    let mut overscan = Overscan::for_region(region);
+    let mut picture = picture_size(region, overscan);
+    let initial_scale = match video.scale {
+        ScaleMode::Integer(scale) => scale,
+        ScaleMode::Fit => scaling::DEFAULT_SCALE,
+    };
+    let (window_width, window_height) = scaling::window_size(picture, initial_scale);
+    let window = video_subsystem
+        .window("nesemu", window_width, window_height)
+        .position_centered()
+        .resizable()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = if pacing.vsync {
+        window.into_canvas().present_vsync().build()
+    } else {
+        window.into_canvas().build()
+    }
+    .map_err(|e| e.to_string())?;
+    set_fullscreen(&mut canvas, video.fullscreen)?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            overscan.width() as u32,
+            overscan.height() as u32,
+        )
+        .map_err(|e| e.to_string())?;
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let mut scheduler = FrameScheduler::new(pacing, region.frames_per_second());
+    scheduler.set_speed(source.speed() as f64);
+    let mut recorder = None;
+    let mut slot = save_slot % STATE_SLOTS;
+    let mut rewinding = false;
+    let mut ram_search: Option<RamSearch> = None;
+    // where the memory view is showing, while it's open
+    let mut memory_view: Option<(AddressSpace, u16)> = None;
+    // take one after the next frame runs
+    let mut screenshot = false;
+    let mut osd = Osd::new(Instant::now());
+    let mut recorded_frame = vec![0u8; overscan.width() * overscan.height() * 3];
+    'running: loop {
+        let mut advance = false;
+        for event in event_pump.poll_iter() {
+            input.handle_event(&event);
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(FAST_FORWARD_KEY),
+                    ..
+                } => scheduler.set_fast_forward(true),
+                Event::KeyUp {
+                    keycode: Some(FAST_FORWARD_KEY),
+                    ..
+                } => scheduler.set_fast_forward(false),
+                Event::KeyDown {
+                    keycode: Some(SLOW_MOTION_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    scheduler.toggle_slow_motion();
+                    osd.message(if scheduler.speed() == Some(1.0) {
+                        "Normal speed"
+                    } else {
+                        "Slow motion"
+                    });
+                }
+                Event::KeyDown {
+                    keycode: Some(RECORD_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    let message =
+                        toggle_recording(&mut recorder, &recording_config, overscan, region);
+                    eprintln!("{}", message);
+                    osd.message(message);
+                }
+                Event::KeyDown {
+                    keycode: Some(PAUSE_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    let paused = !source.is_paused();
+                    source.set_paused(paused);
+                    osd.message(if paused { "Paused" } else { "Resumed" });
+                }
+                // held down it keeps stepping at the key repeat rate
+                Event::KeyDown {
+                    keycode: Some(FRAME_ADVANCE_KEY),
+                    ..
+                } => {
+                    source.set_paused(true);
+                    advance = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(RESET_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    source.reset();
+                    osd.message("Reset");
+                }
+                Event::KeyDown {
+                    keycode: Some(POWER_CYCLE_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    source.power_cycle();
+                    osd.message("Power cycled");
+                }
+                Event::KeyDown {
+                    keycode: Some(REWIND_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    rewinding = true;
+                    osd.message("Rewinding");
+                }
+                Event::KeyUp {
+                    keycode: Some(REWIND_KEY),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(SAVE_STATE_KEY),
+                    repeat: false,
+                    ..
+                } => osd.message(match source.save_state(slot) {
+                    Ok(()) => format!("State {} saved", slot),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        e
+                    }
+                }),
+                Event::KeyDown {
+                    keycode: Some(LOAD_STATE_KEY),
+                    repeat: false,
+                    ..
+                } => osd.message(match source.load_state(slot) {
+                    Ok(()) => format!("State {} loaded", slot),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        e
+                    }
+                }),
+                Event::KeyDown {
+                    keycode: Some(keycode @ (SLOWER_KEY | FASTER_KEY)),
+                    ..
+                } => {
+                    let speed = pacing::step_speed(source.speed(), keycode == FASTER_KEY);
+                    source.set_speed(speed);
+                    scheduler.set_speed(source.speed() as f64);
+                    osd.message(format!("Speed {}%", (source.speed() * 100.0).round()));
+                }
+                Event::KeyDown {
+                    keycode: Some(RAM_SEARCH_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    ram_search = match ram_search {
+                        Some(_) => {
+                            osd.set_panel(Vec::new());
+                            None
+                        }
+                        None => Some(RamSearch::new(source.ram())),
+                    };
+                }
+                Event::KeyDown {
+                    keycode: Some(MEMORY_VIEW_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    memory_view = match memory_view {
+                        Some(_) => {
+                            osd.set_panel(Vec::new());
+                            None
+                        }
+                        None => Some((AddressSpace::Cpu, 0)),
+                    };
+                }
+                Event::KeyDown {
+                    keycode: Some(FPS_KEY),
+                    repeat: false,
+                    ..
+                } => osd.toggle_fps(),
+                Event::KeyDown {
+                    keycode: Some(SCREENSHOT_KEY),
+                    repeat: false,
+                    ..
+                } => screenshot = true,
+                Event::KeyDown {
+                    keycode: Some(PLAY_TIME_KEY),
+                    repeat: false,
+                    ..
+                } => osd.message(format!(
+                    "Played {}, frame {}",
+                    format_duration(source.play_time()),
+                    source.frame_count()
+                )),
+                Event::DropFile { filename, .. } => {
+                    if recorder.is_some() {
+                        let message =
+                            toggle_recording(&mut recorder, &recording_config, overscan, region);
+                        eprintln!("{}", message);
+                        osd.message(message);
+                    }
+                    match source.load_rom(&filename) {
+                        Ok(()) => {
+                            let name = Path::new(&filename)
+                                .file_name()
+                                .map_or(filename.clone(), |name| {
+                                    name.to_string_lossy().into_owned()
+                                });
+                            osd.message(format!("Loaded {}", name));
+                        }
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            osd.message(e);
+                        }
+                    }
+                    if source.region() != region {
+                        region = source.region();
+                        overscan = Overscan::for_region(region);
+                        picture = picture_size(region, overscan);
+                        texture = texture_creator
+                            .create_texture_streaming(
+                                PixelFormatEnum::RGB24,
+                                overscan.width() as u32,
+                                overscan.height() as u32,
+                            )
+                            .map_err(|e| e.to_string())?;
+                        recorded_frame = vec![0u8; overscan.width() * overscan.height() * 3];
+                        scheduler.set_frame_rate(region.frames_per_second());
+                        fit_window(&mut canvas, picture, video)?;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(FULLSCREEN_KEY),
+                    repeat: false,
+                    ..
+                } => {
+                    video.fullscreen = !video.fullscreen;
+                    set_fullscreen(&mut canvas, video.fullscreen)?;
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(scale) = scale_hotkey(keycode, keymod) {
+                        video.scale = scale;
+                        osd.message(match scale {
+                            ScaleMode::Integer(scale) => format!("Scale {}x", scale),
+                            ScaleMode::Fit => "Fit to window".to_string(),
+                        });
+                        fit_window(&mut canvas, picture, video)?;
+                    } else if let Some(picked) = slot_hotkey(keycode, keymod) {
+                        slot = picked;
+                        osd.message(format!("State slot {}", slot));
+                    } else if let Some(search) = ram_search.as_mut() {
+                        if keycode == RAM_SEARCH_RESTART_KEY {
+                            *search = RamSearch::new(source.ram());
+                            osd.message("RAM search restarted");
+                        } else if let Some(filter) = ram_search_filter(keycode) {
+                            let left = search.filter(source.ram(), filter);
+                            osd.message(format!("{}: {} left", filter, left));
+                        }
+                    } else if let Some((space, address)) = memory_view.as_mut() {
+                        if keycode == MEMORY_VIEW_SPACE_KEY {
+                            *space = match space {
+                                AddressSpace::Cpu => AddressSpace::Ppu,
+                                AddressSpace::Ppu => AddressSpace::Cpu,
+                            };
+                            *address = 0;
+                        } else if let Some(scrolled) = memory_view_scroll(keycode, *space, *address)
+                        {
+                            *address = scrolled;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // the frame about to run borrows the source, so the panel shows RAM from before it
+        if let Some(search) = &ram_search {
+            osd.set_panel(ram_search_panel(search, source.ram()));
+        } else if let Some((space, address)) = memory_view {
+            osd.set_panel(memory_view_panel(source, space, address));
+        }
+        // rewound frames have been recorded already
+        let ran = !rewinding && (advance || !source.is_paused());
+        let frame = if rewinding {
+            source.rewind_frame()
+        } else if advance {
+            source.advance_frame()
+        } else {
+            source.run_frame()
+        };
+        osd.tick(Instant::now());
+        if ran && recorder.is_some() {
+            let pitch = overscan.width() * 3;
+            upload_frame(frame, overscan, &palette, &mut recorded_frame, pitch);
+        }
+        // the picture without the on screen display
+        let png = std::mem::take(&mut screenshot).then(|| {
+            let (width, height) = (overscan.width(), overscan.height());
+            let mut rgb = vec![0; width * height * 3];
+            upload_frame(frame, overscan, &palette, &mut rgb, width * 3);
+            encode_png(&rgb, width, height)
+        });
+        if scheduler.should_present(Instant::now()) {
+            // the frame again without holding on to the source, which draws over it
+            let frame = source.frame();
+            texture.with_lock(None, |pixels, pitch| {
+                upload_frame(frame, overscan, &palette, pixels, pitch);
+                if source.has_overlay() {
+                    source.draw_overlay(pixels, pitch, overscan);
+                }
+                let (width, height) = (overscan.width(), overscan.height());
+                osd.draw(pixels, width, height, pitch, region.frames_per_second());
+            })?;
+            canvas.set_draw_color(Color::BLACK);
+            canvas.clear();
+            let viewport = scaling::viewport(canvas.output_size()?, picture, video.scale);
+            let target = Rect::new(
+                viewport.x as i32,
+                viewport.y as i32,
+                viewport.width,
+                viewport.height,
+            );
+            canvas.copy(&texture, None, target)?;
+            canvas.present();
+        }
+        if let Some(png) = png {
+            osd.message(match source.save_screenshot(&png) {
+                Ok(path) => format!("Saved {}", path),
+                Err(e) => e,
+            });
+        }
+        if let Some(recording) = recorder.as_mut().filter(|_| ran) {
+            if let Err(e) = recording.push_frame(&recorded_frame, source.frame_audio()) {
+                let message = format!("Recording stopped: {}", e);
+                eprintln!("{}", message);
+                osd.message(message);
+                toggle_recording(&mut recorder, &recording_config, overscan, region);
+            }
+        }
+
+        let queued = audio_device
+            .as_ref()
+            .and_then(|_| audio.lock().ok().map(|ring| ring.len()));
+        let delay = scheduler.frame_delay(
+            Instant::now(),
+            queued,
+            audio_config.target_samples(),
+            audio_config.sample_rate,
+        );
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+    if recorder.is_some() {
+        eprintln!(
+            "{}",
+            toggle_recording(&mut recorder, &recording_config, overscan, region)
+        );
+    }
+    Ok(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{HEIGHT, WIDTH};
+
+    #[test]
+    fn uploads_visible_rows() {
+        let mut frame = vec![0x0Fu16; WIDTH * HEIGHT];
+        frame[8 * WIDTH] = 0x30;
+        let overscan = Overscan::for_region(Region::Ntsc);
+        let pitch = WIDTH * 3 + 4;
+        let mut pixels = vec![0xAAu8; pitch * overscan.height()];
+        upload_frame(&frame, overscan, &Palette::default(), &mut pixels, pitch);
+        assert_eq!(pixels[..3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixels[3..6], Palette::default().rgb(0x0F));
+        // padding at the end of each row is left alone
+        assert_eq!(pixels[WIDTH * 3], 0xAA);
+    }
+}
@@ -0,0 +1,486 @@
+use super::FrameSource;
+use crate::memory_editor::AddressSpace;
+use crate::overscan::Overscan;
+use crate::region::Region;
+use std::cell::Cell;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Runs a frame source on an emulation thread of its own, so the next frame is being emulated
+// while the window thread presents the last one and handles events. The emulation thread only
+// runs a frame when asked for one, and the window thread asks for the next as soon as it gets
+// one, which keeps the emulation exactly one frame ahead: never further, because nothing more
+// was asked for, and never stalled on a window that's busy. Frames go back and forth in two
+// buffers, so none are allocated once running.
+//
+// Everything else is a job run on the emulation thread in between frames. Jobs that change the
+// game (loading, resets, save states) wait for the frame ahead first, so the next frame shown
+// is from after them.
+
+// a frame and what the window reads about it every frame, without waiting on the emulation
+#[derive(Default)]
+struct Snapshot {
+    pixels: Vec<u16>,
+    audio: Vec<f32>,
+    ram: Vec<u8>,
+    paused: bool,
+    overlay: bool,
+}
+
+impl Snapshot {
+    fn capture<S: FrameSource>(&mut self, source: &S) {
+        copy(&mut self.pixels, source.frame());
+        copy(&mut self.audio, source.frame_audio());
+        copy(&mut self.ram, source.ram());
+        self.paused = source.is_paused();
+        self.overlay = source.has_overlay();
+    }
+}
+
+// reuses the buffer's allocation once it's big enough
+fn copy<T: Copy>(buffer: &mut Vec<T>, from: &[T]) {
+    buffer.clear();
+    buffer.extend_from_slice(from);
+}
+
+type Job<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+enum Request<S> {
+    // a frame into the snapshot, which comes back with it
+    Run(Snapshot),
+    Advance(Snapshot),
+    Rewind(Snapshot),
+    Job(Job<S>),
+}
+
+/// A frame source running on its own thread, see `ThreadedSource::spawn`
+pub struct ThreadedSource<S> {
+    requests: Sender<Request<S>>,
+    frames: Receiver<Snapshot>,
+    // what the last frame run, advance or rewind returned
+    current: Snapshot,
+    // the other buffer, None while the emulation thread has it for the frame ahead
+    spare: Option<Snapshot>,
+    // the picture sent to draw_overlay and back
+    overlay: Cell<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<S: FrameSource + 'static> ThreadedSource<S> {
+    /// Start the emulation thread and make the source on it with `build`, so the source
+    /// doesn't need to be `Send`. Fails with `build`'s error.
+    pub fn spawn<F>(build: F) -> Result<ThreadedSource<S>, String>
+    where
+        F: FnOnce() -> Result<S, String> + Send + 'static,
+    {
+        let (requests, received) = mpsc::channel();
+        // the frame ahead is the only one ever sent, so sending never waits
+        let (produced, frames) = mpsc::sync_channel(1);
+        let (started, ready) = mpsc::sync_channel(1);
+        let thread = thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || {
+                let source = match build() {
+                    Ok(source) => source,
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    }
+                };
+                let mut first = Snapshot::default();
+                first.capture(&source);
+                if started.send(Ok(first)).is_ok() {
+                    serve(source, received, produced);
+                }
+            })
+            .map_err(|e| format!("Can't start the emulation thread: {}", e))?;
+        let current = match ready.recv() {
+            Ok(started) => started?,
+            // the error's been printed by the panic hook
+            Err(_) => return Err("The emulation thread stopped".to_string()),
+        };
+        Ok(ThreadedSource {
+            requests,
+            frames,
+            current,
+            spare: Some(Snapshot::default()),
+            overlay: Cell::new(Vec::new()),
+            thread: Some(thread),
+        })
+    }
+
+    /// Run `job` on the emulation thread, after the frame ahead, and wait for what it returns
+    pub fn call<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut S) -> R + Send + 'static,
+    {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.send(Request::Job(Box::new(move |source| {
+            let _ = reply.send(job(source));
+        })));
+        result.recv().unwrap_or_else(|_| stopped())
+    }
+
+    // call once the frame ahead is in
+    fn call_settled<R, F>(&mut self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut S) -> R + Send + 'static,
+    {
+        self.settle();
+        self.call(job)
+    }
+
+    fn send(&self, request: Request<S>) {
+        if self.requests.send(request).is_err() {
+            stopped();
+        }
+    }
+
+    fn receive(&mut self) {
+        let next = self.frames.recv().unwrap_or_else(|_| stopped());
+        self.spare = Some(mem::replace(&mut self.current, next));
+    }
+
+    // take in the frame ahead, if one's being run
+    fn settle(&mut self) {
+        if self.spare.is_none() {
+            self.receive();
+        }
+    }
+
+    fn run_ahead(&mut self, request: fn(Snapshot) -> Request<S>) {
+        if let Some(spare) = self.spare.take() {
+            self.send(request(spare));
+        }
+    }
+}
+
+fn stopped() -> ! {
+    panic!("the emulation thread stopped");
+}
+
+// the emulation thread, until the ThreadedSource is dropped
+fn serve<S: FrameSource>(
+    mut source: S,
+    requests: Receiver<Request<S>>,
+    frames: SyncSender<Snapshot>,
+) {
+    for request in requests {
+        let mut snapshot = match request {
+            Request::Run(snapshot) => {
+                source.run_frame();
+                snapshot
+            }
+            Request::Advance(snapshot) => {
+                source.advance_frame();
+                snapshot
+            }
+            Request::Rewind(snapshot) => {
+                source.rewind_frame();
+                snapshot
+            }
+            Request::Job(job) => {
+                job(&mut source);
+                continue;
+            }
+        };
+        snapshot.capture(&source);
+        if frames.send(snapshot).is_err() {
+            break;
+        }
+    }
+}
+
+impl<S> Drop for ThreadedSource<S> {
+    fn drop(&mut self) {
+        // hanging up ends the emulation thread's loop
+        let (hung_up, _) = mpsc::channel();
+        drop(mem::replace(&mut self.requests, hung_up));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<S: FrameSource + 'static> FrameSource for ThreadedSource<S> {
+    fn run_frame(&mut self) -> &[u16] {
+        if self.current.paused {
+            self.current.audio.clear();
+            return &self.current.pixels;
+        }
+        // nothing's ahead after a pause or a job, so this one's waited for
+        self.run_ahead(Request::Run);
+        self.receive();
+        if !self.current.paused {
+            self.run_ahead(Request::Run);
+        }
+        &self.current.pixels
+    }
+
+    fn advance_frame(&mut self) -> &[u16] {
+        self.settle();
+        self.run_ahead(Request::Advance);
+        self.receive();
+        &self.current.pixels
+    }
+
+    fn frame(&self) -> &[u16] {
+        &self.current.pixels
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        // the frame ahead has run, so it's the one paused on
+        self.call_settled(move |source| source.set_paused(paused));
+        self.current.paused = paused;
+    }
+
+    fn is_paused(&self) -> bool {
+        self.current.paused
+    }
+
+    fn reset(&mut self) {
+        self.call_settled(|source| source.reset());
+    }
+
+    fn power_cycle(&mut self) {
+        self.call_settled(|source| source.power_cycle());
+    }
+
+    fn frame_audio(&self) -> &[f32] {
+        &self.current.audio
+    }
+
+    fn region(&self) -> Region {
+        self.call(|source| source.region())
+    }
+
+    fn load_rom(&mut self, path: &str) -> Result<(), String> {
+        let path = path.to_string();
+        self.call_settled(move |source| source.load_rom(&path))
+    }
+
+    fn save_state(&mut self, slot: u32) -> Result<(), String> {
+        self.call_settled(move |source| source.save_state(slot))
+    }
+
+    fn load_state(&mut self, slot: u32) -> Result<(), String> {
+        self.call_settled(move |source| source.load_state(slot))
+    }
+
+    fn rewind_frame(&mut self) -> &[u16] {
+        self.settle();
+        self.run_ahead(Request::Rewind);
+        self.receive();
+        &self.current.pixels
+    }
+
+    fn speed(&self) -> f32 {
+        self.call(|source| source.speed())
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.call(move |source| source.set_speed(speed));
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.current.ram
+    }
+
+    fn read_memory(&self, space: AddressSpace, address: u16, len: usize) -> Vec<u8> {
+        self.call(move |source| source.read_memory(space, address, len))
+    }
+
+    fn save_screenshot(&mut self, png: &[u8]) -> Result<String, String> {
+        let png = png.to_vec();
+        self.call(move |source| source.save_screenshot(&png))
+    }
+
+    fn play_time(&self) -> Duration {
+        self.call(|source| source.play_time())
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.call(|source| source.frame_count())
+    }
+
+    fn has_overlay(&self) -> bool {
+        self.current.overlay
+    }
+
+    fn draw_overlay(&self, pixels: &mut [u8], pitch: usize, overscan: Overscan) {
+        if !self.current.overlay {
+            return;
+        }
+        let mut picture = self.overlay.take();
+        copy(&mut picture, pixels);
+        let picture = self.call(move |source| {
+            source.draw_overlay(&mut picture, pitch, overscan);
+            picture
+        });
+        pixels.copy_from_slice(&picture);
+        self.overlay.set(picture);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // counts frames, with the count as the picture
+    #[derive(Default)]
+    struct Counter {
+        frames: u64,
+        paused: bool,
+        pixels: Vec<u16>,
+    }
+
+    impl Counter {
+        fn run(&mut self) -> &[u16] {
+            self.frames += 1;
+            self.pixels = vec![self.frames as u16; 4];
+            &self.pixels
+        }
+    }
+
+    impl FrameSource for Counter {
+        fn run_frame(&mut self) -> &[u16] {
+            if self.paused {
+                return &self.pixels;
+            }
+            self.run()
+        }
+
+        fn advance_frame(&mut self) -> &[u16] {
+            self.run()
+        }
+
+        fn frame(&self) -> &[u16] {
+            &self.pixels
+        }
+
+        fn set_paused(&mut self, paused: bool) {
+            self.paused = paused;
+        }
+
+        fn is_paused(&self) -> bool {
+            self.paused
+        }
+
+        fn reset(&mut self) {}
+
+        fn power_cycle(&mut self) {
+            self.frames = 0;
+        }
+
+        fn frame_audio(&self) -> &[f32] {
+            &[]
+        }
+
+        fn region(&self) -> Region {
+            Region::Ntsc
+        }
+
+        fn load_rom(&mut self, path: &str) -> Result<(), String> {
+            Err(format!("{}: no", path))
+        }
+
+        fn save_state(&mut self, _: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn load_state(&mut self, _: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn rewind_frame(&mut self) -> &[u16] {
+            self.frames = self.frames.saturating_sub(1);
+            self.pixels = vec![self.frames as u16; 4];
+            &self.pixels
+        }
+
+        fn speed(&self) -> f32 {
+            1.0
+        }
+
+        fn set_speed(&mut self, _: f32) {}
+
+        fn ram(&self) -> &[u8] {
+            &[]
+        }
+
+        fn read_memory(&self, _: AddressSpace, _: u16, len: usize) -> Vec<u8> {
+            vec![0; len]
+        }
+
+        fn save_screenshot(&mut self, _: &[u8]) -> Result<String, String> {
+            Ok(String::new())
+        }
+
+        fn play_time(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn frame_count(&self) -> u64 {
+            self.frames
+        }
+
+        fn has_overlay(&self) -> bool {
+            true
+        }
+
+        fn draw_overlay(&self, pixels: &mut [u8], _: usize, _: Overscan) {
+            pixels.fill(self.frames as u8);
+        }
+    }
+
+    fn spawn() -> ThreadedSource<Counter> {
+        ThreadedSource::spawn(|| Ok(Counter::default())).unwrap()
+    }
+
+    #[test]
+    fn runs_one_frame_ahead() {
+        let mut source = spawn();
+        for frame in 1..=5 {
+            assert_eq!(source.run_frame(), [frame; 4]);
+            // the next one's been asked for, and nothing after it
+            thread::sleep(Duration::from_millis(5));
+            assert_eq!(source.frame_count(), frame as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn pausing_shows_the_frame_ahead() {
+        let mut source = spawn();
+        source.run_frame();
+        source.set_paused(true);
+        assert_eq!(source.frame(), [2; 4]);
+        assert_eq!(source.run_frame(), [2; 4]);
+        assert_eq!(source.frame_count(), 2);
+        assert_eq!(source.advance_frame(), [3; 4]);
+        assert_eq!(source.rewind_frame(), [2; 4]);
+        source.set_paused(false);
+        assert_eq!(source.run_frame(), [3; 4]);
+    }
+
+    #[test]
+    fn jobs_reach_the_source() {
+        let mut source = spawn();
+        source.run_frame();
+        source.power_cycle();
+        assert_eq!(source.run_frame(), [1; 4]);
+        assert_eq!(source.load_rom("game.nes"), Err("game.nes: no".to_string()));
+        let mut pixels = [0u8; 6];
+        source.draw_overlay(&mut pixels, 3, Overscan::default());
+        assert_eq!(pixels, [2; 6]);
+    }
+
+    #[test]
+    fn build_errors_come_back() {
+        let built = ThreadedSource::<Counter>::spawn(|| Err("no rom".to_string()));
+        assert_eq!(built.err(), Some("no rom".to_string()));
+    }
+}
@@ -0,0 +1,76 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Field helpers for the `serde` feature. serde only derives arrays up to 32 long, so RAM, VRAM,
+// OAM and the frame go through `array` and `boxed_array` as plain sequences. The channels and
+// frame counter point at lookup tables picked by the region, those are written out by value
+// and read back as whichever of the region's tables they match.
+
+pub mod array {
+    use super::*;
+
+    pub fn serialize<S, T, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        array.as_slice().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &format!("{} items", N).as_str()))
+    }
+}
+
+pub mod boxed_array {
+    pub use super::array::serialize;
+    use super::*;
+
+    // through a Vec, so 64KB of RAM never sits on the stack
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<Box<[T; N]>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items
+            .into_boxed_slice()
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &format!("{} items", N).as_str()))
+    }
+}
+
+pub fn table<S, T>(table: &&'static T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    table.serialize(serializer)
+}
+
+/// The one of `tables` that was written out, `what` names it in the error when none match
+pub fn one_of<'de, D, T>(
+    deserializer: D,
+    tables: &[&'static T],
+    what: &str,
+) -> Result<&'static T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialEq,
+{
+    let written = T::deserialize(deserializer)?;
+    tables
+        .iter()
+        .copied()
+        .find(|&table| *table == written)
+        .ok_or_else(|| D::Error::custom(format!("{} of no region", what)))
+}
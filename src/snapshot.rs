@@ -0,0 +1,137 @@
+use crate::cpu::Registers;
+use crate::irq::IrqSource;
+use crate::mapper::Mapper;
+use crate::region::Region;
+
+// A read-only copy of the machine's state for UIs and tests to look at: the CPU registers and
+// flags, the PPU's registers and where it is in the frame, the banks the board has mapped in
+// and the timing counters. It's all plain values, so taking one allocates nothing and keeping
+// one doesn't hold on to the emulator.
+
+/// Size of the PRG windows `Banks::prg` covers, at $8000, $A000, $C000 and $E000
+pub const PRG_WINDOW: usize = 0x2000;
+/// Size of the CHR windows `Banks::chr` covers, eight of them from $0000
+pub const CHR_WINDOW: usize = 0x400;
+
+/// The status register's flags, one by one
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flags {
+    pub carry: bool,
+    pub zero: bool,
+    pub interrupt_disable: bool,
+    pub decimal: bool,
+    pub overflow: bool,
+    pub negative: bool,
+}
+
+impl Flags {
+    /// From the status byte, as PHP pushes it
+    pub fn from_status(status: u8) -> Flags {
+        Flags {
+            carry: status & 0b0000_0001 != 0,
+            zero: status & 0b0000_0010 != 0,
+            interrupt_disable: status & 0b0000_0100 != 0,
+            decimal: status & 0b0000_1000 != 0,
+            overflow: status & 0b0100_0000 != 0,
+            negative: status & 0b1000_0000 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// The flags as PHP pushes them, but without B
+    pub status: u8,
+    pub flags: Flags,
+}
+
+impl CpuState {
+    pub fn of(registers: &Registers) -> CpuState {
+        CpuState {
+            pc: registers.pc,
+            sp: registers.sp(),
+            a: registers.accumulator,
+            x: registers.idx,
+            y: registers.idy(),
+            status: registers.status(),
+            flags: Flags::from_status(registers.status()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PpuState {
+    /// $2000
+    pub ctrl: u8,
+    /// $2001
+    pub mask: u8,
+    /// $2002's flags, without the open bus bits
+    pub status: u8,
+    /// Current VRAM address
+    pub v: u16,
+    /// Temporary VRAM address
+    pub t: u16,
+    pub fine_x: u8,
+    /// The $2005/$2006 write toggle, set after the first write
+    pub write_latch: bool,
+    /// $2003
+    pub oam_addr: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub odd_frame: bool,
+}
+
+/// Which banks the board has mapped in, as bank numbers of the window size into PRG ROM and
+/// CHR. None where there's no ROM (or CHR) mapped, or no cartridge.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Banks {
+    pub prg: [Option<usize>; 4],
+    pub chr: [Option<usize>; 8],
+}
+
+impl Banks {
+    pub fn of(mapper: Option<&dyn Mapper>) -> Banks {
+        let Some(mapper) = mapper else {
+            return Banks::default();
+        };
+        Banks {
+            prg: std::array::from_fn(|window| {
+                let address = 0x8000 + (window * PRG_WINDOW) as u16;
+                Some(mapper.prg_offset(address)? / PRG_WINDOW)
+            }),
+            chr: std::array::from_fn(|window| {
+                let address = (window * CHR_WINDOW) as u16;
+                Some(mapper.chr_offset(address)? / CHR_WINDOW)
+            }),
+        }
+    }
+}
+
+/// See `Emulator::snapshot`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineState {
+    pub cpu: CpuState,
+    pub ppu: PpuState,
+    pub banks: Banks,
+    pub region: Region,
+    /// CPU cycles since power on
+    pub cycles: u64,
+    /// Frames since power on, as save states keep it
+    pub frame_count: u64,
+    /// Frames run since the game was loaded, rewound and replayed ones included
+    pub frames_run: u64,
+    /// An NMI is waiting for the CPU
+    pub nmi_pending: bool,
+    /// What's holding the CPU's IRQ line, empty when nothing is
+    pub irq: IrqSource,
+}
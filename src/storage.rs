@@ -0,0 +1,328 @@
+use crate::mapper::Mapper;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Persistence for battery saves and save states, addressed by a flat key such as
+/// `"game.sav"`. Implement this to plug in other storage (browser local storage, cloud saves).
+pub trait StorageBackend {
+    /// Returns `Ok(None)` when nothing has been stored under `key`
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn save(&mut self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn remove(&mut self, key: &str) -> io::Result<()>;
+    fn keys(&self) -> io::Result<Vec<String>>;
+}
+
+/// Stores every key as a file in a single directory
+#[derive(Debug, Clone)]
+pub struct FileSystemStorage {
+    root: PathBuf,
+}
+
+impl FileSystemStorage {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        FileSystemStorage {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        // keys are plain file names, never paths out of the storage directory
+        if key.is_empty() || key == "." || key == ".." || key.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid storage key {:?}", key),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl StorageBackend for FileSystemStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        fs::create_dir_all(&self.root)?;
+        fs::write(path, data)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                keys.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Keeps everything in a map, for tests and targets without a filesystem
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    entries: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn save(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.entries.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> io::Result<Vec<String>> {
+        let mut keys: Vec<String> = self.entries.keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+// Every file kept for a game starts with the same name: the rom's file name, or its CRC-32
+// with `SaveNaming::Hash` so renaming the rom keeps its saves. A game named after its file
+// claims the name with a "<name>.id" entry holding its CRC, and a different game turning up
+// with the same file name later gets the CRC added to its name instead of the other's saves.
+//
+//   smb.sav        battery save
+//   smb.st0        save state slot 0
+//   smb-001.png    screenshots, numbered
+//   smb.played     play time, see `playtime`
+
+/// What a game's files are named after
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SaveNaming {
+    /// The rom's file name without the extension
+    #[default]
+    FileName,
+    /// The CRC-32 of the rom, in hex
+    Hash,
+}
+
+impl SaveNaming {
+    pub fn parse(text: &str) -> Option<SaveNaming> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "filename" => Some(SaveNaming::FileName),
+            "hash" => Some(SaveNaming::Hash),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaveNaming::FileName => "filename",
+            SaveNaming::Hash => "hash",
+        }
+    }
+}
+
+/// The storage keys of one game's files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameFiles {
+    name: String,
+}
+
+// screenshots numbered past this overwrite the last one
+const MAX_SCREENSHOTS: u32 = 999;
+
+impl GameFiles {
+    /// Name the files of the game with checksum `crc`, loaded from a rom called `file_name`
+    pub fn claim(
+        storage: &mut dyn StorageBackend,
+        naming: SaveNaming,
+        file_name: &str,
+        crc: u32,
+    ) -> io::Result<GameFiles> {
+        if naming == SaveNaming::Hash {
+            return Ok(Self::by_hash(crc));
+        }
+        let hash = format!("{:08X}", crc);
+        let name = sanitize(file_name);
+        let id_key = format!("{}.id", name);
+        let name = match storage.load(&id_key)? {
+            None => {
+                storage.save(&id_key, hash.as_bytes())?;
+                name
+            }
+            Some(id) if String::from_utf8_lossy(&id).trim() == hash => name,
+            Some(_) => format!("{}-{}", name, hash),
+        };
+        Ok(GameFiles { name })
+    }
+
+    /// Named after the game's checksum, which needs nothing from the storage
+    pub fn by_hash(crc: u32) -> GameFiles {
+        GameFiles {
+            name: format!("{:08X}", crc),
+        }
+    }
+
+    /// What every key starts with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn battery(&self) -> String {
+        format!("{}.sav", self.name)
+    }
+
+    pub fn state(&self, slot: u32) -> String {
+        format!("{}.st{}", self.name, slot)
+    }
+
+    /// The first screenshot key not in use yet
+    pub fn next_screenshot(&self, storage: &dyn StorageBackend) -> io::Result<String> {
+        let keys = storage.keys()?;
+        let key = |n| format!("{}-{:03}.png", self.name, n);
+        Ok((1..MAX_SCREENSHOTS)
+            .map(key)
+            .find(|key| !keys.contains(key))
+            .unwrap_or_else(|| key(MAX_SCREENSHOTS)))
+    }
+}
+
+// rom file names can hold anything, keys only what's safe in a file name everywhere
+fn sanitize(file_name: &str) -> String {
+    let name: String = file_name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "game".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Write the cartridge's PRG RAM (battery backed SRAM) to `key`
+pub fn save_sram(
+    storage: &mut dyn StorageBackend,
+    key: &str,
+    mapper: &dyn Mapper,
+) -> io::Result<()> {
+    storage.save(key, mapper.prg_ram())
+}
+
+/// Restore PRG RAM from `key`, returns false when there was no save to load
+pub fn load_sram(
+    storage: &dyn StorageBackend,
+    key: &str,
+    mapper: &mut dyn Mapper,
+) -> io::Result<bool> {
+    match storage.load(key)? {
+        Some(data) => {
+            let ram = mapper.prg_ram_mut();
+            let len = data.len().min(ram.len());
+            ram[..len].copy_from_slice(&data[..len]);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(storage: &mut dyn StorageBackend) {
+        assert_eq!(storage.load("game.sav").unwrap(), None);
+        storage.save("game.sav", &[1, 2, 3]).unwrap();
+        storage.save("game.st1", &[4]).unwrap();
+        assert_eq!(storage.load("game.sav").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(storage.keys().unwrap(), vec!["game.sav", "game.st1"]);
+        storage.remove("game.sav").unwrap();
+        storage.remove("game.sav").unwrap();
+        assert_eq!(storage.load("game.sav").unwrap(), None);
+    }
+
+    #[test]
+    fn memory_storage() {
+        round_trip(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn filesystem_storage() {
+        let root = std::env::temp_dir().join(format!("nesemu-storage-{}", std::process::id()));
+        let mut storage = FileSystemStorage::new(&root);
+        round_trip(&mut storage);
+        assert!(storage.save("../escape.sav", &[0]).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn games_with_the_same_file_name_get_their_own_files() {
+        let mut storage = MemoryStorage::new();
+        let smb = GameFiles::claim(&mut storage, SaveNaming::FileName, "smb", 0x1234).unwrap();
+        assert_eq!(smb.battery(), "smb.sav");
+        assert_eq!(smb.state(2), "smb.st2");
+        let again = GameFiles::claim(&mut storage, SaveNaming::FileName, "smb", 0x1234).unwrap();
+        assert_eq!(again, smb);
+
+        let hack = GameFiles::claim(&mut storage, SaveNaming::FileName, "smb", 0xABCD).unwrap();
+        assert_eq!(hack.name(), "smb-0000ABCD");
+        let hashed = GameFiles::claim(&mut storage, SaveNaming::Hash, "smb", 0xABCD).unwrap();
+        assert_eq!(hashed.name(), "0000ABCD");
+    }
+
+    #[test]
+    fn file_names_are_made_safe() {
+        let mut storage = MemoryStorage::new();
+        let files = GameFiles::claim(&mut storage, SaveNaming::FileName, "..a/b:c", 0).unwrap();
+        assert_eq!(files.name(), "a_b_c");
+        let files = GameFiles::claim(&mut storage, SaveNaming::FileName, "..", 0).unwrap();
+        assert_eq!(files.name(), "game");
+    }
+
+    #[test]
+    fn screenshots_are_numbered() {
+        let mut storage = MemoryStorage::new();
+        let files = GameFiles::claim(&mut storage, SaveNaming::Hash, "smb", 1).unwrap();
+        assert_eq!(files.next_screenshot(&storage).unwrap(), "00000001-001.png");
+        storage.save("00000001-001.png", &[]).unwrap();
+        assert_eq!(files.next_screenshot(&storage).unwrap(), "00000001-002.png");
+    }
+}
@@ -0,0 +1,414 @@
+use crate::mapper::PRG_BANK_SIZE;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Labels for addresses, read from the symbol files assemblers and other emulators write, so
+// the disassembler, the tracer and debuggers can show `reset:` and `JSR update_sprites` instead
+// of bare addresses. Three formats are understood, picked by the file's name:
+//
+//   - ca65's debug info (`ld65 --dbgfile game.dbg`): the `sym` lines of type `lab`, at the CPU
+//     address they were linked at. Cheap local labels (`@loop`) are left out, there are
+//     too many of the same name.
+//   - FCEUX's name lists, which asm6f also writes: `game.nes.ram.nl` for RAM and registers,
+//     `game.nes.0.nl`, `game.nes.1.nl` and on for each 16KB PRG bank, lines like
+//     `$C004#reset#comment`. Any other `.nl` is taken as plain CPU addresses.
+//   - Mesen's label files (`.mlb`), lines like `P:0004:reset:comment` where the letter says what
+//     the address is into: P PRG ROM, R internal RAM, S save RAM and W work RAM from $6000, G
+//     anything else by CPU address. Mesen 2's long names (`NesPrgRom`, `NesInternalRam`, ...)
+//     work too.
+//
+// A label on PRG ROM is kept by its offset into the ROM where the file says which bank it's in,
+// so banked code gets the right label for whatever's mapped in; the rest are by CPU address.
+// Labels are saved as `.mlb`, CPU addresses the cartridge doesn't map to PRG ROM written as G.
+
+/// What a label is on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Location {
+    /// An address as the CPU sees it, whatever's mapped there
+    Cpu(u16),
+    /// An offset into PRG ROM
+    Prg(usize),
+}
+
+/// A label and what was written about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub comment: String,
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    /// A line that isn't in the file's format, numbered from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// A file whose name doesn't say what format it's in
+    UnknownFormat(String),
+    /// A label name that can't be written to a symbol file
+    BadName(String),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Io(e) => write!(f, "{}", e),
+            SymbolError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            SymbolError::UnknownFormat(name) => {
+                write!(f, "{} isn't a .dbg, .nl or .mlb symbol file", name)
+            }
+            SymbolError::BadName(name) => write!(f, "{:?} isn't a usable label", name),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> Self {
+        SymbolError::Io(e)
+    }
+}
+
+/// What the addresses in an FCEUX name list are
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NameList {
+    /// CPU addresses, for `.ram.nl` files and ones named any other way
+    Cpu,
+    /// Addresses in the 16KB PRG bank, for `.N.nl` files
+    Bank(usize),
+}
+
+/// The labels for a game
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<Location, Symbol>,
+}
+
+fn check_name(name: &str) -> Result<(), SymbolError> {
+    // the separators of the file formats can't be in a name
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || ":#\",".contains(c))
+    {
+        Err(SymbolError::BadName(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    let text = text.trim();
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Label `location` as `name`, replacing any label it had
+    pub fn add(
+        &mut self,
+        location: Location,
+        name: &str,
+        comment: &str,
+    ) -> Result<(), SymbolError> {
+        check_name(name)?;
+        self.symbols.insert(
+            location,
+            Symbol {
+                name: name.to_string(),
+                comment: comment.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns false if `location` had no label
+    pub fn remove(&mut self, location: Location) -> bool {
+        self.symbols.remove(&location).is_some()
+    }
+
+    pub fn get(&self, location: Location) -> Option<&Symbol> {
+        self.symbols.get(&location)
+    }
+
+    /// The label for CPU `address`, which is `prg_offset` into PRG ROM if it's mapped there
+    pub fn lookup(&self, address: u16, prg_offset: Option<usize>) -> Option<&Symbol> {
+        prg_offset
+            .and_then(|offset| self.symbols.get(&Location::Prg(offset)))
+            .or_else(|| self.symbols.get(&Location::Cpu(address)))
+    }
+
+    /// Where `name` is, the first if there are several
+    pub fn find(&self, name: &str) -> Option<Location> {
+        self.symbols
+            .iter()
+            .find(|(_, symbol)| symbol.name == name)
+            .map(|(&location, _)| location)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Location, &Symbol)> {
+        self.symbols
+            .iter()
+            .map(|(&location, symbol)| (location, symbol))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+    }
+
+    /// Add the labels in the symbol file at `path`, returning how many weren't there before
+    pub fn load_file(&mut self, path: &Path) -> Result<usize, SymbolError> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let text = fs::read_to_string(path)?;
+        let before = self.len();
+        if name.ends_with(".dbg") {
+            self.parse_dbg(&text)?;
+        } else if name.ends_with(".mlb") {
+            self.parse_mlb(&text)?;
+        } else if let Some(stem) = name.strip_suffix(".nl") {
+            let list = match stem.rsplit_once('.').map(|(_, last)| last.parse()) {
+                Some(Ok(bank)) => NameList::Bank(bank),
+                _ => NameList::Cpu,
+            };
+            self.parse_nl(&text, list)?;
+        } else {
+            return Err(SymbolError::UnknownFormat(path.display().to_string()));
+        }
+        Ok(self.len() - before)
+    }
+
+    /// Add the code labels from ca65 debug info
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (number, line) in text.lines().enumerate() {
+            let Some(fields) = line.strip_prefix("sym\t") else {
+                continue;
+            };
+            let field = |key: &str| {
+                fields
+                    .split(',')
+                    .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+            };
+            if field("type") != Some("lab") {
+                continue;
+            }
+            let bad = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let name = field("name")
+                .and_then(|name| name.strip_prefix('"')?.strip_suffix('"'))
+                .ok_or_else(|| bad("sym without a name"))?;
+            let address = field("val")
+                .and_then(parse_hex)
+                .filter(|&address| address <= 0xFFFF)
+                .ok_or_else(|| bad("sym without an address"))?;
+            if !name.starts_with('@') {
+                self.add(Location::Cpu(address as u16), name, "")
+                    .map_err(|e| bad(&e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the labels from an FCEUX name list
+    pub fn parse_nl(&mut self, text: &str, list: NameList) -> Result<(), SymbolError> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            // `\` lines carry on the comment before, which isn't kept past its first line
+            if line.is_empty() || line.starts_with('\\') {
+                continue;
+            }
+            let bad = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let mut parts = line.splitn(3, '#');
+            let address = parts.next().unwrap_or_default();
+            // `$0300/10` names an array, the label goes on its start
+            let address = address.split('/').next().unwrap_or_default();
+            let address = parse_hex(address)
+                .filter(|&address| address <= 0xFFFF)
+                .ok_or_else(|| bad("expected $address#name#comment"))?;
+            let name = parts.next().unwrap_or_default();
+            let comment = parts.next().unwrap_or_default();
+            if name.is_empty() {
+                continue;
+            }
+            let location = match list {
+                NameList::Bank(bank) if address >= 0x8000 => {
+                    Location::Prg(bank * PRG_BANK_SIZE + address % PRG_BANK_SIZE)
+                }
+                _ => Location::Cpu(address as u16),
+            };
+            self.add(location, name, comment)
+                .map_err(|e| bad(&e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Add the labels from a Mesen label file
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let bad = |message: &str| SymbolError::Parse {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let mut parts = line.splitn(4, ':');
+            let kind = parts.next().unwrap_or_default();
+            // a label over a range goes on its start
+            let address = parts.next().unwrap_or_default();
+            let address = address.split('-').next().unwrap_or_default();
+            let address = parse_hex(address).ok_or_else(|| bad("expected type:address:name"))?;
+            let name = parts.next().unwrap_or_default();
+            let comment = parts.next().unwrap_or_default().replace("\\n", "\n");
+            // comment only lines have no name
+            if name.is_empty() {
+                continue;
+            }
+            let cpu = |address: usize| {
+                u16::try_from(address)
+                    .map(Location::Cpu)
+                    .map_err(|_| bad("address out of range"))
+            };
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(address),
+                "R" | "NesInternalRam" => cpu(address & 0x7FF)?,
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => cpu(0x6000 + address)?,
+                "G" | "NesMemory" => cpu(address)?,
+                // CHR, palette and other PPU labels
+                _ => continue,
+            };
+            self.add(location, name, &comment)
+                .map_err(|e| bad(&e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// The labels as a Mesen label file. `prg_offset` says where the CPU addresses the
+    /// cartridge maps to PRG ROM are in it, so they're saved as PRG labels.
+    pub fn to_mlb(&self, prg_offset: impl Fn(u16) -> Option<usize>) -> String {
+        let mut text = String::new();
+        for (location, symbol) in self.iter() {
+            let (kind, address) = match location {
+                Location::Prg(offset) => ('P', offset),
+                Location::Cpu(address) => match (address, prg_offset(address)) {
+                    (_, Some(offset)) => ('P', offset),
+                    (0x0000..=0x07FF, None) => ('R', address as usize),
+                    (0x6000..=0x7FFF, None) => ('W', address as usize - 0x6000),
+                    (_, None) => ('G', address as usize),
+                },
+            };
+            let _ = write!(text, "{}:{:04X}:{}", kind, address, symbol.name);
+            if !symbol.comment.is_empty() {
+                let _ = write!(text, ":{}", symbol.comment.replace('\n', "\\n"));
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_each_format() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .parse_dbg(
+                "version\tmajor=2,minor=0\n\
+                 sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0xC004,seg=0,type=lab\n\
+                 sym\tid=1,name=\"@loop\",addrsize=absolute,scope=0,def=2,val=0xC010,seg=0,type=lab\n\
+                 sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=3,val=0x2000,type=equ\n",
+            )
+            .unwrap();
+        symbols
+            .parse_nl(
+                "$0300/10#buffer#sprites\n\\ for OAM DMA\n$C010##\n",
+                NameList::Cpu,
+            )
+            .unwrap();
+        symbols
+            .parse_nl("$8010#nmi_handler#\n", NameList::Bank(1))
+            .unwrap();
+        symbols
+            .parse_mlb("R:0010:player_x:left edge\nP:7FF0:irq_handler\nS:0000:save_slot\nP:0020::a comment\n")
+            .unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.find("reset"), Some(Location::Cpu(0xC004)));
+        assert_eq!(symbols.find("buffer"), Some(Location::Cpu(0x0300)));
+        assert_eq!(symbols.find("nmi_handler"), Some(Location::Prg(0x4010)));
+        assert_eq!(symbols.find("irq_handler"), Some(Location::Prg(0x7FF0)));
+        assert_eq!(symbols.find("save_slot"), Some(Location::Cpu(0x6000)));
+        assert_eq!(
+            symbols.get(Location::Cpu(0x0010)).unwrap().comment,
+            "left edge"
+        );
+        // a PRG label wins over a CPU one at the same address
+        symbols.add(Location::Cpu(0xC010), "other", "").unwrap();
+        assert_eq!(
+            symbols.lookup(0xC010, Some(0x4010)).unwrap().name,
+            "nmi_handler"
+        );
+        assert_eq!(symbols.lookup(0xC010, None).unwrap().name, "other");
+
+        let err = symbols.parse_nl("C000 reset\n", NameList::Cpu).unwrap_err();
+        assert!(matches!(err, SymbolError::Parse { line: 1, .. }));
+        assert!(symbols.add(Location::Cpu(0), "two words", "").is_err());
+    }
+
+    #[test]
+    fn saves_as_mlb() {
+        let mut symbols = SymbolTable::new();
+        symbols.add(Location::Cpu(0x0010), "player_x", "").unwrap();
+        symbols.add(Location::Cpu(0x2000), "PPUCTRL", "").unwrap();
+        symbols
+            .add(Location::Cpu(0xC004), "reset", "power on\nand reset")
+            .unwrap();
+        symbols.add(Location::Prg(0x10), "nmi", "").unwrap();
+        let text = symbols.to_mlb(|address| (address >= 0xC000).then(|| address as usize - 0xC000));
+        assert_eq!(
+            text,
+            "R:0010:player_x\n\
+             G:2000:PPUCTRL\n\
+             P:0004:reset:power on\\nand reset\n\
+             P:0010:nmi\n"
+        );
+        let mut read = SymbolTable::new();
+        read.parse_mlb(&text).unwrap();
+        assert_eq!(
+            read.get(Location::Prg(4)).unwrap().comment,
+            "power on\nand reset"
+        );
+        assert_eq!(read.len(), 4);
+    }
+}
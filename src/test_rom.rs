@@ -0,0 +1,242 @@
+use crate::emulator::Emulator;
+use crate::hash::to_hex;
+use crate::memory_editor::AddressSpace;
+use crate::{NesRom, RomError};
+use std::fmt;
+
+// Runs test ROMs that tell whether they passed, blargg's above all, without a window. They
+// say so in one of three ways:
+//
+// - Newer ROMs write $DE $B0 $61 to $6001-$6003 once they're running, then keep a status at
+//   $6000: $80 while running, $81 to ask for the reset button to be pressed at least 100ms
+//   later, and anything else once they're done, 0 being a pass. A message for whoever's
+//   watching is at $6004 on, ending in a zero byte.
+// - Older ones, such as the branch timing tests, keep their result at $00F8: 1 for a pass,
+//   the number of the failing test from 2 up, and 0 if something went wrong in the test
+//   itself. There's no telling when they've finished, so they get their frames and are read
+//   at the end.
+// - The rest only draw their result. They pass if the last frame hashes the same as on an
+//   emulator known to pass them.
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const MESSAGE: u16 = 0x6004;
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+// frames to hold off before pressing reset, a little over 100ms
+const RESET_DELAY: u32 = 7;
+const ZERO_PAGE_RESULT: u16 = 0x00F8;
+// longest message read, in case the zero byte never comes
+const MAX_MESSAGE: u16 = 0x1000;
+
+/// How a test ROM gives its result
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    /// The status byte and message at $6000
+    Status,
+    /// A result byte at $00F8, read after all the frames have run
+    ZeroPage,
+    /// Only the picture, which should end up with this SHA-1
+    FrameHash(&'static str),
+}
+
+/// What a test ROM said
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// It finished with result `code` and `message`, if it gave one
+    Failed {
+        code: u8,
+        message: String,
+    },
+    /// It was still running when its frames ran out
+    TimedOut {
+        message: String,
+    },
+    /// The CPU jammed at `pc`
+    Jammed {
+        pc: u16,
+    },
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Passed
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Failed { code, message } if message.is_empty() => {
+                write!(f, "failed with code {}", code)
+            }
+            Outcome::Failed { code, message } => {
+                write!(f, "failed with code {}: {}", code, message.trim())
+            }
+            Outcome::TimedOut { message } if message.is_empty() => write!(f, "timed out"),
+            Outcome::TimedOut { message } => write!(f, "timed out: {}", message.trim()),
+            Outcome::Jammed { pc } => write!(f, "CPU jammed at ${:04X}", pc),
+        }
+    }
+}
+
+/// Run `rom` for up to `frames` frames and return what it said
+pub fn run(rom: &NesRom, protocol: Protocol, frames: u32) -> Result<Outcome, RomError> {
+    let mut emulator = Emulator::new();
+    emulator.load_cartridge(rom)?;
+    Ok(run_loaded(&mut emulator, protocol, frames))
+}
+
+/// Run the test already loaded into `emulator`, for up to `frames` frames
+pub fn run_loaded(emulator: &mut Emulator, protocol: Protocol, frames: u32) -> Outcome {
+    let mut reset_in = None;
+    for _ in 0..frames {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+        if let Some(pc) = emulator.cpu().jammed() {
+            return Outcome::Jammed { pc };
+        }
+        if protocol != Protocol::Status || !has_signature(emulator) {
+            continue;
+        }
+        match emulator.peek_memory(AddressSpace::Cpu, STATUS) {
+            RUNNING => {}
+            NEEDS_RESET => match reset_in {
+                Some(0) => {
+                    emulator.reset();
+                    reset_in = None;
+                }
+                Some(frames) => reset_in = Some(frames - 1),
+                None => reset_in = Some(RESET_DELAY),
+            },
+            0 => return Outcome::Passed,
+            code => {
+                return Outcome::Failed {
+                    code,
+                    message: message(emulator),
+                }
+            }
+        }
+    }
+    match protocol {
+        Protocol::Status => Outcome::TimedOut {
+            message: if has_signature(emulator) {
+                message(emulator)
+            } else {
+                String::new()
+            },
+        },
+        Protocol::ZeroPage => match emulator.peek_memory(AddressSpace::Cpu, ZERO_PAGE_RESULT) {
+            1 => Outcome::Passed,
+            code => Outcome::Failed {
+                code,
+                message: String::new(),
+            },
+        },
+        Protocol::FrameHash(expected) => {
+            let hash = to_hex(&emulator.frame().sha1());
+            if hash.eq_ignore_ascii_case(expected) {
+                Outcome::Passed
+            } else {
+                Outcome::Failed {
+                    code: 0,
+                    message: format!("last frame hashed to {}, expected {}", hash, expected),
+                }
+            }
+        }
+    }
+}
+
+fn has_signature(emulator: &Emulator) -> bool {
+    (0..3).all(|i| emulator.peek_memory(AddressSpace::Cpu, STATUS + 1 + i) == SIGNATURE[i as usize])
+}
+
+/// The text at $6004, up to its zero byte
+fn message(emulator: &Emulator) -> String {
+    let bytes: Vec<u8> = (MESSAGE..MESSAGE + MAX_MESSAGE)
+        .map(|address| emulator.peek_memory(AddressSpace::Cpu, address))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn run_program(source: &str, protocol: Protocol, frames: u32) -> Outcome {
+        let mut emulator = Emulator::new();
+        emulator.cpu_mut().load_bytes(&assemble(source).unwrap());
+        run_loaded(&mut emulator, protocol, frames)
+    }
+
+    // sign, say it's running, then end with `status` and the message "ok"
+    fn status_program(status: u8) -> String {
+        format!(
+            "
+                    lda #$80
+                    sta $6000
+                    lda #$DE
+                    sta $6001
+                    lda #$B0
+                    sta $6002
+                    lda #$61
+                    sta $6003
+                    lda #$6F
+                    sta $6004
+                    lda #$6B
+                    sta $6005
+                    lda #0
+                    sta $6006
+                    lda #${:02X}
+                    sta $6000
+            wait:   jmp wait
+            ",
+            status
+        )
+    }
+
+    #[test]
+    fn status_byte() {
+        let passed = run_program(&status_program(0), Protocol::Status, 2);
+        assert_eq!(passed, Outcome::Passed);
+        let failed = run_program(&status_program(3), Protocol::Status, 2);
+        assert_eq!(
+            failed,
+            Outcome::Failed {
+                code: 3,
+                message: "ok".to_string()
+            }
+        );
+        assert_eq!(failed.to_string(), "failed with code 3: ok");
+        let running = run_program(&status_program(0x80), Protocol::Status, 2);
+        assert_eq!(
+            running,
+            Outcome::TimedOut {
+                message: "ok".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn zero_page_result() {
+        let source = "
+                    lda #1
+                    sta $F8
+            wait:   jmp wait
+        ";
+        assert!(run_program(source, Protocol::ZeroPage, 1).passed());
+        let jam = "
+                    lda #1
+                    sta $F8
+                    .byte $02
+        ";
+        assert_eq!(
+            run_program(jam, Protocol::ZeroPage, 1),
+            Outcome::Jammed { pc: 0x8004 }
+        );
+    }
+}
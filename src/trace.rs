@@ -0,0 +1,319 @@
+use crate::cpu::{NesCpu, Processor};
+use crate::instructions::{is_official, AddressingMode, Instructions};
+use crate::memory::Bus;
+use std::fmt;
+use std::io::{self, Write};
+
+// The instruction trace, one line per instruction laid out exactly like nestest.log, so a trace
+// can be diffed against that log or against another emulator writing the same format:
+//
+//   C72A  D0 E0     BNE $C70C                       A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 93 CYC:31
+//   C6BD  04 A9    *NOP $A9 = 00                    A:AA X:97 Y:4E P:EF SP:F5 PPU: 12,236 CYC:...
+//
+// Each line is written before the instruction runs: the registers, the PPU scanline and dot
+// and the CPU cycle count are as it starts. Operands show the address the instruction works
+// on and the byte (or for JMP indirect, the word) there, read without side effects. Unofficial
+// opcodes are marked with a `*`.
+//
+// With labels on, an instruction at an address with a label gets a line of its own before it,
+// `reset:`, like the disassembler's. That's off by default so traces still diff against logs.
+
+/// Writes the trace of every instruction the CPU runs
+pub struct TraceLogger {
+    out: Box<dyn Write + Send>,
+    enabled: bool,
+    labels: bool,
+    // the first write that failed, tracing stops after it
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for TraceLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceLogger")
+            .field("enabled", &self.enabled)
+            .field("labels", &self.labels)
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TraceLogger {
+    /// Log into `out`, enabled. Wrap files in a `BufWriter`, there's a line per instruction.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        TraceLogger {
+            out: Box::new(out),
+            enabled: true,
+            labels: false,
+            error: None,
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Put a line with the label before labelled instructions
+    pub fn set_labels(&mut self, labels: bool) {
+        self.labels = labels;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && self.error.is_none()
+    }
+
+    /// Why tracing stopped, if a write failed
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Log the instruction `cpu` is about to run
+    pub(crate) fn log(&mut self, cpu: &NesCpu) {
+        if !self.is_enabled() {
+            return;
+        }
+        let label = if self.labels {
+            cpu.memory.label(cpu.reg.pc)
+        } else {
+            None
+        };
+        let written = match label {
+            Some(label) => writeln!(self.out, "{}:\n{}", label, line(cpu)),
+            None => writeln!(self.out, "{}", line(cpu)),
+        };
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+}
+
+/// nestest.log's name for an instruction
+fn mnemonic(instruction: &Instructions) -> &str {
+    match instruction {
+        Instructions::ISC => "ISB",
+        Instructions::USBC => "SBC",
+        _ => instruction.asm(),
+    }
+}
+
+/// The instruction at `pc` and the operand as nestest.log shows it
+fn instruction_text(cpu: &NesCpu, pc: u16) -> String {
+    let memory = &cpu.memory;
+    // the APU and controller registers are shown as $FF, as nestest.log has them
+    let peek = |address: u16| match address {
+        0x4000..=0x4017 => 0xFF,
+        _ => memory.peek_byte(address),
+    };
+    let peek_zero_page_word = |address: u8| {
+        u16::from_le_bytes([peek(address as u16), peek(address.wrapping_add(1) as u16)])
+    };
+    let opcode = peek(pc);
+    let (instruction, mode) = NesCpu::decode_instruction(opcode);
+    let byte = peek(pc.wrapping_add(1));
+    let word = u16::from_le_bytes([byte, peek(pc.wrapping_add(2))]);
+    let (x, y) = (cpu.reg.idx, cpu.reg.idy());
+    let operand = match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", byte),
+        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", byte, peek(byte as u16)),
+        AddressingMode::ZeroPageX => {
+            let address = byte.wrapping_add(x);
+            format!(
+                "${:02X},X @ {:02X} = {:02X}",
+                byte,
+                address,
+                peek(address as u16)
+            )
+        }
+        AddressingMode::ZeroPageY => {
+            let address = byte.wrapping_add(y);
+            format!(
+                "${:02X},Y @ {:02X} = {:02X}",
+                byte,
+                address,
+                peek(address as u16)
+            )
+        }
+        AddressingMode::Absolute => match instruction {
+            Instructions::Jump | Instructions::JumpSubroutine => format!("${:04X}", word),
+            _ => format!("${:04X} = {:02X}", word, peek(word)),
+        },
+        AddressingMode::AbsoluteX => {
+            let address = word.wrapping_add(x as u16);
+            format!("${:04X},X @ {:04X} = {:02X}", word, address, peek(address))
+        }
+        AddressingMode::AbsoluteY => {
+            let address = word.wrapping_add(y as u16);
+            format!("${:04X},Y @ {:04X} = {:02X}", word, address, peek(address))
+        }
+        AddressingMode::Indirect => {
+            // the high byte comes from the same page, as the CPU reads it
+            let high = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = u16::from_le_bytes([peek(word), peek(high)]);
+            format!("(${:04X}) = {:04X}", word, target)
+        }
+        AddressingMode::XIndirect => {
+            let pointer = byte.wrapping_add(x);
+            let address = peek_zero_page_word(pointer);
+            format!(
+                "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                byte,
+                pointer,
+                address,
+                peek(address)
+            )
+        }
+        AddressingMode::YIndirect => {
+            let base = peek_zero_page_word(byte);
+            let address = base.wrapping_add(y as u16);
+            format!(
+                "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                byte,
+                base,
+                address,
+                peek(address)
+            )
+        }
+        AddressingMode::Relative => {
+            let target = pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+    };
+    if operand.is_empty() {
+        mnemonic(&instruction).to_string()
+    } else {
+        format!("{} {}", mnemonic(&instruction), operand)
+    }
+}
+
+/// The trace line for the instruction `cpu` is about to run
+pub fn line(cpu: &NesCpu) -> String {
+    let pc = cpu.reg.pc;
+    let opcode = cpu.memory.peek_byte(pc);
+    let (_, mode) = NesCpu::decode_instruction(opcode);
+    let bytes: Vec<String> = (0..mode.get_increment())
+        .map(|i| format!("{:02X}", cpu.memory.peek_byte(pc.wrapping_add(i))))
+        .collect();
+    let ppu = cpu.memory.ppu();
+    format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc,
+        bytes.join(" "),
+        if is_official(opcode) { ' ' } else { '*' },
+        instruction_text(cpu, pc),
+        cpu.reg.accumulator,
+        cpu.reg.idx,
+        cpu.reg.idy(),
+        cpu.reg.status(),
+        cpu.reg.sp(),
+        ppu.scanline(),
+        ppu.dot(),
+        cpu.tick
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::symbols::Location;
+    use std::sync::{Arc, Mutex};
+
+    // a Write the test can still read after handing it over
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cpu_running(source: &str) -> NesCpu {
+        NesCpu::new_from_bytes(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn nestest_layout() {
+        let mut cpu = cpu_running("jmp $C5F5");
+        cpu.set_pc(0x8000);
+        assert_eq!(
+            line(&cpu),
+            "8000  4C F5 C5  JMP $C5F5                       \
+             A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0"
+        );
+    }
+
+    #[test]
+    fn operands_show_where_they_point() {
+        let mut cpu = cpu_running(
+            "
+            lda ($80,X)
+            lda ($89),Y
+            sta $0300,X
+            jmp ($02FF)
+            .byte $04, $A9      ; NOP zero page, unofficial
+            ",
+        );
+        cpu.memory.write_bytes(0x0080, &[0x00, 0x03]);
+        cpu.memory.write_bytes(0x0089, &[0x00, 0x03]);
+        cpu.memory.write_byte(0x0300, 0x89);
+        cpu.memory.write_byte(0x02FF, 0x34);
+        cpu.memory.write_byte(0x0200, 0x12);
+        let text = |cpu: &NesCpu, pc| instruction_text(cpu, pc);
+        assert_eq!(text(&cpu, 0x8000), "LDA ($80,X) @ 80 = 0300 = 89");
+        assert_eq!(text(&cpu, 0x8002), "LDA ($89),Y = 0300 @ 0300 = 89");
+        assert_eq!(text(&cpu, 0x8004), "STA $0300,X @ 0300 = 89");
+        assert_eq!(text(&cpu, 0x8007), "JMP ($02FF) = 1234");
+        cpu.set_pc(0x800A);
+        assert!(line(&cpu).starts_with("800A  04 A9    *NOP $A9 = 00 "));
+    }
+
+    #[test]
+    fn logs_while_enabled() {
+        let out = Shared::default();
+        let mut cpu = cpu_running("inx\ninx\ninx");
+        cpu.set_trace_logger(Some(TraceLogger::new(out.clone())));
+        cpu.fetch_decode_next();
+        cpu.trace_logger_mut().unwrap().set_enabled(false);
+        cpu.fetch_decode_next();
+        cpu.set_logging(true);
+        cpu.fetch_decode_next();
+        let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("8000  E8        INX "));
+        assert!(lines[1].starts_with("8002  E8        INX "));
+        assert!(lines[1].ends_with("A:00 X:02 Y:00 P:24 SP:FD PPU:  0, 12 CYC:4"));
+    }
+
+    #[test]
+    fn labels_go_on_their_own_line() {
+        let out = Shared::default();
+        let mut cpu = cpu_running("inx\ninx");
+        let symbols = cpu.memory.symbols_mut();
+        symbols.add(Location::Cpu(0x8001), "second", "").unwrap();
+        let mut tracer = TraceLogger::new(out.clone());
+        tracer.set_labels(true);
+        cpu.set_trace_logger(Some(tracer));
+        cpu.fetch_decode_next();
+        cpu.fetch_decode_next();
+        let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("8000  E8        INX "));
+        assert_eq!(lines[1], "second:");
+        assert!(lines[2].starts_with("8001  E8        INX "));
+    }
+}
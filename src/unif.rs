@@ -0,0 +1,220 @@
+use crate::header::{RomHeader, Timing};
+use crate::mapper::Mirroring;
+use crate::{into_banks, NesRom, RomError};
+
+// https://www.nesdev.org/wiki/UNIF
+// A 32 byte header ("UNIF", revision, padding) then chunks of a 4 byte ID, a little endian
+// length and the data. Boards are named instead of numbered, so only boards with a known
+// iNES mapper can be loaded. PRG and CHR come in up to 16 numbered chunks each.
+
+pub const UNIF_MAGIC: [u8; 4] = *b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+const CHUNK_HEADER_SIZE: usize = 8;
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+// board names without their NES-/HVC-/UNL- prefix
+const BOARDS: &[(u16, &[&str])] = &[
+    (0, &["NROM", "NROM-128", "NROM-256", "RROM", "RROM-128"]),
+    (
+        1,
+        &[
+            "SAROM", "SBROM", "SCROM", "SEROM", "SFROM", "SGROM", "SHROM", "SJROM", "SKROM",
+            "SLROM", "SL1ROM", "SNROM", "SOROM", "SUROM", "SXROM",
+        ],
+    ),
+    (2, &["UNROM", "UOROM"]),
+    (3, &["CNROM"]),
+    (
+        4,
+        &[
+            "TBROM", "TEROM", "TFROM", "TGROM", "TKROM", "TLROM", "TL1ROM", "TR1ROM", "TSROM",
+            "TVROM",
+        ],
+    ),
+    (7, &["ANROM", "AMROM", "AOROM"]),
+    (9, &["PNROM"]),
+    (13, &["CPROM"]),
+    (66, &["GNROM", "MHROM"]),
+];
+
+fn board_mapper(board: &str) -> Option<u16> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(_, names)| names.contains(&name))
+        .map(|&(mapper, _)| mapper)
+}
+
+pub fn is_unif(bytes: &[u8]) -> bool {
+    bytes.starts_with(&UNIF_MAGIC)
+}
+
+/// Convert a UNIF image to the equivalent NES 2.0 rom
+pub fn parse(bytes: &[u8]) -> Result<NesRom, RomError> {
+    if !is_unif(bytes) {
+        return Err(RomError::BadMagic);
+    }
+    let mut board = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = None;
+    let mut battery = false;
+    let mut timing = Timing::Ntsc;
+
+    let mut rest = bytes
+        .get(UNIF_HEADER_SIZE..)
+        .ok_or(RomError::TruncatedHeader)?;
+    while !rest.is_empty() {
+        let (id, length) = match rest.get(..CHUNK_HEADER_SIZE) {
+            Some(chunk) => (
+                &chunk[..4],
+                u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize,
+            ),
+            None => return Err(RomError::BadUnif),
+        };
+        let data = rest
+            .get(CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + length)
+            .ok_or(RomError::BadUnif)?;
+        rest = &rest[CHUNK_HEADER_SIZE + length..];
+
+        let bank = (id[3] as char).to_digit(16).unwrap_or(0) as usize;
+        match &id[..3] {
+            b"MAP" if id[3] == b'R' => {
+                // zero terminated
+                let name = data.split(|&b| b == 0).next().unwrap_or(&[]);
+                board = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            b"PRG" => prg_chunks[bank] = Some(data),
+            b"CHR" => chr_chunks[bank] = Some(data),
+            b"MIR" if id[3] == b'R' => {
+                mirroring = match data.first() {
+                    Some(0) => Some(Mirroring::Horizontal),
+                    Some(1) => Some(Mirroring::Vertical),
+                    Some(2) => Some(Mirroring::SingleScreenLower),
+                    Some(3) => Some(Mirroring::SingleScreenUpper),
+                    Some(4) => Some(Mirroring::FourScreen),
+                    // 5 is mapper controlled
+                    _ => None,
+                }
+            }
+            b"BAT" if id[3] == b'R' => battery = true,
+            b"TVC" if id[3] == b'I' => {
+                timing = match data.first() {
+                    Some(1) => Timing::Pal,
+                    Some(2) => Timing::MultiRegion,
+                    _ => Timing::Ntsc,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let board = board.ok_or(RomError::BadUnif)?;
+    let mapper = board_mapper(&board).ok_or(RomError::UnknownBoard(board))?;
+    let prg: Vec<u8> = prg_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    let chr: Vec<u8> = chr_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    let prg_banks = prg.len().div_ceil(PRG_BANK_SIZE);
+    let chr_banks = chr.len().div_ceil(CHR_BANK_SIZE);
+
+    let mut header = RomHeader::new(mapper, prg_banks * PRG_BANK_SIZE, chr_banks * CHR_BANK_SIZE);
+    if battery {
+        header.battery = true;
+        header.prg_nvram_size = header.prg_ram_size;
+        header.prg_ram_size = 0;
+    }
+    // including single screen, which NES 2.0 has no bit for
+    if let Some(mirroring) = mirroring {
+        header.mirroring = mirroring;
+    }
+    header.timing = timing;
+    Ok(NesRom::new(
+        header,
+        None,
+        into_banks(&prg),
+        into_banks(&chr),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::Region;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn unif(board: &str, extra: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = UNIF_MAGIC.to_vec();
+        bytes.resize(UNIF_HEADER_SIZE, 0);
+        bytes.extend(chunk(b"MAPR", format!("{}\0", board).as_bytes()));
+        bytes.extend(chunk(b"NAME", b"Test\0"));
+        // out of order, PRG1 still goes after PRG0
+        bytes.extend(chunk(b"PRG1", &[2; PRG_BANK_SIZE]));
+        bytes.extend(chunk(b"PRG0", &[1; PRG_BANK_SIZE]));
+        for extra in extra {
+            bytes.extend_from_slice(extra);
+        }
+        bytes
+    }
+
+    #[test]
+    fn converts_to_nes_rom() {
+        let bytes = unif(
+            "NES-NROM-256",
+            &[
+                chunk(b"CHR0", &[3; CHR_BANK_SIZE]),
+                chunk(b"MIRR", &[1]),
+                chunk(b"TVCI", &[1]),
+                chunk(b"BATR", &[1]),
+            ],
+        );
+        let rom = NesRom::from_bytes(&bytes).unwrap();
+        assert_eq!(rom.mapper_number(), 0);
+        assert_eq!(rom.prg_rom.len(), 2);
+        assert_eq!(rom.prg_rom[0][0], 1);
+        assert_eq!(rom.prg_rom[1][0], 2);
+        assert_eq!(rom.chr_rom.len(), 1);
+        assert_eq!(rom.mirroring(), Mirroring::Vertical);
+        assert_eq!(rom.region(), Region::Pal);
+        assert!(rom.has_battery());
+        assert_eq!(rom.header.prg_nvram_size, 8192);
+    }
+
+    #[test]
+    fn chr_ram_and_single_screen() {
+        let rom = parse(&unif("NES-AOROM", &[chunk(b"MIRR", &[3])])).unwrap();
+        assert_eq!(rom.mapper_number(), 7);
+        assert!(rom.has_chr_ram());
+        assert_eq!(rom.header.chr_ram_size, 8192);
+        assert_eq!(rom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn rejects_unknown_boards_and_bad_chunks() {
+        assert!(matches!(
+            parse(&unif("UNL-SOMETHING", &[])),
+            Err(RomError::UnknownBoard(board)) if board == "UNL-SOMETHING"
+        ));
+        let mut truncated = unif("NES-NROM-256", &[]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(parse(&truncated), Err(RomError::BadUnif)));
+    }
+}
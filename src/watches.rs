@@ -0,0 +1,349 @@
+use crate::breakpoints::{BadCondition, Condition};
+use crate::cpu::NesCpu;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+
+// Watches follow game variables over time without a full trace. A watch is an expression
+// written like a breakpoint condition, `[$0075]`, `[$0300] & $0F` or `A`, sampled either at
+// the end of every frame or each time the CPU writes a byte it reads. On-write watches need
+// their addresses written as numbers, `[$0300]` and not `[$0300 + X]`, so it's known which
+// writes to look for. They're sampled once the instruction writing has finished, so a read-modify-write
+// instruction gives one sample of where it ended up.
+//
+// Samples queue up for `drain`, the oldest dropped past `MAX_SAMPLES`, and can also be streamed
+// out as CSV as they're taken, a line each:
+//
+//   frame,cycle,pc,watch,value
+//   12,357391,$C0F3,[$0075],3
+
+/// Samples kept for `drain` before the oldest are dropped
+pub const MAX_SAMPLES: usize = 65536;
+
+/// When a watch is sampled
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchTrigger {
+    /// At the end of every frame
+    Frame,
+    /// After each instruction that writes a byte it reads
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub id: u32,
+    pub expression: Condition,
+    pub trigger: WatchTrigger,
+    /// What it came to last time, None until it's been sampled
+    pub last: Option<i64>,
+    // the bytes it reads, for on-write watches
+    addresses: Vec<u16>,
+}
+
+/// A watch's value and when it was taken
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchSample {
+    pub id: u32,
+    /// Frames since power on
+    pub frame: u64,
+    /// CPU cycles since power on
+    pub cycle: u64,
+    /// The instruction that wrote, or where the CPU is at the end of the frame
+    pub pc: u16,
+    pub value: i64,
+}
+
+/// The watches, in the order they were added, and their samples
+#[derive(Default)]
+pub struct Watches {
+    list: Vec<Watch>,
+    next_id: u32,
+    samples: VecDeque<WatchSample>,
+    out: Option<Box<dyn Write + Send>>,
+    // the first write that failed, streaming stops after it
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Watches {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watches")
+            .field("list", &self.list)
+            .field("samples", &self.samples.len())
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Watches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watch `expression` and return the watch's id. The CPU adds and removes them, to tell
+    /// the bus which writes to look out for.
+    pub(crate) fn add(
+        &mut self,
+        expression: Condition,
+        trigger: WatchTrigger,
+    ) -> Result<u32, BadCondition> {
+        let addresses = match trigger {
+            WatchTrigger::Frame => Vec::new(),
+            WatchTrigger::Write => match expression.addresses() {
+                Some(addresses) if !addresses.is_empty() => addresses,
+                _ => {
+                    return Err(BadCondition(format!(
+                        "`{}` doesn't read memory at fixed addresses to watch for writes to",
+                        expression
+                    )))
+                }
+            },
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Watch {
+            id,
+            expression,
+            trigger,
+            last: None,
+            addresses,
+        });
+        Ok(id)
+    }
+
+    pub(crate) fn remove(&mut self, id: u32) -> bool {
+        let before = self.list.len();
+        self.list.retain(|watch| watch.id != id);
+        self.list.len() != before
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Watch> {
+        self.list.iter().find(|watch| watch.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Watch> {
+        self.list.iter()
+    }
+
+    /// Remove every watch and the samples not yet drained
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.samples.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The samples taken since the last drain, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = WatchSample> + '_ {
+        self.samples.drain(..)
+    }
+
+    /// Stream samples to `out` as CSV from now on, starting with the header, or stop with None
+    pub fn set_output(&mut self, out: Option<Box<dyn Write + Send>>) {
+        self.out = out;
+        self.error = None;
+        if let Some(out) = &mut self.out {
+            if let Err(e) = writeln!(out, "frame,cycle,pc,watch,value") {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Why streaming stopped, if a write failed
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Stop streaming, flushing what's been written, or return why it stopped early
+    pub fn finish_output(&mut self) -> io::Result<()> {
+        let out = self.out.take();
+        match (self.error.take(), out) {
+            (Some(e), _) => Err(e),
+            (None, Some(mut out)) => out.flush(),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Every byte on-write watches read, for the bus to look out for
+    pub(crate) fn written_addresses(&self) -> Vec<u16> {
+        self.list
+            .iter()
+            .flat_map(|watch| watch.addresses.iter().copied())
+            .collect()
+    }
+
+    /// Sample the frame watches, at the end of a frame
+    pub(crate) fn sample_frame(&mut self, cpu: &NesCpu) {
+        self.sample(cpu, cpu.reg.pc, |watch| {
+            watch.trigger == WatchTrigger::Frame
+        });
+    }
+
+    /// Sample the on-write watches reading any of `written`, after the instruction at `pc`
+    /// wrote them
+    pub(crate) fn sample_writes(&mut self, cpu: &NesCpu, pc: u16, written: &[u16]) {
+        self.sample(cpu, pc, |watch| {
+            watch.trigger == WatchTrigger::Write
+                && watch
+                    .addresses
+                    .iter()
+                    .any(|address| written.contains(address))
+        });
+    }
+
+    fn sample(&mut self, cpu: &NesCpu, pc: u16, due: impl Fn(&Watch) -> bool) {
+        let frame = cpu.memory.ppu().frame_count();
+        let cycle = cpu.tick as u64;
+        for watch in self.list.iter_mut().filter(|watch| due(watch)) {
+            let value = watch.expression.value(cpu);
+            watch.last = Some(value);
+            let sample = WatchSample {
+                id: watch.id,
+                frame,
+                cycle,
+                pc,
+                value,
+            };
+            if self.samples.len() == MAX_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+            if let (Some(out), None) = (&mut self.out, &self.error) {
+                let written = writeln!(
+                    out,
+                    "{},{},${:04X},{},{}",
+                    frame,
+                    cycle,
+                    pc,
+                    csv_field(&watch.expression.to_string()),
+                    value
+                );
+                if let Err(e) = written {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+}
+
+// quoted when it has a comma or a quote in it
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The bytes on-write watches read, which the bus notes writes to until the CPU has finished
+/// the instruction and looks
+#[derive(Debug, Clone)]
+pub(crate) struct WriteWatch {
+    // a bit per address
+    watched: Vec<u64>,
+    written: Vec<u16>,
+}
+
+impl WriteWatch {
+    pub(crate) fn new(addresses: &[u16]) -> Self {
+        let mut watched = vec![0; 0x10000 / 64];
+        for &address in addresses {
+            watched[address as usize / 64] |= 1 << (address % 64);
+        }
+        WriteWatch {
+            watched,
+            written: Vec::new(),
+        }
+    }
+
+    pub(crate) fn write(&mut self, address: u16) {
+        if self.watched[address as usize / 64] >> (address % 64) & 1 != 0
+            && !self.written.contains(&address)
+        {
+            self.written.push(address);
+        }
+    }
+
+    /// The watched bytes written since the last look
+    pub(crate) fn take_written(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.written)
+    }
+
+    pub(crate) fn any_written(&self) -> bool {
+        !self.written.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Bus;
+    use std::sync::{Arc, Mutex};
+
+    // a Write the test can still read after handing it over
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn watch(text: &str, trigger: WatchTrigger) -> Result<u32, BadCondition> {
+        Watches::new().add(Condition::parse(text).unwrap(), trigger)
+    }
+
+    #[test]
+    fn on_write_watches_need_fixed_addresses() {
+        assert!(watch("[$0300] & $0F", WatchTrigger::Write).is_ok());
+        assert!(watch("[$0300 + X]", WatchTrigger::Write).is_err());
+        assert!(watch("A", WatchTrigger::Write).is_err());
+        assert!(watch("A", WatchTrigger::Frame).is_ok());
+    }
+
+    #[test]
+    fn notes_each_watched_byte_written_once() {
+        let mut watch = WriteWatch::new(&[0x0300, 0x6000]);
+        watch.write(0x0301);
+        assert!(!watch.any_written());
+        watch.write(0x0300);
+        watch.write(0x0300);
+        watch.write(0x6000);
+        assert_eq!(watch.take_written(), [0x0300, 0x6000]);
+        assert!(!watch.any_written());
+    }
+
+    #[test]
+    fn samples_stream_as_csv() {
+        let mut cpu = NesCpu::new_from_bytes(&[0xEA]);
+        cpu.memory.write_byte(0x0075, 3);
+        let mut watches = Watches::new();
+        let id = watches
+            .add(Condition::parse("[$0075]").unwrap(), WatchTrigger::Frame)
+            .unwrap();
+        let out = Shared::default();
+        watches.set_output(Some(Box::new(out.clone())));
+        watches.sample_frame(&cpu);
+        assert_eq!(watches.get(id).unwrap().last, Some(3));
+        let samples: Vec<WatchSample> = watches.drain().collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].pc, 0x8000);
+        assert_eq!(
+            String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            format!(
+                "frame,cycle,pc,watch,value\n0,{},$8000,[$0075],3\n",
+                cpu.tick
+            )
+        );
+        assert_eq!(csv_field("A, X"), "\"A, X\"");
+    }
+}
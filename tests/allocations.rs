@@ -0,0 +1,104 @@
+use nesemu::events::EventKind;
+use nesemu::rewind::{RewindBuffer, RewindConfig};
+use nesemu::{parse_bin_file, Emulator};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Counts heap allocations to show that `run_frame` makes none, from the first frame after
+// loading: the picture, the audio samples, the event queue and the history for jam reports
+// all live in buffers made up front. A regression shows up here rather than as a stutter.
+//
+// The count is per thread, so the tests can run side by side.
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const ROM: &str = "test-bin/full_nes_palette.nes";
+const FRAMES: usize = 120;
+
+fn running(history: bool) -> Emulator {
+    let rom = parse_bin_file(ROM).unwrap();
+    let mut emulator = Emulator::new();
+    emulator.keep_history(history);
+    emulator.load_cartridge(&rom).unwrap();
+    emulator
+}
+
+// allocations over `FRAMES` frames, taking the samples and events as a frontend would
+fn allocations_running(emulator: &mut Emulator) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    for _ in 0..FRAMES {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+        emulator.drain_events().for_each(drop);
+    }
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn frames_allocate_nothing() {
+    assert_eq!(allocations_running(&mut running(false)), 0);
+}
+
+#[test]
+fn frames_with_history_allocate_nothing() {
+    // how the frontend runs, keeping the last instructions for jam reports
+    assert_eq!(allocations_running(&mut running(true)), 0);
+}
+
+#[test]
+fn queueing_events_allocates_nothing() {
+    let mut emulator = running(false);
+    emulator.subscribe(EventKind::ScanlineStart);
+    emulator.subscribe(EventKind::VBlankStart);
+    assert_eq!(allocations_running(&mut emulator), 0);
+}
+
+#[test]
+fn rewind_snapshots_allocate_nothing_once_full() {
+    let mut emulator = running(true);
+    let config = RewindConfig {
+        seconds: 1,
+        interval: 1,
+    };
+    let mut rewind = RewindBuffer::new(config, emulator.region().frames_per_second());
+    let mut run = |emulator: &mut Emulator| {
+        emulator.run_frame();
+        emulator.clear_audio_samples();
+        rewind.frame(|state| emulator.save_state_into(state));
+    };
+    for _ in 0..FRAMES {
+        run(&mut emulator);
+    }
+    let before = ALLOCATIONS.with(Cell::get);
+    for _ in 0..FRAMES {
+        run(&mut emulator);
+    }
+    assert_eq!(ALLOCATIONS.with(Cell::get) - before, 0);
+}
@@ -0,0 +1,154 @@
+#![cfg(feature = "blargg-tests")]
+
+use nesemu::test_rom::{self, Protocol, Protocol::*};
+use nesemu::{parse_bin_file, RomError};
+use std::path::{Path, PathBuf};
+
+// Runs blargg's CPU, PPU and APU test ROMs headlessly and reports each one's result. The ROMs
+// aren't in the repository, apart from the branch timing tests, so they're looked for in the
+// directory BLARGG_ROMS names, laid out as in the nes-test-roms collection
+// (https://github.com/christopherpow/nes-test-roms), then in test-bin. Missing ROMs and ones on
+// boards that aren't emulated yet are listed and skipped, as is a suite with none of its ROMs
+// found.
+//
+//   BLARGG_ROMS=~/nes-test-roms cargo test --features blargg-tests --test blargg -- --nocapture
+
+const ROMS_ENV: &str = "BLARGG_ROMS";
+const LOCAL: &str = "test-bin";
+// long enough for the slowest of them, the instruction tests run for around 30 seconds
+const FRAMES: u32 = 60 * 60;
+
+const CPU: &[(&str, Protocol)] = &[
+    ("branch_timing_tests/Branch_Basics.nes", ZeroPage),
+    ("branch_timing_tests/2.Backward_Branch.nes", ZeroPage),
+    ("branch_timing_tests/3.Forward_Branch.nes", ZeroPage),
+    ("instr_test-v5/rom_singles/01-basics.nes", Status),
+    ("instr_test-v5/rom_singles/02-implied.nes", Status),
+    ("instr_test-v5/rom_singles/03-immediate.nes", Status),
+    ("instr_test-v5/rom_singles/04-zero_page.nes", Status),
+    ("instr_test-v5/rom_singles/05-zp_xy.nes", Status),
+    ("instr_test-v5/rom_singles/06-absolute.nes", Status),
+    ("instr_test-v5/rom_singles/07-abs_xy.nes", Status),
+    ("instr_test-v5/rom_singles/08-ind_x.nes", Status),
+    ("instr_test-v5/rom_singles/09-ind_y.nes", Status),
+    ("instr_test-v5/rom_singles/10-branches.nes", Status),
+    ("instr_test-v5/rom_singles/11-stack.nes", Status),
+    ("instr_test-v5/rom_singles/12-jmp_jsr.nes", Status),
+    ("instr_test-v5/rom_singles/13-rts.nes", Status),
+    ("instr_test-v5/rom_singles/14-rti.nes", Status),
+    ("instr_test-v5/rom_singles/15-brk.nes", Status),
+    ("instr_test-v5/rom_singles/16-special.nes", Status),
+    ("instr_timing/rom_singles/1-instr_timing.nes", Status),
+    ("instr_timing/rom_singles/2-branch_timing.nes", Status),
+    ("cpu_dummy_reads.nes", Status),
+];
+
+const PPU: &[(&str, Protocol)] = &[
+    ("ppu_vbl_nmi/rom_singles/01-vbl_basics.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/02-vbl_set_time.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/03-vbl_clear_time.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/04-nmi_control.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/05-nmi_timing.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/06-suppression.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/07-nmi_on_timing.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/08-nmi_off_timing.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/09-even_odd_frames.nes", Status),
+    ("ppu_vbl_nmi/rom_singles/10-even_odd_timing.nes", Status),
+    ("ppu_open_bus/ppu_open_bus.nes", Status),
+    ("ppu_read_buffer/test_ppu_read_buffer.nes", Status),
+];
+
+const APU: &[(&str, Protocol)] = &[
+    ("apu_test/rom_singles/1-len_ctr.nes", Status),
+    ("apu_test/rom_singles/2-len_table.nes", Status),
+    ("apu_test/rom_singles/3-irq_flag.nes", Status),
+    ("apu_test/rom_singles/4-jitter.nes", Status),
+    ("apu_test/rom_singles/5-len_timing.nes", Status),
+    ("apu_test/rom_singles/6-irq_flag_timing.nes", Status),
+    ("apu_test/rom_singles/7-dmc_basics.nes", Status),
+    ("apu_test/rom_singles/8-dmc_rates.nes", Status),
+    ("apu_reset/4015_cleared.nes", Status),
+    ("apu_reset/4017_timing.nes", Status),
+    ("apu_reset/4017_written.nes", Status),
+    ("apu_reset/irq_flag_cleared.nes", Status),
+    ("apu_reset/len_ctrs_enabled.nes", Status),
+    ("apu_reset/works_immediately.nes", Status),
+];
+
+fn find(rom: &str) -> Option<PathBuf> {
+    let roots = std::env::var_os(ROMS_ENV)
+        .map(PathBuf::from)
+        .into_iter()
+        .chain([PathBuf::from(LOCAL)]);
+    for root in roots {
+        // test-bin keeps single ROMs at the top rather than in a directory of their own
+        let name = Path::new(rom).file_name().unwrap();
+        for path in [root.join(rom), root.join(name)] {
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+fn run_suite(suite: &str, roms: &[(&str, Protocol)]) {
+    let mut found = 0;
+    let mut skipped = 0;
+    let mut failures = Vec::new();
+    for &(rom, protocol) in roms {
+        let Some(path) = find(rom) else {
+            println!("{} {:<50} missing", suite, rom);
+            continue;
+        };
+        found += 1;
+        let result = parse_bin_file(path.to_str().unwrap())
+            .and_then(|image| test_rom::run(&image, protocol, FRAMES));
+        let report = match result {
+            Ok(outcome) if outcome.passed() => {
+                println!("{} {:<50} passed", suite, rom);
+                continue;
+            }
+            Ok(outcome) => outcome.to_string(),
+            Err(e @ RomError::UnsupportedMapper(_)) => {
+                println!("{} {:<50} skipped, {}", suite, rom, e);
+                skipped += 1;
+                continue;
+            }
+            Err(e) => format!("couldn't load: {}", e),
+        };
+        println!("{} {:<50} {}", suite, rom, report);
+        failures.push(format!("{}: {}", rom, report));
+    }
+    if found == 0 {
+        println!(
+            "none of the {} test ROMs were found, set {} to a copy of nes-test-roms",
+            suite, ROMS_ENV
+        );
+        return;
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} {} test ROMs failed ({} skipped):\n{}",
+        failures.len(),
+        found,
+        suite,
+        skipped,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn cpu() {
+    run_suite("cpu", CPU);
+}
+
+#[test]
+fn ppu() {
+    run_suite("ppu", PPU);
+}
+
+#[test]
+fn apu() {
+    run_suite("apu", APU);
+}
@@ -0,0 +1,540 @@
+use nesemu::cpu::NesCpu;
+use nesemu::disasm::disassemble;
+use nesemu::memory::{AccessKind, Bus};
+use std::collections::VecDeque;
+use std::fmt;
+
+// Differential test of the CPU against a small reference 6502 written here from the datasheet,
+// kept apart from the emulator's own decoding so a mistake isn't shared by both. Each program
+// is 64KB of random bytes run on both cores in flat memory, instruction by instruction, and the
+// registers, flags and every byte either one wrote are compared after each step. Only the 151
+// documented opcodes are run: whenever the next opcode is an undocumented one it's swapped for
+// a documented one in both memories first. There's no decimal mode, as on the NES.
+//
+// The first difference is reported with the instructions that led up to it. Runs are seeded,
+// so one can be repeated with more programs or from another seed:
+//
+//   DIFFERENTIAL_PROGRAMS=10000 DIFFERENTIAL_SEED=7 cargo test --release --test differential
+
+const PROGRAMS_ENV: &str = "DIFFERENTIAL_PROGRAMS";
+const SEED_ENV: &str = "DIFFERENTIAL_SEED";
+const PROGRAMS: u64 = 200;
+// short enough that the APU frame IRQ, which can't be turned off in flat memory, never fires
+const STEPS: usize = 1000;
+// instructions shown before a difference
+const CONTEXT: usize = 8;
+const TRACE_CAPACITY: usize = 64;
+
+const CARRY: u8 = 0x01;
+const ZERO: u8 = 0x02;
+const INTERRUPT: u8 = 0x04;
+const DECIMAL: u8 = 0x08;
+const BREAK: u8 = 0x10;
+const UNUSED: u8 = 0x20;
+const OVERFLOW: u8 = 0x40;
+const NEGATIVE: u8 = 0x80;
+
+#[rustfmt::skip]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Adc, And, Asl, Bcc, Bcs, Beq, Bit, Bmi, Bne, Bpl, Brk, Bvc, Bvs, Clc, Cld, Cli, Clv, Cmp,
+    Cpx, Cpy, Dec, Dex, Dey, Eor, Inc, Inx, Iny, Jmp, Jsr, Lda, Ldx, Ldy, Lsr, Nop, Ora, Pha,
+    Php, Pla, Plp, Rol, Ror, Rti, Rts, Sbc, Sec, Sed, Sei, Sta, Stx, Sty, Tax, Tay, Tsx, Txa,
+    Txs, Tya,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+use Mode::*;
+use Op::*;
+
+// the documented opcodes, from the MOS programming manual
+#[rustfmt::skip]
+const OPCODES: &[(u8, Op, Mode)] = &[
+    (0x69, Adc, Immediate), (0x65, Adc, ZeroPage), (0x75, Adc, ZeroPageX), (0x6D, Adc, Absolute),
+    (0x7D, Adc, AbsoluteX), (0x79, Adc, AbsoluteY), (0x61, Adc, IndirectX), (0x71, Adc, IndirectY),
+    (0x29, And, Immediate), (0x25, And, ZeroPage), (0x35, And, ZeroPageX), (0x2D, And, Absolute),
+    (0x3D, And, AbsoluteX), (0x39, And, AbsoluteY), (0x21, And, IndirectX), (0x31, And, IndirectY),
+    (0x0A, Asl, Accumulator), (0x06, Asl, ZeroPage), (0x16, Asl, ZeroPageX), (0x0E, Asl, Absolute),
+    (0x1E, Asl, AbsoluteX),
+    (0x90, Bcc, Relative), (0xB0, Bcs, Relative), (0xF0, Beq, Relative), (0x30, Bmi, Relative),
+    (0xD0, Bne, Relative), (0x10, Bpl, Relative), (0x50, Bvc, Relative), (0x70, Bvs, Relative),
+    (0x24, Bit, ZeroPage), (0x2C, Bit, Absolute),
+    (0x00, Brk, Implied),
+    (0x18, Clc, Implied), (0xD8, Cld, Implied), (0x58, Cli, Implied), (0xB8, Clv, Implied),
+    (0xC9, Cmp, Immediate), (0xC5, Cmp, ZeroPage), (0xD5, Cmp, ZeroPageX), (0xCD, Cmp, Absolute),
+    (0xDD, Cmp, AbsoluteX), (0xD9, Cmp, AbsoluteY), (0xC1, Cmp, IndirectX), (0xD1, Cmp, IndirectY),
+    (0xE0, Cpx, Immediate), (0xE4, Cpx, ZeroPage), (0xEC, Cpx, Absolute),
+    (0xC0, Cpy, Immediate), (0xC4, Cpy, ZeroPage), (0xCC, Cpy, Absolute),
+    (0xC6, Dec, ZeroPage), (0xD6, Dec, ZeroPageX), (0xCE, Dec, Absolute), (0xDE, Dec, AbsoluteX),
+    (0xCA, Dex, Implied), (0x88, Dey, Implied),
+    (0x49, Eor, Immediate), (0x45, Eor, ZeroPage), (0x55, Eor, ZeroPageX), (0x4D, Eor, Absolute),
+    (0x5D, Eor, AbsoluteX), (0x59, Eor, AbsoluteY), (0x41, Eor, IndirectX), (0x51, Eor, IndirectY),
+    (0xE6, Inc, ZeroPage), (0xF6, Inc, ZeroPageX), (0xEE, Inc, Absolute), (0xFE, Inc, AbsoluteX),
+    (0xE8, Inx, Implied), (0xC8, Iny, Implied),
+    (0x4C, Jmp, Absolute), (0x6C, Jmp, Indirect), (0x20, Jsr, Absolute),
+    (0xA9, Lda, Immediate), (0xA5, Lda, ZeroPage), (0xB5, Lda, ZeroPageX), (0xAD, Lda, Absolute),
+    (0xBD, Lda, AbsoluteX), (0xB9, Lda, AbsoluteY), (0xA1, Lda, IndirectX), (0xB1, Lda, IndirectY),
+    (0xA2, Ldx, Immediate), (0xA6, Ldx, ZeroPage), (0xB6, Ldx, ZeroPageY), (0xAE, Ldx, Absolute),
+    (0xBE, Ldx, AbsoluteY),
+    (0xA0, Ldy, Immediate), (0xA4, Ldy, ZeroPage), (0xB4, Ldy, ZeroPageX), (0xAC, Ldy, Absolute),
+    (0xBC, Ldy, AbsoluteX),
+    (0x4A, Lsr, Accumulator), (0x46, Lsr, ZeroPage), (0x56, Lsr, ZeroPageX), (0x4E, Lsr, Absolute),
+    (0x5E, Lsr, AbsoluteX),
+    (0xEA, Nop, Implied),
+    (0x09, Ora, Immediate), (0x05, Ora, ZeroPage), (0x15, Ora, ZeroPageX), (0x0D, Ora, Absolute),
+    (0x1D, Ora, AbsoluteX), (0x19, Ora, AbsoluteY), (0x01, Ora, IndirectX), (0x11, Ora, IndirectY),
+    (0x48, Pha, Implied), (0x08, Php, Implied), (0x68, Pla, Implied), (0x28, Plp, Implied),
+    (0x2A, Rol, Accumulator), (0x26, Rol, ZeroPage), (0x36, Rol, ZeroPageX), (0x2E, Rol, Absolute),
+    (0x3E, Rol, AbsoluteX),
+    (0x6A, Ror, Accumulator), (0x66, Ror, ZeroPage), (0x76, Ror, ZeroPageX), (0x6E, Ror, Absolute),
+    (0x7E, Ror, AbsoluteX),
+    (0x40, Rti, Implied), (0x60, Rts, Implied),
+    (0xE9, Sbc, Immediate), (0xE5, Sbc, ZeroPage), (0xF5, Sbc, ZeroPageX), (0xED, Sbc, Absolute),
+    (0xFD, Sbc, AbsoluteX), (0xF9, Sbc, AbsoluteY), (0xE1, Sbc, IndirectX), (0xF1, Sbc, IndirectY),
+    (0x38, Sec, Implied), (0xF8, Sed, Implied), (0x78, Sei, Implied),
+    (0x85, Sta, ZeroPage), (0x95, Sta, ZeroPageX), (0x8D, Sta, Absolute), (0x9D, Sta, AbsoluteX),
+    (0x99, Sta, AbsoluteY), (0x81, Sta, IndirectX), (0x91, Sta, IndirectY),
+    (0x86, Stx, ZeroPage), (0x96, Stx, ZeroPageY), (0x8E, Stx, Absolute),
+    (0x84, Sty, ZeroPage), (0x94, Sty, ZeroPageX), (0x8C, Sty, Absolute),
+    (0xAA, Tax, Implied), (0xA8, Tay, Implied), (0xBA, Tsx, Implied), (0x8A, Txa, Implied),
+    (0x9A, Txs, Implied), (0x98, Tya, Implied),
+];
+
+fn decode(opcode: u8) -> Option<(Op, Mode)> {
+    OPCODES
+        .iter()
+        .find(|&&(code, _, _)| code == opcode)
+        .map(|&(_, op, mode)| (op, mode))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Registers {
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    s: u8,
+    // B and bit 5 don't exist in the CPU, so they're always shown clear and set
+    p: u8,
+}
+
+impl Registers {
+    fn of(cpu: &NesCpu) -> Self {
+        Registers {
+            pc: cpu.reg.pc,
+            a: cpu.reg.accumulator,
+            x: cpu.reg.idx,
+            y: cpu.reg.idy(),
+            s: cpu.reg.sp(),
+            p: cpu.reg.status() & !BREAK | UNUSED,
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.a, self.x, self.y, self.p, self.s
+        )
+    }
+}
+
+struct Reference {
+    reg: Registers,
+    memory: Vec<u8>,
+    written: Vec<u16>,
+}
+
+impl Reference {
+    fn read(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.written.push(address);
+    }
+
+    // pointers in the zero page wrap within it
+    fn read_pointer(&self, address: u8) -> u16 {
+        u16::from_le_bytes([
+            self.read(address as u16),
+            self.read(address.wrapping_add(1) as u16),
+        ])
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let byte = self.read(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        u16::from_le_bytes([self.fetch(), self.fetch()])
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(0x0100 | self.reg.s as u16, value);
+        self.reg.s = self.reg.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.reg.s = self.reg.s.wrapping_add(1);
+        self.read(0x0100 | self.reg.s as u16)
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.reg.p |= flag;
+        } else {
+            self.reg.p &= !flag;
+        }
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.reg.p & flag != 0
+    }
+
+    fn set_zn(&mut self, value: u8) -> u8 {
+        self.set_flag(ZERO, value == 0);
+        self.set_flag(NEGATIVE, value & 0x80 != 0);
+        value
+    }
+
+    fn address(&mut self, mode: Mode) -> u16 {
+        match mode {
+            Immediate => {
+                let address = self.reg.pc;
+                self.reg.pc = self.reg.pc.wrapping_add(1);
+                address
+            }
+            ZeroPage => self.fetch() as u16,
+            ZeroPageX => self.fetch().wrapping_add(self.reg.x) as u16,
+            ZeroPageY => self.fetch().wrapping_add(self.reg.y) as u16,
+            Absolute => self.fetch_word(),
+            AbsoluteX => self.fetch_word().wrapping_add(self.reg.x as u16),
+            AbsoluteY => self.fetch_word().wrapping_add(self.reg.y as u16),
+            Indirect => {
+                // the high byte comes from the same page, JMP ($10FF) reads $10FF and $1000
+                let pointer = self.fetch_word();
+                let high = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+                u16::from_le_bytes([self.read(pointer), self.read(high)])
+            }
+            IndirectX => {
+                let pointer = self.fetch().wrapping_add(self.reg.x);
+                self.read_pointer(pointer)
+            }
+            IndirectY => {
+                let pointer = self.fetch();
+                self.read_pointer(pointer).wrapping_add(self.reg.y as u16)
+            }
+            Implied | Accumulator | Relative => unreachable!("{:?} has no operand address", mode),
+        }
+    }
+
+    fn add(&mut self, value: u8) {
+        let a = self.reg.a;
+        let sum = a as u16 + value as u16 + self.flag(CARRY) as u16;
+        let result = sum as u8;
+        self.set_flag(CARRY, sum > 0xFF);
+        self.set_flag(OVERFLOW, (a ^ result) & (value ^ result) & 0x80 != 0);
+        self.reg.a = self.set_zn(result);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(CARRY, register >= value);
+        self.set_zn(register.wrapping_sub(value));
+    }
+
+    // ASL, LSR, ROL and ROR, on the accumulator or in memory
+    fn shift(&mut self, op: Op, mode: Mode) {
+        let address = (mode != Accumulator).then(|| self.address(mode));
+        let value = address.map_or(self.reg.a, |address| self.read(address));
+        let carry_in = self.flag(CARRY) as u8;
+        let (result, carry_out) = match op {
+            Asl => (value << 1, value & 0x80 != 0),
+            Lsr => (value >> 1, value & 0x01 != 0),
+            Rol => (value << 1 | carry_in, value & 0x80 != 0),
+            Ror => (value >> 1 | carry_in << 7, value & 0x01 != 0),
+            _ => unreachable!(),
+        };
+        self.set_flag(CARRY, carry_out);
+        self.set_zn(result);
+        match address {
+            Some(address) => self.write(address, result),
+            None => self.reg.a = result,
+        }
+    }
+
+    fn branch(&mut self, taken: bool) {
+        let offset = self.fetch() as i8;
+        if taken {
+            self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
+        }
+    }
+
+    fn step(&mut self) {
+        self.written.clear();
+        let opcode = self.fetch();
+        let (op, mode) = decode(opcode).expect("only documented opcodes are run");
+        match op {
+            Adc | And | Bit | Cmp | Cpx | Cpy | Eor | Lda | Ldx | Ldy | Ora | Sbc => {
+                let address = self.address(mode);
+                let value = self.read(address);
+                match op {
+                    Adc => self.add(value),
+                    Sbc => self.add(!value),
+                    And => self.reg.a = self.set_zn(self.reg.a & value),
+                    Eor => self.reg.a = self.set_zn(self.reg.a ^ value),
+                    Ora => self.reg.a = self.set_zn(self.reg.a | value),
+                    Lda => self.reg.a = self.set_zn(value),
+                    Ldx => self.reg.x = self.set_zn(value),
+                    Ldy => self.reg.y = self.set_zn(value),
+                    Cmp => self.compare(self.reg.a, value),
+                    Cpx => self.compare(self.reg.x, value),
+                    Cpy => self.compare(self.reg.y, value),
+                    Bit => {
+                        self.set_flag(ZERO, self.reg.a & value == 0);
+                        self.set_flag(OVERFLOW, value & 0x40 != 0);
+                        self.set_flag(NEGATIVE, value & 0x80 != 0);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Sta | Stx | Sty => {
+                let address = self.address(mode);
+                let value = match op {
+                    Sta => self.reg.a,
+                    Stx => self.reg.x,
+                    _ => self.reg.y,
+                };
+                self.write(address, value);
+            }
+            Inc | Dec => {
+                let address = self.address(mode);
+                let value = self.read(address);
+                let result = if op == Inc {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                self.set_zn(result);
+                self.write(address, result);
+            }
+            Asl | Lsr | Rol | Ror => self.shift(op, mode),
+            Bcc => self.branch(!self.flag(CARRY)),
+            Bcs => self.branch(self.flag(CARRY)),
+            Bne => self.branch(!self.flag(ZERO)),
+            Beq => self.branch(self.flag(ZERO)),
+            Bpl => self.branch(!self.flag(NEGATIVE)),
+            Bmi => self.branch(self.flag(NEGATIVE)),
+            Bvc => self.branch(!self.flag(OVERFLOW)),
+            Bvs => self.branch(self.flag(OVERFLOW)),
+            Jmp => self.reg.pc = self.address(mode),
+            Jsr => {
+                let target = self.fetch_word();
+                let [low, high] = self.reg.pc.wrapping_sub(1).to_le_bytes();
+                self.push(high);
+                self.push(low);
+                self.reg.pc = target;
+            }
+            Rts => {
+                let address = u16::from_le_bytes([self.pull(), self.pull()]);
+                self.reg.pc = address.wrapping_add(1);
+            }
+            Brk => {
+                // the byte after BRK is skipped
+                let [low, high] = self.reg.pc.wrapping_add(1).to_le_bytes();
+                self.push(high);
+                self.push(low);
+                self.push(self.reg.p | BREAK | UNUSED);
+                self.set_flag(INTERRUPT, true);
+                self.reg.pc = u16::from_le_bytes([self.read(0xFFFE), self.read(0xFFFF)]);
+            }
+            Rti => {
+                self.reg.p = self.pull() & !BREAK | UNUSED;
+                self.reg.pc = u16::from_le_bytes([self.pull(), self.pull()]);
+            }
+            Pha => self.push(self.reg.a),
+            Php => self.push(self.reg.p | BREAK | UNUSED),
+            Pla => {
+                let value = self.pull();
+                self.reg.a = self.set_zn(value);
+            }
+            Plp => self.reg.p = self.pull() & !BREAK | UNUSED,
+            Clc => self.set_flag(CARRY, false),
+            Sec => self.set_flag(CARRY, true),
+            Cli => self.set_flag(INTERRUPT, false),
+            Sei => self.set_flag(INTERRUPT, true),
+            Clv => self.set_flag(OVERFLOW, false),
+            Cld => self.set_flag(DECIMAL, false),
+            Sed => self.set_flag(DECIMAL, true),
+            Inx => self.reg.x = self.set_zn(self.reg.x.wrapping_add(1)),
+            Iny => self.reg.y = self.set_zn(self.reg.y.wrapping_add(1)),
+            Dex => self.reg.x = self.set_zn(self.reg.x.wrapping_sub(1)),
+            Dey => self.reg.y = self.set_zn(self.reg.y.wrapping_sub(1)),
+            Tax => self.reg.x = self.set_zn(self.reg.a),
+            Tay => self.reg.y = self.set_zn(self.reg.a),
+            Txa => self.reg.a = self.set_zn(self.reg.x),
+            Tya => self.reg.a = self.set_zn(self.reg.y),
+            Tsx => self.reg.x = self.set_zn(self.reg.s),
+            Txs => self.reg.s = self.reg.x,
+            Nop => {}
+        }
+    }
+}
+
+// xorshift64*, enough to make programs from a seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn byte(&mut self) -> u8 {
+        (self.next() >> 56) as u8
+    }
+}
+
+fn instruction_text(memory: &[u8], pc: u16) -> String {
+    let code: Vec<u8> = (0..3)
+        .map(|offset| memory[pc.wrapping_add(offset) as usize])
+        .collect();
+    disassemble(&code, pc)
+        .first()
+        .map_or_else(String::new, ToString::to_string)
+}
+
+/// Run one program, returning a report of the first difference
+fn run(seed: u64) -> Result<(), String> {
+    let mut rng = Rng::new(seed);
+    let memory: Vec<u8> = (0..0x10000).map(|_| rng.byte()).collect();
+    let mut cpu = NesCpu::new();
+    cpu.memory.set_flat(true);
+    cpu.memory.write_bytes(0, &memory);
+    let mut reference = Reference {
+        reg: Registers {
+            pc: u16::from_le_bytes([rng.byte(), rng.byte()]),
+            a: rng.byte(),
+            x: rng.byte(),
+            y: rng.byte(),
+            s: rng.byte(),
+            p: rng.byte() & !BREAK | UNUSED,
+        },
+        memory,
+        written: Vec::new(),
+    };
+    let start = reference.reg;
+    cpu.reg.pc = start.pc;
+    cpu.reg.accumulator = start.a;
+    cpu.reg.idx = start.x;
+    cpu.reg.set_idy(start.y);
+    cpu.reg.set_sp(start.s);
+    cpu.reg.set_status(start.p);
+
+    let mut history = VecDeque::with_capacity(CONTEXT);
+    for step in 0..STEPS {
+        let pc = reference.reg.pc;
+        if decode(reference.read(pc)).is_none() {
+            let opcode = OPCODES[rng.next() as usize % OPCODES.len()].0;
+            reference.memory[pc as usize] = opcode;
+            cpu.memory.write_byte(pc, opcode);
+        }
+        let before = reference.reg;
+        let text = instruction_text(&reference.memory, pc);
+
+        cpu.memory.enable_trace(TRACE_CAPACITY);
+        cpu.fetch_decode_next();
+        let written: Vec<u16> = cpu
+            .memory
+            .drain_trace()
+            .iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .map(|access| access.address)
+            .collect();
+        reference.step();
+
+        let mut differences = Vec::new();
+        let got = Registers::of(&cpu);
+        if got != reference.reg {
+            differences.push(format!(
+                "registers {}\n     expected {}",
+                got, reference.reg
+            ));
+        }
+        let mut addresses: Vec<u16> = written.iter().chain(&reference.written).copied().collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        for address in addresses {
+            let (got, expected) = (cpu.memory.peek_byte(address), reference.read(address));
+            if got != expected {
+                differences.push(format!(
+                    "${:04X} is ${:02X}, expected ${:02X}",
+                    address, got, expected
+                ));
+            }
+        }
+        if !differences.is_empty() {
+            let mut report = format!("seed {} step {}:\n", seed, step);
+            for line in &history {
+                report += &format!("    {}\n", line);
+            }
+            report += &format!("  > {:<32}{}\n", text, before);
+            for difference in differences {
+                report += &format!("    {}\n", difference);
+            }
+            return Err(report);
+        }
+
+        if history.len() == CONTEXT {
+            history.pop_front();
+        }
+        history.push_back(format!("{:<32}{}", text, before));
+    }
+    Ok(())
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    std::env::var(name).map_or(default, |value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{}: `{}` isn't a number", name, value))
+    })
+}
+
+#[test]
+fn matches_the_reference_cpu() {
+    let programs = env_number(PROGRAMS_ENV, PROGRAMS);
+    let seed = env_number(SEED_ENV, 0);
+    for seed in seed..seed + programs {
+        if let Err(report) = run(seed) {
+            panic!("the CPU differs from the reference at {}", report);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use std::process::Command;
+
+// Runs the nesemu-headless binary the way CI scripts do
+
+const ROM: &str = "test-bin/full_nes_palette.nes";
+
+#[test]
+fn traces_start_from_the_reset_vector() {
+    let output = Command::new(env!("CARGO_BIN_EXE_nesemu-headless"))
+        .args(["--frames", "1", "--trace", ROM])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let log = String::from_utf8(output.stdout).unwrap();
+    // the reset vector is $E007, the load address nestest's automation uses is $C000
+    let first = log.lines().next().unwrap();
+    assert!(first.starts_with("E007  78        SEI"), "{}", first);
+}
@@ -0,0 +1,78 @@
+use nesemu::trace::TraceLogger;
+use nesemu::{parse_bin_file, Emulator};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// Runs nestest in its automated mode, from $C000 with no PPU needed, and compares the trace
+// with the log nestest.log from a real NES, line by line. The test ends with the RTS at $C66E
+// the log ends on. Bytes $02 and $03 hold nestest's own error codes for what it found broken.
+
+const ROM: &str = "test-bin/nestest.nes";
+const GOLDEN_LOG: &str = "nestest.log";
+const START: u16 = 0xC000;
+const END: u16 = 0xC66E;
+// lines of the log shown before a mismatch
+const CONTEXT: usize = 5;
+
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn matches_the_golden_log() {
+    let golden = std::fs::read_to_string(GOLDEN_LOG).unwrap();
+    let golden: Vec<&str> = golden.lines().collect();
+    let rom = parse_bin_file(ROM).unwrap();
+    let mut emulator = Emulator::new();
+    // loading runs the 7 cycle reset sequence the log starts after, then nestest's automated
+    // mode starts at $C000 rather than the reset vector
+    emulator.load_cartridge(&rom).unwrap();
+    emulator.cpu_mut().set_pc(START);
+    let out = Shared::default();
+    emulator
+        .cpu_mut()
+        .set_trace_logger(Some(TraceLogger::new(out.clone())));
+
+    let mut ran = 0;
+    while ran < golden.len() && emulator.cpu().jammed().is_none() {
+        let done = emulator.cpu().reg.pc == END;
+        emulator.step_instruction();
+        ran += 1;
+        if done {
+            break;
+        }
+    }
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    for (number, (&expected, &actual)) in golden.iter().zip(&lines).enumerate() {
+        if expected != actual {
+            let before = golden[number.saturating_sub(CONTEXT)..number].join("\n");
+            panic!(
+                "trace differs from {} at line {}, after\n{}\n\nexpected\n{}\ngot\n{}\n\
+                 nestest error codes: $02 = {:02X}, $03 = {:02X}",
+                GOLDEN_LOG,
+                number + 1,
+                before,
+                expected,
+                actual,
+                emulator.ram()[0x02],
+                emulator.ram()[0x03]
+            );
+        }
+    }
+    assert_eq!(
+        lines.len(),
+        golden.len(),
+        "trace stopped after {} lines of {}",
+        lines.len(),
+        golden.len()
+    );
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "serde")]
+
+use nesemu::apu::Apu;
+use nesemu::cpu::Registers;
+use nesemu::emulator::Emulator;
+use nesemu::memory::Memory;
+use nesemu::parse_bin_file;
+
+// The core's state through serde rather than save states, in JSON and bincode
+//
+//   cargo test --features serde --test serde
+
+fn running() -> Emulator {
+    let rom = parse_bin_file("test-bin/full_nes_palette.nes").unwrap();
+    let mut emulator = Emulator::new();
+    emulator.load_cartridge(&rom).unwrap();
+    for _ in 0..30 {
+        emulator.run_frame();
+    }
+    emulator
+}
+
+#[test]
+fn json_round_trips() {
+    let emulator = running();
+    let cpu = emulator.cpu();
+    let json = serde_json::to_string(&cpu.memory).unwrap();
+    let memory: Memory = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&memory).unwrap(), json);
+    assert_eq!(memory.ram(), cpu.memory.ram());
+
+    let registers = serde_json::to_value(&cpu.reg).unwrap();
+    assert_eq!(registers["pc"], cpu.reg.pc);
+    let back: Registers = serde_json::from_value(registers).unwrap();
+    assert_eq!(back.status(), cpu.reg.status());
+}
+
+#[test]
+fn a_bus_read_back_runs_the_same() {
+    let mut emulator = running();
+    let memory = bincode::serialize(&emulator.cpu().memory).unwrap();
+    let registers = bincode::serialize(&emulator.cpu().reg).unwrap();
+
+    // the board comes back ROM and all, so this needs no rom loaded
+    let mut copy = Emulator::new();
+    copy.cpu_mut().memory = bincode::deserialize(&memory).unwrap();
+    copy.cpu_mut().reg = bincode::deserialize(&registers).unwrap();
+    for _ in 0..10 {
+        emulator.run_frame();
+        copy.run_frame();
+    }
+    assert_eq!(copy.frame().sha1(), emulator.frame().sha1());
+    assert_eq!(copy.cpu().memory.ram(), emulator.cpu().memory.ram());
+}
+
+#[test]
+fn tables_of_no_region_are_refused() {
+    let emulator = running();
+    let mut json = serde_json::to_value(emulator.cpu().memory.apu()).unwrap();
+    json["noise"]["periods"][0] = 5.into();
+    let Err(error) = serde_json::from_value::<Apu>(json) else {
+        panic!("read back a noise channel with made up periods");
+    };
+    assert!(error
+        .to_string()
+        .contains("noise period table of no region"));
+}
@@ -0,0 +1,203 @@
+use nesemu::cpu::NesCpu;
+use nesemu::memory::{AccessKind, Bus, BusAccess};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+// Runs the SingleStepTests CPU tests (https://github.com/SingleStepTests/65x02, the nes6502
+// set, once known as TomHarte/ProcessorTests): a JSON file per opcode, each with 10,000 cases
+// of registers and RAM before and after one instruction and every bus access it makes, cycle
+// by cycle. Memory is made flat for them, 64KB of RAM with no registers, as the tests expect.
+// Each case checks the registers, the RAM it lists, the cycles taken and the bus accesses in
+// order. B and bit 5 of P aren't compared, the CPU doesn't hold them.
+//
+// The files are too big for the repository, so they're read from the directory
+// SINGLE_STEP_TESTS names, or test-bin/nes6502/v1, and the test only runs when asked for:
+//
+//   SINGLE_STEP_TESTS=~/65x02/nes6502/v1 cargo test --release --test single_step -- --ignored --nocapture
+//
+// SINGLE_STEP_OPCODES limits it to some opcodes, such as `a9,b1`.
+
+const TESTS_ENV: &str = "SINGLE_STEP_TESTS";
+const OPCODES_ENV: &str = "SINGLE_STEP_OPCODES";
+const DEFAULT_DIR: &str = "test-bin/nes6502/v1";
+const UNHELD_FLAGS: u8 = 0x30;
+// accesses kept, more than any instruction makes so extra ones show up
+const TRACE_CAPACITY: usize = 64;
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+fn access_text(address: u16, value: u8, kind: &str) -> String {
+    format!("{} ${:04X} = ${:02X}", kind, address, value)
+}
+
+fn kind_name(access: &BusAccess) -> &'static str {
+    match access.kind {
+        AccessKind::Read => "read",
+        AccessKind::Write => "write",
+    }
+}
+
+/// Run one case, returning what came out different
+fn run(case: &Case) -> Result<(), String> {
+    let mut cpu = NesCpu::new();
+    cpu.memory.set_flat(true);
+    for &(address, value) in &case.initial.ram {
+        cpu.memory.write_byte(address, value);
+    }
+    let initial = &case.initial;
+    cpu.reg.pc = initial.pc;
+    cpu.reg.set_sp(initial.s);
+    cpu.reg.accumulator = initial.a;
+    cpu.reg.idx = initial.x;
+    cpu.reg.set_idy(initial.y);
+    cpu.reg.set_status(initial.p);
+    cpu.memory.enable_trace(TRACE_CAPACITY);
+    let start = cpu.tick;
+    cpu.fetch_decode_next();
+    let cycles = cpu.tick - start;
+    let accesses = cpu.memory.drain_trace();
+
+    let expected = &case.expected;
+    let mut differences = String::new();
+    let mut compare = |what: &str, got: u16, want: u16, width: usize| {
+        if got != want {
+            let _ = writeln!(
+                differences,
+                "    {} is ${:0width$X}, expected ${:0width$X}",
+                what,
+                got,
+                want,
+                width = width
+            );
+        }
+    };
+    compare("PC", cpu.reg.pc, expected.pc, 4);
+    compare("SP", cpu.reg.sp() as u16, expected.s as u16, 2);
+    compare("A", cpu.reg.accumulator as u16, expected.a as u16, 2);
+    compare("X", cpu.reg.idx as u16, expected.x as u16, 2);
+    compare("Y", cpu.reg.idy() as u16, expected.y as u16, 2);
+    compare(
+        "P",
+        (cpu.reg.status() | UNHELD_FLAGS) as u16,
+        (expected.p | UNHELD_FLAGS) as u16,
+        2,
+    );
+    for &(address, value) in &expected.ram {
+        compare(
+            &format!("${:04X}", address),
+            cpu.memory.peek_byte(address) as u16,
+            value as u16,
+            2,
+        );
+    }
+    if cycles != case.cycles.len() {
+        let _ = writeln!(
+            differences,
+            "    took {} cycles, expected {}",
+            cycles,
+            case.cycles.len()
+        );
+    }
+    let got: Vec<String> = accesses
+        .iter()
+        .map(|access| access_text(access.address, access.value, kind_name(access)))
+        .collect();
+    let want: Vec<String> = case
+        .cycles
+        .iter()
+        .map(|(address, value, kind)| access_text(*address, *value, kind))
+        .collect();
+    if got != want {
+        let _ = writeln!(differences, "    bus accesses, made and expected:");
+        for i in 0..got.len().max(want.len()) {
+            let made = got.get(i).map_or("", String::as_str);
+            let expected = want.get(i).map_or("", String::as_str);
+            let marker = if made == expected { " " } else { "*" };
+            let _ = writeln!(differences, "    {} {:<24}{}", marker, made, expected);
+        }
+    }
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(differences)
+    }
+}
+
+fn opcodes() -> Vec<u8> {
+    match std::env::var(OPCODES_ENV) {
+        Ok(list) => list
+            .split(',')
+            .map(|opcode| {
+                u8::from_str_radix(opcode.trim(), 16)
+                    .unwrap_or_else(|_| panic!("{}: `{}` isn't an opcode", OPCODES_ENV, opcode))
+            })
+            .collect(),
+        Err(_) => (0..=0xFF).collect(),
+    }
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests JSON files, see SINGLE_STEP_TESTS"]
+fn every_opcode_matches() {
+    let dir = std::env::var_os(TESTS_ENV).map_or_else(|| PathBuf::from(DEFAULT_DIR), PathBuf::from);
+    assert!(
+        dir.is_dir(),
+        "{} isn't a directory, set {} to the nes6502/v1 tests",
+        dir.display(),
+        TESTS_ENV
+    );
+    let mut failing = Vec::new();
+    for opcode in opcodes() {
+        let path = dir.join(format!("{:02x}.json", opcode));
+        let Ok(json) = std::fs::read_to_string(&path) else {
+            println!("${:02X} missing", opcode);
+            continue;
+        };
+        let cases: Vec<Case> =
+            serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let mut failed = 0;
+        for case in &cases {
+            if let Err(differences) = run(case) {
+                // the first failure in full, the count for the rest
+                if failed == 0 {
+                    println!("${:02X} {} failed:\n{}", opcode, case.name, differences);
+                }
+                failed += 1;
+            }
+        }
+        println!(
+            "${:02X} {} of {} passed",
+            opcode,
+            cases.len() - failed,
+            cases.len()
+        );
+        if failed > 0 {
+            failing.push(format!("${:02X} ({} failed)", opcode, failed));
+        }
+    }
+    assert!(
+        failing.is_empty(),
+        "{} opcodes failed: {}",
+        failing.len(),
+        failing.join(", ")
+    );
+}
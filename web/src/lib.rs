@@ -0,0 +1,110 @@
+// The emulator in a browser. index.html does the page: the file picker, forwarding key
+// presses and calling run_frame and draw once a frame. This side keeps the console and turns
+// frames into canvas pixels. There's no sound yet.
+
+use nesemu::input::{
+    BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START,
+    BUTTON_UP,
+};
+use nesemu::palette::Palette;
+use nesemu::ppu::{HEIGHT, WIDTH};
+use nesemu::{image_from_bytes, Emulator, RomImage};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{CanvasRenderingContext2d, ImageData};
+
+// the SDL frontend's player 1 keys, by KeyboardEvent.code so they don't move with the layout
+const KEYS: [(&str, u8); 8] = [
+    ("KeyX", BUTTON_A),
+    ("KeyZ", BUTTON_B),
+    ("ShiftRight", BUTTON_SELECT),
+    ("Enter", BUTTON_START),
+    ("ArrowUp", BUTTON_UP),
+    ("ArrowDown", BUTTON_DOWN),
+    ("ArrowLeft", BUTTON_LEFT),
+    ("ArrowRight", BUTTON_RIGHT),
+];
+
+#[wasm_bindgen]
+pub struct WebEmulator {
+    emulator: Emulator,
+    palette: Palette,
+    buttons: u8,
+    // the last frame as RGBA, what ImageData takes
+    rgba: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl WebEmulator {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WebEmulator {
+        WebEmulator {
+            emulator: Emulator::new(),
+            palette: Palette::default(),
+            buttons: 0,
+            rgba: vec![0; WIDTH * HEIGHT * 4],
+        }
+    }
+
+    /// Load an iNES, NES 2.0 or UNIF rom, zipped or gzipped or not, and start it
+    pub fn load_rom(&mut self, bytes: Vec<u8>) -> Result<(), JsError> {
+        let rom = match image_from_bytes(bytes)? {
+            RomImage::Cartridge(rom) => rom,
+            RomImage::Disk(_) => return Err(JsError::new("FDS disks need the BIOS")),
+        };
+        self.emulator.load_cartridge(&rom)?;
+        Ok(())
+    }
+
+    pub fn run_frame(&mut self) {
+        self.emulator.set_buttons(0, self.buttons);
+        self.emulator.run_frame();
+        self.emulator.clear_audio_samples();
+    }
+
+    /// Put the last frame at the top left of `context`'s canvas, which should be 256x240
+    pub fn draw(&mut self, context: &CanvasRenderingContext2d) -> Result<(), JsValue> {
+        let pixels = self.emulator.frame().pixels();
+        for (&pixel, rgba) in pixels.iter().zip(self.rgba.chunks_exact_mut(4)) {
+            let [r, g, b] = self.palette.rgb(pixel);
+            rgba.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+        let image = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(&self.rgba),
+            WIDTH as u32,
+            HEIGHT as u32,
+        )?;
+        context.put_image_data(&image, 0.0, 0.0)
+    }
+
+    /// Press the button on `code`, a KeyboardEvent.code. False for keys that aren't buttons,
+    /// which the page should leave alone.
+    pub fn key_down(&mut self, code: &str) -> bool {
+        let button = button_for_key(code);
+        self.buttons |= button;
+        button != 0
+    }
+
+    pub fn key_up(&mut self, code: &str) -> bool {
+        let button = button_for_key(code);
+        self.buttons &= !button;
+        button != 0
+    }
+
+    /// How often to call `run_frame`, 60.1 for NTSC games and 50 for PAL
+    pub fn frames_per_second(&self) -> f64 {
+        self.emulator.region().frames_per_second()
+    }
+}
+
+impl Default for WebEmulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn button_for_key(code: &str) -> u8 {
+    KEYS.iter()
+        .find(|&&(key, _)| key == code)
+        .map_or(0, |&(_, button)| button)
+}